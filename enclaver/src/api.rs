@@ -1,23 +1,295 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
 use async_trait::async_trait;
 use http::{Method, Request, Response};
 use hyper::header;
 use hyper::{Body, StatusCode};
+use log::warn;
 use pkcs8::{DecodePublicKey, SubjectPublicKeyInfo};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+use crate::attestation::channel_binding_nonce;
+use crate::connections::ConnectionTable;
 use crate::http_util::{self, HttpHandler};
+use crate::journal::unix_time;
 use crate::nsm::{AttestationParams, AttestationProvider};
+use crate::spiffe::SvidStore;
+use crate::token::TokenIssuer;
 
 const MIME_APPLICATION_CBOR: &str = "application/cbor";
+const MIME_APPLICATION_JSON: &str = "application/json";
+
+// Channels whose attestations are kept fresh at once; the least recently fetched
+// one is dropped to make room for another
+const MAX_CHANNELS: usize = 256;
+
+/// How the enclave was set up, as chosen at boot, served at /v1/context
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ApiContext {
+    /// URL of the egress proxy, if egress is enabled
+    pub egress_proxy: Option<String>,
+
+    /// URLs of the named egress proxies, by name
+    pub egress_proxies: BTreeMap<String, String>,
+}
+
+/// Attestations bound to channels of the application, e.g. TLS connections to a
+/// peer that wants to check the enclave again for as long as the connection lasts.
+/// Each carries the nonce of its channel binding, see channel_binding_nonce, and is
+/// produced again every refresh interval while the application keeps fetching it.
+#[derive(Clone)]
+pub struct ChannelAttestations {
+    attester: Arc<dyn AttestationProvider + Send + Sync>,
+    refresh: Duration,
+    channels: Arc<Mutex<HashMap<Vec<u8>, ChannelAttestation>>>,
+}
+
+struct ChannelAttestation {
+    doc: Vec<u8>,
+    produced: Instant,
+    fetched: Instant,
+}
+
+impl ChannelAttestations {
+    pub fn new(attester: Box<dyn AttestationProvider + Send + Sync>, refresh: Duration) -> Self {
+        Self {
+            attester: Arc::from(attester),
+            refresh,
+            channels: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The latest attestation of the channel, at most one refresh interval old,
+    /// produced now if the channel has none yet
+    pub fn get(&self, channel_binding: &[u8]) -> Result<Vec<u8>> {
+        let nonce = channel_binding_nonce(channel_binding);
+        let now = Instant::now();
+
+        if let Some(channel) = self.channels.lock().unwrap().get_mut(&nonce) {
+            if now.duration_since(channel.produced) < self.refresh {
+                channel.fetched = now;
+                return Ok(channel.doc.clone());
+            }
+        }
+
+        let doc = self.produce(&nonce)?;
+        self.store(nonce, doc.clone(), now);
+        Ok(doc)
+    }
+
+    /// Produces the attestations of the channels fetched within the last two refresh
+    /// intervals again as they age, and forgets the others. Runs until aborted.
+    pub async fn refresh(self) {
+        loop {
+            tokio::time::sleep(self.refresh / 10).await;
+
+            for nonce in self.due(Instant::now()) {
+                match self.produce(&nonce) {
+                    Ok(doc) => {
+                        if let Some(channel) = self.channels.lock().unwrap().get_mut(&nonce) {
+                            channel.doc = doc;
+                            channel.produced = Instant::now();
+                        }
+                    }
+                    // The application gets a new one on its next fetch instead
+                    Err(err) => warn!("failed to refresh a channel attestation: {err}"),
+                }
+            }
+        }
+    }
+
+    fn produce(&self, nonce: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.attester.attestation(AttestationParams {
+            nonce: Some(nonce.to_vec()),
+            public_key: None,
+            user_data: None,
+        })?)
+    }
+
+    fn store(&self, nonce: Vec<u8>, doc: Vec<u8>, now: Instant) {
+        let mut channels = self.channels.lock().unwrap();
+        if !channels.contains_key(&nonce) && channels.len() >= MAX_CHANNELS {
+            let oldest = channels
+                .iter()
+                .min_by_key(|(_, channel)| channel.fetched)
+                .map(|(nonce, _)| nonce.clone());
+            if let Some(oldest) = oldest {
+                channels.remove(&oldest);
+            }
+        }
+
+        channels.insert(
+            nonce,
+            ChannelAttestation {
+                doc,
+                produced: now,
+                fetched: now,
+            },
+        );
+    }
+
+    // The channels whose attestations are about to age past the refresh interval,
+    // dropping those the application stopped fetching
+    fn due(&self, now: Instant) -> Vec<Vec<u8>> {
+        let mut channels = self.channels.lock().unwrap();
+        channels.retain(|_, channel| now.duration_since(channel.fetched) < 2 * self.refresh);
+
+        channels
+            .iter()
+            .filter(|(_, channel)| now.duration_since(channel.produced) >= self.refresh * 9 / 10)
+            .map(|(nonce, _)| nonce.clone())
+            .collect()
+    }
+}
+
+/// Loads the certificates of the TLS ingress ports again, for /v1/tls/reload
+pub trait TlsReloader: Send + Sync {
+    /// Swaps in the certificates as they are now, all of them or none, and returns
+    /// the ports that present them from then on
+    fn reload(&self) -> Result<Vec<u16>>;
+}
 
 pub struct ApiHandler {
     attester: Box<dyn AttestationProvider + Send + Sync>,
+    context: ApiContext,
+    svids: Option<SvidStore>,
+    tokens: Option<TokenIssuer>,
+    channels: Option<ChannelAttestations>,
+    tls_reloader: Option<Arc<dyn TlsReloader>>,
+    csr: Option<String>,
+    connections: Option<ConnectionTable>,
 }
 
 impl ApiHandler {
     pub fn new(attester: Box<dyn AttestationProvider + Send + Sync>) -> Self {
-        Self { attester }
+        Self {
+            attester,
+            context: ApiContext::default(),
+            svids: None,
+            tokens: None,
+            channels: None,
+            tls_reloader: None,
+            csr: None,
+            connections: None,
+        }
+    }
+
+    pub fn with_context(mut self, context: ApiContext) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// Serves the SVID of the enclave at /v1/svid, once it has one
+    pub fn with_svids(mut self, svids: SvidStore) -> Self {
+        self.svids = Some(svids);
+        self
+    }
+
+    /// Issues tokens at /v1/token, and serves what verifiers need to check them
+    pub fn with_tokens(mut self, tokens: TokenIssuer) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    /// Serves attestations bound to channels at /v1/attestation/channel
+    pub fn with_channel_attestations(mut self, channels: ChannelAttestations) -> Self {
+        self.channels = Some(channels);
+        self
+    }
+
+    /// Reloads the certificates of the TLS ingress ports at /v1/tls/reload
+    pub fn with_tls_reloader(mut self, reloader: Arc<dyn TlsReloader>) -> Self {
+        self.tls_reloader = Some(reloader);
+        self
+    }
+
+    /// Serves the PEM encoded certificate signing request for the key of the ingress
+    /// ports with a csr at /v1/tls/csr
+    pub fn with_certificate_request(mut self, csr: String) -> Self {
+        self.csr = Some(csr);
+        self
+    }
+
+    /// Lists the connections the proxies are relaying at /v1/debug/connections
+    pub fn with_connection_table(mut self, connections: ConnectionTable) -> Self {
+        self.connections = Some(connections);
+        self
+    }
+
+    fn handle_context(&self) -> Result<Response<Body>> {
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, MIME_APPLICATION_JSON)
+            .body(Body::from(serde_json::to_vec(&self.context)?))?)
+    }
+
+    fn handle_svid(&self) -> Result<Response<Body>> {
+        let svid = match self.svids.as_ref().map(SvidStore::get) {
+            Some(Some(svid)) => svid,
+            Some(None) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::from("no SVID has been issued yet"))?)
+            }
+            None => return Ok(http_util::not_found()),
+        };
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, MIME_APPLICATION_JSON)
+            .body(Body::from(serde_json::to_vec(&svid)?))?)
+    }
+
+    fn handle_token(&self, body: &[u8]) -> Result<Response<Body>> {
+        let tokens = match self.tokens {
+            Some(ref tokens) => tokens,
+            None => return Ok(http_util::not_found()),
+        };
+
+        let token_req: TokenRequest = match serde_json::from_slice(body) {
+            Ok(req) => req,
+            Err(err) => return Ok(http_util::bad_request(err.to_string())),
+        };
+
+        let (token, expires_at) = tokens.issue(&token_req.audience, unix_time())?;
+
+        json_response(&TokenResponse { token, expires_at })
+    }
+
+    fn handle_jwks(&self) -> Result<Response<Body>> {
+        match self.tokens {
+            Some(ref tokens) => json_response(&tokens.jwks()),
+            None => Ok(http_util::not_found()),
+        }
+    }
+
+    fn handle_discovery(&self) -> Result<Response<Body>> {
+        match self.tokens {
+            Some(ref tokens) => json_response(&tokens.discovery()),
+            None => Ok(http_util::not_found()),
+        }
+    }
+
+    // An attestation carrying the token signing key, which ties the JWKS to the enclave
+    fn handle_token_attestation(&self) -> Result<Response<Body>> {
+        let tokens = match self.tokens {
+            Some(ref tokens) => tokens,
+            None => return Ok(http_util::not_found()),
+        };
+
+        let att_doc = self.attester.attestation(AttestationParams {
+            nonce: None,
+            public_key: Some(tokens.public_key_as_der()?),
+            user_data: None,
+        })?;
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, MIME_APPLICATION_CBOR)
+            .body(Body::from(att_doc))?)
     }
 
     async fn handle_attestation(
@@ -42,6 +314,65 @@ impl ApiHandler {
             .header(header::CONTENT_TYPE, MIME_APPLICATION_CBOR)
             .body(Body::from(att_doc))?)
     }
+
+    fn handle_channel_attestation(&self, body: &[u8]) -> Result<Response<Body>> {
+        let channels = match self.channels {
+            Some(ref channels) => channels,
+            None => return Ok(http_util::not_found()),
+        };
+
+        let channel_req: ChannelAttestationRequest = match serde_json::from_slice(body) {
+            Ok(req) => req,
+            Err(err) => return Ok(http_util::bad_request(err.to_string())),
+        };
+
+        let channel_binding = match base64::decode(&channel_req.channel_binding) {
+            Ok(binding) if !binding.is_empty() => binding,
+            Ok(_) => return Ok(http_util::bad_request("empty channel_binding".to_string())),
+            Err(err) => return Ok(http_util::bad_request(err.to_string())),
+        };
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, MIME_APPLICATION_CBOR)
+            .body(Body::from(channels.get(&channel_binding)?))?)
+    }
+
+    // Connections open at the time keep the certificates they were made with. If
+    // any certificate fails to load, the ones in use stay in place.
+    fn handle_tls_reload(&self) -> Result<Response<Body>> {
+        let reloader = match self.tls_reloader {
+            Some(ref reloader) => reloader,
+            None => return Ok(http_util::not_found()),
+        };
+
+        match reloader.reload() {
+            Ok(ports) => json_response(&TlsReloadResponse { ports }),
+            Err(err) => {
+                warn!("failed to reload the TLS ingress certificates: {err}");
+                Ok(Response::builder()
+                    .status(StatusCode::UNPROCESSABLE_ENTITY)
+                    .body(Body::from(err.to_string()))?)
+            }
+        }
+    }
+
+    fn handle_csr(&self) -> Result<Response<Body>> {
+        match self.csr {
+            Some(ref csr) => Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/pkcs10")
+                .body(Body::from(csr.clone()))?),
+            None => Ok(http_util::not_found()),
+        }
+    }
+
+    fn handle_debug_connections(&self) -> Result<Response<Body>> {
+        match self.connections {
+            Some(ref connections) => json_response(&connections.snapshot()),
+            None => Ok(http_util::not_found()),
+        }
+    }
 }
 
 #[async_trait]
@@ -56,11 +387,89 @@ impl HttpHandler for ApiHandler {
 
                 _ => Ok(http_util::method_not_allowed()),
             },
+            "/v1/attestation/channel" => match head.method {
+                Method::POST => self.handle_channel_attestation(&body),
+
+                _ => Ok(http_util::method_not_allowed()),
+            },
+            "/v1/context" => match head.method {
+                Method::GET => self.handle_context(),
+
+                _ => Ok(http_util::method_not_allowed()),
+            },
+            "/v1/svid" => match head.method {
+                Method::GET => self.handle_svid(),
+
+                _ => Ok(http_util::method_not_allowed()),
+            },
+            "/v1/token" => match head.method {
+                Method::POST => self.handle_token(&body),
+
+                _ => Ok(http_util::method_not_allowed()),
+            },
+            "/v1/token/attestation" => match head.method {
+                Method::GET => self.handle_token_attestation(),
+
+                _ => Ok(http_util::method_not_allowed()),
+            },
+            "/v1/tls/reload" => match head.method {
+                Method::POST => self.handle_tls_reload(),
+
+                _ => Ok(http_util::method_not_allowed()),
+            },
+            "/v1/tls/csr" => match head.method {
+                Method::GET => self.handle_csr(),
+
+                _ => Ok(http_util::method_not_allowed()),
+            },
+            "/v1/debug/connections" => match head.method {
+                Method::GET => self.handle_debug_connections(),
+
+                _ => Ok(http_util::method_not_allowed()),
+            },
+            "/.well-known/jwks.json" => match head.method {
+                Method::GET => self.handle_jwks(),
+
+                _ => Ok(http_util::method_not_allowed()),
+            },
+            "/.well-known/openid-configuration" => match head.method {
+                Method::GET => self.handle_discovery(),
+
+                _ => Ok(http_util::method_not_allowed()),
+            },
             _ => Ok(http_util::not_found()),
         }
     }
 }
 
+fn json_response<T: Serialize>(value: &T) -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, MIME_APPLICATION_JSON)
+        .body(Body::from(serde_json::to_vec(value)?))?)
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    audience: String,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    token: String,
+    expires_at: u64,
+}
+
+#[derive(Serialize)]
+struct TlsReloadResponse {
+    ports: Vec<u16>,
+}
+
+#[derive(Deserialize)]
+struct ChannelAttestationRequest {
+    channel_binding: String,
+}
+
 #[derive(Deserialize)]
 struct AttestationRequest {
     nonce: Option<String>,
@@ -139,3 +548,285 @@ async fn test_attestation_handler() {
     let resp = handler.handle(req).await.unwrap();
     assert!(resp.status() == StatusCode::OK);
 }
+
+#[tokio::test]
+async fn test_context_handler() {
+    use crate::nsm::StaticAttestationProvider;
+    use assert2::assert;
+
+    let handler = ApiHandler::new(Box::new(StaticAttestationProvider::new(Vec::new())))
+        .with_context(ApiContext {
+            egress_proxy: Some("http://127.0.0.1:10000/".to_string()),
+            ..Default::default()
+        });
+
+    let req = Request::builder()
+        .method("GET")
+        .uri("/v1/context")
+        .body(Body::empty())
+        .unwrap();
+
+    let resp = handler.handle(req).await.unwrap();
+    assert!(resp.status() == StatusCode::OK);
+
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let context: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(context["egress_proxy"] == "http://127.0.0.1:10000/");
+}
+
+#[tokio::test]
+async fn test_svid_handler() {
+    use crate::nsm::StaticAttestationProvider;
+    use crate::spiffe::Svid;
+    use assert2::assert;
+
+    let get = || {
+        Request::builder()
+            .method("GET")
+            .uri("/v1/svid")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let handler = ApiHandler::new(Box::new(StaticAttestationProvider::new(Vec::new())));
+    let resp = handler.handle(get()).await.unwrap();
+    assert!(resp.status() == StatusCode::NOT_FOUND);
+
+    let svids = SvidStore::default();
+    let handler = handler.with_svids(svids.clone());
+    let resp = handler.handle(get()).await.unwrap();
+    assert!(resp.status() == StatusCode::SERVICE_UNAVAILABLE);
+
+    svids.set(Svid {
+        spiffe_id: "spiffe://example.org/enclave/app".to_string(),
+        certificates: String::new(),
+        private_key: String::new(),
+        bundle: String::new(),
+        expires_at: 1000,
+    });
+    let resp = handler.handle(get()).await.unwrap();
+    assert!(resp.status() == StatusCode::OK);
+
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let svid: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(svid["spiffe_id"] == "spiffe://example.org/enclave/app");
+}
+
+#[tokio::test]
+async fn test_token_handler() {
+    use crate::keypair::KeyPair;
+    use crate::nsm::StaticAttestationProvider;
+    use assert2::assert;
+
+    let post = || {
+        Request::builder()
+            .method("POST")
+            .uri("/v1/token")
+            .body(Body::from(r#"{"audience": "https://api.example.com"}"#))
+            .unwrap()
+    };
+
+    let handler = ApiHandler::new(Box::new(StaticAttestationProvider::new(vec![1, 2, 3])));
+    let resp = handler.handle(post()).await.unwrap();
+    assert!(resp.status() == StatusCode::NOT_FOUND);
+
+    let pcrs = ["00".repeat(48), "01".repeat(48), "02".repeat(48)];
+    let tokens = TokenIssuer::new(
+        KeyPair::generate().unwrap(),
+        "https://enclave.example.com",
+        "app",
+        pcrs,
+        300,
+    )
+    .unwrap();
+    let handler = handler.with_tokens(tokens);
+
+    let resp = handler.handle(post()).await.unwrap();
+    assert!(resp.status() == StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let token: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(token["token"].as_str().unwrap().split('.').count() == 3);
+
+    let req = Request::builder()
+        .method("GET")
+        .uri("/.well-known/jwks.json")
+        .body(Body::empty())
+        .unwrap();
+    let resp = handler.handle(req).await.unwrap();
+    assert!(resp.status() == StatusCode::OK);
+
+    let req = Request::builder()
+        .method("GET")
+        .uri("/v1/token/attestation")
+        .body(Body::empty())
+        .unwrap();
+    let resp = handler.handle(req).await.unwrap();
+    assert!(resp.status() == StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(body.as_ref() == [1, 2, 3]);
+}
+
+#[tokio::test]
+async fn test_channel_attestation_handler() {
+    use crate::nsm::{NsmError, StaticAttestationProvider};
+    use assert2::assert;
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    // Returns a counter followed by the nonce, to tell fresh documents from cached ones
+    struct CountingAttestationProvider(AtomicU8);
+
+    impl AttestationProvider for CountingAttestationProvider {
+        fn attestation(&self, params: AttestationParams) -> Result<Vec<u8>, NsmError> {
+            let mut doc = vec![self.0.fetch_add(1, Ordering::SeqCst)];
+            doc.extend(params.nonce.unwrap());
+            Ok(doc)
+        }
+    }
+
+    let post = |binding: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/v1/attestation/channel")
+            .body(Body::from(format!(r#"{{"channel_binding": "{binding}"}}"#)))
+            .unwrap()
+    };
+    let exporter = base64::encode("exporter");
+
+    let handler = ApiHandler::new(Box::new(StaticAttestationProvider::new(Vec::new())));
+    let resp = handler.handle(post(&exporter)).await.unwrap();
+    assert!(resp.status() == StatusCode::NOT_FOUND);
+
+    let channels = ChannelAttestations::new(
+        Box::new(CountingAttestationProvider(AtomicU8::new(0))),
+        Duration::from_secs(300),
+    );
+    let handler = handler.with_channel_attestations(channels.clone());
+
+    let resp = handler.handle(post(&exporter)).await.unwrap();
+    assert!(resp.status() == StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(body[0] == 0);
+    assert!(body[1..] == channel_binding_nonce(b"exporter"));
+
+    // Served from the cache until it is refreshed
+    let resp = handler.handle(post(&exporter)).await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(body[0] == 0);
+
+    let resp = handler
+        .handle(post(&base64::encode("other")))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(body[0] == 1);
+
+    let resp = handler.handle(post("")).await.unwrap();
+    assert!(resp.status() == StatusCode::BAD_REQUEST);
+
+    // Both are refreshed as they age, and dropped once no longer fetched
+    let now = Instant::now();
+    assert!(channels.due(now).is_empty());
+    assert!(channels.due(now + Duration::from_secs(280)).len() == 2);
+    assert!(channels.due(now + Duration::from_secs(600)).is_empty());
+    assert!(channels.channels.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_tls_reload_handler() {
+    use crate::nsm::StaticAttestationProvider;
+    use assert2::assert;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    struct Reloader(AtomicBool);
+
+    impl TlsReloader for Reloader {
+        fn reload(&self) -> Result<Vec<u16>> {
+            match self.0.load(Ordering::SeqCst) {
+                true => Ok(vec![443, 8443]),
+                false => Err(anyhow::anyhow!("no such file")),
+            }
+        }
+    }
+
+    let post = || {
+        Request::builder()
+            .method("POST")
+            .uri("/v1/tls/reload")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let handler = ApiHandler::new(Box::new(StaticAttestationProvider::new(Vec::new())));
+    let resp = handler.handle(post()).await.unwrap();
+    assert!(resp.status() == StatusCode::NOT_FOUND);
+
+    let reloader = Arc::new(Reloader(AtomicBool::new(false)));
+    let handler = handler.with_tls_reloader(reloader.clone());
+    let resp = handler.handle(post()).await.unwrap();
+    assert!(resp.status() == StatusCode::UNPROCESSABLE_ENTITY);
+
+    reloader.0.store(true, Ordering::SeqCst);
+    let resp = handler.handle(post()).await.unwrap();
+    assert!(resp.status() == StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let reloaded: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(reloaded["ports"] == serde_json::json!([443, 8443]));
+}
+
+#[tokio::test]
+async fn test_debug_connections_handler() {
+    use crate::connections::ConnectionKind;
+    use crate::nsm::StaticAttestationProvider;
+    use assert2::assert;
+
+    let get = || {
+        Request::builder()
+            .uri("/v1/debug/connections")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let handler = ApiHandler::new(Box::new(StaticAttestationProvider::new(Vec::new())));
+    let resp = handler.handle(get()).await.unwrap();
+    assert!(resp.status() == StatusCode::NOT_FOUND);
+
+    let table = ConnectionTable::default();
+    let _tracked = table.track(
+        ConnectionKind::Egress,
+        10000,
+        "127.0.0.1:40000".to_string(),
+        "example.com:443".to_string(),
+    );
+    let handler = handler.with_connection_table(table);
+    let resp = handler.handle(get()).await.unwrap();
+    assert!(resp.status() == StatusCode::OK);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let connections: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(connections[0]["kind"] == "egress");
+    assert!(connections[0]["target"] == "example.com:443");
+}
+
+#[tokio::test]
+async fn test_csr_handler() {
+    use crate::nsm::StaticAttestationProvider;
+    use assert2::assert;
+
+    let get = || {
+        Request::builder()
+            .uri("/v1/tls/csr")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let handler = ApiHandler::new(Box::new(StaticAttestationProvider::new(Vec::new())));
+    let resp = handler.handle(get()).await.unwrap();
+    assert!(resp.status() == StatusCode::NOT_FOUND);
+
+    let csr = "-----BEGIN CERTIFICATE REQUEST-----\nMA==\n-----END CERTIFICATE REQUEST-----\n";
+    let handler = handler.with_certificate_request(csr.to_string());
+    let resp = handler.handle(get()).await.unwrap();
+    assert!(resp.status() == StatusCode::OK);
+    assert!(resp.headers()["Content-Type"] == "application/pkcs10");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(body == csr.as_bytes());
+}
@@ -0,0 +1,458 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use aws_nitro_enclaves_nsm_api::api::AttestationDoc;
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+
+use crate::der;
+
+// Signature algorithms used in the Nitro certificate chain
+static CHAIN_SIG_ALGS: &[&webpki::SignatureAlgorithm] =
+    &[&webpki::ECDSA_P384_SHA384, &webpki::ECDSA_P256_SHA256];
+
+const COSE_SIGN1_TAG: u64 = 18;
+const P384_SCALAR_LEN: usize = 48;
+
+/// Goes before the channel binding in the nonce of a channel attestation, so that it
+/// cannot pass for the nonce of any other attestation
+pub const CHANNEL_BINDING_LABEL: &[u8] = b"enclaver channel binding v1:";
+
+/// Goes before the public key in the nonce of the attestation an attested certificate
+/// carries
+pub const ATTESTED_KEY_LABEL: &[u8] = b"enclaver attested key v1:";
+
+/// The X.509 extension an attested certificate carries its attestation document in,
+/// as an OCTET STRING: 2.25.40458724186022756599088798449786804361, a UUID OID, DER
+/// encoded without its tag and length
+pub const ATTESTATION_EXTENSION_OID: &[u8] = &[
+    0x69, 0xbc, 0xf0, 0x88, 0xc7, 0x92, 0x94, 0xe2, 0xb3, 0x81, 0xa3, 0xa6, 0xe0, 0xbe, 0xfb, 0xcd,
+    0x87, 0xb1, 0x09,
+];
+
+// How far ahead of the verifier's clock the timestamp of an attestation may be
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+// Verifies attestation documents produced by the Nitro Secure Module against
+// a trusted root certificate and a set of expected PCR values.
+pub struct AttestationVerifier {
+    root_cert: Vec<u8>,
+    expected_pcrs: BTreeMap<usize, Vec<u8>>,
+}
+
+impl AttestationVerifier {
+    pub fn new(root_cert_pem: &[u8], expected_pcrs: BTreeMap<usize, Vec<u8>>) -> Result<Self> {
+        let root_cert = rustls_pemfile::certs(&mut &root_cert_pem[..])?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("no certificate found in the root certificate PEM"))?;
+
+        Ok(Self {
+            root_cert,
+            expected_pcrs,
+        })
+    }
+
+    // Verifies `doc` and returns its contents. The document must carry `nonce`.
+    pub fn verify(&self, doc: &[u8], nonce: &[u8]) -> Result<AttestationDoc> {
+        let cose = CoseSign1::parse(doc)?;
+        let att_doc = AttestationDoc::from_binary(&cose.payload)
+            .map_err(|e| anyhow!("failed to parse attestation document: {e:?}"))?;
+
+        let anchor = webpki::TrustAnchor::try_from_cert_der(&self.root_cert)
+            .map_err(|e| anyhow!("invalid root certificate: {e:?}"))?;
+        let cert = webpki::EndEntityCert::try_from(att_doc.certificate.as_slice())
+            .map_err(|e| anyhow!("invalid attestation certificate: {e:?}"))?;
+        let intermediates: Vec<&[u8]> = att_doc.cabundle.iter().map(|c| c.as_slice()).collect();
+        let now = webpki::Time::try_from(SystemTime::now())
+            .map_err(|_| anyhow!("system time is before the unix epoch"))?;
+
+        cert.verify_for_usage(
+            CHAIN_SIG_ALGS,
+            &[anchor],
+            &intermediates,
+            now,
+            webpki::KeyUsage::client_auth(),
+            &[],
+        )
+        .map_err(|e| anyhow!("attestation certificate chain is not trusted: {e:?}"))?;
+
+        cert.verify_signature(
+            &webpki::ECDSA_P384_SHA384,
+            &cose.signed_data()?,
+            &ecdsa_fixed_to_der(&cose.signature)?,
+        )
+        .map_err(|e| anyhow!("invalid attestation document signature: {e:?}"))?;
+
+        match att_doc.nonce {
+            Some(ref doc_nonce) if doc_nonce.as_slice() == nonce => {}
+            _ => return Err(anyhow!("attestation document nonce does not match")),
+        }
+
+        if is_debug_mode(&att_doc.pcrs) {
+            return Err(anyhow!(
+                "attestation document is from an enclave running in debug mode"
+            ));
+        }
+
+        check_pcrs(&att_doc.pcrs, &self.expected_pcrs)?;
+
+        Ok(att_doc)
+    }
+
+    /// Verifies the attestation document of an attested certificate, DER encoded, and
+    /// returns its contents. The document must bind the public key of the certificate,
+    /// both as its public key and through its nonce.
+    pub fn verify_certificate(&self, cert: &[u8]) -> Result<AttestationDoc> {
+        let (public_key, doc) = certificate_attestation(cert)
+            .ok_or_else(|| anyhow!("certificate carries no attestation document"))?;
+        let att_doc = self.verify(doc, &attested_key_nonce(public_key))?;
+
+        match att_doc.public_key {
+            Some(ref key) if key.as_slice() == public_key => Ok(att_doc),
+            _ => Err(anyhow!(
+                "attestation document does not bind the key of the certificate"
+            )),
+        }
+    }
+}
+
+/// The nonce of an attestation bound to a channel of the enclave, e.g. the TLS
+/// exporter value of a connection (RFC 9266): SHA-256 of CHANNEL_BINDING_LABEL
+/// followed by the channel binding
+pub fn channel_binding_nonce(channel_binding: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(CHANNEL_BINDING_LABEL);
+    hasher.update(channel_binding);
+    hasher.finalize().to_vec()
+}
+
+/// The nonce of the attestation of an attested certificate: SHA-256 of
+/// ATTESTED_KEY_LABEL followed by the DER SubjectPublicKeyInfo of the certificate
+pub fn attested_key_nonce(public_key: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(ATTESTED_KEY_LABEL);
+    hasher.update(public_key);
+    hasher.finalize().to_vec()
+}
+
+// The DER SubjectPublicKeyInfo of a DER encoded certificate, and the attestation
+// document in its extension, if it has one
+pub(crate) fn certificate_attestation(cert: &[u8]) -> Option<(&[u8], &[u8])> {
+    // Certificate, then TBSCertificate, which only has extensions as of v3
+    let (_, cert, _) = der::next(cert)?;
+    let (_, tbs, _) = der::next(cert)?;
+    let (tag, _, mut rest) = der::next(tbs)?;
+    if tag != der::VERSION {
+        return None;
+    }
+
+    // The serial number, signature algorithm, issuer, validity and subject come
+    // before the key, the optional unique IDs after it
+    for _ in 0..5 {
+        rest = der::next(rest)?.2;
+    }
+    let public_key = der::first(rest)?;
+    rest = &rest[public_key.len()..];
+
+    while !rest.is_empty() {
+        let (tag, contents, next) = der::next(rest)?;
+        if tag == der::EXTENSIONS {
+            let (_, mut extensions, _) = der::next(contents)?;
+            while !extensions.is_empty() {
+                let (_, extension, next) = der::next(extensions)?;
+                let (_, oid, value) = der::next(extension)?;
+                if oid == ATTESTATION_EXTENSION_OID {
+                    // Critical is optional, the value comes last
+                    let (mut tag, mut doc, mut after) = der::next(value)?;
+                    if tag == der::BOOLEAN {
+                        (tag, doc, after) = der::next(after)?;
+                    }
+                    return (tag == der::OCTET_STRING && after.is_empty())
+                        .then_some((public_key, doc));
+                }
+                extensions = next;
+            }
+            return None;
+        }
+        rest = next;
+    }
+
+    None
+}
+
+/// Checks the enclave at the other end of a long-lived channel again and again, with
+/// the fresh attestations it produces for the channel. Each must be bound to the
+/// channel, at most max_age old, and newer than the last one accepted, so that an old
+/// attestation cannot be passed off as a fresh one.
+pub struct ChannelVerifier {
+    verifier: AttestationVerifier,
+    channel_binding: Vec<u8>,
+    max_age: Duration,
+
+    // Timestamp of the last attestation accepted, in milliseconds since the epoch
+    last_timestamp: Option<u64>,
+}
+
+impl ChannelVerifier {
+    pub fn new(verifier: AttestationVerifier, channel_binding: &[u8], max_age: Duration) -> Self {
+        Self {
+            verifier,
+            channel_binding: channel_binding.to_vec(),
+            max_age,
+            last_timestamp: None,
+        }
+    }
+
+    /// Verifies the latest attestation of the peer and returns its contents
+    pub fn verify(&mut self, doc: &[u8]) -> Result<AttestationDoc> {
+        let nonce = channel_binding_nonce(&self.channel_binding);
+        let att_doc = self.verifier.verify(doc, &nonce)?;
+
+        self.check_timestamp(att_doc.timestamp, SystemTime::now())?;
+        self.last_timestamp = Some(att_doc.timestamp);
+
+        Ok(att_doc)
+    }
+
+    /// Whether the peer is due for a new attestation: none was accepted yet, or the
+    /// last one is older than max_age
+    pub fn is_stale(&self) -> bool {
+        self.is_stale_at(SystemTime::now())
+    }
+
+    fn is_stale_at(&self, now: SystemTime) -> bool {
+        match self.last_timestamp {
+            Some(timestamp) => age(timestamp, now) > self.max_age,
+            None => true,
+        }
+    }
+
+    fn check_timestamp(&self, timestamp: u64, now: SystemTime) -> Result<()> {
+        let now_ms = now.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        if timestamp > now_ms + MAX_CLOCK_SKEW.as_millis() as u64 {
+            return Err(anyhow!("attestation document is from the future"));
+        }
+
+        if age(timestamp, now) > self.max_age {
+            return Err(anyhow!(
+                "attestation document is older than {} seconds",
+                self.max_age.as_secs()
+            ));
+        }
+
+        if self.last_timestamp.is_some_and(|last| timestamp <= last) {
+            return Err(anyhow!(
+                "attestation document is not newer than the last one accepted"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// How old an attestation with the timestamp is, none if it is from the future
+fn age(timestamp: u64, now: SystemTime) -> Duration {
+    let then = UNIX_EPOCH + Duration::from_millis(timestamp);
+    now.duration_since(then).unwrap_or_default()
+}
+
+/// Enclaves started in debug mode report all zero PCRs, so their attestations
+/// cannot be told apart and prove nothing about the image.
+pub fn is_debug_mode<V: AsRef<[u8]>>(pcrs: &BTreeMap<usize, V>) -> bool {
+    pcrs.get(&0)
+        .is_some_and(|pcr0| pcr0.as_ref().iter().all(|b| *b == 0))
+}
+
+fn check_pcrs<V: AsRef<[u8]>>(
+    actual: &BTreeMap<usize, V>,
+    expected: &BTreeMap<usize, Vec<u8>>,
+) -> Result<()> {
+    for (index, value) in expected {
+        match actual.get(index) {
+            Some(actual) if actual.as_ref() == value.as_slice() => {}
+            _ => return Err(anyhow!("PCR{index} does not match the expected value")),
+        }
+    }
+
+    Ok(())
+}
+
+// The parts of a COSE_Sign1 structure (RFC 8152) we need to verify it
+struct CoseSign1 {
+    protected: Vec<u8>,
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl CoseSign1 {
+    fn parse(buf: &[u8]) -> Result<Self> {
+        let items = match serde_cbor::from_slice(buf)? {
+            Value::Array(items) => items,
+            Value::Tag(COSE_SIGN1_TAG, inner) => match *inner {
+                Value::Array(items) => items,
+                _ => return Err(anyhow!("malformed COSE_Sign1")),
+            },
+            _ => return Err(anyhow!("malformed COSE_Sign1")),
+        };
+
+        match <[Value; 4]>::try_from(items) {
+            Ok([Value::Bytes(protected), _, Value::Bytes(payload), Value::Bytes(signature)]) => {
+                Ok(Self {
+                    protected,
+                    payload,
+                    signature,
+                })
+            }
+            _ => Err(anyhow!("malformed COSE_Sign1")),
+        }
+    }
+
+    // The Sig_structure the signature is computed over
+    fn signed_data(&self) -> Result<Vec<u8>> {
+        Ok(serde_cbor::to_vec(&Value::Array(vec![
+            Value::Text("Signature1".to_string()),
+            Value::Bytes(self.protected.clone()),
+            Value::Bytes(vec![]),
+            Value::Bytes(self.payload.clone()),
+        ]))?)
+    }
+}
+
+// COSE carries ECDSA signatures as r || s, while webpki expects them DER encoded
+fn ecdsa_fixed_to_der(sig: &[u8]) -> Result<Vec<u8>> {
+    if sig.len() != 2 * P384_SCALAR_LEN {
+        return Err(anyhow!("unexpected ECDSA signature length {}", sig.len()));
+    }
+
+    let mut body = Vec::new();
+    for scalar in sig.chunks(P384_SCALAR_LEN) {
+        let start = scalar
+            .iter()
+            .position(|b| *b != 0)
+            .unwrap_or(scalar.len() - 1);
+        let scalar = &scalar[start..];
+
+        body.push(0x02);
+        if scalar[0] & 0x80 != 0 {
+            body.push(scalar.len() as u8 + 1);
+            body.push(0);
+        } else {
+            body.push(scalar.len() as u8);
+        }
+        body.extend_from_slice(scalar);
+    }
+
+    let mut der = vec![0x30, body.len() as u8];
+    der.append(&mut body);
+    Ok(der)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        channel_binding_nonce, check_pcrs, ecdsa_fixed_to_der, is_debug_mode, AttestationVerifier,
+        ChannelVerifier, CoseSign1,
+    };
+    use assert2::assert;
+    use serde_cbor::Value;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_cose_sign1() {
+        let buf = serde_cbor::to_vec(&Value::Array(vec![
+            Value::Bytes(vec![0xa1, 0x01, 0x38, 0x22]),
+            Value::Map(BTreeMap::new()),
+            Value::Bytes(b"payload".to_vec()),
+            Value::Bytes(b"signature".to_vec()),
+        ]))
+        .unwrap();
+
+        let cose = CoseSign1::parse(&buf).unwrap();
+        assert!(cose.payload == b"payload");
+        assert!(cose.signature == b"signature");
+
+        let signed: Value = serde_cbor::from_slice(&cose.signed_data().unwrap()).unwrap();
+        assert!(
+            signed
+                == Value::Array(vec![
+                    Value::Text("Signature1".to_string()),
+                    Value::Bytes(vec![0xa1, 0x01, 0x38, 0x22]),
+                    Value::Bytes(vec![]),
+                    Value::Bytes(b"payload".to_vec()),
+                ])
+        );
+
+        assert!(CoseSign1::parse(&serde_cbor::to_vec(&Value::Array(vec![])).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_ecdsa_fixed_to_der() {
+        let mut sig = vec![0u8; 96];
+        sig[1] = 0x7f;
+        sig[48] = 0x80;
+
+        let der = ecdsa_fixed_to_der(&sig).unwrap();
+        assert!(der[..4] == [0x30, 2 + 47 + 2 + 49, 0x02, 47]);
+        assert!(der[4] == 0x7f);
+        assert!(der[51..54] == [0x02, 49, 0x00]);
+        assert!(der[54] == 0x80);
+
+        assert!(ecdsa_fixed_to_der(&sig[1..]).is_err());
+    }
+
+    #[test]
+    fn test_check_pcrs() {
+        let actual = BTreeMap::from([(0, vec![1; 48]), (1, vec![2; 48])]);
+
+        assert!(check_pcrs(&actual, &BTreeMap::from([(0, vec![1; 48])])).is_ok());
+        assert!(check_pcrs(&actual, &BTreeMap::from([(1, vec![1; 48])])).is_err());
+        assert!(check_pcrs(&actual, &BTreeMap::from([(8, vec![1; 48])])).is_err());
+    }
+
+    #[test]
+    fn test_is_debug_mode() {
+        assert!(is_debug_mode(&BTreeMap::from([
+            (0, vec![0; 48]),
+            (1, vec![0; 48])
+        ])));
+        assert!(!is_debug_mode(&BTreeMap::from([(0, vec![1; 48])])));
+        assert!(!is_debug_mode(&BTreeMap::<usize, Vec<u8>>::new()));
+    }
+
+    #[test]
+    fn test_channel_binding_nonce() {
+        let nonce = channel_binding_nonce(b"exporter");
+        assert!(nonce.len() == 32);
+        assert!(nonce == channel_binding_nonce(b"exporter"));
+        assert!(nonce != channel_binding_nonce(b"other exporter"));
+    }
+
+    #[test]
+    fn test_channel_verifier_timestamps() {
+        let root = include_bytes!("test.crt");
+        let verifier = AttestationVerifier::new(root, BTreeMap::new()).unwrap();
+        let mut channel = ChannelVerifier::new(verifier, b"exporter", Duration::from_secs(300));
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let ms = |secs: u64| (1_700_000_000 + secs) * 1000;
+        assert!(channel.is_stale_at(now));
+
+        assert!(channel.check_timestamp(ms(0), now).is_ok());
+        assert!(channel.check_timestamp(ms(30), now).is_ok());
+        assert!(channel.check_timestamp(ms(120), now).is_err());
+        assert!(channel.check_timestamp(ms(0) - 301_000, now).is_err());
+
+        channel.last_timestamp = Some(ms(0));
+        assert!(!channel.is_stale_at(now + Duration::from_secs(300)));
+        assert!(channel.is_stale_at(now + Duration::from_secs(301)));
+
+        // An attestation is only accepted if it is newer than the last one
+        assert!(channel.check_timestamp(ms(0), now).is_err());
+        assert!(channel
+            .check_timestamp(ms(1), now + Duration::from_secs(1))
+            .is_ok());
+    }
+}
@@ -0,0 +1,222 @@
+//! The startup banner, the first line odyn writes to the app log. It describes the
+//! enclave a log stream came from, so a captured log still says what produced it long
+//! after the enclave is gone, and lets the host check that the enclave it started is
+//! the one it meant to.
+
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::Manifest;
+pub use crate::service_config::policy_hash;
+
+/// Starts the banner line, followed by the banner as JSON
+pub const BANNER_PREFIX: &str = "enclaver-banner: ";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StartupBanner {
+    /// Version of odyn
+    pub version: String,
+
+    /// Name of the enclave, from the manifest
+    pub name: String,
+
+    /// Whether the image was built to run in debug mode
+    pub debug: bool,
+
+    /// What listens inside the enclave, other than the default egress proxy
+    pub services: Vec<BannerService>,
+
+    /// Port of the default egress proxy, which odyn picks when the manifest does not
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress_proxy_port: Option<u16>,
+
+    /// Hex encoded SHA-256 of the egress policy, see policy_hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy_hash: Option<String>,
+
+    /// PCR0, PCR1 and PCR2 in hex, unset when odyn runs without bootstrapping
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pcrs: Option<[String; 3]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BannerService {
+    pub name: String,
+    pub port: u16,
+}
+
+impl StartupBanner {
+    /// The banner of an enclave running the manifest. The host builds one from its
+    /// own copy of the manifest to check the banner of the enclave against.
+    pub fn new(manifest: &Manifest) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            name: manifest.name.clone(),
+            debug: manifest.is_debug(),
+            services: manifest
+                .listen_ports()
+                .into_iter()
+                .map(|(port, name)| BannerService { name, port })
+                .collect(),
+            egress_proxy_port: None,
+            policy_hash: policy_hash(manifest),
+            pcrs: None,
+        }
+    }
+
+    pub fn with_egress_proxy_port(mut self, port: Option<u16>) -> Self {
+        self.egress_proxy_port = port;
+        self
+    }
+
+    pub fn with_pcrs(mut self, pcrs: [String; 3]) -> Self {
+        self.pcrs = Some(pcrs);
+        self
+    }
+
+    pub fn to_line(&self) -> String {
+        // Serializing plain strings and numbers cannot fail
+        format!("{BANNER_PREFIX}{}", serde_json::to_string(self).unwrap())
+    }
+
+    /// None if the line is not a banner
+    pub fn parse(line: &str) -> Option<serde_json::Result<Self>> {
+        line.strip_prefix(BANNER_PREFIX).map(serde_json::from_str)
+    }
+
+    /// How the banner differs from the expected one. The version of odyn and the
+    /// port it picked for the egress proxy are its own business, and the PCRs are
+    /// only compared if both sides know them.
+    pub fn mismatches(&self, expected: &StartupBanner) -> Vec<String> {
+        let mut mismatches = Vec::new();
+
+        if self.name != expected.name {
+            mismatches.push(format!(
+                "name is {:?}, expected {:?}",
+                self.name, expected.name
+            ));
+        }
+
+        if self.debug != expected.debug {
+            mismatches.push(format!(
+                "debug is {}, expected {}",
+                self.debug, expected.debug
+            ));
+        }
+
+        let mut services = self.services.clone();
+        let mut expected_services = expected.services.clone();
+        services.sort_by_key(|service| service.port);
+        expected_services.sort_by_key(|service| service.port);
+        if services != expected_services {
+            mismatches.push(format!(
+                "services are {}, expected {}",
+                describe_services(&services),
+                describe_services(&expected_services)
+            ));
+        }
+
+        if self.policy_hash != expected.policy_hash {
+            mismatches.push(format!(
+                "egress policy hash is {}, expected {}",
+                self.policy_hash.as_deref().unwrap_or("unset"),
+                expected.policy_hash.as_deref().unwrap_or("unset")
+            ));
+        }
+
+        if let (Some(pcrs), Some(expected_pcrs)) = (&self.pcrs, &expected.pcrs) {
+            for (index, (pcr, expected_pcr)) in pcrs.iter().zip(expected_pcrs).enumerate() {
+                if !pcr.eq_ignore_ascii_case(expected_pcr) {
+                    mismatches.push(format!("PCR{index} is {pcr}, expected {expected_pcr}"));
+                }
+            }
+        }
+
+        mismatches
+    }
+}
+
+fn describe_services(services: &[BannerService]) -> String {
+    let services: Vec<String> = services
+        .iter()
+        .map(|service| format!("{} on {}", service.name, service.port))
+        .collect();
+    format!("[{}]", services.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StartupBanner, BANNER_PREFIX};
+    use crate::manifest::Manifest;
+    use assert2::assert;
+
+    fn manifest(egress: &str) -> Manifest {
+        let raw = format!(
+            r#"
+version: v1
+name: "test"
+target: "test:latest"
+sources:
+  app: "app:latest"
+ingress:
+  - listen_port: 8080
+api:
+  listen_port: 9000
+{egress}
+"#
+        );
+        serde_yaml::from_str(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_banner_round_trip() {
+        let banner = StartupBanner::new(&manifest("egress:\n  allow: [\"*.example.com\"]"))
+            .with_egress_proxy_port(Some(10000))
+            .with_pcrs(["00".repeat(48), "11".repeat(48), "22".repeat(48)]);
+
+        let line = banner.to_line();
+        assert!(line.starts_with(BANNER_PREFIX));
+        assert!(!line.contains('\n'));
+        assert!(StartupBanner::parse(&line).unwrap().unwrap() == banner);
+
+        assert!(StartupBanner::parse("Starting [\"/bin/app\"]").is_none());
+        assert!(StartupBanner::parse(&format!("{BANNER_PREFIX}{{")).is_some_and(|r| r.is_err()));
+    }
+
+    #[test]
+    fn test_banner_mismatches() {
+        let base = manifest("egress:\n  allow: [\"*.example.com\"]");
+        let expected = StartupBanner::new(&base).with_pcrs([
+            "AA".repeat(48),
+            "11".repeat(48),
+            "22".repeat(48),
+        ]);
+
+        // The version and egress proxy port are not checked, and PCRs only when known
+        let mut banner = StartupBanner::new(&base).with_egress_proxy_port(Some(10000));
+        banner.version = "0.0.1".to_string();
+        assert!(banner.mismatches(&expected).is_empty());
+
+        let banner = banner.with_pcrs(["aa".repeat(48), "11".repeat(48), "33".repeat(48)]);
+        let mismatches = banner.mismatches(&expected);
+        assert!(mismatches.len() == 1);
+        assert!(mismatches[0].starts_with("PCR2 is 3333"));
+
+        // A relaxed policy or a missing listener shows up
+        let mut other = StartupBanner::new(&manifest("egress:\n  allow: [\"**\"]"));
+        other.services.pop();
+        let mismatches = other.mismatches(&expected);
+        assert!(mismatches.len() == 2);
+        assert!(
+            mismatches[0]
+                == "services are [ingress on 8080], expected [ingress on 8080, api on 9000]"
+        );
+        assert!(mismatches[1].starts_with("egress policy hash is "));
+
+        let no_egress = StartupBanner::new(&manifest(""));
+        assert!(no_egress.policy_hash.is_none());
+        assert!(no_egress.mismatches(&expected)[0].ends_with(&format!(
+            "is unset, expected {}",
+            expected.policy_hash.as_ref().unwrap()
+        )));
+    }
+}
@@ -0,0 +1,489 @@
+//! Load for `enclaver bench`, to check the sizing of an instance or compare Enclaver
+//! versions. The ingress bench runs on the host and sends HTTP requests to an ingress
+//! port, through enclaver-run, the vsock and odyn to the app. The egress bench runs in
+//! odyn of a debug enclave, which the host asks for it on a vsock port of its own, and
+//! sends them through the egress proxy, the vsock and the host relay to a remote.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, Result};
+use http::{header, Request, StatusCode, Uri};
+use hyper::body::HttpBody;
+use hyper::client::conn::SendRequest;
+use hyper::Body;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_vsock::VsockStream;
+
+use crate::constants::DEBUG_BENCH_PORT;
+
+const MAX_CONNECTIONS: usize = 1024;
+const MAX_DURATION_SECONDS: u64 = 600;
+const MAX_REPLY_SIZE: u64 = 64 * 1024;
+
+// How long a worker waits before connecting again after a failure, so that a port
+// that refuses connections is not hammered
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// The load to put on a URL: as many connections at once as given, each sending up to
+/// requests_per_connection GET requests before it is replaced by a new one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BenchRequest {
+    pub url: String,
+    pub connections: usize,
+    pub duration_seconds: u64,
+    pub requests_per_connection: u32,
+}
+
+impl BenchRequest {
+    fn check(&self) -> Result<Uri> {
+        let uri: Uri = self.url.parse()?;
+        if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+            return Err(anyhow!("{} is not an http or https URL", self.url));
+        }
+        if !(1..=MAX_CONNECTIONS).contains(&self.connections) {
+            return Err(anyhow!("connections must be 1 to {MAX_CONNECTIONS}"));
+        }
+        if !(1..=MAX_DURATION_SECONDS).contains(&self.duration_seconds) {
+            return Err(anyhow!(
+                "the duration must be 1 to {MAX_DURATION_SECONDS} seconds"
+            ));
+        }
+        if self.requests_per_connection == 0 {
+            return Err(anyhow!("each connection needs to send a request"));
+        }
+
+        Ok(uri)
+    }
+}
+
+/// Latencies in milliseconds, of the requests that completed in time
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Latency {
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl Latency {
+    fn of(mut samples: Vec<Duration>) -> Self {
+        samples.sort_unstable();
+        let ms = |p: f64| percentile(&samples, p).as_secs_f64() * 1000.0;
+
+        Self {
+            p50_ms: ms(0.5),
+            p90_ms: ms(0.9),
+            p99_ms: ms(0.99),
+            max_ms: ms(1.0),
+        }
+    }
+}
+
+// The nearest rank percentile of sorted samples
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+
+    let rank = ((p * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1]
+}
+
+/// How the URL held up. Failed connections and requests, and responses other than
+/// 2xx, count as errors.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub url: String,
+    pub duration_seconds: f64,
+    pub connections_opened: u64,
+    pub requests: u64,
+    pub errors: u64,
+    pub received_bytes: u64,
+    pub connections_per_second: f64,
+    pub requests_per_second: f64,
+    pub received_bytes_per_second: f64,
+    pub connect_latency: Latency,
+    pub request_latency: Latency,
+}
+
+/// What odyn answers a bench request with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum BenchReply {
+    Done { report: Report },
+    Failed { error: String },
+}
+
+#[derive(Default)]
+struct Stats {
+    connections: u64,
+    requests: u64,
+    errors: u64,
+    received_bytes: u64,
+    connect: Vec<Duration>,
+    latency: Vec<Duration>,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.connections += other.connections;
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.received_bytes += other.received_bytes;
+        self.connect.extend(other.connect);
+        self.latency.extend(other.latency);
+    }
+
+    fn report(self, url: &str, elapsed: Duration) -> Report {
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+
+        Report {
+            url: url.to_string(),
+            duration_seconds: seconds,
+            connections_opened: self.connections,
+            requests: self.requests,
+            errors: self.errors,
+            received_bytes: self.received_bytes,
+            connections_per_second: self.connections as f64 / seconds,
+            requests_per_second: self.requests as f64 / seconds,
+            received_bytes_per_second: self.received_bytes as f64 / seconds,
+            connect_latency: Latency::of(self.connect),
+            request_latency: Latency::of(self.latency),
+        }
+    }
+}
+
+// Where the load goes: straight to the URL, or to an HTTP proxy that is sent the URL
+// in absolute form
+struct Target {
+    uri: Uri,
+    addr: String,
+    proxied: bool,
+    tls: Option<(TlsConnector, ServerName)>,
+}
+
+impl Target {
+    fn new(uri: Uri, proxy: Option<SocketAddr>) -> Result<Self> {
+        let host = uri.host().unwrap_or_default().to_string();
+        let https = uri.scheme_str() == Some("https");
+        if https && proxy.is_some() {
+            return Err(anyhow!(
+                "only http URLs are benchmarked through the egress proxy"
+            ));
+        }
+
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let tls = if https {
+            let name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']'))?;
+            Some((TlsConnector::from(any_certificate_config()), name))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            addr: proxy.map_or(format!("{host}:{port}"), |proxy| proxy.to_string()),
+            proxied: proxy.is_some(),
+            uri,
+            tls,
+        })
+    }
+
+    async fn connect(&self) -> Result<SendRequest<Body>> {
+        let tcp = TcpStream::connect(&self.addr).await?;
+        tcp.set_nodelay(true)?;
+
+        match self.tls {
+            Some((ref connector, ref name)) => {
+                handshake(connector.connect(name.clone(), tcp).await?).await
+            }
+            None => handshake(tcp).await,
+        }
+    }
+
+    // Sends a request and reads the whole response, returning its status and size
+    async fn send(&self, sender: &mut SendRequest<Body>) -> Result<(StatusCode, u64)> {
+        let uri = if self.proxied {
+            self.uri.clone()
+        } else {
+            let path = self.uri.path_and_query().map_or("/", |path| path.as_str());
+            path.parse()?
+        };
+        let authority = self
+            .uri
+            .authority()
+            .map_or("", |authority| authority.as_str());
+        let req = Request::get(uri)
+            .header(header::HOST, authority)
+            .body(Body::empty())?;
+
+        futures::future::poll_fn(|cx| sender.poll_ready(cx)).await?;
+        let mut body = sender.send_request(req).await?;
+        let status = body.status();
+
+        let mut received = 0;
+        while let Some(chunk) = body.data().await {
+            received += chunk?.len() as u64;
+        }
+
+        Ok((status, received))
+    }
+}
+
+async fn handshake<S>(stream: S) -> Result<SendRequest<Body>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, conn) = hyper::client::conn::handshake(stream).await?;
+    tokio::task::spawn(async move {
+        _ = conn.await;
+    });
+
+    Ok(sender)
+}
+
+// One of the connections at once, replaced by a new one once it has sent its requests
+// or failed, until the deadline
+async fn worker(target: Arc<Target>, requests_per_connection: u32, deadline: Instant) -> Stats {
+    let deadline = tokio::time::Instant::from_std(deadline);
+    let mut stats = Stats::default();
+
+    while tokio::time::Instant::now() < deadline {
+        let started = Instant::now();
+        let mut sender = match tokio::time::timeout_at(deadline, target.connect()).await {
+            Ok(Ok(sender)) => sender,
+            Ok(Err(_)) => {
+                stats.errors += 1;
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+            Err(_) => break,
+        };
+        stats.connections += 1;
+        stats.connect.push(started.elapsed());
+
+        for _ in 0..requests_per_connection {
+            let started = Instant::now();
+            match tokio::time::timeout_at(deadline, target.send(&mut sender)).await {
+                Ok(Ok((status, received))) => {
+                    stats.requests += 1;
+                    stats.received_bytes += received;
+                    stats.latency.push(started.elapsed());
+                    if !status.is_success() {
+                        stats.errors += 1;
+                    }
+                }
+                Ok(Err(_)) => {
+                    stats.errors += 1;
+                    break;
+                }
+                Err(_) => return stats,
+            }
+        }
+    }
+
+    stats
+}
+
+/// Puts the load of request on its URL, through the HTTP proxy if given, and reports
+/// how it held up
+pub async fn run(request: &BenchRequest, proxy: Option<SocketAddr>) -> Result<Report> {
+    let target = Arc::new(Target::new(request.check()?, proxy)?);
+
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(request.duration_seconds);
+    let workers: Vec<_> = (0..request.connections)
+        .map(|_| {
+            let target = target.clone();
+            tokio::task::spawn(worker(target, request.requests_per_connection, deadline))
+        })
+        .collect();
+
+    let mut stats = Stats::default();
+    for worker in workers {
+        stats.merge(worker.await?);
+    }
+
+    Ok(stats.report(&request.url, started.elapsed()))
+}
+
+/// Asks odyn of the debug enclave with the given CID to put the load of request on its
+/// URL, through its egress proxy (host side)
+pub async fn egress(cid: u32, request: &BenchRequest) -> Result<Report> {
+    request.check()?;
+
+    let mut conn = VsockStream::connect(cid, DEBUG_BENCH_PORT)
+        .await
+        .map_err(|e| {
+            anyhow!("failed to connect to enclave {cid}, only debug enclaves run benchmarks: {e}")
+        })?;
+
+    conn.write_all(&serde_json::to_vec(request)?).await?;
+    AsyncWriteExt::shutdown(&mut conn).await?;
+
+    match read_message(&mut conn).await? {
+        BenchReply::Done { report } => Ok(report),
+        BenchReply::Failed { error } => Err(anyhow!("the enclave failed the benchmark: {error}")),
+    }
+}
+
+/// Runs the benchmark a connection asks for through the egress proxy listening on
+/// proxy, and answers with its report (enclave side)
+pub async fn reply<S>(conn: &mut S, proxy: SocketAddr) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request: BenchRequest = read_message(conn).await?;
+    let reply = match run(&request, Some(proxy)).await {
+        Ok(report) => BenchReply::Done { report },
+        Err(err) => BenchReply::Failed {
+            error: err.to_string(),
+        },
+    };
+
+    conn.write_all(&serde_json::to_vec(&reply)?).await?;
+    conn.shutdown().await?;
+    Ok(())
+}
+
+// Reads a JSON message, up to the point the peer shuts down its side
+async fn read_message<S, T>(conn: &mut S) -> Result<T>
+where
+    S: AsyncRead + Unpin,
+    T: for<'de> Deserialize<'de>,
+{
+    let mut buf = Vec::new();
+    conn.take(MAX_REPLY_SIZE + 1).read_to_end(&mut buf).await?;
+    if buf.len() as u64 > MAX_REPLY_SIZE {
+        return Err(anyhow!("bench message exceeds {MAX_REPLY_SIZE} bytes"));
+    }
+
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+// Ingress ports are benchmarked for their speed, whoever their certificate is from
+struct AnyCertificate;
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn any_certificate_config() -> Arc<ClientConfig> {
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(AnyCertificate));
+
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{percentile, run, BenchReply, BenchRequest};
+    use assert2::assert;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    fn request(url: &str) -> BenchRequest {
+        BenchRequest {
+            url: url.to_string(),
+            connections: 2,
+            duration_seconds: 1,
+            requests_per_connection: 5,
+        }
+    }
+
+    #[test]
+    fn test_percentile() {
+        let samples: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert!(percentile(&samples, 0.5) == Duration::from_millis(50));
+        assert!(percentile(&samples, 0.99) == Duration::from_millis(99));
+        assert!(percentile(&samples, 1.0) == Duration::from_millis(100));
+        assert!(percentile(&samples[..1], 0.5) == Duration::from_millis(1));
+        assert!(percentile(&[], 0.5) == Duration::ZERO);
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(request("http://127.0.0.1:8080/").check().is_ok());
+        assert!(request("https://api.example.com").check().is_ok());
+
+        assert!(request("ftp://example.com/").check().is_err());
+        assert!(request("/path").check().is_err());
+        let no_connections = BenchRequest {
+            connections: 0,
+            ..request("http://127.0.0.1/")
+        };
+        assert!(no_connections.check().is_err());
+        let no_duration = BenchRequest {
+            duration_seconds: 0,
+            ..request("http://127.0.0.1/")
+        };
+        assert!(no_duration.check().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: hyper::Request<Body>| async move {
+                // Only proxies are sent URLs in absolute form
+                let status = match req.uri().host() {
+                    None => StatusCode::OK,
+                    Some(_) => StatusCode::NOT_FOUND,
+                };
+                let mut resp = Response::new(Body::from("x".repeat(100)));
+                *resp.status_mut() = status;
+                Ok::<_, Infallible>(resp)
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let report = run(&request(&format!("http://{addr}/")), None)
+            .await
+            .unwrap();
+        assert!(report.requests > 0);
+        assert!(report.errors == 0);
+        assert!(report.received_bytes == report.requests * 100);
+        assert!(report.connections_opened * 5 >= report.requests);
+        assert!(report.request_latency.p99_ms <= report.request_latency.max_ms);
+
+        // As a proxy, the server is sent the URL in absolute form, and answers 404
+        let report = run(&request("http://example.com/"), Some(addr))
+            .await
+            .unwrap();
+        assert!(report.requests > 0);
+        assert!(report.errors == report.requests);
+        assert!(run(&request("https://example.com/"), Some(addr))
+            .await
+            .is_err());
+
+        let reply = BenchReply::Failed {
+            error: "no egress".to_string(),
+        };
+        let json = serde_json::to_string(&reply).unwrap();
+        assert!(json == r#"{"result":"failed","error":"no egress"}"#);
+    }
+}
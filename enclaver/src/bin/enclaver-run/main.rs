@@ -1,22 +1,35 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use enclaver::constants::{EIF_FILE_NAME, MANIFEST_FILE_NAME, RELEASE_BUNDLE_DIR};
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use enclaver::boot_config::{DebugOverrides, RuntimeConfigDocument};
+use enclaver::bundle::Bundle;
+use enclaver::compression::Compression;
+use enclaver::constants::{MANIFEST_FILE_NAME, RELEASE_BUNDLE_DIR};
+use enclaver::cpu_topology::{self, ProxyPinning};
+use enclaver::eif_chunks;
+use enclaver::events::EventOutput;
 use enclaver::manifest::load_manifest_raw;
 use enclaver::nitro_cli::NitroCLI;
-use enclaver::run::{Enclave, EnclaveExitStatus, EnclaveOpts};
-use enclaver::utils;
+use enclaver::proxy::budget::BudgetConfig;
+use enclaver::resolver::{parse_server, ResolverConfig};
+use enclaver::run::{
+    AttestedConfigOpts, Enclave, EnclaveExitStatus, EnclaveOpts, ExitCodeMapping,
+    TERMINATE_HELPER_COMMAND,
+};
+use enclaver::utils::{self, LogRendering, LOG_LINE_MAX_LEN};
+use http::Uri;
 use log::info;
 use std::{
-    path::PathBuf,
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    os::fd::RawFd,
+    path::{Path, PathBuf},
     process::{ExitCode, Termination},
+    time::Duration,
 };
-use tokio::io::{stdout, AsyncWriteExt};
+use tempfile::TempDir;
+use tokio::io::{stdout, AsyncReadExt, AsyncWriteExt};
 use tokio_util::sync::CancellationToken;
 
-const ENCLAVE_SIGNALED_EXIT_CODE: u8 = 107;
-const ENCLAVE_FATAL: u8 = 108;
-const ENCLAVER_INTERRUPTED: u8 = 109;
-
 #[derive(Debug, Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
@@ -26,15 +39,178 @@ struct Cli {
     #[clap(long, value_parser)]
     manifest_file: Option<PathBuf>,
 
+    /// Release bundle exported by `enclaver image export` to run, instead of --eif-file
+    /// and --manifest-file. It is unpacked into a temporary directory, under $TMPDIR
+    /// if set, and checked against its checksums and measurements first.
+    #[clap(long, value_parser, conflicts_with_all = ["eif_file", "manifest_file"])]
+    bundle: Option<PathBuf>,
+
     #[clap(long)]
     cpu_count: Option<i32>,
 
+    /// Memory of the app in MiB. The enclave is given the supervisor_memory_mb of the
+    /// manifest on top of it.
     #[clap(long)]
     memory_mb: Option<i32>,
 
+    /// Most connections the enclave may have open, across all ingress ports
+    #[clap(long)]
+    ingress_max_connections: Option<u32>,
+
+    /// Most connections the enclave may accept per second, across all ingress ports
+    #[clap(long)]
+    ingress_accepts_per_second: Option<u32>,
+
+    /// Host address to listen on for all ingress ports, over the bind_addr of each in
+    /// the manifest, e.g. 0.0.0.0 inside a container whose ports are published
+    #[clap(long, value_parser)]
+    ingress_bind_addr: Option<IpAddr>,
+
+    /// Once asked to stop, wait up to this many seconds for the open ingress
+    /// connections to close before terminating the enclave, over defaults.drain_seconds
+    /// of the manifest. 0 drops them right away.
+    #[clap(long, value_name = "SECONDS")]
+    drain_seconds: Option<u64>,
+
+    /// Once the enclave has started, pin the threads of the host proxies to the online
+    /// CPUs that share no core with it, on its NUMA node if any are
+    #[clap(long, conflicts_with = "proxy_cpus")]
+    pin_proxies: bool,
+
+    /// Once the enclave has started, pin the threads of the host proxies to these CPUs,
+    /// e.g. 0-1,8-9
+    #[clap(long, value_name = "LIST", value_parser = parse_proxy_cpus)]
+    proxy_cpus: Option<ProxyPinning>,
+
     #[clap(long)]
     debug_mode: bool,
 
+    /// Additionally allow egress to this host or pattern, e.g. '*'. Only available with
+    /// --debug-mode.
+    #[clap(long, requires = "debug_mode")]
+    allow_egress: Vec<String>,
+
+    /// Additionally publish this enclave port. Only available with --debug-mode.
+    #[clap(long, requires = "debug_mode")]
+    publish_extra: Vec<u16>,
+
+    /// Serve host level Prometheus metrics, the measured identity of the enclave at
+    /// /v1/identity, and its last reported status at /v1/status, on this address, e.g.
+    /// 0.0.0.0:9100
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// POST enclave lifecycle events as JSON to this URL. May be given multiple times.
+    #[clap(long = "webhook-url")]
+    webhooks: Vec<Uri>,
+
+    /// Emit newline delimited enclave lifecycle events in the given format
+    #[clap(long, value_enum)]
+    events: Option<EventsFormat>,
+
+    /// Write events to this file descriptor instead of stdout
+    #[clap(long, requires = "events")]
+    events_fd: Option<RawFd>,
+
+    /// Print the nitro-cli invocation and proxy plan, check the host, and exit without
+    /// starting anything
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Runtime config document to hand to the application inside the enclave
+    #[clap(long, value_parser)]
+    runtime_config: Option<PathBuf>,
+
+    /// Signature over the runtime config document (RSA PKCS#1 v1.5, SHA-256)
+    #[clap(long, value_parser, requires = "runtime_config")]
+    runtime_config_signature: Option<PathBuf>,
+
+    /// Config to release to the enclave only after it attests to the PCRs of the EIF
+    #[clap(long, value_parser, requires = "attestation_root_cert")]
+    attested_config: Option<PathBuf>,
+
+    /// Sealed file backing a `file` secret in the manifest, as NAME=PATH. Only released
+    /// to the enclave after it attests to the PCRs of the EIF. May be given multiple times.
+    #[clap(long, value_parser = parse_secret_file, requires = "attestation_root_cert")]
+    secret_file: Vec<(String, PathBuf)>,
+
+    /// PEM encoded root certificate of the attestation chain, i.e. the AWS Nitro
+    /// Enclaves root certificate
+    #[clap(long, value_parser)]
+    attestation_root_cert: Option<PathBuf>,
+
+    /// DNS server used by the egress proxy to resolve hosts, and to answer the enclave's
+    /// queries if the manifest sets egress.dns, instead of the ones in /etc/resolv.conf, e.g. 10.0.0.2 or 10.0.0.2:5353. May be given multiple times.
+    #[clap(long, value_parser = parse_server)]
+    dns_server: Vec<SocketAddr>,
+
+    /// Domain appended by the egress proxy to single label hosts. May be given multiple
+    /// times.
+    #[clap(long)]
+    dns_search: Vec<String>,
+
+    /// DNS-over-HTTPS endpoint used by the egress proxy to resolve hosts, and to answer
+    /// the enclave's queries if the manifest sets egress.dns, e.g. https://1.1.1.1/dns-query
+    #[clap(long, conflicts_with = "dns_server")]
+    dns_over_https: Option<Uri>,
+
+    /// Network namespace the egress proxy connects out from, e.g. /var/run/netns/enclaver.
+    /// Set it up with only the routes the enclave is allowed to reach.
+    #[clap(long, value_parser)]
+    egress_netns: Option<PathBuf>,
+
+    /// Network namespace the standby egress relays of the manifest connect out from, e.g.
+    /// one routed through another ENI, in the order of egress.relays after the first.
+    /// Relays without one use --egress-netns. May be given multiple times.
+    #[clap(long, value_parser)]
+    egress_relay_netns: Vec<PathBuf>,
+
+    /// Once the enclave and proxies are started, switch to this unprivileged user and
+    /// deny the syscalls the supervisor no longer needs
+    #[clap(long)]
+    user: Option<String>,
+
+    /// Directory to keep a journal of the enclave's status transitions in, read by
+    /// `enclaver ps`
+    #[clap(long, value_parser)]
+    state_dir: Option<PathBuf>,
+
+    /// File to append the egress audit log of the enclave to, one JSON event per
+    /// connection or request its egress proxy allowed or denied
+    #[clap(long, value_parser)]
+    egress_audit_log: Option<PathBuf>,
+
+    /// Exit with the application's own status: its exit code, or 128+N if it was
+    /// killed by signal N, instead of the signaled exit code from the manifest
+    #[clap(long)]
+    passthrough_exit_code: bool,
+
+    /// If other enclaves hold the CPUs or memory this one needs, keep retrying for up
+    /// to this many seconds instead of failing right away
+    #[clap(long, value_name = "SECONDS")]
+    wait_for_capacity: Option<u64>,
+
+    /// Longest line of enclave or debug console output logged as one record, longer
+    /// lines are logged in pieces
+    #[clap(
+        long,
+        value_name = "BYTES",
+        value_parser = parse_line_len,
+        default_value_t = LOG_LINE_MAX_LEN
+    )]
+    log_line_max_bytes: usize,
+
+    /// Write the output of the enclave and its debug console to stdout byte for byte,
+    /// instead of logging it with control characters and invalid UTF-8 escaped
+    #[clap(long)]
+    log_raw: bool,
+
+    /// Ask the enclave to compress its log and egress audit log streams, with none,
+    /// lz4, zstd or zstd:<level>. lz4 costs the enclave the least CPU, higher zstd
+    /// levels save more vsock bandwidth.
+    #[clap(long, value_name = "CODEC", default_value_t = Compression::None)]
+    log_compression: Compression,
+
     #[clap(subcommand)]
     sub_command: Option<SubCommand>,
 
@@ -42,6 +218,11 @@ struct Cli {
     verbosity: u8,
 }
 
+#[derive(Debug, Clone, ValueEnum)]
+enum EventsFormat {
+    Json,
+}
+
 #[derive(Debug, Subcommand)]
 enum SubCommand {
     #[clap(name = "print-manifest")]
@@ -49,24 +230,25 @@ enum SubCommand {
 
     #[clap(name = "describe-eif")]
     DescribeEif,
+
+    /// Terminates the enclave once stdin is closed. Started by the supervisor before
+    /// it drops privileges with --user.
+    #[clap(name = TERMINATE_HELPER_COMMAND, hide = true)]
+    TerminateHelper { enclave_id: String },
 }
 
 enum CLISuccess {
-    EnclaveStatus(EnclaveExitStatus),
+    EnclaveStatus(EnclaveExitStatus, ExitCodeMapping),
     Ok,
 }
 
 impl Termination for CLISuccess {
     fn report(self) -> ExitCode {
-        use CLISuccess::*;
-        use EnclaveExitStatus::*;
-
         match self {
-            EnclaveStatus(Exited(code)) => ExitCode::from(code as u8),
-            EnclaveStatus(Signaled(_signal)) => ExitCode::from(ENCLAVE_SIGNALED_EXIT_CODE),
-            EnclaveStatus(Fatal(_err)) => ExitCode::from(ENCLAVE_FATAL),
-            EnclaveStatus(Cancelled) => ExitCode::from(ENCLAVER_INTERRUPTED),
-            Ok => ExitCode::SUCCESS,
+            CLISuccess::EnclaveStatus(status, exit_codes) => {
+                ExitCode::from(exit_codes.exit_code(&status))
+            }
+            CLISuccess::Ok => ExitCode::SUCCESS,
         }
     }
 }
@@ -74,15 +256,124 @@ impl Termination for CLISuccess {
 async fn run(args: Cli) -> Result<CLISuccess> {
     let shutdown_signal = enclaver::utils::register_shutdown_signal_handler().await?;
 
+    let event_output = match (args.events, args.events_fd) {
+        (Some(EventsFormat::Json), Some(fd)) => Some(EventOutput::Fd(fd)),
+        (Some(EventsFormat::Json), None) => Some(EventOutput::Stdout),
+        (None, _) => None,
+    };
+    if args.log_raw && matches!(event_output, Some(EventOutput::Stdout)) {
+        return Err(anyhow!(
+            "--log-raw and --events both write to stdout, pass --events-fd as well"
+        ));
+    }
+
+    let runtime_config = match args.runtime_config {
+        Some(ref path) => {
+            let document = tokio::fs::read(path).await?;
+            let signature = match args.runtime_config_signature {
+                Some(ref path) => Some(tokio::fs::read(path).await?),
+                None => None,
+            };
+            Some(RuntimeConfigDocument::new(&document, signature.as_deref()))
+        }
+        None => None,
+    };
+
+    let attested_config = match args.attestation_root_cert {
+        Some(ref root_cert) => {
+            if args.attested_config.is_none() && args.secret_file.is_empty() {
+                return Err(anyhow!(
+                    "--attestation-root-cert requires --attested-config or --secret-file"
+                ));
+            }
+
+            let config = match args.attested_config {
+                Some(ref path) => Some(tokio::fs::read(path).await?),
+                None => None,
+            };
+
+            let mut secret_files = BTreeMap::new();
+            for (name, path) in &args.secret_file {
+                secret_files.insert(name.clone(), tokio::fs::read(path).await?);
+            }
+
+            Some(AttestedConfigOpts {
+                config,
+                secret_files,
+                root_cert_pem: tokio::fs::read(root_cert).await?,
+            })
+        }
+        None => None,
+    };
+
+    // The unpacked bundle is kept until the enclave exits
+    let (eif_path, manifest_path, _bundle_dir) = match args.bundle {
+        Some(ref path) => {
+            let dir = TempDir::new()?;
+            let bundle = Bundle::unpack(path, dir.path()).await?;
+            let eif_info = bundle.verify_measurements().await?;
+            info!(
+                "unpacked bundle {}, PCR0 {}",
+                path.display(),
+                eif_info.measurements().identity().pcr0
+            );
+            (
+                Some(bundle.eif_path()),
+                Some(bundle.manifest_path()),
+                Some(dir),
+            )
+        }
+        None => (args.eif_file, args.manifest_file, None),
+    };
+
     let enclave = Enclave::new(EnclaveOpts {
-        eif_path: args.eif_file,
-        manifest_path: args.manifest_file,
+        eif_path,
+        manifest_path,
         cpu_count: args.cpu_count,
         memory_mb: args.memory_mb,
+        ingress_budget: BudgetConfig {
+            max_connections: args.ingress_max_connections,
+            accepts_per_second: args.ingress_accepts_per_second,
+        },
         debug_mode: args.debug_mode,
+        metrics_addr: args.metrics_addr,
+        webhooks: args.webhooks,
+        event_output,
+        debug_overrides: DebugOverrides {
+            allow_egress: args.allow_egress,
+            extra_ingress: args.publish_extra,
+        },
+        runtime_config,
+        attested_config,
+        resolver: ResolverConfig {
+            servers: args.dns_server,
+            search: args.dns_search,
+            doh: args.dns_over_https,
+        },
+        egress_netns: args.egress_netns,
+        egress_relay_netns: args.egress_relay_netns,
+        run_as: args.user,
+        state_dir: args.state_dir,
+        egress_audit_log: args.egress_audit_log,
+        passthrough_exit_code: args.passthrough_exit_code,
+        wait_for_capacity: args.wait_for_capacity.map(Duration::from_secs),
+        ingress_bind_addr: args.ingress_bind_addr,
+        log_rendering: LogRendering {
+            max_line_len: args.log_line_max_bytes,
+            raw: args.log_raw,
+        },
+        log_compression: args.log_compression,
+        drain_seconds: args.drain_seconds,
+        proxy_pinning: args
+            .proxy_cpus
+            .or(args.pin_proxies.then_some(ProxyPinning::Auto)),
     })
     .await?;
 
+    if args.dry_run {
+        return dry_run(&enclave);
+    }
+
     let cancellation = CancellationToken::new();
 
     // Wait for the shutdown signal in a separate task. If the signal comes, cancel the
@@ -96,12 +387,50 @@ async fn run(args: Cli) -> Result<CLISuccess> {
         })?
     };
 
+    let exit_codes = enclave.exit_codes();
     let status = enclave.run(cancellation).await?;
 
     cancel_task.abort();
     _ = cancel_task.await;
 
-    Ok(CLISuccess::EnclaveStatus(status))
+    Ok(CLISuccess::EnclaveStatus(status, exit_codes))
+}
+
+fn parse_secret_file(spec: &str) -> Result<(String, PathBuf)> {
+    match spec.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_string(), PathBuf::from(path)))
+        }
+        _ => Err(anyhow!("expected NAME=PATH, got {spec}")),
+    }
+}
+
+fn parse_line_len(len: &str) -> Result<usize> {
+    match len.parse()? {
+        0 => Err(anyhow!("must be at least 1 byte")),
+        len => Ok(len),
+    }
+}
+
+fn parse_proxy_cpus(list: &str) -> Result<ProxyPinning> {
+    match cpu_topology::parse_cpu_list(list)? {
+        cpus if cpus.is_empty() => Err(anyhow!("must list at least 1 CPU")),
+        cpus => Ok(ProxyPinning::Cpus(cpus)),
+    }
+}
+
+fn dry_run(enclave: &Enclave) -> Result<CLISuccess> {
+    print!("{}", enclave.plan()?);
+
+    let checks = enclave.preflight();
+    for check in &checks {
+        println!("{check}");
+    }
+
+    match checks.iter().any(|c| c.failed()) {
+        true => Err(anyhow!("host checks failed")),
+        false => Ok(CLISuccess::Ok),
+    }
 }
 
 async fn dump_manifest() -> Result<CLISuccess> {
@@ -113,7 +442,7 @@ async fn dump_manifest() -> Result<CLISuccess> {
 }
 
 async fn describe_eif() -> Result<CLISuccess> {
-    let eif_path = PathBuf::from(RELEASE_BUNDLE_DIR).join(EIF_FILE_NAME);
+    let eif_path = eif_chunks::release_eif(Path::new(RELEASE_BUNDLE_DIR)).await?;
     let cli = NitroCLI::new();
     let eif_info = cli.describe_eif(&eif_path).await?;
     let eif_info_bytes = serde_json::to_vec_pretty(&eif_info)?;
@@ -122,6 +451,17 @@ async fn describe_eif() -> Result<CLISuccess> {
     Ok(CLISuccess::Ok)
 }
 
+async fn terminate_helper(enclave_id: &str) -> Result<CLISuccess> {
+    // Returns on EOF, including when the supervisor dies without closing it cleanly
+    let mut discard = Vec::new();
+    _ = tokio::io::stdin().read_to_end(&mut discard).await;
+
+    info!("terminating enclave {enclave_id}");
+    NitroCLI::new().terminate_enclave(enclave_id).await?;
+
+    Ok(CLISuccess::Ok)
+}
+
 #[tokio::main]
 async fn main() -> Result<CLISuccess> {
     let args = Cli::parse();
@@ -137,5 +477,6 @@ async fn main() -> Result<CLISuccess> {
         None => run(args).await,
         Some(SubCommand::PrintManifest) => dump_manifest().await,
         Some(SubCommand::DescribeEif) => describe_eif().await,
+        Some(SubCommand::TerminateHelper { enclave_id }) => terminate_helper(&enclave_id).await,
     }
 }
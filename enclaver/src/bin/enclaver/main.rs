@@ -1,10 +1,23 @@
 use anyhow::{anyhow, Result};
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use enclaver::{
-    build::EnclaveArtifactBuilder, constants::MANIFEST_FILE_NAME, manifest::load_manifest,
-    run_container::RunWrapper,
+    bench::{self, BenchRequest, Report},
+    build::EnclaveArtifactBuilder,
+    compose::Compose,
+    connections,
+    constants::{COMPOSE_FILE_NAME, MANIFEST_FILE_NAME},
+    iac, identity,
+    journal::{self, JournalEntry, DEFAULT_STATE_DIR},
+    kms_policy,
+    manifest::{load_manifest, Manifest},
+    policy_update::{self, SignedPolicyUpdate},
+    run_container::{Confinement, LogDriver, RunWrapper},
+    tls_issuance,
 };
-use log::{debug, error};
+use log::{debug, error, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::io::{stdout, AsyncWriteExt};
 
 #[derive(Debug, Parser)]
@@ -34,6 +47,16 @@ enum Commands {
         #[clap(long = "pull")]
         /// Pull every container image to ensure the latest version
         force_pull: bool,
+
+        #[clap(long, value_enum, conflicts_with = "eif_file")]
+        /// Also write infrastructure as code to deploy the image on EC2: a launch template
+        /// whose user data runs it, and a KMS key policy on the PCRs of the EIF.
+        emit: Option<EmitArg>,
+
+        #[clap(long, requires = "emit")]
+        /// File to write the infrastructure as code to. Defaults to enclaver.tf for
+        /// terraform and enclaver.cloudformation.yaml for cloudformation.
+        emit_file: Option<PathBuf>,
     },
 
     #[clap(name = "run")]
@@ -63,15 +86,403 @@ enum Commands {
         image_name: Option<String>,
 
         #[clap(short = 'p', long = "publish")]
-        /// Port to expose on the host machine, for example: 8080:80.
+        /// Port to expose on the host machine, for example: 8080:80, or 127.0.0.1:8080:80
+        /// to only expose it on one host address.
         port_forwards: Vec<String>,
 
         #[clap(short, long)]
         /// Run the enclave supervisor in debug mode
         debug_mode: bool,
+
+        #[clap(long = "log-driver", value_enum, default_value_t = LogDriverArg::Stdio)]
+        /// Where to send the output of the enclave.
+        log_driver: LogDriverArg,
+
+        #[clap(long = "log-file", required_if_eq("log_driver", "file"))]
+        /// File to append the output of the enclave to, used with --log-driver=file.
+        log_file: Option<PathBuf>,
+
+        #[clap(long)]
+        /// Check the image and host, print what would be run, and exit without starting
+        /// the enclave.
+        dry_run: bool,
+
+        #[clap(long)]
+        /// Run the wrapper container unprivileged, with all capabilities but
+        /// NET_BIND_SERVICE dropped and the bundled seccomp profile applied.
+        confine: bool,
+
+        #[clap(long, requires = "confine")]
+        /// SELinux type to run the confined wrapper container as, e.g. enclaver.process
+        /// from the bundled policy.
+        selinux_type: Option<String>,
+
+        #[clap(long, default_value = DEFAULT_STATE_DIR)]
+        /// Host directory to keep the status journal of the enclave in.
+        state_dir: PathBuf,
+
+        #[clap(long)]
+        /// Do not keep a status journal for the enclave.
+        no_journal: bool,
+
+        #[clap(long, value_name = "SECONDS")]
+        /// If other enclaves hold the CPUs or memory this one needs, keep retrying for
+        /// up to this many seconds instead of failing right away.
+        wait_for_capacity: Option<u64>,
+    },
+
+    #[clap(name = "compose")]
+    /// Run several enclaves, and the host services they use, together.
+    Compose {
+        #[clap(subcommand)]
+        command: ComposeCommands,
+    },
+
+    #[clap(name = "ps")]
+    /// List the enclaves run on this host and their latest status.
+    ///
+    /// Status transitions are recorded by `enclaver run`, so they can be looked at
+    /// after the enclave has exited.
+    Ps {
+        #[clap(long, value_name = "NAME")]
+        /// Show every recorded status transition of the named enclave.
+        history: Option<String>,
+
+        #[clap(long, default_value = DEFAULT_STATE_DIR)]
+        /// Directory the status journals are kept in.
+        state_dir: PathBuf,
+
+        #[clap(long)]
+        /// Print JSON, including the measured identity of each enclave.
+        json: bool,
+    },
+
+    #[clap(name = "image")]
+    /// Work with Enclaver release images.
+    Image {
+        #[clap(subcommand)]
+        command: ImageCommands,
+    },
+
+    #[clap(name = "kms-policy")]
+    /// Work with KMS key policies for Enclaver images.
+    KmsPolicy {
+        #[clap(subcommand)]
+        command: KmsPolicyCommands,
+    },
+
+    #[clap(name = "policy")]
+    /// Work with the egress policy of running enclaves.
+    Policy {
+        #[clap(subcommand)]
+        command: PolicyCommands,
+    },
+
+    #[clap(name = "tls")]
+    /// Get the ingress certificate of a running enclave issued by a CA.
+    Tls {
+        #[clap(subcommand)]
+        command: TlsCommands,
+    },
+
+    #[clap(name = "debug")]
+    /// Inspect running debug enclaves.
+    Debug {
+        #[clap(subcommand)]
+        command: DebugCommands,
+    },
+
+    #[clap(name = "bench")]
+    /// Measure how much load a running enclave takes through its ingress or egress.
+    Bench {
+        #[clap(subcommand)]
+        command: BenchCommands,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ComposeCommands {
+    #[clap(name = "up")]
+    /// Start everything in a compose file, and stop it all again once one of them
+    /// exits or on Ctrl-C.
+    ///
+    /// Host services are started first, then the enclaves, each after what it
+    /// depends on or links to. An enclave counts as started once its application is
+    /// running. Every container joins a docker network named after the project, where
+    /// the others reach it by its name.
+    Up {
+        #[clap(long = "file", short = 'f', default_value = COMPOSE_FILE_NAME)]
+        /// Path to the compose file.
+        compose_file: PathBuf,
+
+        #[clap(long, default_value = DEFAULT_STATE_DIR)]
+        /// Host directory to keep the status journals of the enclaves in.
+        state_dir: PathBuf,
+
+        #[clap(long, value_name = "SECONDS", default_value_t = 300)]
+        /// How long to wait for each enclave to start before giving up.
+        wait_seconds: u64,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ImageCommands {
+    #[clap(name = "export")]
+    /// Export a release image as a bundle, to run without a container registry.
+    ///
+    /// The bundle is a zstd compressed tarball of the EIF, the manifest, the
+    /// measurements of the EIF and a SHA256SUMS file over them. Start it on the
+    /// enclave host with `enclaver-run --bundle`.
+    Export {
+        #[clap(index = 1, name = "image")]
+        /// Name of a release image built by `enclaver build`.
+        image_name: String,
+
+        #[clap(long, short = 'o')]
+        /// File to write the bundle to, e.g. bundle.tar.zst.
+        output: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum KmsPolicyCommands {
+    #[clap(name = "generate")]
+    /// Print the KMS key policy condition on the PCRs of an image.
+    ///
+    /// The PCRs are read from the EIF in a release image, or from an EIF file with
+    /// --eif. By default only the Condition block is printed, to paste into a key
+    /// policy statement. With --principal, a complete statement is printed instead,
+    /// ready to add to the Statement list of a policy passed to `aws kms put-key-policy`.
+    Generate {
+        #[clap(long = "file", short = 'f', conflicts_with_all = ["image", "eif"])]
+        /// Enclaver Manifest file in which to look for an image name.
+        ///
+        /// Defaults to enclaver.yaml if neither an image nor an EIF is specified.
+        manifest_file: Option<String>,
+
+        #[clap(index = 1, name = "image", conflicts_with = "eif")]
+        /// Name of a release image built by `enclaver build`.
+        image_name: Option<String>,
+
+        #[clap(long)]
+        /// Path of an EIF file, e.g. one built with `enclaver build --eif-only`.
+        eif: Option<String>,
+
+        #[clap(long)]
+        /// ARN of the IAM principal the enclave runs as, to print a complete statement.
+        principal: Option<String>,
+
+        #[clap(long = "action", requires = "principal")]
+        /// KMS action to allow in the statement. May be repeated. Defaults to kms:Decrypt
+        /// and kms:GenerateDataKey.
+        actions: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum PolicyCommands {
+    #[clap(name = "push")]
+    /// Narrow the egress policy of a running enclave without restarting it.
+    ///
+    /// The policy update is a YAML document with a serial, which must be higher than
+    /// that of the last update applied, and allow and deny lists. It must be signed
+    /// with the private key matching egress.policy_signing_key in the manifest. The
+    /// enclave only ever allows what both the manifest and the update allow.
+    Push {
+        #[clap(index = 1, name = "name")]
+        /// Name of the running enclave, as listed by `enclaver ps`.
+        name: String,
+
+        #[clap(long = "file", short = 'f')]
+        /// Policy update document.
+        file: PathBuf,
+
+        #[clap(long)]
+        /// RSA PKCS#1 v1.5 SHA-256 signature over the policy update document.
+        signature: PathBuf,
+
+        #[clap(long, default_value = DEFAULT_STATE_DIR)]
+        /// Directory the status journals are kept in.
+        state_dir: PathBuf,
+
+        #[clap(long)]
+        /// CID of the enclave. Defaults to the CID it was last started with, as
+        /// recorded in its status journal.
+        cid: Option<u32>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum TlsCommands {
+    #[clap(name = "csr")]
+    /// Print the certificate signing request for the ingress key of a running enclave.
+    ///
+    /// The key is generated inside the enclave for the ingress ports with tls.csr in
+    /// the manifest, and never leaves it. The request carries an attestation document
+    /// binding the key to the enclave, for a CA to check before issuing.
+    Csr {
+        #[clap(index = 1, name = "name")]
+        /// Name of the running enclave, as listed by `enclaver ps`.
+        name: String,
+
+        #[clap(long, default_value = DEFAULT_STATE_DIR)]
+        /// Directory the status journals are kept in.
+        state_dir: PathBuf,
+
+        #[clap(long)]
+        /// CID of the enclave. Defaults to the CID it was last started with, as
+        /// recorded in its status journal.
+        cid: Option<u32>,
+    },
+
+    #[clap(name = "install")]
+    /// Install the certificate chain a CA issued for the ingress key of a running enclave.
+    ///
+    /// The chain is a PEM file with the leaf certificate first. It is rejected unless
+    /// the leaf is for the key the enclave generated. Installing a new chain later
+    /// renews the certificate without restarting the enclave.
+    Install {
+        #[clap(index = 1, name = "name")]
+        /// Name of the running enclave, as listed by `enclaver ps`.
+        name: String,
+
+        #[clap(index = 2, name = "chain")]
+        /// PEM file of the issued certificate chain.
+        chain: PathBuf,
+
+        #[clap(long, default_value = DEFAULT_STATE_DIR)]
+        /// Directory the status journals are kept in.
+        state_dir: PathBuf,
+
+        #[clap(long)]
+        /// CID of the enclave. Defaults to the CID it was last started with, as
+        /// recorded in its status journal.
+        cid: Option<u32>,
     },
 }
 
+#[derive(Debug, Subcommand)]
+enum DebugCommands {
+    #[clap(name = "conns")]
+    /// List the ingress connections and egress tunnels a debug enclave is relaying.
+    ///
+    /// Only enclaves built with debug: true in their manifest list their connections.
+    /// Bytes are counted on the side that opened each connection, the client of an
+    /// ingress port or the application for egress.
+    Conns {
+        #[clap(index = 1, name = "name")]
+        /// Name of the running enclave, as listed by `enclaver ps`.
+        name: String,
+
+        #[clap(long, default_value = DEFAULT_STATE_DIR)]
+        /// Directory the status journals are kept in.
+        state_dir: PathBuf,
+
+        #[clap(long)]
+        /// CID of the enclave. Defaults to the CID it was last started with, as
+        /// recorded in its status journal.
+        cid: Option<u32>,
+
+        #[clap(long)]
+        /// Print JSON.
+        json: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum BenchCommands {
+    #[clap(name = "ingress")]
+    /// Send HTTP requests to an ingress port of an enclave, through enclaver-run, the
+    /// vsock and odyn to the application.
+    ///
+    /// Certificates of https ports are not verified.
+    Ingress {
+        #[clap(index = 1, name = "url")]
+        /// URL to GET, e.g. http://127.0.0.1:8080/health.
+        url: String,
+
+        #[clap(flatten)]
+        load: LoadArgs,
+
+        #[clap(long)]
+        /// Print JSON.
+        json: bool,
+    },
+
+    #[clap(name = "egress")]
+    /// Have a debug enclave send HTTP requests through its egress proxy, the vsock and
+    /// the host relay.
+    ///
+    /// Only enclaves built with debug: true in their manifest run benchmarks, and the
+    /// URL must be http and allowed by their egress policy.
+    Egress {
+        #[clap(index = 1, name = "name")]
+        /// Name of the running enclave, as listed by `enclaver ps`.
+        name: String,
+
+        #[clap(index = 2, name = "url")]
+        /// URL to GET from inside the enclave, e.g. http://10.0.1.20:8080/.
+        url: String,
+
+        #[clap(long, default_value = DEFAULT_STATE_DIR)]
+        /// Directory the status journals are kept in.
+        state_dir: PathBuf,
+
+        #[clap(long)]
+        /// CID of the enclave. Defaults to the CID it was last started with, as
+        /// recorded in its status journal.
+        cid: Option<u32>,
+
+        #[clap(flatten)]
+        load: LoadArgs,
+
+        #[clap(long)]
+        /// Print JSON.
+        json: bool,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct LoadArgs {
+    #[clap(long, short = 'c', default_value_t = 16)]
+    /// Connections open at once.
+    connections: usize,
+
+    #[clap(long, short = 'd', value_name = "SECONDS", default_value_t = 10)]
+    /// How long to keep up the load.
+    duration: u64,
+
+    #[clap(long, default_value_t = 1)]
+    /// Requests sent on each connection before it is replaced by a new one. Raise it
+    /// to measure requests rather than connections.
+    requests_per_connection: u32,
+}
+
+impl LoadArgs {
+    fn request(self, url: String) -> BenchRequest {
+        BenchRequest {
+            url,
+            connections: self.connections,
+            duration_seconds: self.duration,
+            requests_per_connection: self.requests_per_connection,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum EmitArg {
+    Terraform,
+    Cloudformation,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LogDriverArg {
+    Stdio,
+    Journald,
+    Syslog,
+    File,
+}
+
 async fn run(args: Cli) -> Result<()> {
     match args.subcommand {
         // Build an OCI image based on a manifest file.
@@ -79,16 +490,31 @@ async fn run(args: Cli) -> Result<()> {
             manifest_file,
             eif_file: None,
             force_pull,
+            emit,
+            emit_file,
         } => {
             let builder = EnclaveArtifactBuilder::new(force_pull)?;
-            let (eif_info, release_img, tag) = builder.build_release(&manifest_file).await?;
+            let (eif_info, release_img, manifest) = builder.build_release(&manifest_file).await?;
             let eif_info_bytes = serde_json::to_vec_pretty(&eif_info)?;
 
-            println!("Built Release Image: {release_img} ({tag})");
+            println!("Built Release Image: {release_img} ({})", manifest.target);
             println!("EIF Info:");
 
             stdout().write_all(&eif_info_bytes).await?;
             println!();
+            print_debug_build(&manifest);
+
+            if let Some(emit) = emit {
+                let format = match emit {
+                    EmitArg::Terraform => iac::Format::Terraform,
+                    EmitArg::Cloudformation => iac::Format::CloudFormation,
+                };
+                let path = emit_file.unwrap_or_else(|| PathBuf::from(format.default_file_name()));
+
+                let snippet = iac::render(format, &manifest, eif_info.measurements())?;
+                std::fs::write(&path, snippet)?;
+                println!("Wrote {emit:?} to {}", path.display());
+            }
 
             Ok(())
         }
@@ -98,9 +524,11 @@ async fn run(args: Cli) -> Result<()> {
             manifest_file,
             eif_file: Some(eif_file),
             force_pull,
+            ..
         } => {
             let builder = EnclaveArtifactBuilder::new(force_pull)?;
-            let (eif_info, eif_path) = builder.build_eif_only(&manifest_file, &eif_file).await?;
+            let (eif_info, eif_path, manifest) =
+                builder.build_eif_only(&manifest_file, &eif_file).await?;
             let eif_info_bytes = serde_json::to_vec_pretty(&eif_info)?;
 
             println!("Built EIF: {}", eif_path.display());
@@ -108,6 +536,7 @@ async fn run(args: Cli) -> Result<()> {
 
             stdout().write_all(&eif_info_bytes).await?;
             println!();
+            print_debug_build(&manifest);
 
             Ok(())
         }
@@ -118,10 +547,20 @@ async fn run(args: Cli) -> Result<()> {
             image_name,
             port_forwards,
             debug_mode,
+            log_driver,
+            log_file,
+            dry_run,
+            confine,
+            selinux_type,
+            state_dir,
+            no_journal,
+            wait_for_capacity,
         } => {
-            let image_name = match (manifest_file, image_name) {
+            // The host resources of the wrapper container and the host addresses of the
+            // ingress ports come from the manifest, if the image is looked up in one
+            let (image_name, host, bind_addrs) = match (manifest_file, image_name) {
                 // If an image was specified, use it
-                (None, Some(image_name)) => Ok(image_name),
+                (None, Some(image_name)) => Ok((image_name, None, HashMap::new())),
 
                 // If no image was specified, either use the specified manifest file or the default
                 // to try to look up the target image name.
@@ -129,7 +568,13 @@ async fn run(args: Cli) -> Result<()> {
                     let manifest_file =
                         manifest_file.unwrap_or_else(|| MANIFEST_FILE_NAME.to_string());
                     let manifest = load_manifest(manifest_file).await?;
-                    Ok(manifest.target)
+                    let bind_addrs = manifest
+                        .ingress
+                        .iter()
+                        .flatten()
+                        .filter_map(|item| Some((item.listen_port, item.bind_addr?)))
+                        .collect();
+                    Ok((manifest.target, manifest.host, bind_addrs))
                 }
 
                 // Specifying both is an error
@@ -138,12 +583,63 @@ async fn run(args: Cli) -> Result<()> {
                 )),
             }?;
 
-            let mut runner = RunWrapper::new()?;
+            let log_driver = match (log_driver, log_file) {
+                (LogDriverArg::Stdio, _) => LogDriver::Stdio,
+                (LogDriverArg::Journald, _) => LogDriver::Journald,
+                (LogDriverArg::Syslog, _) => LogDriver::Syslog,
+                (LogDriverArg::File, Some(path)) => LogDriver::File(path),
+                (LogDriverArg::File, None) => {
+                    return Err(anyhow!("--log-driver=file requires --log-file"))
+                }
+            };
+
+            let confinement = match confine {
+                true => Some(Confinement { selinux_type }),
+                false => None,
+            };
+
+            // The journal is best effort, e.g. the state dir may not be writable
+            let state_dir = match no_journal || dry_run {
+                true => None,
+                false => match std::fs::create_dir_all(&state_dir) {
+                    Ok(_) => Some(state_dir),
+                    Err(e) => {
+                        warn!(
+                            "not keeping a status journal in {}: {e}",
+                            state_dir.display()
+                        );
+                        None
+                    }
+                },
+            };
+
+            let mut runner = RunWrapper::new(log_driver)?
+                .with_confinement(confinement)
+                .with_state_dir(state_dir)
+                .with_wait_for_capacity(wait_for_capacity)
+                .with_host(host)
+                .with_ingress_bind_addrs(bind_addrs);
+
+            // The container is still started in a dry run, so that enclaver-run can report
+            // on the manifest baked into the image, but no ports are published.
+            let port_forwards = match dry_run {
+                true => {
+                    let checks = runner.preflight(&image_name, &port_forwards).await?;
+                    for check in &checks {
+                        println!("{check}");
+                    }
+                    if checks.iter().any(|c| c.failed()) {
+                        return Err(anyhow!("host checks failed"));
+                    }
+                    vec![]
+                }
+                false => port_forwards,
+            };
 
             let shutdown_signal = enclaver::utils::register_shutdown_signal_handler().await?;
 
             tokio::select! {
-                res = runner.run_enclaver_image(&image_name, port_forwards, debug_mode) => {
+                res = runner.run_enclaver_image(&image_name, port_forwards, debug_mode, dry_run) => {
                     debug!("enclave exited");
                     match res {
                         Ok(_) => debug!("enclave exited successfully"),
@@ -159,9 +655,364 @@ async fn run(args: Cli) -> Result<()> {
 
             Ok(())
         }
+
+        // Run the enclaves and host services of a compose file.
+        Commands::Compose {
+            command:
+                ComposeCommands::Up {
+                    compose_file,
+                    state_dir,
+                    wait_seconds,
+                },
+        } => {
+            let compose = Compose::load(&compose_file).await?;
+            std::fs::create_dir_all(&state_dir)
+                .map_err(|e| anyhow!("failed to create {}: {e}", state_dir.display()))?;
+
+            let shutdown_signal = enclaver::utils::register_shutdown_signal_handler().await?;
+            compose
+                .up(&state_dir, Duration::from_secs(wait_seconds), async {
+                    _ = shutdown_signal.await;
+                })
+                .await
+        }
+
+        // Show the status journals kept by `enclaver run`.
+        Commands::Ps {
+            history: Some(name),
+            state_dir,
+            json: true,
+        } => {
+            let history = journal::read_history(&state_dir, &name)?;
+            println!("{}", serde_json::to_string_pretty(&history)?);
+
+            Ok(())
+        }
+
+        Commands::Ps {
+            history: Some(name),
+            state_dir,
+            json: false,
+        } => {
+            for entry in journal::read_history(&state_dir, &name)? {
+                println!(
+                    "{}  {:<17} {}",
+                    journal::format_timestamp(entry.timestamp),
+                    entry.event,
+                    entry.describe_details()
+                );
+            }
+
+            Ok(())
+        }
+
+        Commands::Ps {
+            history: None,
+            state_dir,
+            json: true,
+        } => {
+            let mut enclaves = Vec::new();
+            for (name, last) in journal::list(&state_dir)? {
+                let cpus = last_started(&state_dir, &name)?
+                    .and_then(|entry| entry.details.get("cpus").cloned());
+                enclaves.push(serde_json::json!({
+                    "cpus": cpus,
+                    "identity": identity::load(&state_dir, &name)?,
+                    "name": name,
+                    "status": last,
+                }));
+            }
+            println!("{}", serde_json::to_string_pretty(&enclaves)?);
+
+            Ok(())
+        }
+
+        Commands::Ps {
+            history: None,
+            state_dir,
+            json: false,
+        } => {
+            let enclaves = journal::list(&state_dir)?;
+            println!("{:<24} {:<17} {:<20}  DETAILS", "NAME", "STATUS", "SINCE");
+            for (name, last) in enclaves {
+                match last {
+                    Some(entry) => println!(
+                        "{name:<24} {:<17} {:<20}  {}",
+                        entry.event,
+                        journal::format_timestamp(entry.timestamp),
+                        entry.describe_details()
+                    ),
+                    None => println!("{name:<24} {:<17} {:<20}", "-", "-"),
+                }
+            }
+
+            Ok(())
+        }
+
+        // Export a release image for hosts without access to a container registry.
+        Commands::Image {
+            command: ImageCommands::Export { image_name, output },
+        } => {
+            let builder = EnclaveArtifactBuilder::new(false)?;
+            let eif_info = builder.export_release(&image_name, &output).await?;
+
+            println!("{}", serde_json::to_string_pretty(&eif_info)?);
+
+            Ok(())
+        }
+
+        // Print a KMS key policy for the measurements of an image.
+        Commands::KmsPolicy {
+            command:
+                KmsPolicyCommands::Generate {
+                    manifest_file,
+                    image_name,
+                    eif,
+                    principal,
+                    actions,
+                },
+        } => {
+            let builder = EnclaveArtifactBuilder::new(false)?;
+            let eif_info = match (eif, image_name) {
+                (Some(eif), _) => builder.describe_eif(&eif).await?,
+                (None, Some(image_name)) => builder.describe_release(&image_name).await?,
+                (None, None) => {
+                    let manifest_file =
+                        manifest_file.unwrap_or_else(|| MANIFEST_FILE_NAME.to_string());
+                    let manifest = load_manifest(manifest_file).await?;
+                    builder.describe_release(&manifest.target).await?
+                }
+            };
+            let measurements = eif_info.measurements();
+
+            let policy = match principal {
+                Some(principal) => {
+                    let actions = match actions.is_empty() {
+                        true => kms_policy::DEFAULT_ACTIONS
+                            .iter()
+                            .map(|a| a.to_string())
+                            .collect(),
+                        false => actions,
+                    };
+                    kms_policy::statement(measurements, &principal, &actions)?
+                }
+                None => kms_policy::condition(measurements)?,
+            };
+            println!("{}", serde_json::to_string_pretty(&policy)?);
+
+            Ok(())
+        }
+
+        // Narrow the egress policy of a running enclave.
+        Commands::Policy {
+            command:
+                PolicyCommands::Push {
+                    name,
+                    file,
+                    signature,
+                    state_dir,
+                    cid,
+                },
+        } => {
+            let cid = match cid {
+                Some(cid) => cid,
+                None => started_cid(&state_dir, &name)?,
+            };
+
+            let update = SignedPolicyUpdate::new(
+                &std::fs::read(&file)
+                    .map_err(|e| anyhow!("failed to read {}: {e}", file.display()))?,
+                &std::fs::read(&signature)
+                    .map_err(|e| anyhow!("failed to read {}: {e}", signature.display()))?,
+            );
+            let serial = policy_update::push(cid, &update).await?;
+
+            println!("applied egress policy update {serial} to {name}");
+
+            Ok(())
+        }
+
+        // Export the request for the in-enclave ingress key.
+        Commands::Tls {
+            command:
+                TlsCommands::Csr {
+                    name,
+                    state_dir,
+                    cid,
+                },
+        } => {
+            let cid = match cid {
+                Some(cid) => cid,
+                None => started_cid(&state_dir, &name)?,
+            };
+
+            let csr = tls_issuance::fetch_csr(cid).await?;
+            stdout().write_all(csr.as_bytes()).await?;
+
+            Ok(())
+        }
+
+        // Push the issued chain back into the enclave.
+        Commands::Tls {
+            command:
+                TlsCommands::Install {
+                    name,
+                    chain,
+                    state_dir,
+                    cid,
+                },
+        } => {
+            let cid = match cid {
+                Some(cid) => cid,
+                None => started_cid(&state_dir, &name)?,
+            };
+
+            let chain = std::fs::read_to_string(&chain)
+                .map_err(|e| anyhow!("failed to read {}: {e}", chain.display()))?;
+            let ports = tls_issuance::install(cid, &chain).await?;
+
+            println!("installed the ingress certificate of {name} on ports {ports:?}");
+
+            Ok(())
+        }
+
+        // List what a debug enclave is connected to.
+        Commands::Debug {
+            command:
+                DebugCommands::Conns {
+                    name,
+                    state_dir,
+                    cid,
+                    json,
+                },
+        } => {
+            let cid = match cid {
+                Some(cid) => cid,
+                None => started_cid(&state_dir, &name)?,
+            };
+
+            let conns = connections::fetch(cid).await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&conns)?);
+                return Ok(());
+            }
+
+            println!(
+                "{:<6} {:<8} {:<6} {:<22} {:<32} {:>12} {:>12} {:>8}",
+                "ID", "KIND", "PORT", "PEER", "TARGET", "RECEIVED", "SENT", "AGE"
+            );
+            for conn in conns {
+                let target = match conn.server_name {
+                    Some(ref server_name) => format!("{} ({server_name})", conn.target),
+                    None => conn.target.clone(),
+                };
+                println!(
+                    "{:<6} {:<8} {:<6} {:<22} {:<32} {:>12} {:>12} {:>7}s",
+                    conn.id,
+                    conn.kind,
+                    conn.port,
+                    conn.peer,
+                    target,
+                    conn.received_bytes,
+                    conn.sent_bytes,
+                    conn.age_seconds
+                );
+            }
+
+            Ok(())
+        }
+
+        // Put load on an ingress port from the host.
+        Commands::Bench {
+            command: BenchCommands::Ingress { url, load, json },
+        } => print_report(&bench::run(&load.request(url), None).await?, json),
+
+        // Have a debug enclave put load on a remote through its egress.
+        Commands::Bench {
+            command:
+                BenchCommands::Egress {
+                    name,
+                    url,
+                    state_dir,
+                    cid,
+                    load,
+                    json,
+                },
+        } => {
+            let cid = match cid {
+                Some(cid) => cid,
+                None => started_cid(&state_dir, &name)?,
+            };
+
+            print_report(&bench::egress(cid, &load.request(url)).await?, json)
+        }
     }
 }
 
+fn print_report(report: &Report, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(report)?);
+        return Ok(());
+    }
+
+    println!("{} for {:.1}s", report.url, report.duration_seconds);
+    println!(
+        "{:<12} {:>10} {:>12} {:>10} {:>10} {:>10} {:>10}",
+        "", "TOTAL", "PER SECOND", "P50", "P90", "P99", "MAX"
+    );
+    for (name, total, rate, latency) in [
+        (
+            "connections",
+            report.connections_opened,
+            report.connections_per_second,
+            &report.connect_latency,
+        ),
+        (
+            "requests",
+            report.requests,
+            report.requests_per_second,
+            &report.request_latency,
+        ),
+    ] {
+        println!(
+            "{:<12} {:>10} {:>12.1} {:>8.2}ms {:>8.2}ms {:>8.2}ms {:>8.2}ms",
+            name, total, rate, latency.p50_ms, latency.p90_ms, latency.p99_ms, latency.max_ms
+        );
+    }
+    println!(
+        "received {} bytes, {:.0} bytes per second",
+        report.received_bytes, report.received_bytes_per_second
+    );
+    println!("errors {}", report.errors);
+
+    Ok(())
+}
+
+// Debug images only run in debug mode, where their attestations carry all zero PCRs,
+// so they must not be mistaken for the production build of the same app
+fn print_debug_build(manifest: &Manifest) {
+    if manifest.is_debug() {
+        println!("Debug Build: true (runs only with --debug-mode, attests to all zero PCRs)");
+    }
+}
+
+// The journal entry of the last time the named enclave was started
+fn last_started(state_dir: &Path, name: &str) -> Result<Option<JournalEntry>> {
+    Ok(journal::read_history(state_dir, name)?
+        .into_iter()
+        .rev()
+        .find(|entry| entry.event == "started"))
+}
+
+// The CID the named enclave was last started with
+fn started_cid(state_dir: &Path, name: &str) -> Result<u32> {
+    last_started(state_dir, name)?
+        .and_then(|entry| entry.details.get("cid").cloned())
+        .and_then(|cid| cid.as_u64())
+        .map(|cid| cid as u32)
+        .ok_or_else(|| anyhow!("no CID recorded for {name}, pass --cid"))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Cli::parse();
@@ -1,28 +1,127 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use log::info;
 use tokio::task::JoinHandle;
 
 use crate::config::Configuration;
-use enclaver::api::ApiHandler;
+use enclaver::api::{ApiHandler, ChannelAttestations, TlsReloader};
+use enclaver::connections::ConnectionTable;
 use enclaver::http_util::HttpServer;
+use enclaver::keypair::KeyPair;
 use enclaver::nsm::{Nsm, NsmAttestationProvider};
+use enclaver::spiffe::SvidStore;
+use enclaver::token::TokenIssuer;
+
+// The signing key is generated at boot and never leaves the enclave, so tokens
+// can only be verified against the JWKS of this very enclave
+fn token_issuer(config: &Configuration, nsm: &Nsm) -> Result<Option<TokenIssuer>> {
+    let tokens = match config
+        .manifest
+        .api
+        .as_ref()
+        .and_then(|api| api.tokens.as_ref())
+    {
+        Some(tokens) => tokens,
+        None => return Ok(None),
+    };
+
+    let mut pcrs: [String; 3] = Default::default();
+    for (index, pcr) in pcrs.iter_mut().enumerate() {
+        *pcr = nsm
+            .describe_pcr(index as u16)?
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+    }
+
+    let issuer = TokenIssuer::new(
+        KeyPair::generate()?,
+        &tokens.issuer,
+        &config.manifest.name,
+        pcrs,
+        tokens.lifetime_seconds(),
+    )?;
+
+    Ok(Some(issuer))
+}
+
+// Attestations carry the same default user_data as those of /v1/attestation
+fn channel_attestations(config: &Configuration, nsm: Arc<Nsm>) -> Option<ChannelAttestations> {
+    let channel = config
+        .manifest
+        .api
+        .as_ref()
+        .and_then(|api| api.channel_attestation.as_ref())?;
+
+    let attester = NsmAttestationProvider::new(nsm)
+        .with_default_user_data(config.attestation_user_data.clone());
+
+    Some(ChannelAttestations::new(
+        Box::new(attester),
+        Duration::from_secs(channel.refresh_seconds()),
+    ))
+}
 
 pub struct ApiService {
     task: Option<JoinHandle<()>>,
 }
 
 impl ApiService {
-    pub fn start(config: &Configuration, nsm: Arc<Nsm>) -> Result<Self> {
+    pub fn start(
+        config: &Configuration,
+        nsm: Arc<Nsm>,
+        svids: SvidStore,
+        tls_reloader: Option<Arc<dyn TlsReloader>>,
+        csr: Option<String>,
+        connections: Option<ConnectionTable>,
+    ) -> Result<Self> {
         let task = if let Some(port) = config.api_port() {
             info!("Starting API on port {port}");
 
             let srv = HttpServer::bind(port)?;
-            let handler = ApiHandler::new(Box::new(NsmAttestationProvider::new(nsm)));
+            let tokens = token_issuer(config, &nsm)?;
+            let attester = NsmAttestationProvider::new(nsm.clone())
+                .with_default_user_data(config.attestation_user_data.clone());
+            let handler = ApiHandler::new(Box::new(attester)).with_context(config.api_context());
+            let handler = if config.manifest.spiffe.is_some() {
+                handler.with_svids(svids)
+            } else {
+                handler
+            };
+            let handler = match tokens {
+                Some(tokens) => handler.with_tokens(tokens),
+                None => handler,
+            };
+            let handler = match tls_reloader {
+                Some(reloader) => handler.with_tls_reloader(reloader),
+                None => handler,
+            };
+            let handler = match csr {
+                Some(csr) => handler.with_certificate_request(csr),
+                None => handler,
+            };
+            let handler = match connections {
+                Some(connections) => handler.with_connection_table(connections),
+                None => handler,
+            };
+            let channels = channel_attestations(config, nsm);
+            let handler = match channels {
+                Some(ref channels) => handler.with_channel_attestations(channels.clone()),
+                None => handler,
+            };
 
             Some(tokio::task::spawn(async move {
-                _ = srv.serve(handler).await;
+                match channels {
+                    Some(channels) => {
+                        tokio::select! {
+                            _ = srv.serve(handler) => {}
+                            _ = channels.refresh() => {}
+                        }
+                    }
+                    None => _ = srv.serve(handler).await,
+                }
             }))
         } else {
             None
@@ -0,0 +1,38 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use log::info;
+
+use crate::config::Configuration;
+use enclaver::config_provider;
+use enclaver::nsm::{AttestationParams, Nsm};
+
+const ATTESTED_CONFIG_FILE_NAME: &str = "attested-config";
+const ATTESTED_CONFIG_ENV_VAR: &str = "ENCLAVER_ATTESTED_CONFIG";
+
+// Proves our identity to the host's config provider, if it runs one, and makes
+// the config it releases available to the application. Returns the sealed secret
+// files released along with it.
+pub async fn fetch(config: &Configuration, nsm: &Nsm) -> Result<BTreeMap<String, Vec<u8>>> {
+    let attest = |nonce| {
+        Ok(nsm.attestation(AttestationParams {
+            nonce: Some(nonce),
+            user_data: config.attestation_user_data.clone(),
+            public_key: None,
+        })?)
+    };
+
+    let payload = match config_provider::fetch(attest).await? {
+        Some(payload) => payload,
+        None => return Ok(BTreeMap::new()),
+    };
+
+    if let Some(document) = payload.config()? {
+        let path = config.config_dir.join(ATTESTED_CONFIG_FILE_NAME);
+        std::fs::write(&path, document)?;
+        std::env::set_var(ATTESTED_CONFIG_ENV_VAR, &path);
+        info!("Attested config released by the host");
+    }
+
+    payload.secret_files()
+}
@@ -0,0 +1,96 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use tokio::task::JoinHandle;
+
+use crate::config::Configuration;
+use enclaver::attestation::attested_key_nonce;
+use enclaver::journal::unix_time;
+use enclaver::keypair::KeyPair;
+use enclaver::nsm::{AttestationParams, Nsm};
+use enclaver::tls::{self, RenewedCertificate};
+
+// The certificate chain of an attestation document is only valid for a few hours, so
+// the certificate is issued again well before it has to be
+const RENEWAL_INTERVAL: Duration = Duration::from_secs(3600);
+const CERT_LIFETIME_SECS: u64 = 2 * 3600;
+
+// Issues the certificate of the attested ingress ports: a fresh key, bound into an
+// attestation that a self-signed certificate for it carries, so the private key
+// never leaves the enclave.
+struct CertIssuer {
+    cert: RenewedCertificate,
+    nsm: Arc<Nsm>,
+    name: String,
+    user_data: Option<Vec<u8>>,
+}
+
+impl CertIssuer {
+    fn issue(&self) -> Result<()> {
+        let keypair = KeyPair::generate()?;
+        let public_key = keypair.public_key_as_der()?;
+        let attestation = self.nsm.attestation(AttestationParams {
+            nonce: Some(attested_key_nonce(&public_key)),
+            user_data: self.user_data.clone(),
+            public_key: Some(public_key),
+        })?;
+
+        let cert = tls::attested_certificate(
+            &keypair,
+            &self.name,
+            &attestation,
+            unix_time(),
+            CERT_LIFETIME_SECS,
+        )?;
+        self.cert.set(&keypair, cert)
+    }
+
+    async fn renew(self) {
+        loop {
+            tokio::time::sleep(RENEWAL_INTERVAL).await;
+
+            match self.issue() {
+                Ok(()) => info!("Renewed the attested TLS certificate"),
+                Err(err) => warn!("failed to renew the attested TLS certificate: {err}"),
+            }
+        }
+    }
+}
+
+pub struct AttestedTlsService {
+    task: Option<JoinHandle<()>>,
+}
+
+impl AttestedTlsService {
+    // Issues the first certificate before ingress starts, then keeps it renewed
+    pub fn start(config: &Configuration, nsm: Arc<Nsm>) -> Result<Self> {
+        let cert = match config.attested_cert {
+            Some(ref cert) => cert.clone(),
+            None => return Ok(Self { task: None }),
+        };
+
+        let issuer = CertIssuer {
+            cert,
+            nsm,
+            name: config.manifest.name.clone(),
+            user_data: config.attestation_user_data.clone(),
+        };
+        issuer
+            .issue()
+            .map_err(|e| anyhow!("failed to issue the attested TLS certificate: {e}"))?;
+        info!("Issued the attested TLS certificate");
+
+        let task = tokio::task::spawn(issuer.renew());
+
+        Ok(Self { task: Some(task) })
+    }
+
+    pub async fn stop(self) {
+        if let Some(task) = self.task {
+            task.abort();
+            _ = task.await;
+        }
+    }
+}
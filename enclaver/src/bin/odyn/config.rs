@@ -1,19 +1,49 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use http::Uri;
-use log::debug;
-use std::collections::HashMap;
+use log::{debug, info};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{Ipv4Addr, TcpListener};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use enclaver::api::ApiContext;
+use enclaver::boot_config::DebugOverrides;
 use enclaver::constants::{HTTP_EGRESS_PROXY_PORT, MANIFEST_FILE_NAME};
-use enclaver::manifest::{self, Manifest};
+use enclaver::manifest::{self, EgressProxy, Manifest, ServerTls};
 use enclaver::proxy::kms::KmsEndpointProvider;
-use enclaver::tls;
+use enclaver::proxy::s3::S3EndpointProvider;
+use enclaver::service_config;
+use enclaver::tls::{self, RenewedCertificate};
 
 pub struct Configuration {
     pub config_dir: PathBuf,
     pub manifest: Manifest,
+
+    // Hex encoded SHA-256 of the manifest file, as bundled in the image
+    pub manifest_digest: String,
+
     pub listener_configs: HashMap<u16, ListenerConfig>,
+
+    // Port of the egress proxy on localhost, if egress is enabled
+    pub egress_proxy_port: Option<u16>,
+
+    // Default user_data for attestations, describing the runtime config in use
+    pub attestation_user_data: Option<Vec<u8>>,
+
+    // Certificate the attested ingress ports present, once it has been issued
+    pub attested_cert: Option<RenewedCertificate>,
+
+    // Certificate the ingress ports with a csr present, once a CA has issued it
+    pub issued_cert: Option<RenewedCertificate>,
+
+    // Certificates of the ingress ports whose key_file is KMS encrypted, once the KMS
+    // proxy has decrypted the key
+    pub kms_key_certs: HashMap<u16, RenewedCertificate>,
+
+    // AWS variables of the environment of enclaver-run, for the credentials of the
+    // KMS proxy
+    pub aws_env: BTreeMap<String, String>,
 }
 
 #[derive(Clone)]
@@ -27,16 +57,47 @@ impl Configuration {
         let mut manifest_path = config_dir.as_ref().to_path_buf();
         manifest_path.push(MANIFEST_FILE_NAME);
 
-        let manifest = enclaver::manifest::load_manifest(manifest_path.to_str().unwrap()).await?;
+        let (raw_manifest, manifest) =
+            enclaver::manifest::load_manifest_raw(manifest_path.to_str().unwrap()).await?;
 
         let mut tls_path = config_dir.as_ref().to_path_buf();
         tls_path.extend(["tls", "server"]);
 
         let mut listener_configs = HashMap::new();
+        let mut kms_key_certs = HashMap::new();
+
+        let attested_cert = manifest
+            .ingress
+            .iter()
+            .flatten()
+            .any(|item| item.tls.as_ref().is_some_and(|tls| tls.is_attested()))
+            .then(RenewedCertificate::default);
+        let issued_cert = manifest
+            .ingress
+            .iter()
+            .flatten()
+            .any(|item| item.tls.as_ref().is_some_and(|tls| tls.csr.is_some()))
+            .then(RenewedCertificate::default);
 
         if let Some(ref ingress) = manifest.ingress {
             for item in ingress {
                 let cfg = match item.tls {
+                    Some(ref tls) if tls.is_attested() => {
+                        let cert = attested_cert.clone().unwrap_or_default();
+                        let tls_config = tls::renewed_server_config(cert, client_auth(tls))?;
+                        ListenerConfig::TLS(tls::with_alpn(tls_config, tls.alpn_protocols()))
+                    }
+                    Some(ref tls) if tls.csr.is_some() => {
+                        let cert = issued_cert.clone().unwrap_or_default();
+                        let tls_config = tls::renewed_server_config(cert, client_auth(tls))?;
+                        ListenerConfig::TLS(tls::with_alpn(tls_config, tls.alpn_protocols()))
+                    }
+                    Some(ref tls) if tls.is_kms_encrypted() => {
+                        let cert = RenewedCertificate::default();
+                        kms_key_certs.insert(item.listen_port, cert.clone());
+                        let tls_config = tls::renewed_server_config(cert, client_auth(tls))?;
+                        ListenerConfig::TLS(tls::with_alpn(tls_config, tls.alpn_protocols()))
+                    }
                     Some(_) => {
                         let tls_config = Configuration::load_tls_server_config(&tls_path, item)?;
                         ListenerConfig::TLS(tls_config)
@@ -48,61 +109,159 @@ impl Configuration {
             }
         }
 
+        let egress_proxy_port = select_egress_proxy_port(&manifest)?;
+
         Ok(Self {
             config_dir: config_dir.as_ref().to_path_buf(),
             manifest,
+            manifest_digest: service_config::manifest_digest(&raw_manifest),
             listener_configs,
+            egress_proxy_port,
+            attestation_user_data: None,
+            attested_cert,
+            issued_cert,
+            kms_key_certs,
+            aws_env: BTreeMap::new(),
         })
     }
 
+    // Ports with a csr share one key, so its request names the DNS names of all of
+    // them, the first one first
+    pub fn csr_ports(&self) -> (Vec<u16>, Vec<String>) {
+        let mut ports = Vec::new();
+        let mut dns_names: Vec<String> = Vec::new();
+        for item in self.manifest.ingress.iter().flatten() {
+            if let Some(csr) = item.tls.as_ref().and_then(|tls| tls.csr.as_ref()) {
+                ports.push(item.listen_port);
+                for name in &csr.dns_names {
+                    if !dns_names.contains(name) {
+                        dns_names.push(name.clone());
+                    }
+                }
+            }
+        }
+
+        (ports, dns_names)
+    }
+
+    // The key and certificate files of a TLS ingress port
+    pub fn tls_server_files(&self, port: u16) -> (PathBuf, PathBuf) {
+        let mut tls_path = self.config_dir.clone();
+        tls_path.extend(["tls", "server"]);
+
+        server_files(&tls_path, port)
+    }
+
+    // Loads the key and certificate files of the TLS ingress ports again, e.g. once
+    // the app has replaced them. Ports whose key odyn generates or decrypts are left
+    // alone.
+    pub fn reload_tls_server_configs(&self) -> Result<HashMap<u16, Arc<rustls::ServerConfig>>> {
+        let mut tls_path = self.config_dir.clone();
+        tls_path.extend(["tls", "server"]);
+
+        self.manifest
+            .ingress
+            .iter()
+            .flatten()
+            .filter(|item| item.tls.as_ref().is_some_and(|tls| tls.is_reloadable()))
+            .map(|item| {
+                let tls_config = Configuration::load_tls_server_config(&tls_path, item)?;
+                Ok((item.listen_port, tls_config))
+            })
+            .collect()
+    }
+
     fn load_tls_server_config(
         tls_path: &Path,
         ingress: &manifest::Ingress,
     ) -> Result<Arc<rustls::ServerConfig>> {
-        let mut ingress_path = tls_path.to_path_buf();
-        ingress_path.push(&ingress.listen_port.to_string());
-
-        let mut key_path = ingress_path.clone();
-        key_path.push("key.pem");
-
-        let mut cert_path = ingress_path.clone();
-        cert_path.push("cert.pem");
+        let (key_path, cert_path) = server_files(tls_path, ingress.listen_port);
 
         debug!("Loading key_file: {}", key_path.to_string_lossy());
         debug!("Loading cert_file: {}", cert_path.to_string_lossy());
-        tls::load_server_config(key_path, cert_path)
-    }
 
-    pub fn egress_proxy_uri(&self) -> Option<Uri> {
-        let enabled = if let Some(ref egress) = self.manifest.egress {
-            if let Some(ref allow) = egress.allow {
-                !allow.is_empty()
-            } else {
-                false
-            }
+        let client_auth = ingress.tls.as_ref().and_then(client_auth);
+        let alpn = ingress
+            .tls
+            .as_ref()
+            .map_or(&[][..], |tls| tls.alpn_protocols());
+
+        // Routes without a certificate of their own are presented the one of the port
+        let sni_certs: Vec<_> = ingress
+            .tls
+            .iter()
+            .flat_map(|tls| tls.routes.iter().flatten())
+            .filter_map(|route| {
+                let key = PathBuf::from(route.key_file.as_ref()?);
+                let cert = PathBuf::from(route.cert_file.as_ref()?);
+                Some((route.server_name.clone(), key, cert))
+            })
+            .collect();
+        let tls_config = if !sni_certs.is_empty() {
+            tls::load_sni_server_config(key_path, cert_path, &sni_certs, client_auth)?
         } else {
-            false
+            match client_auth {
+                Some((ca_file, required)) => {
+                    tls::load_mtls_server_config(key_path, cert_path, ca_file, required)?
+                }
+                None => tls::load_server_config(key_path, cert_path)?,
+            }
         };
 
-        if enabled {
-            let port = self
-                .manifest
-                .egress
-                .as_ref()
-                .unwrap()
-                .proxy_port
-                .unwrap_or(HTTP_EGRESS_PROXY_PORT);
-
-            Some(
-                Uri::builder()
-                    .scheme("http")
-                    .authority(format!("127.0.0.1:{port}"))
-                    .path_and_query("")
-                    .build()
-                    .unwrap(),
-            )
-        } else {
-            None
+        Ok(tls::with_alpn(tls_config, alpn))
+    }
+
+    pub fn apply_debug_overrides(&mut self, overrides: &DebugOverrides) -> Result<()> {
+        overrides.apply(&mut self.manifest);
+
+        for port in &overrides.extra_ingress {
+            self.listener_configs
+                .entry(*port)
+                .or_insert(ListenerConfig::TCP);
+        }
+
+        // Extra ingress ports may take the one picked for the proxy
+        self.egress_proxy_port = select_egress_proxy_port(&self.manifest)?;
+        Ok(())
+    }
+
+    // Adds key to the JSON object carried as the default attestation user_data
+    pub fn tag_attestation(&mut self, key: &str, value: Value) -> Result<()> {
+        let mut tags = match self.attestation_user_data {
+            Some(ref data) => match serde_json::from_slice(data)? {
+                Value::Object(tags) => tags,
+                _ => return Err(anyhow!("attestation user_data is not a JSON object")),
+            },
+            None => Map::new(),
+        };
+
+        tags.insert(key.to_string(), value);
+        self.attestation_user_data = Some(serde_json::to_vec(&tags)?);
+
+        Ok(())
+    }
+
+    pub fn egress_proxy_uri(&self) -> Option<Uri> {
+        self.egress_proxy_port.map(local_proxy_uri)
+    }
+
+    /// The named egress proxies and their URIs
+    pub fn named_egress_proxies(&self) -> Vec<(&EgressProxy, Uri)> {
+        self.manifest
+            .egress_proxies()
+            .map(|proxy| (proxy, local_proxy_uri(proxy.proxy_port)))
+            .collect()
+    }
+
+    /// How the enclave was set up, as the API and the status file describe it
+    pub fn api_context(&self) -> ApiContext {
+        ApiContext {
+            egress_proxy: self.egress_proxy_uri().map(|uri| uri.to_string()),
+            egress_proxies: self
+                .named_egress_proxies()
+                .into_iter()
+                .map(|(proxy, uri)| (proxy.name.clone(), uri.to_string()))
+                .collect(),
         }
     }
 
@@ -110,9 +269,86 @@ impl Configuration {
         self.manifest.kms_proxy.as_ref().map(|kp| kp.listen_port)
     }
 
+    pub fn s3_proxy_port(&self) -> Option<u16> {
+        self.manifest.s3_proxy.as_ref().map(|sp| sp.listen_port)
+    }
+
     pub fn api_port(&self) -> Option<u16> {
         self.manifest.api.as_ref().map(|a| a.listen_port)
     }
+
+    pub fn health_port(&self) -> Option<u16> {
+        self.manifest.health.as_ref().map(|h| h.listen_port)
+    }
+}
+
+fn server_files(tls_path: &Path, port: u16) -> (PathBuf, PathBuf) {
+    let mut ingress_path = tls_path.to_path_buf();
+    ingress_path.push(port.to_string());
+
+    (ingress_path.join("key.pem"), ingress_path.join("cert.pem"))
+}
+
+// The client CA of a TLS ingress port, and whether clients must present a certificate
+fn client_auth(tls: &ServerTls) -> Option<(&Path, bool)> {
+    let ca_file = tls.client_ca_file.as_ref()?;
+    debug!("Loading client_ca_file: {ca_file}");
+
+    Some((Path::new(ca_file), tls.requires_client_auth()))
+}
+
+fn local_proxy_uri(port: u16) -> Uri {
+    Uri::builder()
+        .scheme("http")
+        .authority(format!("127.0.0.1:{port}"))
+        .path_and_query("")
+        .build()
+        .unwrap()
+}
+
+// A proxy_port from the manifest is used as is, as long as nothing else in the
+// manifest listens on it. Otherwise the default is used unless it is taken, in
+// which case any free port on localhost will do.
+fn select_egress_proxy_port(manifest: &Manifest) -> Result<Option<u16>> {
+    if !manifest
+        .egress
+        .as_ref()
+        .is_some_and(|egress| egress.is_enabled())
+    {
+        return Ok(None);
+    }
+
+    manifest.check_egress_proxy_ports()?;
+    if let Some(port) = manifest
+        .egress
+        .as_ref()
+        .and_then(|egress| egress.proxy_port)
+    {
+        return Ok(Some(port));
+    }
+
+    let taken: Vec<u16> = manifest.listen_ports().iter().map(|(p, _)| *p).collect();
+    if !taken.contains(&HTTP_EGRESS_PROXY_PORT) && is_port_free(HTTP_EGRESS_PROXY_PORT) {
+        return Ok(Some(HTTP_EGRESS_PROXY_PORT));
+    }
+
+    // Ports the kernel hands out are free now, but the manifest's listeners may not
+    // have bound theirs yet
+    for _ in 0..16 {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+            .local_addr()?
+            .port();
+        if !taken.contains(&port) {
+            info!("egress proxy port {HTTP_EGRESS_PROXY_PORT} is taken, using {port} instead");
+            return Ok(Some(port));
+        }
+    }
+
+    Err(anyhow!("unable to find a free port for the egress proxy"))
+}
+
+fn is_port_free(port: u16) -> bool {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, port)).is_ok()
 }
 
 impl KmsEndpointProvider for Configuration {
@@ -127,3 +363,16 @@ impl KmsEndpointProvider for Configuration {
         ep.unwrap_or_else(|| format!("kms.{region}.amazonaws.com"))
     }
 }
+
+impl S3EndpointProvider for Configuration {
+    fn endpoint(&self, region: &str) -> String {
+        let ep = self
+            .manifest
+            .s3_proxy
+            .as_ref()
+            .and_then(|sp| sp.endpoints.as_ref().map(|eps| eps.get(region).cloned()))
+            .flatten();
+
+        ep.unwrap_or_else(|| format!("s3.{region}.amazonaws.com"))
+    }
+}
@@ -2,40 +2,71 @@ use anyhow::Result;
 use circbuf::CircBuf;
 use futures::Stream;
 use ignore_result::Ignore;
+use std::collections::BTreeMap;
 use std::os::unix::io::AsRawFd;
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::sync::watch::{Receiver, Sender};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
 use tokio_pipe::{PipeRead, PipeWrite};
 use tokio_vsock::VsockStream;
 
 use crate::launcher::ExitStatus;
+use enclaver::compression::{FrameWriter, StreamRequest};
+use enclaver::policy::limits::LimitStats;
+use enclaver::protocol::EnclaveStatus;
+use enclaver::proxy::audit::DenialStats;
+use enclaver::status::{FatalCode, LogStats, LogSubscriberStats, MemoryStats};
 
 const APP_LOG_CAPACITY: usize = 128 * 1024;
 
 struct LogCursor {
     pos: usize,
+
+    // Id of the subscriber reading with it, whose stats it counts towards
+    subscriber: Option<u64>,
 }
 
 impl LogCursor {
+    #[cfg(test)]
     fn new() -> Self {
-        Self { pos: 0usize }
+        Self {
+            pos: 0usize,
+            subscriber: None,
+        }
     }
 }
 
+// Appends are announced over a watch channel carrying the position of the tail.
+// Notifications coalesce: a busy reader wakes up once no matter how many appends
+// happened in the meantime and catches up by reading from its cursor.
+// A reader that falls behind the trimmed head skips ahead to the oldest
+// available data; the skipped bytes are lost to that reader, and counted as such
+// in the stats of the subscriber.
 struct ByteLog {
     buffer: CircBuf,
     head: usize,
-    watches: WatchSet,
+    tail: watch::Sender<usize>,
+
+    // Subscribers connected now, by id, and the totals of all there have been
+    subscribers: BTreeMap<u64, LogSubscriberStats>,
+    next_subscriber: u64,
+    delivered: u64,
+    skipped: u64,
 }
 
 impl ByteLog {
     fn new() -> Self {
+        let (tail, _) = watch::channel(0usize);
+
         Self {
             buffer: CircBuf::with_capacity(APP_LOG_CAPACITY).unwrap(),
             head: 0usize,
-            watches: WatchSet::new(),
+            tail,
+            subscribers: BTreeMap::new(),
+            next_subscriber: 0,
+            delivered: 0,
+            skipped: 0,
         }
     }
 
@@ -56,15 +87,87 @@ impl ByteLog {
         assert!(self.buffer.write(data).unwrap() == data.len());
 
         // notify the watchers that an append happened
-        self.watches.notify();
+        self.tail.send_replace(self.head + self.buffer.len());
 
         trim_cnt
     }
 
-    fn read(&self, cursor: &mut LogCursor, mut buf: &mut [u8]) -> usize {
+    // A cursor at pos, the offset of the next byte a client wants. If those bytes
+    // were trimmed, it starts at the oldest available data instead. An offset past
+    // the tail cannot come from this log, e.g. the client saw a previous boot of
+    // the enclave, so it starts over from the head.
+    fn resume(&self, pos: usize) -> LogCursor {
+        let tail = self.head + self.buffer.len();
+        let pos = if pos < self.head || pos > tail {
+            self.head
+        } else {
+            pos
+        };
+
+        LogCursor {
+            pos,
+            subscriber: None,
+        }
+    }
+
+    // A cursor for a new subscriber, as resume does. Bytes it asked for that were
+    // trimmed already count as skipped.
+    fn subscribe(&mut self, pos: usize) -> LogCursor {
+        let id = self.next_subscriber;
+        self.next_subscriber += 1;
+        self.subscribers.insert(
+            id,
+            LogSubscriberStats {
+                id,
+                ..Default::default()
+            },
+        );
+
+        let mut cursor = self.resume(pos);
+        cursor.subscriber = Some(id);
+        if pos < cursor.pos {
+            self.count(&cursor, 0, cursor.pos - pos);
+        }
+
+        cursor
+    }
+
+    fn unsubscribe(&mut self, cursor: &LogCursor) {
+        if let Some(id) = cursor.subscriber {
+            self.subscribers.remove(&id);
+        }
+    }
+
+    fn count(&mut self, cursor: &LogCursor, delivered: usize, skipped: usize) {
+        let id = match cursor.subscriber {
+            Some(id) => id,
+            None => return,
+        };
+
+        if let Some(stats) = self.subscribers.get_mut(&id) {
+            stats.delivered_bytes += delivered as u64;
+            stats.skipped_bytes += skipped as u64;
+            self.delivered += delivered as u64;
+            self.skipped += skipped as u64;
+        }
+    }
+
+    fn stats(&self) -> LogStats {
+        LogStats {
+            produced_bytes: (self.head + self.buffer.len()) as u64,
+            trimmed_bytes: self.head as u64,
+            delivered_bytes: self.delivered,
+            skipped_bytes: self.skipped,
+            subscribers: self.subscribers.values().copied().collect(),
+        }
+    }
+
+    fn read(&mut self, cursor: &mut LogCursor, mut buf: &mut [u8]) -> usize {
         let mut copied = 0usize;
+        let mut skipped = 0usize;
 
         let mut offset = if cursor.pos < self.head {
+            skipped = self.head - cursor.pos;
             cursor.pos = self.head;
             0usize
         } else {
@@ -86,12 +189,13 @@ impl ByteLog {
         }
 
         cursor.pos += copied;
+        self.count(cursor, copied, skipped);
 
         copied
     }
 
-    fn watch(&mut self) -> Receiver<()> {
-        self.watches.add()
+    fn watch(&self) -> watch::Receiver<usize> {
+        self.tail.subscribe()
     }
 
     #[cfg(test)]
@@ -171,6 +275,10 @@ impl LogReader {
         self.log.lock().unwrap().read(cursor, buf)
     }
 
+    fn stats(&self) -> LogStats {
+        self.log.lock().unwrap().stats()
+    }
+
     #[cfg(test)]
     fn len(&self) -> usize {
         self.log.lock().unwrap().len()
@@ -179,25 +287,51 @@ impl LogReader {
     async fn write_all<W: AsyncWrite + Unpin>(
         &self,
         cursor: &mut LogCursor,
-        writer: &mut W,
+        writer: &mut FrameWriter<W>,
     ) -> Result<()> {
-        let mut buf = vec![0u8; 4096];
+        let mut buf = vec![0u8; writer.chunk_len()];
         loop {
             let nread = self.read(cursor, &mut buf);
             if nread == 0 {
                 break;
             }
-            writer.write_all(&buf[..nread]).await?;
+            writer.write(&buf[..nread]).await?;
         }
 
         Ok(())
     }
 
-    async fn stream<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
-        let mut cursor = LogCursor::new();
-        let mut w = self.log.lock().unwrap().watch();
+    // The client opens with the offset of the next byte it wants, as a big-endian
+    // u64, and gets back the offset the stream actually starts at before the log
+    // bytes. A reconnecting client sends the offset it got up to and receives no
+    // duplicates; a larger offset in the reply means bytes were trimmed meanwhile.
+    // The host may ask for the bytes to be compressed, see enclaver::compression.
+    async fn stream<S: AsyncRead + AsyncWrite + Unpin>(&self, sock: &mut S) -> Result<()> {
+        let request = StreamRequest::read(sock).await?;
+        let (mut cursor, w) = {
+            let mut log = self.log.lock().unwrap();
+            (
+                log.subscribe(usize::try_from(request.cursor).unwrap_or(usize::MAX)),
+                log.watch(),
+            )
+        };
+
+        let result = self.follow(&mut cursor, w, &request, sock).await;
+        self.log.lock().unwrap().unsubscribe(&cursor);
+        result
+    }
+
+    async fn follow<S: AsyncWrite + Unpin>(
+        &self,
+        cursor: &mut LogCursor,
+        mut w: watch::Receiver<usize>,
+        request: &StreamRequest,
+        sock: &mut S,
+    ) -> Result<()> {
+        let mut writer = request.start(sock, cursor.pos as u64).await?;
+
         loop {
-            self.write_all(&mut cursor, writer).await?;
+            self.write_all(cursor, &mut writer).await?;
 
             // wait for new data
             // unwrap() since the sender never closes first
@@ -222,6 +356,12 @@ impl AppLog {
         })
     }
 
+    pub fn stats(&self) -> AppLogStats {
+        AppLogStats {
+            reader: self.reader.clone(),
+        }
+    }
+
     // serve the log over vsock
     async fn serve_log(incoming: impl Stream<Item = VsockStream>, lr: LogReader) -> Result<()> {
         use futures::stream::StreamExt;
@@ -249,76 +389,108 @@ impl AppLog {
                 )?;
                 Ok(())
             }),
-            Err(e) => tokio::task::spawn(async move { Err(e) }),
+            Err(e) => tokio::task::spawn(async move { Err(e.into()) }),
         }
     }
 }
 
-enum EntrypointStatus {
-    Running,
-    Exited(ExitStatus),
-    Fatal(String),
+// Counters of the app log, read for the stats reported with the running status
+#[derive(Clone)]
+pub struct AppLogStats {
+    reader: LogReader,
 }
 
-impl EntrypointStatus {
-    fn as_json(&self) -> String {
-        match self {
-            Self::Running => "{ \"status\": \"running\" }\n".to_string(),
-            Self::Exited(exit_status) => match exit_status {
-                ExitStatus::Exited(code) => {
-                    format!("{{ \"status\": \"exited\", \"code\": {code} }}\n")
-                }
-                ExitStatus::Signaled(sig) => {
-                    format!("{{ \"status\": \"signaled\", \"signal\": \"{sig}\" }}\n")
-                }
-            },
-            Self::Fatal(err) => format!("{{ \"status\": \"fatal\", \"error\": \"{err}\" }}\n"),
-        }
+impl AppLogStats {
+    pub fn get(&self) -> LogStats {
+        self.reader.stats()
     }
 }
 
-struct AppStatusInner {
-    status: EntrypointStatus,
-    watches: WatchSet,
-}
-
-impl AppStatusInner {
-    fn new() -> Self {
-        Self {
-            status: EntrypointStatus::Running,
-            watches: WatchSet::new(),
+impl From<ExitStatus> for EnclaveStatus {
+    fn from(exit_status: ExitStatus) -> Self {
+        match exit_status {
+            ExitStatus::Exited(code) => Self::Exited { code },
+            ExitStatus::Signaled(sig) => Self::Signaled { signal: sig as i32 },
         }
     }
-
-    fn exited(&mut self, status: ExitStatus) {
-        self.status = EntrypointStatus::Exited(status);
-        self.watches.notify();
-    }
-
-    fn fatal(&mut self, err: String) {
-        self.status = EntrypointStatus::Fatal(err);
-        self.watches.notify();
-    }
 }
 
+// Only the latest status is kept: a subscriber that has not caught up
+// skips intermediate states and gets sent the most recent one.
 #[derive(Clone)]
 pub struct AppStatus {
-    inner: Arc<Mutex<AppStatusInner>>,
+    status: Arc<watch::Sender<EnclaveStatus>>,
 }
 
 impl AppStatus {
     pub fn new() -> Self {
+        let (status, _) = watch::channel(EnclaveStatus::Running {
+            memory: None,
+            egress_limits: Vec::new(),
+            egress_denials: Vec::new(),
+            app_log: None,
+        });
+
         Self {
-            inner: Arc::new(Mutex::new(AppStatusInner::new())),
+            status: Arc::new(status),
         }
     }
 
     pub fn exited(&self, status: ExitStatus) {
-        self.inner.lock().unwrap().exited(status);
+        self.status.send_replace(status.into());
     }
 
-    pub fn fatal(&self, err: String) {
-        self.inner.lock().unwrap().fatal(err);
+    /// Updates the memory, egress limit, egress denial and app log counters reported
+    /// with the running status, and does nothing once the entrypoint is done
+    pub fn report(
+        &self,
+        stats: MemoryStats,
+        limits: Vec<LimitStats>,
+        denials: Vec<DenialStats>,
+        log: Option<LogStats>,
+    ) {
+        self.status.send_if_modified(|status| match status {
+            EnclaveStatus::Running {
+                memory,
+                egress_limits,
+                egress_denials,
+                app_log,
+            } if *memory != Some(stats)
+                || *egress_limits != limits
+                || *egress_denials != denials
+                || *app_log != log =>
+            {
+                *memory = Some(stats);
+                *egress_limits = limits;
+                *egress_denials = denials;
+                *app_log = log;
+                true
+            }
+            _ => false,
+        });
+    }
+
+    /// The status of the entrypoint, for the health listener. It leaves out the stats
+    /// and the error of a fatal status, as the listener may be reachable from outside.
+    pub fn health(&self) -> serde_json::Value {
+        match &*self.status.borrow() {
+            EnclaveStatus::Running { .. } => serde_json::json!({ "status": "running" }),
+            EnclaveStatus::Fatal { code, .. } => {
+                serde_json::json!({ "status": "fatal", "code": code })
+            }
+            status => serde_json::to_value(status).unwrap(),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(*self.status.borrow(), EnclaveStatus::Running { .. })
+    }
+
+    pub fn fatal(&self, code: FatalCode, error: String) {
+        self.status.send_replace(EnclaveStatus::Fatal {
+            code: Some(code),
+            error,
+        });
     }
 
     pub fn start_serving(&self, port: u32) -> JoinHandle<Result<()>> {
@@ -338,15 +510,15 @@ impl AppStatus {
                     Ok(())
                 })
             }
-            Err(e) => tokio::task::spawn(async move { Err(e) }),
+            Err(e) => tokio::task::spawn(async move { Err(e.into()) }),
         }
     }
 
     async fn stream(&self, mut sock: VsockStream) {
-        let mut w = self.inner.lock().unwrap().watches.add();
+        let mut w = self.status.subscribe();
 
         loop {
-            let json_str = self.inner.lock().unwrap().status.as_json();
+            let json_str = w.borrow_and_update().to_line();
             _ = sock.write_all(json_str.as_bytes()).await;
 
             // wait for new data
@@ -356,40 +528,14 @@ impl AppStatus {
     }
 }
 
-// Broadcast mechanism that something being watched has changed
-struct WatchSet {
-    watches: Vec<Sender<()>>,
-}
-
-impl WatchSet {
-    fn new() -> Self {
-        Self {
-            watches: Vec::new(),
-        }
-    }
-
-    fn add(&mut self) -> Receiver<()> {
-        let (tx, rx) = tokio::sync::watch::channel(());
-        self.watches.push(tx);
-        rx
-    }
-
-    fn notify(&mut self) {
-        // first, clean up any closed channels
-        self.watches.retain(|s| !s.is_closed());
-
-        // now notify
-        for w in &self.watches {
-            _ = w.send(())
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use anyhow::{anyhow, Result};
     use assert2::assert;
     use enclaver::constants::STATUS_PORT;
+    use enclaver::policy::limits::LimitStats;
+    use enclaver::proxy::audit::DenialStats;
+    use enclaver::status::{FatalCode, LogStats, LogSubscriberStats, MemoryStats};
     use json::{object, JsonValue};
     use nix::sys::signal::Signal;
     use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader, Lines};
@@ -398,7 +544,7 @@ mod tests {
     use super::{ByteLog, LogCursor};
     use crate::launcher::ExitStatus;
 
-    fn check_log(log: &ByteLog, mut expected: u8) {
+    fn check_log(log: &mut ByteLog, mut expected: u8) {
         // check that the log contents monotonically increase
         let mut c = LogCursor::new();
 
@@ -442,7 +588,7 @@ mod tests {
             quanta += 1;
             logged += data.len();
 
-            check_log(&log, 0);
+            check_log(&mut log, 0);
         }
 
         let mut expected = 0u8;
@@ -457,12 +603,97 @@ mod tests {
             quanta += 1;
 
             expected = expected.wrapping_add(trimmed as u8);
-            check_log(&log, expected);
+            check_log(&mut log, expected);
 
             logged += data.len();
         }
     }
 
+    #[test]
+    fn test_byte_log_watch_coalesces() {
+        let mut log = ByteLog::new();
+        let mut w = log.watch();
+
+        log.append(b"foo");
+        log.append(b"bar");
+
+        assert!(w.has_changed().unwrap());
+        assert!(*w.borrow_and_update() == 6);
+        assert!(!w.has_changed().unwrap());
+    }
+
+    #[test]
+    fn test_byte_log_resume() {
+        let mut log = ByteLog::new();
+        let mut buf = vec![0u8; 16];
+
+        log.append(b"foobar");
+        let mut c = log.resume(3);
+        assert!(log.read(&mut c, &mut buf) == 3);
+        assert!(&buf[..3] == b"bar");
+
+        // past the tail, e.g. from a previous boot
+        assert!(log.resume(100).pos == 0);
+
+        // trimmed from the head
+        log.append(&vec![0u8; log.cap()]);
+        assert!(log.resume(3).pos == 6);
+    }
+
+    #[test]
+    fn test_byte_log_stats() {
+        let mut log = ByteLog::new();
+        let mut buf = vec![0u8; 16];
+
+        log.append(b"foobar");
+        let mut first = log.subscribe(2);
+        assert!(log.read(&mut first, &mut buf) == 4);
+
+        // The second subscriber falls behind and loses what was trimmed meanwhile
+        let mut second = log.subscribe(0);
+        assert!(log.read(&mut second, &mut buf[..2]) == 2);
+        log.append(&vec![0u8; log.cap()]);
+        assert!(log.read(&mut second, &mut buf) == 16);
+
+        let stats = log.stats();
+        assert!(stats.produced_bytes == 6 + log.cap() as u64);
+        assert!(stats.trimmed_bytes == 6);
+        assert!(stats.delivered_bytes == 22);
+        assert!(stats.skipped_bytes == 4);
+        assert!(
+            stats.subscribers
+                == [
+                    LogSubscriberStats {
+                        id: 0,
+                        delivered_bytes: 4,
+                        skipped_bytes: 0,
+                    },
+                    LogSubscriberStats {
+                        id: 1,
+                        delivered_bytes: 18,
+                        skipped_bytes: 4,
+                    },
+                ]
+        );
+
+        // Subscribing past the trimmed head counts as skipped too
+        let third = log.subscribe(0);
+        assert!(log.stats().skipped_bytes == 10);
+
+        // Those gone still count towards the totals
+        log.unsubscribe(&first);
+        log.unsubscribe(&second);
+        log.unsubscribe(&third);
+        let stats = log.stats();
+        assert!(stats.subscribers.is_empty());
+        assert!(stats.delivered_bytes == 22);
+
+        // Reads that are not of a subscriber do not count
+        let mut c = LogCursor::new();
+        log.read(&mut c, &mut buf);
+        assert!(log.stats().delivered_bytes == 22);
+    }
+
     #[tokio::test]
     async fn test_app_log() {
         use rand::RngCore;
@@ -528,7 +759,7 @@ mod tests {
         let mut client2 = app_status_lines().await.unwrap();
 
         // Running
-        let mut expected = object! { status: "running" };
+        let mut expected = object! { version: 1, status: "running" };
 
         let mut status = read_json(&mut client1).await.unwrap();
 
@@ -537,9 +768,60 @@ mod tests {
         status = read_json(&mut client2).await.unwrap();
         assert!(status == expected);
 
+        // Running, with the memory odyn uses, how often egress limits kicked in and
+        // what the egress proxies denied
+        let stats = MemoryStats {
+            heap_bytes: 4096,
+            heap_peak_bytes: 8192,
+            buffer_bytes: 1024,
+            buffer_limit_bytes: None,
+        };
+        let limits = vec![LimitStats {
+            rule: "upload.example.com".to_string(),
+            policy: None,
+            dropped: 3,
+            throttled: 5,
+        }];
+        let denials = vec![DenialStats {
+            policy: Some("uploads".to_string()),
+            denied: 7,
+        }];
+        let log = LogStats {
+            produced_bytes: 300,
+            trimmed_bytes: 100,
+            delivered_bytes: 250,
+            skipped_bytes: 50,
+            subscribers: vec![LogSubscriberStats {
+                id: 1,
+                delivered_bytes: 200,
+                skipped_bytes: 0,
+            }],
+        };
+        app_status.report(stats, limits.clone(), denials.clone(), Some(log.clone()));
+        expected = object! {
+            version: 1,
+            status: "running",
+            memory: { heap_bytes: 4096, heap_peak_bytes: 8192, buffer_bytes: 1024 },
+            egress_limits: [{ rule: "upload.example.com", dropped: 3, throttled: 5 }],
+            egress_denials: [{ policy: "uploads", denied: 7 }],
+            app_log: {
+                produced_bytes: 300,
+                trimmed_bytes: 100,
+                delivered_bytes: 250,
+                skipped_bytes: 50,
+                subscribers: [{ id: 1, delivered_bytes: 200, skipped_bytes: 0 }],
+            },
+        };
+
+        status = read_json(&mut client1).await.unwrap();
+        assert!(status == expected);
+
+        status = read_json(&mut client2).await.unwrap();
+        assert!(status == expected);
+
         // Exited
         app_status.exited(ExitStatus::Exited(2));
-        expected = object! { status: "exited", code: 2 };
+        expected = object! { version: 1, status: "exited", code: 2 };
 
         status = read_json(&mut client1).await.unwrap();
         assert!(status == expected);
@@ -549,7 +831,29 @@ mod tests {
 
         // Signaled
         app_status.exited(ExitStatus::Signaled(Signal::SIGTERM));
-        expected = object! { status: "signaled", signal: "SIGTERM" };
+        expected = object! { version: 1, status: "signaled", signal: "SIGTERM" };
+
+        status = read_json(&mut client1).await.unwrap();
+        assert!(status == expected);
+
+        status = read_json(&mut client2).await.unwrap();
+        assert!(status == expected);
+
+        // Nothing is reported any more once the entrypoint is done
+        app_status.report(stats, limits, denials, Some(log));
+        assert!(matches!(
+            *app_status.status.borrow(),
+            super::EnclaveStatus::Signaled { .. }
+        ));
+
+        // Fatal, with an error that needs escaping
+        app_status.fatal(FatalCode::ConfigError, "invalid \"egress\"".to_string());
+        expected = object! {
+            version: 1,
+            status: "fatal",
+            code: "config_error",
+            error: "invalid \"egress\"",
+        };
 
         status = read_json(&mut client1).await.unwrap();
         assert!(status == expected);
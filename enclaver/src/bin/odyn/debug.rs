@@ -0,0 +1,62 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use anyhow::Result;
+use futures::StreamExt;
+use http::Uri;
+use log::{error, info};
+use tokio::task::JoinHandle;
+
+use enclaver::bench;
+use enclaver::connections::{self, ConnectionTable};
+use enclaver::constants::{DEBUG_BENCH_PORT, DEBUG_CONNECTIONS_PORT};
+
+pub struct DebugService {
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl DebugService {
+    /// Lists the connections in the table to the host, for `enclaver debug conns`, and
+    /// runs `enclaver bench egress` through the egress proxy. There is only a table in
+    /// debug mode.
+    pub fn start(connections: Option<ConnectionTable>, egress_proxy: Option<Uri>) -> Result<Self> {
+        let connections = match connections {
+            Some(connections) => connections,
+            None => return Ok(Self { tasks: Vec::new() }),
+        };
+
+        info!("Listing connections on vsock port {DEBUG_CONNECTIONS_PORT}");
+        let mut incoming = enclaver::vsock::serve(DEBUG_CONNECTIONS_PORT)?;
+
+        let mut tasks = vec![tokio::task::spawn(async move {
+            while let Some(mut conn) = incoming.next().await {
+                if let Err(err) = connections::reply(&mut conn, &connections).await {
+                    error!("failed to list connections: {err}");
+                }
+            }
+        })];
+
+        // One benchmark at a time, so that they do not skew each other
+        if let Some(port) = egress_proxy.and_then(|uri| uri.port_u16()) {
+            info!("Running egress benchmarks on vsock port {DEBUG_BENCH_PORT}");
+            let mut incoming = enclaver::vsock::serve(DEBUG_BENCH_PORT)?;
+            let proxy = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+
+            tasks.push(tokio::task::spawn(async move {
+                while let Some(mut conn) = incoming.next().await {
+                    if let Err(err) = bench::reply(&mut conn, proxy).await {
+                        error!("failed to run an egress benchmark: {err}");
+                    }
+                }
+            }));
+        }
+
+        Ok(Self { tasks })
+    }
+
+    pub async fn stop(self) {
+        for task in self.tasks {
+            task.abort();
+            _ = task.await;
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use anyhow::Result;
+use futures::StreamExt;
+use log::{error, info};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use tokio::task::JoinHandle;
+
+use enclaver::constants::DRAIN_PORT;
+use enclaver::proxy::drain::{self, DrainReply};
+
+pub struct DrainService {
+    task: JoinHandle<()>,
+}
+
+impl DrainService {
+    /// Sends SIGTERM to the process group of the app when the host drains the enclave,
+    /// so it can finish serving the connections it has open before it is terminated
+    pub fn start(app: Pid) -> Result<Self> {
+        info!("Listening for drains on vsock port {DRAIN_PORT}");
+        let mut incoming = enclaver::vsock::serve(DRAIN_PORT)?;
+
+        let task = tokio::task::spawn(async move {
+            while let Some(mut conn) = incoming.next().await {
+                info!("Draining, sending SIGTERM to the app");
+                let result = match killpg(app, Signal::SIGTERM) {
+                    Ok(()) => DrainReply::Signaled,
+                    Err(err) => DrainReply::Failed {
+                        error: err.to_string(),
+                    },
+                };
+
+                if let Err(err) = drain::reply(&mut conn, &result).await {
+                    error!("failed to answer a drain: {err}");
+                }
+            }
+        });
+
+        Ok(Self { task })
+    }
+
+    pub async fn stop(self) {
+        self.task.abort();
+        _ = self.task.await;
+    }
+}
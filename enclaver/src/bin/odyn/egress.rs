@@ -1,47 +1,440 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
 
-use anyhow::Result;
-use log::info;
+use anyhow::{anyhow, Result};
+use http::Uri;
+use log::{error, info};
+use tokio::process::Command;
 use tokio::task::JoinHandle;
 
 use crate::config::Configuration;
-use enclaver::constants::HTTP_EGRESS_VSOCK_PORT;
+use crate::health::ServiceTasks;
+use enclaver::connections::ConnectionTable;
+use enclaver::constants::{
+    DNS_VSOCK_PORT, EGRESS_AUDIT_PORT, HTTP_EGRESS_VSOCK_PORT, TRANSPARENT_EGRESS_PORT,
+    UDP_EGRESS_VSOCK_PORT,
+};
+use enclaver::manifest::{Egress, ForwardTls, IMDS_RELAY_POLICY};
+use enclaver::policy::wasm::{WasmHook, DEFAULT_FUEL};
 use enclaver::policy::EgressPolicy;
+use enclaver::proxy::audit::AuditLog;
+use enclaver::proxy::dns::EnclaveDnsForwarder;
 use enclaver::proxy::egress_http::EnclaveHttpProxy;
+use enclaver::proxy::forward::{ClientCredentials, CredentialFiles, EgressForwarder, OriginateTls};
+use enclaver::proxy::host_relays::HostRelays;
+use enclaver::proxy::revoke::Revoker;
+use enclaver::proxy::synthetic_dns::{SyntheticDns, SyntheticNames};
+use enclaver::proxy::transparent::TransparentProxy;
+use enclaver::proxy::udp::EnclaveUdpRelay;
+use enclaver::spiffe::SvidStore;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const DNS_PORT: u16 = 53;
+const RT_SCOPE_LINK: u8 = 253;
 
 pub struct EgressService {
-    proxy: Option<JoinHandle<()>>,
+    proxies: Vec<JoinHandle<()>>,
+    policy: Option<Arc<EgressPolicy>>,
+    named_policies: Vec<Arc<EgressPolicy>>,
+    audit: AuditLog,
+    revoker: Revoker,
+    imds_proxy_uri: Option<Uri>,
 }
 
 impl EgressService {
-    pub async fn start(config: &Configuration) -> Result<Self> {
-        let task = if let Some(proxy_uri) = config.egress_proxy_uri() {
-            info!("Starting egress");
+    pub async fn start(
+        config: &Configuration,
+        svids: &SvidStore,
+        hook: Option<Arc<WasmHook>>,
+        connections: Option<ConnectionTable>,
+    ) -> Result<Self> {
+        let mut proxies = Vec::new();
+        let mut named_policies = Vec::new();
+
+        // Decisions of all the HTTP proxies, streamed to the host
+        let audit = AuditLog::default();
 
-            let policy = Arc::new(EgressPolicy::new(config.manifest.egress.as_ref().unwrap()));
+        // Shared by everything but the named proxies, so that narrowing it at runtime
+        // applies to all of them at once
+        let policy = config
+            .manifest
+            .egress
+            .as_ref()
+            .map(|egress| Arc::new(with_hook(EgressPolicy::new(egress), &hook)));
+
+        // The tunnels of that policy, for policy updates to revoke
+        let revoker = Revoker::new(
+            config
+                .manifest
+                .egress
+                .as_ref()
+                .and_then(|egress| egress.revoked_connections.as_ref()),
+        )
+        .with_audit(audit.clone());
+
+        // Every proxy connects out through the same relays, and fails over together
+        let relays = HostRelays::new(
+            config
+                .manifest
+                .egress
+                .as_ref()
+                .map_or(vec![HTTP_EGRESS_VSOCK_PORT], Egress::relay_ports),
+        );
+
+        if let (Some(proxy_uri), Some(policy)) = (config.egress_proxy_uri(), &policy) {
+            info!("Starting egress");
 
             set_proxy_env_var(&proxy_uri.to_string());
 
-            let proxy = EnclaveHttpProxy::bind(proxy_uri.port_u16().unwrap()).await?;
+            let proxy = start_proxy(
+                &proxy_uri,
+                relays.clone(),
+                policy.clone(),
+                &audit,
+                connections.clone(),
+                Some(revoker.clone()),
+            );
+            proxies.push(proxy.await?);
+        }
+
+        if let (Some(egress), Some(policy)) = (config.manifest.egress.as_ref(), &policy) {
+            if egress.is_transparent() {
+                info!("Starting transparent egress on port {TRANSPARENT_EGRESS_PORT}");
+
+                proxies.extend(
+                    start_transparent(relays.clone(), policy.clone(), revoker.clone()).await?,
+                );
+            }
+
+            if egress.is_dns_enabled() {
+                info!("Starting DNS forwarder");
+
+                proxies.push(start_dns_forwarder(egress, policy.clone()).await?);
+            }
+
+            for forward in config.manifest.egress_forwards() {
+                info!(
+                    "Forwarding port {} to {}:{}",
+                    forward.local_port, forward.host, forward.port
+                );
+
+                let mut forwarder = EgressForwarder::bind(forward)
+                    .await?
+                    .with_revoker(revoker.clone());
+                if let Some(ref tls) = forward.tls {
+                    info!("Originating TLS to {}:{}", forward.host, forward.port);
+                    forwarder = forwarder.with_tls(originate_tls(tls, svids));
+                }
+
+                let (relays, policy) = (relays.clone(), policy.clone());
+                proxies.push(tokio::task::spawn(async move {
+                    forwarder.serve(relays, policy).await;
+                }));
+            }
+        }
+
+        // The host checks these against the policy, on a vsock port of their own
+        for rule in config.manifest.egress_udp() {
+            info!(
+                "Relaying UDP port {} to {}:{}",
+                rule.local_port, rule.host, rule.port
+            );
+
+            let relay = EnclaveUdpRelay::bind(rule).await?;
+            proxies.push(tokio::task::spawn(async move {
+                relay.serve(UDP_EGRESS_VSOCK_PORT).await;
+            }));
+        }
+
+        // All of them funnel through the same host relay, which records the policy name
+        for (proxy, proxy_uri) in config.named_egress_proxies() {
+            info!("Starting egress proxy {} on {proxy_uri}", proxy.name);
+
+            let policy = EgressPolicy::new(&proxy.policy()).with_name(&proxy.name);
+            let policy = Arc::new(with_hook(policy, &hook));
 
-            Some(tokio::task::spawn(async move {
-                proxy.serve(HTTP_EGRESS_VSOCK_PORT, policy).await;
-            }))
-        } else {
-            None
-        };
+            std::env::set_var(named_proxy_env_var(&proxy.name), proxy_uri.to_string());
 
-        Ok(Self { proxy: task })
+            let proxy = start_proxy(
+                &proxy_uri,
+                relays.clone(),
+                policy.clone(),
+                &audit,
+                connections.clone(),
+                None,
+            );
+            proxies.push(proxy.await?);
+            named_policies.push(policy);
+        }
+
+        // Only odyn itself is told where this one listens, for the IMDS relay and the
+        // AWS proxies
+        let mut imds_proxy_uri = None;
+        if let Some(relay) = config
+            .manifest
+            .egress
+            .as_ref()
+            .and_then(|egress| egress.imds_relay_policy())
+        {
+            let policy = Arc::new(EgressPolicy::new(&relay).with_name(IMDS_RELAY_POLICY));
+            let proxy = EnclaveHttpProxy::bind(0)
+                .await?
+                .with_audit(audit.clone())
+                .with_connection_table(connections.clone());
+            let uri: Uri = format!("http://{}", proxy.local_addr()?).parse()?;
+            info!("Starting the egress proxy of the IMDS relay on {uri}");
+
+            let (relays, serving) = (relays.clone(), policy.clone());
+            proxies.push(tokio::task::spawn(async move {
+                proxy.serve(relays, serving).await;
+            }));
+            named_policies.push(policy);
+            imds_proxy_uri = Some(uri);
+        }
+
+        if policy.is_some() && relays.has_standby() {
+            info!("Checking the health of the egress relays");
+
+            proxies.push(tokio::task::spawn(relays.check()));
+        }
+
+        if config.egress_proxy_uri().is_some() || !named_policies.is_empty() {
+            info!("Serving the egress audit log on vsock port {EGRESS_AUDIT_PORT}");
+
+            let audit = audit.clone();
+            proxies.push(tokio::task::spawn(async move {
+                if let Err(err) = audit.serve(EGRESS_AUDIT_PORT).await {
+                    error!("failed to serve the egress audit log: {err}");
+                }
+            }));
+        }
+
+        Ok(Self {
+            proxies,
+            policy,
+            named_policies,
+            audit,
+            revoker,
+            imds_proxy_uri,
+        })
+    }
+
+    /// The proxy that odyn reaches IMDS through, if the IMDS relay is enabled
+    pub fn imds_proxy_uri(&self) -> Option<Uri> {
+        self.imds_proxy_uri.clone()
+    }
+
+    /// The policy of the default egress, which policy updates narrow. Those of the
+    /// named proxies and the IMDS relay are left as the manifest has them.
+    pub fn policy(&self) -> Option<Arc<EgressPolicy>> {
+        self.policy.clone()
+    }
+
+    /// The open tunnels of the default egress, which policy updates may revoke
+    pub fn revoker(&self) -> Revoker {
+        self.revoker.clone()
+    }
+
+    /// The policies of the default egress and of the named proxies
+    pub fn policies(&self) -> Vec<Arc<EgressPolicy>> {
+        self.policy
+            .iter()
+            .chain(self.named_policies.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// The decisions of the HTTP proxies
+    pub fn audit(&self) -> AuditLog {
+        self.audit.clone()
+    }
+
+    pub fn tasks(&self) -> Option<ServiceTasks> {
+        ServiceTasks::new(&self.proxies)
     }
 
     pub async fn stop(self) {
-        if let Some(proxy) = self.proxy {
+        for proxy in self.proxies {
             proxy.abort();
             _ = proxy.await;
         }
     }
 }
 
+/// Loads the policy hook of the manifest from the image, if there is one. Done ahead
+/// of the egress proxies, so that attestations can carry its digest from the start.
+pub async fn load_policy_hook(config: &Configuration) -> Result<Option<Arc<WasmHook>>> {
+    let Some(hook) = config
+        .manifest
+        .egress
+        .as_ref()
+        .and_then(|egress| egress.policy_hook.as_ref())
+    else {
+        return Ok(None);
+    };
+
+    let wasm = tokio::fs::read(&hook.wasm)
+        .await
+        .map_err(|err| anyhow!("failed to read the policy hook {}: {err}", hook.wasm))?;
+    let hook = WasmHook::new(&wasm, hook.fuel.unwrap_or(DEFAULT_FUEL))?;
+    info!("Loaded the policy hook, sha256 {}", hook.digest());
+
+    Ok(Some(Arc::new(hook)))
+}
+
+// The same hook decides for the default policy and for the named proxies
+fn with_hook(policy: EgressPolicy, hook: &Option<Arc<WasmHook>>) -> EgressPolicy {
+    match hook {
+        Some(hook) => policy.with_hook(hook.clone()),
+        None => policy,
+    }
+}
+
+async fn start_proxy(
+    proxy_uri: &Uri,
+    relays: HostRelays,
+    policy: Arc<EgressPolicy>,
+    audit: &AuditLog,
+    connections: Option<ConnectionTable>,
+    revoker: Option<Revoker>,
+) -> Result<JoinHandle<()>> {
+    let mut proxy = EnclaveHttpProxy::bind(proxy_uri.port_u16().unwrap())
+        .await?
+        .with_audit(audit.clone())
+        .with_connection_table(connections);
+    if let Some(revoker) = revoker {
+        proxy = proxy.with_revoker(revoker);
+    }
+
+    Ok(tokio::task::spawn(async move {
+        proxy.serve(relays, policy).await;
+    }))
+}
+
+// The manifest makes sure there is either the SVID or both files. The SVID is only
+// issued once egress is up, which is fine as it is looked up on every connection.
+fn originate_tls(tls: &ForwardTls, svids: &SvidStore) -> OriginateTls {
+    let credentials: Arc<dyn ClientCredentials> = match (&tls.cert_file, &tls.key_file) {
+        (Some(cert_file), Some(key_file)) if !tls.uses_svid() => Arc::new(CredentialFiles {
+            cert_file: PathBuf::from(cert_file),
+            key_file: PathBuf::from(key_file),
+        }),
+        _ => Arc::new(svids.clone()),
+    };
+
+    OriginateTls::new(credentials, tls.ca_file.as_ref().map(PathBuf::from))
+}
+
+// Resolves every name to a synthetic address, and sends every TCP connection that
+// is not to localhost to the transparent proxy, which recovers the name
+async fn start_transparent(
+    relays: HostRelays,
+    policy: Arc<EgressPolicy>,
+    revoker: Revoker,
+) -> Result<Vec<JoinHandle<()>>> {
+    let names = SyntheticNames::default();
+
+    let dns = SyntheticDns::bind(
+        SocketAddr::from((Ipv4Addr::LOCALHOST, DNS_PORT)),
+        names.clone(),
+    )
+    .await?;
+    let proxy = TransparentProxy::bind(TRANSPARENT_EGRESS_PORT, names)
+        .await?
+        .with_revoker(revoker);
+
+    route_via_lo().await?;
+    redirect_tcp(TRANSPARENT_EGRESS_PORT).await?;
+    use_local_dns()?;
+
+    Ok(vec![
+        tokio::task::spawn(dns.serve()),
+        tokio::task::spawn(async move {
+            proxy.serve(relays, policy).await;
+        }),
+    ])
+}
+
+async fn start_dns_forwarder(egress: &Egress, policy: Arc<EgressPolicy>) -> Result<JoinHandle<()>> {
+    let forwarder = EnclaveDnsForwarder::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, DNS_PORT)))
+        .await?
+        .with_host_names(egress.host_names(), egress.host_address());
+
+    use_local_dns()?;
+
+    Ok(tokio::task::spawn(async move {
+        forwarder.serve(DNS_VSOCK_PORT, policy).await;
+    }))
+}
+
+// Points the resolver of the application at the DNS server odyn runs on localhost
+fn use_local_dns() -> Result<()> {
+    Ok(std::fs::write(RESOLV_CONF, "nameserver 127.0.0.1\n")?)
+}
+
+// Without a route, connecting to anything but localhost fails before the
+// connection ever reaches the REDIRECT rule
+async fn route_via_lo() -> Result<()> {
+    let (conn, handle, _receiver) = rtnetlink::new_connection()?;
+    let conn_task = tokio::spawn(conn);
+
+    // lo is the one and only interface
+    let result = handle
+        .route()
+        .add()
+        .v4()
+        .output_interface(1)
+        .scope(RT_SCOPE_LINK)
+        .execute()
+        .await;
+
+    conn_task.abort();
+    _ = conn_task.await;
+
+    Ok(result?)
+}
+
+async fn redirect_tcp(port: u16) -> Result<()> {
+    let port = port.to_string();
+    let status = Command::new("iptables")
+        .args([
+            "-t",
+            "nat",
+            "-A",
+            "OUTPUT",
+            "-p",
+            "tcp",
+            "!",
+            "-d",
+            "127.0.0.0/8",
+        ])
+        .args(["-j", "REDIRECT", "--to-ports", &port])
+        .status()
+        .await
+        .map_err(|e| anyhow!("failed to run iptables, is it in the image? {e}"))?;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "iptables failed to install the REDIRECT rule: {status}"
+        )),
+    }
+}
+
+// e.g. ENCLAVER_EGRESS_PROXY_METRICS_SIDECAR for metrics-sidecar
+fn named_proxy_env_var(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect();
+
+    format!("ENCLAVER_EGRESS_PROXY_{name}")
+}
+
 fn set_proxy_env_var(value: &str) {
     std::env::set_var("http_proxy", value);
     std::env::set_var("https_proxy", value);
@@ -0,0 +1,192 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{error, info};
+use serde_json::{json, Map, Value};
+use tokio::task::{AbortHandle, JoinHandle};
+
+use crate::config::Configuration;
+use crate::console::AppStatus;
+use enclaver::http_util::{self, HttpHandler, HttpServer};
+
+const HEALTH_PATH: &str = "/health";
+
+// The tasks a service runs, none of which ends while the service is healthy
+#[derive(Clone)]
+pub struct ServiceTasks {
+    tasks: Vec<AbortHandle>,
+}
+
+impl ServiceTasks {
+    /// None for a service that runs no tasks, e.g. because it is not configured
+    pub fn new<'a, T: 'a>(tasks: impl IntoIterator<Item = &'a JoinHandle<T>>) -> Option<Self> {
+        let tasks: Vec<_> = tasks.into_iter().map(JoinHandle::abort_handle).collect();
+        (!tasks.is_empty()).then_some(Self { tasks })
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.tasks.iter().all(|task| !task.is_finished())
+    }
+}
+
+struct HealthHandler {
+    app_status: AppStatus,
+    services: Vec<(&'static str, ServiceTasks)>,
+}
+
+impl HealthHandler {
+    fn report(&self) -> Result<Response<Body>> {
+        let mut healthy = self.app_status.is_running();
+
+        let mut services = Map::new();
+        for (name, tasks) in &self.services {
+            let ok = tasks.is_healthy();
+            healthy &= ok;
+            services.insert(name.to_string(), json!(if ok { "ok" } else { "failed" }));
+        }
+
+        let body = json!({
+            "status": if healthy { "ok" } else { "unavailable" },
+            "app": self.app_status.health(),
+            "services": Value::Object(services),
+            "uptime_seconds": uptime_seconds()?,
+        });
+
+        Ok(Response::builder()
+            .status(if healthy {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            })
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))?)
+    }
+}
+
+#[async_trait]
+impl HttpHandler for HealthHandler {
+    async fn handle(&self, req: Request<Body>) -> Result<Response<Body>> {
+        match req.uri().path() {
+            HEALTH_PATH => match *req.method() {
+                Method::GET => self.report(),
+
+                _ => Ok(http_util::method_not_allowed()),
+            },
+
+            _ => Ok(http_util::not_found()),
+        }
+    }
+}
+
+// How long the enclave has been up, as it runs nothing but odyn and the app
+fn uptime_seconds() -> Result<u64> {
+    let uptime = std::fs::read_to_string("/proc/uptime")?;
+    let seconds: f64 = uptime
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .parse()?;
+    Ok(seconds as u64)
+}
+
+// Tells load balancers whether the enclave is up, without them reaching into the app
+pub struct HealthService {
+    task: Option<JoinHandle<()>>,
+}
+
+impl HealthService {
+    pub fn start(
+        config: &Configuration,
+        app_status: AppStatus,
+        services: Vec<(&'static str, Option<ServiceTasks>)>,
+    ) -> Result<Self> {
+        let task = if let Some(port) = config.health_port() {
+            info!("Starting health listener on port {port}");
+
+            let srv = HttpServer::bind(port)?;
+            let handler = HealthHandler {
+                app_status,
+                services: services
+                    .into_iter()
+                    .filter_map(|(name, tasks)| Some((name, tasks?)))
+                    .collect(),
+            };
+
+            Some(tokio::task::spawn(async move {
+                if let Err(err) = srv.serve(handler).await {
+                    error!("Error serving the health listener: {err}");
+                }
+            }))
+        } else {
+            None
+        };
+
+        Ok(Self { task })
+    }
+
+    pub async fn stop(self) {
+        if let Some(task) = self.task {
+            task.abort();
+            _ = task.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HealthHandler, ServiceTasks};
+    use crate::console::AppStatus;
+    use crate::launcher::ExitStatus;
+    use assert2::assert;
+    use enclaver::http_util::HttpHandler;
+    use hyper::{Body, Request, StatusCode};
+
+    async fn get(handler: &HealthHandler, path: &str) -> (StatusCode, serde_json::Value) {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        let resp = handler.handle(req).await.unwrap();
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_health_handler() {
+        let running = tokio::spawn(std::future::pending::<()>());
+        let done = tokio::spawn(async {});
+        while !done.is_finished() {
+            tokio::task::yield_now().await;
+        }
+
+        let app_status = AppStatus::new();
+        let mut handler = HealthHandler {
+            app_status: app_status.clone(),
+            services: vec![("ingress", ServiceTasks::new([&running]).unwrap())],
+        };
+        assert!(ServiceTasks::new(Vec::<&tokio::task::JoinHandle<()>>::new()).is_none());
+
+        let (status, body) = get(&handler, "/health").await;
+        assert!(status == StatusCode::OK);
+        assert!(body["status"] == "ok");
+        assert!(body["app"]["status"] == "running");
+        assert!(body["services"]["ingress"] == "ok");
+        assert!(body["uptime_seconds"].is_u64());
+
+        // A service whose task ended is down, and so is the enclave
+        handler
+            .services
+            .push(("egress", ServiceTasks::new([&done]).unwrap()));
+        let (status, body) = get(&handler, "/health").await;
+        assert!(status == StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body["services"]["egress"] == "failed");
+
+        handler.services.pop();
+        app_status.exited(ExitStatus::Exited(1));
+        let (status, body) = get(&handler, "/health").await;
+        assert!(status == StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body["app"]["status"] == "exited");
+        assert!(body["app"]["code"] == 1);
+
+        assert!(get(&handler, "/").await.0 == StatusCode::NOT_FOUND);
+        running.abort();
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::{anyhow, Result};
+use http::Uri;
+use log::{error, info};
+use tokio::task::JoinHandle;
+
+use enclaver::http_util::HttpServer;
+use enclaver::proxy::aws_util;
+use enclaver::proxy::imds_relay::ImdsRelayHandler;
+
+use crate::config::Configuration;
+
+pub struct ImdsRelayService {
+    relay: Option<JoinHandle<()>>,
+}
+
+impl ImdsRelayService {
+    pub async fn start(config: &Configuration, imds_proxy_uri: Option<Uri>) -> Result<Self> {
+        let Some(relay) = config
+            .manifest
+            .egress
+            .as_ref()
+            .and_then(|egress| egress.imds_relay.as_ref())
+        else {
+            return Ok(Self { relay: None });
+        };
+        let proxy_uri = imds_proxy_uri.ok_or(anyhow!("the IMDS relay has no egress proxy"))?;
+
+        info!("Starting the IMDS relay on port {}", relay.listen_port);
+
+        let imds = aws_util::imds_client_with_proxy(proxy_uri).await?;
+        let server = HttpServer::bind(relay.listen_port)?;
+        let handler = ImdsRelayHandler::new(Box::new(imds), relay.imds().paths());
+
+        // Picked up by the AWS SDKs and CLI in place of the address of IMDS
+        std::env::set_var(
+            "AWS_EC2_METADATA_SERVICE_ENDPOINT",
+            format!("http://127.0.0.1:{}", relay.listen_port),
+        );
+
+        Ok(Self {
+            relay: Some(tokio::task::spawn(async move {
+                if let Err(err) = server.serve(handler).await {
+                    error!("Error serving the IMDS relay: {err}");
+                }
+            })),
+        })
+    }
+
+    pub async fn stop(self) {
+        if let Some(relay) = self.relay {
+            relay.abort();
+            _ = relay.await;
+        }
+    }
+}
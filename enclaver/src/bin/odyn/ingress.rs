@@ -1,43 +1,154 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
 use anyhow::Result;
 use ignore_result::Ignore;
 use log::info;
+use rustls::ServerConfig;
 use tokio::sync::watch;
 use tokio::task::JoinHandle;
 
 use crate::config::{Configuration, ListenerConfig};
+use crate::health::ServiceTasks;
+use enclaver::api::TlsReloader;
+use enclaver::connections::ConnectionTable;
+use enclaver::nsm::{Nsm, NsmAttestationProvider};
 use enclaver::proxy::ingress::EnclaveProxy;
+use enclaver::proxy::keepalive::Keepalive;
+use enclaver::proxy::relay::{BufferBudget, Buffering};
+use enclaver::proxy::well_known::AttestationSource;
+
+// Swaps the certificates of the TLS ingress ports that load theirs from files
+struct TlsReloads {
+    config: Arc<Configuration>,
+    ports: HashMap<u16, watch::Sender<Arc<ServerConfig>>>,
+}
+
+impl TlsReloader for TlsReloads {
+    fn reload(&self) -> Result<Vec<u16>> {
+        // Every port is loaded before any is swapped, so a bad file changes nothing
+        let tls_configs = self.config.reload_tls_server_configs()?;
+
+        let mut ports = Vec::new();
+        for (port, tls_config) in tls_configs {
+            if let Some(updates) = self.ports.get(&port) {
+                updates.send_replace(tls_config);
+                ports.push(port);
+            }
+        }
+        ports.sort_unstable();
+
+        info!("Reloaded the TLS certificates of ingress ports {ports:?}");
+        Ok(ports)
+    }
+}
 
 pub struct IngressService {
     proxies: Vec<JoinHandle<()>>,
     shutdown: watch::Sender<()>,
+    tls_reloads: Option<Arc<TlsReloads>>,
 }
 
 impl IngressService {
-    pub fn start(config: &Configuration) -> Result<Self> {
+    pub fn start(
+        config: &Arc<Configuration>,
+        nsm: Arc<Nsm>,
+        buffer_budget: BufferBudget,
+        connections: Option<ConnectionTable>,
+    ) -> Result<Self> {
         let mut tasks = Vec::new();
+        let mut reloadable = HashMap::new();
+
+        // Attestations carry the same default user_data as those of /v1/attestation
+        let attester: Arc<dyn AttestationSource> = Arc::new(
+            NsmAttestationProvider::new(nsm)
+                .with_default_user_data(config.attestation_user_data.clone()),
+        );
 
         let (tx, rx) = tokio::sync::watch::channel(());
         for (port, cfg) in &config.listener_configs {
-            match cfg {
+            let item = config
+                .manifest
+                .ingress
+                .iter()
+                .flatten()
+                .find(|item| item.listen_port == *port);
+
+            let proxy = match cfg {
                 ListenerConfig::TCP => {
                     info!("Starting TCP ingress on port {}", *port);
-                    let proxy = EnclaveProxy::bind(*port)?;
-                    tasks.push(tokio::spawn(proxy.serve(rx.clone())));
+                    EnclaveProxy::bind(*port)?
                 }
                 ListenerConfig::TLS(tls_cfg) => {
                     info!("Starting TLS ingress on port {}", *port);
-                    let proxy = EnclaveProxy::bind_tls(*port, tls_cfg.clone())?;
-                    tasks.push(tokio::spawn(proxy.serve(rx.clone())));
+                    let tls = item.and_then(|item| item.tls.as_ref());
+                    if tls.is_some_and(|tls| tls.is_reloadable()) {
+                        let (updates, tls_cfgs) = watch::channel(tls_cfg.clone());
+                        reloadable.insert(*port, updates);
+                        EnclaveProxy::bind_reloadable_tls(*port, tls_cfgs)?
+                    } else {
+                        EnclaveProxy::bind_tls(*port, tls_cfg.clone())?
+                    }
                 }
-            }
+            };
+
+            let proxy = proxy
+                .with_target_port(item.map_or(*port, |item| item.target_port()))
+                .with_max_connections(item.and_then(|item| item.max_connections))
+                .with_accepts_per_second(item.and_then(|item| item.accepts_per_second))
+                .with_keepalive(Keepalive::from_manifest(
+                    item.and_then(|item| item.keepalive_seconds),
+                ))
+                .with_buffering(Buffering::from_manifest(
+                    item.and_then(|item| item.buffer_bytes),
+                ))
+                .with_buffer_budget(buffer_budget.clone())
+                .with_access_log(item.is_some_and(|item| item.logs_access()))
+                .with_connection_table(connections.clone())
+                .with_attestation(
+                    item.is_some_and(|item| item.serves_attestation())
+                        .then(|| attester.clone()),
+                )
+                .with_proxy_protocol(
+                    item.and_then(|item| item.tls.as_ref())
+                        .is_some_and(|tls| tls.sends_proxy_protocol()),
+                )
+                .with_sni_routes(
+                    item.and_then(|item| item.tls.as_ref())
+                        .and_then(|tls| tls.routes.as_ref())
+                        .iter()
+                        .flat_map(|routes| routes.iter())
+                        .map(|route| (route.server_name.clone(), route.port))
+                        .collect(),
+                );
+            tasks.push(tokio::spawn(proxy.serve(rx.clone())));
         }
 
+        let tls_reloads = (!reloadable.is_empty()).then(|| {
+            Arc::new(TlsReloads {
+                config: config.clone(),
+                ports: reloadable,
+            })
+        });
+
         Ok(Self {
             proxies: tasks,
             shutdown: tx,
+            tls_reloads,
         })
     }
 
+    // Reloads the certificates of the ports that have files to load them from
+    pub fn tls_reloader(&self) -> Option<Arc<dyn TlsReloader>> {
+        self.tls_reloads
+            .clone()
+            .map(|reloads| reloads as Arc<dyn TlsReloader>)
+    }
+
+    pub fn tasks(&self) -> Option<ServiceTasks> {
+        ServiceTasks::new(&self.proxies)
+    }
+
     pub async fn stop(self) {
         self.shutdown.send(()).ignore();
 
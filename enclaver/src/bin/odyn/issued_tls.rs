@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use log::{error, info};
+use tokio::task::JoinHandle;
+
+use crate::config::Configuration;
+use enclaver::attestation::attested_key_nonce;
+use enclaver::constants::TLS_ISSUANCE_PORT;
+use enclaver::keypair::KeyPair;
+use enclaver::nsm::{AttestationParams, Nsm};
+use enclaver::tls::{self, RenewedCertificate};
+use enclaver::tls_issuance::{self, IssuanceReply, IssuanceRequest};
+
+pub struct IssuedTlsService {
+    csr: Option<String>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl IssuedTlsService {
+    /// Generates the key of the ingress ports with a csr and the request for it, then
+    /// serves the request to the host and takes the chain the CA issued in return.
+    /// The ports fail handshakes until the first chain is installed.
+    pub fn start(config: &Configuration, nsm: Arc<Nsm>) -> Result<Self> {
+        let cert = match config.issued_cert {
+            Some(ref cert) => cert.clone(),
+            None => {
+                return Ok(Self {
+                    csr: None,
+                    task: None,
+                })
+            }
+        };
+
+        let (ports, dns_names) = config.csr_ports();
+        let keypair = KeyPair::generate()?;
+        let public_key = keypair.public_key_as_der()?;
+        let attestation = nsm.attestation(AttestationParams {
+            nonce: Some(attested_key_nonce(&public_key)),
+            user_data: config.attestation_user_data.clone(),
+            public_key: Some(public_key),
+        })?;
+        let csr = tls::certificate_request(&keypair, &dns_names, &attestation)
+            .map_err(|e| anyhow!("failed to make the ingress certificate request: {e}"))?;
+        let csr = tls::pem_encode("CERTIFICATE REQUEST", &csr);
+        info!("Generated the ingress key, waiting for a certificate for {dns_names:?}");
+
+        info!("Serving the ingress certificate request on vsock port {TLS_ISSUANCE_PORT}");
+        let mut incoming = enclaver::vsock::serve(TLS_ISSUANCE_PORT)?;
+
+        let issuer = Issuer {
+            keypair,
+            cert,
+            csr: csr.clone(),
+            ports,
+        };
+        let task = tokio::task::spawn(async move {
+            while let Some(mut conn) = incoming.next().await {
+                let reply = match tls_issuance::read_message(&mut conn).await {
+                    Ok(request) => issuer.answer(request),
+                    Err(err) => IssuanceReply::Rejected {
+                        error: err.to_string(),
+                    },
+                };
+
+                if let Err(err) = tls_issuance::reply(&mut conn, &reply).await {
+                    error!("failed to answer a certificate request: {err}");
+                }
+            }
+        });
+
+        Ok(Self {
+            csr: Some(csr),
+            task: Some(task),
+        })
+    }
+
+    /// The PEM encoded certificate signing request, for /v1/tls/csr
+    pub fn csr(&self) -> Option<String> {
+        self.csr.clone()
+    }
+
+    pub async fn stop(self) {
+        if let Some(task) = self.task {
+            task.abort();
+            _ = task.await;
+        }
+    }
+}
+
+struct Issuer {
+    keypair: KeyPair,
+    cert: RenewedCertificate,
+    csr: String,
+    ports: Vec<u16>,
+}
+
+impl Issuer {
+    fn answer(&self, request: IssuanceRequest) -> IssuanceReply {
+        match request {
+            IssuanceRequest::Csr => IssuanceReply::Csr {
+                csr: self.csr.clone(),
+            },
+            IssuanceRequest::Install { chain } => match self.install(&chain) {
+                Ok(()) => {
+                    info!(
+                        "Installed the issued ingress certificate on {:?}",
+                        self.ports
+                    );
+                    IssuanceReply::Installed {
+                        ports: self.ports.clone(),
+                    }
+                }
+                Err(err) => {
+                    error!("Rejected the issued ingress certificate: {err}");
+                    IssuanceReply::Rejected {
+                        error: err.to_string(),
+                    }
+                }
+            },
+        }
+    }
+
+    // Connections open at the time keep the certificate they were made with
+    fn install(&self, chain: &str) -> Result<()> {
+        let chain = tls::read_issued_chain(&self.keypair, chain.as_bytes())?;
+        self.cert.set_chain(&self.keypair, chain)
+    }
+}
@@ -1,44 +1,60 @@
 use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
-use aws_credential_types::provider::ProvideCredentials;
+use aws_credential_types::cache::{CredentialsCache, ProvideCachedCredentials};
+use aws_credential_types::provider::SharedCredentialsProvider;
+use aws_types::region::Region;
+use http::Uri;
 use log::{error, info};
 use tokio::task::JoinHandle;
 
 use enclaver::http_util::HttpServer;
 use enclaver::keypair::KeyPair;
+use enclaver::manifest::KmsCredentialSource;
 use enclaver::nsm::{Nsm, NsmAttestationProvider};
+use enclaver::proxy::aws_credentials::{self, ContainerCredentialsProvider};
 use enclaver::proxy::aws_util;
 use enclaver::proxy::kms::{KmsProxyConfig, KmsProxyHandler};
 
 use crate::config::Configuration;
+use crate::health::ServiceTasks;
+use crate::secrets;
 
-const NO_EGRESS_ERROR: &str = "KMS proxy is configured but egress is not. Configure egress allow policy to access the IMDS at 169.254.169.254 and the AWS KMS endpoint";
+const NO_EGRESS_ERROR: &str = "KMS proxy is configured but egress is not. Configure egress allow policy to access the IMDS at 169.254.169.254, or egress.imds_relay, and the AWS KMS endpoint";
+
+const DEFAULT_SESSION_NAME: &str = "enclaver";
 
 pub struct KmsProxyService {
     proxy: Option<JoinHandle<()>>,
 }
 
 impl KmsProxyService {
-    pub async fn start(config: Arc<Configuration>, nsm: Arc<Nsm>) -> Result<Self> {
+    /// IMDS is reached through imds_proxy_uri if given, through the egress proxy of
+    /// the app if not
+    pub async fn start(
+        config: Arc<Configuration>,
+        nsm: Arc<Nsm>,
+        imds_proxy_uri: Option<Uri>,
+    ) -> Result<Self> {
         let task = if let Some(port) = config.kms_proxy_port() {
             if let Some(proxy_uri) = config.egress_proxy_uri() {
                 info!("Starting KMS proxy");
-                let attester = Box::new(NsmAttestationProvider::new(nsm));
+                let attester = Box::new(
+                    NsmAttestationProvider::new(nsm)
+                        .with_default_user_data(config.attestation_user_data.clone()),
+                );
 
                 // If a keypair will be needed elsewhere, this should be moved out
                 info!("Generating public/private keypair");
                 let keypair = Arc::new(KeyPair::generate()?);
 
-                let imds = aws_util::imds_client_with_proxy(proxy_uri.clone()).await?;
+                let (provider, region) =
+                    credentials_provider(&config, &proxy_uri, imds_proxy_uri).await?;
 
-                info!("Fetching credentials from IMDSv2");
-                let sdk_config = aws_util::load_config_from_imds(imds).await?;
-                let credentials = sdk_config
-                    .credentials_provider()
-                    .ok_or(anyhow!("credentials provider is missing"))?
-                    .provide_credentials()
-                    .await?;
+                // Refreshed ahead of their expiry, but fetched once now so that a
+                // source that does not work fails the boot
+                let credentials = CredentialsCache::lazy().create_cache(provider);
+                credentials.provide_cached_credentials().await?;
                 info!("Credentials fetched");
 
                 let client = Box::new(enclaver::http_client::new_http_proxy_client(proxy_uri));
@@ -47,12 +63,14 @@ impl KmsProxyService {
                     client,
                     keypair,
                     attester,
-                    endpoints: config,
+                    endpoints: config.clone(),
                 };
 
                 let proxy = HttpServer::bind(port)?;
                 let handler = KmsProxyHandler::new(kms_config);
 
+                decrypt_tls_keys(&config, &handler, region.as_ref()).await?;
+
                 // Set and env var to avoid configuring the port in two places
                 std::env::set_var("AWS_KMS_ENDPOINT", format!("http://127.0.0.1:{port}"));
 
@@ -71,6 +89,10 @@ impl KmsProxyService {
         Ok(Self { proxy: task })
     }
 
+    pub fn tasks(&self) -> Option<ServiceTasks> {
+        ServiceTasks::new(&self.proxy)
+    }
+
     pub async fn stop(self) {
         if let Some(proxy) = self.proxy {
             proxy.abort();
@@ -78,3 +100,108 @@ impl KmsProxyService {
         }
     }
 }
+
+// The credentials the proxy signs with, from the source the manifest names, and the
+// region of KMS. The region comes from the manifest, from the environment of
+// enclaver-run, or from IMDS, in that order.
+async fn credentials_provider(
+    config: &Configuration,
+    proxy_uri: &Uri,
+    imds_proxy_uri: Option<Uri>,
+) -> Result<(SharedCredentialsProvider, Region)> {
+    let kms_credentials = config
+        .manifest
+        .kms_proxy
+        .as_ref()
+        .and_then(|kp| kp.credentials.as_ref());
+    let source = kms_credentials.map_or(KmsCredentialSource::Imds, |c| c.source);
+
+    let region = kms_credentials
+        .and_then(|c| c.region.clone())
+        .map(Region::new)
+        .or_else(|| aws_credentials::env_region(&config.aws_env));
+
+    let sdk_config = if source == KmsCredentialSource::Imds || region.is_none() {
+        let imds_uri = imds_proxy_uri.unwrap_or_else(|| proxy_uri.clone());
+        let imds = aws_util::imds_client_with_proxy(imds_uri).await?;
+        Some(aws_util::load_config_from_imds(imds).await?)
+    } else {
+        None
+    };
+
+    let region = match region.or_else(|| sdk_config.as_ref()?.region().cloned()) {
+        Some(region) => region,
+        None => return Err(anyhow!("region is missing")),
+    };
+
+    let from = match source {
+        KmsCredentialSource::Imds => "IMDSv2",
+        KmsCredentialSource::Env => "the environment of enclaver-run",
+        KmsCredentialSource::Container => "the container credentials endpoint",
+        KmsCredentialSource::WebIdentity => "STS with a web identity token",
+    };
+    info!("Fetching credentials from {from}");
+    let provider = match source {
+        KmsCredentialSource::Imds => sdk_config
+            .as_ref()
+            .and_then(|c| c.credentials_provider())
+            .ok_or(anyhow!("credentials provider is missing"))?
+            .clone(),
+        KmsCredentialSource::Env => {
+            SharedCredentialsProvider::new(aws_credentials::env_credentials(&config.aws_env)?)
+        }
+        KmsCredentialSource::Container => SharedCredentialsProvider::new(
+            ContainerCredentialsProvider::new(&config.aws_env, proxy_uri.clone())?,
+        ),
+        KmsCredentialSource::WebIdentity => {
+            // Validated along with the manifest
+            let c = kms_credentials.ok_or(anyhow!("credentials are missing"))?;
+            let (role_arn, token_from) = match (&c.role_arn, &c.token_from) {
+                (Some(role_arn), Some(token_from)) => (role_arn, token_from),
+                _ => return Err(anyhow!("role_arn and token_from are missing")),
+            };
+
+            SharedCredentialsProvider::new(aws_credentials::web_identity_provider(
+                proxy_uri.clone(),
+                region.clone(),
+                secrets::secret_path(config, token_from),
+                role_arn.clone(),
+                c.session_name
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_SESSION_NAME.to_string()),
+            )?)
+        }
+    };
+
+    Ok((provider, region))
+}
+
+// Decrypts the keys of the TLS ingress ports whose key_file is KMS encrypted, so the
+// ports have a certificate to present by the time ingress starts
+async fn decrypt_tls_keys(
+    config: &Configuration,
+    handler: &KmsProxyHandler,
+    region: &str,
+) -> Result<()> {
+    for (port, cert) in &config.kms_key_certs {
+        let (key_path, cert_path) = config.tls_server_files(*port);
+        info!("Decrypting the TLS key of ingress port {port}");
+
+        let ciphertext = kms_ciphertext(&std::fs::read(&key_path)?);
+        let key = handler
+            .decrypt(region, &ciphertext)
+            .await
+            .map_err(|err| anyhow!("decrypting {}: {err}", key_path.display()))?;
+        cert.set_pem_key(&key, cert_path)?;
+    }
+
+    Ok(())
+}
+
+// A ciphertext blob as written by the AWS CLI, either base64 encoded or decoded
+fn kms_ciphertext(file: &[u8]) -> Vec<u8> {
+    std::str::from_utf8(file)
+        .ok()
+        .and_then(|text| base64::decode(text.trim()).ok())
+        .unwrap_or_else(|| file.to_vec())
+}
@@ -27,8 +27,13 @@ impl std::fmt::Display for ExitStatus {
     }
 }
 
-// runs the child and reaps all of its children as well
-pub fn run_child(argv: &[OsString], creds: &Credentials) -> Result<ExitStatus> {
+// A running child. It leads a process group of its own, with the same ID as its PID.
+pub struct Child {
+    pub pid: Pid,
+    pub exit: JoinHandle<Result<ExitStatus>>,
+}
+
+fn spawn_child(argv: &[OsString], creds: &Credentials) -> Result<Pid> {
     // Don't use tokio::process::Command because it wants to reap the process.
     // However we need to run waitpid() ourselves to reap the zombies and it'll
     // end up picking up the spawned child as well.
@@ -40,14 +45,15 @@ pub fn run_child(argv: &[OsString], creds: &Credentials) -> Result<ExitStatus> {
         .spawn()?;
 
     debug!("Child process started");
-    let child_pid = Pid::from_raw(child.id() as i32);
-
-    reap(child_pid)
+    Ok(Pid::from_raw(child.id() as i32))
 }
 
 // runs the child and reaps all of its children as well
-pub fn start_child(argv: Vec<OsString>, creds: Credentials) -> JoinHandle<Result<ExitStatus>> {
-    tokio::task::spawn_blocking(move || run_child(&argv, &creds))
+pub fn start_child(argv: Vec<OsString>, creds: Credentials) -> Result<Child> {
+    let pid = spawn_child(&argv, &creds)?;
+    let exit = tokio::task::spawn_blocking(move || reap(pid));
+
+    Ok(Child { pid, exit })
 }
 
 // Reap processes until a process with sentinel pid exits.
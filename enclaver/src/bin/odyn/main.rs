@@ -1,29 +1,67 @@
 #![allow(clippy::new_without_default)]
 
 pub mod api;
+pub mod attested_config;
+pub mod attested_tls;
 pub mod config;
 pub mod console;
+pub mod debug;
+pub mod drain;
 pub mod egress;
 pub mod enclave;
+pub mod health;
+pub mod imds_relay;
 pub mod ingress;
+pub mod issued_tls;
 pub mod kms_proxy;
 pub mod launcher;
+pub mod memory;
+pub mod policy_update;
+pub mod runtime_config;
+pub mod s3_proxy;
+pub mod secrets;
+pub mod spiffe;
+pub mod stats;
+pub mod status_file;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::Parser;
-use log::{error, info};
+use log::{error, info, warn};
 use std::ffi::OsString;
+use std::path::Path;
 use std::sync::Arc;
 
-use enclaver::constants::{APP_LOG_PORT, STATUS_PORT};
+use enclaver::banner::StartupBanner;
+use enclaver::boot_config::{self, DebugOverrides};
+use enclaver::connections::ConnectionTable;
+use enclaver::constants::{APP_LOG_PORT, MANIFEST_FILE_NAME, STATUS_PORT};
 use enclaver::nsm::Nsm;
+use enclaver::proxy::relay::BufferBudget;
+use enclaver::service_config::ServiceConfig;
+use enclaver::spiffe::SvidStore;
+use enclaver::status::FatalCode;
 
 use api::ApiService;
+use attested_tls::AttestedTlsService;
 use config::Configuration;
-use console::{AppLog, AppStatus};
+use console::{AppLog, AppLogStats, AppStatus};
+use debug::DebugService;
+use drain::DrainService;
 use egress::EgressService;
+use health::HealthService;
+use imds_relay::ImdsRelayService;
 use ingress::IngressService;
+use issued_tls::IssuedTlsService;
 use kms_proxy::KmsProxyService;
+use memory::CountingAllocator;
+use policy_update::PolicyUpdateService;
+use s3_proxy::S3ProxyService;
+use spiffe::SpiffeService;
+use stats::StatsService;
+use status_file::StatusFileService;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator::new();
 
 #[derive(Parser)]
 struct CliArgs {
@@ -43,50 +81,278 @@ struct CliArgs {
     verbosity: u8,
 }
 
-async fn launch(args: &CliArgs) -> Result<launcher::ExitStatus> {
-    let config = Arc::new(Configuration::load(&args.config_dir).await?);
+// A failure to launch the entrypoint, with the stage it failed in
+struct LaunchError {
+    code: FatalCode,
+    error: anyhow::Error,
+}
+
+trait Stage<T> {
+    fn stage(self, code: FatalCode) -> Result<T, LaunchError>;
+}
+
+impl<T, E: Into<anyhow::Error>> Stage<T> for Result<T, E> {
+    fn stage(self, code: FatalCode) -> Result<T, LaunchError> {
+        self.map_err(|err| LaunchError {
+            code,
+            error: err.into(),
+        })
+    }
+}
+
+async fn launch(
+    args: &CliArgs,
+    app_log: Option<AppLogStats>,
+    app_status: &AppStatus,
+) -> Result<launcher::ExitStatus, LaunchError> {
+    use FatalCode::*;
+
+    let console = app_log.is_some();
+
+    let mut config = Configuration::load(&args.config_dir)
+        .await
+        .stage(ConfigError)?;
+
+    // Lets verifiers check that the output of the application cannot leave the enclave
+    if !console {
+        config
+            .tag_attestation("console", serde_json::Value::Bool(false))
+            .stage(ConfigError)?;
+    }
 
     let nsm = Arc::new(Nsm::new());
 
     if !args.no_bootstrap {
-        enclave::bootstrap(nsm.clone()).await?;
+        enclave::bootstrap(nsm.clone())
+            .await
+            .stage(BootstrapFailed)?;
         info!("Enclave initialized");
+
+        check_debug_mode(&config, &nsm).stage(ConfigError)?;
+    }
+
+    if config.manifest.is_debug() {
+        config
+            .tag_attestation("debug", serde_json::Value::Bool(true))
+            .stage(ConfigError)?;
     }
 
-    let egress = EgressService::start(&config).await?;
-    let ingress = IngressService::start(&config)?;
-    let kms_proxy = KmsProxyService::start(config.clone(), nsm.clone()).await?;
-    let api = ApiService::start(&config, nsm.clone())?;
+    if let Some(boot_config) = boot_config::fetch().await.stage(ConfigError)? {
+        if let Some(overrides) = boot_config.debug_overrides {
+            apply_debug_overrides(&mut config, &nsm, &overrides).stage(ConfigError)?;
+        }
+
+        if let Some(rc) = boot_config.runtime_config {
+            runtime_config::apply(&mut config, &nsm, &rc).stage(ConfigError)?;
+        }
+
+        config.aws_env = boot_config.aws_env;
+    }
+
+    // Lets verifiers check what the enclave runs with, not only which image it is
+    let services = ServiceConfig::new(&config.manifest, console);
+    config
+        .tag_attestation(
+            "manifest",
+            serde_json::Value::String(config.manifest_digest.clone()),
+        )
+        .stage(ConfigError)?;
+    config
+        .tag_attestation("services", serde_json::Value::String(services.digest()))
+        .stage(ConfigError)?;
+
+    // The module is not part of the manifest, only its path is
+    let policy_hook = egress::load_policy_hook(&config).await.stage(ConfigError)?;
+    if let Some(ref hook) = policy_hook {
+        config
+            .tag_attestation(
+                "policy_hook",
+                serde_json::Value::String(hook.digest().to_string()),
+            )
+            .stage(ConfigError)?;
+    }
+
+    // The app log says what it came from, for whoever reads it later
+    let banner = startup_banner(&config, &nsm, !args.no_bootstrap).stage(BootstrapFailed)?;
+    println!("{}", banner.to_line());
+
+    let sealed_files = attested_config::fetch(&config, &nsm)
+        .await
+        .stage(BootstrapFailed)?;
+
+    let config = Arc::new(config);
+
+    // Only a debug build lists what it is connected to, e.g. to find leaked connections,
+    // and runs egress benchmarks for the host
+    let connections = config.manifest.is_debug().then(ConnectionTable::default);
+    let debug = DebugService::start(connections.clone(), config.egress_proxy_uri())
+        .stage(ServiceStartFailed)?;
+
+    let svids = SvidStore::default();
+    let egress = EgressService::start(&config, &svids, policy_hook, connections.clone())
+        .await
+        .stage(ServiceStartFailed)?;
+    let policy_update = PolicyUpdateService::start(&config, egress.policy(), egress.revoker())
+        .stage(ServiceStartFailed)?;
+    secrets::fetch_all(&config, &sealed_files)
+        .await
+        .stage(BootstrapFailed)?;
+    let spiffe = SpiffeService::start(&config, nsm.clone(), svids.clone())
+        .await
+        .stage(ServiceStartFailed)?;
+    let buffer_budget = BufferBudget::from_manifest(
+        config
+            .manifest
+            .defaults
+            .as_ref()
+            .and_then(|d| d.proxy_buffer_mb),
+    );
+    let stats = StatsService::start(
+        &ALLOCATOR,
+        buffer_budget.clone(),
+        egress.policies(),
+        egress.audit(),
+        app_log,
+        app_status.clone(),
+    );
+    let attested_tls = AttestedTlsService::start(&config, nsm.clone()).stage(ServiceStartFailed)?;
+    let issued_tls = IssuedTlsService::start(&config, nsm.clone()).stage(ServiceStartFailed)?;
+    // The KMS proxy decrypts the keys of the ingress ports that have them encrypted
+    let kms_proxy = KmsProxyService::start(config.clone(), nsm.clone(), egress.imds_proxy_uri())
+        .await
+        .stage(ServiceStartFailed)?;
+    let ingress = IngressService::start(&config, nsm.clone(), buffer_budget, connections.clone())
+        .stage(ServiceStartFailed)?;
+    let services = vec![
+        ("egress", egress.tasks()),
+        ("ingress", ingress.tasks()),
+        ("kms_proxy", kms_proxy.tasks()),
+    ];
+    let health = HealthService::start(&config, app_status.clone(), services.clone())
+        .stage(ServiceStartFailed)?;
+    let s3_proxy = S3ProxyService::start(config.clone(), egress.imds_proxy_uri())
+        .await
+        .stage(ServiceStartFailed)?;
+    let imds_relay = ImdsRelayService::start(&config, egress.imds_proxy_uri())
+        .await
+        .stage(ServiceStartFailed)?;
+    let api = ApiService::start(
+        &config,
+        nsm.clone(),
+        svids,
+        ingress.tls_reloader(),
+        issued_tls.csr(),
+        connections,
+    )
+    .stage(ServiceStartFailed)?;
+    let status_file = StatusFileService::start(&config, services, policy_update.applied())
+        .stage(ServiceStartFailed)?;
 
     let creds = launcher::Credentials { uid: 0, gid: 0 };
 
     info!("Starting {:?}", args.entrypoint);
-    let exit_status = launcher::start_child(args.entrypoint.clone(), creds).await??;
+    let child =
+        launcher::start_child(args.entrypoint.clone(), creds).stage(EntrypointSpawnFailed)?;
+    let drain = DrainService::start(child.pid).stage(ServiceStartFailed)?;
+    let exit_status = child
+        .exit
+        .await
+        .stage(EntrypointSpawnFailed)?
+        .stage(EntrypointSpawnFailed)?;
     info!("Entrypoint {}", exit_status);
 
+    drain.stop().await;
+    status_file.stop().await;
     api.stop().await;
-    kms_proxy.stop().await;
+    imds_relay.stop().await;
+    s3_proxy.stop().await;
+    health.stop().await;
     ingress.stop().await;
+    kms_proxy.stop().await;
+    issued_tls.stop().await;
+    attested_tls.stop().await;
+    stats.stop().await;
+    spiffe.stop().await;
+    policy_update.stop().await;
     egress.stop().await;
+    debug.stop().await;
 
     Ok(exit_status)
 }
 
+// The host chooses whether to start the enclave in debug mode, so check it got that
+// right. A production image must never run with a readable console, and a debug
+// image must never hold production secrets.
+fn check_debug_mode(config: &Configuration, nsm: &Nsm) -> Result<()> {
+    match (nsm.is_debug_mode()?, config.manifest.is_debug()) {
+        (true, false) => Err(anyhow!("refusing to run a production image in debug mode")),
+        (false, true) => Err(anyhow!(
+            "refusing to run a debug image outside of debug mode"
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Describes the enclave as configured, once the boot config has been applied. The
+// PCRs are only known once the enclave is bootstrapped.
+fn startup_banner(config: &Configuration, nsm: &Nsm, bootstrapped: bool) -> Result<StartupBanner> {
+    let banner =
+        StartupBanner::new(&config.manifest).with_egress_proxy_port(config.egress_proxy_port);
+    if !bootstrapped {
+        return Ok(banner);
+    }
+
+    let mut pcrs: [String; 3] = Default::default();
+    for (index, pcr) in pcrs.iter_mut().enumerate() {
+        *pcr = nsm
+            .describe_pcr(index as u16)?
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+    }
+
+    Ok(banner.with_pcrs(pcrs))
+}
+
+fn apply_debug_overrides(
+    config: &mut Configuration,
+    nsm: &Nsm,
+    overrides: &DebugOverrides,
+) -> Result<()> {
+    // Never trust the host to relax the policy of a production enclave
+    match nsm.is_debug_mode() {
+        Ok(true) => {
+            warn!(
+                "DEBUG OVERRIDES ACTIVE, the manifest is being relaxed at runtime: {overrides:?}"
+            );
+            config.apply_debug_overrides(overrides)?;
+        }
+        Ok(false) => warn!("ignoring debug overrides, enclave is not running in debug mode"),
+        Err(err) => warn!("ignoring debug overrides, unable to determine debug mode: {err}"),
+    }
+
+    Ok(())
+}
+
 async fn run(args: &CliArgs) -> Result<()> {
     // Start the status and logs listeners ASAP so that if we fail to
     // initialize, we can communicate the status and stream the logs
     let app_status = AppStatus::new();
     let app_status_task = app_status.start_serving(STATUS_PORT);
 
+    let console = !args.no_console && console_enabled(&args.config_dir).await;
+
     let mut console_task = None;
-    if !args.no_console {
+    let mut app_log_stats = None;
+    if console {
         let app_log = AppLog::with_stdio_redirect()?;
+        app_log_stats = Some(app_log.stats());
         console_task = Some(app_log.start_serving(APP_LOG_PORT));
     }
 
-    match launch(args).await {
+    match launch(args, app_log_stats, &app_status).await {
         Ok(exit_status) => app_status.exited(exit_status),
-        Err(err) => app_status.fatal(err.to_string()),
+        Err(err) => app_status.fatal(err.code, err.error.to_string()),
     };
 
     app_status_task.await??;
@@ -99,6 +365,16 @@ async fn run(args: &CliArgs) -> Result<()> {
     Ok(())
 }
 
+// The manifest can disable the console too. If it cannot be read, launching fails
+// anyway, and the console is the only way to tell why.
+async fn console_enabled(config_dir: &str) -> bool {
+    let path = Path::new(config_dir).join(MANIFEST_FILE_NAME);
+    match enclaver::manifest::load_manifest(path).await {
+        Ok(manifest) => manifest.console_enabled(),
+        Err(_) => true,
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args = CliArgs::parse();
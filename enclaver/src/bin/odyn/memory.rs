@@ -0,0 +1,114 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use enclaver::proxy::relay::BufferBudget;
+use enclaver::status::MemoryStats;
+
+// odyn shares the memory of the enclave with the app, so it keeps count of what it
+// takes for itself
+pub struct CountingAllocator {
+    allocated: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl CountingAllocator {
+    pub const fn new() -> Self {
+        Self {
+            allocated: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, size: usize) {
+        let allocated = self.allocated.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak.fetch_max(allocated, Ordering::Relaxed);
+    }
+
+    fn sub(&self, size: usize) {
+        self.allocated.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            self.add(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            self.add(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        self.sub(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            self.sub(layout.size());
+            self.add(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// What odyn holds of the memory of the enclave, and how much of it the stream
+/// buffers of the ingress proxies take
+pub fn stats(allocator: &CountingAllocator, budget: &BufferBudget) -> MemoryStats {
+    MemoryStats {
+        heap_bytes: allocator.allocated() as u64,
+        heap_peak_bytes: allocator.peak() as u64,
+        buffer_bytes: budget.used() as u64,
+        buffer_limit_bytes: budget.limit().map(|limit| limit as u64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{stats, CountingAllocator};
+    use assert2::assert;
+    use enclaver::proxy::relay::{BufferBudget, Buffering};
+    use std::alloc::{GlobalAlloc, Layout};
+
+    #[test]
+    fn test_counting_allocator() {
+        let allocator = CountingAllocator::new();
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            assert!(allocator.allocated() == 1024);
+
+            let ptr = allocator.realloc(ptr, layout, 4096);
+            assert!(allocator.allocated() == 4096);
+
+            allocator.dealloc(ptr, Layout::from_size_align(4096, 8).unwrap());
+        }
+
+        assert!(allocator.allocated() == 0);
+        assert!(allocator.peak() == 4096);
+
+        let budget = BufferBudget::new(Some(1024 * 1024));
+        let _reservation = budget.reserve(Buffering::new(8192)).unwrap();
+        let stats = stats(&allocator, &budget);
+        assert!(stats.buffer_bytes == 16384);
+        assert!(stats.buffer_limit_bytes == Some(1024 * 1024));
+    }
+}
@@ -0,0 +1,188 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use log::{error, info};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::config::Configuration;
+use enclaver::constants::POLICY_UPDATE_PORT;
+use enclaver::policy::EgressPolicy;
+use enclaver::policy_update::{self, PolicyUpdateReply, SignedPolicyUpdate};
+use enclaver::proxy::revoke::Revoker;
+
+// Updates are handled one at a time, so a peer that connects and then stalls must
+// not hold up the ones after it
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The last policy update applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppliedUpdate {
+    pub serial: u64,
+
+    /// Unix time it was applied at, in seconds
+    pub applied_at: u64,
+}
+
+pub struct PolicyUpdateService {
+    task: Option<JoinHandle<()>>,
+    applied: watch::Receiver<Option<AppliedUpdate>>,
+}
+
+impl PolicyUpdateService {
+    /// Accepts signed policy updates from the host, if the manifest has a key to
+    /// verify them with. Each one narrows policy, the policy of the default egress
+    /// only, and has revoker deal with the open tunnels it no longer allows. Serials
+    /// start from the floor of the manifest, as nothing is kept across restarts.
+    pub fn start(
+        config: &Configuration,
+        policy: Option<Arc<EgressPolicy>>,
+        revoker: Revoker,
+    ) -> Result<Self> {
+        let egress = config.manifest.egress.as_ref();
+        let signing_key = match egress.and_then(|e| e.policy_signing_key.clone()) {
+            Some(signing_key) => signing_key,
+            None => {
+                return Ok(Self {
+                    task: None,
+                    applied: watch::channel(None).1,
+                })
+            }
+        };
+
+        let policy = policy.ok_or_else(|| {
+            anyhow!("egress.policy_signing_key is set, but no egress is configured")
+        })?;
+
+        info!("Accepting egress policy updates on vsock port {POLICY_UPDATE_PORT}");
+        let mut incoming = enclaver::vsock::serve(POLICY_UPDATE_PORT)?;
+        let (applied_tx, applied) = watch::channel(None);
+        let floor = egress.and_then(|e| e.policy_serial_floor).unwrap_or(0);
+
+        let task = tokio::task::spawn(async move {
+            // Connections are handled one at a time, so serials are compared and
+            // applied atomically
+            let mut serial = floor;
+
+            while let Some(mut conn) = incoming.next().await {
+                let update =
+                    tokio::time::timeout(READ_TIMEOUT, policy_update::read_message(&mut conn));
+                let reply = match update.await {
+                    Ok(Ok(update)) => apply(&signing_key, &policy, &revoker, &update, &mut serial),
+                    Ok(Err(err)) => Err(err),
+                    Err(_) => Err(anyhow!("timed out waiting for the policy update")),
+                };
+
+                let reply = match reply {
+                    Ok(applied) => {
+                        info!("Applied egress policy update {applied}");
+                        applied_tx.send_replace(Some(AppliedUpdate {
+                            serial: applied,
+                            applied_at: SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .map_or(0, |since| since.as_secs()),
+                        }));
+                        PolicyUpdateReply::Applied { serial: applied }
+                    }
+                    Err(err) => {
+                        error!("Rejected egress policy update: {err}");
+                        PolicyUpdateReply::Rejected {
+                            error: err.to_string(),
+                        }
+                    }
+                };
+
+                if let Err(err) = policy_update::reply(&mut conn, &reply).await {
+                    error!("failed to answer a policy update: {err}");
+                }
+            }
+        });
+
+        Ok(Self {
+            task: Some(task),
+            applied,
+        })
+    }
+
+    /// Follows the updates applied
+    pub fn applied(&self) -> watch::Receiver<Option<AppliedUpdate>> {
+        self.applied.clone()
+    }
+
+    pub async fn stop(self) {
+        if let Some(task) = self.task {
+            task.abort();
+            _ = task.await;
+        }
+    }
+}
+
+fn apply(
+    signing_key: &str,
+    policy: &EgressPolicy,
+    revoker: &Revoker,
+    update: &SignedPolicyUpdate,
+    serial: &mut u64,
+) -> Result<u64> {
+    let update = update.verify(signing_key)?;
+
+    if update.serial <= *serial {
+        return Err(anyhow!(
+            "serial {} is not above serial {serial}, the last applied or the floor",
+            update.serial
+        ));
+    }
+
+    policy.restrict(EgressPolicy::new(&update.egress()));
+    *serial = update.serial;
+
+    // Tunnels opened before the update were let through by the policy it replaced
+    revoker.revoke(policy);
+
+    Ok(update.serial)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply;
+    use assert2::assert;
+    use enclaver::keypair::KeyPair;
+    use enclaver::manifest::Egress;
+    use enclaver::policy::EgressPolicy;
+    use enclaver::policy_update::SignedPolicyUpdate;
+    use enclaver::proxy::revoke::Revoker;
+
+    #[test]
+    fn test_apply() {
+        let keypair = KeyPair::generate().unwrap();
+        let public_key = keypair.public_key_as_pem().unwrap();
+
+        let egress = Egress {
+            allow: Some(vec!["**.example.com".to_string()]),
+            ..Default::default()
+        };
+        let policy = EgressPolicy::new(&egress);
+        let named = EgressPolicy::new(&egress).with_name("metrics");
+        let revoker = Revoker::new(None);
+
+        let update =
+            SignedPolicyUpdate::sign(&keypair, b"serial: 5\nallow: [api.example.com]\n").unwrap();
+        let mut serial = 3;
+        assert!(apply(&public_key, &policy, &revoker, &update, &mut serial).unwrap() == 5);
+        assert!(serial == 5);
+        assert!(policy.is_connect_allowed("api.example.com", 443));
+        assert!(!policy.is_connect_allowed("www.example.com", 443));
+
+        // Only the default egress is narrowed
+        assert!(named.is_connect_allowed("www.example.com", 443));
+
+        // Replays, and serials at or below the floor, are refused
+        assert!(apply(&public_key, &policy, &revoker, &update, &mut serial).is_err());
+        let mut serial = 5;
+        let stale = SignedPolicyUpdate::sign(&keypair, b"serial: 4\nallow: [\"**\"]\n").unwrap();
+        assert!(apply(&public_key, &policy, &revoker, &stale, &mut serial).is_err());
+        assert!(!policy.is_connect_allowed("www.example.com", 443));
+    }
+}
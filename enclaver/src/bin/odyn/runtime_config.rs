@@ -0,0 +1,49 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::config::Configuration;
+use enclaver::boot_config::RuntimeConfigDocument;
+use enclaver::nsm::Nsm;
+
+// The first PCR available to applications
+const RUNTIME_CONFIG_PCR: u16 = 16;
+
+const RUNTIME_CONFIG_FILE_NAME: &str = "runtime-config";
+const RUNTIME_CONFIG_ENV_VAR: &str = "ENCLAVER_RUNTIME_CONFIG";
+
+// Accepts the runtime config document sent by the host, according to the policy
+// in the manifest, and makes it available to the application.
+pub fn apply(config: &mut Configuration, nsm: &Nsm, rc: &RuntimeConfigDocument) -> Result<()> {
+    let policy = config.manifest.runtime_config.as_ref().ok_or_else(|| {
+        anyhow!("received a runtime config, but the manifest does not enable runtime_config")
+    })?;
+
+    if let Some(ref signing_key) = policy.signing_key {
+        rc.verify(signing_key)?;
+    }
+
+    let document = rc.document()?;
+    let digest = hex(&Sha256::digest(&document));
+    let measured = policy.measured.unwrap_or(false);
+
+    let tag = if measured {
+        nsm.extend_pcr(RUNTIME_CONFIG_PCR, digest.as_bytes().to_vec())?;
+        info!("Runtime config {digest} measured into PCR{RUNTIME_CONFIG_PCR}");
+        json!({ "measured": true, "sha256": digest })
+    } else {
+        info!("Runtime config {digest} is not measured");
+        json!({ "measured": false })
+    };
+
+    let path = config.config_dir.join(RUNTIME_CONFIG_FILE_NAME);
+    std::fs::write(&path, &document)?;
+    std::env::set_var(RUNTIME_CONFIG_ENV_VAR, &path);
+
+    config.tag_attestation("runtime_config", tag)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
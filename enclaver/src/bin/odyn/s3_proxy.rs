@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use aws_credential_types::cache::CredentialsCache;
+use http::Uri;
+use log::{error, info};
+use tokio::task::JoinHandle;
+
+use enclaver::http_util::HttpServer;
+use enclaver::proxy::aws_util;
+use enclaver::proxy::s3::{S3ProxyConfig, S3ProxyHandler};
+
+use crate::config::Configuration;
+
+const NO_EGRESS_ERROR: &str = "S3 proxy is configured but egress is not. Configure egress allow policy to access the IMDS at 169.254.169.254, or egress.imds_relay, and the AWS S3 endpoint";
+
+pub struct S3ProxyService {
+    proxy: Option<JoinHandle<()>>,
+}
+
+impl S3ProxyService {
+    /// IMDS is reached through imds_proxy_uri if given, through the egress proxy of
+    /// the app if not
+    pub async fn start(config: Arc<Configuration>, imds_proxy_uri: Option<Uri>) -> Result<Self> {
+        let task = if let Some(port) = config.s3_proxy_port() {
+            if let Some(proxy_uri) = config.egress_proxy_uri() {
+                info!("Starting S3 proxy");
+
+                let imds_uri = imds_proxy_uri.unwrap_or_else(|| proxy_uri.clone());
+                let imds = aws_util::imds_client_with_proxy(imds_uri).await?;
+                let sdk_config = aws_util::load_config_from_imds(imds).await?;
+
+                // Refreshed ahead of their expiry, as transfers may run for hours
+                let credentials = CredentialsCache::lazy().create_cache(
+                    sdk_config
+                        .credentials_provider()
+                        .ok_or(anyhow!("credentials provider is missing"))?
+                        .clone(),
+                );
+
+                // HTTPS goes through a CONNECT tunnel, which streams the bodies
+                let client = Box::new(enclaver::http_client::new_http_proxy_client(proxy_uri));
+                let s3_config = S3ProxyConfig {
+                    client,
+                    credentials,
+                    endpoints: config,
+                };
+
+                let proxy = HttpServer::bind(port)?;
+                let handler = S3ProxyHandler::new(s3_config);
+
+                // Picked up by the AWS SDKs and CLI, as AWS_KMS_ENDPOINT is for KMS
+                std::env::set_var("AWS_ENDPOINT_URL_S3", format!("http://127.0.0.1:{port}"));
+
+                Some(tokio::task::spawn(async move {
+                    if let Err(err) = proxy.serve(handler).await {
+                        error!("Error serving S3 proxy: {err}");
+                    }
+                }))
+            } else {
+                return Err(anyhow!(NO_EGRESS_ERROR));
+            }
+        } else {
+            None
+        };
+
+        Ok(Self { proxy: task })
+    }
+
+    pub async fn stop(self) {
+        if let Some(proxy) = self.proxy {
+            proxy.abort();
+            _ = proxy.await;
+        }
+    }
+}
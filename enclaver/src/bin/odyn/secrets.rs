@@ -0,0 +1,262 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use http::{header, Method, Request, Uri};
+use hyper::Body;
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::config::Configuration;
+use enclaver::http_client::{new_http_proxy_client, HttpProxyClient};
+use enclaver::manifest::{Secret, VaultSecret};
+
+const SECRETS_DIR_NAME: &str = "secrets";
+const SECRETS_DIR_ENV_VAR: &str = "ENCLAVER_SECRETS_DIR";
+
+const DEFAULT_APPROLE_MOUNT: &str = "approle";
+const DEFAULT_JWT_MOUNT: &str = "jwt";
+const VAULT_TOKEN_HEADER: &str = "X-Vault-Token";
+const MIME_APPLICATION_JSON: &str = "application/json";
+
+// A source of secret values. `resolved` holds the secrets fetched so far, in
+// manifest order, for backends that authenticate with another secret.
+#[async_trait]
+pub trait SecretBackend: Send + Sync {
+    async fn fetch(&self, resolved: &HashMap<String, Vec<u8>>) -> Result<Vec<u8>>;
+}
+
+// A static value straight from the manifest, intended for development
+struct EnvBackend {
+    value: String,
+}
+
+#[async_trait]
+impl SecretBackend for EnvBackend {
+    async fn fetch(&self, _resolved: &HashMap<String, Vec<u8>>) -> Result<Vec<u8>> {
+        Ok(self.value.as_bytes().to_vec())
+    }
+}
+
+// A file provided by the host, only ever released to an attested enclave
+struct FileBackend {
+    contents: Option<Vec<u8>>,
+}
+
+#[async_trait]
+impl SecretBackend for FileBackend {
+    async fn fetch(&self, _resolved: &HashMap<String, Vec<u8>>) -> Result<Vec<u8>> {
+        self.contents
+            .clone()
+            .ok_or_else(|| anyhow!("the host did not provide the sealed file"))
+    }
+}
+
+// A field of a HashiCorp Vault secret, fetched via the egress proxy
+struct VaultBackend {
+    client: HttpProxyClient<Body>,
+    spec: VaultSecret,
+}
+
+impl VaultBackend {
+    async fn login(&self, resolved: &HashMap<String, Vec<u8>>) -> Result<String> {
+        let credential = |name: &String| {
+            resolved
+                .get(name)
+                .ok_or_else(|| anyhow!("secret {name} is not available"))
+                .and_then(|v| Ok(String::from_utf8(v.clone())?.trim().to_string()))
+        };
+
+        let (mount, body) = match (&self.spec.auth.approle, &self.spec.auth.jwt) {
+            (Some(approle), _) => (
+                approle.mount.as_deref().unwrap_or(DEFAULT_APPROLE_MOUNT),
+                json!({
+                    "role_id": approle.role_id,
+                    "secret_id": credential(&approle.secret_id_from)?,
+                }),
+            ),
+            (None, Some(jwt)) => (
+                jwt.mount.as_deref().unwrap_or(DEFAULT_JWT_MOUNT),
+                json!({
+                    "role": jwt.role,
+                    "jwt": credential(&jwt.jwt_from)?,
+                }),
+            ),
+            (None, None) => return Err(anyhow!("no Vault auth method configured")),
+        };
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri(&format!("auth/{mount}/login"))?)
+            .header(header::CONTENT_TYPE, MIME_APPLICATION_JSON)
+            .body(Body::from(serde_json::to_vec(&body)?))?;
+
+        match self.request(req).await?["auth"]["client_token"] {
+            Value::String(ref token) => Ok(token.clone()),
+            _ => Err(anyhow!("Vault login response is missing a client token")),
+        }
+    }
+
+    async fn request(&self, req: Request<Body>) -> Result<Value> {
+        let uri = req.uri().clone();
+        let resp = self.client.request(req).await?;
+
+        if !resp.status().is_success() {
+            return Err(anyhow!("Vault returned {} for {uri}", resp.status()));
+        }
+
+        let body = hyper::body::to_bytes(resp.into_body()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    fn uri(&self, path: &str) -> Result<Uri> {
+        Ok(format!(
+            "{}/v1/{}",
+            self.spec.address.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+        .parse()?)
+    }
+}
+
+#[async_trait]
+impl SecretBackend for VaultBackend {
+    async fn fetch(&self, resolved: &HashMap<String, Vec<u8>>) -> Result<Vec<u8>> {
+        let token = self.login(resolved).await?;
+
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(self.uri(&self.spec.path)?)
+            .header(VAULT_TOKEN_HEADER, token)
+            .body(Body::empty())?;
+
+        extract_field(&self.request(req).await?, &self.spec.field)
+    }
+}
+
+// Reads a field out of a Vault read response, for both KV version 1 and 2
+fn extract_field(resp: &Value, field: &str) -> Result<Vec<u8>> {
+    let data = &resp["data"];
+
+    // KV version 2 nests the secret data next to its metadata
+    let value = match (data.get("data"), data.get("metadata")) {
+        (Some(inner), Some(_)) if inner.is_object() => &inner[field],
+        _ => &data[field],
+    };
+
+    match value {
+        Value::String(s) => Ok(s.as_bytes().to_vec()),
+        Value::Null => Err(anyhow!("field {field} not found in the Vault secret")),
+        other => Ok(other.to_string().into_bytes()),
+    }
+}
+
+fn backend_for(
+    secret: &Secret,
+    config: &Configuration,
+    sealed_files: &BTreeMap<String, Vec<u8>>,
+) -> Result<Box<dyn SecretBackend>> {
+    if let Some(ref vault) = secret.vault {
+        let proxy_uri = config
+            .egress_proxy_uri()
+            .ok_or_else(|| anyhow!("Vault secrets require egress to be configured"))?;
+
+        return Ok(Box::new(VaultBackend {
+            client: new_http_proxy_client(proxy_uri),
+            spec: vault.clone(),
+        }));
+    }
+
+    if let Some(name) = secret.file_name() {
+        return Ok(Box::new(FileBackend {
+            contents: sealed_files.get(name).cloned(),
+        }));
+    }
+
+    if let Some(ref env) = secret.env {
+        warn!(
+            "secret {} is a static value from the manifest, only use this for development",
+            secret.name
+        );
+        return Ok(Box::new(EnvBackend {
+            value: env.value.clone(),
+        }));
+    }
+
+    Err(anyhow!("secret {} has no backend", secret.name))
+}
+
+// Where the secret called name is written once it has been fetched
+pub fn secret_path(config: &Configuration, name: &str) -> PathBuf {
+    config.config_dir.join(SECRETS_DIR_NAME).join(name)
+}
+
+// Fetches every secret declared in the manifest and hands them to the application,
+// as files in a private directory and optionally as environment variables.
+pub async fn fetch_all(
+    config: &Configuration,
+    sealed_files: &BTreeMap<String, Vec<u8>>,
+) -> Result<()> {
+    let secrets = match config.manifest.secrets {
+        Some(ref secrets) if !secrets.is_empty() => secrets,
+        _ => return Ok(()),
+    };
+
+    let dir = config.config_dir.join(SECRETS_DIR_NAME);
+    std::fs::create_dir_all(&dir)?;
+
+    let mut resolved = HashMap::new();
+    for secret in secrets {
+        let value = backend_for(secret, config, sealed_files)?
+            .fetch(&resolved)
+            .await
+            .map_err(|e| anyhow!("failed to fetch secret {}: {e}", secret.name))?;
+
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(dir.join(&secret.name))?
+            .write_all(&value)?;
+
+        if let Some(ref var) = secret.env_var {
+            let text = std::str::from_utf8(&value)
+                .map_err(|_| anyhow!("secret {} is not valid UTF-8", secret.name))?;
+            std::env::set_var(var, text);
+        }
+
+        info!("Fetched secret {}", secret.name);
+        resolved.insert(secret.name.clone(), value);
+    }
+
+    std::env::set_var(SECRETS_DIR_ENV_VAR, &dir);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_field;
+    use assert2::assert;
+    use serde_json::json;
+
+    #[test]
+    fn test_extract_field() {
+        let kv1 = json!({ "data": { "password": "hunter2", "port": 5432 } });
+        assert!(extract_field(&kv1, "password").unwrap() == b"hunter2");
+        assert!(extract_field(&kv1, "port").unwrap() == b"5432");
+
+        let kv2 = json!({
+            "data": {
+                "data": { "password": "hunter2" },
+                "metadata": { "version": 3 },
+            }
+        });
+        assert!(extract_field(&kv2, "password").unwrap() == b"hunter2");
+        assert!(extract_field(&kv2, "username").is_err());
+    }
+}
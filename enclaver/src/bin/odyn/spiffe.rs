@@ -0,0 +1,163 @@
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use http::{header, Method, Request, Uri};
+use hyper::Body;
+use log::{info, warn};
+use tokio::task::JoinHandle;
+
+use crate::config::Configuration;
+use enclaver::http_client::{new_http_proxy_client, HttpProxyClient};
+use enclaver::journal::unix_time;
+use enclaver::keypair::KeyPair;
+use enclaver::nsm::{AttestationParams, Nsm};
+use enclaver::spiffe::{self, Svid, SvidRequest, SvidResponse, SvidStore};
+
+const SVID_DIR_NAME: &str = "svid";
+const SVID_DIR_ENV_VAR: &str = "ENCLAVER_SVID_DIR";
+
+const MIME_APPLICATION_JSON: &str = "application/json";
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+// Trades attestations for X.509 SVIDs with the SPIFFE server in the manifest.
+// Each request carries a fresh key bound into the attestation, so the private key
+// never leaves the enclave.
+struct SvidIssuer {
+    client: HttpProxyClient<Body>,
+    server: Uri,
+    nsm: Arc<Nsm>,
+    user_data: Option<Vec<u8>>,
+    dir: PathBuf,
+}
+
+impl SvidIssuer {
+    async fn issue(&self) -> Result<Svid> {
+        let keypair = KeyPair::generate()?;
+        let attestation = self.nsm.attestation(AttestationParams {
+            nonce: None,
+            user_data: self.user_data.clone(),
+            public_key: Some(keypair.public_key_as_der()?),
+        })?;
+
+        let body = SvidRequest {
+            attestation: base64::encode(attestation),
+        };
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.server.clone())
+            .header(header::CONTENT_TYPE, MIME_APPLICATION_JSON)
+            .body(Body::from(serde_json::to_vec(&body)?))?;
+
+        let resp = self.client.request(req).await?;
+        if !resp.status().is_success() {
+            return Err(anyhow!(
+                "SPIFFE server {} returned {}",
+                self.server,
+                resp.status()
+            ));
+        }
+
+        let body = hyper::body::to_bytes(resp.into_body()).await?;
+        let resp: SvidResponse = serde_json::from_slice(&body)?;
+
+        Ok(resp.into_svid(keypair.private_key_as_pem()?))
+    }
+
+    // Writes the files of the SVID in the layout of the spiffe-helper
+    fn save(&self, svid: &Svid) -> Result<()> {
+        write_file(&self.dir.join("svid.pem"), &svid.certificates, 0o644)?;
+        write_file(&self.dir.join("svid_key.pem"), &svid.private_key, 0o600)?;
+        write_file(&self.dir.join("svid_bundle.pem"), &svid.bundle, 0o644)?;
+        Ok(())
+    }
+
+    async fn renew(self, svids: SvidStore, mut expires_at: u64) {
+        loop {
+            tokio::time::sleep(spiffe::renewal_delay(unix_time(), expires_at)).await;
+
+            match self.issue().await {
+                Ok(svid) => {
+                    if let Err(err) = self.save(&svid) {
+                        warn!("failed to save the renewed SVID: {err}");
+                    }
+                    info!("Renewed SVID {}", svid.spiffe_id);
+                    expires_at = svid.expires_at;
+                    svids.set(svid);
+                }
+                Err(err) => {
+                    warn!("failed to renew the SVID: {err}");
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+}
+
+// Renamed into place, so the application never reads a partial file
+fn write_file(path: &Path, contents: &str, mode: u32) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&tmp)?
+        .write_all(contents.as_bytes())?;
+    std::fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+pub struct SpiffeService {
+    task: Option<JoinHandle<()>>,
+}
+
+impl SpiffeService {
+    // Obtains the first SVID before the application starts, then keeps it renewed
+    pub async fn start(config: &Configuration, nsm: Arc<Nsm>, svids: SvidStore) -> Result<Self> {
+        let spiffe = match config.manifest.spiffe {
+            Some(ref spiffe) => spiffe,
+            None => return Ok(Self { task: None }),
+        };
+
+        let proxy_uri = config
+            .egress_proxy_uri()
+            .ok_or_else(|| anyhow!("SPIFFE requires egress to be configured"))?;
+
+        let issuer = SvidIssuer {
+            client: new_http_proxy_client(proxy_uri),
+            server: spiffe.server.parse()?,
+            nsm,
+            user_data: config.attestation_user_data.clone(),
+            dir: config.config_dir.join(SVID_DIR_NAME),
+        };
+        std::fs::create_dir_all(&issuer.dir)?;
+
+        let svid = issuer
+            .issue()
+            .await
+            .map_err(|e| anyhow!("failed to obtain an SVID: {e}"))?;
+        issuer.save(&svid)?;
+        info!("Obtained SVID {}", svid.spiffe_id);
+
+        std::env::set_var(SVID_DIR_ENV_VAR, &issuer.dir);
+
+        let expires_at = svid.expires_at;
+        svids.set(svid);
+
+        let task = tokio::task::spawn(issuer.renew(svids, expires_at));
+
+        Ok(Self { task: Some(task) })
+    }
+
+    pub async fn stop(self) {
+        if let Some(task) = self.task {
+            task.abort();
+            _ = task.await;
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+use crate::console::{AppLogStats, AppStatus};
+use crate::memory::{self, CountingAllocator};
+use enclaver::policy::EgressPolicy;
+use enclaver::proxy::audit::AuditLog;
+use enclaver::proxy::relay::BufferBudget;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Reports the memory odyn uses, how often the egress limits kicked in, how many
+// connections and requests the egress proxies denied and how much of the app log
+// reached its subscribers, with the running status until stopped
+pub struct StatsService {
+    task: JoinHandle<()>,
+}
+
+impl StatsService {
+    pub fn start(
+        allocator: &'static CountingAllocator,
+        budget: BufferBudget,
+        policies: Vec<Arc<EgressPolicy>>,
+        audit: AuditLog,
+        app_log: Option<AppLogStats>,
+        app_status: AppStatus,
+    ) -> Self {
+        let task = tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(REPORT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let limits = policies.iter().flat_map(|p| p.limit_stats()).collect();
+                let log = app_log.as_ref().map(AppLogStats::get);
+                app_status.report(
+                    memory::stats(allocator, &budget),
+                    limits,
+                    audit.denials(),
+                    log,
+                );
+            }
+        });
+
+        Self { task }
+    }
+
+    pub async fn stop(self) {
+        self.task.abort();
+        _ = self.task.await;
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use nix::mount::MsFlags;
+use nix::sys::statfs::{statfs, TMPFS_MAGIC};
+use serde::Serialize;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::config::Configuration;
+use crate::health::ServiceTasks;
+use crate::policy_update::AppliedUpdate;
+use enclaver::api::ApiContext;
+
+// On a tmpfs of its own, which the app can read but never fills up
+const STATUS_DIR: &str = "/run/enclaver";
+const STATUS_DIR_OPTIONS: &str = "mode=0755,size=1m";
+const STATUS_FILE_NAME: &str = "status.json";
+const STATUS_FILE_ENV_VAR: &str = "ENCLAVER_STATUS_FILE";
+
+// How often the services are checked on
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+struct Status<'a> {
+    name: &'a str,
+    manifest: &'a str,
+    debug: bool,
+
+    #[serde(flatten)]
+    context: &'a ApiContext,
+
+    services: BTreeMap<&'static str, &'static str>,
+    policy_serial: Option<u64>,
+    policy_updated_at: Option<u64>,
+}
+
+// What the file describes, but for the policy updates
+struct Enclave {
+    name: String,
+    manifest: String,
+    debug: bool,
+    context: ApiContext,
+    services: Vec<(&'static str, ServiceTasks)>,
+}
+
+impl Enclave {
+    fn status(&self, policy: Option<AppliedUpdate>) -> Status<'_> {
+        Status {
+            name: &self.name,
+            manifest: &self.manifest,
+            debug: self.debug,
+            context: &self.context,
+            services: self
+                .services
+                .iter()
+                .map(|(name, tasks)| (*name, if tasks.is_healthy() { "ok" } else { "failed" }))
+                .collect(),
+            policy_serial: policy.map(|update| update.serial),
+            policy_updated_at: policy.map(|update| update.applied_at),
+        }
+    }
+}
+
+// The file, as last written
+struct StatusFile {
+    path: PathBuf,
+    written: Vec<u8>,
+}
+
+impl StatusFile {
+    // Replaces the file in one go, so the app never reads half of it. Returns
+    // whether it changed.
+    fn write(&mut self, status: &Status) -> Result<bool> {
+        let mut json = serde_json::to_vec_pretty(status)?;
+        json.push(b'\n');
+        if json == self.written {
+            return Ok(false);
+        }
+
+        let tmp = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp, &json)?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.written = json;
+
+        Ok(true)
+    }
+}
+
+// Keeps a JSON file up to date with how the enclave was set up, whether its proxies
+// are up and when the egress policy was last updated, for apps that check on them by
+// reading a file rather than through the API
+pub struct StatusFileService {
+    task: JoinHandle<()>,
+}
+
+impl StatusFileService {
+    /// The file is written before this returns, so it exists once the app starts
+    pub fn start(
+        config: &Configuration,
+        services: Vec<(&'static str, Option<ServiceTasks>)>,
+        mut policy_updates: watch::Receiver<Option<AppliedUpdate>>,
+    ) -> Result<Self> {
+        let enclave = Enclave {
+            name: config.manifest.name.clone(),
+            manifest: config.manifest_digest.clone(),
+            debug: config.manifest.is_debug(),
+            context: config.api_context(),
+            services: services
+                .into_iter()
+                .filter_map(|(name, tasks)| Some((name, tasks?)))
+                .collect(),
+        };
+
+        let dir = Path::new(STATUS_DIR);
+        mount_tmpfs(dir)?;
+        let mut file = StatusFile {
+            path: dir.join(STATUS_FILE_NAME),
+            written: Vec::new(),
+        };
+        let policy = *policy_updates.borrow();
+        file.write(&enclave.status(policy))?;
+        std::env::set_var(STATUS_FILE_ENV_VAR, &file.path);
+        info!(
+            "Writing the status of the enclave to {}",
+            file.path.display()
+        );
+
+        let task = tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            // Without a sender there are no updates to wait for
+            let mut updates_open = true;
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    changed = policy_updates.changed(), if updates_open => {
+                        updates_open = changed.is_ok();
+                    }
+                }
+
+                let policy = *policy_updates.borrow();
+                if let Err(err) = file.write(&enclave.status(policy)) {
+                    error!("failed to write {}: {err}", file.path.display());
+                }
+            }
+        });
+
+        Ok(Self { task })
+    }
+
+    pub async fn stop(self) {
+        self.task.abort();
+        _ = self.task.await;
+    }
+}
+
+// Makes sure dir is on a tmpfs, mounting one there unless it already is
+fn mount_tmpfs(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    if statfs(dir)?.filesystem_type() == TMPFS_MAGIC {
+        return Ok(());
+    }
+
+    nix::mount::mount(
+        Some("tmpfs"),
+        dir,
+        Some("tmpfs"),
+        MsFlags::MS_NODEV | MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some(STATUS_DIR_OPTIONS),
+    )
+    .map_err(|e| anyhow!("failed to mount a tmpfs on {}: {e}", dir.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Enclave, StatusFile};
+    use crate::health::ServiceTasks;
+    use crate::policy_update::AppliedUpdate;
+    use assert2::assert;
+    use enclaver::api::ApiContext;
+
+    #[tokio::test]
+    async fn test_status_file() {
+        let running = tokio::spawn(std::future::pending::<()>());
+        let done = tokio::spawn(async {});
+        while !done.is_finished() {
+            tokio::task::yield_now().await;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = StatusFile {
+            path: dir.path().join("status.json"),
+            written: Vec::new(),
+        };
+        let enclave = Enclave {
+            name: "app".to_string(),
+            manifest: "abcd".to_string(),
+            debug: false,
+            context: ApiContext {
+                egress_proxy: Some("http://127.0.0.1:10000/".to_string()),
+                ..Default::default()
+            },
+            services: vec![
+                ("egress", ServiceTasks::new([&running]).unwrap()),
+                ("kms_proxy", ServiceTasks::new([&done]).unwrap()),
+            ],
+        };
+
+        assert!(file.write(&enclave.status(None)).unwrap());
+        assert!(!file.write(&enclave.status(None)).unwrap());
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&file.path).unwrap()).unwrap();
+        assert!(json["name"] == "app");
+        assert!(json["egress_proxy"] == "http://127.0.0.1:10000/");
+        assert!(json["services"]["egress"] == "ok");
+        assert!(json["services"]["kms_proxy"] == "failed");
+        assert!(json["policy_updated_at"].is_null());
+
+        let update = AppliedUpdate {
+            serial: 3,
+            applied_at: 1_700_000_000,
+        };
+        assert!(file.write(&enclave.status(Some(update))).unwrap());
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&file.path).unwrap()).unwrap();
+        assert!(json["policy_serial"] == 3);
+        assert!(json["policy_updated_at"] == 1_700_000_000);
+        assert!(!dir.path().join("status.json.tmp").exists());
+
+        running.abort();
+    }
+}
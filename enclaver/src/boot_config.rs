@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_vsock::VsockStream;
+
+use crate::constants::BOOT_CONFIG_PORT;
+use crate::manifest::{Egress, Ingress, KmsCredentialSource, Manifest};
+use crate::vsock::{self, VMADDR_CID_HOST};
+
+const MAX_BOOT_CONFIG_SIZE: u64 = 64 * 1024;
+
+pub const AWS_ACCESS_KEY_ID: &str = "AWS_ACCESS_KEY_ID";
+pub const AWS_SECRET_ACCESS_KEY: &str = "AWS_SECRET_ACCESS_KEY";
+pub const AWS_SESSION_TOKEN: &str = "AWS_SESSION_TOKEN";
+pub const AWS_REGION: &str = "AWS_REGION";
+pub const AWS_DEFAULT_REGION: &str = "AWS_DEFAULT_REGION";
+pub const AWS_CONTAINER_CREDENTIALS_RELATIVE_URI: &str = "AWS_CONTAINER_CREDENTIALS_RELATIVE_URI";
+pub const AWS_CONTAINER_CREDENTIALS_FULL_URI: &str = "AWS_CONTAINER_CREDENTIALS_FULL_URI";
+pub const AWS_CONTAINER_AUTHORIZATION_TOKEN: &str = "AWS_CONTAINER_AUTHORIZATION_TOKEN";
+
+// Configuration handed from enclaver-run to odyn when the enclave boots. None of
+// this is part of the EIF, so none of it is reflected in the PCRs.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug_overrides: Option<DebugOverrides>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runtime_config: Option<RuntimeConfigDocument>,
+
+    // The variables of the environment of enclaver-run that the credentials of the
+    // KMS proxy come from, see aws_env
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aws_env: BTreeMap<String, String>,
+}
+
+// A per-environment configuration document for the application, along with an
+// optional signature over it. Both are base64 encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuntimeConfigDocument {
+    pub document: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl RuntimeConfigDocument {
+    pub fn new(document: &[u8], signature: Option<&[u8]>) -> Self {
+        Self {
+            document: base64::encode(document),
+            signature: signature.map(base64::encode),
+        }
+    }
+
+    pub fn document(&self) -> Result<Vec<u8>> {
+        Ok(base64::decode(&self.document)?)
+    }
+
+    // Verifies the RSA PKCS#1 v1.5 SHA-256 signature over the document
+    pub fn verify(&self, public_key_pem: &str) -> Result<()> {
+        let signature = match self.signature {
+            Some(ref signature) => base64::decode(signature)?,
+            None => return Err(anyhow!("runtime config is not signed")),
+        };
+
+        crate::keypair::verify_pkcs1v15_sha256(public_key_pem, &self.document()?, &signature)
+            .map_err(|_| anyhow!("runtime config signature verification failed"))
+    }
+}
+
+// Relaxations of the manifest, only honored by enclaves running in debug mode.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DebugOverrides {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_egress: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_ingress: Vec<u16>,
+}
+
+impl DebugOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.allow_egress.is_empty() && self.extra_ingress.is_empty()
+    }
+
+    pub fn apply(&self, manifest: &mut Manifest) {
+        if !self.allow_egress.is_empty() {
+            let egress = manifest.egress.get_or_insert_with(Egress::default);
+
+            egress
+                .allow
+                .get_or_insert_with(Vec::new)
+                .extend(self.allow_egress.iter().cloned());
+        }
+
+        if !self.extra_ingress.is_empty() {
+            let ingress = manifest.ingress.get_or_insert_with(Vec::new);
+            for port in &self.extra_ingress {
+                if !ingress.iter().any(|item| item.listen_port == *port) {
+                    ingress.push(Ingress {
+                        listen_port: *port,
+                        target_port: None,
+                        tls: None,
+                        max_connections: None,
+                        accepts_per_second: None,
+                        keepalive_seconds: None,
+                        buffer_bytes: None,
+                        access_log: None,
+                        bind_addr: None,
+                        serve_attestation: None,
+                    });
+                }
+            }
+        }
+    }
+}
+
+// The variables of the environment of enclaver-run that the KMS proxy takes its
+// credentials from, for source. Fails if those it cannot do without are not set.
+pub fn aws_env(source: KmsCredentialSource) -> Result<BTreeMap<String, String>> {
+    aws_env_from(source, |name| std::env::var(name).ok())
+}
+
+fn aws_env_from(
+    source: KmsCredentialSource,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<BTreeMap<String, String>> {
+    let (names, required): (&[&str], &[&str]) = match source {
+        KmsCredentialSource::Imds | KmsCredentialSource::WebIdentity => return Ok(BTreeMap::new()),
+        KmsCredentialSource::Env => (
+            &[AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, AWS_SESSION_TOKEN],
+            &[AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY],
+        ),
+        KmsCredentialSource::Container => (
+            &[
+                AWS_CONTAINER_CREDENTIALS_RELATIVE_URI,
+                AWS_CONTAINER_CREDENTIALS_FULL_URI,
+                AWS_CONTAINER_AUTHORIZATION_TOKEN,
+            ],
+            &[],
+        ),
+    };
+
+    let env: BTreeMap<String, String> = names
+        .iter()
+        .chain(&[AWS_REGION, AWS_DEFAULT_REGION])
+        .filter_map(|name| Some((name.to_string(), var(name)?)))
+        .collect();
+
+    if let Some(missing) = required.iter().find(|name| !env.contains_key(**name)) {
+        return Err(anyhow!(
+            "the KMS proxy takes its credentials from {missing}, which is not set"
+        ));
+    }
+    if source == KmsCredentialSource::Container
+        && !env.contains_key(AWS_CONTAINER_CREDENTIALS_RELATIVE_URI)
+        && !env.contains_key(AWS_CONTAINER_CREDENTIALS_FULL_URI)
+    {
+        return Err(anyhow!(
+            "the KMS proxy takes its credentials from a container credentials endpoint, but \
+             neither {AWS_CONTAINER_CREDENTIALS_RELATIVE_URI} nor \
+             {AWS_CONTAINER_CREDENTIALS_FULL_URI} is set"
+        ));
+    }
+
+    Ok(env)
+}
+
+// Serves the boot config to the enclave (host side).
+pub async fn serve(config: BootConfig) -> Result<()> {
+    let buf = serde_json::to_vec(&config)?;
+    let mut incoming = vsock::serve(BOOT_CONFIG_PORT)?;
+
+    while let Some(mut conn) = incoming.next().await {
+        if let Err(err) = conn.write_all(&buf).await {
+            error!("failed to send boot config: {err}");
+        }
+    }
+
+    Ok(())
+}
+
+// Fetches the boot config from the host (enclave side). Returns None if the host
+// is not serving one.
+pub async fn fetch() -> Result<Option<BootConfig>> {
+    let conn = match VsockStream::connect(VMADDR_CID_HOST, BOOT_CONFIG_PORT).await {
+        // VsockStream::connect can return Ok even if the connect failed
+        Ok(conn) if conn.peer_addr().is_ok() => conn,
+        _ => {
+            debug!("no boot config available from the host");
+            return Ok(None);
+        }
+    };
+
+    let mut buf = Vec::new();
+    conn.take(MAX_BOOT_CONFIG_SIZE + 1)
+        .read_to_end(&mut buf)
+        .await?;
+
+    if buf.len() as u64 > MAX_BOOT_CONFIG_SIZE {
+        return Err(anyhow!("boot config exceeds {MAX_BOOT_CONFIG_SIZE} bytes"));
+    }
+
+    Ok(Some(serde_json::from_slice(&buf)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{aws_env_from, DebugOverrides, RuntimeConfigDocument};
+    use crate::keypair::KeyPair;
+    use crate::manifest::{KmsCredentialSource, Manifest};
+    use assert2::assert;
+    use rsa::PaddingScheme;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_runtime_config_signature() {
+        let keypair = KeyPair::generate().unwrap();
+        let public_key = keypair.public_key_as_pem().unwrap();
+
+        let document = b"endpoint: https://stage.example.com";
+        let signature = keypair
+            .private
+            .sign(
+                PaddingScheme::new_pkcs1v15_sign::<Sha256>(),
+                &Sha256::digest(document),
+            )
+            .unwrap();
+
+        let signed = RuntimeConfigDocument::new(document, Some(&signature));
+        assert!(signed.document().unwrap() == document);
+        assert!(signed.verify(&public_key).is_ok());
+
+        let tampered = RuntimeConfigDocument {
+            document: base64::encode(b"endpoint: https://evil.example.com"),
+            ..signed
+        };
+        assert!(tampered.verify(&public_key).is_err());
+
+        let unsigned = RuntimeConfigDocument::new(document, None);
+        assert!(unsigned.verify(&public_key).is_err());
+    }
+
+    #[test]
+    fn test_aws_env() {
+        let host = |name: &str| match name {
+            "AWS_ACCESS_KEY_ID" => Some("AKID".to_string()),
+            "AWS_SECRET_ACCESS_KEY" => Some("secret".to_string()),
+            "AWS_REGION" => Some("eu-west-1".to_string()),
+            "AWS_CONTAINER_CREDENTIALS_RELATIVE_URI" => Some("/v2/credentials/1".to_string()),
+            _ => None,
+        };
+
+        let env = aws_env_from(KmsCredentialSource::Env, host).unwrap();
+        let names: Vec<&str> = env.keys().map(String::as_str).collect();
+        assert!(names == ["AWS_ACCESS_KEY_ID", "AWS_REGION", "AWS_SECRET_ACCESS_KEY"]);
+
+        let env = aws_env_from(KmsCredentialSource::Container, host).unwrap();
+        assert!(env.len() == 2);
+        assert!(!env.contains_key("AWS_SECRET_ACCESS_KEY"));
+
+        assert!(aws_env_from(KmsCredentialSource::WebIdentity, host)
+            .unwrap()
+            .is_empty());
+        assert!(aws_env_from(KmsCredentialSource::Env, |_| None).is_err());
+        assert!(aws_env_from(KmsCredentialSource::Container, |_| None).is_err());
+    }
+
+    #[test]
+    fn test_apply_debug_overrides() {
+        let mut manifest: Manifest = serde_yaml::from_str(
+            r#"
+version: v1
+name: "test"
+target: "target-image:latest"
+sources:
+  app: "app-image:latest"
+ingress:
+  - listen_port: 80
+"#,
+        )
+        .unwrap();
+
+        let overrides = DebugOverrides {
+            allow_egress: vec!["*".to_string()],
+            extra_ingress: vec![80, 8080],
+        };
+        overrides.apply(&mut manifest);
+
+        let egress = manifest.egress.unwrap();
+        assert!(egress.allow == Some(vec!["*".to_string()]));
+        assert!(egress.deny == None);
+
+        let ports: Vec<u16> = manifest
+            .ingress
+            .unwrap()
+            .iter()
+            .map(|i| i.listen_port)
+            .collect();
+        assert!(ports == vec![80, 8080]);
+    }
+}
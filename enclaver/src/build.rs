@@ -1,19 +1,25 @@
+use crate::bundle;
 use crate::constants::{
     EIF_FILE_NAME, ENCLAVE_CONFIG_DIR, ENCLAVE_ODYN_PATH, MANIFEST_FILE_NAME, RELEASE_BUNDLE_DIR,
 };
+use crate::eif_chunks;
 use crate::images::{FileBuilder, FileSource, ImageManager, ImageRef, LayerBuilder};
-use crate::manifest::{load_manifest, Manifest};
+use crate::manifest::{load_manifest, Build, Manifest, ManifestError};
 use crate::nitro_cli::{EIFInfo, KnownIssue};
-use anyhow::{anyhow, Result};
-use bollard::container::{Config, LogOutput, LogsOptions, WaitContainerOptions};
+use crate::preflight;
+use bollard::container::{
+    Config, DownloadFromContainerOptions, LogOutput, LogsOptions, WaitContainerOptions,
+};
 use bollard::models::{ContainerConfig, HostConfig, Mount, MountTypeEnum};
 use bollard::Docker;
 use futures_util::stream::{StreamExt, TryStreamExt};
 use log::{debug, info, warn};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tempfile::TempDir;
-use tokio::fs::{canonicalize, rename};
+use thiserror::Error;
+use tokio::fs::{canonicalize, create_dir, rename, File};
+use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 
 const ENCLAVE_OVERLAY_CHOWN: &str = "0:0";
@@ -24,6 +30,41 @@ const ODYN_IMAGE: &str = "registry.edgebit.io/odyn:latest";
 const ODYN_IMAGE_BINARY_PATH: &str = "/usr/local/bin/odyn";
 const RELEASE_BASE_IMAGE: &str = "registry.edgebit.io/enclaver-wrapper-base:latest";
 
+// The kernel command line nitro-cli builds EIFs with, replaced for build.kernel_args
+const NITRO_CLI_KERNEL_CMDLINE_PATH: &str = "/usr/share/nitro_enclaves/blobs/cmdline";
+const KERNEL_CMDLINE_FILE_NAME: &str = "cmdline";
+
+type Result<T> = std::result::Result<T, BuildError>;
+
+/// Why building an EIF or release image failed
+#[derive(Debug, Error)]
+pub enum BuildError {
+    #[error(transparent)]
+    Manifest(#[from] ManifestError),
+
+    #[error("connecting to docker: {0}")]
+    DockerConnect(#[source] bollard::errors::Error),
+
+    #[error(transparent)]
+    Docker(#[from] bollard::errors::Error),
+
+    /// Failures to pull, inspect or amend the images the build starts from
+    #[error(transparent)]
+    Image(#[from] anyhow::Error),
+
+    #[error("{0}")]
+    NitroCli(String),
+
+    #[error("writing release bundle: {0}")]
+    Bundle(#[source] anyhow::Error),
+
+    #[error("invalid EIF info from nitro-cli: {0}")]
+    EifInfo(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
 pub struct EnclaveArtifactBuilder {
     docker: Arc<Docker>,
     image_manager: ImageManager,
@@ -32,10 +73,8 @@ pub struct EnclaveArtifactBuilder {
 
 impl EnclaveArtifactBuilder {
     pub fn new(pull_tags: bool) -> Result<Self> {
-        let docker_client = Arc::new(
-            Docker::connect_with_local_defaults()
-                .map_err(|e| anyhow!("connecting to docker: {}", e))?,
-        );
+        let docker_client =
+            Arc::new(Docker::connect_with_local_defaults().map_err(BuildError::DockerConnect)?);
 
         Ok(Self {
             pull_tags,
@@ -44,21 +83,28 @@ impl EnclaveArtifactBuilder {
         })
     }
 
-    /// Build a release image based on the referenced manifest.
-    pub async fn build_release(&self, manifest_path: &str) -> Result<(EIFInfo, ImageRef, String)> {
+    /// Build a release image based on the referenced manifest. The image is tagged
+    /// with the target of the manifest, which is returned along with it.
+    pub async fn build_release(
+        &self,
+        manifest_path: &str,
+    ) -> Result<(EIFInfo, ImageRef, Manifest)> {
         let ibr = self.common_build(manifest_path).await?;
         let eif_path = ibr.build_dir.path().join(EIF_FILE_NAME);
         let release_img = self
-            .package_eif(eif_path, manifest_path, &ibr.resolved_sources)
+            .package_eif(
+                eif_path,
+                &ibr.eif_info,
+                manifest_path,
+                &ibr.resolved_sources,
+            )
             .await?;
 
-        let release_tag = &ibr.manifest.target;
-
         self.image_manager
-            .tag_image(&release_img, release_tag)
+            .tag_image(&release_img, &ibr.manifest.target)
             .await?;
 
-        Ok((ibr.eif_info, release_img, release_tag.to_string()))
+        Ok((ibr.eif_info, release_img, ibr.manifest))
     }
 
     /// Build an EIF, as would be included in a release image, based on the referenced manifest.
@@ -66,12 +112,12 @@ impl EnclaveArtifactBuilder {
         &self,
         manifest_path: &str,
         dst_path: &str,
-    ) -> Result<(EIFInfo, PathBuf)> {
+    ) -> Result<(EIFInfo, PathBuf, Manifest)> {
         let ibr = self.common_build(manifest_path).await?;
         let eif_path = ibr.build_dir.path().join(EIF_FILE_NAME);
         rename(&eif_path, dst_path).await?;
 
-        Ok((ibr.eif_info, canonicalize(dst_path).await?))
+        Ok((ibr.eif_info, canonicalize(dst_path).await?, ibr.manifest))
     }
 
     /// Load the referenced manifest, amend the image it references to match what we expect in
@@ -84,7 +130,7 @@ impl EnclaveArtifactBuilder {
         let resolved_sources = self.resolve_sources(&manifest).await?;
 
         let amended_img = self
-            .amend_source_image(&resolved_sources, manifest_path)
+            .amend_source_image(&resolved_sources, &manifest, manifest_path)
             .await?;
 
         info!("built intermediate image: {}", amended_img);
@@ -99,14 +145,32 @@ impl EnclaveArtifactBuilder {
                 certificate_path = Some(canonicalize(parent_path.join(&signature.certificate)).await?);
                 key_path = Some(canonicalize(parent_path.join(&signature.key)).await?);
             } else {
-                return Err(anyhow!("Failed to get parent path of manifest"));
+                return Err(BuildError::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "Failed to get parent path of manifest",
+                )));
             }
         }
 
         let eif_info = self
-            .image_to_eif(&amended_img, &build_dir, EIF_FILE_NAME, key_path, certificate_path)
+            .image_to_eif(
+                &amended_img,
+                &build_dir,
+                EIF_FILE_NAME,
+                key_path,
+                certificate_path,
+                manifest.build.as_ref(),
+            )
             .await?;
 
+        // Caught here rather than once the enclave fails to boot
+        let memory_mb = manifest.enclave_memory_mb(None);
+        if let Ok(eif) = tokio::fs::metadata(build_dir.path().join(EIF_FILE_NAME)).await {
+            if let Some(warning) = preflight::eif_memory_warning(eif.len(), memory_mb) {
+                warn!("{warning}");
+            }
+        }
+
         Ok(IntermediateBuildResult {
             manifest,
             resolved_sources,
@@ -120,6 +184,7 @@ impl EnclaveArtifactBuilder {
     async fn amend_source_image(
         &self,
         sources: &ResolvedSources,
+        manifest: &Manifest,
         manifest_path: &str,
     ) -> Result<ImageRef> {
         let img_config = self
@@ -155,9 +220,15 @@ impl EnclaveArtifactBuilder {
             String::from(ENCLAVE_ODYN_PATH),
             String::from("--config-dir"),
             String::from("/etc/enclaver"),
-            String::from("--"),
         ];
 
+        // Part of the measured image, so verifiers can rely on it as much as on the manifest
+        if !manifest.console_enabled() {
+            odyn_command.push(String::from("--no-console"));
+        }
+
+        odyn_command.push(String::from("--"));
+
         odyn_command.append(&mut entrypoint);
         odyn_command.append(&mut cmd);
 
@@ -191,36 +262,87 @@ impl EnclaveArtifactBuilder {
 
     /// Convert an EIF file into a release OCI image.
     ///
+    /// The EIF is split into content-defined chunks, each copied in a layer of its
+    /// own, so that registries only store and transfer the chunks that changed since
+    /// the previous version. enclaver-run assembles the EIF again before starting it.
+    ///
     /// TODO: this currently is incomplete; file permissions are wrong, the base image
     /// doesn't match our current requirements, and the exact intended format is still
     /// TBD.
     async fn package_eif(
         &self,
         eif_path: PathBuf,
+        eif_info: &EIFInfo,
         manifest_path: &str,
         sources: &ResolvedSources,
     ) -> Result<ImageRef> {
         info!("packaging EIF into release image");
         debug!("EIF file: {}", eif_path.to_string_lossy());
 
+        let release_dir = PathBuf::from(RELEASE_BUNDLE_DIR);
+        let build_dir = eif_path.parent().unwrap().to_path_buf();
+        let chunks = eif_chunks::split(&eif_path, &build_dir.join(eif_chunks::CHUNKS_DIR_NAME))
+            .await
+            .map_err(BuildError::Image)?;
+
+        let mut layers = LayerBuilder::new();
+
+        if chunks.len() > eif_chunks::MAX_CHUNKS {
+            warn!(
+                "EIF splits into {} chunks, more than {} layers, adding it as one",
+                chunks.len(),
+                eif_chunks::MAX_CHUNKS
+            );
+            layers.append_file(FileBuilder {
+                path: release_dir.join(EIF_FILE_NAME),
+                source: FileSource::Local { path: eif_path },
+                chown: RELEASE_OVERLAY_CHOWN.to_string(),
+            });
+        } else {
+            info!("EIF split into {} chunks", chunks.len());
+
+            let mut added = Vec::new();
+            for chunk in &chunks {
+                if added.contains(&&chunk.digest) {
+                    continue;
+                }
+                added.push(&chunk.digest);
+
+                let chunk_path = PathBuf::from(eif_chunks::CHUNKS_DIR_NAME).join(&chunk.digest);
+                layers.append_file(FileBuilder {
+                    path: release_dir.join(&chunk_path),
+                    source: FileSource::Local {
+                        path: build_dir.join(&chunk_path),
+                    },
+                    chown: RELEASE_OVERLAY_CHOWN.to_string(),
+                });
+            }
+
+            let index_path = build_dir.join(eif_chunks::INDEX_FILE_NAME);
+            tokio::fs::write(&index_path, eif_chunks::index(&chunks)).await?;
+            layers.append_file(FileBuilder {
+                path: release_dir.join(eif_chunks::INDEX_FILE_NAME),
+                source: FileSource::Local { path: index_path },
+                chown: RELEASE_OVERLAY_CHOWN.to_string(),
+            });
+        }
+
+        // Last, as it changes more often than the EIF
+        layers.append_file(FileBuilder {
+            path: release_dir.join(MANIFEST_FILE_NAME),
+            source: FileSource::Local {
+                path: PathBuf::from(manifest_path),
+            },
+            chown: RELEASE_OVERLAY_CHOWN.to_string(),
+        });
+
+        for (key, value) in eif_info.labels() {
+            layers.set_label(key, value);
+        }
+
         let packaged_img = self
             .image_manager
-            .append_layer(
-                &sources.release_base,
-                LayerBuilder::new()
-                    .append_file(FileBuilder {
-                        path: PathBuf::from(RELEASE_BUNDLE_DIR).join(MANIFEST_FILE_NAME),
-                        source: FileSource::Local {
-                            path: PathBuf::from(manifest_path),
-                        },
-                        chown: RELEASE_OVERLAY_CHOWN.to_string(),
-                    })
-                    .append_file(FileBuilder {
-                        path: PathBuf::from(RELEASE_BUNDLE_DIR).join(EIF_FILE_NAME),
-                        source: FileSource::Local { path: eif_path },
-                        chown: RELEASE_OVERLAY_CHOWN.to_string(),
-                    }),
-            )
+            .append_layer(&sources.release_base, &layers)
             .await?;
 
         Ok(packaged_img)
@@ -237,7 +359,8 @@ impl EnclaveArtifactBuilder {
         build_dir: &TempDir,
         eif_name: &str,
         key: Option<PathBuf>,
-        certificate: Option<PathBuf>
+        certificate: Option<PathBuf>,
+        build: Option<&Build>,
     ) -> Result<EIFInfo> {
         let build_dir_path = build_dir.path().to_str().unwrap();
 
@@ -304,12 +427,174 @@ impl EnclaveArtifactBuilder {
             });
         }
 
+        if let Some(build) = build.filter(|build| !build.kernel_args().is_empty()) {
+            let cmdline = self.kernel_cmdline(nitro_cli.to_str(), build).await?;
+            info!("building with kernel command line: {cmdline}");
+
+            let cmdline_path = build_dir.path().join(KERNEL_CMDLINE_FILE_NAME);
+            tokio::fs::write(&cmdline_path, cmdline).await?;
+            mounts.push(Mount {
+                typ: Some(MountTypeEnum::BIND),
+                source: Some(cmdline_path.to_string_lossy().to_string()),
+                target: Some(String::from(NITRO_CLI_KERNEL_CMDLINE_PATH)),
+                read_only: Some(true),
+                ..Default::default()
+            });
+        }
+
+        let json_buf = self
+            .run_nitro_cli(nitro_cli.to_str(), None, cmd, mounts)
+            .await?;
+
+        let _ = self.docker.remove_image(&img_tag, None, None).await?;
+
+        Ok(serde_json::from_slice(&json_buf)?)
+    }
+
+    /// The kernel command line of the nitro-cli image with the arguments of the manifest.
+    /// Toolchains that do not keep the command line in a file cannot take any.
+    async fn kernel_cmdline(&self, nitro_cli: &str, build: &Build) -> Result<String> {
+        let default = self
+            .run_nitro_cli(
+                nitro_cli,
+                Some(vec!["cat"]),
+                vec![NITRO_CLI_KERNEL_CMDLINE_PATH],
+                vec![],
+            )
+            .await
+            .map_err(|err| {
+                BuildError::NitroCli(format!(
+                    "build.kernel_args is not supported by {nitro_cli}, failed to read {NITRO_CLI_KERNEL_CMDLINE_PATH}: {err}"
+                ))
+            })?;
+
+        Ok(build.kernel_cmdline(&String::from_utf8_lossy(&default)))
+    }
+
+    /// Measure the EIF packaged in a release image, by running `enclaver-run describe-eif`
+    /// from the image itself, which also assembles an EIF packaged in chunks.
+    pub async fn describe_release(&self, image_name: &str) -> Result<EIFInfo> {
+        let img = self.image_manager.find_or_pull(image_name).await?;
+
+        let json_buf = self
+            .run_nitro_cli(img.to_str(), None, vec!["describe-eif"], vec![])
+            .await?;
+
+        Ok(serde_json::from_slice(&json_buf)?)
+    }
+
+    /// Measure an EIF file, by mounting it into a nitro-cli container.
+    pub async fn describe_eif(&self, eif_path: &str) -> Result<EIFInfo> {
+        let eif_path = canonicalize(eif_path).await?;
+        let nitro_cli = self.resolve_external_source_image(NITRO_CLI_IMAGE).await?;
+        let mounted_path = format!("/build/{EIF_FILE_NAME}");
+
+        let mounts = vec![Mount {
+            typ: Some(MountTypeEnum::BIND),
+            source: Some(eif_path.to_string_lossy().to_string()),
+            target: Some(mounted_path.clone()),
+            read_only: Some(true),
+            ..Default::default()
+        }];
+
+        let json_buf = self
+            .run_nitro_cli(
+                nitro_cli.to_str(),
+                None,
+                vec!["describe-eif", "--eif-path", &mounted_path],
+                mounts,
+            )
+            .await?;
+
+        Ok(serde_json::from_slice(&json_buf)?)
+    }
+
+    /// Export the EIF and manifest of a release image, with the measurements of the
+    /// EIF, as a bundle at bundle_path. Returns the measurements.
+    pub async fn export_release(&self, image_name: &str, bundle_path: &Path) -> Result<EIFInfo> {
+        let img = self.image_manager.find_or_pull(image_name).await?;
+        let eif_info = self.describe_release(image_name).await?;
+
+        let build_dir = TempDir::new()?;
+        let release_tar = build_dir.path().join("release.tar");
+
+        let container_id = self
+            .docker
+            .create_container::<&str, &str>(
+                None,
+                Config {
+                    image: Some(img.to_str()),
+                    ..Default::default()
+                },
+            )
+            .await?
+            .id;
+
+        info!("copying {RELEASE_BUNDLE_DIR} out of {image_name}");
+
+        let mut tar_file = File::create(&release_tar).await?;
+        let mut download = self.docker.download_from_container(
+            &container_id,
+            Some(DownloadFromContainerOptions {
+                path: RELEASE_BUNDLE_DIR,
+            }),
+        );
+        while let Some(chunk) = download.next().await {
+            tar_file.write_all(&chunk?).await?;
+        }
+        tar_file.flush().await?;
+
+        self.docker.remove_container(&container_id, None).await?;
+
+        // The archive holds the directory itself, e.g. enclave/application.eif
+        let release_dir = build_dir.path().join("release");
+        tokio_tar::Archive::new(File::open(&release_tar).await?)
+            .unpack(&release_dir)
+            .await?;
+        let release_dir = release_dir.join(RELEASE_BUNDLE_DIR.trim_start_matches('/'));
+        let eif_path = eif_chunks::release_eif(&release_dir)
+            .await
+            .map_err(BuildError::Bundle)?;
+
+        let bundle_dir = build_dir.path().join("bundle");
+        create_dir(&bundle_dir).await?;
+        rename(eif_path, bundle_dir.join(EIF_FILE_NAME)).await?;
+        rename(
+            release_dir.join(MANIFEST_FILE_NAME),
+            bundle_dir.join(MANIFEST_FILE_NAME),
+        )
+        .await?;
+        tokio::fs::write(
+            bundle_dir.join(bundle::MEASUREMENTS_FILE_NAME),
+            serde_json::to_vec_pretty(&eif_info)?,
+        )
+        .await?;
+
+        bundle::pack(&bundle_dir, bundle_path)
+            .await
+            .map_err(BuildError::Bundle)?;
+
+        Ok(eif_info)
+    }
+
+    /// Run nitro-cli in a container of `image`, logging what it writes to stderr, and
+    /// return what it writes to stdout.
+    async fn run_nitro_cli(
+        &self,
+        image: &str,
+        entrypoint: Option<Vec<&str>>,
+        cmd: Vec<&str>,
+        mounts: Vec<Mount>,
+    ) -> Result<Vec<u8>> {
+        let cmd_name = cmd[0].to_string();
+
         let build_container_id = self
             .docker
             .create_container::<&str, &str>(
                 None,
                 Config {
-                    image: Some(nitro_cli.to_str()),
+                    image: Some(image),
+                    entrypoint,
                     cmd: Some(cmd),
                     attach_stderr: Some(true),
                     attach_stdout: Some(true),
@@ -324,8 +609,8 @@ impl EnclaveArtifactBuilder {
             .id;
 
         info!(
-            "starting nitro-cli build-eif in container: {}",
-            build_container_id
+            "starting nitro-cli {} in container: {}",
+            cmd_name, build_container_id
         );
 
         self.docker
@@ -353,7 +638,7 @@ impl EnclaveArtifactBuilder {
                 detected_nitro_cli_issue = KnownIssue::detect(&line);
             }
 
-            info!(target: "nitro-cli::build-eif", "{trimmed}");
+            info!(target: "nitro-cli", "{trimmed}");
         }
 
         if let Some(issue) = detected_nitro_cli_issue {
@@ -369,11 +654,13 @@ impl EnclaveArtifactBuilder {
             .try_collect::<Vec<_>>()
             .await?
             .first()
-            .ok_or_else(|| anyhow!("missing wait response from daemon",))?
+            .ok_or_else(|| BuildError::NitroCli("missing wait response from daemon".to_string()))?
             .status_code;
 
         if status_code != 0 {
-            return Err(anyhow!("non-zero exit code from nitro-cli",));
+            return Err(BuildError::NitroCli(format!(
+                "non-zero exit code from nitro-cli: {status_code}"
+            )));
         }
 
         let mut json_buf = Vec::with_capacity(4096);
@@ -393,9 +680,8 @@ impl EnclaveArtifactBuilder {
         self.docker
             .remove_container(&build_container_id, None)
             .await?;
-        let _ = self.docker.remove_image(&img_tag, None, None).await?;
 
-        Ok(serde_json::from_slice(&json_buf)?)
+        Ok(json_buf)
     }
 
     fn analyze_manifest(&self, manifest: &Manifest) {
@@ -408,6 +694,10 @@ impl EnclaveArtifactBuilder {
         if manifest.egress.is_none() {
             info!("no egress specified in manifest; this enclave will have no outbound network access");
         }
+
+        if manifest.is_debug() {
+            warn!("building a DEBUG image; it can only run in debug mode, where its console is readable and PCRs are zero");
+        }
     }
 
     // External images are images whose tags we do not normally manage. In other words,
@@ -415,9 +705,9 @@ impl EnclaveArtifactBuilder {
     // otherwise we should not overwrite that tag.
     async fn resolve_external_source_image(&self, image_name: &str) -> Result<ImageRef> {
         if self.pull_tags {
-            self.image_manager.pull_image(image_name).await
+            Ok(self.image_manager.pull_image(image_name).await?)
         } else {
-            self.image_manager.find_or_pull(image_name).await
+            Ok(self.image_manager.find_or_pull(image_name).await?)
         }
     }
 
@@ -427,8 +717,8 @@ impl EnclaveArtifactBuilder {
         default: &str,
     ) -> Result<ImageRef> {
         match name_override {
-            Some(image_name) => self.image_manager.find_or_pull(image_name).await,
-            None => self.image_manager.pull_image(default).await,
+            Some(image_name) => Ok(self.image_manager.find_or_pull(image_name).await?),
+            None => Ok(self.image_manager.pull_image(default).await?),
         }
     }
 
@@ -0,0 +1,201 @@
+//! Release bundles: the EIF and manifest of a release image, along with the
+//! measurements of the EIF, in one zstd compressed tarball. They move enclaves into
+//! environments without a container registry, where enclaver-run starts them with
+//! --bundle.
+
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+use tokio_tar::{Archive, Builder};
+
+use crate::constants::{EIF_FILE_NAME, MANIFEST_FILE_NAME};
+use crate::nitro_cli::{EIFInfo, NitroCLI};
+
+pub const MEASUREMENTS_FILE_NAME: &str = "measurements.json";
+pub const CHECKSUMS_FILE_NAME: &str = "SHA256SUMS";
+
+const CONTENTS: [&str; 3] = [EIF_FILE_NAME, MANIFEST_FILE_NAME, MEASUREMENTS_FILE_NAME];
+
+/// Write a bundle of the EIF, manifest and measurements in dir to path, along with
+/// a SHA256SUMS file over them, in the format of sha256sum.
+pub async fn pack(dir: &Path, path: &Path) -> Result<()> {
+    let mut checksums = String::new();
+    for name in CONTENTS {
+        let digest = sha256_file(dir.join(name)).await?;
+        checksums.push_str(&format!("{digest}  {name}\n"));
+    }
+    tokio::fs::write(dir.join(CHECKSUMS_FILE_NAME), checksums).await?;
+
+    let tar = NamedTempFile::new_in(dir)?;
+    let mut builder = Builder::new(tokio::fs::File::from_std(tar.reopen()?));
+    for name in CONTENTS.into_iter().chain([CHECKSUMS_FILE_NAME]) {
+        builder.append_path_with_name(dir.join(name), name).await?;
+    }
+    builder.into_inner().await?.flush().await?;
+
+    let output = File::create(path)?;
+    tokio::task::spawn_blocking(move || zstd::stream::copy_encode(tar, output, 0)).await??;
+
+    Ok(())
+}
+
+/// A bundle unpacked into a directory
+pub struct Bundle {
+    dir: PathBuf,
+}
+
+impl Bundle {
+    /// Unpack the bundle at path into dir, which should be empty, and check each file
+    /// against SHA256SUMS. Any other file in the bundle is refused.
+    pub async fn unpack(path: &Path, dir: &Path) -> Result<Self> {
+        let input = File::open(path)
+            .map_err(|err| anyhow!("failed to open bundle {}: {err}", path.display()))?;
+        let tar = NamedTempFile::new_in(dir)?;
+        let output = tar.reopen()?;
+        tokio::task::spawn_blocking(move || zstd::stream::copy_decode(input, output))
+            .await?
+            .map_err(|err| anyhow!("failed to decompress bundle: {err}"))?;
+
+        let mut archive = Archive::new(tokio::fs::File::from_std(tar.reopen()?));
+        let mut entries = archive.entries()?;
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry?;
+            let entry_path = entry.path()?.into_owned();
+            let name = match entry_path.components().collect::<Vec<_>>()[..] {
+                [Component::Normal(name)] => name.to_str(),
+                _ => None,
+            };
+            match name {
+                Some(name) if name == CHECKSUMS_FILE_NAME || CONTENTS.contains(&name) => {
+                    entry.unpack(dir.join(name)).await?;
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "unexpected file in bundle: {}",
+                        entry_path.display()
+                    ))
+                }
+            }
+        }
+
+        let bundle = Self {
+            dir: dir.to_path_buf(),
+        };
+        bundle.verify_checksums().await?;
+
+        Ok(bundle)
+    }
+
+    pub fn eif_path(&self) -> PathBuf {
+        self.dir.join(EIF_FILE_NAME)
+    }
+
+    pub fn manifest_path(&self) -> PathBuf {
+        self.dir.join(MANIFEST_FILE_NAME)
+    }
+
+    /// The measurements of the EIF, as recorded when the bundle was exported
+    pub async fn measurements(&self) -> Result<EIFInfo> {
+        let buf = tokio::fs::read(self.dir.join(MEASUREMENTS_FILE_NAME)).await?;
+        Ok(serde_json::from_slice(&buf)?)
+    }
+
+    /// Measure the EIF with nitro-cli, and check that it matches the recorded
+    /// measurements. Returns the measurements.
+    pub async fn verify_measurements(&self) -> Result<EIFInfo> {
+        let recorded = self.measurements().await?;
+        let measured = NitroCLI::new().describe_eif(&self.eif_path()).await?;
+
+        if measured.measurements() != recorded.measurements() {
+            return Err(anyhow!(
+                "EIF in bundle does not match its recorded measurements"
+            ));
+        }
+
+        Ok(measured)
+    }
+
+    async fn verify_checksums(&self) -> Result<()> {
+        let checksums = tokio::fs::read_to_string(self.dir.join(CHECKSUMS_FILE_NAME))
+            .await
+            .map_err(|err| anyhow!("bundle has no readable {CHECKSUMS_FILE_NAME}: {err}"))?;
+
+        for name in CONTENTS {
+            let expected = checksums
+                .lines()
+                .find_map(|line| match line.split_once("  ") {
+                    Some((digest, file)) if file == name => Some(digest),
+                    _ => None,
+                })
+                .ok_or_else(|| anyhow!("{name} is not listed in {CHECKSUMS_FILE_NAME}"))?;
+
+            if !self.dir.join(name).exists() {
+                return Err(anyhow!("{name} is missing from the bundle"));
+            }
+            if sha256_file(self.dir.join(name)).await? != expected.to_ascii_lowercase() {
+                return Err(anyhow!("checksum mismatch for {name} in bundle"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Hex encoded SHA-256 of a file, which may be an EIF of several GB
+async fn sha256_file(path: PathBuf) -> Result<String> {
+    let digest = tokio::task::spawn_blocking(move || -> Result<_> {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut File::open(path)?, &mut hasher)?;
+        Ok(hasher.finalize())
+    })
+    .await??;
+
+    Ok(digest.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pack, Bundle, CONTENTS};
+    use assert2::assert;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_pack_unpack() {
+        let src = TempDir::new().unwrap();
+        for name in CONTENTS {
+            std::fs::write(src.path().join(name), format!("contents of {name}")).unwrap();
+        }
+        let bundle_path = src.path().join("bundle.tar.zst");
+        pack(src.path(), &bundle_path).await.unwrap();
+
+        let dst = TempDir::new().unwrap();
+        let bundle = Bundle::unpack(&bundle_path, dst.path()).await.unwrap();
+        assert!(std::fs::read(bundle.eif_path()).unwrap() == b"contents of application.eif");
+        assert!(std::fs::read(bundle.manifest_path()).unwrap() == b"contents of enclaver.yaml");
+
+        let sums = std::fs::read_to_string(dst.path().join("SHA256SUMS")).unwrap();
+        assert!(sums.lines().count() == 3);
+        assert!(sums.contains("  application.eif\n"));
+
+        // A file swapped after packing no longer matches SHA256SUMS
+        std::fs::write(src.path().join(CONTENTS[0]), "tampered").unwrap();
+        let mut builder = tokio_tar::Builder::new(Vec::new());
+        for name in CONTENTS.into_iter().chain(["SHA256SUMS"]) {
+            builder
+                .append_path_with_name(src.path().join(name), name)
+                .await
+                .unwrap();
+        }
+        let archive = builder.into_inner().await.unwrap();
+        let tampered = src.path().join("tampered.tar.zst");
+        std::fs::write(&tampered, zstd::encode_all(archive.as_slice(), 0).unwrap()).unwrap();
+
+        let dst = TempDir::new().unwrap();
+        assert!(Bundle::unpack(&tampered, dst.path()).await.is_err());
+    }
+}
@@ -0,0 +1,549 @@
+//! Running several enclaves, and the host services they use, together from one
+//! compose file, e.g. an application split into enclaves that call each other.
+//!
+//! Every container of a compose file joins one docker network, where the others
+//! reach it by its name. An enclave that links to another connects out through its
+//! egress proxy to the name, which lands on the ingress proxy of the other wrapper
+//! container, and from there goes over the vsock of the other enclave. Both hops
+//! stay under the policies in the manifests of the two enclaves.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use bollard::errors::Error as DockerError;
+use bollard::network::CreateNetworkOptions;
+use bollard::Docker;
+use futures_util::future::select_all;
+use log::{error, info, warn};
+use serde::Deserialize;
+
+use crate::journal::{self, unix_time};
+use crate::manifest::{load_manifest, Manifest};
+use crate::policy::EgressPolicy;
+use crate::run_container::{LogDriver, NetworkAttachment, RunWrapper};
+
+// How often the status journal of a starting enclave is looked at
+const READY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Journal events after which an enclave is not going to come up
+const ENDED_EVENTS: [&str; 5] = ["exited", "signaled", "fatal", "stopped", "error"];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ComposeFile {
+    pub version: String,
+
+    /// Prefix of the container names and name of the docker network. Defaults to
+    /// the name of the directory of the compose file.
+    pub name: Option<String>,
+
+    pub enclaves: Vec<ComposeEnclave>,
+    pub services: Option<Vec<HostService>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ComposeEnclave {
+    pub name: String,
+
+    /// Path of the Enclaver manifest of the enclave, relative to the compose file
+    pub manifest: String,
+
+    /// Image to run in place of the target of the manifest, e.g. one pinned by digest
+    pub image: Option<String>,
+
+    /// Ports to publish on the host, as with `enclaver run -p`
+    pub publish: Option<Vec<String>>,
+
+    /// Ingress ports of other enclaves, or ports of host services, that the enclave
+    /// connects to, as name:port
+    pub links: Option<Vec<String>>,
+
+    pub depends_on: Option<Vec<String>>,
+    pub debug: Option<bool>,
+}
+
+/// A plain container that runs next to the enclaves, e.g. a database they use
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostService {
+    pub name: String,
+    pub image: String,
+    pub command: Option<Vec<String>>,
+
+    /// Environment variables, as KEY=VALUE
+    pub environment: Option<Vec<String>>,
+
+    pub publish: Option<Vec<String>>,
+    pub depends_on: Option<Vec<String>>,
+}
+
+impl ComposeFile {
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        let file: ComposeFile = serde_yaml::from_slice(buf)?;
+        if file.version != "v1" {
+            return Err(anyhow!("unsupported compose file version {}", file.version));
+        }
+
+        if file.enclaves.is_empty() {
+            return Err(anyhow!("no enclaves to run"));
+        }
+
+        let mut names = HashSet::new();
+        for name in file.names() {
+            if !is_valid_name(name) {
+                return Err(anyhow!(
+                    "name {name:?} must be lowercase letters, digits and dashes, starting with a letter"
+                ));
+            }
+            if !names.insert(name) {
+                return Err(anyhow!("name {name} is used more than once"));
+            }
+        }
+
+        for enclave in &file.enclaves {
+            for link in enclave.links.iter().flatten() {
+                let (name, _) = parse_link(link)?;
+                if !names.contains(name) {
+                    return Err(anyhow!("{} links to unknown {name}", enclave.name));
+                }
+            }
+        }
+
+        file.startup_order()?;
+        Ok(file)
+    }
+
+    pub fn services(&self) -> &[HostService] {
+        self.services.as_deref().unwrap_or_default()
+    }
+
+    // Host services first, as enclaves usually depend on them
+    fn names(&self) -> impl Iterator<Item = &str> {
+        self.services()
+            .iter()
+            .map(|service| service.name.as_str())
+            .chain(self.enclaves.iter().map(|enclave| enclave.name.as_str()))
+    }
+
+    // What has to be up before name starts, links included
+    fn dependencies(&self, name: &str) -> Vec<&str> {
+        let service = self.services().iter().find(|service| service.name == name);
+        let enclave = self.enclaves.iter().find(|enclave| enclave.name == name);
+
+        let depends_on = service
+            .and_then(|service| service.depends_on.as_ref())
+            .or_else(|| enclave.and_then(|enclave| enclave.depends_on.as_ref()));
+        let links = enclave
+            .and_then(|enclave| enclave.links.as_ref())
+            .into_iter()
+            .flatten()
+            .filter_map(|link| parse_link(link).ok().map(|(name, _)| name));
+
+        depends_on
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .chain(links)
+            .collect()
+    }
+
+    /// Names in the order they are started, each after everything it depends on.
+    /// Otherwise the order of the file is kept.
+    pub fn startup_order(&self) -> Result<Vec<&str>> {
+        let names: Vec<&str> = self.names().collect();
+        for name in &names {
+            if let Some(dep) = self.dependencies(name).iter().find(|d| !names.contains(d)) {
+                return Err(anyhow!("{name} depends on unknown {dep}"));
+            }
+        }
+
+        let mut order: Vec<&str> = Vec::new();
+        while order.len() < names.len() {
+            let next = names.iter().find(|name| {
+                !order.contains(name)
+                    && self
+                        .dependencies(name)
+                        .iter()
+                        .all(|dep| order.contains(dep))
+            });
+            match next {
+                Some(name) => order.push(name),
+                None => {
+                    let left: Vec<&str> = names
+                        .iter()
+                        .filter(|name| !order.contains(name))
+                        .copied()
+                        .collect();
+                    return Err(anyhow!("dependency cycle between {}", left.join(", ")));
+                }
+            }
+        }
+
+        Ok(order)
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+// Splits name:port
+fn parse_link(link: &str) -> Result<(&str, u16)> {
+    link.split_once(':')
+        .and_then(|(name, port)| Some((name, port.parse().ok().filter(|p| *p != 0)?)))
+        .ok_or_else(|| anyhow!("link {link:?} does not match the format name:port"))
+}
+
+/// A compose file along with the manifests of its enclaves
+pub struct Compose {
+    file: ComposeFile,
+    project: String,
+    manifests: HashMap<String, Manifest>,
+}
+
+impl Compose {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let buf = tokio::fs::read(path)
+            .await
+            .map_err(|e| anyhow!("failed to read {}: {e}", path.display()))?;
+        let file = ComposeFile::parse(&buf)
+            .map_err(|e| anyhow!("invalid compose file {}: {e}", path.display()))?;
+
+        let dir = path
+            .canonicalize()?
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from("/"));
+        let project = match file.name {
+            Some(ref name) => name.clone(),
+            None => dir
+                .file_name()
+                .map(|name| name.to_string_lossy().to_ascii_lowercase())
+                .unwrap_or_else(|| "enclaver".to_string()),
+        };
+
+        let mut manifests = HashMap::new();
+        for enclave in &file.enclaves {
+            let manifest = load_manifest(dir.join(&enclave.manifest)).await?;
+            manifests.insert(enclave.name.clone(), manifest);
+        }
+
+        let compose = Self {
+            file,
+            project,
+            manifests,
+        };
+        compose.check()?;
+
+        Ok(compose)
+    }
+
+    // Checks the compose file against the manifests of its enclaves
+    fn check(&self) -> Result<()> {
+        let mut manifest_names = HashSet::new();
+        for (name, manifest) in &self.manifests {
+            // The status journals are kept by manifest name
+            if !manifest_names.insert(&manifest.name) {
+                return Err(anyhow!(
+                    "enclave {name} has the manifest name {} of another enclave",
+                    manifest.name
+                ));
+            }
+        }
+
+        for enclave in &self.file.enclaves {
+            let manifest = &self.manifests[&enclave.name];
+            for link in enclave.links.iter().flatten() {
+                let (target, port) = parse_link(link)?;
+                if let Some(other) = self.manifests.get(target) {
+                    if !other
+                        .ingress
+                        .iter()
+                        .flatten()
+                        .any(|item| item.listen_port == port)
+                    {
+                        return Err(anyhow!(
+                            "{} links to {link}, which is not an ingress port of {target}",
+                            enclave.name
+                        ));
+                    }
+                }
+
+                let allowed = manifest.egress.as_ref().is_some_and(|egress| {
+                    EgressPolicy::new(egress).is_connect_allowed(target, port)
+                });
+                if !allowed {
+                    return Err(anyhow!(
+                        "{} links to {link}, which its egress policy does not allow",
+                        enclave.name
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Name of the docker network, and prefix of the container names
+    pub fn project(&self) -> &str {
+        &self.project
+    }
+
+    /// Starts everything in the compose file in order, each enclave once it reports
+    /// that its application is running, then waits until one of them exits or
+    /// shutdown completes. Everything is stopped again in reverse order.
+    pub async fn up(
+        &self,
+        state_dir: &Path,
+        ready_timeout: Duration,
+        shutdown: impl std::future::Future<Output = ()>,
+    ) -> Result<()> {
+        let docker = Docker::connect_with_local_defaults()
+            .map_err(|e| anyhow!("connecting to docker: {e}"))?;
+        let created_network = self.create_network(&docker).await?;
+
+        let mut running: Vec<(String, RunWrapper)> = Vec::new();
+        tokio::pin!(shutdown);
+
+        let res = tokio::select! {
+            res = self.start_all(state_dir, ready_timeout, &mut running) => res,
+            _ = &mut shutdown => Err(anyhow!("interrupted while starting")),
+        };
+
+        if res.is_ok() {
+            info!("{} is up", self.project);
+            let exits = running
+                .iter_mut()
+                .map(|(name, runner)| Box::pin(async move { (name.clone(), runner.wait().await) }));
+
+            tokio::select! {
+                ((name, res), _, _) = select_all(exits) => match res {
+                    Ok(()) => info!("{name} exited, stopping the others"),
+                    Err(e) => error!("{name} failed: {e:#}, stopping the others"),
+                },
+                _ = &mut shutdown => info!("stopping {}", self.project),
+            }
+        }
+
+        for (name, runner) in running.iter_mut().rev() {
+            if let Err(e) = runner.cleanup().await {
+                warn!("failed to stop {name}: {e:#}");
+            }
+        }
+
+        if created_network {
+            if let Err(e) = docker.remove_network(&self.project).await {
+                warn!("failed to remove network {}: {e}", self.project);
+            }
+        }
+
+        res
+    }
+
+    // Creates the network of the project, unless it was left behind by an earlier run.
+    // Returns whether it was created.
+    async fn create_network(&self, docker: &Docker) -> Result<bool> {
+        let options = CreateNetworkOptions {
+            name: self.project.clone(),
+            check_duplicate: true,
+            ..Default::default()
+        };
+
+        match docker.create_network(options).await {
+            Ok(_) => Ok(true),
+            Err(DockerError::DockerResponseServerError {
+                status_code: 409, ..
+            }) => Ok(false),
+            Err(e) => Err(anyhow!("failed to create network {}: {e}", self.project)),
+        }
+    }
+
+    async fn start_all(
+        &self,
+        state_dir: &Path,
+        ready_timeout: Duration,
+        running: &mut Vec<(String, RunWrapper)>,
+    ) -> Result<()> {
+        for name in self.file.startup_order()? {
+            let runner = RunWrapper::new(LogDriver::Stdio)?
+                .with_name(Some(format!("{}-{name}", self.project)))
+                .with_network(Some(NetworkAttachment {
+                    network: self.project.clone(),
+                    alias: name.to_string(),
+                }));
+
+            if let Some(service) = self.file.services().iter().find(|s| s.name == name) {
+                info!("starting host service {name}");
+                running.push((name.to_string(), runner));
+                let (_, runner) = running.last_mut().unwrap();
+                runner
+                    .start_service_image(
+                        &service.image,
+                        service.publish.clone().unwrap_or_default(),
+                        service.command.clone(),
+                        service.environment.clone().unwrap_or_default(),
+                    )
+                    .await?;
+                continue;
+            }
+
+            let enclave = self.file.enclaves.iter().find(|e| e.name == name).unwrap();
+            let manifest = &self.manifests[name];
+            let image = enclave.image.as_ref().unwrap_or(&manifest.target);
+            let bind_addrs = manifest
+                .ingress
+                .iter()
+                .flatten()
+                .filter_map(|item| Some((item.listen_port, item.bind_addr?)))
+                .collect();
+
+            info!("starting enclave {name} from {image}");
+            let started = unix_time();
+            running.push((
+                name.to_string(),
+                runner
+                    .with_state_dir(Some(state_dir.to_path_buf()))
+                    .with_host(manifest.host.clone())
+                    .with_ingress_bind_addrs(bind_addrs),
+            ));
+            let (_, runner) = running.last_mut().unwrap();
+            runner
+                .start_enclaver_image(
+                    image,
+                    enclave.publish.clone().unwrap_or_default(),
+                    enclave.debug.unwrap_or(false),
+                    false,
+                )
+                .await?;
+
+            wait_ready(state_dir, &manifest.name, started, ready_timeout)
+                .await
+                .map_err(|e| anyhow!("enclave {name} {e}"))?;
+            info!("enclave {name} is running");
+        }
+
+        Ok(())
+    }
+}
+
+// Waits for the status journal of the enclave to record that its application is
+// running, after started
+async fn wait_ready(state_dir: &Path, name: &str, started: u64, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let history = journal::read_history(state_dir, name).unwrap_or_default();
+        for entry in history.iter().filter(|entry| entry.timestamp >= started) {
+            if entry.event == "healthy" {
+                return Ok(());
+            }
+            if ENDED_EVENTS.contains(&entry.event.as_str()) {
+                return Err(anyhow!(
+                    "ended before it was up: {} {}",
+                    entry.event,
+                    entry.describe_details()
+                ));
+            }
+        }
+
+        if Instant::now() >= deadline {
+            return Err(anyhow!("was not up after {}s", timeout.as_secs()));
+        }
+        tokio::time::sleep(READY_POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_link, Compose, ComposeFile};
+    use crate::manifest::Manifest;
+    use assert2::assert;
+    use std::collections::HashMap;
+
+    const FILE: &str = r#"
+version: v1
+enclaves:
+  - name: frontend
+    manifest: frontend/enclaver.yaml
+    publish: ["443:443"]
+    links: ["keys:8443"]
+    depends_on: [auth]
+  - name: keys
+    manifest: keys/enclaver.yaml
+  - name: auth
+    manifest: auth/enclaver.yaml
+    links: ["redis:6379"]
+services:
+  - name: redis
+    image: redis:7
+"#;
+
+    fn manifest(name: &str, rest: &str) -> Manifest {
+        let raw = format!(
+            "version: v1\nname: {name}\ntarget: {name}:latest\nsources:\n  app: app:latest\n{rest}"
+        );
+        serde_yaml::from_str(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_startup_order() {
+        let file = ComposeFile::parse(FILE.as_bytes()).unwrap();
+        assert!(file.startup_order().unwrap() == vec!["redis", "keys", "auth", "frontend"]);
+
+        let cycle = FILE.replace("links: [\"redis:6379\"]", "depends_on: [frontend]");
+        let err = ComposeFile::parse(cycle.as_bytes()).unwrap_err();
+        assert!(err.to_string() == "dependency cycle between frontend, auth");
+
+        let unknown = FILE.replace("depends_on: [auth]", "depends_on: [db]");
+        assert!(ComposeFile::parse(unknown.as_bytes()).is_err());
+        let duplicate = FILE.replace("name: redis", "name: keys");
+        assert!(ComposeFile::parse(duplicate.as_bytes()).is_err());
+        let invalid = FILE.replace("name: redis", "name: Redis_1");
+        assert!(ComposeFile::parse(invalid.as_bytes()).is_err());
+        let dangling = FILE.replace("keys:8443", "vault:8200");
+        assert!(ComposeFile::parse(dangling.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_parse_link() {
+        assert!(parse_link("keys:8443").unwrap() == ("keys", 8443));
+        assert!(parse_link("keys").is_err());
+        assert!(parse_link("keys:0").is_err());
+        assert!(parse_link("keys:https").is_err());
+    }
+
+    #[test]
+    fn test_check_links() {
+        let compose = |frontend_egress: &str, keys_ingress: &str| Compose {
+            file: ComposeFile::parse(FILE.as_bytes()).unwrap(),
+            project: "app".to_string(),
+            manifests: HashMap::from([
+                (
+                    "frontend".to_string(),
+                    manifest("frontend", frontend_egress),
+                ),
+                ("keys".to_string(), manifest("keys", keys_ingress)),
+                (
+                    "auth".to_string(),
+                    manifest("auth", "egress:\n  allow: [\"redis\"]\n"),
+                ),
+            ]),
+        };
+        let egress = "egress:\n  allow: [\"keys\"]\n";
+        let ingress = "ingress:\n  - listen_port: 8443\n";
+
+        assert!(compose(egress, ingress).check().is_ok());
+        assert!(compose("", ingress).check().is_err());
+        assert!(compose("egress:\n  allow: [\"auth\"]\n", ingress)
+            .check()
+            .is_err());
+        assert!(compose(egress, "ingress:\n  - listen_port: 443\n")
+            .check()
+            .is_err());
+    }
+}
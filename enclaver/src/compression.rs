@@ -0,0 +1,419 @@
+//! Compression of the streams odyn serves the host over vsock, the app log and the
+//! egress audit log, so that high-volume logs take less of the bandwidth the vsock
+//! shares with ingress traffic.
+//!
+//! Both streams open with the host sending the offset or event number to resume from,
+//! as a big-endian u64, and odyn answering with the one the stream starts at. A host
+//! that wants compression sets the top bit of its request and follows it with the
+//! codec, a byte, and its level, a signed byte. odyn sets the top bit of its answer in
+//! turn and follows it with the codec it settled on, after which the stream is made of
+//! frames: the lengths of the compressed and the uncompressed bytes, as big-endian
+//! u32s, then the compressed bytes. An odyn from before compression answers without
+//! the top bit, and the host opens the stream again without asking for it.
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_vsock::VsockStream;
+
+// Set on the request and the answer of a stream that negotiates compression
+const COMPRESSED: u64 = 1 << 63;
+
+const CODEC_NONE: u8 = 0;
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+
+const HEADER_LEN: usize = 8;
+
+/// Bytes compressed into one frame. Larger frames compress better but hold back what
+/// the host sees for longer.
+const FRAME_LEN: usize = 64 * 1024;
+
+// Largest frame the host accepts, compressed or not
+const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// How the streams from odyn are compressed, traded off between the CPU of the
+/// enclave and the bandwidth of the vsock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd(i8),
+}
+
+impl Compression {
+    fn to_wire(self) -> (u8, i8) {
+        match self {
+            Compression::None => (CODEC_NONE, 0),
+            Compression::Lz4 => (CODEC_LZ4, 0),
+            Compression::Zstd(level) => (CODEC_ZSTD, level),
+        }
+    }
+
+    // Codecs this build does not know are not compressed
+    fn from_wire(codec: u8, level: i8) -> Self {
+        match codec {
+            CODEC_LZ4 => Compression::Lz4,
+            CODEC_ZSTD => Compression::Zstd(level),
+            _ => Compression::None,
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::block::compress(data)),
+            Compression::Zstd(level) => zstd::bulk::compress(data, level.into()),
+        }
+    }
+
+    fn decode(self, data: &[u8], len: usize) -> std::io::Result<Vec<u8>> {
+        let decoded = match self {
+            Compression::None => data.to_vec(),
+            Compression::Lz4 => lz4_flex::block::decompress(data, len)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+            Compression::Zstd(_) => zstd::bulk::decompress(data, len)?,
+        };
+        if decoded.len() != len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("frame of {len} bytes decoded to {}", decoded.len()),
+            ));
+        }
+        Ok(decoded)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    /// One of `none`, `lz4`, `zstd` or `zstd:<level>`
+    fn from_str(s: &str) -> Result<Self> {
+        let (codec, level) = match s.split_once(':') {
+            Some((codec, level)) => (codec, Some(level)),
+            None => (s, None),
+        };
+
+        match (codec, level) {
+            ("none", None) => Ok(Compression::None),
+            ("lz4", None) => Ok(Compression::Lz4),
+            ("zstd", None) => Ok(Compression::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL as i8)),
+            ("zstd", Some(level)) => {
+                let level: i8 = level
+                    .parse()
+                    .map_err(|_| anyhow!("invalid zstd level {level}"))?;
+                if !zstd::compression_level_range().contains(&level.into()) {
+                    return Err(anyhow!("invalid zstd level {level}"));
+                }
+                Ok(Compression::Zstd(level))
+            }
+            _ => Err(anyhow!(
+                "unknown compression {s}, expected none, lz4, zstd or zstd:<level>"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd(level) => write!(f, "zstd:{level}"),
+        }
+    }
+}
+
+/// The request a stream was opened with (enclave side)
+pub struct StreamRequest {
+    /// Offset or event number the host wants to resume from
+    pub cursor: u64,
+
+    // Unset for a host that did not negotiate compression
+    compression: Option<Compression>,
+}
+
+impl StreamRequest {
+    pub async fn read<S: AsyncRead + Unpin>(conn: &mut S) -> Result<Self> {
+        let request = conn.read_u64().await?;
+        if request & COMPRESSED == 0 {
+            return Ok(Self {
+                cursor: request,
+                compression: None,
+            });
+        }
+
+        let codec = conn.read_u8().await?;
+        let level = conn.read_i8().await?;
+        Ok(Self {
+            cursor: request & !COMPRESSED,
+            compression: Some(Compression::from_wire(codec, level)),
+        })
+    }
+
+    /// Answers with the offset or event number the stream starts at, and returns the
+    /// writer of the rest of the stream
+    pub async fn start<W: AsyncWrite + Unpin>(
+        &self,
+        mut conn: W,
+        start: u64,
+    ) -> Result<FrameWriter<W>> {
+        let compression = match self.compression {
+            Some(compression) => {
+                conn.write_u64(start | COMPRESSED).await?;
+                conn.write_u8(compression.to_wire().0).await?;
+                compression
+            }
+            None => {
+                conn.write_u64(start).await?;
+                Compression::None
+            }
+        };
+
+        Ok(FrameWriter {
+            inner: conn,
+            compression,
+        })
+    }
+}
+
+/// Writes a stream in the frames of the compression negotiated, or as is without
+pub struct FrameWriter<W> {
+    inner: W,
+    compression: Compression,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    /// Bytes worth passing to write at once
+    pub fn chunk_len(&self) -> usize {
+        match self.compression {
+            Compression::None => 4096,
+            _ => FRAME_LEN,
+        }
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if self.compression == Compression::None {
+            return self.inner.write_all(data).await;
+        }
+
+        for chunk in data.chunks(FRAME_LEN) {
+            let frame = self.compression.encode(chunk)?;
+            self.inner.write_u32(frame.len() as u32).await?;
+            self.inner.write_u32(chunk.len() as u32).await?;
+            self.inner.write_all(&frame).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A stream from odyn (host side), decompressed if compression was negotiated
+pub struct Decoded<R> {
+    inner: R,
+    compression: Compression,
+
+    // The frame being read, and the decompressed bytes of the last one not yet read
+    frame: Vec<u8>,
+    filled: usize,
+    decoded: Vec<u8>,
+    pos: usize,
+}
+
+impl<R> Decoded<R> {
+    fn new(inner: R, compression: Compression) -> Self {
+        Self {
+            inner,
+            compression,
+            frame: Vec::new(),
+            filled: 0,
+            decoded: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+}
+
+fn frame_len(header: &[u8]) -> (usize, usize) {
+    let compressed = u32::from_be_bytes(header[..4].try_into().unwrap());
+    let len = u32::from_be_bytes(header[4..HEADER_LEN].try_into().unwrap());
+    (compressed as usize, len as usize)
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Decoded<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.compression == Compression::None {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+
+        let this = &mut *self;
+        loop {
+            if this.pos < this.decoded.len() {
+                let n = buf.remaining().min(this.decoded.len() - this.pos);
+                buf.put_slice(&this.decoded[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            let wanted = if this.filled < HEADER_LEN {
+                HEADER_LEN
+            } else {
+                let (compressed, len) = frame_len(&this.frame);
+                if compressed > MAX_FRAME_LEN || len > MAX_FRAME_LEN {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("frame of {compressed} bytes for {len} exceeds {MAX_FRAME_LEN}"),
+                    )));
+                }
+                HEADER_LEN + compressed
+            };
+
+            if this.filled == wanted {
+                let (_, len) = frame_len(&this.frame);
+                this.decoded = this
+                    .compression
+                    .decode(&this.frame[HEADER_LEN..wanted], len)?;
+                this.pos = 0;
+                this.filled = 0;
+                continue;
+            }
+
+            this.frame.resize(wanted, 0);
+            let mut read = ReadBuf::new(&mut this.frame[this.filled..wanted]);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            let n = read.filled().len();
+            if n == 0 {
+                if this.filled == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+            this.filled += n;
+        }
+    }
+}
+
+/// Opens a stream on a connection to odyn (host side), resuming at cursor. Returns the
+/// offset or event number the stream starts at, and None if odyn answered without
+/// compression because it predates it.
+async fn open<S>(
+    mut conn: S,
+    cursor: u64,
+    compression: Compression,
+) -> Result<Option<(Decoded<S>, u64)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if compression == Compression::None {
+        conn.write_u64(cursor).await?;
+        let start = conn.read_u64().await?;
+        return Ok(Some((Decoded::new(conn, Compression::None), start)));
+    }
+
+    let (codec, level) = compression.to_wire();
+    conn.write_u64(cursor | COMPRESSED).await?;
+    conn.write_u8(codec).await?;
+    conn.write_i8(level).await?;
+
+    let start = conn.read_u64().await?;
+    if start & COMPRESSED == 0 {
+        return Ok(None);
+    }
+    let accepted = Compression::from_wire(conn.read_u8().await?, level);
+    Ok(Some((Decoded::new(conn, accepted), start & !COMPRESSED)))
+}
+
+/// Connects to a stream odyn serves on the given vsock port (host side), resuming at
+/// cursor, and returns it along with the offset or event number it starts at. If the
+/// enclave predates compression, compression is turned off for the reconnects too.
+pub async fn connect(
+    cid: u32,
+    port: u32,
+    cursor: u64,
+    compression: &mut Compression,
+) -> Result<(Decoded<VsockStream>, u64)> {
+    let conn = VsockStream::connect(cid, port).await?;
+    if let Some(opened) = open(conn, cursor, *compression).await? {
+        return Ok(opened);
+    }
+
+    warn!("the enclave on vsock port {port} does not compress its streams, reconnecting without");
+    *compression = Compression::None;
+    let conn = VsockStream::connect(cid, port).await?;
+    open(conn, cursor, Compression::None)
+        .await?
+        .ok_or_else(|| anyhow!("unexpected answer from vsock port {port}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open, Compression, StreamRequest};
+    use assert2::assert;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_parse() {
+        assert!("none".parse::<Compression>().unwrap() == Compression::None);
+        assert!("lz4".parse::<Compression>().unwrap() == Compression::Lz4);
+        assert!("zstd".parse::<Compression>().unwrap() == Compression::Zstd(3));
+        assert!("zstd:-5".parse::<Compression>().unwrap() == Compression::Zstd(-5));
+        assert!("zstd:19".parse::<Compression>().unwrap().to_string() == "zstd:19");
+        assert!("zstd:100".parse::<Compression>().is_err());
+        assert!("lz4:1".parse::<Compression>().is_err());
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_streams() {
+        let data: Vec<u8> = (0..200_000u32)
+            .flat_map(|i| format!("line {}\n", i % 1000).into_bytes())
+            .collect();
+
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd(1)] {
+            let (host, mut enclave) = tokio::io::duplex(64 * 1024);
+            let sent = data.clone();
+            let server = tokio::task::spawn(async move {
+                let request = StreamRequest::read(&mut enclave).await.unwrap();
+                assert!(request.cursor == 10);
+                let mut writer = request.start(&mut enclave, 12).await.unwrap();
+                writer.write(&sent).await.unwrap();
+                enclave.shutdown().await.unwrap();
+            });
+
+            let (mut stream, start) = open(host, 10, compression).await.unwrap().unwrap();
+            assert!(start == 12);
+            assert!(stream.compression() == compression);
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            assert!(received == data);
+            server.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_legacy_enclave() {
+        // An odyn from before compression reads the request as a cursor, and answers
+        // with one of its own
+        let (host, mut enclave) = tokio::io::duplex(1024);
+        let server = tokio::task::spawn(async move {
+            enclave.read_u64().await.unwrap();
+            enclave.write_u64(0).await.unwrap();
+        });
+
+        assert!(open(host, 0, Compression::Lz4).await.unwrap().is_none());
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use log::{debug, error, info};
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::sync::watch;
+use tokio_vsock::VsockStream;
+
+use crate::attestation::AttestationVerifier;
+use crate::constants::CONFIG_PROVIDER_PORT;
+use crate::identity::{IdentityRecord, VerifiedAttestation};
+use crate::journal::unix_time;
+use crate::vsock::{self, VMADDR_CID_HOST};
+
+const NONCE_SIZE: usize = 32;
+const MAX_MESSAGE_SIZE: u64 = 1024 * 1024;
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The config provider protocol is a single exchange of newline delimited JSON
+// messages: the host sends a Challenge, the enclave answers with Evidence and
+// the host either releases the config or explains why it refused to.
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Challenge {
+    nonce: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Evidence {
+    attestation: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Release {
+    Released(AttestedPayload),
+    Error(String),
+}
+
+// What the host releases to an attested enclave: an optional config blob and
+// any number of named secret files. All contents are base64 encoded.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttestedPayload {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub secret_files: BTreeMap<String, String>,
+}
+
+impl AttestedPayload {
+    pub fn new(config: Option<&[u8]>, secret_files: &BTreeMap<String, Vec<u8>>) -> Self {
+        Self {
+            config: config.map(base64::encode),
+            secret_files: secret_files
+                .iter()
+                .map(|(name, contents)| (name.clone(), base64::encode(contents)))
+                .collect(),
+        }
+    }
+
+    pub fn config(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.config.as_ref().map(base64::decode).transpose()?)
+    }
+
+    pub fn secret_files(&self) -> Result<BTreeMap<String, Vec<u8>>> {
+        self.secret_files
+            .iter()
+            .map(|(name, contents)| Ok((name.clone(), base64::decode(contents)?)))
+            .collect()
+    }
+}
+
+// Releases a payload to the enclave (host side), but only once the enclave has
+// proven with a fresh attestation document that it runs the expected image.
+pub struct ConfigProvider {
+    verifier: AttestationVerifier,
+    payload: AttestedPayload,
+    identity: Option<IdentityRecord>,
+}
+
+impl ConfigProvider {
+    pub fn new(verifier: AttestationVerifier, payload: AttestedPayload) -> Self {
+        Self {
+            verifier,
+            payload,
+            identity: None,
+        }
+    }
+
+    // Record each verified attestation as the enclave's current identity
+    pub fn with_identity(mut self, identity: IdentityRecord) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    // Only challenges the enclave whose CID enclave_cid is given once it is launched, so
+    // no other enclave on the host can ask for the config
+    pub async fn serve(self, mut enclave_cid: watch::Receiver<Option<u32>>) -> Result<()> {
+        let mut incoming = vsock::serve(CONFIG_PROVIDER_PORT)?;
+
+        while let Some(conn) = incoming.next().await {
+            let peer_cid = match conn.peer_addr() {
+                Ok(addr) => addr.cid(),
+                Err(err) => {
+                    error!("refused to release attested config to an unknown peer: {err}");
+                    continue;
+                }
+            };
+
+            // The enclave can connect before nitro-cli has told us its CID
+            let expected_cid = match enclave_cid.wait_for(Option::is_some).await {
+                Ok(cid) => cid.unwrap_or_default(),
+                Err(_) => return Ok(()),
+            };
+            if let Err(err) = check_peer(peer_cid, expected_cid) {
+                error!("refused to release attested config: {err}");
+                continue;
+            }
+
+            let mut conn = BufReader::new(conn);
+            match tokio::time::timeout(EXCHANGE_TIMEOUT, self.exchange(&mut conn)).await {
+                Ok(Ok(())) => info!("released attested config to the enclave"),
+                Ok(Err(err)) => error!("refused to release attested config: {err}"),
+                Err(_) => error!("timed out waiting for the enclave attestation"),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn exchange<S>(&self, conn: &mut S) -> Result<()>
+    where
+        S: AsyncBufRead + AsyncWrite + Unpin,
+    {
+        let mut nonce = vec![0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        write_message(
+            conn,
+            &Challenge {
+                nonce: base64::encode(&nonce),
+            },
+        )
+        .await?;
+
+        let evidence: Evidence = read_message(conn).await?;
+        let release = match base64::decode(evidence.attestation)
+            .map_err(anyhow::Error::from)
+            .and_then(|doc| Ok((self.verifier.verify(&doc, &nonce)?, doc)))
+        {
+            Ok((att_doc, doc)) => {
+                if let Some(ref identity) = self.identity {
+                    identity.set_attestation(VerifiedAttestation {
+                        fingerprint: Sha256::digest(&doc)
+                            .iter()
+                            .map(|b| format!("{b:02x}"))
+                            .collect(),
+                        module_id: att_doc.module_id,
+                        verified_at: unix_time(),
+                    });
+                }
+                Release::Released(self.payload.clone())
+            }
+            Err(err) => {
+                write_message(conn, &Release::Error(err.to_string())).await?;
+                return Err(err);
+            }
+        };
+
+        write_message(conn, &release).await
+    }
+}
+
+fn check_peer(peer_cid: u32, enclave_cid: u32) -> Result<()> {
+    if peer_cid != enclave_cid {
+        return Err(anyhow!(
+            "CID {peer_cid} is not that of the enclave, {enclave_cid}"
+        ));
+    }
+    Ok(())
+}
+
+// Fetches the attested payload from the host (enclave side), using `attest` to
+// produce an attestation document over the host's nonce. Returns None if the
+// host is not serving one.
+pub async fn fetch<F>(attest: F) -> Result<Option<AttestedPayload>>
+where
+    F: FnOnce(Vec<u8>) -> Result<Vec<u8>>,
+{
+    let conn = match VsockStream::connect(VMADDR_CID_HOST, CONFIG_PROVIDER_PORT).await {
+        // VsockStream::connect can return Ok even if the connect failed
+        Ok(conn) if conn.peer_addr().is_ok() => conn,
+        _ => {
+            debug!("no attested config available from the host");
+            return Ok(None);
+        }
+    };
+
+    let mut conn = BufReader::new(conn);
+
+    let challenge: Challenge = read_message(&mut conn).await?;
+    let doc = attest(base64::decode(challenge.nonce)?)?;
+    write_message(
+        &mut conn,
+        &Evidence {
+            attestation: base64::encode(doc),
+        },
+    )
+    .await?;
+
+    match read_message(&mut conn).await? {
+        Release::Released(payload) => Ok(Some(payload)),
+        Release::Error(err) => Err(anyhow!("host refused to release the config: {err}")),
+    }
+}
+
+async fn read_message<S, T>(conn: &mut S) -> Result<T>
+where
+    S: AsyncBufRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut buf = Vec::new();
+    conn.take(MAX_MESSAGE_SIZE + 1)
+        .read_until(b'\n', &mut buf)
+        .await?;
+
+    if buf.len() as u64 > MAX_MESSAGE_SIZE {
+        return Err(anyhow!("message exceeds {MAX_MESSAGE_SIZE} bytes"));
+    }
+
+    if buf.is_empty() {
+        return Err(anyhow!("connection closed by peer"));
+    }
+
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+async fn write_message<S, T>(conn: &mut S, msg: &T) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let mut buf = serde_json::to_vec(msg)?;
+    buf.push(b'\n');
+    conn.write_all(&buf).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_peer, read_message, write_message, AttestedPayload, Release};
+    use assert2::assert;
+    use std::collections::BTreeMap;
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn test_messages() {
+        let payload = AttestedPayload::new(Some(b"foo"), &BTreeMap::new());
+
+        let mut buf = Vec::new();
+        write_message(&mut buf, &Release::Released(payload.clone()))
+            .await
+            .unwrap();
+        write_message(&mut buf, &Release::Error("nope".to_string()))
+            .await
+            .unwrap();
+        assert!(buf == b"{\"released\":{\"config\":\"Zm9v\"}}\n{\"error\":\"nope\"}\n");
+
+        let mut conn = BufReader::new(&buf[..]);
+        let release: Release = read_message(&mut conn).await.unwrap();
+        assert!(matches!(release, Release::Released(p) if p == payload));
+        let release: Release = read_message(&mut conn).await.unwrap();
+        assert!(matches!(release, Release::Error(e) if e == "nope"));
+        assert!(read_message::<_, Release>(&mut conn).await.is_err());
+    }
+
+    #[test]
+    fn test_check_peer() {
+        assert!(check_peer(16, 16).is_ok());
+        assert!(check_peer(17, 16).is_err());
+        assert!(check_peer(2, 16).is_err());
+    }
+
+    #[test]
+    fn test_payload() {
+        let secret_files = BTreeMap::from([("api-token".to_string(), b"s3cr3t".to_vec())]);
+        let payload = AttestedPayload::new(None, &secret_files);
+
+        assert!(payload.config().unwrap() == None);
+        assert!(payload.secret_files().unwrap() == secret_files);
+    }
+}
@@ -0,0 +1,243 @@
+//! The connections the proxies of a debug enclave are relaying, for finding leaked
+//! connections or the policy a tunnel went through. odyn lists them at
+//! /v1/debug/connections of its API and on a vsock port of their own, which
+//! `enclaver debug conns` reads from the host.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_vsock::VsockStream;
+
+use crate::constants::DEBUG_CONNECTIONS_PORT;
+use crate::metrics::StreamMetrics;
+
+const MAX_CONNECTIONS_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionKind {
+    Ingress,
+    Egress,
+}
+
+impl std::fmt::Display for ConnectionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionKind::Ingress => write!(f, "ingress"),
+            ConnectionKind::Egress => write!(f, "egress"),
+        }
+    }
+}
+
+/// A connection as it stands. Bytes are counted on the side that opened it, the
+/// client of an ingress port or the application for egress.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub kind: ConnectionKind,
+
+    // The ingress port, or the port of the egress proxy
+    pub port: u16,
+    pub peer: String,
+    pub target: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+
+    pub received_bytes: u64,
+    pub sent_bytes: u64,
+    pub age_seconds: u64,
+}
+
+struct Entry {
+    kind: ConnectionKind,
+    port: u16,
+    peer: String,
+    target: String,
+    server_name: Option<String>,
+    counts: StreamMetrics,
+    started: Instant,
+}
+
+#[derive(Default)]
+struct Entries {
+    next_id: u64,
+    open: BTreeMap<u64, Entry>,
+}
+
+/// The open connections of the proxies that were given the table
+#[derive(Clone, Default)]
+pub struct ConnectionTable {
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl ConnectionTable {
+    /// Lists a connection until the returned guard is dropped. Its bytes are those
+    /// counted by the streams wrapped with the counts of the guard.
+    pub fn track(
+        &self,
+        kind: ConnectionKind,
+        port: u16,
+        peer: String,
+        target: String,
+    ) -> TrackedConnection {
+        let counts = StreamMetrics::default();
+        let mut entries = self.entries.lock().unwrap();
+        entries.next_id += 1;
+        let id = entries.next_id;
+        entries.open.insert(
+            id,
+            Entry {
+                kind,
+                port,
+                peer,
+                target,
+                server_name: None,
+                counts: counts.clone(),
+                started: Instant::now(),
+            },
+        );
+
+        TrackedConnection {
+            table: self.clone(),
+            id,
+            counts,
+        }
+    }
+
+    /// The open connections, oldest first
+    pub fn snapshot(&self) -> Vec<ConnectionInfo> {
+        self.entries
+            .lock()
+            .unwrap()
+            .open
+            .iter()
+            .map(|(id, entry)| ConnectionInfo {
+                id: *id,
+                kind: entry.kind,
+                port: entry.port,
+                peer: entry.peer.clone(),
+                target: entry.target.clone(),
+                server_name: entry.server_name.clone(),
+                received_bytes: entry.counts.received_bytes(),
+                sent_bytes: entry.counts.sent_bytes(),
+                age_seconds: entry.started.elapsed().as_secs(),
+            })
+            .collect()
+    }
+}
+
+pub struct TrackedConnection {
+    table: ConnectionTable,
+    id: u64,
+    counts: StreamMetrics,
+}
+
+impl TrackedConnection {
+    pub fn counts(&self) -> &StreamMetrics {
+        &self.counts
+    }
+
+    /// Records the TLS server name the client asked for
+    pub fn set_server_name(&self, server_name: Option<&str>) {
+        if let Some(entry) = self.table.entries.lock().unwrap().open.get_mut(&self.id) {
+            entry.server_name = server_name.map(str::to_string);
+        }
+    }
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        self.table.entries.lock().unwrap().open.remove(&self.id);
+    }
+}
+
+/// Lists the open connections of the enclave with the given CID (host side). Only
+/// debug enclaves answer.
+pub async fn fetch(cid: u32) -> Result<Vec<ConnectionInfo>> {
+    let mut conn = VsockStream::connect(cid, DEBUG_CONNECTIONS_PORT)
+        .await
+        .map_err(|e| {
+            anyhow!("failed to connect to enclave {cid}, only debug enclaves list connections: {e}")
+        })?;
+
+    read_connections(&mut conn).await
+}
+
+async fn read_connections<S: AsyncRead + Unpin>(conn: &mut S) -> Result<Vec<ConnectionInfo>> {
+    let mut buf = Vec::new();
+    conn.take(MAX_CONNECTIONS_SIZE + 1)
+        .read_to_end(&mut buf)
+        .await?;
+    if buf.len() as u64 > MAX_CONNECTIONS_SIZE {
+        return Err(anyhow!(
+            "connection list exceeds {MAX_CONNECTIONS_SIZE} bytes"
+        ));
+    }
+
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+/// Answers a connection with the open connections of the table (enclave side)
+pub async fn reply<S: AsyncWrite + Unpin>(conn: &mut S, table: &ConnectionTable) -> Result<()> {
+    conn.write_all(&serde_json::to_vec(&table.snapshot())?)
+        .await?;
+    conn.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_connections, reply, ConnectionKind, ConnectionTable};
+    use assert2::assert;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_connection_table() {
+        let table = ConnectionTable::default();
+        let ingress = table.track(
+            ConnectionKind::Ingress,
+            443,
+            "vsock:3:5000".to_string(),
+            "127.0.0.1:8443".to_string(),
+        );
+        ingress.set_server_name(Some("api.example.com"));
+        let egress = table.track(
+            ConnectionKind::Egress,
+            10000,
+            "127.0.0.1:40000".to_string(),
+            "example.com:443".to_string(),
+        );
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut counted = ingress.counts().wrap(server);
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        counted.read_exact(&mut buf).await.unwrap();
+        counted.write_all(b"hi").await.unwrap();
+
+        let snapshot = table.snapshot();
+        assert!(snapshot.len() == 2);
+        assert!(snapshot[0].kind == ConnectionKind::Ingress);
+        assert!(snapshot[0].server_name.as_deref() == Some("api.example.com"));
+        assert!(snapshot[0].received_bytes == 5);
+        assert!(snapshot[0].sent_bytes == 2);
+        assert!(snapshot[1].target == "example.com:443");
+        assert!(snapshot[1].id > snapshot[0].id);
+
+        drop(ingress);
+        let snapshot = table.snapshot();
+        assert!(snapshot.len() == 1);
+        assert!(snapshot[0].kind == ConnectionKind::Egress);
+
+        let (mut host, mut enclave) = tokio::io::duplex(4096);
+        reply(&mut enclave, &table).await.unwrap();
+        assert!(read_connections(&mut host).await.unwrap() == snapshot);
+
+        drop(egress);
+        assert!(table.snapshot().is_empty());
+    }
+}
@@ -1,22 +1,47 @@
 // Path and filename constants
 pub const EIF_FILE_NAME: &str = "application.eif";
 pub const MANIFEST_FILE_NAME: &str = "enclaver.yaml";
+pub const COMPOSE_FILE_NAME: &str = "enclaver-compose.yaml";
 
 pub const ENCLAVE_CONFIG_DIR: &str = "/etc/enclaver";
 pub const ENCLAVE_ODYN_PATH: &str = "/sbin/odyn";
 
 pub const RELEASE_BUNDLE_DIR: &str = "/enclave";
 
+// Always handed to the wrapper container, which launches the enclave with it
+pub const NITRO_ENCLAVES_DEVICE: &str = "/dev/nitro_enclaves";
+
+// Resources of the enclave, unless the manifest or enclaver-run flags say otherwise
+pub const DEFAULT_CPU_COUNT: i32 = 2;
+pub const DEFAULT_MEMORY_MB: i32 = 4096;
+
+// Memory of the enclave kept for odyn and its proxies, on top of that of the app
+pub const DEFAULT_SUPERVISOR_MEMORY_MB: i32 = 256;
+
 // Port Constants
 
 // start "internal" ports above the 16-bit boundary (reserved for proxying TCP)
 pub const STATUS_PORT: u32 = 17000;
 pub const APP_LOG_PORT: u32 = 17001;
 pub const HTTP_EGRESS_VSOCK_PORT: u32 = 17002;
+pub const BOOT_CONFIG_PORT: u32 = 17003;
+pub const CONFIG_PROVIDER_PORT: u32 = 17004;
+pub const DNS_VSOCK_PORT: u32 = 17005;
+pub const UDP_EGRESS_VSOCK_PORT: u32 = 17006;
+pub const POLICY_UPDATE_PORT: u32 = 17007;
+pub const EGRESS_AUDIT_PORT: u32 = 17008;
+pub const DEBUG_CONNECTIONS_PORT: u32 = 17009;
+pub const TLS_ISSUANCE_PORT: u32 = 17010;
+pub const DEBUG_BENCH_PORT: u32 = 17011;
+pub const DRAIN_PORT: u32 = 17012;
 
 // Default TCP Port that the egress proxy listens on inside the enclave, if not
 // specified in the manifest.
 pub const HTTP_EGRESS_PROXY_PORT: u16 = 10000;
 
+// TCP Port inside the enclave that outbound connections are redirected to in
+// transparent egress mode.
+pub const TRANSPARENT_EGRESS_PORT: u16 = 10001;
+
 // The hostname to refer to the host side from inside the enclave.
 pub const OUTSIDE_HOST: &str = "host";
@@ -0,0 +1,278 @@
+//! Which CPUs of the host an enclave was given, and keeping the threads of
+//! enclaver-run, the host proxies among them, off the cores of the enclave. On large
+//! instances the allocator may give an enclave CPUs whose hyperthread siblings, or
+//! NUMA node, the proxies serving it would otherwise share or cross.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+
+const SYSFS_CPU_DIR: &str = "/sys/devices/system/cpu";
+const TASKS_DIR: &str = "/proc/self/task";
+
+/// Which CPUs the threads of enclaver-run are pinned to once the enclave has started
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyPinning {
+    /// The online CPUs that share no core with the enclave, those on its NUMA node
+    /// if there are any
+    Auto,
+    Cpus(Vec<u32>),
+}
+
+/// Where an enclave and the host proxies serving it run, as recorded when it starts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuAssignment {
+    pub enclave: Vec<u32>,
+
+    /// NUMA node of the CPUs of the enclave, if they are all on one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numa_node: Option<u32>,
+
+    /// CPUs the host proxies were pinned to. Unset if they run on any CPU.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_proxies: Option<Vec<u32>>,
+}
+
+#[derive(Debug)]
+struct Cpu {
+    online: bool,
+    siblings: Vec<u32>,
+    node: Option<u32>,
+}
+
+/// The CPUs of the host, as sysfs describes them
+#[derive(Debug, Default)]
+pub struct CpuTopology {
+    cpus: BTreeMap<u32, Cpu>,
+}
+
+impl CpuTopology {
+    pub fn read() -> Result<Self> {
+        Self::read_from(Path::new(SYSFS_CPU_DIR))
+    }
+
+    fn read_from(dir: &Path) -> Result<Self> {
+        let mut cpus = BTreeMap::new();
+        for dirent in fs::read_dir(dir)? {
+            let path = dirent?.path();
+            let id = match path
+                .file_name()
+                .and_then(|name| name.to_str()?.strip_prefix("cpu")?.parse().ok())
+            {
+                Some(id) => id,
+                None => continue,
+            };
+
+            // CPU 0 can usually not be taken offline, and has no online file
+            let online =
+                fs::read_to_string(path.join("online")).map_or(true, |online| online.trim() == "1");
+            // Offline CPUs, like those given to enclaves, have no topology
+            let siblings = fs::read_to_string(path.join("topology/thread_siblings_list"))
+                .ok()
+                .and_then(|list| parse_cpu_list(&list).ok())
+                .unwrap_or_else(|| vec![id]);
+            let node = fs::read_dir(&path)?.flatten().find_map(|dirent| {
+                dirent
+                    .file_name()
+                    .to_str()?
+                    .strip_prefix("node")?
+                    .parse()
+                    .ok()
+            });
+
+            cpus.insert(
+                id,
+                Cpu {
+                    online,
+                    siblings,
+                    node,
+                },
+            );
+        }
+
+        if cpus.is_empty() {
+            return Err(anyhow!("no CPUs in {}", dir.display()));
+        }
+
+        Ok(Self { cpus })
+    }
+
+    /// NUMA node of the CPUs, if they are all on the same one
+    pub fn numa_node(&self, cpus: &[u32]) -> Option<u32> {
+        let mut nodes = cpus
+            .iter()
+            .map(|id| self.cpus.get(id).and_then(|cpu| cpu.node));
+        let first = nodes.next()??;
+        nodes.all(|node| node == Some(first)).then_some(first)
+    }
+
+    /// Online CPUs that share no core with the enclave CPUs, only those on their
+    /// NUMA node if there are any
+    pub fn proxy_cpus(&self, enclave: &[u32]) -> Vec<u32> {
+        let free: Vec<(&u32, &Cpu)> = self
+            .cpus
+            .iter()
+            .filter(|(id, cpu)| {
+                cpu.online
+                    && !enclave.contains(id)
+                    && !cpu.siblings.iter().any(|sibling| enclave.contains(sibling))
+            })
+            .collect();
+
+        let node = self.numa_node(enclave);
+        let local: Vec<u32> = free
+            .iter()
+            .filter(|(_, cpu)| node.is_some() && cpu.node == node)
+            .map(|(id, _)| **id)
+            .collect();
+
+        match local.is_empty() {
+            true => free.iter().map(|(id, _)| **id).collect(),
+            false => local,
+        }
+    }
+}
+
+/// Records where the enclave runs, and pins the threads of enclaver-run as asked.
+/// Pinning is best effort, the enclave runs either way.
+pub fn assign(enclave: Vec<u32>, pinning: Option<&ProxyPinning>) -> CpuAssignment {
+    let topology = CpuTopology::read().unwrap_or_else(|e| {
+        warn!("failed to read the CPU topology: {e}");
+        CpuTopology::default()
+    });
+
+    let host_proxies = match pinning {
+        None => None,
+        Some(ProxyPinning::Cpus(cpus)) => Some(cpus.clone()),
+        Some(ProxyPinning::Auto) => Some(topology.proxy_cpus(&enclave)),
+    };
+    let host_proxies = match host_proxies {
+        Some(cpus) if cpus.is_empty() => {
+            warn!("no CPUs left for the host proxies, not pinning them");
+            None
+        }
+        Some(cpus) => match pin_threads(&cpus) {
+            Ok(()) => {
+                info!("pinned the host proxies to CPUs {cpus:?}");
+                Some(cpus)
+            }
+            Err(e) => {
+                warn!("failed to pin the host proxies to CPUs {cpus:?}: {e}");
+                None
+            }
+        },
+        None => None,
+    };
+
+    CpuAssignment {
+        numa_node: topology.numa_node(&enclave),
+        enclave,
+        host_proxies,
+    }
+}
+
+// Pins every thread of the process. Threads started later inherit the CPUs of the
+// thread that starts them.
+fn pin_threads(cpus: &[u32]) -> Result<()> {
+    let mut set = CpuSet::new();
+    for cpu in cpus {
+        set.set(*cpu as usize)?;
+    }
+
+    for dirent in fs::read_dir(TASKS_DIR)? {
+        let tid = match dirent?
+            .file_name()
+            .to_str()
+            .and_then(|tid| tid.parse().ok())
+        {
+            Some(tid) => tid,
+            None => continue,
+        };
+
+        // The thread may have exited in the meantime
+        match sched_setaffinity(Pid::from_raw(tid), &set) {
+            Ok(()) | Err(Errno::ESRCH) => {}
+            Err(e) => return Err(anyhow!("thread {tid}: {e}")),
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses a list of CPUs as the kernel prints them, e.g. 0-3,8
+pub fn parse_cpu_list(list: &str) -> Result<Vec<u32>> {
+    let mut cpus = Vec::new();
+
+    for item in list.trim().split(',').filter(|i| !i.is_empty()) {
+        match item.split_once('-') {
+            Some((start, end)) => {
+                let start: u32 = start.trim().parse()?;
+                let end: u32 = end.trim().parse()?;
+                if end < start {
+                    return Err(anyhow!("invalid CPU range {item}"));
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(item.trim().parse()?),
+        }
+    }
+
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cpu_list, CpuTopology};
+    use assert2::assert;
+    use std::fs;
+    use std::path::Path;
+
+    fn add_cpu(dir: &Path, id: u32, online: bool, siblings: Option<&str>, node: u32) {
+        let cpu = dir.join(format!("cpu{id}"));
+        fs::create_dir_all(cpu.join(format!("node{node}"))).unwrap();
+        fs::write(cpu.join("online"), if online { "1\n" } else { "0\n" }).unwrap();
+        if let Some(siblings) = siblings {
+            fs::create_dir(cpu.join("topology")).unwrap();
+            fs::write(cpu.join("topology/thread_siblings_list"), siblings).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert!(parse_cpu_list("0-3,8\n").unwrap() == vec![0, 1, 2, 3, 8]);
+        assert!(parse_cpu_list("").unwrap().is_empty());
+        assert!(parse_cpu_list("3-1").is_err());
+    }
+
+    #[test]
+    fn test_proxy_cpus() {
+        // Two nodes of two cores with two threads each. CPU 3 was given to an
+        // enclave, while CPU 7 on the same core was left online.
+        let dir = tempfile::tempdir().unwrap();
+        add_cpu(dir.path(), 0, true, Some("0,4"), 0);
+        add_cpu(dir.path(), 4, true, Some("0,4"), 0);
+        add_cpu(dir.path(), 1, true, Some("1,5"), 0);
+        add_cpu(dir.path(), 5, true, Some("1,5"), 0);
+        add_cpu(dir.path(), 2, true, Some("2,6"), 1);
+        add_cpu(dir.path(), 6, true, Some("2,6"), 1);
+        add_cpu(dir.path(), 3, false, None, 1);
+        add_cpu(dir.path(), 7, true, Some("3,7"), 1);
+        fs::create_dir(dir.path().join("cpufreq")).unwrap();
+
+        let topology = CpuTopology::read_from(dir.path()).unwrap();
+        assert!(topology.numa_node(&[3, 7]) == Some(1));
+        assert!(topology.numa_node(&[1, 3]).is_none());
+        assert!(topology.proxy_cpus(&[3]) == vec![2, 6]);
+        assert!(topology.proxy_cpus(&[1]) == vec![0, 4]);
+
+        // Nothing is left on the node of the enclave
+        assert!(topology.proxy_cpus(&[2, 3, 6, 7]) == vec![0, 1, 4, 5]);
+    }
+}
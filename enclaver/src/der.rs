@@ -0,0 +1,100 @@
+//! Just enough DER to read and write the X.509 certificates the enclave deals with
+//! itself, without a full ASN.1 library.
+
+pub(crate) const BOOLEAN: u8 = 0x01;
+pub(crate) const INTEGER: u8 = 0x02;
+pub(crate) const BIT_STRING: u8 = 0x03;
+pub(crate) const OCTET_STRING: u8 = 0x04;
+pub(crate) const NULL: u8 = 0x05;
+pub(crate) const OID: u8 = 0x06;
+pub(crate) const UTF8_STRING: u8 = 0x0c;
+pub(crate) const UTC_TIME: u8 = 0x17;
+pub(crate) const SEQUENCE: u8 = 0x30;
+pub(crate) const SET: u8 = 0x31;
+
+// The explicitly tagged fields of a TBSCertificate
+pub(crate) const VERSION: u8 = 0xa0;
+pub(crate) const EXTENSIONS: u8 = 0xa3;
+
+// The attributes of a CertificationRequestInfo, implicitly tagged
+pub(crate) const ATTRIBUTES: u8 = 0xa0;
+
+// A GeneralName of a subject alternative name, implicitly tagged
+pub(crate) const DNS_NAME: u8 = 0x82;
+
+/// Splits the first element off der: its tag, its contents and what follows it
+pub(crate) fn next(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+
+    let len = match first {
+        0..=0x7f => first as usize,
+        0x81..=0x84 => {
+            let count = (first & 0x7f) as usize;
+            let bytes = rest.get(..count)?;
+            rest = &rest[count..];
+            bytes.iter().fold(0, |len, &b| (len << 8) | b as usize)
+        }
+        _ => return None,
+    };
+
+    let contents = rest.get(..len)?;
+    Some((tag, contents, &rest[len..]))
+}
+
+/// The first element of der as a whole, tag and length included
+pub(crate) fn first(der: &[u8]) -> Option<&[u8]> {
+    let (_, _, rest) = next(der)?;
+    Some(&der[..der.len() - rest.len()])
+}
+
+/// An element of tag with contents
+pub(crate) fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut der = vec![tag];
+
+    let len = contents.len();
+    if len < 0x80 {
+        der.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        der.push(0x80 | (bytes.len() - skip) as u8);
+        der.extend(&bytes[skip..]);
+    }
+
+    der.extend(contents);
+    der
+}
+
+/// A SEQUENCE of elements already encoded
+pub(crate) fn sequence(elements: &[&[u8]]) -> Vec<u8> {
+    encode(SEQUENCE, &elements.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, first, next, sequence, INTEGER, OCTET_STRING, SEQUENCE};
+    use assert2::assert;
+
+    #[test]
+    fn test_der() {
+        assert!(encode(INTEGER, &[2]) == [0x02, 0x01, 0x02]);
+
+        let long = encode(OCTET_STRING, &[7; 300]);
+        assert!(long[..4] == [0x04, 0x82, 0x01, 0x2c]);
+        assert!(long.len() == 304);
+
+        let der = [sequence(&[&encode(INTEGER, &[2]), &long]), vec![0x05, 0x00]].concat();
+        let (tag, contents, rest) = next(&der).unwrap();
+        assert!(tag == SEQUENCE);
+        assert!(rest == [0x05, 0x00]);
+        assert!(first(&der).unwrap().len() == der.len() - 2);
+
+        let (_, octets, rest) = next(&contents[3..]).unwrap();
+        assert!(octets == [7; 300]);
+        assert!(rest.is_empty());
+
+        assert!(next(&long[..303]).is_none());
+        assert!(next(&[0x04, 0x80]).is_none());
+    }
+}
@@ -0,0 +1,266 @@
+//! Content-defined chunking of EIFs, so that each chunk can go into a layer of its
+//! own in the release image. Where the EIF is cut depends only on the bytes just
+//! before the cut, so a change to one part of the EIF leaves the chunks around it,
+//! and their layers, as they were in the previous version. Registries then only
+//! store and move the layers of the chunks that changed.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use log::info;
+use sha2::{Digest, Sha256};
+
+use crate::constants::EIF_FILE_NAME;
+
+/// Directory of the release bundle that holds the chunks, each named by its digest
+pub const CHUNKS_DIR_NAME: &str = "chunks";
+
+/// File of the release bundle listing the digests of the chunks, one per line, in
+/// the order they make up the EIF
+pub const INDEX_FILE_NAME: &str = "application.eif.chunks";
+
+/// Docker refuses images of more than 127 layers. An EIF that would need more
+/// chunks than this goes into the image whole.
+pub const MAX_CHUNKS: usize = 100;
+
+const MIN_CHUNK_LEN: usize = 32 << 20;
+const MAX_CHUNK_LEN: usize = 128 << 20;
+
+// Past MIN_CHUNK_LEN, the EIF is cut where the low 25 bits of the hash are clear,
+// for chunks of 64 MiB on average
+const BOUNDARY_MASK: u64 = (1 << 25) - 1;
+
+// Random values for the rolling hash, one per byte value. Changing them moves every
+// boundary, so no chunk of an EIF would match one of an earlier version.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, seeded with the golden ratio
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// A piece of an EIF, stored in a file named by its digest
+pub struct Chunk {
+    pub digest: String,
+    pub len: u64,
+}
+
+/// Split the EIF at eif_path into chunk files in dir, which is created if needed.
+/// Returns the chunks in order, which may repeat if parts of the EIF are identical.
+pub async fn split(eif_path: &Path, dir: &Path) -> Result<Vec<Chunk>> {
+    let eif_path = eif_path.to_path_buf();
+    let dir = dir.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        split_file(&eif_path, &dir, MIN_CHUNK_LEN, MAX_CHUNK_LEN, BOUNDARY_MASK)
+    })
+    .await?
+}
+
+/// The contents of the index file for chunks
+pub fn index(chunks: &[Chunk]) -> String {
+    chunks.iter().map(|c| format!("{}\n", c.digest)).collect()
+}
+
+/// The path of the EIF of a release bundle. If the bundle holds the EIF as chunks,
+/// it is assembled next to them on first use, after checking the digest of each.
+pub async fn release_eif(release_dir: &Path) -> Result<PathBuf> {
+    let eif_path = release_dir.join(EIF_FILE_NAME);
+    let index_path = release_dir.join(INDEX_FILE_NAME);
+    if eif_path.exists() || !index_path.exists() {
+        return Ok(eif_path);
+    }
+
+    let release_dir = release_dir.to_path_buf();
+    let dst = eif_path.clone();
+    tokio::task::spawn_blocking(move || assemble(&release_dir, &index_path, &dst)).await??;
+
+    Ok(eif_path)
+}
+
+fn assemble(release_dir: &Path, index_path: &Path, dst: &Path) -> Result<()> {
+    let index = std::fs::read_to_string(index_path)?;
+    let digests: Vec<&str> = index.lines().filter(|l| !l.is_empty()).collect();
+    info!("assembling EIF from {} chunks", digests.len());
+
+    // Renamed into place once complete, so a failed attempt is not mistaken for the EIF
+    let partial = dst.with_extension("eif.partial");
+    let mut out = File::create(&partial)?;
+    let mut buf = vec![0u8; 1 << 20];
+
+    for digest in digests {
+        let path = release_dir.join(CHUNKS_DIR_NAME).join(digest);
+        let mut chunk = File::open(&path)
+            .map_err(|err| anyhow!("failed to open EIF chunk {}: {err}", path.display()))?;
+        let mut hasher = Sha256::new();
+
+        loop {
+            let n = chunk.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            out.write_all(&buf[..n])?;
+        }
+
+        if hex(&hasher.finalize()) != digest {
+            return Err(anyhow!("EIF chunk {digest} does not match its digest"));
+        }
+    }
+
+    out.sync_all()?;
+    std::fs::rename(partial, dst)?;
+
+    Ok(())
+}
+
+fn split_file(
+    eif_path: &Path,
+    dir: &Path,
+    min_len: usize,
+    max_len: usize,
+    mask: u64,
+) -> Result<Vec<Chunk>> {
+    std::fs::create_dir_all(dir)?;
+
+    let mut input = File::open(eif_path)?;
+    let mut buf = vec![0u8; max_len];
+    let mut filled = 0;
+    let mut chunks = Vec::new();
+
+    loop {
+        while filled < max_len {
+            let n = input.read(&mut buf[filled..])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if filled == 0 {
+            return Ok(chunks);
+        }
+
+        let len = cut_point(&buf[..filled], min_len, mask);
+        let data = &buf[..len];
+        let digest = hex(&Sha256::digest(data));
+
+        let path = dir.join(&digest);
+        if !path.exists() {
+            std::fs::write(path, data)?;
+        }
+        chunks.push(Chunk {
+            digest,
+            len: len as u64,
+        });
+
+        buf.copy_within(len..filled, 0);
+        filled -= len;
+    }
+}
+
+// Length of the next chunk at the start of data, which is either all that is left
+// of the EIF or as long as a chunk may be
+fn cut_point(data: &[u8], min_len: usize, mask: u64) -> usize {
+    if data.len() <= min_len {
+        return data.len();
+    }
+
+    // Each byte is shifted out of the hash after 64 more, so it only depends on a
+    // 64 byte window
+    let mut hash: u64 = 0;
+    for (i, b) in data.iter().enumerate().skip(min_len) {
+        hash = (hash << 1).wrapping_add(GEAR[*b as usize]);
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+
+    data.len()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cut_point, index, release_eif, split_file, INDEX_FILE_NAME};
+    use crate::constants::EIF_FILE_NAME;
+    use assert2::assert;
+    use rand::{RngCore, SeedableRng};
+    use tempfile::TempDir;
+
+    const MIN: usize = 256;
+    const MAX: usize = 4096;
+    const MASK: u64 = (1 << 9) - 1;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        rand::rngs::StdRng::seed_from_u64(7).fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn test_cut_point() {
+        let data = random_bytes(MAX);
+        let cut = cut_point(&data, MIN, MASK);
+        assert!(cut > MIN && cut <= MAX);
+
+        // Boundaries move along with the bytes before them
+        let mut shifted = data[..MIN].to_vec();
+        shifted.extend_from_slice(b"inserted");
+        shifted.extend_from_slice(&data[MIN..]);
+        assert!(cut_point(&shifted, MIN, MASK) == cut + 8);
+
+        assert!(cut_point(&data[..MIN], MIN, MASK) == MIN);
+    }
+
+    #[tokio::test]
+    async fn test_split_and_assemble() {
+        let data = random_bytes(64 * 1024);
+        let dir = TempDir::new().unwrap();
+        let eif_path = dir.path().join("source.eif");
+        std::fs::write(&eif_path, &data).unwrap();
+
+        let release_dir = dir.path().join("release");
+        let chunks = split_file(&eif_path, &release_dir.join("chunks"), MIN, MAX, MASK).unwrap();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().map(|c| c.len).sum::<u64>() == data.len() as u64);
+
+        // An insertion near the start leaves the chunks after it as they were
+        let mut edited = data.clone();
+        edited.splice(10..10, b"edit".iter().copied());
+        std::fs::write(&eif_path, &edited).unwrap();
+        let edited_chunks =
+            split_file(&eif_path, &dir.path().join("edited"), MIN, MAX, MASK).unwrap();
+        let unchanged = edited_chunks
+            .iter()
+            .filter(|e| chunks.iter().any(|c| c.digest == e.digest))
+            .count();
+        assert!(unchanged >= chunks.len() - 2);
+
+        std::fs::write(release_dir.join(INDEX_FILE_NAME), index(&chunks)).unwrap();
+        let assembled = release_eif(&release_dir).await.unwrap();
+        assert!(assembled == release_dir.join(EIF_FILE_NAME));
+        assert!(std::fs::read(assembled).unwrap() == data);
+
+        // A corrupted chunk is refused
+        std::fs::remove_file(release_dir.join(EIF_FILE_NAME)).unwrap();
+        std::fs::write(release_dir.join("chunks").join(&chunks[1].digest), b"bad").unwrap();
+        assert!(release_eif(&release_dir).await.is_err());
+        assert!(!release_dir.join(EIF_FILE_NAME).exists());
+    }
+}
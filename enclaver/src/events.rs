@@ -0,0 +1,344 @@
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use aws_config::imds;
+use futures::future::join_all;
+use http::{Method, Request, Uri};
+use hyper::client::{Client, HttpConnector};
+use hyper::{header, Body};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{debug, error};
+use serde::Serialize;
+
+use crate::cpu_topology::CpuAssignment;
+use crate::journal::{unix_time, StatusJournal};
+use crate::nitro_cli::EIFMeasurements;
+use crate::status::FatalCode;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+const MIME_APPLICATION_JSON: &str = "application/json";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event")]
+pub enum EnclaveEvent {
+    #[serde(rename = "started")]
+    Started {
+        enclave_id: String,
+        cid: u32,
+
+        // Where the enclave and the host proxies run, if nitro-cli reported its CPUs
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cpus: Option<CpuAssignment>,
+
+        // Set for a debug image run in debug mode, whose attestations carry all zero PCRs
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        debug: bool,
+    },
+
+    // Sent before started when the status journal shows the enclave ran before, i.e.
+    // a supervisor restarted enclaver-run
+    #[serde(rename = "restarted")]
+    Restarted { previous_starts: u64 },
+
+    #[serde(rename = "ingress_listening")]
+    IngressListening { port: u16 },
+
+    #[serde(rename = "egress_listening")]
+    EgressListening { vsock_port: u32 },
+
+    // The entrypoint inside the enclave has been launched
+    #[serde(rename = "healthy")]
+    Healthy,
+
+    #[serde(rename = "exited")]
+    Exited { code: i32 },
+
+    #[serde(rename = "signaled")]
+    Signaled { signal: i32 },
+
+    #[serde(rename = "fatal")]
+    Fatal {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<FatalCode>,
+        error: String,
+    },
+
+    #[serde(rename = "stopped")]
+    Stopped,
+
+    // The host side failed to start or supervise the enclave
+    #[serde(rename = "error")]
+    Error { error: String },
+}
+
+// Information about the enclave attached to every event
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EventContext {
+    pub manifest_name: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub measurements: Option<EIFMeasurements>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+}
+
+impl EventContext {
+    // Looks up the identity of the EC2 instance we are running on. Any failure
+    // (e.g. not running on EC2) simply leaves the fields empty.
+    pub async fn fetch_instance_identity(&mut self) {
+        let client = match imds::Client::builder().build().await {
+            Ok(client) => client,
+            Err(err) => {
+                debug!("unable to create IMDS client: {err}");
+                return;
+            }
+        };
+
+        self.instance_id = imds_get(&client, "/latest/meta-data/instance-id").await;
+        self.region = imds_get(&client, "/latest/meta-data/placement/region").await;
+    }
+}
+
+async fn imds_get(client: &imds::Client, path: &str) -> Option<String> {
+    match client.get(path).await {
+        Ok(val) => Some(val),
+        Err(err) => {
+            debug!("unable to fetch {path} from IMDS: {err}");
+            None
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EventPayload<'a> {
+    #[serde(flatten)]
+    event: &'a EnclaveEvent,
+
+    timestamp: u64,
+
+    #[serde(flatten)]
+    context: &'a EventContext,
+}
+
+// The journal belongs to a single enclave, so its entries leave out the context
+#[derive(Serialize)]
+struct JournalPayload<'a> {
+    timestamp: u64,
+
+    #[serde(flatten)]
+    event: &'a EnclaveEvent,
+}
+
+// Where to write the newline delimited JSON event stream
+#[derive(Debug, Clone, Copy)]
+pub enum EventOutput {
+    Stdout,
+    Fd(RawFd),
+}
+
+impl EventOutput {
+    fn open(self) -> Box<dyn Write + Send> {
+        match self {
+            EventOutput::Stdout => Box::new(std::io::stdout()),
+
+            // The fd is handed to us by whoever started the process, and is
+            // ours to own from here on.
+            EventOutput::Fd(fd) => Box::new(unsafe { File::from_raw_fd(fd) }),
+        }
+    }
+}
+
+// Delivers enclave lifecycle events to the configured webhooks and event stream.
+pub struct EventNotifier {
+    context: EventContext,
+    webhooks: Vec<Uri>,
+    client: Client<HttpsConnector<HttpConnector>>,
+    output: Option<Mutex<Box<dyn Write + Send>>>,
+    journal: Option<Mutex<StatusJournal>>,
+}
+
+impl EventNotifier {
+    pub fn new(context: EventContext, webhooks: Vec<Uri>) -> Self {
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Self {
+            context,
+            webhooks,
+            client: Client::builder().build(connector),
+            output: None,
+            journal: None,
+        }
+    }
+
+    pub fn with_output(mut self, output: Option<EventOutput>) -> Self {
+        self.output = output.map(|o| Mutex::new(o.open()));
+        self
+    }
+
+    pub fn with_journal(mut self, journal: Option<StatusJournal>) -> Self {
+        self.journal = journal.map(Mutex::new);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.webhooks.is_empty() && self.output.is_none() && self.journal.is_none()
+    }
+
+    // Writes the event to the event stream and posts it to every webhook. Delivery
+    // is best effort: failures are logged and never interrupt the enclave.
+    pub async fn notify(&self, event: EnclaveEvent) {
+        if self.is_empty() {
+            return;
+        }
+
+        if let Some(ref journal) = self.journal {
+            let entry = JournalPayload {
+                timestamp: unix_time(),
+                event: &event,
+            };
+            if let Err(err) = journal.lock().unwrap().append(&entry) {
+                error!("failed to write status journal: {err}");
+            }
+        }
+
+        if self.webhooks.is_empty() && self.output.is_none() {
+            return;
+        }
+
+        let body = match self.payload(&event) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("failed to serialize event: {err}");
+                return;
+            }
+        };
+
+        if let Some(ref output) = self.output {
+            let mut output = output.lock().unwrap();
+            if let Err(err) = output
+                .write_all(&body)
+                .and_then(|_| output.write_all(b"\n"))
+                .and_then(|_| output.flush())
+            {
+                error!("failed to write event: {err}");
+            }
+        }
+
+        let deliveries = self
+            .webhooks
+            .iter()
+            .map(|uri| self.post(uri.clone(), body.clone()));
+
+        for (uri, res) in self.webhooks.iter().zip(join_all(deliveries).await) {
+            if let Err(err) = res {
+                error!("failed to deliver event to webhook {uri}: {err}");
+            }
+        }
+    }
+
+    fn payload(&self, event: &EnclaveEvent) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&EventPayload {
+            event,
+            timestamp: unix_time(),
+            context: &self.context,
+        })?)
+    }
+
+    async fn post(&self, uri: Uri, body: Vec<u8>) -> Result<()> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, MIME_APPLICATION_JSON)
+            .body(Body::from(body))?;
+
+        let resp = tokio::time::timeout(WEBHOOK_TIMEOUT, self.client.request(req))
+            .await
+            .map_err(|_| anyhow!("timed out"))??;
+
+        if !resp.status().is_success() {
+            return Err(anyhow!("unexpected status {}", resp.status()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EnclaveEvent, EventContext, EventNotifier};
+    use crate::journal::{read_history, StatusJournal};
+    use assert2::assert;
+
+    #[test]
+    fn test_payload() {
+        let context = EventContext {
+            manifest_name: "test".to_string(),
+            instance_id: Some("i-0123456789abcdef0".to_string()),
+            ..Default::default()
+        };
+
+        let notifier = EventNotifier::new(context, vec![]);
+        let payload = notifier.payload(&EnclaveEvent::Exited { code: 3 }).unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+
+        assert!(payload["event"] == "exited");
+        assert!(payload["code"] == 3);
+        assert!(payload["manifest_name"] == "test");
+        assert!(payload["instance_id"] == "i-0123456789abcdef0");
+        assert!(payload.get("measurements").is_none());
+        assert!(payload["timestamp"].is_u64());
+    }
+
+    #[test]
+    fn test_started_debug() {
+        let notifier = EventNotifier::new(EventContext::default(), vec![]);
+        let started = |debug| EnclaveEvent::Started {
+            enclave_id: "i-0123456789abcdef0-enc0123456789abcd".to_string(),
+            cid: 16,
+            cpus: None,
+            debug,
+        };
+
+        let payload = notifier.payload(&started(true)).unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert!(payload["debug"] == true);
+
+        let payload = notifier.payload(&started(false)).unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert!(payload.get("debug").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = StatusJournal::open(dir.path(), "test").unwrap();
+        let notifier =
+            EventNotifier::new(EventContext::default(), vec![]).with_journal(Some(journal));
+
+        notifier.notify(EnclaveEvent::Healthy).await;
+        notifier.notify(EnclaveEvent::Exited { code: 3 }).await;
+        notifier
+            .notify(EnclaveEvent::Restarted { previous_starts: 1 })
+            .await;
+
+        let history = read_history(dir.path(), "test").unwrap();
+        assert!(history.len() == 3);
+        assert!(history[0].event == "healthy");
+        assert!(history[1].describe_details() == "code=3");
+        assert!(!history[1].details.contains_key("manifest_name"));
+        assert!(history[2].event == "restarted");
+        assert!(history[2].describe_details() == "previous_starts=1");
+    }
+}
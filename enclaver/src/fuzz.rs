@@ -0,0 +1,155 @@
+//! Entry points for the cargo-fuzz targets in fuzz/, one per parser that handles
+//! untrusted or semi-trusted input crossing the enclave boundary. None of them may
+//! panic, whatever the input. The tests below run each over mutations of a few
+//! seeds, so regressions show up in the regular test suite too.
+
+use std::net::IpAddr;
+
+use crate::policy::domain_filter::DomainFilter;
+use crate::policy::ip_filter::IpFilter;
+
+// The first line is matched against the filter built from the remaining lines
+fn split_query(data: &[u8]) -> (String, Vec<String>) {
+    let text = String::from_utf8_lossy(data);
+    let mut lines = text.lines().map(str::to_string);
+    let query = lines.next().unwrap_or_default();
+    (query, lines.collect())
+}
+
+/// Domain patterns from egress policies, matched against CONNECT hosts
+pub fn domain_filter(data: &[u8]) {
+    let (query, patterns) = split_query(data);
+
+    let mut filter = DomainFilter::new();
+    for pattern in &patterns {
+        _ = filter.add(pattern);
+    }
+    filter.matches(&query, None);
+    filter.matches(&query, Some(443));
+}
+
+/// IP and CIDR patterns from egress policies, matched against CONNECT addresses
+pub fn ip_filter(data: &[u8]) {
+    let (query, patterns) = split_query(data);
+
+    let mut filter = IpFilter::new();
+    for pattern in &patterns {
+        _ = filter.add(pattern);
+    }
+    if let Ok(addr) = query.parse::<IpAddr>() {
+        filter.matches(addr, None);
+        filter.matches(addr, Some(443));
+    }
+}
+
+/// The length prefixed JSON a host relay reads from the enclave over vsock
+#[cfg(feature = "proxy")]
+pub fn connect_request(data: &[u8]) {
+    use crate::protocol::{ConnectRequest, JsonTransport, Versioned};
+
+    let mut reader = data;
+    _ = futures::executor::block_on(Versioned::<ConnectRequest>::recv(&mut reader));
+}
+
+/// The status lines the host reads from odyn over vsock
+#[cfg(feature = "proxy")]
+pub fn status_line(data: &[u8]) {
+    _ = crate::protocol::EnclaveStatus::parse(&String::from_utf8_lossy(data));
+}
+
+/// The CMS envelopes KMS returns to the KMS proxy
+#[cfg(feature = "odyn")]
+pub fn pkcs7(data: &[u8]) {
+    _ = crate::proxy::pkcs7::ContentInfo::parse_ber(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const ITERATIONS: usize = 2000;
+
+    // Truncates, flips bits in, and splices random bytes into the seeds
+    fn run(target: fn(&[u8]), seeds: &[&[u8]]) {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for seed in seeds {
+            target(seed);
+        }
+
+        for _ in 0..ITERATIONS {
+            let mut data = seeds[rng.gen_range(0..seeds.len())].to_vec();
+            match rng.gen_range(0..3) {
+                0 if !data.is_empty() => data.truncate(rng.gen_range(0..data.len())),
+                1 if !data.is_empty() => {
+                    for _ in 0..rng.gen_range(1..8) {
+                        let i = rng.gen_range(0..data.len());
+                        data[i] ^= 1 << rng.gen_range(0..8);
+                    }
+                }
+                _ => {
+                    let at = rng.gen_range(0..=data.len());
+                    let junk: Vec<u8> = (0..rng.gen_range(1..32)).map(|_| rng.gen()).collect();
+                    data.splice(at..at, junk);
+                }
+            }
+
+            target(&data);
+        }
+    }
+
+    #[test]
+    fn fuzz_domain_filter() {
+        run(
+            super::domain_filter,
+            &[
+                b"web.prod.example.com\nweb.*.example.com\n**.example.org",
+                b"api.example.com\napi.example.com:443\n*.example.com:8000-8100",
+                b"example.com\n*\n**\n.\n*.*.*",
+                b"\n\n",
+            ],
+        );
+    }
+
+    #[test]
+    fn fuzz_ip_filter() {
+        run(
+            super::ip_filter,
+            &[
+                b"10.0.0.1\n10.0.0.0/8\n66.254.33.22",
+                b"::1\n::/0\nfe80::/10\n10.0.0.0/33",
+                b"10.0.0.1\n10.0.0.0/8:443\n[fc00::/7]:1-1024\n[::1",
+            ],
+        );
+    }
+    #[cfg(feature = "proxy")]
+    #[test]
+    fn fuzz_connect_request() {
+        let json = br#"{"host":"example.com","port":443,"policy":"metrics"}"#;
+        let mut framed = (json.len() as u16).to_le_bytes().to_vec();
+        framed.extend_from_slice(json);
+
+        run(super::connect_request, &[&framed, &[0xff, 0xff, b'{']]);
+    }
+    #[cfg(feature = "proxy")]
+    #[test]
+    fn fuzz_status_line() {
+        run(
+            super::status_line,
+            &[
+                br#"{ "status": "running" }"#,
+                br#"{ "status": "exited", "code": 3 }"#,
+                br#"{ "status": "fatal", "error": "boom" }"#,
+                br#"{ "status": "fatal", "code": "config_error", "error": "boom" }"#,
+                br#"{ "version": 1, "status": "signaled", "signal": "SIGTERM" }"#,
+            ],
+        );
+    }
+    #[cfg(feature = "odyn")]
+    #[test]
+    fn fuzz_pkcs7() {
+        let input = base64::decode(crate::proxy::pkcs7::tests::INPUT).unwrap();
+        run(super::pkcs7, &[&input]);
+    }
+}
@@ -17,7 +17,10 @@ pub struct HttpServer {
 
 impl HttpServer {
     pub fn bind(listen_port: u16) -> Result<Self> {
-        let listen_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, listen_port));
+        Self::bind_addr(SocketAddr::from((Ipv4Addr::LOCALHOST, listen_port)))
+    }
+
+    pub fn bind_addr(listen_addr: SocketAddr) -> Result<Self> {
         let incoming = AddrIncoming::bind(&listen_addr)?;
         Ok(Self { incoming })
     }
@@ -0,0 +1,521 @@
+//! Infrastructure as code for running a release image on EC2, rendered from the
+//! result of a build: a launch template for instances whose user data runs the
+//! image, and a KMS key that only enclaves with the measurements of the EIF can use.
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::constants::DEFAULT_CPU_COUNT;
+use crate::kms_policy;
+use crate::manifest::Manifest;
+use crate::nitro_cli::EIFMeasurements;
+
+/// The latest Amazon Linux 2, which packages the Nitro Enclaves CLI
+const AMI_PARAMETER: &str = "/aws/service/ami-amazon-linux-latest/amzn2-ami-hvm-x86_64-gp2";
+
+/// The cheapest instance type with enough vCPUs to spare two for an enclave
+const DEFAULT_INSTANCE_TYPE: &str = "c6a.xlarge";
+
+/// What key administrators may do, short of using the key themselves
+const KEY_ADMIN_ACTIONS: &[&str] = &[
+    "kms:Create*",
+    "kms:Describe*",
+    "kms:Enable*",
+    "kms:List*",
+    "kms:Put*",
+    "kms:Update*",
+    "kms:Revoke*",
+    "kms:Disable*",
+    "kms:Get*",
+    "kms:Delete*",
+    "kms:TagResource",
+    "kms:UntagResource",
+    "kms:ScheduleKeyDeletion",
+    "kms:CancelKeyDeletion",
+    "kms:Encrypt",
+];
+
+const EGRESS_NOTE: &str = "\
+Egress: the enclave has no network of its own. enclaver-run relays its
+connections from the instance, so the outbound rules of the instance must
+allow the addresses of:";
+
+const DEBUG_NOTE: &str = "\
+WARNING: this is a debug image. It only runs in debug mode, where the enclave
+attests to zeroed PCRs, so the KMS key is not usable from it.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Terraform,
+    CloudFormation,
+}
+
+impl Format {
+    /// Where the snippet is written, unless told otherwise
+    pub fn default_file_name(&self) -> &'static str {
+        match self {
+            Format::Terraform => "enclaver.tf",
+            Format::CloudFormation => "enclaver.cloudformation.yaml",
+        }
+    }
+}
+
+/// Renders the snippet for the release image of manifest
+pub fn render(
+    format: Format,
+    manifest: &Manifest,
+    measurements: &EIFMeasurements,
+) -> Result<String> {
+    let mut out = header(manifest);
+
+    match format {
+        Format::Terraform => out.push_str(&terraform(manifest, measurements)?),
+        Format::CloudFormation => out.push_str(&cloudformation(manifest, measurements)?),
+    }
+
+    Ok(out)
+}
+
+// Comments on what the template cannot express: security groups match addresses,
+// so the hosts the egress policy allows are only listed
+fn header(manifest: &Manifest) -> String {
+    let mut lines = vec![
+        format!("Generated by `enclaver build` for {}", manifest.target),
+        String::new(),
+    ];
+
+    let egress = egress_destinations(manifest);
+    if egress.is_empty() {
+        lines.push("Egress: none, the enclave has no outbound network access.".to_string());
+    } else {
+        lines.extend(EGRESS_NOTE.lines().map(str::to_string));
+        lines.extend(egress.iter().map(|dest| format!("  - {dest}")));
+    }
+
+    let ingress: Vec<String> = manifest
+        .ingress
+        .iter()
+        .flatten()
+        .map(|ingress| ingress.listen_port.to_string())
+        .collect();
+    if !ingress.is_empty() {
+        lines.push(String::new());
+        lines.push(format!(
+            "Ingress: the instance publishes port(s) {}, open them to clients in its security group.",
+            ingress.join(", ")
+        ));
+    }
+
+    if manifest.is_debug() {
+        lines.push(String::new());
+        lines.extend(DEBUG_NOTE.lines().map(str::to_string));
+    }
+
+    let mut out: String = lines
+        .iter()
+        .map(|line| match line.is_empty() {
+            true => "#\n".to_string(),
+            false => format!("# {line}\n"),
+        })
+        .collect();
+    out.push('\n');
+    out
+}
+
+// Every host and port the policy could let the enclave connect to, in manifest order
+fn egress_destinations(manifest: &Manifest) -> Vec<String> {
+    let mut dests: Vec<String> = Vec::new();
+    let mut push = |dest: String| {
+        if !dests.contains(&dest) {
+            dests.push(dest);
+        }
+    };
+
+    for egress in manifest.egress.iter() {
+        let policies = std::iter::once((&egress.allow, &egress.protocols, &egress.databases))
+            .chain(
+                egress
+                    .proxies
+                    .iter()
+                    .flatten()
+                    .map(|proxy| (&proxy.allow, &proxy.protocols, &proxy.databases)),
+            );
+
+        for (allow, protocols, databases) in policies {
+            for host in allow.iter().flatten() {
+                push(host.clone());
+            }
+            for protocol in protocols.iter().flatten() {
+                for host in &protocol.allow {
+                    push(format!("{host} ({})", protocol.protocol));
+                }
+            }
+            for db in databases.iter().flatten() {
+                push(format!("{}:{}", db.host, db.port));
+            }
+        }
+
+        for forward in egress.forward.iter().flatten() {
+            push(format!("{}:{}", forward.host, forward.port));
+        }
+    }
+
+    dests
+}
+
+fn resources(manifest: &Manifest) -> (i32, i32) {
+    let defaults = manifest.defaults.as_ref();
+    (
+        defaults
+            .and_then(|d| d.cpu_count)
+            .unwrap_or(DEFAULT_CPU_COUNT),
+        manifest.enclave_memory_mb(None),
+    )
+}
+
+/// The instance user data: installs the Nitro Enclaves CLI and Docker, reserves
+/// the resources of the enclave, and runs the release image under systemd
+pub fn user_data(manifest: &Manifest) -> String {
+    let (cpu_count, memory_mb) = resources(manifest);
+    let image = &manifest.target;
+    let publish: String = manifest
+        .ingress
+        .iter()
+        .flatten()
+        .map(|ingress| format!(" -p {0}:{0}", ingress.listen_port))
+        .collect();
+
+    format!(
+        r#"#!/bin/bash
+set -euo pipefail
+
+amazon-linux-extras install -y aws-nitro-enclaves-cli
+yum install -y aws-nitro-enclaves-cli-devel docker
+
+# Reserved at boot, while huge pages are still easy to find
+sed -i 's/^cpu_count:.*/cpu_count: {cpu_count}/' /etc/nitro_enclaves/allocator.yaml
+sed -i 's/^memory_mib:.*/memory_mib: {memory_mb}/' /etc/nitro_enclaves/allocator.yaml
+systemctl enable --now nitro-enclaves-allocator.service
+systemctl enable --now docker
+
+cat > /etc/systemd/system/enclave.service <<'UNIT'
+[Unit]
+Description=Enclaver
+After=docker.service
+Requires=docker.service
+Requires=nitro-enclaves-allocator.service
+
+[Service]
+TimeoutStartSec=0
+Restart=always
+ExecStartPre=-/usr/bin/docker rm -f %n
+ExecStartPre=/usr/bin/docker pull {image}
+ExecStart=/usr/bin/docker run --rm --name %n --device=/dev/nitro_enclaves:/dev/nitro_enclaves:rw{publish} {image}
+
+[Install]
+WantedBy=multi-user.target
+UNIT
+
+systemctl daemon-reload
+systemctl enable --now enclave.service
+"#
+    )
+}
+
+fn terraform(manifest: &Manifest, measurements: &EIFMeasurements) -> Result<String> {
+    let condition = kms_policy::condition(measurements)?;
+    let conditions: String = condition["StringEqualsIgnoreCase"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(variable, value)| {
+            format!(
+                r#"
+    condition {{
+      test     = "StringEqualsIgnoreCase"
+      variable = {}
+      values   = [{}]
+    }}
+"#,
+                hcl_string(variable),
+                hcl_string(value.as_str().unwrap_or_default())
+            )
+        })
+        .collect();
+
+    let actions = hcl_list(kms_policy::DEFAULT_ACTIONS);
+    let admin_actions = hcl_list(KEY_ADMIN_ACTIONS);
+    let description = hcl_string(&format!(
+        "Only usable by enclaves running {}",
+        manifest.target
+    ));
+    let user_data: String = user_data(manifest)
+        .lines()
+        .map(|line| match line.is_empty() {
+            true => "\n".to_string(),
+            false => format!("    {}\n", hcl_escape(line)),
+        })
+        .collect();
+
+    Ok(format!(
+        r#"variable "enclave_instance_type" {{
+  description = "Instance type, which must support Nitro Enclaves and have vCPUs to spare for the enclave"
+  type        = string
+  default     = "{DEFAULT_INSTANCE_TYPE}"
+}}
+
+variable "enclave_instance_profile" {{
+  description = "Name of the IAM instance profile of the instances running the enclave"
+  type        = string
+}}
+
+variable "enclave_role_arn" {{
+  description = "ARN of the IAM role of that instance profile, which the enclave calls KMS as"
+  type        = string
+}}
+
+data "aws_ssm_parameter" "enclave_ami" {{
+  name = "{AMI_PARAMETER}"
+}}
+
+data "aws_caller_identity" "current" {{}}
+
+resource "aws_launch_template" "enclave" {{
+  name_prefix   = "enclaver-"
+  image_id      = data.aws_ssm_parameter.enclave_ami.value
+  instance_type = var.enclave_instance_type
+  user_data     = base64encode(local.enclave_user_data)
+
+  iam_instance_profile {{
+    name = var.enclave_instance_profile
+  }}
+
+  enclave_options {{
+    enabled = true
+  }}
+
+  # The KMS proxy reaches IMDSv2 through the docker0 bridge, one hop further away
+  metadata_options {{
+    http_tokens                 = "required"
+    http_put_response_hop_limit = 2
+  }}
+}}
+
+data "aws_iam_policy_document" "enclave_key" {{
+  statement {{
+    sid       = "AllowKeyAdministration"
+    effect    = "Allow"
+    actions   = {admin_actions}
+    resources = ["*"]
+
+    principals {{
+      type        = "AWS"
+      identifiers = ["arn:aws:iam::${{data.aws_caller_identity.current.account_id}}:root"]
+    }}
+  }}
+
+  statement {{
+    sid       = "AllowEnclaveWithMeasurements"
+    effect    = "Allow"
+    actions   = {actions}
+    resources = ["*"]
+
+    principals {{
+      type        = "AWS"
+      identifiers = [var.enclave_role_arn]
+    }}
+{conditions}  }}
+}}
+
+resource "aws_kms_key" "enclave" {{
+  description = {description}
+  policy      = data.aws_iam_policy_document.enclave_key.json
+}}
+
+locals {{
+  enclave_user_data = <<-EOT
+{user_data}  EOT
+}}
+
+output "enclave_kms_key_arn" {{
+  value = aws_kms_key.enclave.arn
+}}
+
+output "enclave_launch_template_id" {{
+  value = aws_launch_template.enclave.id
+}}
+"#
+    ))
+}
+
+fn cloudformation(manifest: &Manifest, measurements: &EIFMeasurements) -> Result<String> {
+    let actions: Vec<String> = kms_policy::DEFAULT_ACTIONS
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+    let mut enclave_statement = kms_policy::statement(measurements, "", &actions)?;
+    enclave_statement["Principal"]["AWS"] = json!({ "Ref": "EnclaveRoleArn" });
+
+    let template = json!({
+        "AWSTemplateFormatVersion": "2010-09-09",
+        "Parameters": {
+            "EnclaveInstanceType": {
+                "Type": "String",
+                "Default": DEFAULT_INSTANCE_TYPE,
+                "Description": "Instance type, which must support Nitro Enclaves and have vCPUs to spare for the enclave",
+            },
+            "EnclaveInstanceProfile": {
+                "Type": "String",
+                "Description": "Name of the IAM instance profile of the instances running the enclave",
+            },
+            "EnclaveRoleArn": {
+                "Type": "String",
+                "Description": "ARN of the IAM role of that instance profile, which the enclave calls KMS as",
+            },
+            "EnclaveAmi": {
+                "Type": "AWS::SSM::Parameter::Value<AWS::EC2::Image::Id>",
+                "Default": AMI_PARAMETER,
+            },
+        },
+        "Resources": {
+            "EnclaveLaunchTemplate": {
+                "Type": "AWS::EC2::LaunchTemplate",
+                "Properties": {
+                    "LaunchTemplateData": {
+                        "ImageId": { "Ref": "EnclaveAmi" },
+                        "InstanceType": { "Ref": "EnclaveInstanceType" },
+                        "IamInstanceProfile": { "Name": { "Ref": "EnclaveInstanceProfile" } },
+                        "EnclaveOptions": { "Enabled": true },
+                        // The KMS proxy reaches IMDSv2 through the docker0 bridge
+                        "MetadataOptions": {
+                            "HttpTokens": "required",
+                            "HttpPutResponseHopLimit": 2,
+                        },
+                        "UserData": { "Fn::Base64": user_data(manifest) },
+                    },
+                },
+            },
+            "EnclaveKey": {
+                "Type": "AWS::KMS::Key",
+                "Properties": {
+                    "Description": format!("Only usable by enclaves running {}", manifest.target),
+                    "KeyPolicy": {
+                        "Version": "2012-10-17",
+                        "Statement": [
+                            {
+                                "Sid": "AllowKeyAdministration",
+                                "Effect": "Allow",
+                                "Principal": {
+                                    "AWS": { "Fn::Sub": "arn:aws:iam::${AWS::AccountId}:root" },
+                                },
+                                "Action": KEY_ADMIN_ACTIONS,
+                                "Resource": "*",
+                            },
+                            enclave_statement,
+                        ],
+                    },
+                },
+            },
+        },
+        "Outputs": {
+            "EnclaveKmsKeyArn": { "Value": { "Fn::GetAtt": ["EnclaveKey", "Arn"] } },
+            "EnclaveLaunchTemplateId": { "Value": { "Ref": "EnclaveLaunchTemplate" } },
+        },
+    });
+
+    Ok(serde_yaml::to_string(&template)?)
+}
+
+// Template sequences start with ${ and %{ in HCL strings and heredocs
+fn hcl_escape(s: &str) -> String {
+    s.replace("${", "$${").replace("%{", "%%{")
+}
+
+fn hcl_string(s: &str) -> String {
+    hcl_escape(&Value::String(s.to_string()).to_string())
+}
+
+fn hcl_list(items: &[&str]) -> String {
+    let items: Vec<String> = items.iter().map(|item| hcl_string(item)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, user_data, Format};
+    use crate::manifest::Manifest;
+    use crate::nitro_cli::EIFMeasurements;
+    use assert2::assert;
+    use serde_json::json;
+
+    fn manifest() -> Manifest {
+        serde_yaml::from_str(
+            r#"
+version: v1
+name: "test"
+target: "registry.example.com/app:enclave"
+sources:
+  app: "app-image:latest"
+defaults:
+  memory_mb: 2048
+ingress:
+  - listen_port: 8001
+egress:
+  allow:
+    - "*.amazonaws.com"
+  forward:
+    - local_port: 5432
+      host: db.internal
+      port: 5432
+"#,
+        )
+        .unwrap()
+    }
+
+    fn measurements() -> EIFMeasurements {
+        serde_json::from_value(json!({
+            "PCR0": "00".repeat(48),
+            "PCR1": "01".repeat(48),
+            "PCR2": "02".repeat(48),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_user_data() {
+        let script = user_data(&manifest());
+        // 2048 MiB for the app, 256 for odyn
+        assert!(script.contains("memory_mib: 2304/"));
+        assert!(script.contains("cpu_count: 2/"));
+        assert!(script.contains("rw -p 8001:8001 registry.example.com/app:enclave\n"));
+    }
+
+    #[test]
+    fn test_render_terraform() {
+        let tf = render(Format::Terraform, &manifest(), &measurements()).unwrap();
+        assert!(tf.contains("#   - *.amazonaws.com\n#   - db.internal:5432\n"));
+        assert!(tf.contains(&format!(
+            "variable = \"kms:RecipientAttestation:PCR1\"\n      values   = [\"{}\"]",
+            "01".repeat(48)
+        )));
+        assert!(tf.contains("    systemctl enable --now enclave.service\n  EOT\n"));
+    }
+
+    #[test]
+    fn test_render_cloudformation() {
+        let yaml = render(Format::CloudFormation, &manifest(), &measurements()).unwrap();
+        let template: serde_json::Value = serde_yaml::from_str(&yaml).unwrap();
+
+        let statement =
+            &template["Resources"]["EnclaveKey"]["Properties"]["KeyPolicy"]["Statement"][1];
+        assert!(statement["Principal"]["AWS"]["Ref"] == "EnclaveRoleArn");
+        assert!(
+            statement["Condition"]["StringEqualsIgnoreCase"]["kms:RecipientAttestation:PCR2"]
+                == "02".repeat(48)
+        );
+
+        let script = &template["Resources"]["EnclaveLaunchTemplate"]["Properties"]
+            ["LaunchTemplateData"]["UserData"]["Fn::Base64"];
+        assert!(script.as_str() == Some(user_data(&manifest()).as_str()));
+    }
+}
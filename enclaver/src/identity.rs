@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::journal;
+
+const IDENTITY_EXTENSION: &str = "identity.json";
+
+/// What a running enclave is, as measured: the PCRs of its image and, once the
+/// enclave has proven them with an attestation document, a fingerprint of it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MeasuredIdentity {
+    #[serde(rename = "PCR0")]
+    pub pcr0: String,
+
+    #[serde(rename = "PCR1")]
+    pub pcr1: String,
+
+    #[serde(rename = "PCR2")]
+    pub pcr2: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<VerifiedAttestation>,
+}
+
+/// An attestation document the host verified against the expected PCRs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifiedAttestation {
+    /// Hex encoded SHA-256 of the COSE signed document
+    pub fingerprint: String,
+    pub module_id: String,
+    pub verified_at: u64,
+}
+
+/// The identity of the enclave run by this process, shared between the tasks that
+/// learn about it and the ones that report it. Every change is also written to the
+/// state dir, if there is one, for `enclaver ps` to pick up.
+#[derive(Clone)]
+pub struct IdentityRecord {
+    identity: Arc<Mutex<MeasuredIdentity>>,
+    path: Option<PathBuf>,
+}
+
+impl IdentityRecord {
+    pub fn new(identity: MeasuredIdentity, state_dir: Option<&Path>, name: &str) -> Result<Self> {
+        let path = match state_dir {
+            Some(state_dir) => Some(journal::state_path(state_dir, name, IDENTITY_EXTENSION)?),
+            None => None,
+        };
+
+        let record = Self {
+            identity: Arc::new(Mutex::new(identity)),
+            path,
+        };
+        record.save(&record.current())?;
+
+        Ok(record)
+    }
+
+    pub fn current(&self) -> MeasuredIdentity {
+        self.identity.lock().unwrap().clone()
+    }
+
+    pub fn set_attestation(&self, attestation: VerifiedAttestation) {
+        let identity = {
+            let mut identity = self.identity.lock().unwrap();
+            identity.attestation = Some(attestation);
+            identity.clone()
+        };
+
+        if let Err(err) = self.save(&identity) {
+            error!("failed to save the enclave identity: {err}");
+        }
+    }
+
+    fn save(&self, identity: &MeasuredIdentity) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        // Renamed into place, so readers never see a partial file
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(identity)?)?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+}
+
+/// The last identity recorded for the enclave named name, if any
+pub fn load(state_dir: &Path, name: &str) -> Result<Option<MeasuredIdentity>> {
+    let path = journal::state_path(state_dir, name, IDENTITY_EXTENSION)?;
+    match fs::read(path) {
+        Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, IdentityRecord, MeasuredIdentity, VerifiedAttestation};
+    use assert2::assert;
+
+    #[test]
+    fn test_identity_record() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load(dir.path(), "app").unwrap().is_none());
+
+        let identity = MeasuredIdentity {
+            pcr0: "00".repeat(48),
+            pcr1: "01".repeat(48),
+            pcr2: "02".repeat(48),
+            attestation: None,
+        };
+        let record = IdentityRecord::new(identity.clone(), Some(dir.path()), "app").unwrap();
+        assert!(load(dir.path(), "app").unwrap() == Some(identity));
+
+        let attestation = VerifiedAttestation {
+            fingerprint: "ab".repeat(32),
+            module_id: "i-0123-enc0123".to_string(),
+            verified_at: 1,
+        };
+        record.set_attestation(attestation.clone());
+        assert!(record.current().attestation == Some(attestation.clone()));
+
+        let loaded = load(dir.path(), "app").unwrap().unwrap();
+        assert!(loaded.attestation == Some(attestation));
+        assert!(serde_json::to_value(&loaded).unwrap()["PCR1"] == "01".repeat(48));
+    }
+}
@@ -5,6 +5,7 @@ use bollard::models::{BuildInfo, CreateImageInfo, ImageId};
 use bollard::Docker;
 use futures_util::stream::{StreamExt, TryStreamExt};
 use log::{debug, trace};
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Write;
 use std::path::PathBuf;
@@ -255,6 +256,8 @@ pub struct LayerBuilder {
     files: Vec<FileBuilder>,
 
     entrypoint: Option<Vec<String>>,
+
+    labels: BTreeMap<String, String>,
 }
 
 impl LayerBuilder {
@@ -262,6 +265,7 @@ impl LayerBuilder {
         Self {
             files: vec![],
             entrypoint: None,
+            labels: BTreeMap::new(),
         }
     }
 
@@ -277,6 +281,12 @@ impl LayerBuilder {
         self
     }
 
+    /// Set a label on the resulting image.
+    pub fn set_label(&mut self, key: String, value: String) -> &mut Self {
+        self.labels.insert(key, value);
+        self
+    }
+
     /// Realize the LayerBuilder to a tarred up Docker context containing a Dockerfile
     /// which will build the requested layer, and write the resulting context to `dst`.
     ///
@@ -332,10 +342,26 @@ impl LayerBuilder {
                 .await?;
         }
 
+        // Write out the LABELs, quoted as JSON strings which Dockerfile accepts
+        for (key, value) in &self.labels {
+            trace!("writing LABEL: {}={}", key, value);
+            dw.write_all(
+                format!(
+                    "LABEL {}={}\n",
+                    serde_json::to_string(key)?,
+                    serde_json::to_string(value)?
+                )
+                .as_bytes(),
+            )
+            .await?;
+        }
+
         dw.flush().await?;
 
-        // Write the entire context directory to a tarball.
+        // Write the entire context directory to a tarball. COPY keeps the mtimes of the
+        // context, so fixing them keeps layers of unchanged files identical across builds.
         let mut tb = tokio_tar::Builder::new(dst);
+        tb.mode(tokio_tar::HeaderMode::Deterministic);
         tb.append_dir_all(".", tempdir).await?;
 
         Ok(())
@@ -0,0 +1,249 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Where the host keeps the status journals of enclaves, one per manifest name
+pub const DEFAULT_STATE_DIR: &str = "/var/lib/enclaver";
+
+const JOURNAL_EXTENSION: &str = "jsonl";
+
+// Past this size a journal is moved aside when it is next opened, keeping the
+// previous generation around for history
+const MAX_JOURNAL_SIZE: u64 = 256 * 1024;
+
+/// Newline delimited JSON record of the status transitions of one enclave, which
+/// outlives the enclaver-run process that wrote it.
+pub struct StatusJournal {
+    file: File,
+}
+
+impl StatusJournal {
+    /// Opens the journal of the enclave named name for appending. The file is opened
+    /// up front so it can still be written after the supervisor drops privileges.
+    pub fn open(state_dir: &Path, name: &str) -> Result<Self> {
+        fs::create_dir_all(state_dir)?;
+
+        let path = journal_path(state_dir, name)?;
+        if fs::metadata(&path).is_ok_and(|m| m.len() > MAX_JOURNAL_SIZE) {
+            fs::rename(&path, previous_path(&path))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| anyhow!("failed to open status journal {}: {e}", path.display()))?;
+
+        Ok(Self { file })
+    }
+
+    pub fn append<T: Serialize>(&mut self, entry: &T) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        // A single write, so concurrent readers never see a partial line
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub timestamp: u64,
+    pub event: String,
+
+    // The fields specific to the event, e.g. the exit code
+    #[serde(flatten)]
+    pub details: Map<String, Value>,
+}
+
+impl JournalEntry {
+    pub fn describe_details(&self) -> String {
+        self.details
+            .iter()
+            .map(|(k, v)| match v {
+                Value::String(s) => format!("{k}={s}"),
+                v => format!("{k}={v}"),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// All recorded entries for the enclave named name, oldest first. Lines that cannot
+/// be parsed, e.g. one cut short by a crash, are skipped.
+pub fn read_history(state_dir: &Path, name: &str) -> Result<Vec<JournalEntry>> {
+    let path = journal_path(state_dir, name)?;
+    if !path.exists() {
+        return Err(anyhow!(
+            "no status journal for {name} in {}",
+            state_dir.display()
+        ));
+    }
+
+    let mut entries = Vec::new();
+    for path in [previous_path(&path), path] {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => debug!("skipping journal line in {}: {err}", path.display()),
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Number of times the enclave named name was started, as far back as its journal
+/// goes. 0 if it has no journal yet.
+pub fn count_starts(state_dir: &Path, name: &str) -> u64 {
+    match read_history(state_dir, name) {
+        Ok(entries) => entries.iter().filter(|e| e.event == "started").count() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// Names of the enclaves with a journal in state_dir, along with their latest entry
+pub fn list(state_dir: &Path) -> Result<Vec<(String, Option<JournalEntry>)>> {
+    if !state_dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut names = Vec::new();
+    for dirent in fs::read_dir(state_dir)? {
+        let path = dirent?.path();
+        if path.extension().is_some_and(|ext| ext == JOURNAL_EXTENSION) {
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+
+    names
+        .into_iter()
+        .map(|name| {
+            let last = read_history(state_dir, &name)?.pop();
+            Ok((name, last))
+        })
+        .collect()
+}
+
+/// Formats seconds since the epoch as an RFC 3339 UTC time
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+
+    // Civil date from days since the epoch, per Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Seconds since the epoch, as recorded in journal entries
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Path of the state file with the given extension for the enclave named name
+pub(crate) fn state_path(state_dir: &Path, name: &str, extension: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(anyhow!("{name:?} cannot be used as a journal name"));
+    }
+
+    Ok(state_dir.join(format!("{name}.{extension}")))
+}
+
+fn journal_path(state_dir: &Path, name: &str) -> Result<PathBuf> {
+    state_path(state_dir, name, JOURNAL_EXTENSION)
+}
+
+fn previous_path(path: &Path) -> PathBuf {
+    path.with_extension(format!("{JOURNAL_EXTENSION}.1"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count_starts, format_timestamp, list, read_history, StatusJournal};
+    use assert2::assert;
+    use serde_json::json;
+    use std::io::Write;
+
+    #[test]
+    fn test_journal() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut journal = StatusJournal::open(dir.path(), "app").unwrap();
+        journal
+            .append(&json!({"timestamp": 1, "event": "healthy"}))
+            .unwrap();
+        journal
+            .append(&json!({"timestamp": 2, "event": "exited", "code": 3}))
+            .unwrap();
+        journal.file.write_all(b"{\"timest").unwrap();
+
+        let history = read_history(dir.path(), "app").unwrap();
+        assert!(history.len() == 2);
+        assert!(history[1].event == "exited");
+        assert!(history[1].describe_details() == "code=3");
+
+        let enclaves = list(dir.path()).unwrap();
+        assert!(enclaves.len() == 1);
+        assert!(enclaves[0].0 == "app");
+        assert!(enclaves[0].1.as_ref().unwrap().timestamp == 2);
+
+        assert!(read_history(dir.path(), "other").is_err());
+        assert!(StatusJournal::open(dir.path(), "../app").is_err());
+    }
+
+    #[test]
+    fn test_count_starts() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(count_starts(dir.path(), "app") == 0);
+
+        let mut journal = StatusJournal::open(dir.path(), "app").unwrap();
+        for event in ["started", "exited", "restarted", "started", "healthy"] {
+            journal
+                .append(&json!({"timestamp": 1, "event": event}))
+                .unwrap();
+        }
+
+        assert!(count_starts(dir.path(), "app") == 2);
+        assert!(count_starts(dir.path(), "other") == 0);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert!(format_timestamp(0) == "1970-01-01T00:00:00Z");
+        assert!(format_timestamp(951782400) == "2000-02-29T00:00:00Z");
+        assert!(format_timestamp(1792245845) == "2026-10-17T14:04:05Z");
+    }
+}
@@ -1,6 +1,7 @@
-use anyhow::Result;
-use rsa::pkcs8::{EncodePublicKey, LineEnding};
-use rsa::{RsaPrivateKey, RsaPublicKey};
+use anyhow::{anyhow, Result};
+use rsa::pkcs8::{DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::{PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
 
 const RSA_KEY_LEN: usize = 2048;
 
@@ -32,4 +33,25 @@ impl KeyPair {
     pub fn public_key_as_pem(&self) -> Result<String> {
         Ok(self.public.to_public_key_pem(LineEnding::LF)?)
     }
+
+    pub fn private_key_as_pem(&self) -> Result<String> {
+        Ok(self.private.to_pkcs8_pem(LineEnding::LF)?.to_string())
+    }
+
+    pub fn private_key_as_der(&self) -> Result<Vec<u8>> {
+        Ok(self.private.to_pkcs8_der()?.as_bytes().to_vec())
+    }
+}
+
+/// Verifies an RSA PKCS#1 v1.5 SHA-256 signature over data, with a PEM encoded public key
+pub fn verify_pkcs1v15_sha256(public_key_pem: &str, data: &[u8], signature: &[u8]) -> Result<()> {
+    let key = RsaPublicKey::from_public_key_pem(public_key_pem)?;
+    let digest = Sha256::digest(data);
+
+    key.verify(
+        PaddingScheme::new_pkcs1v15_sign::<Sha256>(),
+        &digest,
+        signature,
+    )
+    .map_err(|_| anyhow!("signature verification failed"))
 }
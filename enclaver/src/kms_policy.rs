@@ -0,0 +1,93 @@
+//! KMS key policies that only release keys to enclaves running a given image. KMS
+//! checks the conditions against the attestation document the enclave sends along
+//! with its requests, which the KMS proxy in odyn adds.
+
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+
+use crate::nitro_cli::EIFMeasurements;
+
+/// What an enclave usually needs a key for: unwrapping data keys and making new ones
+pub const DEFAULT_ACTIONS: &[&str] = &["kms:Decrypt", "kms:GenerateDataKey"];
+
+const STATEMENT_ID: &str = "AllowEnclaveWithMeasurements";
+
+/// The Condition block of a key policy statement, requiring each PCR of the image,
+/// including PCR8 if the EIF is signed
+pub fn condition(measurements: &EIFMeasurements) -> Result<Value> {
+    let mut pcrs = Map::new();
+    for (index, value) in measurements.pcrs()? {
+        let value: String = value.iter().map(|b| format!("{b:02x}")).collect();
+        pcrs.insert(
+            format!("kms:RecipientAttestation:PCR{index}"),
+            Value::String(value),
+        );
+    }
+
+    Ok(json!({ "StringEqualsIgnoreCase": pcrs }))
+}
+
+/// A complete key policy statement, allowing principal the actions only from an
+/// enclave with the measurements
+pub fn statement(
+    measurements: &EIFMeasurements,
+    principal: &str,
+    actions: &[String],
+) -> Result<Value> {
+    Ok(json!({
+        "Sid": STATEMENT_ID,
+        "Effect": "Allow",
+        "Principal": { "AWS": principal },
+        "Action": actions,
+        "Resource": "*",
+        "Condition": condition(measurements)?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{condition, statement};
+    use crate::nitro_cli::EIFMeasurements;
+    use assert2::assert;
+    use serde_json::json;
+
+    #[test]
+    fn test_condition() {
+        let measurements: EIFMeasurements = serde_json::from_value(json!({
+            "PCR0": "00FF",
+            "PCR1": "a0",
+            "PCR2": "0b",
+        }))
+        .unwrap();
+
+        let cond = condition(&measurements).unwrap();
+        assert!(
+            cond == json!({
+                "StringEqualsIgnoreCase": {
+                    "kms:RecipientAttestation:PCR0": "00ff",
+                    "kms:RecipientAttestation:PCR1": "a0",
+                    "kms:RecipientAttestation:PCR2": "0b",
+                }
+            })
+        );
+
+        let signed: EIFMeasurements = serde_json::from_value(json!({
+            "PCR0": "00",
+            "PCR1": "01",
+            "PCR2": "02",
+            "PCR8": "08",
+        }))
+        .unwrap();
+        let stmt = statement(
+            &signed,
+            "arn:aws:iam::123456789012:role/enclave",
+            &["kms:Decrypt".to_string()],
+        )
+        .unwrap();
+        assert!(stmt["Principal"]["AWS"] == "arn:aws:iam::123456789012:role/enclave");
+        assert!(stmt["Action"] == json!(["kms:Decrypt"]));
+        assert!(
+            stmt["Condition"]["StringEqualsIgnoreCase"]["kms:RecipientAttestation:PCR8"] == "08"
+        );
+    }
+}
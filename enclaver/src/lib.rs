@@ -2,39 +2,136 @@
 
 extern crate core;
 
+// The manifest and policy types, always built. See the features in Cargo.toml
+// for how to keep dependents from pulling in docker and the proxies.
+pub mod constants;
+pub mod identity;
+pub mod journal;
+pub mod manifest;
+pub mod policy;
+
+#[cfg(feature = "verify")]
+pub mod attestation;
+
+#[cfg(feature = "verify")]
+pub mod service_config;
+
+#[cfg(feature = "verify")]
+mod der;
+
+#[cfg(feature = "docker")]
 pub mod build;
 
+#[cfg(feature = "docker")]
 mod images;
 
-pub mod constants;
+#[cfg(any(feature = "docker", feature = "run_enclave"))]
+pub mod bundle;
+
+#[cfg(any(feature = "docker", feature = "run_enclave"))]
+pub mod eif_chunks;
+
+#[cfg(feature = "docker")]
+pub mod run_container;
+
+#[cfg(feature = "docker")]
+pub mod compose;
 
+#[cfg(feature = "runtime")]
 pub mod nitro_cli;
 
-pub mod manifest;
+#[cfg(feature = "runtime")]
+pub mod kms_policy;
+
+#[cfg(feature = "runtime")]
+pub mod iac;
 
+#[cfg(feature = "runtime")]
 pub mod http_client;
+
+#[cfg(feature = "runtime")]
 pub mod keypair;
-pub mod policy;
-pub mod run_container;
+
+#[cfg(feature = "runtime")]
+pub mod metrics;
+
+#[cfg(feature = "runtime")]
+pub mod preflight;
+
+#[cfg(feature = "runtime")]
+pub mod cpu_topology;
+
+#[cfg(feature = "runtime")]
+pub mod resolver;
 
 #[cfg(feature = "run_enclave")]
 pub mod run;
 
+#[cfg(feature = "run_enclave")]
+pub mod events;
+
+#[cfg(feature = "run_enclave")]
+pub mod netns;
+
+#[cfg(feature = "run_enclave")]
+pub mod sandbox;
+
 #[cfg(feature = "odyn")]
 pub mod nsm;
 
 #[cfg(feature = "odyn")]
 pub mod api;
 
+#[cfg(feature = "odyn")]
+pub mod spiffe;
+
+#[cfg(feature = "odyn")]
+pub mod token;
+
 #[cfg(feature = "proxy")]
 pub mod proxy;
 
 #[cfg(feature = "vsock")]
 pub mod vsock;
 
+#[cfg(feature = "vsock")]
+pub mod boot_config;
+
+#[cfg(feature = "vsock")]
+pub mod config_provider;
+
+#[cfg(feature = "vsock")]
+pub mod status;
+
+#[cfg(feature = "vsock")]
+pub mod banner;
+
+#[cfg(feature = "vsock")]
+pub mod compression;
+
+#[cfg(any(feature = "docker", feature = "vsock"))]
+pub mod policy_update;
+
+#[cfg(any(feature = "docker", feature = "vsock"))]
+pub mod connections;
+
+#[cfg(any(feature = "docker", feature = "vsock"))]
+pub mod bench;
+
+#[cfg(any(feature = "docker", feature = "vsock"))]
+pub mod tls_issuance;
+
 #[cfg(feature = "proxy")]
 pub mod tls;
 
+#[cfg(feature = "proxy")]
+pub mod protocol;
+
+#[cfg(feature = "runtime")]
 pub mod utils;
 
+#[cfg(any(all(test, feature = "runtime"), feature = "fuzzing"))]
+pub mod fuzz;
+
+#[cfg(feature = "runtime")]
 pub mod http_util;
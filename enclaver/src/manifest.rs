@@ -1,15 +1,84 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
 
-use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 use std::pin::Pin;
 use tokio::fs::File;
 use tokio::io::AsyncRead;
 
+use thiserror::Error;
 use tokio::io::AsyncReadExt;
 
+use crate::constants::{
+    DEFAULT_MEMORY_MB, DEFAULT_SUPERVISOR_MEMORY_MB, HTTP_EGRESS_VSOCK_PORT, NITRO_ENCLAVES_DEVICE,
+    OUTSIDE_HOST, TRANSPARENT_EGRESS_PORT,
+};
+use crate::policy::EgressPolicy;
+
+// Where odyn answers DNS queries inside the enclave, with egress.dns or transparent egress
+const DNS_PORT: u16 = 53;
+
+// How long tunnels a policy update revoked stay open, with revoked_connections drain
+const DEFAULT_DRAIN_SECONDS: u64 = 30;
+
+/// Why a manifest could not be loaded
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error("failed to open {}: {source}", path.display())]
+    Open {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("failed to read {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("invalid configuration in {}: {source}", path.display())]
+    Config { path: PathBuf, source: ConfigError },
+}
+
+/// What is wrong with the contents of a manifest
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error(transparent)]
+    Syntax(#[from] serde_yaml::Error),
+
+    #[error("{0}")]
+    Secret(String),
+
+    #[error("{0}")]
+    Ingress(String),
+
+    #[error("{0}")]
+    EgressProxy(String),
+
+    #[error("{0}")]
+    Database(String),
+
+    #[error("{0}")]
+    Spiffe(String),
+
+    #[error("{0}")]
+    Api(String),
+
+    #[error("{0}")]
+    KmsProxy(String),
+
+    #[error("{0}")]
+    Defaults(String),
+
+    #[error("{0}")]
+    Build(String),
+
+    #[error("{0}")]
+    Host(String),
+}
+
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Manifest {
@@ -18,11 +87,169 @@ pub struct Manifest {
     pub target: String,
     pub sources: Sources,
     pub signature: Option<Signature>,
+    pub build: Option<Build>,
     pub ingress: Option<Vec<Ingress>>,
     pub egress: Option<Egress>,
     pub defaults: Option<Defaults>,
     pub kms_proxy: Option<KmsProxy>,
+    pub s3_proxy: Option<S3Proxy>,
     pub api: Option<Api>,
+    pub health: Option<Health>,
+    pub runtime_config: Option<RuntimeConfig>,
+    pub secrets: Option<Vec<Secret>>,
+    pub spiffe: Option<Spiffe>,
+    pub console: Option<bool>,
+    pub debug: Option<bool>,
+    pub host: Option<Host>,
+}
+
+impl Manifest {
+    /// Whether the output of the application may leave the enclave. Defaults to true.
+    pub fn console_enabled(&self) -> bool {
+        self.console.unwrap_or(true)
+    }
+
+    /// Whether the image was built to run in debug mode. Defaults to false.
+    pub fn is_debug(&self) -> bool {
+        self.debug.unwrap_or(false)
+    }
+
+    /// Memory in MiB that odyn and its proxies are given on top of that of the app.
+    /// Defaults to DEFAULT_SUPERVISOR_MEMORY_MB, plus proxy_buffer_mb if set.
+    pub fn supervisor_memory_mb(&self) -> i32 {
+        let defaults = self.defaults.as_ref();
+        match defaults.and_then(|d| d.supervisor_memory_mb) {
+            Some(mb) => mb as i32,
+            None => {
+                let buffers = defaults.and_then(|d| d.proxy_buffer_mb).unwrap_or(0);
+                DEFAULT_SUPERVISOR_MEMORY_MB + buffers as i32
+            }
+        }
+    }
+
+    /// Memory in MiB of the enclave, that of the app, memory_mb unless given, and that
+    /// of odyn together
+    pub fn enclave_memory_mb(&self, app_memory_mb: Option<i32>) -> i32 {
+        let app_memory_mb = app_memory_mb
+            .or_else(|| self.defaults.as_ref().and_then(|d| d.memory_mb))
+            .unwrap_or(DEFAULT_MEMORY_MB);
+        app_memory_mb + self.supervisor_memory_mb()
+    }
+
+    /// Ports listened on inside the enclave, other than the default egress proxy,
+    /// along with what listens on them
+    pub fn listen_ports(&self) -> Vec<(u16, String)> {
+        let mut ports: Vec<(u16, String)> = self
+            .ingress
+            .iter()
+            .flatten()
+            .map(|item| (item.target_port(), "ingress".to_string()))
+            .collect();
+
+        if let Some(ref kms_proxy) = self.kms_proxy {
+            ports.push((kms_proxy.listen_port, "kms_proxy".to_string()));
+        }
+        if let Some(ref s3_proxy) = self.s3_proxy {
+            ports.push((s3_proxy.listen_port, "s3_proxy".to_string()));
+        }
+        if let Some(ref api) = self.api {
+            ports.push((api.listen_port, "api".to_string()));
+        }
+        if let Some(ref health) = self.health {
+            ports.push((health.listen_port, "health".to_string()));
+        }
+        if let Some(relay) = self.egress.as_ref().and_then(|e| e.imds_relay.as_ref()) {
+            ports.push((relay.listen_port, "imds_relay".to_string()));
+        }
+
+        if self.egress.as_ref().is_some_and(Egress::is_transparent) {
+            ports.push((
+                TRANSPARENT_EGRESS_PORT,
+                "transparent egress proxy".to_string(),
+            ));
+        }
+
+        for proxy in self.egress_proxies() {
+            ports.push((proxy.proxy_port, format!("egress proxy {}", proxy.name)));
+        }
+
+        for forward in self.egress_forwards() {
+            ports.push((
+                forward.local_port,
+                format!("egress forward to {}:{}", forward.host, forward.port),
+            ));
+        }
+
+        ports
+    }
+
+    /// The ports forwarded to remotes through the egress channel
+    pub fn egress_forwards(&self) -> impl Iterator<Item = &EgressForward> {
+        self.egress
+            .iter()
+            .flat_map(|egress| egress.forward.iter().flatten())
+    }
+
+    /// The UDP ports relayed to remotes through the host
+    pub fn egress_udp(&self) -> impl Iterator<Item = &EgressForward> {
+        self.egress
+            .iter()
+            .flat_map(|egress| egress.udp.iter().flatten())
+    }
+
+    /// The names of services on the host, and the ports they map to
+    pub fn egress_services(&self) -> impl Iterator<Item = &EgressService> {
+        self.egress
+            .iter()
+            .flat_map(|egress| egress.services.iter().flatten())
+    }
+
+    /// The named egress proxies, besides the default one
+    pub fn egress_proxies(&self) -> impl Iterator<Item = &EgressProxy> {
+        self.egress
+            .iter()
+            .flat_map(|egress| egress.proxies.iter().flatten())
+    }
+
+    /// Fails if the port of an egress proxy or forward is also used by another listener
+    pub fn check_egress_proxy_ports(&self) -> Result<(), ConfigError> {
+        let mut ports = self.listen_ports();
+        if let Some(port) = self.egress.as_ref().and_then(|egress| egress.proxy_port) {
+            ports.push((port, "egress proxy".to_string()));
+        }
+
+        for (i, (port, owner)) in ports.iter().enumerate() {
+            if !owner.contains("egress proxy") && !owner.starts_with("egress forward") {
+                continue;
+            }
+
+            if let Some((_, other)) = ports
+                .iter()
+                .enumerate()
+                .find_map(|(j, p)| (j != i && p.0 == *port).then_some(p))
+            {
+                return Err(ConfigError::EgressProxy(format!(
+                    "port {port} of the {owner} is also used by the {other}"
+                )));
+            }
+        }
+
+        let mut names: Vec<&str> = self.egress_proxies().map(|p| p.name.as_str()).collect();
+        if names.contains(&IMDS_RELAY_POLICY) {
+            return Err(ConfigError::EgressProxy(format!(
+                "egress proxy name {IMDS_RELAY_POLICY} is reserved for the IMDS relay"
+            )));
+        }
+        names.sort();
+        if let Some(name) = names.windows(2).find(|w| w[0] == w[1]) {
+            return Err(ConfigError::EgressProxy(format!(
+                "egress proxy {} is defined more than once",
+                name[0]
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -40,103 +267,2852 @@ pub struct Signature {
     pub key: PathBuf,
 }
 
+/// Resources of the host that `enclaver run` gives the wrapper container, on top of
+/// the Nitro Enclaves device
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Host {
+    /// Devices of the host, as path, or host path and container path separated by a
+    /// colon
+    pub devices: Option<Vec<String>>,
+    pub tmpfs: Option<Vec<HostTmpfs>>,
+    pub ulimits: Option<Vec<HostUlimit>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostTmpfs {
+    pub path: String,
+
+    /// Unset leaves the size to docker, which defaults to half of the memory of the
+    /// host
+    pub size_mb: Option<u64>,
+}
+
+/// A resource limit of the wrapper container, -1 being unlimited
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostUlimit {
+    pub name: String,
+    pub soft: i64,
+    pub hard: i64,
+}
+
+impl Host {
+    /// The names of the resource limits docker sets, as in setrlimit(2)
+    pub const ULIMITS_ALLOWED: &'static [&'static str] = &[
+        "core",
+        "cpu",
+        "data",
+        "fsize",
+        "locks",
+        "memlock",
+        "msgqueue",
+        "nice",
+        "nofile",
+        "nproc",
+        "rss",
+        "rtprio",
+        "rttime",
+        "sigpending",
+        "stack",
+    ];
+
+    /// The devices as host path and container path
+    pub fn devices(&self) -> Vec<(&str, &str)> {
+        self.devices
+            .iter()
+            .flatten()
+            .map(|device| device.split_once(':').unwrap_or((device, device)))
+            .collect()
+    }
+}
+
+/// How the EIF is built
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Build {
+    pub kernel_args: Option<Vec<String>>,
+}
+
+impl Build {
+    /// The names of the kernel parameters that may be set, none of which can take the
+    /// console or init away from odyn or weaken the isolation of the enclave
+    pub const KERNEL_ARGS_ALLOWED: &'static [&'static str] = &[
+        "clocksource",
+        "tsc",
+        "cgroup_enable",
+        "cgroup_disable",
+        "cgroup_no_v1",
+        "systemd.unified_cgroup_hierarchy",
+        "transparent_hugepage",
+        "hugepagesz",
+        "hugepages",
+        "default_hugepagesz",
+        "init_on_alloc",
+        "init_on_free",
+        "page_alloc.shuffle",
+        "randomize_kstack_offset",
+        "quiet",
+        "loglevel",
+    ];
+
+    pub fn kernel_args(&self) -> &[String] {
+        self.kernel_args.as_deref().unwrap_or_default()
+    }
+
+    /// The kernel command line of an EIF built with these arguments, starting from the
+    /// default one of the toolchain. An argument replaces a default of the same name.
+    pub fn kernel_cmdline(&self, default: &str) -> String {
+        let name = |arg: &str| arg.split('=').next().unwrap_or_default().to_string();
+        let names: Vec<String> = self.kernel_args().iter().map(|arg| name(arg)).collect();
+
+        default
+            .split_whitespace()
+            .filter(|arg| !names.contains(&name(arg)))
+            .chain(self.kernel_args().iter().map(String::as_str))
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Ingress {
     pub listen_port: u16,
+
+    /// Port of the app inside the enclave that connections to listen_port are
+    /// proxied to. Defaults to listen_port.
+    pub target_port: Option<u16>,
+
     pub tls: Option<ServerTls>,
+    pub max_connections: Option<u32>,
+    pub accepts_per_second: Option<u32>,
+    pub keepalive_seconds: Option<u32>,
+    pub buffer_bytes: Option<u32>,
+    pub access_log: Option<bool>,
+    pub bind_addr: Option<IpAddr>,
+
+    /// Whether odyn answers GET /.well-known/enclaver/attestation on the port with an
+    /// attestation document, rather than passing the request on to the app
+    pub serve_attestation: Option<bool>,
+}
+
+impl Ingress {
+    /// Port of the app that connections to the port go to
+    pub fn target_port(&self) -> u16 {
+        self.target_port.unwrap_or(self.listen_port)
+    }
+
+    /// Address of the host that enclaver-run listens on for the port, all of them
+    /// unless bind_addr is set
+    pub fn host_addr(&self) -> IpAddr {
+        self.bind_addr.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+
+    /// Whether each connection to the port is logged when it closes. Defaults to false.
+    pub fn logs_access(&self) -> bool {
+        self.access_log.unwrap_or(false)
+    }
+
+    /// Whether odyn serves attestations on the port. Defaults to false.
+    pub fn serves_attestation(&self) -> bool {
+        self.serve_attestation.unwrap_or(false)
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct ServerTls {
-    pub key_file: String,
-    pub cert_file: String,
+    pub key_file: Option<String>,
+    pub cert_file: Option<String>,
+
+    /// Whether odyn generates the key itself and presents a self-signed certificate
+    /// that carries an attestation document binding the key, in place of key_file and
+    /// cert_file. Defaults to false.
+    pub attested: Option<bool>,
+
+    /// Whether odyn generates the key itself and has a CA issue its certificate, in
+    /// place of key_file and cert_file. The key never leaves the enclave, only a
+    /// certificate signing request for it does.
+    pub csr: Option<CertificateRequest>,
+
+    /// Whether key_file holds the PEM encoded key encrypted with AWS KMS, rather than
+    /// the key itself. odyn decrypts it through the KMS proxy before the port starts,
+    /// so the plaintext key is never in the image. Defaults to false.
+    pub kms_encrypted_key: Option<bool>,
+
+    /// Path inside the enclave of the PEM encoded CA certificates that client
+    /// certificates must chain to
+    pub client_ca_file: Option<String>,
+
+    /// Whether clients must present a certificate. Defaults to true if
+    /// client_ca_file is set.
+    pub require_client_auth: Option<bool>,
+
+    /// Whether the app is sent a PROXY protocol v2 header with the identity of the
+    /// client ahead of each connection. Defaults to false.
+    pub proxy_protocol: Option<bool>,
+
+    /// Server names whose connections go to other ports of the app than target_port
+    pub routes: Option<Vec<SniRoute>>,
+
+    /// Protocols offered to clients through ALPN, in order of preference, e.g. h2 and
+    /// http/1.1 for a gRPC server. None are offered by default.
+    pub alpn: Option<Vec<String>>,
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// What the certificate signing request of a port asks for. The ports with a csr share
+/// one key, so its request names the DNS names of all of them.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateRequest {
+    pub dns_names: Vec<String>,
+}
+
+/// Connections that ask for server_name are proxied to port of the app, and presented
+/// the certificate of key_file and cert_file if set, the one of the listen port if not
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniRoute {
+    pub server_name: String,
+    pub port: u16,
+    pub key_file: Option<String>,
+    pub cert_file: Option<String>,
+}
+
+impl ServerTls {
+    pub fn requires_client_auth(&self) -> bool {
+        self.require_client_auth
+            .unwrap_or(self.client_ca_file.is_some())
+    }
+
+    pub fn sends_proxy_protocol(&self) -> bool {
+        self.proxy_protocol.unwrap_or(false)
+    }
+
+    pub fn is_attested(&self) -> bool {
+        self.attested.unwrap_or(false)
+    }
+
+    /// Whether odyn generates the key of the port, rather than it being in the image
+    pub fn generates_key(&self) -> bool {
+        self.is_attested() || self.csr.is_some()
+    }
+
+    pub fn is_kms_encrypted(&self) -> bool {
+        self.kms_encrypted_key.unwrap_or(false)
+    }
+
+    pub fn alpn_protocols(&self) -> &[String] {
+        self.alpn.as_deref().unwrap_or_default()
+    }
+
+    /// Whether the key and certificate files of the port can be loaded again once the
+    /// app has replaced them
+    pub fn is_reloadable(&self) -> bool {
+        !self.generates_key() && !self.is_kms_encrypted()
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Egress {
     pub proxy_port: Option<u16>,
+    pub transparent: Option<bool>,
+    pub dns: Option<bool>,
+    pub default: Option<EgressDefault>,
     pub allow: Option<Vec<String>>,
     pub deny: Option<Vec<String>>,
+    pub protocols: Option<Vec<ProtocolEgress>>,
+    pub databases: Option<Vec<DatabaseEgress>>,
+    pub limits: Option<Vec<EgressLimit>>,
+    pub requests: Option<Vec<RequestRule>>,
+    pub imds: Option<Imds>,
+    pub http2_prior_knowledge: Option<Vec<String>>,
+    pub verify_sni: Option<bool>,
+    pub policy_signing_key: Option<String>,
+
+    /// Serial that policy updates must be above, as odyn forgets the last one it
+    /// applied when it restarts
+    pub policy_serial_floor: Option<u64>,
+
+    pub revoked_connections: Option<RevokedConnections>,
+    pub proxies: Option<Vec<EgressProxy>>,
+    pub forward: Option<Vec<EgressForward>>,
+    pub udp: Option<Vec<EgressForward>>,
+    pub services: Option<Vec<EgressService>>,
+    pub host_address: Option<Ipv4Addr>,
+    pub pin_dns: Option<bool>,
+    pub policy_hook: Option<PolicyHook>,
+    pub imds_relay: Option<ImdsRelay>,
+    pub relays: Option<Vec<EgressRelay>>,
 }
 
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+impl Egress {
+    /// Whether anything at all may leave the enclave
+    pub fn is_enabled(&self) -> bool {
+        self.default_action() == EgressDefault::Allow
+            || self.allow.as_ref().is_some_and(|allow| !allow.is_empty())
+            || self
+                .protocols
+                .as_ref()
+                .is_some_and(|protocols| !protocols.is_empty())
+            || self
+                .databases
+                .as_ref()
+                .is_some_and(|databases| !databases.is_empty())
+    }
+
+    /// What happens to connections that no allow or deny rule matches. Defaults to deny.
+    pub fn default_action(&self) -> EgressDefault {
+        self.default.unwrap_or_default()
+    }
+
+    /// Whether connections of applications that ignore the proxy are intercepted.
+    /// Defaults to false.
+    pub fn is_transparent(&self) -> bool {
+        self.transparent.unwrap_or(false)
+    }
+
+    /// Whether odyn forwards DNS queries for allowed names to the host. Defaults to false.
+    pub fn is_dns_enabled(&self) -> bool {
+        self.dns.unwrap_or(false)
+    }
+
+    /// What egress.dns answers for the host and its services. Defaults to 127.0.0.1.
+    pub fn host_address(&self) -> Ipv4Addr {
+        self.host_address.unwrap_or(Ipv4Addr::LOCALHOST)
+    }
+
+    /// Whether the host pins allowed names to the addresses they resolved to.
+    /// Defaults to false.
+    pub fn pins_dns(&self) -> bool {
+        self.pin_dns.unwrap_or(false)
+    }
+
+    /// The vsock ports of the host egress relays, in order of preference. Defaults to
+    /// the one relay every host runs.
+    pub fn relay_ports(&self) -> Vec<u32> {
+        match self.relays {
+            Some(ref relays) if !relays.is_empty() => {
+                relays.iter().map(|relay| relay.vsock_port).collect()
+            }
+            _ => vec![HTTP_EGRESS_VSOCK_PORT],
+        }
+    }
+
+    /// The policy odyn reaches IMDS through for the relay, if there is one: HTTP to
+    /// IMDS, held to what the relay may read
+    pub fn imds_relay_policy(&self) -> Option<Egress> {
+        let relay = self.imds_relay.as_ref()?;
+
+        Some(Egress {
+            allow: Some(IMDS_ADDRESSES.iter().map(|a| format!("{a}:80")).collect()),
+            imds: Some(relay.imds()),
+            ..Default::default()
+        })
+    }
+
+    /// The names the policies allow by name, rather than by wildcard or address,
+    /// without their ports. These are the names the host can pin ahead of time.
+    pub fn pinned_names(&self) -> Vec<String> {
+        let host_names = self.host_names();
+        let policies = std::iter::once(self.clone())
+            .chain(self.proxies.iter().flatten().map(|proxy| proxy.policy()));
+
+        let mut names = Vec::new();
+        for policy in policies {
+            let patterns = policy
+                .allow
+                .iter()
+                .flatten()
+                .chain(policy.protocols.iter().flatten().flat_map(|p| &p.allow))
+                .chain(policy.databases.iter().flatten().map(|db| &db.host));
+
+            for pattern in patterns {
+                let Ok((host, _)) = crate::policy::ports::split(pattern) else {
+                    continue;
+                };
+                let host = host.trim_end_matches('.').to_ascii_lowercase();
+                let literal = !host.contains(['*', '/']) && host.parse::<IpAddr>().is_err();
+                if literal && !host_names.contains(&host) && !names.contains(&host) {
+                    names.push(host);
+                }
+            }
+        }
+
+        names
+    }
+
+    /// The names that refer to the host: the host itself and each of its services
+    pub fn host_names(&self) -> Vec<String> {
+        std::iter::once(OUTSIDE_HOST.to_string())
+            .chain(
+                self.services
+                    .iter()
+                    .flatten()
+                    .map(|service| service.name.to_ascii_lowercase()),
+            )
+            .collect()
+    }
+}
+
+/// What the egress policy does with connections no rule matches
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EgressDefault {
+    #[default]
+    Deny,
+    Allow,
+}
+
+/// An additional egress proxy with a policy of its own, e.g. a broader one for a
+/// metrics sidecar than for the application
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct Defaults {
-    pub cpu_count: Option<i32>,
-    pub memory_mb: Option<i32>,
+pub struct EgressProxy {
+    pub name: String,
+    pub proxy_port: u16,
+    pub default: Option<EgressDefault>,
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub protocols: Option<Vec<ProtocolEgress>>,
+    pub databases: Option<Vec<DatabaseEgress>>,
+    pub limits: Option<Vec<EgressLimit>>,
+    pub requests: Option<Vec<RequestRule>>,
+    pub imds: Option<Imds>,
+    pub http2_prior_knowledge: Option<Vec<String>>,
+    pub verify_sni: Option<bool>,
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+impl EgressProxy {
+    /// The policy of this proxy, in the shape of the default one
+    pub fn policy(&self) -> Egress {
+        Egress {
+            proxy_port: Some(self.proxy_port),
+            transparent: None,
+            dns: None,
+            default: self.default,
+            allow: self.allow.clone(),
+            deny: self.deny.clone(),
+            protocols: self.protocols.clone(),
+            databases: self.databases.clone(),
+            limits: self.limits.clone(),
+            requests: self.requests.clone(),
+            imds: self.imds.clone(),
+            http2_prior_knowledge: self.http2_prior_knowledge.clone(),
+            verify_sni: self.verify_sni,
+            policy_signing_key: None,
+            policy_serial_floor: None,
+            revoked_connections: None,
+            proxies: None,
+            forward: None,
+            udp: None,
+            services: None,
+            host_address: None,
+            pin_dns: None,
+            policy_hook: None,
+            imds_relay: None,
+            relays: None,
+        }
+    }
+}
+
+/// A port inside the enclave that odyn pipes to a fixed remote, for clients that
+/// cannot use an HTTP proxy. TCP for egress.forward, UDP for egress.udp.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct KmsProxy {
-    pub listen_port: u16,
-    pub endpoints: Option<HashMap<String, String>>,
+pub struct EgressForward {
+    pub local_port: u16,
+    pub host: String,
+    pub port: u16,
+    pub tls: Option<ForwardTls>,
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// TLS that odyn originates to the remote of a forward, presenting a client
+/// certificate, so the application speaks plaintext and never holds the key. The
+/// certificate and key are either files inside the enclave or the SVID of the enclave.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct Api {
-    pub listen_port: u16,
+pub struct ForwardTls {
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    pub svid: Option<bool>,
+    pub ca_file: Option<String>,
 }
 
-fn parse_manifest(buf: &[u8]) -> Result<Manifest> {
-    let manifest: Manifest = serde_yaml::from_slice(buf)?;
+impl ForwardTls {
+    /// Whether the SVID is presented instead of a certificate from files. Defaults to false.
+    pub fn uses_svid(&self) -> bool {
+        self.svid.unwrap_or(false)
+    }
+}
 
-    Ok(manifest)
+/// A name for a service listening on localhost of the host, e.g. redis.host.internal,
+/// so applications can reach it with an ordinary URL. Whatever port the application
+/// asks for, the host connects to this one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EgressService {
+    pub name: String,
+    pub port: u16,
 }
 
-pub async fn load_manifest_raw<P: AsRef<Path>>(path: P) -> Result<(Vec<u8>, Manifest)> {
-    let mut file: Pin<Box<dyn AsyncRead>> = if path.as_ref() == Path::new("-") {
-        Box::pin(tokio::io::stdin())
-    } else {
-        match File::open(&path).await {
-            Ok(file) => Box::pin(file),
-            Err(err) => anyhow::bail!("failed to open {}: {err}", path.as_ref().display()),
+/// A relay on the host that egress connects out through, e.g. a standby one run by
+/// another enclaver-run on a separate ENI
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EgressRelay {
+    pub vsock_port: u32,
+}
+
+/// Egress for a non-HTTP protocol tunneled through CONNECT, which the proxy
+/// understands well enough to follow its upgrade to TLS.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProtocolEgress {
+    pub protocol: Protocol,
+    pub allow: Vec<String>,
+    pub ports: Option<Vec<u16>>,
+}
+
+/// A database the enclave may connect to, optionally only over TLS
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseEgress {
+    pub host: String,
+    pub port: u16,
+    pub engine: Option<Protocol>,
+    pub require_tls: Option<bool>,
+}
+
+impl DatabaseEgress {
+    /// The database protocol, as given or inferred from the port
+    pub fn engine(&self) -> Option<Protocol> {
+        match (self.engine, self.port) {
+            (Some(engine), _) => Some(engine),
+            (None, 5432) => Some(Protocol::Postgres),
+            (None, 3306) => Some(Protocol::Mysql),
+            _ => None,
         }
-    };
+    }
+}
 
-    let mut buf = Vec::new();
-    file.read_to_end(&mut buf).await?;
+/// What the enclave proxy does with the open tunnels a policy update takes away
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RevokedConnections {
+    #[serde(default)]
+    pub mode: RevokeMode,
 
-    let manifest = parse_manifest(&buf)
-        .map_err(|e| anyhow!("invalid configuration in {}: {e}", path.as_ref().display()))?;
+    /// How long a draining tunnel stays open. Defaults to 30 seconds.
+    pub drain_seconds: Option<u64>,
+}
 
-    Ok((buf, manifest))
+impl RevokedConnections {
+    pub fn drain_seconds(&self) -> u64 {
+        self.drain_seconds.unwrap_or(DEFAULT_DRAIN_SECONDS)
+    }
 }
 
-pub async fn load_manifest<P: AsRef<Path>>(path: P) -> Result<Manifest> {
-    let (_, manifest) = load_manifest_raw(path).await?;
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RevokeMode {
+    /// Leave them open until either side closes them
+    #[default]
+    Keep,
 
-    Ok(manifest)
+    /// Close them once drain_seconds have passed, unless they close before
+    Drain,
+
+    /// Close them right away
+    Terminate,
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::manifest::parse_manifest;
+/// A WebAssembly module in the image that has the last word on each connection the
+/// egress policies allow, see `policy::wasm` for what it exports
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyHook {
+    /// Absolute path of the module in the image
+    pub wasm: String,
 
-    #[test]
-    fn test_parse_manifest_with_unknown_fields() {
-        assert!(parse_manifest(br#"foo: "bar""#).is_err());
+    /// Fuel a decision may use, about as many instructions. Defaults to a million.
+    pub fuel: Option<u64>,
+}
+
+/// Caps how fast the enclave may open connections to a set of hosts, and send data
+/// to them, whichever rule allowed the connections. All hosts of a limit share it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EgressLimit {
+    pub hosts: Vec<String>,
+
+    /// Connections and requests per second
+    pub rate_limit: Option<u32>,
+
+    /// Bytes per second sent to the hosts
+    pub bandwidth: Option<u32>,
+}
+
+/// Narrows the requests the egress proxy forwards to a set of hosts, for plain HTTP
+/// services such as IMDS-compatible endpoints. Rules do not allow anything, and the
+/// first that matches a request applies.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestRule {
+    pub hosts: Vec<String>,
+
+    /// e.g. GET. Any method if not set.
+    pub methods: Option<Vec<String>>,
+
+    /// e.g. /latest/meta-data/. Any path if not set.
+    pub path_prefixes: Option<Vec<String>>,
+
+    /// Largest request body. Unlimited if not set.
+    pub max_body_bytes: Option<u64>,
+}
+
+/// What an enclave needs of the instance metadata service: the region, and the
+/// credentials of the instance role
+pub const IMDS_DEFAULT_PATHS: &[&str] = &[
+    "/latest/meta-data/placement/region",
+    "/latest/meta-data/iam/security-credentials/",
+];
+
+/// Narrows the requests the egress proxy forwards to the instance metadata service
+/// down to reading a few paths, e.g. so that the enclave cannot read the user data of
+/// the instance. Like request rules, it does not allow IMDS by itself.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Imds {
+    /// Path prefixes that may be read. Defaults to IMDS_DEFAULT_PATHS.
+    pub paths: Option<Vec<String>>,
+
+    /// Whether reads must carry an IMDSv2 session token. Defaults to true.
+    pub require_token: Option<bool>,
+}
+
+/// Addresses of the instance metadata service
+pub const IMDS_ADDRESSES: &[&str] = &["169.254.169.254", "[fd00:ec2::254]"];
+
+/// Name of the policy odyn reaches IMDS with for the relay, in its connect requests
+pub const IMDS_RELAY_POLICY: &str = "imds_relay";
+
+/// A local endpoint of odyn in the shape of IMDS, which serves the app the paths of
+/// IMDS it needs from an IMDSv2 client of odyn. odyn reaches IMDS through a policy of
+/// its own for it, so the policies of the app need not allow IMDS at all.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImdsRelay {
+    pub listen_port: u16,
+
+    /// Path prefixes that may be read. Defaults to IMDS_DEFAULT_PATHS.
+    pub paths: Option<Vec<String>>,
+}
+
+impl ImdsRelay {
+    /// What odyn may ask IMDS for on behalf of the relay
+    pub fn imds(&self) -> Imds {
+        Imds {
+            paths: self.paths.clone(),
+            require_token: Some(true),
+        }
     }
+}
 
-    #[test]
-    fn test_parse_minimal_manifest() {
-        let raw_manifest = br#"
-version: v1
-name: "test"
-target: "target-image:latest"
-sources:
-  app: "app-image:latest"
-#r"#;
+impl Imds {
+    pub fn paths(&self) -> Vec<String> {
+        match self.paths {
+            Some(ref paths) => paths.clone(),
+            None => IMDS_DEFAULT_PATHS.iter().map(|p| p.to_string()).collect(),
+        }
+    }
 
-        let manifest = parse_manifest(raw_manifest).unwrap();
+    pub fn requires_token(&self) -> bool {
+        self.require_token.unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Smtp,
+    Imap,
+    Postgres,
+    Mysql,
+}
+
+impl Protocol {
+    /// The well known ports of the protocol, with and without implicit TLS
+    pub fn default_ports(&self) -> &'static [u16] {
+        match self {
+            Protocol::Smtp => &[25, 465, 587],
+            Protocol::Imap => &[143, 993],
+            Protocol::Postgres => &[5432],
+            Protocol::Mysql => &[3306],
+        }
+    }
+
+    pub fn is_database(&self) -> bool {
+        matches!(self, Protocol::Postgres | Protocol::Mysql)
+    }
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Protocol::Smtp => write!(f, "smtp"),
+            Protocol::Imap => write!(f, "imap"),
+            Protocol::Postgres => write!(f, "postgres"),
+            Protocol::Mysql => write!(f, "mysql"),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Defaults {
+    pub cpu_count: Option<i32>,
+
+    /// Memory of the app. The enclave is given supervisor_memory_mb on top of it.
+    pub memory_mb: Option<i32>,
+
+    /// Memory kept for odyn and its proxies, see Manifest::supervisor_memory_mb
+    pub supervisor_memory_mb: Option<u32>,
+    pub ingress_max_connections: Option<u32>,
+    pub ingress_accepts_per_second: Option<u32>,
+    pub proxy_buffer_mb: Option<u32>,
+    pub drain_seconds: Option<u64>,
+    pub exit_codes: Option<ExitCodes>,
+}
+
+/// Exit codes enclaver-run reports when the enclave ends without the application
+/// exiting on its own
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExitCodes {
+    pub signaled: Option<u8>,
+    pub fatal: Option<u8>,
+    pub interrupted: Option<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KmsProxy {
+    pub listen_port: u16,
+    pub endpoints: Option<HashMap<String, String>>,
+
+    /// Where the proxy gets the AWS credentials it signs requests with, the instance
+    /// profile from IMDS unless set
+    pub credentials: Option<KmsCredentials>,
+}
+
+/// Credentials of the KMS proxy, for hosts without an instance profile to lend it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KmsCredentials {
+    pub source: KmsCredentialSource,
+
+    /// Region of KMS, and of STS for web_identity. Defaults to the AWS_REGION of
+    /// enclaver-run for env and container, and to that of the instance otherwise.
+    pub region: Option<String>,
+
+    /// Role assumed with the web identity token
+    pub role_arn: Option<String>,
+
+    /// Name of an earlier secret holding the web identity token
+    pub token_from: Option<String>,
+
+    pub session_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KmsCredentialSource {
+    /// The instance profile, from IMDS
+    Imds,
+
+    /// AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN of enclaver-run,
+    /// handed to odyn at boot
+    Env,
+
+    /// The container credentials endpoint of enclaver-run, e.g. that of its ECS task
+    Container,
+
+    /// AssumeRoleWithWebIdentity, e.g. with the token of an EKS service account
+    WebIdentity,
+}
+
+/// A proxy that signs S3 requests of the application with the credentials of the
+/// instance, streaming the bodies through
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct S3Proxy {
+    pub listen_port: u16,
+    pub endpoints: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Api {
+    pub listen_port: u16,
+    pub tokens: Option<ApiTokens>,
+    pub channel_attestation: Option<ApiChannelAttestation>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiTokens {
+    pub issuer: String,
+    pub lifetime_seconds: Option<u64>,
+}
+
+impl ApiTokens {
+    pub const DEFAULT_LIFETIME_SECONDS: u64 = 300;
+    pub const MAX_LIFETIME_SECONDS: u64 = 3600;
+
+    /// How long tokens are valid for
+    pub fn lifetime_seconds(&self) -> u64 {
+        self.lifetime_seconds
+            .unwrap_or(Self::DEFAULT_LIFETIME_SECONDS)
+    }
+}
+
+/// Attestations bound to a channel of the application, e.g. a TLS connection, which
+/// odyn keeps fresh so that the peer can check the enclave again mid-session
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiChannelAttestation {
+    pub refresh_seconds: Option<u64>,
+}
+
+impl ApiChannelAttestation {
+    pub const DEFAULT_REFRESH_SECONDS: u64 = 300;
+    pub const MIN_REFRESH_SECONDS: u64 = 10;
+    pub const MAX_REFRESH_SECONDS: u64 = 3600;
+
+    /// How often the attestation of each channel is produced again
+    pub fn refresh_seconds(&self) -> u64 {
+        self.refresh_seconds
+            .unwrap_or(Self::DEFAULT_REFRESH_SECONDS)
+    }
+}
+
+/// The HTTP listener odyn reports the health of the enclave on, for load balancers.
+/// It is only published on the host if the port is also an ingress port.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Health {
+    pub listen_port: u16,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub measured: Option<bool>,
+    pub signing_key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Secret {
+    pub name: String,
+    pub env_var: Option<String>,
+    pub vault: Option<VaultSecret>,
+    pub file: Option<FileSecret>,
+    pub env: Option<EnvSecret>,
+}
+
+impl Secret {
+    /// The name of the host provided file backing this secret, if any
+    pub fn file_name(&self) -> Option<&str> {
+        self.file
+            .as_ref()
+            .map(|f| f.name.as_deref().unwrap_or(&self.name))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VaultSecret {
+    pub address: String,
+    pub path: String,
+    pub field: String,
+    pub auth: VaultAuth,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VaultAuth {
+    pub approle: Option<VaultAppRole>,
+    pub jwt: Option<VaultJwt>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VaultAppRole {
+    pub mount: Option<String>,
+    pub role_id: String,
+    pub secret_id_from: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VaultJwt {
+    pub mount: Option<String>,
+    pub role: String,
+    pub jwt_from: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSecret {
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvSecret {
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Spiffe {
+    pub server: String,
+}
+
+fn parse_manifest(buf: &[u8]) -> Result<Manifest, ConfigError> {
+    let manifest: Manifest = serde_yaml::from_slice(buf)?;
+
+    if let Some(ref secrets) = manifest.secrets {
+        validate_secrets(secrets)?;
+    }
+
+    if let Some(credentials) = manifest
+        .kms_proxy
+        .as_ref()
+        .and_then(|kms| kms.credentials.as_ref())
+    {
+        validate_kms_credentials(credentials, manifest.secrets.as_deref().unwrap_or_default())?;
+    }
+
+    manifest.check_egress_proxy_ports()?;
+
+    if let Some(item) = manifest
+        .ingress
+        .iter()
+        .flatten()
+        .find(|item| item.target_port == Some(0))
+    {
+        return Err(ConfigError::Ingress(format!(
+            "ingress target_port of port {} must not be 0",
+            item.listen_port
+        )));
+    }
+
+    for tls in manifest
+        .ingress
+        .iter()
+        .flatten()
+        .filter_map(|i| i.tls.as_ref())
+    {
+        if tls.client_ca_file.is_none() && tls.requires_client_auth() {
+            return Err(ConfigError::Ingress(
+                "ingress tls require_client_auth needs a client_ca_file".to_string(),
+            ));
+        }
+        validate_server_certs(tls)?;
+        validate_sni_routes(tls.routes.as_deref().unwrap_or_default())?;
+        validate_alpn(tls.alpn_protocols())?;
+        if tls.is_kms_encrypted() && manifest.kms_proxy.is_none() {
+            return Err(ConfigError::Ingress(
+                "ingress tls kms_encrypted_key needs the kms_proxy".to_string(),
+            ));
+        }
+    }
+
+    if let Some(ref egress) = manifest.egress {
+        validate_egress_patterns(egress)?;
+        for proxy in egress.proxies.iter().flatten() {
+            validate_egress_patterns(&proxy.policy())?;
+        }
+
+        if egress.policy_serial_floor.is_some() && egress.policy_signing_key.is_none() {
+            return Err(ConfigError::EgressProxy(
+                "egress policy_serial_floor needs a policy_signing_key".to_string(),
+            ));
+        }
+
+        if let Some(ref revoked) = egress.revoked_connections {
+            if egress.policy_signing_key.is_none() {
+                return Err(ConfigError::EgressProxy(
+                    "egress revoked_connections needs a policy_signing_key".to_string(),
+                ));
+            }
+            if revoked.drain_seconds.is_some() && revoked.mode != RevokeMode::Drain {
+                return Err(ConfigError::EgressProxy(
+                    "egress revoked_connections drain_seconds needs mode drain".to_string(),
+                ));
+            }
+        }
+
+        if let Some(ref hook) = egress.policy_hook {
+            if !hook.wasm.starts_with('/') {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress policy_hook {} must be an absolute path in the image",
+                    hook.wasm
+                )));
+            }
+            if hook.fuel == Some(0) {
+                return Err(ConfigError::EgressProxy(
+                    "egress policy_hook needs fuel to decide".to_string(),
+                ));
+            }
+        }
+
+        if (egress.is_transparent() || egress.is_dns_enabled()) && !egress.is_enabled() {
+            return Err(ConfigError::EgressProxy(
+                "transparent egress and DNS require default allow or an allow, protocols or databases rule"
+                    .to_string(),
+            ));
+        }
+
+        // Forwarded connections go through the same policy as proxied ones, so a
+        // forward it denies could never connect
+        let policy = EgressPolicy::new(egress);
+        for forward in egress.forward.iter().flatten() {
+            if !policy.is_connect_allowed(&forward.host, forward.port) {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress forward to {}:{} is not allowed by the egress policy",
+                    forward.host, forward.port
+                )));
+            }
+            if let Some(ref tls) = forward.tls {
+                validate_forward_tls(forward, tls, manifest.spiffe.is_some())?;
+            }
+        }
+
+        // Service names are matched against the policy like any other name
+        let mut service_names = Vec::new();
+        for service in egress.services.iter().flatten() {
+            let name = service.name.to_ascii_lowercase();
+            if name == OUTSIDE_HOST {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress service name {OUTSIDE_HOST} already refers to the host itself"
+                )));
+            }
+            if service_names.contains(&name) {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress service name {} is used more than once",
+                    service.name
+                )));
+            }
+            if service.port == 0 {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress service {} needs a port",
+                    service.name
+                )));
+            }
+            if !policy.is_name_allowed(&name) {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress service {} is not allowed by the egress policy",
+                    service.name
+                )));
+            }
+            service_names.push(name);
+        }
+
+        // Ports up to 65535 are those of ingress, and of odyn and the host, which only
+        // leaves the default relay among them
+        let relay_ports = egress.relay_ports();
+        for (i, port) in relay_ports.iter().enumerate() {
+            if *port <= u16::MAX as u32 && *port != HTTP_EGRESS_VSOCK_PORT {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress relay vsock port {port} is reserved"
+                )));
+            }
+            if relay_ports[..i].contains(port) {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress relay vsock port {port} is used more than once"
+                )));
+            }
+        }
+
+        // The host relay checks datagrams against the allow and deny lists, protocol
+        // and database rules are for TCP only
+        let mut udp_ports = Vec::new();
+        for udp in egress.udp.iter().flatten() {
+            if udp.tls.is_some() {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress udp to {}:{} cannot originate TLS",
+                    udp.host, udp.port
+                )));
+            }
+            if !policy.is_host_allowed(&udp.host, udp.port) {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress udp to {}:{} is not allowed by the egress policy",
+                    udp.host, udp.port
+                )));
+            }
+            if udp.local_port == DNS_PORT && (egress.is_transparent() || egress.is_dns_enabled()) {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress udp port {DNS_PORT} is already used to answer DNS queries"
+                )));
+            }
+            if udp_ports.contains(&udp.local_port) {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress udp port {} is used more than once",
+                    udp.local_port
+                )));
+            }
+            udp_ports.push(udp.local_port);
+        }
+
+        for limit in egress.limits.iter().flatten() {
+            validate_egress_limit(limit)?;
+        }
+        for proxy in egress.proxies.iter().flatten() {
+            for limit in proxy.limits.iter().flatten() {
+                validate_egress_limit(limit)?;
+            }
+        }
+
+        let proxy_requests = egress.proxies.iter().flatten().map(|p| &p.requests);
+        for rule in std::iter::once(&egress.requests)
+            .chain(proxy_requests)
+            .flatten()
+            .flatten()
+        {
+            validate_request_rule(rule)?;
+        }
+
+        let proxy_imds = egress.proxies.iter().flatten().map(|p| p.imds.clone());
+        let relay_imds = egress.imds_relay.as_ref().map(ImdsRelay::imds);
+        for imds in std::iter::once(egress.imds.clone())
+            .chain(proxy_imds)
+            .chain(std::iter::once(relay_imds))
+            .flatten()
+        {
+            if let Some(path) = imds.paths().iter().find(|path| !path.starts_with('/')) {
+                return Err(ConfigError::EgressProxy(format!(
+                    "egress imds path {path} must start with /"
+                )));
+            }
+        }
+
+        // Both answer DNS queries on port 53 inside the enclave
+        if egress.is_transparent() && egress.is_dns_enabled() {
+            return Err(ConfigError::EgressProxy(
+                "transparent egress already resolves names, egress.dns cannot be set with it"
+                    .to_string(),
+            ));
+        }
+    }
+
+    if let Some(tokens) = manifest.api.as_ref().and_then(|api| api.tokens.as_ref()) {
+        // OIDC verifiers only fetch keys from https issuers
+        if !tokens.issuer.starts_with("https://") {
+            return Err(ConfigError::Api(format!(
+                "token issuer {} must be an https:// URL",
+                tokens.issuer
+            )));
+        }
+        if !(1..=ApiTokens::MAX_LIFETIME_SECONDS).contains(&tokens.lifetime_seconds()) {
+            return Err(ConfigError::Api(format!(
+                "token lifetime must be between 1 and {} seconds",
+                ApiTokens::MAX_LIFETIME_SECONDS
+            )));
+        }
+    }
+
+    if let Some(channel) = manifest
+        .api
+        .as_ref()
+        .and_then(|api| api.channel_attestation.as_ref())
+    {
+        let refresh =
+            ApiChannelAttestation::MIN_REFRESH_SECONDS..=ApiChannelAttestation::MAX_REFRESH_SECONDS;
+        if !refresh.contains(&channel.refresh_seconds()) {
+            return Err(ConfigError::Api(format!(
+                "channel attestation refresh must be between {} and {} seconds",
+                refresh.start(),
+                refresh.end()
+            )));
+        }
+    }
+
+    for arg in manifest.build.iter().flat_map(Build::kernel_args) {
+        validate_kernel_arg(arg)?;
+    }
+
+    if let Some(ref host) = manifest.host {
+        validate_host(host)?;
+    }
+
+    // 0 would report an enclave that never finished as a success
+    if let Some(exit_codes) = manifest
+        .defaults
+        .as_ref()
+        .and_then(|d| d.exit_codes.as_ref())
+    {
+        for (name, code) in [
+            ("signaled", exit_codes.signaled),
+            ("fatal", exit_codes.fatal),
+            ("interrupted", exit_codes.interrupted),
+        ] {
+            if code == Some(0) {
+                return Err(ConfigError::Defaults(format!(
+                    "defaults.exit_codes.{name} must not be 0"
+                )));
+            }
+        }
+    }
+
+    // No stream would fit in a budget of 0
+    if let Some(0) = manifest.defaults.as_ref().and_then(|d| d.proxy_buffer_mb) {
+        return Err(ConfigError::Defaults(
+            "defaults.proxy_buffer_mb must not be 0".to_string(),
+        ));
+    }
+
+    // The buffers of the proxies are part of the memory of odyn
+    if let Some(Defaults {
+        supervisor_memory_mb: Some(supervisor),
+        proxy_buffer_mb: Some(buffers),
+        ..
+    }) = manifest.defaults
+    {
+        if supervisor < buffers {
+            return Err(ConfigError::Defaults(format!(
+                "defaults.supervisor_memory_mb ({supervisor}) must be at least \
+                 defaults.proxy_buffer_mb ({buffers})"
+            )));
+        }
+    }
+
+    // The trust bundle is only as trustworthy as the connection it arrives on
+    if let Some(ref spiffe) = manifest.spiffe {
+        if !spiffe.server.starts_with("https://") {
+            return Err(ConfigError::Spiffe(format!(
+                "SPIFFE server {} must be an https:// URL",
+                spiffe.server
+            )));
+        }
+    }
+
+    for db in manifest.egress.iter().flat_map(|egress| {
+        egress
+            .databases
+            .iter()
+            .chain(
+                egress
+                    .proxies
+                    .iter()
+                    .flatten()
+                    .filter_map(|p| p.databases.as_ref()),
+            )
+            .flatten()
+    }) {
+        match db.engine() {
+            Some(engine) if engine.is_database() => {}
+            Some(engine) => {
+                return Err(ConfigError::Database(format!(
+                    "database {}:{} has engine {engine}, which is not a database",
+                    db.host, db.port
+                )))
+            }
+            None => {
+                return Err(ConfigError::Database(format!(
+                    "unable to infer the engine of database {}:{}, please specify one",
+                    db.host, db.port
+                )))
+            }
+        }
+    }
+
+    Ok(manifest)
+}
+
+// Patterns with a port suffix that does not parse would otherwise be dropped
+pub(crate) fn validate_egress_patterns(egress: &Egress) -> Result<(), ConfigError> {
+    let patterns = egress
+        .allow
+        .iter()
+        .chain(egress.deny.iter())
+        .chain(egress.http2_prior_knowledge.iter())
+        .flatten()
+        .chain(
+            egress
+                .protocols
+                .iter()
+                .flatten()
+                .flat_map(|p| p.allow.iter()),
+        )
+        .chain(egress.limits.iter().flatten().flat_map(|l| l.hosts.iter()))
+        .chain(
+            egress
+                .requests
+                .iter()
+                .flatten()
+                .flat_map(|r| r.hosts.iter()),
+        );
+
+    for pattern in patterns {
+        if let Err(err) = crate::policy::ports::split(pattern) {
+            return Err(ConfigError::EgressProxy(format!(
+                "invalid egress pattern {pattern}: {err}"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// A port whose key odyn generates presents its certificate on every route, the
+// others need a certificate of their own
+fn validate_server_certs(tls: &ServerTls) -> Result<(), ConfigError> {
+    let has_certs = tls.key_file.is_some() || tls.cert_file.is_some();
+    if tls.is_attested() && tls.csr.is_some() {
+        return Err(ConfigError::Ingress(
+            "ingress tls is either attested or has a csr".to_string(),
+        ));
+    }
+    if let Some(ref csr) = tls.csr {
+        if csr.dns_names.is_empty() || csr.dns_names.iter().any(|name| name.is_empty()) {
+            return Err(ConfigError::Ingress(
+                "ingress tls csr needs dns_names".to_string(),
+            ));
+        }
+    }
+
+    if tls.generates_key() {
+        let routes_have_certs = tls
+            .routes
+            .iter()
+            .flatten()
+            .any(|route| route.key_file.is_some() || route.cert_file.is_some());
+        if has_certs || routes_have_certs {
+            return Err(ConfigError::Ingress(
+                "attested or csr ingress tls takes no key_file or cert_file".to_string(),
+            ));
+        }
+    } else if tls.key_file.is_none() || tls.cert_file.is_none() {
+        return Err(ConfigError::Ingress(
+            "ingress tls needs key_file and cert_file, attested or a csr".to_string(),
+        ));
+    }
+
+    // The decrypted key is presented on every route, like a generated one
+    if tls.is_kms_encrypted() {
+        let routes_have_certs = tls
+            .routes
+            .iter()
+            .flatten()
+            .any(|route| route.key_file.is_some() || route.cert_file.is_some());
+        if tls.generates_key() || routes_have_certs {
+            return Err(ConfigError::Ingress(
+                "ingress tls kms_encrypted_key only applies to the key_file of the port"
+                    .to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// ALPN protocol names are 1 to 255 bytes on the wire
+fn validate_alpn(protocols: &[String]) -> Result<(), ConfigError> {
+    for (i, protocol) in protocols.iter().enumerate() {
+        if protocol.is_empty() || protocol.len() > 255 {
+            return Err(ConfigError::Ingress(format!(
+                "ingress tls alpn protocol {protocol:?} must be 1 to 255 bytes"
+            )));
+        }
+        if protocols[..i].contains(protocol) {
+            return Err(ConfigError::Ingress(format!(
+                "ingress tls alpn protocol {protocol} is listed more than once"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_sni_routes(routes: &[SniRoute]) -> Result<(), ConfigError> {
+    let mut names = HashSet::new();
+    for route in routes {
+        let name = route.server_name.to_ascii_lowercase();
+        if name.is_empty() || !names.insert(name) {
+            return Err(ConfigError::Ingress(format!(
+                "ingress tls route server_name \"{}\" must be set and unique",
+                route.server_name
+            )));
+        }
+        if route.port == 0 {
+            return Err(ConfigError::Ingress(format!(
+                "ingress tls route for {} needs a port",
+                route.server_name
+            )));
+        }
+        match (&route.key_file, &route.cert_file) {
+            (None, None) => {}
+            (Some(key), Some(cert)) if key.starts_with('/') && cert.starts_with('/') => {}
+            _ => {
+                return Err(ConfigError::Ingress(format!(
+                    "ingress tls route for {} needs both key_file and cert_file, as absolute paths, or neither",
+                    route.server_name
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_request_rule(rule: &RequestRule) -> Result<(), ConfigError> {
+    if rule.hosts.is_empty() {
+        return Err(ConfigError::EgressProxy(
+            "egress request rule needs at least one host".to_string(),
+        ));
+    }
+    // Methods are matched as sent, which is in upper case by convention
+    for method in rule.methods.iter().flatten() {
+        if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+            return Err(ConfigError::EgressProxy(format!(
+                "egress request method {method} must be in upper case, e.g. GET"
+            )));
+        }
+    }
+    for prefix in rule.path_prefixes.iter().flatten() {
+        if !prefix.starts_with('/') {
+            return Err(ConfigError::EgressProxy(format!(
+                "egress request path prefix {prefix} must start with /"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_egress_limit(limit: &EgressLimit) -> Result<(), ConfigError> {
+    if limit.hosts.is_empty() {
+        return Err(ConfigError::EgressProxy(
+            "egress limit needs at least one host".to_string(),
+        ));
+    }
+    if limit.rate_limit.is_none() && limit.bandwidth.is_none() {
+        return Err(ConfigError::EgressProxy(format!(
+            "egress limit for {} needs a rate_limit or a bandwidth",
+            limit.hosts.join(", ")
+        )));
+    }
+    // A limit of 0 would block the hosts, which deny rules are for
+    if limit.rate_limit == Some(0) || limit.bandwidth == Some(0) {
+        return Err(ConfigError::EgressProxy(format!(
+            "egress limit for {} must not be 0, deny the hosts instead",
+            limit.hosts.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+// One name or name=value each, so an argument cannot smuggle in another
+fn validate_kernel_arg(arg: &str) -> Result<(), ConfigError> {
+    let (name, value) = match arg.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (arg, None),
+    };
+
+    if !Build::KERNEL_ARGS_ALLOWED.contains(&name) {
+        return Err(ConfigError::Build(format!(
+            "kernel argument {arg:?} is not allowed, only {}",
+            Build::KERNEL_ARGS_ALLOWED.join(", ")
+        )));
+    }
+    let valid = |c: char| c.is_ascii_alphanumeric() || "_-.,:".contains(c);
+    if value.is_some_and(|value| value.is_empty() || !value.chars().all(valid)) {
+        return Err(ConfigError::Build(format!(
+            "kernel argument {arg:?} has an invalid value"
+        )));
+    }
+
+    Ok(())
+}
+
+fn validate_host(host: &Host) -> Result<(), ConfigError> {
+    let mut seen = HashSet::new();
+    for (on_host, in_container) in host.devices() {
+        if !on_host.starts_with("/dev/") || !in_container.starts_with("/dev/") {
+            return Err(ConfigError::Host(format!(
+                "host device {on_host} must be under /dev, on the host and in the container"
+            )));
+        }
+        // The Nitro Enclaves device is always there, and nothing may take its place
+        if in_container == NITRO_ENCLAVES_DEVICE || !seen.insert(in_container) {
+            return Err(ConfigError::Host(format!(
+                "host device {in_container} is given more than once"
+            )));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    for tmpfs in host.tmpfs.iter().flatten() {
+        if !tmpfs.path.starts_with('/') || tmpfs.path == "/" || !seen.insert(&tmpfs.path) {
+            return Err(ConfigError::Host(format!(
+                "host tmpfs {} must be an absolute path other than /, mounted once",
+                tmpfs.path
+            )));
+        }
+        if tmpfs.size_mb == Some(0) {
+            return Err(ConfigError::Host(format!(
+                "host tmpfs {} must not be of size 0",
+                tmpfs.path
+            )));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    for ulimit in host.ulimits.iter().flatten() {
+        if !Host::ULIMITS_ALLOWED.contains(&ulimit.name.as_str()) || !seen.insert(&ulimit.name) {
+            return Err(ConfigError::Host(format!(
+                "host ulimit {} is unknown or set more than once, only {} may be set",
+                ulimit.name,
+                Host::ULIMITS_ALLOWED.join(", ")
+            )));
+        }
+        let valid = |value: i64| value >= -1;
+        let within = ulimit.hard == -1 || (ulimit.soft != -1 && ulimit.soft <= ulimit.hard);
+        if !valid(ulimit.soft) || !valid(ulimit.hard) || !within {
+            return Err(ConfigError::Host(format!(
+                "host ulimit {} needs a soft limit of at most the hard one, -1 for unlimited",
+                ulimit.name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// The client certificate comes from exactly one place
+fn validate_forward_tls(
+    forward: &EgressForward,
+    tls: &ForwardTls,
+    has_spiffe: bool,
+) -> Result<(), ConfigError> {
+    let target = format!("{}:{}", forward.host, forward.port);
+    match (tls.uses_svid(), &tls.cert_file, &tls.key_file) {
+        (true, None, None) if has_spiffe => Ok(()),
+        (true, None, None) => Err(ConfigError::EgressProxy(format!(
+            "egress forward to {target} presents the SVID, which requires spiffe"
+        ))),
+        (true, _, _) => Err(ConfigError::EgressProxy(format!(
+            "egress forward to {target} presents either the SVID or cert_file and key_file, not both"
+        ))),
+        (false, Some(_), Some(_)) => Ok(()),
+        (false, _, _) => Err(ConfigError::EgressProxy(format!(
+            "egress forward to {target} needs cert_file and key_file, or svid"
+        ))),
+    }
+}
+
+fn validate_secrets(secrets: &[Secret]) -> Result<(), ConfigError> {
+    let mut seen = Vec::new();
+
+    for secret in secrets {
+        // Secrets are written to files named after them
+        if secret.name.is_empty() || secret.name.contains('/') || secret.name.starts_with('.') {
+            return Err(ConfigError::Secret(format!(
+                "invalid secret name {:?}",
+                secret.name
+            )));
+        }
+
+        let backends = [
+            secret.vault.is_some(),
+            secret.file.is_some(),
+            secret.env.is_some(),
+        ];
+        if backends.iter().filter(|b| **b).count() != 1 {
+            return Err(ConfigError::Secret(format!(
+                "secret {} must specify exactly one of vault, file or env",
+                secret.name
+            )));
+        }
+
+        // Vault credentials come from secrets declared earlier in the list
+        if let Some(ref vault) = secret.vault {
+            let from = match (&vault.auth.approle, &vault.auth.jwt) {
+                (Some(approle), None) => &approle.secret_id_from,
+                (None, Some(jwt)) => &jwt.jwt_from,
+                _ => {
+                    return Err(ConfigError::Secret(format!(
+                        "secret {} must specify exactly one of approle or jwt auth",
+                        secret.name
+                    )))
+                }
+            };
+
+            if !seen.contains(&from) {
+                return Err(ConfigError::Secret(format!(
+                    "secret {} authenticates with {from}, which must be declared before it",
+                    secret.name
+                )));
+            }
+        }
+
+        if seen.contains(&&secret.name) {
+            return Err(ConfigError::Secret(format!(
+                "secret {} is declared more than once",
+                secret.name
+            )));
+        }
+        seen.push(&secret.name);
+    }
+
+    Ok(())
+}
+
+fn validate_kms_credentials(
+    credentials: &KmsCredentials,
+    secrets: &[Secret],
+) -> Result<(), ConfigError> {
+    let web_identity = [&credentials.role_arn, &credentials.token_from];
+    if credentials.source != KmsCredentialSource::WebIdentity {
+        if web_identity.iter().any(|field| field.is_some()) || credentials.session_name.is_some() {
+            return Err(ConfigError::KmsProxy(
+                "kms_proxy credentials role_arn, token_from and session_name are only for \
+                 web_identity"
+                    .to_string(),
+            ));
+        }
+        return Ok(());
+    }
+
+    let (role_arn, token_from) = match web_identity {
+        [Some(role_arn), Some(token_from)] => (role_arn, token_from),
+        _ => {
+            return Err(ConfigError::KmsProxy(
+                "kms_proxy web_identity credentials need role_arn and token_from".to_string(),
+            ))
+        }
+    };
+
+    if !role_arn.starts_with("arn:") {
+        return Err(ConfigError::KmsProxy(format!(
+            "kms_proxy credentials role_arn {role_arn:?} is not an ARN"
+        )));
+    }
+
+    // Secrets are all fetched before the KMS proxy starts
+    if !secrets.iter().any(|secret| secret.name == *token_from) {
+        return Err(ConfigError::KmsProxy(format!(
+            "kms_proxy credentials take their token from {token_from}, which is not a secret"
+        )));
+    }
+
+    Ok(())
+}
+
+pub async fn load_manifest_raw<P: AsRef<Path>>(
+    path: P,
+) -> Result<(Vec<u8>, Manifest), ManifestError> {
+    let path = path.as_ref();
+    let mut file: Pin<Box<dyn AsyncRead>> = if path == Path::new("-") {
+        Box::pin(tokio::io::stdin())
+    } else {
+        match File::open(path).await {
+            Ok(file) => Box::pin(file),
+            Err(source) => {
+                return Err(ManifestError::Open {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        }
+    };
+
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)
+        .await
+        .map_err(|source| ManifestError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+    let manifest = parse_manifest(&buf).map_err(|source| ManifestError::Config {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    Ok((buf, manifest))
+}
+
+pub async fn load_manifest<P: AsRef<Path>>(path: P) -> Result<Manifest, ManifestError> {
+    let (_, manifest) = load_manifest_raw(path).await?;
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::{
+        load_manifest, parse_manifest, ConfigError, EgressDefault, EgressForward, EgressLimit,
+        ExitCodes, KmsCredentialSource, ManifestError, Protocol, RevokeMode, IMDS_DEFAULT_PATHS,
+    };
+
+    // The fields every manifest needs, for the tests to add what they check to
+    const HEADER: &str = r#"
+version: v1
+name: "test"
+target: "target-image:latest"
+sources:
+  app: "app-image:latest"
+"#;
+
+    #[test]
+    fn test_parse_manifest_with_unknown_fields() {
+        assert!(matches!(
+            parse_manifest(br#"foo: "bar""#),
+            Err(ConfigError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_minimal_manifest() {
+        let raw_manifest = br#"
+version: v1
+name: "test"
+target: "target-image:latest"
+sources:
+  app: "app-image:latest"
+#r"#;
+
+        let manifest = parse_manifest(raw_manifest).unwrap();
 
         assert_eq!(manifest.version, "v1");
         assert_eq!(manifest.name, "test");
         assert_eq!(manifest.target, "target-image:latest");
         assert_eq!(manifest.sources.app, "app-image:latest");
+        assert!(manifest.console_enabled());
+        assert!(!manifest.is_debug());
+    }
+
+    #[test]
+    fn test_parse_secrets() {
+        let raw_manifest = br#"
+version: v1
+name: "test"
+target: "target-image:latest"
+sources:
+  app: "app-image:latest"
+secrets:
+  - name: vault-secret-id
+    file: {}
+  - name: db-password
+    env_var: DB_PASSWORD
+    vault:
+      address: https://vault.example.com:8200
+      path: secret/data/db
+      field: password
+      auth:
+        approle:
+          role_id: enclave
+          secret_id_from: vault-secret-id
+  - name: debug-flag
+    env:
+      value: "1"
+"#;
+
+        let manifest = parse_manifest(raw_manifest).unwrap();
+        let secrets = manifest.secrets.unwrap();
+        assert_eq!(secrets.len(), 3);
+        assert_eq!(secrets[0].file_name(), Some("vault-secret-id"));
+        assert_eq!(secrets[1].env_var.as_deref(), Some("DB_PASSWORD"));
+        assert_eq!(secrets[2].file_name(), None);
+    }
+
+    #[test]
+    fn test_parse_invalid_secrets() {
+        let no_backend = "secrets:\n  - name: a\n";
+        let two_backends = "secrets:\n  - name: a\n    file: {}\n    env: { value: x }\n";
+        let duplicate = "secrets:\n  - name: a\n    file: {}\n  - name: a\n    file: {}\n";
+        let forward_ref = r#"secrets:
+  - name: a
+    vault:
+      address: https://vault.example.com
+      path: secret/data/a
+      field: a
+      auth:
+        jwt: { role: enclave, jwt_from: b }
+  - name: b
+    file: {}
+"#;
+
+        let bad_name = "secrets:\n  - name: ../a\n    file: {}\n";
+
+        for secrets in [no_backend, two_backends, duplicate, forward_ref, bad_name] {
+            let raw = format!("{HEADER}{secrets}");
+            assert!(parse_manifest(raw.as_bytes()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_databases() {
+        let header = HEADER.to_owned()
+            + r#"egress:
+  databases:
+"#;
+
+        let raw = format!(
+            "{header}    - {{ host: db.internal, port: 5432, require_tls: true }}\n    - {{ host: 10.0.0.5, port: 6033, engine: mysql }}\n"
+        );
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        let egress = manifest.egress.unwrap();
+        assert!(egress.is_enabled());
+
+        let databases = egress.databases.unwrap();
+        assert_eq!(databases[0].engine(), Some(Protocol::Postgres));
+        assert_eq!(databases[1].engine(), Some(Protocol::Mysql));
+
+        let unknown = format!("{header}    - {{ host: db.internal, port: 1433 }}\n");
+        assert!(parse_manifest(unknown.as_bytes()).is_err());
+
+        let not_db = format!("{header}    - {{ host: db.internal, port: 25, engine: smtp }}\n");
+        assert!(parse_manifest(not_db.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_egress_proxy_port_collision() {
+        let header = HEADER.to_owned()
+            + r#"ingress:
+  - listen_port: 8080
+api:
+  listen_port: 9000
+egress:
+  allow: ["example.com"]
+"#;
+
+        for port in [8080, 9000] {
+            let raw = format!("{header}  proxy_port: {port}\n");
+            assert!(matches!(
+                parse_manifest(raw.as_bytes()),
+                Err(ConfigError::EgressProxy(_))
+            ));
+        }
+
+        let raw = format!("{header}  proxy_port: 10001\n");
+        assert!(parse_manifest(raw.as_bytes()).is_ok());
+
+        let named = "  proxies:\n    - { name: metrics, proxy_port: 10002, allow: [\"**\"] }\n";
+        let raw = format!("{header}{named}");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        let proxy = manifest.egress_proxies().next().unwrap();
+        assert!(proxy.policy().allow == Some(vec!["**".to_string()]));
+
+        let raw = format!("{header}  proxy_port: 10002\n{named}");
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::EgressProxy(_))
+        ));
+
+        let raw = format!(
+            "{header}{named}{}",
+            named.replace("  proxies:\n", "").replace("10002", "10003")
+        );
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::EgressProxy(_))
+        ));
+    }
+
+    #[test]
+    fn test_transparent_egress() {
+        let raw = format!("{HEADER}egress:\n  transparent: true\n  allow: [\"**\"]\n");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        assert!(manifest.egress.unwrap().is_transparent());
+
+        let raw = format!("{HEADER}egress:\n  transparent: true\n");
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::EgressProxy(_))
+        ));
+
+        let raw = format!("{HEADER}egress:\n  transparent: true\n  dns: true\n  allow: [\"**\"]\n");
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::EgressProxy(_))
+        ));
+
+        let raw = format!(
+            "{HEADER}ingress:\n  - listen_port: 10001\negress:\n  transparent: true\n  allow: [\"**\"]\n"
+        );
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::EgressProxy(_))
+        ));
+    }
+
+    #[test]
+    fn test_egress_default() {
+        let raw = format!("{HEADER}egress:\n  default: allow\n  deny: [\"**.internal\"]\n");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        let egress = manifest.egress.unwrap();
+        assert_eq!(egress.default_action(), EgressDefault::Allow);
+        assert!(egress.is_enabled());
+
+        // Enough for transparent egress without any allow rule
+        let raw = format!("{HEADER}egress:\n  default: allow\n  transparent: true\n");
+        assert!(parse_manifest(raw.as_bytes()).is_ok());
+
+        let raw = format!("{HEADER}egress:\n  default: deny\n");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        assert!(!manifest.egress.unwrap().is_enabled());
+
+        let raw = format!("{HEADER}egress:\n  default: block\n");
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn test_egress_limits() {
+        let header = HEADER.to_owned()
+            + r#"egress:
+  allow: ["**.example.com"]
+  limits:
+"#;
+
+        let raw = format!(
+            "{header}    - hosts: [\"upload.example.com\"]\n      rate_limit: 10\n      bandwidth: 1048576\n"
+        );
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        assert_eq!(
+            manifest.egress.unwrap().limits.unwrap(),
+            vec![EgressLimit {
+                hosts: vec!["upload.example.com".to_string()],
+                rate_limit: Some(10),
+                bandwidth: Some(1048576),
+            }]
+        );
+
+        for limit in [
+            "    - hosts: [\"upload.example.com\"]\n",
+            "    - hosts: []\n      rate_limit: 10\n",
+            "    - hosts: [\"upload.example.com\"]\n      bandwidth: 0\n",
+            "    - hosts: [\"upload.example.com:https\"]\n      rate_limit: 10\n",
+        ] {
+            let raw = format!("{header}{limit}");
+            assert!(matches!(
+                parse_manifest(raw.as_bytes()),
+                Err(ConfigError::EgressProxy(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_egress_forward() {
+        let forward =
+            "  forward:\n    - local_port: 9092\n      host: kafka.internal\n      port: 9092\n";
+        let raw = format!("{HEADER}egress:\n  allow: [\"*.internal\"]\n{forward}");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        let forwards: Vec<&EgressForward> = manifest.egress_forwards().collect();
+        assert_eq!(forwards.len(), 1);
+        assert_eq!(forwards[0].host, "kafka.internal");
+        assert!(manifest
+            .listen_ports()
+            .contains(&(9092, "egress forward to kafka.internal:9092".to_string())));
+
+        // Denied by the policy
+        let raw = format!("{HEADER}egress:\n  allow: [\"*.example.com\"]\n{forward}");
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::EgressProxy(_))
+        ));
+
+        let raw = format!(
+            "{HEADER}ingress:\n  - listen_port: 9092\negress:\n  allow: [\"*.internal\"]\n{forward}"
+        );
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::EgressProxy(_))
+        ));
+    }
+
+    #[test]
+    fn test_egress_forward_tls() {
+        let forward = |tls: &str| {
+            format!(
+                "{HEADER}egress:\n  allow: [\"api.partner.com\"]\n  forward:\n    - local_port: 8443\n      host: api.partner.com\n      port: 443\n      tls:\n{tls}"
+            )
+        };
+
+        let tls_of = |raw: String| {
+            parse_manifest(raw.as_bytes()).map(|manifest| {
+                manifest
+                    .egress_forwards()
+                    .next()
+                    .unwrap()
+                    .tls
+                    .clone()
+                    .unwrap()
+            })
+        };
+        let spiffe = "spiffe:\n  server: https://spiffe.internal/svid\n";
+
+        let tls = tls_of(forward(
+            "        cert_file: /run/client.crt\n        key_file: /run/client.key\n",
+        ))
+        .unwrap();
+        assert_eq!(tls.cert_file.as_deref(), Some("/run/client.crt"));
+        assert!(!tls.uses_svid());
+
+        // The SVID needs spiffe, and cannot be combined with files
+        let svid = forward("        svid: true\n");
+        assert!(matches!(
+            tls_of(svid.clone()),
+            Err(ConfigError::EgressProxy(_))
+        ));
+        assert!(tls_of(format!("{svid}{spiffe}")).unwrap().uses_svid());
+
+        let both = forward("        svid: true\n        cert_file: /run/client.crt\n");
+        assert!(matches!(
+            tls_of(format!("{both}{spiffe}")),
+            Err(ConfigError::EgressProxy(_))
+        ));
+
+        assert!(matches!(
+            tls_of(forward("        cert_file: /run/client.crt\n")),
+            Err(ConfigError::EgressProxy(_))
+        ));
+
+        let udp = format!(
+            "{HEADER}egress:\n  allow: [\"ntp.internal\"]\n  udp:\n    - local_port: 123\n      host: ntp.internal\n      port: 123\n      tls:\n        svid: true\n"
+        );
+        assert!(matches!(
+            parse_manifest(udp.as_bytes()),
+            Err(ConfigError::EgressProxy(_))
+        ));
+    }
+
+    #[test]
+    fn test_egress_services() {
+        let services = "  services:\n    - name: Redis.host.internal\n      port: 6379\n";
+        let raw = format!("{HEADER}egress:\n  allow: [\"**.host.internal\"]\n{services}");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        let egress = manifest.egress.as_ref().unwrap();
+        assert_eq!(
+            egress.host_names(),
+            vec!["host".to_string(), "redis.host.internal".to_string()]
+        );
+        assert_eq!(egress.host_address(), std::net::Ipv4Addr::LOCALHOST);
+        assert_eq!(manifest.egress_services().count(), 1);
+
+        let raw = format!(
+            "{HEADER}egress:\n  allow: [\"**.host.internal\"]\n  host_address: 10.0.0.1\n{services}"
+        );
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        assert_eq!(
+            manifest.egress.unwrap().host_address(),
+            std::net::Ipv4Addr::new(10, 0, 0, 1)
+        );
+
+        for raw in [
+            // Denied by the policy
+            format!("{HEADER}egress:\n  allow: [\"*.example.com\"]\n{services}"),
+            // Used twice
+            format!(
+                "{HEADER}egress:\n  allow: [\"**\"]\n{services}    - name: redis.host.internal\n      port: 6380\n"
+            ),
+            format!("{HEADER}egress:\n  allow: [\"**\"]\n  services:\n    - name: host\n      port: 80\n"),
+        ] {
+            assert!(matches!(
+                parse_manifest(raw.as_bytes()),
+                Err(ConfigError::EgressProxy(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_egress_relays() {
+        let raw = format!("{HEADER}egress:\n  allow: [\"**\"]\n");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        assert_eq!(manifest.egress.unwrap().relay_ports(), vec![17002]);
+
+        let relays = "  relays:\n    - vsock_port: 17002\n    - vsock_port: 70002\n";
+        let raw = format!("{HEADER}egress:\n  allow: [\"**\"]\n{relays}");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        assert_eq!(manifest.egress.unwrap().relay_ports(), vec![17002, 70002]);
+
+        for relays in [
+            // Used twice
+            "  relays:\n    - vsock_port: 70002\n    - vsock_port: 70002\n",
+            // Taken by ingress, and by the status of the enclave
+            "  relays:\n    - vsock_port: 8080\n",
+            "  relays:\n    - vsock_port: 17000\n",
+        ] {
+            let raw = format!("{HEADER}egress:\n  allow: [\"**\"]\n{relays}");
+            assert!(matches!(
+                parse_manifest(raw.as_bytes()),
+                Err(ConfigError::EgressProxy(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_egress_udp() {
+        let udp = "  udp:\n    - local_port: 123\n      host: time.aws.com\n      port: 123\n";
+        let raw = format!("{HEADER}egress:\n  allow: [\"time.aws.com\"]\n{udp}");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        let rules: Vec<&EgressForward> = manifest.egress_udp().collect();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].port, 123);
+
+        // Denied by the policy
+        let raw = format!("{HEADER}egress:\n  allow: [\"*.example.com\"]\n{udp}");
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::EgressProxy(_))
+        ));
+
+        // Port 53 is taken by the DNS forwarder
+        let udp = "  udp:\n    - local_port: 53\n      host: 10.0.0.2\n      port: 53\n";
+        let raw = format!("{HEADER}egress:\n  dns: true\n  allow: [\"10.0.0.2\"]\n{udp}");
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::EgressProxy(_))
+        ));
+    }
+
+    #[test]
+    fn test_egress_ports() {
+        let allow = r#"["api.example.com:443", "[::1]:8000-8100"]"#;
+        let raw = format!("{HEADER}egress:\n  allow: {allow}\n");
+        parse_manifest(raw.as_bytes()).unwrap();
+
+        for pattern in ["api.example.com:https", "api.example.com:0", "10.0.0.1:9-1"] {
+            let raw = format!("{HEADER}egress:\n  deny: [\"{pattern}\"]\n");
+            assert!(matches!(
+                parse_manifest(raw.as_bytes()),
+                Err(ConfigError::EgressProxy(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_egress_pin_dns() {
+        let allow = r#"["api.example.com:443", "*.example.org", "10.0.0.0/8", "host", "KMS.us-east-1.amazonaws.com"]"#;
+        let databases = "  databases:\n    - host: db.internal\n      port: 5432\n";
+        let proxies = "  proxies:\n    - name: metrics\n      proxy_port: 10001\n      allow: [\"api.example.com\", \"metrics.example.net\"]\n";
+        let raw =
+            format!("{HEADER}egress:\n  pin_dns: true\n  allow: {allow}\n{databases}{proxies}");
+        let egress = parse_manifest(raw.as_bytes()).unwrap().egress.unwrap();
+        assert!(egress.pins_dns());
+        assert_eq!(
+            egress.pinned_names(),
+            vec![
+                "api.example.com".to_string(),
+                "kms.us-east-1.amazonaws.com".to_string(),
+                "db.internal".to_string(),
+                "metrics.example.net".to_string(),
+            ]
+        );
+
+        let raw = format!("{HEADER}egress:\n  allow: {allow}\n");
+        assert!(!parse_manifest(raw.as_bytes())
+            .unwrap()
+            .egress
+            .unwrap()
+            .pins_dns());
+    }
+
+    #[test]
+    fn test_ingress_client_auth() {
+        let header = HEADER.to_owned()
+            + r#"ingress:
+  - listen_port: 8201
+    tls:
+      key_file: /tls/key.pem
+      cert_file: /tls/cert.pem
+"#;
+        let tls_of = |extra: &str| {
+            parse_manifest(format!("{header}{extra}").as_bytes())
+                .map(|manifest| manifest.ingress.unwrap().remove(0).tls.unwrap())
+        };
+
+        let tls = tls_of("").unwrap();
+        assert!(!tls.requires_client_auth());
+        assert!(!tls.sends_proxy_protocol());
+
+        let tls =
+            tls_of("      client_ca_file: /tls/ca.pem\n      proxy_protocol: true\n").unwrap();
+        assert!(tls.requires_client_auth());
+        assert!(tls.sends_proxy_protocol());
+
+        let optional = "      client_ca_file: /tls/ca.pem\n      require_client_auth: false\n";
+        assert!(!tls_of(optional).unwrap().requires_client_auth());
+
+        assert!(tls_of("      require_client_auth: true\n").is_err());
+    }
+
+    #[test]
+    fn test_ingress_attested() {
+        let header = HEADER.to_owned()
+            + r#"ingress:
+  - listen_port: 443
+    tls:
+"#;
+        let tls_of = |tls: &str| {
+            parse_manifest(format!("{header}{tls}").as_bytes())
+                .map(|manifest| manifest.ingress.unwrap().remove(0).tls.unwrap())
+        };
+
+        let tls = tls_of("      attested: true\n").unwrap();
+        assert!(tls.is_attested());
+        assert!(tls.key_file.is_none());
+
+        let routes =
+            "      routes:\n        - server_name: api.example.com\n          port: 8080\n";
+        assert!(tls_of(&format!("      attested: true\n{routes}")).is_ok());
+
+        // The certificate is either attested or given, not both or neither
+        let files = "      key_file: /tls/key.pem\n      cert_file: /tls/cert.pem\n";
+        assert!(!tls_of(files).unwrap().is_attested());
+        assert!(tls_of(&format!("{files}      attested: true\n")).is_err());
+        assert!(tls_of("      key_file: /tls/key.pem\n").is_err());
+        assert!(tls_of("      attested: false\n").is_err());
+        let route_cert = "          key_file: /tls/api.key\n          cert_file: /tls/api.crt\n";
+        assert!(tls_of(&format!("      attested: true\n{routes}{route_cert}")).is_err());
+    }
+
+    #[test]
+    fn test_ingress_csr() {
+        let header = HEADER.to_owned()
+            + r#"ingress:
+  - listen_port: 443
+    tls:
+"#;
+        let tls_of = |tls: &str| {
+            parse_manifest(format!("{header}{tls}").as_bytes())
+                .map(|manifest| manifest.ingress.unwrap().remove(0).tls.unwrap())
+        };
+
+        let csr = "      csr:\n        dns_names: [api.example.com, www.example.com]\n";
+        let tls = tls_of(csr).unwrap();
+        assert!(tls.generates_key());
+        assert!(!tls.is_attested());
+        assert_eq!(tls.csr.unwrap().dns_names.len(), 2);
+
+        let files = "      key_file: /tls/key.pem\n      cert_file: /tls/cert.pem\n";
+        assert!(!tls_of(files).unwrap().generates_key());
+        assert!(tls_of(&format!("{files}{csr}")).is_err());
+        assert!(tls_of(&format!("      attested: true\n{csr}")).is_err());
+        assert!(tls_of("      csr:\n        dns_names: []\n").is_err());
+    }
+
+    #[test]
+    fn test_ingress_kms_encrypted_key() {
+        let header = HEADER.to_owned()
+            + r#"ingress:
+  - listen_port: 443
+    tls:
+      key_file: /tls/key.pem.kms
+      cert_file: /tls/cert.pem
+"#;
+        let kms_proxy = "kms_proxy:\n  listen_port: 9999\n";
+        let tls_of = |tls: &str, tail: &str| {
+            parse_manifest(format!("{header}{tls}{tail}").as_bytes())
+                .map(|manifest| manifest.ingress.unwrap().remove(0).tls.unwrap())
+        };
+
+        let kms = "      kms_encrypted_key: true\n";
+        let tls = tls_of(kms, kms_proxy).unwrap();
+        assert!(tls.is_kms_encrypted());
+        assert!(!tls.is_reloadable());
+        assert!(tls_of("", kms_proxy).unwrap().is_reloadable());
+
+        // The key is decrypted through the KMS proxy, and only the one of the port
+        assert!(tls_of(kms, "").is_err());
+        let route = "      routes:\n        - server_name: api.example.com\n          port: 8080\n";
+        let route_cert = "          key_file: /tls/api.key\n          cert_file: /tls/api.crt\n";
+        assert!(tls_of(&format!("{kms}{route}"), kms_proxy).is_ok());
+        assert!(tls_of(&format!("{kms}{route}{route_cert}"), kms_proxy).is_err());
+    }
+
+    #[test]
+    fn test_kms_credentials() {
+        let header = HEADER.to_owned()
+            + r#"secrets:
+  - name: eks-token
+    file: {}
+kms_proxy:
+  listen_port: 9999
+  credentials:
+"#;
+        let manifest_of = |extra: &str| parse_manifest(format!("{header}{extra}").as_bytes());
+
+        let manifest = manifest_of("    source: container\n    region: eu-west-1\n").unwrap();
+        let credentials = manifest.kms_proxy.unwrap().credentials.unwrap();
+        assert_eq!(credentials.source, KmsCredentialSource::Container);
+        assert_eq!(credentials.region.as_deref(), Some("eu-west-1"));
+
+        let web_identity = "    source: web_identity\n    role_arn: arn:aws:iam::1:role/kms\n";
+        assert!(manifest_of(&format!("{web_identity}    token_from: eks-token\n")).is_ok());
+        assert!(matches!(
+            manifest_of(web_identity),
+            Err(ConfigError::KmsProxy(_))
+        ));
+        assert!(matches!(
+            manifest_of(&format!("{web_identity}    token_from: other\n")),
+            Err(ConfigError::KmsProxy(_))
+        ));
+        assert!(matches!(
+            manifest_of("    source: env\n    role_arn: arn:aws:iam::1:role/kms\n"),
+            Err(ConfigError::KmsProxy(_))
+        ));
+        assert!(manifest_of("    source: sso\n").is_err());
+    }
+
+    #[test]
+    fn test_ingress_alpn() {
+        let header = HEADER.to_owned()
+            + r#"ingress:
+  - listen_port: 443
+    tls:
+      key_file: /tls/key.pem
+      cert_file: /tls/cert.pem
+"#;
+        let tls_of = |alpn: &str| {
+            parse_manifest(format!("{header}{alpn}").as_bytes())
+                .map(|manifest| manifest.ingress.unwrap().remove(0).tls.unwrap())
+        };
+
+        assert!(tls_of("").unwrap().alpn_protocols().is_empty());
+        let tls = tls_of("      alpn: [h2, http/1.1, my-proto]\n").unwrap();
+        assert_eq!(tls.alpn_protocols(), ["h2", "http/1.1", "my-proto"]);
+
+        assert!(tls_of("      alpn: [\"\"]\n").is_err());
+        assert!(tls_of("      alpn: [h2, h2]\n").is_err());
+    }
+
+    #[test]
+    fn test_ingress_sni_routes() {
+        let header = HEADER.to_owned()
+            + r#"ingress:
+  - listen_port: 443
+    tls:
+      key_file: /tls/key.pem
+      cert_file: /tls/cert.pem
+      routes:
+"#;
+        let routes_of = |routes: &str| {
+            parse_manifest(format!("{header}{routes}").as_bytes())
+                .map(|manifest| manifest.ingress.unwrap().remove(0).tls.unwrap().routes)
+        };
+
+        let routes = routes_of(
+            "        - server_name: api.example.com\n          port: 8080\n        - server_name: admin.example.com\n          port: 9090\n          key_file: /tls/admin.key\n          cert_file: /tls/admin.crt\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[1].port, 9090);
+        assert_eq!(routes[1].cert_file.as_deref(), Some("/tls/admin.crt"));
+
+        let api = "        - server_name: api.example.com\n          port: 8080\n";
+        assert!(routes_of(&format!("{api}{}", api.replace("api", "API"))).is_err());
+        assert!(routes_of("        - server_name: api.example.com\n          port: 0\n").is_err());
+        assert!(routes_of(&format!("{api}          key_file: /tls/api.key\n")).is_err());
+    }
+
+    #[test]
+    fn test_ingress_target_port() {
+        let header = HEADER.to_owned()
+            + r#"ingress:
+  - listen_port: 443
+"#;
+        let manifest_of = |extra: &str| parse_manifest(format!("{header}{extra}").as_bytes());
+
+        let manifest = manifest_of("").unwrap();
+        assert_eq!(manifest.ingress.as_ref().unwrap()[0].target_port(), 443);
+
+        let manifest = manifest_of("    target_port: 8080\n").unwrap();
+        assert_eq!(manifest.ingress.as_ref().unwrap()[0].target_port(), 8080);
+        assert_eq!(manifest.listen_ports(), vec![(8080, "ingress".to_string())]);
+
+        assert!(manifest_of("    target_port: 0\n").is_err());
+        let proxy = "    target_port: 8080\negress:\n  allow: [\"**\"]\n  proxy_port: 8080\n";
+        assert!(manifest_of(proxy).is_err());
+    }
+
+    #[test]
+    fn test_ingress_bind_addr() {
+        let header = HEADER.to_owned()
+            + r#"ingress:
+  - listen_port: 8080
+"#;
+        let ingress_of = |extra: &str| {
+            parse_manifest(format!("{header}{extra}").as_bytes())
+                .map(|manifest| manifest.ingress.unwrap().remove(0))
+        };
+
+        assert_eq!(ingress_of("").unwrap().host_addr().to_string(), "0.0.0.0");
+        let local = ingress_of("    bind_addr: 127.0.0.1\n").unwrap();
+        assert_eq!(local.host_addr().to_string(), "127.0.0.1");
+        let v6 = ingress_of("    bind_addr: \"::1\"\n").unwrap();
+        assert_eq!(v6.host_addr().to_string(), "::1");
+        assert!(ingress_of("    bind_addr: localhost\n").is_err());
+    }
+
+    #[test]
+    fn test_ingress_limits() {
+        let raw = r#"
+version: v1
+name: "test"
+target: "target-image:latest"
+sources:
+  app: "app-image:latest"
+ingress:
+  - listen_port: 8080
+    max_connections: 100
+    accepts_per_second: 20
+  - listen_port: 8443
+"#;
+        let ingress = parse_manifest(raw.as_bytes()).unwrap().ingress.unwrap();
+        assert_eq!(ingress[0].max_connections, Some(100));
+        assert_eq!(ingress[0].accepts_per_second, Some(20));
+        assert_eq!(ingress[1].accepts_per_second, None);
+    }
+
+    #[test]
+    fn test_egress_policy_hook() {
+        let raw = format!(
+            "{HEADER}egress:\n  allow: [\"**\"]\n  policy_hook:\n    wasm: /etc/policy.wasm\n"
+        );
+        let hook = parse_manifest(raw.as_bytes())
+            .unwrap()
+            .egress
+            .unwrap()
+            .policy_hook
+            .unwrap();
+        assert!(hook.wasm == "/etc/policy.wasm");
+        assert!(hook.fuel.is_none());
+
+        for hook in ["wasm: policy.wasm", "wasm: /etc/policy.wasm\n    fuel: 0"] {
+            let raw = format!("{HEADER}egress:\n  allow: [\"**\"]\n  policy_hook:\n    {hook}\n");
+            assert!(parse_manifest(raw.as_bytes()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_egress_revoked_connections() {
+        let header = HEADER.to_owned()
+            + r#"egress:
+  allow: ["**"]
+"#;
+        let key = "  policy_signing_key: key.pem\n";
+
+        let raw = format!("{header}{key}  revoked_connections:\n    mode: drain\n");
+        let revoked = parse_manifest(raw.as_bytes())
+            .unwrap()
+            .egress
+            .unwrap()
+            .revoked_connections
+            .unwrap();
+        assert_eq!(revoked.mode, RevokeMode::Drain);
+        assert_eq!(revoked.drain_seconds(), 30);
+
+        let raw = format!("{header}{key}  revoked_connections:\n    drain_seconds: 5\n");
+        assert!(parse_manifest(raw.as_bytes()).is_err());
+        let raw = format!("{header}  revoked_connections:\n    mode: terminate\n");
+        assert!(parse_manifest(raw.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_egress_policy_serial_floor() {
+        let header = HEADER.to_owned()
+            + r#"egress:
+  allow: ["**"]
+"#;
+        let key = "  policy_signing_key: key.pem\n";
+
+        let raw = format!("{header}{key}  policy_serial_floor: 7\n");
+        let egress = parse_manifest(raw.as_bytes()).unwrap().egress.unwrap();
+        assert_eq!(egress.policy_serial_floor, Some(7));
+
+        let raw = format!("{header}  policy_serial_floor: 7\n");
+        assert!(parse_manifest(raw.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_egress_requests() {
+        let header = HEADER.to_owned()
+            + r#"egress:
+  allow: ["**"]
+  requests:
+"#;
+
+        let raw = format!(
+            "{header}    - hosts: [169.254.169.254]\n      methods: [GET]\n      path_prefixes: [/latest/meta-data/]\n"
+        );
+        let rules = parse_manifest(raw.as_bytes())
+            .unwrap()
+            .egress
+            .unwrap()
+            .requests
+            .unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].methods, Some(vec!["GET".to_string()]));
+        assert!(rules[0].max_body_bytes.is_none());
+
+        for rule in [
+            "hosts: []",
+            "hosts: [example.com]\n      methods: [get]",
+            "hosts: [example.com]\n      path_prefixes: [api/]",
+        ] {
+            let raw = format!("{header}    - {rule}\n");
+            assert!(parse_manifest(raw.as_bytes()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_egress_imds() {
+        let header = HEADER.to_owned()
+            + r#"egress:
+  allow: [169.254.169.254]
+"#;
+
+        let imds = parse_manifest(format!("{header}  imds: {{}}\n").as_bytes())
+            .unwrap()
+            .egress
+            .unwrap()
+            .imds
+            .unwrap();
+        assert!(imds.requires_token());
+        assert_eq!(imds.paths(), IMDS_DEFAULT_PATHS);
+
+        let raw = format!("{header}  imds:\n    paths: [latest/user-data]\n");
+        assert!(parse_manifest(raw.as_bytes()).is_err());
+
+        // The relay gets a policy of its own, which the policy of the app leaves alone
+        let raw = format!("{header}  imds_relay:\n    listen_port: 9001\n");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        let ports = manifest.listen_ports();
+        assert!(ports.contains(&(9001, "imds_relay".to_string())));
+        let egress = manifest.egress.unwrap();
+        assert!(egress.imds.is_none());
+        let relay = egress.imds_relay_policy().unwrap();
+        assert_eq!(
+            relay.allow,
+            Some(vec![
+                "169.254.169.254:80".to_string(),
+                "[fd00:ec2::254]:80".to_string()
+            ])
+        );
+        assert!(relay.imds.unwrap().requires_token());
+
+        let raw = format!("{header}  proxies:\n    - {{ name: imds_relay, proxy_port: 10002 }}\n");
+        assert!(parse_manifest(raw.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_parse_api_tokens() {
+        let header = HEADER.to_owned()
+            + r#"api:
+  listen_port: 9000
+"#;
+
+        let raw = format!("{header}  tokens:\n    issuer: https://enclave.example.com\n");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        let tokens = manifest.api.unwrap().tokens.unwrap();
+        assert_eq!(tokens.lifetime_seconds(), 300);
+
+        for tokens in [
+            "  tokens:\n    issuer: http://enclave.example.com\n",
+            "  tokens:\n    issuer: https://enclave.example.com\n    lifetime_seconds: 0\n",
+            "  tokens:\n    issuer: https://enclave.example.com\n    lifetime_seconds: 86400\n",
+        ] {
+            let raw = format!("{header}{tokens}");
+            assert!(matches!(
+                parse_manifest(raw.as_bytes()),
+                Err(ConfigError::Api(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_channel_attestation() {
+        let header = HEADER.to_owned()
+            + r#"api:
+  listen_port: 9000
+"#;
+
+        let raw = format!("{header}  channel_attestation: {{}}\n");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        let channel = manifest.api.unwrap().channel_attestation.unwrap();
+        assert_eq!(channel.refresh_seconds(), 300);
+
+        for refresh in [0, 5, 86400] {
+            let raw = format!("{header}  channel_attestation:\n    refresh_seconds: {refresh}\n");
+            assert!(matches!(
+                parse_manifest(raw.as_bytes()),
+                Err(ConfigError::Api(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_exit_codes() {
+        let header = HEADER.to_owned()
+            + r#"defaults:
+  exit_codes:
+"#;
+
+        let raw = format!("{header}    signaled: 201\n    fatal: 202\n");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        assert_eq!(
+            manifest.defaults.unwrap().exit_codes.unwrap(),
+            ExitCodes {
+                signaled: Some(201),
+                fatal: Some(202),
+                interrupted: None,
+            }
+        );
+
+        let raw = format!("{header}    interrupted: 0\n");
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::Defaults(_))
+        ));
+
+        let raw = format!("{header}    fatal: 300\n");
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_proxy_buffer_mb() {
+        let header = HEADER.to_owned()
+            + r#"defaults:
+"#;
+
+        let raw = format!("{header}  proxy_buffer_mb: 64\n");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        assert_eq!(manifest.defaults.unwrap().proxy_buffer_mb, Some(64));
+
+        let raw = format!("{header}  proxy_buffer_mb: 0\n");
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::Defaults(_))
+        ));
+    }
+
+    #[test]
+    fn test_supervisor_memory_mb() {
+        let header = HEADER.to_owned()
+            + r#"defaults:
+  memory_mb: 1024
+"#;
+        let manifest_of = |extra: &str| parse_manifest(format!("{header}{extra}").as_bytes());
+
+        let manifest = manifest_of("").unwrap();
+        assert_eq!(manifest.supervisor_memory_mb(), 256);
+        assert_eq!(manifest.enclave_memory_mb(None), 1280);
+        assert_eq!(manifest.enclave_memory_mb(Some(2048)), 2304);
+
+        let manifest = manifest_of("  proxy_buffer_mb: 64\n").unwrap();
+        assert_eq!(manifest.supervisor_memory_mb(), 320);
+
+        let manifest = manifest_of("  supervisor_memory_mb: 128\n").unwrap();
+        assert_eq!(manifest.enclave_memory_mb(None), 1152);
+
+        let small = "  supervisor_memory_mb: 32\n  proxy_buffer_mb: 64\n";
+        assert!(matches!(manifest_of(small), Err(ConfigError::Defaults(_))));
+    }
+
+    #[test]
+    fn test_parse_drain_seconds() {
+        let header = HEADER.to_owned()
+            + r#"defaults:
+"#;
+
+        let raw = format!("{header}  drain_seconds: 30\n");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        assert_eq!(manifest.defaults.unwrap().drain_seconds, Some(30));
+
+        let raw = format!("{header}  drain_seconds: -1\n");
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_kernel_args() {
+        let header = HEADER.to_owned()
+            + r#"build:
+  kernel_args:
+"#;
+
+        let raw = format!("{header}    - clocksource=tsc\n    - quiet\n");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        let build = manifest.build.unwrap();
+        assert_eq!(build.kernel_args(), ["clocksource=tsc", "quiet"]);
+        assert_eq!(
+            build.kernel_cmdline("reboot=k panic=30 clocksource=kvm-clock console=ttyS0\n"),
+            "reboot=k panic=30 console=ttyS0 clocksource=tsc quiet"
+        );
+
+        // Nothing that would take over the console or init, or sneak in another argument
+        for arg in [
+            "init=/bin/sh",
+            "console=tty0",
+            "clocksource=tsc init=/bin/sh",
+            "tsc=",
+        ] {
+            let raw = format!("{header}    - {arg:?}\n");
+            assert!(matches!(
+                parse_manifest(raw.as_bytes()),
+                Err(ConfigError::Build(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_host() {
+        let header = HEADER.to_owned()
+            + r#"host:
+"#;
+        let host_of = |host: &str| {
+            parse_manifest(format!("{header}{host}").as_bytes()).map(|manifest| manifest.host)
+        };
+
+        let host = host_of(
+            r#"  devices:
+    - /dev/sgx_enclave
+    - /dev/hugepages_dev:/dev/hugepages_ctr
+  tmpfs:
+    - path: /dev/hugepages
+      size_mb: 1024
+  ulimits:
+    - name: memlock
+      soft: -1
+      hard: -1
+"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            host.devices(),
+            [
+                ("/dev/sgx_enclave", "/dev/sgx_enclave"),
+                ("/dev/hugepages_dev", "/dev/hugepages_ctr"),
+            ]
+        );
+        assert_eq!(host.tmpfs.unwrap()[0].size_mb, Some(1024));
+        assert_eq!(host.ulimits.unwrap()[0].soft, -1);
+
+        for invalid in [
+            "  devices: [/etc/shadow]\n",
+            "  devices: [/dev/nitro_enclaves]\n",
+            "  devices: [/dev/sgx, /dev/sgx]\n",
+            "  tmpfs: [{path: /}]\n",
+            "  tmpfs: [{path: /scratch, size_mb: 0}]\n",
+            "  ulimits: [{name: priority, soft: 1, hard: 1}]\n",
+            "  ulimits: [{name: nofile, soft: 2048, hard: 1024}]\n",
+            "  ulimits: [{name: nofile, soft: -1, hard: 1024}]\n",
+        ] {
+            assert!(
+                matches!(host_of(invalid), Err(ConfigError::Host(_))),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_s3_proxy() {
+        let raw_manifest = br#"
+version: v1
+name: "test"
+target: "target-image:latest"
+sources:
+  app: "app-image:latest"
+s3_proxy:
+  listen_port: 9998
+  endpoints:
+    us-east-1: bucket.vpce-0123.s3.us-east-1.vpce.amazonaws.com
+egress:
+  allow: ["169.254.169.254", "**.amazonaws.com"]
+"#;
+
+        let manifest = parse_manifest(raw_manifest).unwrap();
+        assert!(manifest
+            .listen_ports()
+            .contains(&(9998, "s3_proxy".to_string())));
+        assert_eq!(manifest.s3_proxy.unwrap().endpoints.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_parse_health() {
+        let raw_manifest = br#"
+version: v1
+name: "test"
+target: "target-image:latest"
+sources:
+  app: "app-image:latest"
+ingress:
+  - listen_port: 8081
+health:
+  listen_port: 8081
+"#;
+
+        // Published on the host through the ingress port of the same number
+        let manifest = parse_manifest(raw_manifest).unwrap();
+        let ports = manifest.listen_ports();
+        assert!(ports.contains(&(8081, "health".to_string())));
+        assert!(ports.contains(&(8081, "ingress".to_string())));
+        assert_eq!(manifest.health.unwrap().listen_port, 8081);
+    }
+
+    #[test]
+    fn test_parse_spiffe() {
+        let raw = format!("{HEADER}spiffe:\n  server: https://spire.example.com/attest\n");
+        let manifest = parse_manifest(raw.as_bytes()).unwrap();
+        assert_eq!(
+            manifest.spiffe.unwrap().server,
+            "https://spire.example.com/attest"
+        );
+
+        let raw = format!("{HEADER}spiffe:\n  server: http://spire.example.com/attest\n");
+        assert!(matches!(
+            parse_manifest(raw.as_bytes()),
+            Err(ConfigError::Spiffe(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_load_manifest_errors() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = dir.path().join("missing.yaml");
+        assert!(matches!(
+            load_manifest(&path).await,
+            Err(ManifestError::Open { .. })
+        ));
+
+        std::fs::write(&path, "version: [").unwrap();
+        match load_manifest(&path).await {
+            Err(ManifestError::Config { path: p, source }) => {
+                assert!(p == path);
+                assert!(matches!(source, ConfigError::Syntax(_)));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
     }
 }
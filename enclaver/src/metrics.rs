@@ -0,0 +1,527 @@
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use http::{Method, Request, Response};
+use hyper::header;
+use hyper::{Body, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::http_util::{self, HttpHandler};
+
+const MIME_PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4";
+
+#[derive(Default)]
+pub struct Counter {
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Mirrors a counter kept elsewhere, e.g. inside the enclave. It goes back to 0
+    /// when that one does, which Prometheus takes as a counter reset.
+    pub fn set(&self, v: u64) {
+        self.value.store(v, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge {
+    value: AtomicI64,
+}
+
+impl Gauge {
+    pub fn set(&self, v: i64) {
+        self.value.store(v, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// Upper bounds, in seconds, of the buckets of a latency histogram
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Observations counted into buckets by their upper bounds. The sum is kept in
+// millionths, which is plenty for latencies in seconds.
+pub struct Histogram {
+    bounds: Vec<f64>,
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, v: f64) {
+        if let Some(i) = self.bounds.iter().position(|bound| v <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add((v * 1e6) as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+    }
+
+    // The cumulative count of each bucket, as Prometheus expects them
+    fn cumulative(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        self.bounds
+            .iter()
+            .zip(&self.buckets)
+            .map(|(bound, bucket)| {
+                total += bucket.load(Ordering::Relaxed);
+                (*bound, total)
+            })
+            .collect()
+    }
+}
+
+enum Value {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    Histogram(Arc<Histogram>),
+}
+
+struct Entry {
+    name: String,
+    help: String,
+    labels: Vec<(String, String)>,
+    value: Value,
+}
+
+// A set of metrics that can be rendered in the Prometheus text exposition format.
+#[derive(Default)]
+pub struct Registry {
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn counter(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Arc<Counter> {
+        let counter = Arc::new(Counter::default());
+        self.register(name, help, labels, Value::Counter(counter.clone()));
+        counter
+    }
+
+    pub fn gauge(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Arc<Gauge> {
+        let gauge = Arc::new(Gauge::default());
+        self.register(name, help, labels, Value::Gauge(gauge.clone()));
+        gauge
+    }
+
+    pub fn histogram(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        bounds: &[f64],
+    ) -> Arc<Histogram> {
+        let histogram = Arc::new(Histogram::new(bounds));
+        self.register(name, help, labels, Value::Histogram(histogram.clone()));
+        histogram
+    }
+
+    fn register(&self, name: &str, help: &str, labels: &[(&str, &str)], value: Value) {
+        self.entries.lock().unwrap().push(Entry {
+            name: name.to_string(),
+            help: help.to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            value,
+        });
+    }
+
+    pub fn render(&self) -> String {
+        let entries = self.entries.lock().unwrap();
+        let mut out = String::new();
+        let mut described: Vec<&str> = Vec::new();
+
+        for entry in entries.iter() {
+            if !described.contains(&entry.name.as_str()) {
+                let kind = match entry.value {
+                    Value::Counter(_) => "counter",
+                    Value::Gauge(_) => "gauge",
+                    Value::Histogram(_) => "histogram",
+                };
+                _ = writeln!(out, "# HELP {} {}", entry.name, entry.help);
+                _ = writeln!(out, "# TYPE {} {kind}", entry.name);
+                described.push(&entry.name);
+
+                // keep all the samples of a metric family together
+                for e in entries.iter().filter(|e| e.name == entry.name) {
+                    render_sample(&mut out, e);
+                }
+            }
+        }
+
+        out
+    }
+}
+
+fn render_sample(out: &mut String, entry: &Entry) {
+    match entry.value {
+        Value::Counter(ref c) => write_sample(out, &entry.name, &entry.labels, None, c.get()),
+        Value::Gauge(ref g) => write_sample(out, &entry.name, &entry.labels, None, g.get()),
+        Value::Histogram(ref h) => {
+            let bucket = format!("{}_bucket", entry.name);
+            for (bound, count) in h.cumulative() {
+                write_sample(out, &bucket, &entry.labels, Some(&bound.to_string()), count);
+            }
+            write_sample(out, &bucket, &entry.labels, Some("+Inf"), h.count());
+            write_sample(
+                out,
+                &format!("{}_sum", entry.name),
+                &entry.labels,
+                None,
+                h.sum(),
+            );
+            write_sample(
+                out,
+                &format!("{}_count", entry.name),
+                &entry.labels,
+                None,
+                h.count(),
+            );
+        }
+    }
+}
+
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    labels: &[(String, String)],
+    le: Option<&str>,
+    value: impl std::fmt::Display,
+) {
+    out.push_str(name);
+
+    let mut labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{k}=\"{}\"", escape_label_value(v)))
+        .collect();
+    if let Some(le) = le {
+        labels.push(format!("le=\"{le}\""));
+    }
+    if !labels.is_empty() {
+        _ = write!(out, "{{{}}}", labels.join(","));
+    }
+
+    _ = writeln!(out, " {value}");
+}
+
+fn escape_label_value(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Counts the connections handled by a proxy
+#[derive(Clone, Default)]
+pub struct ConnectionMetrics {
+    total: Arc<Counter>,
+    active: Arc<Gauge>,
+}
+
+impl ConnectionMetrics {
+    pub fn register(registry: &Registry, prefix: &str, labels: &[(&str, &str)]) -> Self {
+        Self {
+            total: registry.counter(
+                &format!("{prefix}_connections_total"),
+                "Total number of connections handled",
+                labels,
+            ),
+            active: registry.gauge(
+                &format!("{prefix}_connections_active"),
+                "Number of connections currently open",
+                labels,
+            ),
+        }
+    }
+
+    // Records a new connection. It is considered active until the guard is dropped.
+    pub fn track(&self) -> ConnectionGuard {
+        self.total.inc();
+        self.active.inc();
+        ConnectionGuard {
+            active: self.active.clone(),
+        }
+    }
+}
+
+pub struct ConnectionGuard {
+    active: Arc<Gauge>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.active.dec();
+    }
+}
+
+// Counts the bytes and failures of the streams of a proxy, as they happen, so that
+// streams open for hours show up before they close
+#[derive(Clone, Default)]
+pub struct StreamMetrics {
+    received: Arc<Counter>,
+    sent: Arc<Counter>,
+    errors: Arc<Counter>,
+}
+
+impl StreamMetrics {
+    pub fn register(registry: &Registry, prefix: &str, labels: &[(&str, &str)]) -> Self {
+        Self {
+            received: registry.counter(
+                &format!("{prefix}_received_bytes_total"),
+                "Total number of bytes received from clients",
+                labels,
+            ),
+            sent: registry.counter(
+                &format!("{prefix}_sent_bytes_total"),
+                "Total number of bytes sent to clients",
+                labels,
+            ),
+            errors: registry.counter(
+                &format!("{prefix}_stream_errors_total"),
+                "Total number of streams that ended with an error, e.g. a reset or a \
+                 keepalive timeout",
+                labels,
+            ),
+        }
+    }
+
+    // Counts what is read from and written to the client stream
+    pub fn wrap<S>(&self, stream: S) -> CountedStream<S> {
+        CountedStream {
+            inner: stream,
+            metrics: self.clone(),
+        }
+    }
+
+    pub fn error(&self) {
+        self.errors.inc();
+    }
+
+    /// Bytes received from clients so far
+    pub fn received_bytes(&self) -> u64 {
+        self.received.get()
+    }
+
+    /// Bytes sent to clients so far
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent.get()
+    }
+}
+
+pub struct CountedStream<S> {
+    inner: S,
+    metrics: StreamMetrics,
+}
+
+impl<S> CountedStream<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.metrics
+            .received
+            .add((buf.filled().len() - before) as u64);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.metrics.sent.add(n as u64);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub struct MetricsHandler {
+    registry: Arc<Registry>,
+}
+
+impl MetricsHandler {
+    pub fn new(registry: Arc<Registry>) -> Self {
+        Self { registry }
+    }
+}
+
+#[async_trait]
+impl HttpHandler for MetricsHandler {
+    async fn handle(&self, req: Request<Body>) -> Result<Response<Body>> {
+        match req.uri().path() {
+            "/metrics" => match *req.method() {
+                Method::GET => Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, MIME_PROMETHEUS_TEXT)
+                    .body(Body::from(self.registry.render()))?),
+
+                _ => Ok(http_util::method_not_allowed()),
+            },
+            _ => Ok(http_util::not_found()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionMetrics, Registry, StreamMetrics};
+
+    #[test]
+    fn test_render() {
+        let registry = Registry::new();
+        let up = registry.gauge("enclave_up", "Whether the enclave is running", &[]);
+        let http = ConnectionMetrics::register(&registry, "ingress", &[("port", "80")]);
+        let https = ConnectionMetrics::register(&registry, "ingress", &[("port", "443")]);
+
+        up.set(1);
+        let _conn1 = http.track();
+        {
+            let _conn2 = https.track();
+        }
+
+        let expected = "\
+# HELP enclave_up Whether the enclave is running
+# TYPE enclave_up gauge
+enclave_up 1
+# HELP ingress_connections_total Total number of connections handled
+# TYPE ingress_connections_total counter
+ingress_connections_total{port=\"80\"} 1
+ingress_connections_total{port=\"443\"} 1
+# HELP ingress_connections_active Number of connections currently open
+# TYPE ingress_connections_active gauge
+ingress_connections_active{port=\"80\"} 1
+ingress_connections_active{port=\"443\"} 0
+";
+
+        assert_eq!(registry.render(), expected);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let registry = Registry::new();
+        let latency = registry.histogram(
+            "connect_seconds",
+            "Time taken to connect",
+            &[("proxy", "egress")],
+            &[0.1, 1.0],
+        );
+        let unlabeled = registry.histogram("empty_seconds", "Nothing yet", &[], &[1.0]);
+
+        latency.observe(0.05);
+        latency.observe(0.5);
+        latency.observe(2.0);
+        assert_eq!(unlabeled.count(), 0);
+
+        let expected = "\
+# HELP connect_seconds Time taken to connect
+# TYPE connect_seconds histogram
+connect_seconds_bucket{proxy=\"egress\",le=\"0.1\"} 1
+connect_seconds_bucket{proxy=\"egress\",le=\"1\"} 2
+connect_seconds_bucket{proxy=\"egress\",le=\"+Inf\"} 3
+connect_seconds_sum{proxy=\"egress\"} 2.55
+connect_seconds_count{proxy=\"egress\"} 3
+# HELP empty_seconds Nothing yet
+# TYPE empty_seconds histogram
+empty_seconds_bucket{le=\"1\"} 0
+empty_seconds_bucket{le=\"+Inf\"} 0
+empty_seconds_sum 0
+empty_seconds_count 0
+";
+
+        assert_eq!(registry.render(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_stream_metrics() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let registry = Registry::new();
+        let metrics = StreamMetrics::register(&registry, "ingress", &[]);
+
+        let (client, server) = tokio::io::duplex(64);
+        let mut server = metrics.wrap(server);
+        let mut client = client;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"pong!").await.unwrap();
+        metrics.error();
+
+        let rendered = registry.render();
+        assert!(rendered.contains("ingress_received_bytes_total 4\n"));
+        assert!(rendered.contains("ingress_sent_bytes_total 5\n"));
+        assert!(rendered.contains("ingress_stream_errors_total 1\n"));
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::future::Future;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use log::error;
+use nix::sched::{setns, CloneFlags};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Runs a future on a dedicated thread that has joined the network namespace at
+/// path, e.g. one created with `ip netns add` under /var/run/netns. Every socket
+/// the future opens, including those of the tasks it spawns and of DNS lookups,
+/// lives in that namespace, so only its routes are reachable.
+///
+/// `setup` runs on the new thread, inside its runtime, and its errors are returned
+/// from here. The returned handle completes when the future does; aborting it does
+/// not stop the thread, which lives until the process exits.
+pub async fn spawn_in<S, F>(path: &Path, name: &str, setup: S) -> Result<JoinHandle<()>>
+where
+    S: FnOnce() -> Result<F> + Send + 'static,
+    F: Future<Output = ()>,
+{
+    let ns = File::open(path)
+        .map_err(|e| anyhow!("failed to open network namespace {}: {e}", path.display()))?;
+
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (done_tx, done_rx) = oneshot::channel::<()>();
+    let thread_name = name.to_string();
+
+    std::thread::Builder::new()
+        .name(thread_name.clone())
+        .spawn(move || {
+            let runtime = setns(ns.as_raw_fd(), CloneFlags::CLONE_NEWNET)
+                .map_err(|e| anyhow!("failed to join network namespace: {e}"))
+                .and_then(|_| {
+                    Ok(tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?)
+                });
+
+            let runtime = match runtime {
+                Ok(runtime) => runtime,
+                Err(err) => {
+                    _ = ready_tx.send(Err(err));
+                    return;
+                }
+            };
+
+            let fut = {
+                let _guard = runtime.enter();
+                match setup() {
+                    Ok(fut) => fut,
+                    Err(err) => {
+                        _ = ready_tx.send(Err(err));
+                        return;
+                    }
+                }
+            };
+
+            _ = ready_tx.send(Ok(()));
+            runtime.block_on(fut);
+            _ = done_tx.send(());
+        })?;
+
+    ready_rx
+        .await
+        .map_err(|_| anyhow!("{name} thread exited during setup"))??;
+
+    Ok(tokio::task::spawn(async move {
+        if done_rx.await.is_err() {
+            error!("{thread_name} thread panicked");
+        }
+    }))
+}
@@ -3,11 +3,14 @@
 use anyhow::{anyhow, Result};
 use log::{debug, error};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::ffi::OsString;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use tokio::process::{ChildStdout, Command};
 
+use crate::identity::MeasuredIdentity;
+
 pub struct NitroCLI {
     program: String,
 }
@@ -39,9 +42,16 @@ impl NitroCLI {
         if output.status.success() {
             Ok(serde_json::from_slice(&output.stdout)?)
         } else {
-            error!("nitro-cli failed ({})", output.status);
-
             let stderr = String::from_utf8(output.stderr)?;
+
+            // Expected while another enclave holds the pool, the caller decides what to
+            // make of it
+            if let Some(shortage) = InsufficientCapacity::detect(&stderr) {
+                debug!("nitro-cli failed ({}), stderr:\n{stderr}", output.status);
+                return Err(shortage.into());
+            }
+
+            error!("nitro-cli failed ({})", output.status);
             error!("stderr:\n{}", stderr);
 
             for path in stderr.lines().filter_map(|line| {
@@ -106,13 +116,189 @@ impl NitroCLI {
     }
 }
 
+/// The output of `nitro-cli build-enclave` and `describe-eif`. Only the measurements are
+/// always present; older versions of nitro-cli leave out the rest. Fields this version of
+/// enclaver does not know about are kept, so passing the output along does not drop them.
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct EIFInfo {
+    #[serde(
+        rename = "EifVersion",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    eif_version: Option<u64>,
+
     #[serde(rename = "Measurements")]
     measurements: EIFMeasurements,
+
+    #[serde(rename = "IsSigned", default, skip_serializing_if = "Option::is_none")]
+    is_signed: Option<bool>,
+
+    #[serde(
+        rename = "SigningCertificate",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    signing_certificate: Option<EIFSigningCertificate>,
+
+    #[serde(rename = "CheckCRC", default, skip_serializing_if = "Option::is_none")]
+    check_crc: Option<bool>,
+
+    #[serde(rename = "ImageName", default, skip_serializing_if = "Option::is_none")]
+    image_name: Option<String>,
+
+    #[serde(
+        rename = "ImageVersion",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    image_version: Option<String>,
+
+    #[serde(rename = "Metadata", default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<EIFMetadata>,
+
+    #[serde(flatten)]
+    extra: BTreeMap<String, serde_json::Value>,
 }
 
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+impl EIFInfo {
+    pub fn measurements(&self) -> &EIFMeasurements {
+        &self.measurements
+    }
+
+    /// Whether the EIF carries a signature. Older nitro-cli versions do not say, in
+    /// which case PCR8 only being measured for signed images tells.
+    pub fn is_signed(&self) -> bool {
+        self.is_signed
+            .unwrap_or_else(|| self.measurements.pcr8.is_some())
+    }
+
+    pub fn signing_certificate(&self) -> Option<&EIFSigningCertificate> {
+        self.signing_certificate.as_ref()
+    }
+
+    pub fn metadata(&self) -> Option<&EIFMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Labels describing the EIF, set on the release image so it can be inspected
+    /// without running nitro-cli.
+    pub fn labels(&self) -> BTreeMap<String, String> {
+        let mut labels = BTreeMap::new();
+        let mut label = |key: &str, value: String| {
+            labels.insert(format!("{EIF_LABEL_PREFIX}{key}"), value);
+        };
+
+        label("pcr0", self.measurements.pcr0.clone());
+        label("pcr1", self.measurements.pcr1.clone());
+        label("pcr2", self.measurements.pcr2.clone());
+        if let Some(ref pcr8) = self.measurements.pcr8 {
+            label("pcr8", pcr8.clone());
+        }
+        label("signed", self.is_signed().to_string());
+
+        if let Some(version) = self.eif_version {
+            label("version", version.to_string());
+        }
+        if let Some(ref cert) = self.signing_certificate {
+            if let Some(ref not_after) = cert.not_after {
+                label("signing-certificate.not-after", not_after.clone());
+            }
+        }
+        if let Some(ref metadata) = self.metadata {
+            if let Some(ref build_time) = metadata.build_time {
+                label("build-time", build_time.clone());
+            }
+            if let (Some(tool), Some(version)) =
+                (&metadata.build_tool, &metadata.build_tool_version)
+            {
+                label("build-tool", format!("{tool} {version}"));
+            }
+            if let Some(ref kernel) = metadata.kernel_version {
+                label("kernel-version", kernel.clone());
+            }
+        }
+
+        labels
+    }
+}
+
+/// Prefix of the labels set on a release image from its EIFInfo
+pub const EIF_LABEL_PREFIX: &str = "io.enclaver.eif.";
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct EIFSigningCertificate {
+    #[serde(
+        rename = "IssuerName",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub issuer_name: Option<serde_json::Value>,
+
+    #[serde(rename = "Algorithm", default, skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<String>,
+
+    #[serde(rename = "NotBefore", default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<String>,
+
+    #[serde(rename = "NotAfter", default, skip_serializing_if = "Option::is_none")]
+    pub not_after: Option<String>,
+
+    #[serde(rename = "Signature", default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct EIFMetadata {
+    #[serde(rename = "BuildTime", default, skip_serializing_if = "Option::is_none")]
+    pub build_time: Option<String>,
+
+    #[serde(rename = "BuildTool", default, skip_serializing_if = "Option::is_none")]
+    pub build_tool: Option<String>,
+
+    #[serde(
+        rename = "BuildToolVersion",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub build_tool_version: Option<String>,
+
+    #[serde(
+        rename = "OperatingSystem",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub operating_system: Option<String>,
+
+    #[serde(
+        rename = "KernelVersion",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub kernel_version: Option<String>,
+
+    #[serde(
+        rename = "DockerInfo",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub docker_info: Option<serde_json::Value>,
+
+    #[serde(
+        rename = "CustomMetadata",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub custom_metadata: Option<serde_json::Value>,
+
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct EIFMeasurements {
     #[serde(rename = "PCR0")]
     pcr0: String,
@@ -125,6 +311,52 @@ pub struct EIFMeasurements {
 
     #[serde(rename = "PCR8", skip_serializing_if = "Option::is_none")]
     pcr8: Option<String>,
+
+    /// Such as the HashAlgorithm reported by nitro-cli
+    #[serde(flatten)]
+    extra: BTreeMap<String, serde_json::Value>,
+}
+
+impl EIFMeasurements {
+    // The measured PCR values, decoded from hex and keyed by PCR index
+    pub fn pcrs(&self) -> Result<BTreeMap<usize, Vec<u8>>> {
+        let mut pcrs = BTreeMap::from([
+            (0, decode_hex(&self.pcr0)?),
+            (1, decode_hex(&self.pcr1)?),
+            (2, decode_hex(&self.pcr2)?),
+        ]);
+
+        if let Some(ref pcr8) = self.pcr8 {
+            pcrs.insert(8, decode_hex(pcr8)?);
+        }
+
+        Ok(pcrs)
+    }
+
+    /// The identity of an enclave running this image, before it has attested
+    pub fn identity(&self) -> MeasuredIdentity {
+        MeasuredIdentity {
+            pcr0: self.pcr0.clone(),
+            pcr1: self.pcr1.clone(),
+            pcr2: self.pcr2.clone(),
+            attestation: None,
+        }
+    }
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return Err(anyhow!("invalid hex string {s}"));
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| anyhow!("invalid hex string {s}"))
+        })
+        .collect()
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
@@ -140,6 +372,15 @@ pub struct EnclaveInfo {
 
     #[serde(rename = "EnclaveCID")]
     pub cid: u32,
+
+    #[serde(rename = "NumberOfCPUs", default)]
+    pub cpu_count: Option<i32>,
+
+    #[serde(rename = "CPUIDs", default)]
+    pub cpu_ids: Option<Vec<u32>>,
+
+    #[serde(rename = "MemoryMiB", default)]
+    pub memory_mib: Option<i32>,
 }
 
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -253,6 +494,34 @@ impl NitroCLIArgs for DescribeEifArgs {
     }
 }
 
+/// The enclave could not be started because the pool of CPUs or memory set aside for
+/// enclaves by the allocator service has too little left, usually because another
+/// enclave holds it
+#[derive(Debug, Eq, PartialEq, Clone, Copy, thiserror::Error)]
+pub enum InsufficientCapacity {
+    #[error("not enough CPUs left in the enclave CPU pool")]
+    Cpus,
+
+    #[error("not enough memory left in the enclave memory pool")]
+    Memory,
+}
+
+impl InsufficientCapacity {
+    pub fn detect(stderr: &str) -> Option<Self> {
+        // E21 and E22: no such CPU, or not enough CPUs, available in the pool
+        if stderr.contains("[ E21 ]") || stderr.contains("[ E22 ]") {
+            return Some(InsufficientCapacity::Cpus);
+        }
+
+        // E27: insufficient memory available
+        if stderr.contains("[ E27 ]") {
+            return Some(InsufficientCapacity::Memory);
+        }
+
+        None
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum KnownIssue {
     ImageTooLargeForRAM,
@@ -310,4 +579,137 @@ mod tests {
             Some(KnownIssue::ImageTooLargeForRAM)
         );
     }
+
+    #[test]
+    fn test_detect_insufficient_capacity() {
+        assert_eq!(
+            InsufficientCapacity::detect(
+                "Start allocating memory...\n[ E27 ] Insufficient memory available. User provided `memory` is 4096 MB, which is more than the available hugepage memory.\n"
+            ),
+            Some(InsufficientCapacity::Memory)
+        );
+        assert_eq!(
+            InsufficientCapacity::detect(
+                "[ E22 ] Insufficient CPUs available in the pool. User provided `cpu-count` is 4, which is more than the configured CPU pool size.\n"
+            ),
+            Some(InsufficientCapacity::Cpus)
+        );
+        assert_eq!(
+            InsufficientCapacity::detect("[ E26 ] Insufficient memory requested.\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_enclave_info() {
+        let info: EnclaveInfo = serde_json::from_value(serde_json::json!({
+            "EnclaveName": "app",
+            "EnclaveID": "i-0123456789abcdef0-enc0123456789abcdef",
+            "ProcessID": 4242,
+            "EnclaveCID": 16,
+            "NumberOfCPUs": 2,
+            "CPUIDs": [1, 5],
+            "MemoryMiB": 1024
+        }))
+        .unwrap();
+        assert_eq!(info.cpu_ids, Some(vec![1, 5]));
+
+        let info: EnclaveInfo = serde_json::from_value(serde_json::json!({
+            "EnclaveName": "app",
+            "EnclaveID": "i-0123456789abcdef0-enc0123456789abcdef",
+            "ProcessID": 4242,
+            "EnclaveCID": 16
+        }))
+        .unwrap();
+        assert_eq!(info.cpu_ids, None);
+    }
+
+    #[test]
+    fn test_describe_eif_output() {
+        let raw = serde_json::json!({
+            "EifVersion": 4,
+            "Measurements": {
+                "HashAlgorithm": "Sha384 { ... }",
+                "PCR0": "00".repeat(48),
+                "PCR1": "01".repeat(48),
+                "PCR2": "02".repeat(48),
+                "PCR8": "08".repeat(48),
+            },
+            "IsSigned": true,
+            "SigningCertificate": {
+                "IssuerName": {"commonName": "enclaver"},
+                "Algorithm": "ecdsa-with-SHA384",
+                "NotBefore": "Jan 1 00:00:00 2024 +00:00",
+                "NotAfter": "Jan 1 00:00:00 2025 +00:00",
+                "Signature": "30:65",
+            },
+            "CheckCRC": true,
+            "ImageName": "app",
+            "ImageVersion": "1.0",
+            "Metadata": {
+                "BuildTime": "2024-06-01T00:00:00Z",
+                "BuildTool": "nitro-cli",
+                "BuildToolVersion": "1.3.1",
+                "OperatingSystem": "Linux",
+                "KernelVersion": "6.1",
+                "DockerInfo": {"Architecture": "amd64"},
+                "Reproducible": true,
+            },
+            "SomethingNew": [1, 2],
+        });
+
+        let info: EIFInfo = serde_json::from_value(raw.clone()).unwrap();
+        assert!(info.is_signed());
+        assert_eq!(
+            info.metadata().unwrap().build_tool_version.as_deref(),
+            Some("1.3.1")
+        );
+        assert_eq!(
+            info.signing_certificate().unwrap().algorithm.as_deref(),
+            Some("ecdsa-with-SHA384")
+        );
+
+        // Nothing is lost passing the output along
+        assert_eq!(serde_json::to_value(&info).unwrap(), raw);
+
+        let labels = info.labels();
+        assert_eq!(labels.get("io.enclaver.eif.pcr8"), Some(&"08".repeat(48)));
+        assert_eq!(
+            labels.get("io.enclaver.eif.build-tool").map(String::as_str),
+            Some("nitro-cli 1.3.1")
+        );
+        assert_eq!(
+            labels
+                .get("io.enclaver.eif.signing-certificate.not-after")
+                .map(String::as_str),
+            Some("Jan 1 00:00:00 2025 +00:00")
+        );
+
+        // Older versions only report the measurements
+        let info: EIFInfo = serde_json::from_value(serde_json::json!({
+            "Measurements": {"PCR0": "00", "PCR1": "01", "PCR2": "02"},
+        }))
+        .unwrap();
+        assert!(!info.is_signed());
+        assert!(info.metadata().is_none());
+        assert_eq!(info.labels().len(), 4);
+    }
+
+    #[test]
+    fn test_measurement_pcrs() {
+        let measurements = EIFMeasurements {
+            pcr0: "00ff".to_string(),
+            pcr1: "a0".to_string(),
+            pcr2: "0b".to_string(),
+            pcr8: None,
+            extra: BTreeMap::new(),
+        };
+
+        let pcrs = measurements.pcrs().unwrap();
+        assert_eq!(pcrs.get(&0), Some(&vec![0x00, 0xff]));
+        assert_eq!(pcrs.get(&8), None);
+
+        assert!(decode_hex("abc").is_err());
+        assert!(decode_hex("zz").is_err());
+    }
 }
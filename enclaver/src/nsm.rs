@@ -1,9 +1,21 @@
 use std::sync::Arc;
 
-use anyhow::{anyhow, Result};
 use serde_bytes::ByteBuf;
+use thiserror::Error;
 
-pub use aws_nitro_enclaves_nsm_api::api::{Request, Response};
+pub use aws_nitro_enclaves_nsm_api::api::{ErrorCode, Request, Response};
+
+type Result<T> = std::result::Result<T, NsmError>;
+
+#[derive(Debug, Error)]
+pub enum NsmError {
+    /// The NSM driver rejected the request
+    #[error("nsm request failed with: {0:?}")]
+    Request(ErrorCode),
+
+    #[error("unexpected response for {0}")]
+    UnexpectedResponse(&'static str),
+}
 
 pub struct AttestationParams {
     pub nonce: Option<Vec<u8>>,
@@ -26,7 +38,7 @@ impl Nsm {
         match self.process_request(Request::GetRandom {})? {
             Response::GetRandom { random } => Ok(random),
 
-            _ => Err(anyhow!("unexpected response for GetRandom")),
+            _ => Err(NsmError::UnexpectedResponse("GetRandom")),
         }
     }
 
@@ -39,13 +51,32 @@ impl Nsm {
 
         match self.process_request(req)? {
             Response::Attestation { document } => Ok(document),
-            _ => Err(anyhow!("unexpected response for Attestation")),
+            _ => Err(NsmError::UnexpectedResponse("Attestation")),
+        }
+    }
+
+    pub fn describe_pcr(&self, index: u16) -> Result<Vec<u8>> {
+        match self.process_request(Request::DescribePCR { index })? {
+            Response::DescribePCR { data, .. } => Ok(data),
+            _ => Err(NsmError::UnexpectedResponse("DescribePCR")),
+        }
+    }
+
+    pub fn extend_pcr(&self, index: u16, data: Vec<u8>) -> Result<Vec<u8>> {
+        match self.process_request(Request::ExtendPCR { index, data })? {
+            Response::ExtendPCR { data } => Ok(data),
+            _ => Err(NsmError::UnexpectedResponse("ExtendPCR")),
         }
     }
 
+    // Enclaves started in debug mode report all zero PCRs
+    pub fn is_debug_mode(&self) -> Result<bool> {
+        Ok(self.describe_pcr(0)?.iter().all(|b| *b == 0))
+    }
+
     fn process_request(&self, req: Request) -> Result<Response> {
         match aws_nitro_enclaves_nsm_api::driver::nsm_process_request(self.fd, req) {
-            Response::Error(err) => Err(anyhow!("nsm request failed with: {:?}", err)),
+            Response::Error(err) => Err(NsmError::Request(err)),
             resp => Ok(resp),
         }
     }
@@ -63,16 +94,30 @@ pub trait AttestationProvider {
 
 pub struct NsmAttestationProvider {
     nsm: Arc<Nsm>,
+    default_user_data: Option<Vec<u8>>,
 }
 
 impl NsmAttestationProvider {
     pub fn new(nsm: Arc<Nsm>) -> Self {
-        Self { nsm }
+        Self {
+            nsm,
+            default_user_data: None,
+        }
+    }
+
+    // Use `user_data` for attestations that do not specify their own
+    pub fn with_default_user_data(mut self, user_data: Option<Vec<u8>>) -> Self {
+        self.default_user_data = user_data;
+        self
     }
 }
 
 impl AttestationProvider for NsmAttestationProvider {
-    fn attestation(&self, params: AttestationParams) -> Result<Vec<u8>> {
+    fn attestation(&self, mut params: AttestationParams) -> Result<Vec<u8>> {
+        if params.user_data.is_none() {
+            params.user_data = self.default_user_data.clone();
+        }
+
         self.nsm.attestation(params)
     }
 }
@@ -1,3 +1,7 @@
+use anyhow::Result;
+
+use super::ports::{self, PortRange};
+
 enum PatternPart {
     Superwild,
     Wild,
@@ -68,7 +72,8 @@ impl Domain {
 }
 
 pub struct DomainFilter {
-    patterns: Vec<Pattern>,
+    // Along with the pattern as written, to tell which one matched
+    patterns: Vec<(Pattern, Option<PortRange>, String)>,
 }
 
 impl DomainFilter {
@@ -80,18 +85,39 @@ impl DomainFilter {
 
     pub fn allow_all() -> Self {
         Self {
-            patterns: vec![Pattern::new("**")],
+            patterns: vec![(Pattern::new("**"), None, "**".to_string())],
         }
     }
 
-    pub fn add(&mut self, pattern: &str) {
-        self.patterns.push(Pattern::new(pattern));
+    /// Add a pattern, which may be limited to a port or port range, e.g. *.example.com:443
+    pub fn add(&mut self, pattern: &str) -> Result<()> {
+        let (parsed, ports) = ports::split(pattern)?;
+        self.patterns
+            .push((Pattern::new(parsed), ports, pattern.to_string()));
+        Ok(())
+    }
+
+    /// Whether a pattern matches a connection to domain on port. With no port, only
+    /// patterns for every port match.
+    pub fn matches(&self, domain: &str, port: Option<u16>) -> bool {
+        self.find(domain, port).is_some()
+    }
+
+    /// The first pattern, as written, that matches a connection to domain on port
+    pub fn find(&self, domain: &str, port: Option<u16>) -> Option<&str> {
+        let dom = Domain::new(domain);
+
+        self.patterns
+            .iter()
+            .find(|(pat, ports, _)| ports::matches(*ports, port) && pat.matches(&dom))
+            .map(|(_, _, source)| source.as_str())
     }
 
-    pub fn matches(&self, domain: &str) -> bool {
+    /// Whether a pattern matches domain, on whichever ports
+    pub fn matches_host(&self, domain: &str) -> bool {
         let dom = Domain::new(domain);
 
-        self.patterns.iter().any(|pat| pat.matches(&dom))
+        self.patterns.iter().any(|(pat, _, _)| pat.matches(&dom))
     }
 }
 
@@ -165,16 +191,32 @@ mod tests {
     #[test]
     fn test_domain_filter() {
         let mut df = DomainFilter::new();
-        df.add("example.com");
-        df.add("*.net");
-        df.add("foo.*.com");
-        df.add("**.amazonaws.com");
-
-        assert!(df.matches("example.com"));
-        assert!(!df.matches("cnn.com"));
-        assert!(df.matches("example.net"));
-        assert!(!df.matches("foo.bar.org"));
-        assert!(df.matches("kms.amazonaws.com"));
-        assert!(df.matches("kms.us-east-1.amazonaws.com"));
+        df.add("example.com").unwrap();
+        df.add("*.net").unwrap();
+        df.add("foo.*.com").unwrap();
+        df.add("**.amazonaws.com").unwrap();
+
+        assert!(df.matches("example.com", None));
+        assert!(!df.matches("cnn.com", None));
+        assert!(df.matches("example.net", None));
+        assert!(!df.matches("foo.bar.org", None));
+        assert!(df.matches("kms.amazonaws.com", None));
+        assert!(df.matches("kms.us-east-1.amazonaws.com", Some(443)));
+    }
+
+    #[test]
+    fn test_domain_filter_ports() {
+        let mut df = DomainFilter::new();
+        df.add("api.example.com:443").unwrap();
+        df.add("*.example.net:8000-8100").unwrap();
+        assert!(df.add("example.org:http").is_err());
+
+        assert!(df.matches("api.example.com", Some(443)));
+        assert!(!df.matches("api.example.com", Some(8443)));
+        assert!(!df.matches("api.example.com", None));
+        assert!(df.matches("www.example.net", Some(8080)));
+        assert!(!df.matches("www.example.net", Some(80)));
+        assert!(df.matches_host("api.example.com"));
+        assert!(!df.matches_host("www.example.com"));
     }
 }
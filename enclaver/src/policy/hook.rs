@@ -0,0 +1,43 @@
+//! A hook that has the last word on connections the rules of a policy allow, for
+//! what static lists cannot express, e.g. only during business hours. A hook can only
+//! deny what the rules allow, never allow what they do not. odyn runs it as a
+//! WebAssembly module shipped in the image, see `wasm`.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// What a hook is asked about a connection, handed to the module as JSON
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HookRequest<'a> {
+    pub host: &'a str,
+    pub port: u16,
+
+    /// Name of the egress proxy whose policy allowed it, unset for the default
+    pub policy: Option<&'a str>,
+
+    /// Seconds since the epoch, by the clock of the enclave
+    pub time: u64,
+}
+
+impl<'a> HookRequest<'a> {
+    pub fn new(host: &'a str, port: u16, policy: Option<&'a str>) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0);
+
+        Self {
+            host,
+            port,
+            policy,
+            time,
+        }
+    }
+}
+
+pub trait EgressHook: Send + Sync {
+    /// Whether a connection the rules allow may go ahead. A hook that fails to
+    /// answer denies it.
+    fn allows(&self, request: &HookRequest) -> bool;
+}
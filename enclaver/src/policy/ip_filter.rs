@@ -1,24 +1,45 @@
 use std::net::IpAddr;
 
 use anyhow::Result;
-use ipnetwork::IpNetwork;
+use ipnetwork::{IpNetwork, Ipv4Network};
+
+use super::ports::{self, PortRange};
 
 #[derive(Debug)]
 struct Pattern(IpNetwork);
 
 impl Pattern {
+    // A network within ::ffff:0:0/96 is kept as the IPv4 network it maps, so that it
+    // matches addresses in either form, e.g. ::ffff:10.0.0.0/104 is 10.0.0.0/8
     fn new(pattern: &str) -> Result<Self> {
-        let ipn: IpNetwork = pattern.parse()?;
+        let ipn = match pattern.parse()? {
+            IpNetwork::V6(net) if net.prefix() >= 96 => match net.ip().to_ipv4_mapped() {
+                Some(ip) => IpNetwork::V4(Ipv4Network::new(ip, net.prefix() - 96)?),
+                None => IpNetwork::V6(net),
+            },
+            ipn => ipn,
+        };
         Ok(Pattern(ipn))
     }
 
+    // IPv4 patterns match IPv4-mapped IPv6 addresses, e.g. ::ffff:1.2.3.4 as
+    // written by dual-stack sockets, as well as IPv6 ones covering them
     fn matches(&self, addr: IpAddr) -> bool {
-        self.0.contains(addr)
+        self.0.contains(addr) || self.0.contains(canonical(addr))
+    }
+}
+
+/// The IPv4 address an IPv4-mapped IPv6 address stands for, or the address itself
+pub fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
     }
 }
 
 pub struct IpFilter {
-    patterns: Vec<Pattern>,
+    // Along with the pattern as written, to tell which one matched
+    patterns: Vec<(Pattern, Option<PortRange>, String)>,
 }
 
 impl IpFilter {
@@ -31,19 +52,42 @@ impl IpFilter {
     pub fn allow_all() -> Self {
         Self {
             patterns: vec![
-                Pattern::new("0.0.0.0/0").unwrap(),
-                Pattern::new("::/0").unwrap(),
+                (
+                    Pattern::new("0.0.0.0/0").unwrap(),
+                    None,
+                    "0.0.0.0/0".to_string(),
+                ),
+                (Pattern::new("::/0").unwrap(), None, "::/0".to_string()),
             ],
         }
     }
 
+    /// Add a pattern, which may be limited to a port or port range, e.g. 10.0.0.0/8:443
+    /// or [fc00::/7]:8000-8100
     pub fn add(&mut self, pattern: &str) -> Result<()> {
-        self.patterns.push(Pattern::new(pattern)?);
+        let (parsed, ports) = ports::split(pattern)?;
+        self.patterns
+            .push((Pattern::new(parsed)?, ports, pattern.to_string()));
         Ok(())
     }
 
-    pub fn matches(&self, addr: IpAddr) -> bool {
-        self.patterns.iter().any(|p| p.matches(addr))
+    /// Whether a pattern matches a connection to addr on port. With no port, only
+    /// patterns for every port match.
+    pub fn matches(&self, addr: IpAddr, port: Option<u16>) -> bool {
+        self.find(addr, port).is_some()
+    }
+
+    /// The first pattern, as written, that matches a connection to addr on port
+    pub fn find(&self, addr: IpAddr, port: Option<u16>) -> Option<&str> {
+        self.patterns
+            .iter()
+            .find(|(p, ports, _)| ports::matches(*ports, port) && p.matches(addr))
+            .map(|(_, _, source)| source.as_str())
+    }
+
+    /// Whether a pattern matches addr, on whichever ports
+    pub fn matches_host(&self, addr: IpAddr) -> bool {
+        self.patterns.iter().any(|(p, _, _)| p.matches(addr))
     }
 }
 
@@ -92,12 +136,26 @@ mod tests {
                 ],
                 negatives: vec![],
             },
-            // TODO: support IPv4 addresses encoded in IPv6
-            // TestCase{
-            //    pattern: "::ffff:42fe:2116/124",
-            //    positives: vec!["66.254.33.1", "66.254.33.22", "66.254.33.255"],
-            //    negatives: vec!["66.254.34.1", "67.254.33.22", "66.254.32.255"],
-            // },
+            TestCase {
+                pattern: "::ffff:42fe:2116/120",
+                positives: vec![
+                    "66.254.33.1",
+                    "66.254.33.22",
+                    "66.254.33.255",
+                    "::ffff:66.254.33.22",
+                ],
+                negatives: vec!["66.254.34.1", "67.254.33.22", "::ffff:66.254.32.255"],
+            },
+            TestCase {
+                pattern: "66.254.33.0/24",
+                positives: vec!["::ffff:66.254.33.1", "::ffff:42fe:21ff"],
+                negatives: vec!["::ffff:66.254.34.1", "::42fe:2101", "64:ff9b::42fe:2101"],
+            },
+            TestCase {
+                pattern: "::/0",
+                positives: vec!["::ffff:66.254.33.1"],
+                negatives: vec!["66.254.33.1"],
+            },
         ];
 
         for tc in &cases {
@@ -125,11 +183,46 @@ mod tests {
         df.add("66.254.34.22/32").unwrap();
         df.add("66.254.35.0/24").unwrap();
 
-        assert!(df.matches("66.254.33.22".parse().unwrap()));
-        assert!(df.matches("66.254.34.22".parse().unwrap()));
-        assert!(df.matches("66.254.35.22".parse().unwrap()));
-        assert!(!df.matches("66.254.33.21".parse().unwrap()));
-        assert!(!df.matches("66.254.34.23".parse().unwrap()));
-        assert!(!df.matches("66.254.36.23".parse().unwrap()));
+        assert!(df.matches("66.254.33.22".parse().unwrap(), None));
+        assert!(df.matches("66.254.34.22".parse().unwrap(), None));
+        assert!(df.matches("66.254.35.22".parse().unwrap(), Some(443)));
+        assert!(!df.matches("66.254.33.21".parse().unwrap(), None));
+        assert!(!df.matches("66.254.34.23".parse().unwrap(), None));
+        assert!(!df.matches("66.254.36.23".parse().unwrap(), None));
+    }
+
+    #[test]
+    fn test_ip_filter_ports() {
+        let mut df = IpFilter::new();
+        df.add("10.0.0.0/8:5432").unwrap();
+        df.add("[fc00::/7]:8000-8100").unwrap();
+
+        assert!(df.matches("10.1.2.3".parse().unwrap(), Some(5432)));
+        assert!(!df.matches("10.1.2.3".parse().unwrap(), Some(5433)));
+        assert!(!df.matches("10.1.2.3".parse().unwrap(), None));
+        assert!(df.matches("fc00::1".parse().unwrap(), Some(8080)));
+        assert!(!df.matches("fc00::1".parse().unwrap(), Some(443)));
+        assert!(df.matches_host("10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ip_filter_mapped() {
+        let mut df = IpFilter::new();
+        df.add("[::ffff:10.0.0.0/104]:443").unwrap();
+        df.add("192.168.0.0/16").unwrap();
+
+        assert!(
+            df.find("10.1.2.3".parse().unwrap(), Some(443)) == Some("[::ffff:10.0.0.0/104]:443")
+        );
+        assert!(df.matches("::ffff:10.1.2.3".parse().unwrap(), Some(443)));
+        assert!(!df.matches("::ffff:10.1.2.3".parse().unwrap(), Some(80)));
+        assert!(df.matches("::ffff:192.168.1.1".parse().unwrap(), None));
+        assert!(!df.matches("::ffff:172.16.0.1".parse().unwrap(), None));
+
+        assert!(
+            super::canonical("::ffff:1.2.3.4".parse().unwrap())
+                == "1.2.3.4".parse::<IpAddr>().unwrap()
+        );
+        assert!(super::canonical("::1".parse().unwrap()) == "::1".parse::<IpAddr>().unwrap());
     }
 }
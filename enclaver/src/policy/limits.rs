@@ -0,0 +1,212 @@
+//! Rate and bandwidth limits of egress rules. Each limit has a token bucket for the
+//! connections and requests to its hosts, and one for the bytes sent to them, both
+//! allowing bursts of up to a second's worth. A connection or request past the rate
+//! limit is refused. Data past the bandwidth is delayed rather than dropped, see
+//! `proxy::throttle`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::domain_filter::DomainFilter;
+use super::ip_filter::IpFilter;
+use super::{host_find, load_filters};
+use crate::manifest::EgressLimit;
+
+/// How often a limit refused or slowed down traffic, reported to the host
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LimitStats {
+    /// The hosts of the limit, as in the manifest
+    pub rule: String,
+
+    /// Name of the egress proxy the limit is part of, unset for the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+
+    /// Connections and requests refused by the rate limit
+    pub dropped: u64,
+
+    /// Times a stream or body was held back by the bandwidth
+    pub throttled: u64,
+}
+
+pub struct EgressLimiter {
+    rule: String,
+    domains: DomainFilter,
+    ips: IpFilter,
+    rate: Option<Mutex<TokenBucket>>,
+    bandwidth: Option<Mutex<TokenBucket>>,
+    dropped: AtomicU64,
+    throttled: AtomicU64,
+}
+
+impl EgressLimiter {
+    pub fn new(spec: &EgressLimit) -> Self {
+        let (domains, ips) = load_filters(&Some(spec.hosts.clone()));
+
+        Self {
+            rule: spec.hosts.join(","),
+            domains,
+            ips,
+            rate: spec
+                .rate_limit
+                .map(|rate| Mutex::new(TokenBucket::new(rate))),
+            bandwidth: spec
+                .bandwidth
+                .map(|rate| Mutex::new(TokenBucket::new(rate))),
+            dropped: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn matches(&self, host: &str, port: u16) -> bool {
+        host_find(&self.domains, &self.ips, host, Some(port)).is_some()
+    }
+
+    /// The hosts of the limit, e.g. for the rule of an audit event
+    pub fn rule(&self) -> &str {
+        &self.rule
+    }
+
+    /// Whether the bytes sent to the hosts are limited
+    pub fn has_bandwidth(&self) -> bool {
+        self.bandwidth.is_some()
+    }
+
+    /// Takes a connection or request from the rate limit. False if it is used up, in
+    /// which case the connection or request must be refused.
+    pub fn admit(&self) -> bool {
+        let admitted = match self.rate {
+            Some(ref rate) => rate.lock().unwrap().try_take(1.0),
+            None => true,
+        };
+        if !admitted {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        admitted
+    }
+
+    pub fn stats(&self) -> LimitStats {
+        LimitStats {
+            rule: self.rule.clone(),
+            policy: None,
+            dropped: self.dropped.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Takes n bytes sent to the hosts from the bandwidth, and returns how long to
+    /// wait before sending more, if the bandwidth is used up
+    pub fn send(&self, n: usize) -> Option<Duration> {
+        let wait = self.bandwidth.as_ref()?.lock().unwrap().take(n as f64)?;
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        Some(wait)
+    }
+}
+
+// Allows bursts of up to one second's worth. take() may overdraw the bucket, so a
+// large write goes through at once and the writes after it wait for the debt.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        let rate = rate.max(1) as f64;
+        Self {
+            rate,
+            tokens: rate,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    fn try_take(&mut self, n: f64) -> bool {
+        self.refill();
+        if self.tokens < n {
+            return false;
+        }
+        self.tokens -= n;
+        true
+    }
+
+    // How long until the bucket is out of debt, if it is
+    fn take(&mut self, n: f64) -> Option<Duration> {
+        self.refill();
+        self.tokens -= n;
+        match self.tokens < 0.0 {
+            true => Some(Duration::from_secs_f64(-self.tokens / self.rate)),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EgressLimiter;
+    use crate::manifest::EgressLimit;
+    use assert2::assert;
+    use std::time::Duration;
+
+    fn limiter(rate_limit: Option<u32>, bandwidth: Option<u32>) -> EgressLimiter {
+        EgressLimiter::new(&EgressLimit {
+            hosts: vec!["*.example.com".to_string(), "10.0.0.0/8".to_string()],
+            rate_limit,
+            bandwidth,
+        })
+    }
+
+    #[test]
+    fn test_matches() {
+        let limiter = limiter(Some(1), None);
+        assert!(limiter.rule() == "*.example.com,10.0.0.0/8");
+        assert!(limiter.matches("api.example.com", 443));
+        assert!(limiter.matches("10.1.2.3", 80));
+        assert!(!limiter.matches("example.org", 443));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = limiter(Some(2), None);
+
+        // A second's worth, then nothing until the bucket refills
+        assert!(limiter.admit());
+        assert!(limiter.admit());
+        assert!(!limiter.admit());
+
+        std::thread::sleep(Duration::from_millis(600));
+        assert!(limiter.admit());
+        assert!(!limiter.admit());
+
+        let stats = limiter.stats();
+        assert!(stats.dropped == 2);
+        assert!(stats.throttled == 0);
+
+        // Without a bandwidth, sending is never held back
+        assert!(limiter.send(1 << 30).is_none());
+    }
+
+    #[test]
+    fn test_bandwidth() {
+        let limiter = limiter(None, Some(10_000));
+
+        // A second's worth goes through, and a write past it waits for the debt
+        assert!(limiter.send(10_000).is_none());
+        let wait = limiter.send(3_000).unwrap();
+        assert!(wait > Duration::from_millis(250) && wait <= Duration::from_millis(300));
+        assert!(limiter.stats().throttled == 1);
+
+        // Without a rate limit, connections are never refused
+        assert!(limiter.admit());
+    }
+}
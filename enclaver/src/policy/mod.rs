@@ -1,53 +1,401 @@
 pub mod domain_filter;
+pub mod hook;
 pub mod ip_filter;
+pub mod limits;
+pub mod ports;
+pub mod requests;
+
+#[cfg(feature = "odyn")]
+pub mod wasm;
 
 use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
 
 use domain_filter::DomainFilter;
+use hook::{EgressHook, HookRequest};
 use ip_filter::IpFilter;
+use limits::{EgressLimiter, LimitStats};
+use requests::RequestFilter;
+
+use crate::manifest::{DatabaseEgress, EgressDefault, Protocol, ProtocolEgress};
+
+// The rule of a connection that only a pushed restriction denied
+const RESTRICTION_RULE: &str = "policy update";
+
+// The rule of a connection that only the policy hook denied
+const HOOK_RULE: &str = "policy hook";
 
 pub struct EgressPolicy {
     domain_allow: DomainFilter,
     domain_deny: DomainFilter,
     ip_allow: IpFilter,
     ip_deny: IpFilter,
+    protocol_rules: Vec<ProtocolRule>,
+    http2_domains: DomainFilter,
+    http2_ips: IpFilter,
+    verify_sni: bool,
+    name: Option<String>,
+
+    // Whether connections no allow or deny rule matches are allowed
+    default_allow: bool,
+
+    // Rate and bandwidth limits, of which the first that matches applies
+    limits: Vec<Arc<EgressLimiter>>,
+
+    // Constraints on plain HTTP requests, of which the first that matches applies
+    requests: Vec<Arc<RequestFilter>>,
+
+    // A narrower policy pushed at runtime, which connections must pass as well
+    restriction: RwLock<Option<Arc<EgressPolicy>>>,
+
+    // Asked last about connections the rules allow
+    hook: Option<Arc<dyn EgressHook>>,
+}
+
+// Allows a protocol to a set of hosts, on a set of ports
+struct ProtocolRule {
+    protocol: Protocol,
+    domains: DomainFilter,
+    ips: IpFilter,
+    ports: Vec<u16>,
+    require_tls: bool,
+}
+
+/// The protocol rule that allowed a tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolMatch {
+    pub protocol: Protocol,
+    pub require_tls: bool,
+}
+
+/// The verdict on a connection, and the rule that decided it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+
+    // The rule that decided, e.g. "allow **.example.com", "deny 10.0.0.0/8:22" or
+    // "protocol smtp", "policy update" if a pushed restriction denied it, or "policy
+    // hook" if the hook did. None if no rule matched and the default of the policy
+    // decided. The proxies also record "verify_sni", "limit ..." and "request ..." for
+    // what they refuse after the policy allowed it.
+    pub rule: Option<String>,
+}
+
+impl Decision {
+    fn allowed(rule: Option<String>) -> Self {
+        Self {
+            allowed: true,
+            rule,
+        }
+    }
+
+    fn denied(rule: Option<String>) -> Self {
+        Self {
+            allowed: false,
+            rule,
+        }
+    }
+}
+
+impl ProtocolRule {
+    fn new(spec: &ProtocolEgress) -> Self {
+        let (domains, ips) = load_filters(&Some(spec.allow.clone()));
+        let ports = match spec.ports {
+            Some(ref ports) => ports.clone(),
+            None => spec.protocol.default_ports().to_vec(),
+        };
+
+        Self {
+            protocol: spec.protocol,
+            domains,
+            ips,
+            ports,
+            require_tls: false,
+        }
+    }
+
+    // Returns None if the engine cannot be inferred, which manifest validation rejects
+    fn from_database(spec: &DatabaseEgress) -> Option<Self> {
+        let (domains, ips) = load_filters(&Some(vec![spec.host.clone()]));
+
+        Some(Self {
+            protocol: spec.engine()?,
+            domains,
+            ips,
+            ports: vec![spec.port],
+            require_tls: spec.require_tls.unwrap_or(false),
+        })
+    }
+
+    fn matches(&self, host: &str, port: u16) -> bool {
+        self.ports.contains(&port) && host_matches(&self.domains, &self.ips, host, Some(port))
+    }
 }
 
 impl EgressPolicy {
     pub fn new(spec: &crate::manifest::Egress) -> Self {
         let (domain_allow, ip_allow) = load_filters(&spec.allow);
         let (domain_deny, ip_deny) = load_filters(&spec.deny);
+        let (http2_domains, http2_ips) = load_filters(&spec.http2_prior_knowledge);
+
+        let protocol_rules = spec
+            .protocols
+            .iter()
+            .flatten()
+            .map(ProtocolRule::new)
+            .chain(
+                spec.databases
+                    .iter()
+                    .flatten()
+                    .filter_map(ProtocolRule::from_database),
+            )
+            .collect();
 
         Self {
             domain_allow,
             domain_deny,
             ip_allow,
             ip_deny,
+            protocol_rules,
+            http2_domains,
+            http2_ips,
+            verify_sni: spec.verify_sni.unwrap_or(false),
+            name: None,
+            default_allow: spec.default_action() == EgressDefault::Allow,
+            limits: spec
+                .limits
+                .iter()
+                .flatten()
+                .map(|limit| Arc::new(EgressLimiter::new(limit)))
+                .collect(),
+            // The IMDS rule comes first, so that request rules cannot widen it
+            requests: spec
+                .imds
+                .iter()
+                .map(RequestFilter::imds)
+                .chain(spec.requests.iter().flatten().map(RequestFilter::new))
+                .map(Arc::new)
+                .collect(),
+            restriction: RwLock::new(None),
+            hook: None,
         }
     }
 
+    /// Names the policy of a named egress proxy, for the host to record
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Has the hook decide on the connections the rules allow, last
+    pub fn with_hook(mut self, hook: Arc<dyn EgressHook>) -> Self {
+        self.hook = Some(hook);
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Whether CONNECT tunnels must open with a TLS ClientHello whose server name
+    /// the policy allows as well
+    pub fn verifies_sni(&self) -> bool {
+        self.verify_sni
+    }
+
     pub fn allow_all() -> Self {
         Self {
             domain_allow: DomainFilter::allow_all(),
             domain_deny: DomainFilter::new(),
             ip_allow: IpFilter::allow_all(),
             ip_deny: IpFilter::new(),
+            protocol_rules: Vec::new(),
+            http2_domains: DomainFilter::new(),
+            http2_ips: IpFilter::new(),
+            verify_sni: false,
+            name: None,
+            default_allow: false,
+            limits: Vec::new(),
+            requests: Vec::new(),
+            restriction: RwLock::new(None),
+            hook: None,
+        }
+    }
+
+    /// Narrows the policy to what restriction allows as well, replacing any previous
+    /// restriction. It can only take away from what the policy allows, never add to it.
+    pub fn restrict(&self, restriction: EgressPolicy) {
+        *self.restriction.write().unwrap() = Some(Arc::new(restriction));
+    }
+
+    /// Checks a connection to host:port against the allow and deny lists
+    pub fn is_host_allowed(&self, host: &str, port: u16) -> bool {
+        log::trace!("is_host_allowed({host}, {port})");
+
+        self.decide_host(host, port).allowed
+    }
+
+    /// Checks a CONNECT tunnel to host:port, which protocol rules may also allow
+    pub fn is_connect_allowed(&self, host: &str, port: u16) -> bool {
+        self.decide_connect(host, port).allowed
+    }
+
+    /// Like is_host_allowed, along with the rule that decided
+    pub fn decide_host(&self, host: &str, port: u16) -> Decision {
+        if let Some(rule) = host_find(&self.domain_deny, &self.ip_deny, host, Some(port)) {
+            return Decision::denied(Some(format!("deny {rule}")));
+        }
+
+        // Deny rules take precedence over the default as well
+        let rule = match host_find(&self.domain_allow, &self.ip_allow, host, Some(port)) {
+            Some(rule) => Some(format!("allow {rule}")),
+            None if self.default_allow => None,
+            None => return Decision::denied(None),
+        };
+
+        if !self.restriction_allows(|r| r.is_host_allowed(host, port)) {
+            return Decision::denied(Some(RESTRICTION_RULE.to_string()));
+        }
+
+        match self.hook_allows(host, port) {
+            true => Decision::allowed(rule),
+            false => Decision::denied(Some(HOOK_RULE.to_string())),
+        }
+    }
+
+    /// Like is_connect_allowed, along with the rule that decided
+    pub fn decide_connect(&self, host: &str, port: u16) -> Decision {
+        let decision = self.decide_host(host, port);
+        if decision.allowed {
+            return decision;
+        }
+
+        match self.protocol(host, port) {
+            Some(m) => Decision::allowed(Some(format!("protocol {}", m.protocol))),
+            None => decision,
+        }
+    }
+
+    /// Whether host may be resolved, i.e. whether any rule could allow a connection to it
+    /// on some port
+    pub fn is_name_allowed(&self, host: &str) -> bool {
+        !self.is_host_denied(host, None)
+            && (self.default_allow
+                || name_matches(&self.domain_allow, &self.ip_allow, host)
+                || self
+                    .protocol_rules
+                    .iter()
+                    .any(|rule| name_matches(&rule.domains, &rule.ips, host)))
+            && self.restriction_allows(|r| r.is_name_allowed(host))
+    }
+
+    /// Whether plain HTTP requests to host:port are forwarded over HTTP/2 without
+    /// first asking the server whether it speaks it
+    pub fn is_http2_prior_knowledge(&self, host: &str, port: u16) -> bool {
+        host_matches(&self.http2_domains, &self.http2_ips, host, Some(port))
+    }
+
+    /// The protocol expected on a tunnel to host:port, if a protocol rule allows it
+    pub fn protocol(&self, host: &str, port: u16) -> Option<ProtocolMatch> {
+        if self.is_host_denied(host, Some(port))
+            || !self.restriction_allows(|r| r.is_connect_allowed(host, port))
+        {
+            return None;
         }
+
+        let rule = self
+            .protocol_rules
+            .iter()
+            .find(|rule| rule.matches(host, port))?;
+
+        self.hook_allows(host, port).then_some(ProtocolMatch {
+            protocol: rule.protocol,
+            require_tls: rule.require_tls,
+        })
     }
 
-    pub fn is_host_allowed(&self, mut host: &str) -> bool {
-        log::trace!("is_host_allowed({host})");
+    /// The limit on connections to host:port, if any. Restrictions pushed at runtime
+    /// carry no limits of their own.
+    pub fn limit(&self, host: &str, port: u16) -> Option<Arc<EgressLimiter>> {
+        self.limits
+            .iter()
+            .find(|limit| limit.matches(host, port))
+            .cloned()
+    }
+
+    /// The request rule for host:port, if any. Restrictions pushed at runtime carry no
+    /// request rules of their own.
+    pub fn request_filter(&self, host: &str, port: u16) -> Option<Arc<RequestFilter>> {
+        self.requests
+            .iter()
+            .find(|filter| filter.matches(host, port))
+            .cloned()
+    }
 
-        // An IPv6 address gets passed with the brackets, e.g. [::1],
-        // and need to be stripped before converting to an IpAddr
-        host = host.strip_prefix('[').unwrap_or(host);
-        host = host.strip_suffix(']').unwrap_or(host);
+    /// How often each limit refused or slowed down traffic so far
+    pub fn limit_stats(&self) -> Vec<LimitStats> {
+        self.limits
+            .iter()
+            .map(|limit| LimitStats {
+                policy: self.name.clone(),
+                ..limit.stats()
+            })
+            .collect()
+    }
+
+    fn restriction_allows(&self, check: impl FnOnce(&EgressPolicy) -> bool) -> bool {
+        match *self.restriction.read().unwrap() {
+            Some(ref restriction) => check(restriction),
+            None => true,
+        }
+    }
 
-        match host.parse::<IpAddr>() {
-            Ok(addr) => self.ip_allow.matches(addr) && !self.ip_deny.matches(addr),
-            Err(_) => self.domain_allow.matches(host) && !self.domain_deny.matches(host),
+    // Names are not run by the hook, only connections
+    fn hook_allows(&self, host: &str, port: u16) -> bool {
+        match self.hook {
+            Some(ref hook) => hook.allows(&HookRequest::new(host, port, self.name())),
+            None => true,
         }
     }
+
+    // With no port, only deny rules for every port apply
+    fn is_host_denied(&self, host: &str, port: Option<u16>) -> bool {
+        host_matches(&self.domain_deny, &self.ip_deny, host, port)
+    }
+}
+
+fn host_matches(domains: &DomainFilter, ips: &IpFilter, host: &str, port: Option<u16>) -> bool {
+    host_find(domains, ips, host, port).is_some()
+}
+
+// The pattern that matches a connection to host:port, as written in the manifest
+fn host_find<'a>(
+    domains: &'a DomainFilter,
+    ips: &'a IpFilter,
+    host: &str,
+    port: Option<u16>,
+) -> Option<&'a str> {
+    match parse_host(host) {
+        Ok(addr) => ips.find(addr, port),
+        Err(host) => domains.find(host, port),
+    }
+}
+
+// Whether a rule matches host, whatever ports it is limited to
+fn name_matches(domains: &DomainFilter, ips: &IpFilter, host: &str) -> bool {
+    match parse_host(host) {
+        Ok(addr) => ips.matches_host(addr),
+        Err(host) => domains.matches_host(host),
+    }
+}
+
+fn parse_host(mut host: &str) -> Result<IpAddr, &str> {
+    // An IPv6 address gets passed with the brackets, e.g. [::1],
+    // and need to be stripped before converting to an IpAddr
+    host = host.strip_prefix('[').unwrap_or(host);
+    host = host.strip_suffix(']').unwrap_or(host);
+
+    host.parse::<IpAddr>().map_err(|_| host)
 }
 
 fn load_filters(opt_spec: &Option<Vec<String>>) -> (DomainFilter, IpFilter) {
@@ -56,11 +404,344 @@ fn load_filters(opt_spec: &Option<Vec<String>>) -> (DomainFilter, IpFilter) {
 
     if let Some(ref spec) = opt_spec {
         for pattern in spec {
+            // Manifest validation rejects the patterns neither accepts
             if ips.add(pattern).is_err() {
-                domains.add(pattern);
+                if let Err(err) = domains.add(pattern) {
+                    log::warn!("ignoring egress pattern {pattern}: {err}");
+                }
             }
         }
     }
 
     (domains, ips)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::hook::{EgressHook, HookRequest};
+    use super::{EgressPolicy, ProtocolMatch};
+    use crate::manifest::{
+        DatabaseEgress, Egress, EgressDefault, EgressLimit, Protocol, ProtocolEgress,
+    };
+    use assert2::assert;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_protocol_rules() {
+        let policy = EgressPolicy::new(&Egress {
+            allow: Some(vec!["api.example.com".to_string()]),
+            deny: Some(vec!["blocked.mail.example.com".to_string()]),
+            protocols: Some(vec![ProtocolEgress {
+                protocol: Protocol::Smtp,
+                allow: vec!["*.mail.example.com".to_string()],
+                ports: None,
+            }]),
+            ..Default::default()
+        });
+
+        let smtp = Some(ProtocolMatch {
+            protocol: Protocol::Smtp,
+            require_tls: false,
+        });
+        assert!(policy.protocol("smtp.mail.example.com", 587) == smtp);
+        assert!(policy.protocol("smtp.mail.example.com", 443) == None);
+        assert!(policy.protocol("blocked.mail.example.com", 587) == None);
+        assert!(policy.protocol("api.example.com", 587) == None);
+
+        assert!(policy.is_connect_allowed("smtp.mail.example.com", 587));
+        assert!(policy.is_connect_allowed("api.example.com", 443));
+        assert!(!policy.is_connect_allowed("smtp.mail.example.com", 443));
+        assert!(!policy.is_host_allowed("smtp.mail.example.com", 587));
+
+        assert!(policy.is_name_allowed("smtp.mail.example.com"));
+        assert!(policy.is_name_allowed("api.example.com"));
+        assert!(!policy.is_name_allowed("blocked.mail.example.com"));
+        assert!(!policy.is_name_allowed("example.org"));
+    }
+
+    #[test]
+    fn test_database_rules() {
+        let policy = EgressPolicy::new(&Egress {
+            databases: Some(vec![
+                DatabaseEgress {
+                    host: "db.internal".to_string(),
+                    port: 5432,
+                    engine: None,
+                    require_tls: Some(true),
+                },
+                DatabaseEgress {
+                    host: "10.0.0.5".to_string(),
+                    port: 6033,
+                    engine: Some(Protocol::Mysql),
+                    require_tls: None,
+                },
+            ]),
+            ..Default::default()
+        });
+
+        let postgres = policy.protocol("db.internal", 5432).unwrap();
+        assert!(postgres.protocol == Protocol::Postgres);
+        assert!(postgres.require_tls);
+
+        let mysql = policy.protocol("10.0.0.5", 6033).unwrap();
+        assert!(mysql.protocol == Protocol::Mysql);
+        assert!(!mysql.require_tls);
+
+        assert!(policy.protocol("db.internal", 3306) == None);
+        assert!(!policy.is_connect_allowed("other.internal", 5432));
+    }
+
+    #[test]
+    fn test_port_rules() {
+        let policy = EgressPolicy::new(&Egress {
+            allow: Some(vec![
+                "api.example.com:443".to_string(),
+                "**.example.org".to_string(),
+                "10.0.0.0/8:8000-8100".to_string(),
+            ]),
+            deny: Some(vec!["mail.example.org:25".to_string()]),
+            http2_prior_knowledge: Some(vec!["grpc.example.org:50051".to_string()]),
+            ..Default::default()
+        });
+
+        assert!(policy.is_connect_allowed("api.example.com", 443));
+        assert!(!policy.is_connect_allowed("api.example.com", 8443));
+        assert!(!policy.is_host_allowed("api.example.com", 25));
+        assert!(policy.is_host_allowed("mail.example.org", 587));
+        assert!(!policy.is_host_allowed("mail.example.org", 25));
+        assert!(policy.is_host_allowed("10.1.2.3", 8080));
+        assert!(!policy.is_host_allowed("10.1.2.3", 443));
+
+        assert!(policy.is_http2_prior_knowledge("grpc.example.org", 50051));
+        assert!(!policy.is_http2_prior_knowledge("grpc.example.org", 80));
+
+        // Names resolve if any port of theirs is allowed
+        assert!(policy.is_name_allowed("api.example.com"));
+        assert!(policy.is_name_allowed("mail.example.org"));
+        assert!(!policy.is_name_allowed("www.example.com"));
+
+        // Wildcards and networks take a port just the same
+        let policy = EgressPolicy::new(&Egress {
+            allow: Some(vec![
+                "*.example.com:8443".to_string(),
+                "10.0.0.0/8:5432".to_string(),
+            ]),
+            ..Default::default()
+        });
+        assert!(policy.is_host_allowed("api.example.com", 8443));
+        assert!(!policy.is_host_allowed("api.example.com", 443));
+        assert!(!policy.is_host_allowed("a.b.example.com", 8443));
+        assert!(policy.is_host_allowed("10.20.30.40", 5432));
+        assert!(!policy.is_host_allowed("10.20.30.40", 5433));
+        assert!(!policy.is_host_allowed("11.0.0.1", 5432));
+        let decision = policy.decide_host("db.example.com", 8443);
+        assert!(decision.rule.as_deref() == Some("allow *.example.com:8443"));
+    }
+
+    #[test]
+    fn test_restrict() {
+        let policy = EgressPolicy::new(&Egress {
+            allow: Some(vec!["**.example.com".to_string()]),
+            databases: Some(vec![DatabaseEgress {
+                host: "db.internal".to_string(),
+                port: 5432,
+                engine: None,
+                require_tls: None,
+            }]),
+            ..Default::default()
+        });
+        assert!(policy.is_host_allowed("api.example.com", 443));
+        assert!(policy.is_host_allowed("www.example.com", 443));
+
+        policy.restrict(EgressPolicy::new(&Egress {
+            allow: Some(vec![
+                "api.example.com".to_string(),
+                "example.org".to_string(),
+                "db.internal".to_string(),
+            ]),
+            ..Default::default()
+        }));
+        assert!(policy.is_host_allowed("api.example.com", 443));
+        assert!(!policy.is_host_allowed("www.example.com", 443));
+        assert!(!policy.is_name_allowed("www.example.com"));
+        assert!(policy.protocol("db.internal", 5432).is_some());
+
+        // Nothing the policy does not allow to begin with
+        assert!(!policy.is_host_allowed("example.org", 443));
+        assert!(!policy.is_name_allowed("example.org"));
+
+        // A later restriction replaces the previous one
+        policy.restrict(EgressPolicy::new(&Egress {
+            allow: Some(vec!["www.example.com".to_string()]),
+            ..Default::default()
+        }));
+        assert!(!policy.is_host_allowed("api.example.com", 443));
+        assert!(policy.is_host_allowed("www.example.com", 443));
+        assert!(policy.protocol("db.internal", 5432).is_none());
+    }
+
+    #[test]
+    fn test_decide() {
+        let policy = EgressPolicy::new(&Egress {
+            allow: Some(vec!["**.example.com".to_string(), "10.0.0.0/8".to_string()]),
+            deny: Some(vec!["10.0.0.1:22".to_string()]),
+            protocols: Some(vec![ProtocolEgress {
+                protocol: Protocol::Smtp,
+                allow: vec!["mail.example.org".to_string()],
+                ports: None,
+            }]),
+            ..Default::default()
+        });
+
+        let decision = policy.decide_host("api.example.com", 443);
+        assert!(decision.allowed);
+        assert!(decision.rule.as_deref() == Some("allow **.example.com"));
+
+        let decision = policy.decide_host("10.0.0.1", 22);
+        assert!(!decision.allowed);
+        assert!(decision.rule.as_deref() == Some("deny 10.0.0.1:22"));
+        assert!(policy.decide_host("10.0.0.1", 80).rule.as_deref() == Some("allow 10.0.0.0/8"));
+
+        let decision = policy.decide_host("example.net", 443);
+        assert!(!decision.allowed);
+        assert!(decision.rule == None);
+
+        let decision = policy.decide_connect("mail.example.org", 587);
+        assert!(decision.allowed);
+        assert!(decision.rule.as_deref() == Some("protocol smtp"));
+        assert!(!policy.decide_host("mail.example.org", 587).allowed);
+
+        policy.restrict(EgressPolicy::new(&Egress {
+            allow: Some(vec!["www.example.com".to_string()]),
+            ..Default::default()
+        }));
+        let decision = policy.decide_host("api.example.com", 443);
+        assert!(!decision.allowed);
+        assert!(decision.rule.as_deref() == Some("policy update"));
+    }
+
+    #[test]
+    fn test_default_allow() {
+        let policy = EgressPolicy::new(&Egress {
+            default: Some(EgressDefault::Allow),
+            allow: Some(vec!["api.example.com".to_string()]),
+            deny: Some(vec![
+                "**.internal".to_string(),
+                "169.254.169.254".to_string(),
+                "mail.example.com:25".to_string(),
+            ]),
+            ..Default::default()
+        });
+
+        // Anything not denied, attributed to the allow rule if one matches
+        let decision = policy.decide_host("www.example.org", 443);
+        assert!(decision.allowed);
+        assert!(decision.rule == None);
+        let decision = policy.decide_host("api.example.com", 443);
+        assert!(decision.rule.as_deref() == Some("allow api.example.com"));
+        assert!(policy.is_host_allowed("10.0.0.1", 5432));
+
+        // Deny rules take precedence
+        let decision = policy.decide_connect("db.internal", 5432);
+        assert!(!decision.allowed);
+        assert!(decision.rule.as_deref() == Some("deny **.internal"));
+        assert!(!policy.is_host_allowed("169.254.169.254", 80));
+        assert!(!policy.is_host_allowed("mail.example.com", 25));
+        assert!(policy.is_host_allowed("mail.example.com", 587));
+
+        assert!(policy.is_name_allowed("www.example.org"));
+        assert!(policy.is_name_allowed("mail.example.com"));
+        assert!(!policy.is_name_allowed("db.internal"));
+
+        // A restriction still narrows it down
+        policy.restrict(EgressPolicy::new(&Egress {
+            allow: Some(vec!["**.example.org".to_string()]),
+            ..Default::default()
+        }));
+        assert!(policy.is_host_allowed("www.example.org", 443));
+        assert!(!policy.is_host_allowed("api.example.com", 443));
+
+        // Deny is the default either way
+        let policy = EgressPolicy::new(&Egress {
+            default: Some(EgressDefault::Deny),
+            allow: Some(vec!["api.example.com".to_string()]),
+            ..Default::default()
+        });
+        assert!(!policy.is_host_allowed("www.example.org", 443));
+        assert!(policy.is_host_allowed("api.example.com", 443));
+    }
+
+    #[test]
+    fn test_hook() {
+        // Denies port 25 of whatever the default policy allows
+        struct NoSmtp;
+        impl EgressHook for NoSmtp {
+            fn allows(&self, request: &HookRequest) -> bool {
+                request.policy.is_some() || request.port != 25
+            }
+        }
+
+        let egress = Egress {
+            allow: Some(vec!["**.example.com".to_string()]),
+            protocols: Some(vec![ProtocolEgress {
+                protocol: Protocol::Smtp,
+                allow: vec!["mail.example.org".to_string()],
+                ports: Some(vec![25, 587]),
+            }]),
+            ..Default::default()
+        };
+        let policy = EgressPolicy::new(&egress).with_hook(Arc::new(NoSmtp));
+
+        assert!(policy.is_host_allowed("api.example.com", 443));
+        let decision = policy.decide_host("api.example.com", 25);
+        assert!(!decision.allowed);
+        assert!(decision.rule.as_deref() == Some("policy hook"));
+        assert!(policy.is_connect_allowed("mail.example.org", 587));
+        assert!(!policy.is_connect_allowed("mail.example.org", 25));
+        assert!(policy.protocol("mail.example.org", 25).is_none());
+
+        // It cannot allow what the rules do not, and leaves names alone
+        assert!(!policy.is_host_allowed("example.net", 443));
+        assert!(policy.is_name_allowed("mail.example.org"));
+
+        // It is told which policy asks
+        let policy = EgressPolicy::new(&egress)
+            .with_name("relay")
+            .with_hook(Arc::new(NoSmtp));
+        assert!(policy.is_connect_allowed("mail.example.org", 25));
+    }
+
+    #[test]
+    fn test_limits() {
+        let policy = EgressPolicy::new(&Egress {
+            allow: Some(vec!["**.example.com".to_string()]),
+            limits: Some(vec![
+                EgressLimit {
+                    hosts: vec!["upload.example.com".to_string()],
+                    rate_limit: Some(1),
+                    bandwidth: None,
+                },
+                EgressLimit {
+                    hosts: vec!["**.example.com".to_string()],
+                    rate_limit: None,
+                    bandwidth: Some(1024),
+                },
+            ]),
+            ..Default::default()
+        });
+
+        // The first limit that matches applies, and is shared by its hosts
+        let limit = policy.limit("upload.example.com", 443).unwrap();
+        assert!(limit.rule() == "upload.example.com");
+        assert!(limit.admit());
+        assert!(!policy.limit("upload.example.com", 443).unwrap().admit());
+
+        let limit = policy.limit("api.example.com", 443).unwrap();
+        assert!(limit.rule() == "**.example.com");
+        assert!(policy.limit("example.org", 443).is_none());
+
+        let stats = policy.limit_stats();
+        assert!(stats.len() == 2);
+        assert!(stats[0].dropped == 1);
+    }
+}
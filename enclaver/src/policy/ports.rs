@@ -0,0 +1,102 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+/// The ports an allow or deny pattern is limited to, e.g. 443 or 8000-8100
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start: u16 = start.parse().map_err(|_| anyhow!("invalid port {start}"))?;
+        let end: u16 = end.parse().map_err(|_| anyhow!("invalid port {end}"))?;
+
+        if start == 0 || start > end {
+            return Err(anyhow!("invalid port range {s}"));
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+/// Split the port suffix off a pattern, e.g. api.example.com:443 or
+/// 10.0.0.0/8:8000-8100. IPv6 addresses and networks take one only in brackets, e.g.
+/// [fc00::/7]:443, as their colons are otherwise ambiguous.
+pub fn split(pattern: &str) -> Result<(&str, Option<PortRange>)> {
+    if let Some(bracketed) = pattern.strip_prefix('[') {
+        let (host, rest) = bracketed
+            .split_once(']')
+            .ok_or_else(|| anyhow!("missing ] in {pattern}"))?;
+        let ports = match rest {
+            "" => None,
+            _ => Some(
+                rest.strip_prefix(':')
+                    .ok_or_else(|| anyhow!("expected a port after ] in {pattern}"))?
+                    .parse()?,
+            ),
+        };
+        return Ok((host, ports));
+    }
+
+    match pattern.split_once(':') {
+        Some((host, ports)) if !ports.contains(':') => Ok((host, Some(ports.parse()?))),
+        _ => Ok((pattern, None)),
+    }
+}
+
+// Whether a pattern limited to ports matches a connection to port. With no port,
+// only patterns for every port match.
+pub(crate) fn matches(ports: Option<PortRange>, port: Option<u16>) -> bool {
+    match (ports, port) {
+        (None, _) => true,
+        (Some(range), Some(port)) => range.contains(port),
+        (Some(_), None) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split, PortRange};
+    use assert2::assert;
+
+    #[test]
+    fn test_split() {
+        let (host, ports) = split("api.example.com:443").unwrap();
+        assert!(host == "api.example.com");
+        assert!(ports == Some("443".parse().unwrap()));
+
+        let (host, ports) = split("10.0.0.0/8:8000-8100").unwrap();
+        assert!(host == "10.0.0.0/8");
+        let ports = ports.unwrap();
+        assert!(ports.contains(8000) && ports.contains(8100) && !ports.contains(8101));
+
+        assert!(split("**.example.com").unwrap() == ("**.example.com", None));
+        assert!(split("fc00::/7").unwrap() == ("fc00::/7", None));
+        assert!(split("[::1]").unwrap() == ("::1", None));
+        let (host, ports) = split("[fc00::/7]:443").unwrap();
+        assert!(host == "fc00::/7");
+        assert!(ports == Some("443".parse().unwrap()));
+
+        for invalid in [
+            "example.com:",
+            "example.com:https",
+            "example.com:0",
+            "[::1]443",
+        ] {
+            assert!(split(invalid).is_err());
+        }
+        assert!("9000-8000".parse::<PortRange>().is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! Request rules, which narrow the plain HTTP requests the egress proxy forwards to
+//! some hosts down to a set of methods, path prefixes and body sizes. The proxy only
+//! sees the requests it forwards itself, so tunnels to the hosts of a rule, through
+//! CONNECT, transparent egress or a forward, are denied altogether.
+//!
+//! The instance metadata service gets a rule of its own, which only lets the enclave
+//! open IMDSv2 sessions and read a few paths with them.
+
+use super::domain_filter::DomainFilter;
+use super::ip_filter::IpFilter;
+use super::{host_find, load_filters};
+use crate::manifest::{Imds, RequestRule, IMDS_ADDRESSES};
+
+const IMDS_TOKEN_PATH: &str = "/latest/api/token";
+const IMDS_TOKEN_TTL_HEADER: &str = "x-aws-ec2-metadata-token-ttl-seconds";
+const IMDS_TOKEN_HEADER: &str = "x-aws-ec2-metadata-token";
+
+pub struct RequestFilter {
+    rule: String,
+    domains: DomainFilter,
+    ips: IpFilter,
+    methods: Option<Vec<String>>,
+    path_prefixes: Option<Vec<String>>,
+    max_body_bytes: Option<u64>,
+
+    // For IMDS, whether reads must carry a session token
+    imds_token: Option<bool>,
+}
+
+impl RequestFilter {
+    pub fn new(spec: &RequestRule) -> Self {
+        let (domains, ips) = load_filters(&Some(spec.hosts.clone()));
+
+        Self {
+            rule: spec.hosts.join(","),
+            domains,
+            ips,
+            methods: spec.methods.clone(),
+            path_prefixes: spec.path_prefixes.clone(),
+            max_body_bytes: spec.max_body_bytes,
+            imds_token: None,
+        }
+    }
+
+    /// The rule for the instance metadata service: reads of the paths of spec, and
+    /// the PUT that opens an IMDSv2 session, without bodies
+    pub fn imds(spec: &Imds) -> Self {
+        let hosts: Vec<String> = IMDS_ADDRESSES.iter().map(|h| h.to_string()).collect();
+        let (domains, ips) = load_filters(&Some(hosts));
+
+        Self {
+            rule: "imds".to_string(),
+            domains,
+            ips,
+            methods: Some(vec!["GET".to_string()]),
+            path_prefixes: Some(spec.paths()),
+            max_body_bytes: Some(0),
+            imds_token: Some(spec.requires_token()),
+        }
+    }
+
+    pub(super) fn matches(&self, host: &str, port: u16) -> bool {
+        host_find(&self.domains, &self.ips, host, Some(port)).is_some()
+    }
+
+    /// The hosts of the rule, e.g. for the rule of an audit event
+    pub fn rule(&self) -> &str {
+        &self.rule
+    }
+
+    /// Largest request body, which the proxy holds bodies without a length to as
+    /// they are sent
+    pub fn max_body_bytes(&self) -> Option<u64> {
+        self.max_body_bytes
+    }
+
+    /// Why a request is not allowed, if it is not. The body is checked by the length
+    /// the request declares, if any, and has_header tells whether the request carries
+    /// a header, by its name in lower case.
+    pub fn check(
+        &self,
+        method: &str,
+        path: &str,
+        content_length: Option<u64>,
+        has_header: impl Fn(&str) -> bool,
+    ) -> Result<(), String> {
+        if let Some(require_token) = self.imds_token {
+            if method == "PUT" && path == IMDS_TOKEN_PATH && has_header(IMDS_TOKEN_TTL_HEADER) {
+                return Ok(());
+            }
+            if require_token && !has_header(IMDS_TOKEN_HEADER) {
+                return Err("IMDSv2 session token is required".to_string());
+            }
+        }
+
+        if let Some(ref methods) = self.methods {
+            if !methods.iter().any(|m| m == method) {
+                return Err(format!("method {method} is not allowed"));
+            }
+        }
+
+        if let Some(ref prefixes) = self.path_prefixes {
+            // What the origin makes of dot segments and encoded slashes is up to it, so
+            // such paths could escape the prefix
+            if !is_plain_path(path) || !prefixes.iter().any(|p| path.starts_with(p.as_str())) {
+                return Err(format!("path {path} is not allowed"));
+            }
+        }
+
+        match (self.max_body_bytes, content_length) {
+            (Some(max), Some(len)) if len > max => {
+                Err(format!("body of {len} bytes is over {max} bytes"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn is_plain_path(path: &str) -> bool {
+    let lower = path.to_ascii_lowercase();
+    !lower.contains("%2e")
+        && !lower.contains("%2f")
+        && !lower.contains("%5c")
+        && !path.contains('\\')
+        && !path
+            .split('/')
+            .any(|segment| segment == "." || segment == "..")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestFilter;
+    use crate::manifest::{Imds, RequestRule};
+    use assert2::assert;
+
+    #[test]
+    fn test_request_filter() {
+        let filter = RequestFilter::new(&RequestRule {
+            hosts: vec!["169.254.169.254".to_string(), "imds.internal".to_string()],
+            methods: Some(vec!["GET".to_string()]),
+            path_prefixes: Some(vec!["/latest/meta-data/".to_string()]),
+            max_body_bytes: Some(0),
+        });
+        assert!(filter.matches("imds.internal", 80));
+        assert!(!filter.matches("example.com", 80));
+        assert!(filter.rule() == "169.254.169.254,imds.internal");
+
+        let allowed = |method, path, len| filter.check(method, path, len, |_| false).is_ok();
+        assert!(allowed("GET", "/latest/meta-data/ami-id", None));
+        assert!(!allowed("PUT", "/latest/meta-data/ami-id", None));
+        assert!(!allowed("GET", "/latest/user-data", None));
+        assert!(!allowed("GET", "/latest/meta-data/../user-data", None));
+        assert!(!allowed("GET", "/latest/meta-data/%2E%2E/user-data", None));
+        assert!(allowed("GET", "/latest/meta-data/", Some(0)));
+        assert!(!allowed("GET", "/latest/meta-data/", Some(1)));
+
+        // Anything goes but what is set
+        let filter = RequestFilter::new(&RequestRule {
+            hosts: vec!["**.internal".to_string()],
+            methods: None,
+            path_prefixes: None,
+            max_body_bytes: Some(1024),
+        });
+        let allowed = |method, path, len| filter.check(method, path, len, |_| false).is_ok();
+        assert!(allowed("DELETE", "/../anything", Some(1024)));
+        assert!(!allowed("POST", "/", Some(1025)));
+    }
+
+    #[test]
+    fn test_imds() {
+        let filter = RequestFilter::imds(&Imds::default());
+        assert!(filter.matches("169.254.169.254", 80));
+        assert!(filter.matches("[fd00:ec2::254]", 80));
+        assert!(!filter.matches("169.254.169.253", 80));
+        assert!(filter.rule() == "imds");
+
+        let token = |header: &str| header == "x-aws-ec2-metadata-token";
+        let ttl = |header: &str| header == "x-aws-ec2-metadata-token-ttl-seconds";
+        let none = |_: &str| false;
+        let allowed = |method, path, has_header: &dyn Fn(&str) -> bool| {
+            filter.check(method, path, None, has_header).is_ok()
+        };
+        let region = "/latest/meta-data/placement/region";
+        let role = "/latest/meta-data/iam/security-credentials/app";
+        assert!(allowed("PUT", "/latest/api/token", &ttl));
+        assert!(allowed("GET", region, &token));
+        assert!(allowed("GET", role, &token));
+        assert!(!allowed("GET", region, &none));
+        assert!(!allowed("GET", "/latest/user-data", &token));
+        assert!(!allowed("PUT", region, &token));
+        assert!(!allowed("PUT", "/latest/api/token", &none));
+
+        // IMDSv1, with paths of its own
+        let filter = RequestFilter::imds(&Imds {
+            paths: Some(vec!["/latest/meta-data/instance-id".to_string()]),
+            require_token: Some(false),
+        });
+        let id = "/latest/meta-data/instance-id";
+        assert!(filter.check("GET", id, None, none).is_ok());
+        assert!(filter.check("GET", region, None, token).is_err());
+    }
+}
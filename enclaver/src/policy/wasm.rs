@@ -0,0 +1,159 @@
+//! Policy hooks as WebAssembly modules, run by an interpreter. A module imports
+//! nothing and exports its `memory`, `alloc(len: i32) -> i32`, which returns where to
+//! write a request of len bytes, and `decide(ptr: i32, len: i32) -> i32`, which is
+//! handed the HookRequest as JSON and returns non-zero to allow the connection.
+//!
+//! Every decision runs in an instance of its own, so nothing carries over from one
+//! connection to the next, and is limited in fuel and memory so that a module that
+//! loops or grows without bound denies the connection rather than stalls the proxy.
+
+use anyhow::{anyhow, Result};
+use wasmi::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use super::hook::{EgressHook, HookRequest};
+use crate::service_config::policy_hook_digest;
+
+/// Fuel a decision may use unless the manifest says otherwise, about as many
+/// instructions
+pub const DEFAULT_FUEL: u64 = 1_000_000;
+
+// Linear memory a decision may grow to
+const MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+pub struct WasmHook {
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+    digest: String,
+}
+
+impl WasmHook {
+    /// Compiles the module and has it decide once, so that a module that cannot answer
+    /// fails odyn at startup instead of denying every connection
+    pub fn new(wasm: &[u8], fuel: u64) -> Result<Self> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module =
+            Module::new(&engine, wasm).map_err(|err| anyhow!("invalid policy hook: {err}"))?;
+
+        let hook = Self {
+            engine,
+            module,
+            fuel,
+            digest: policy_hook_digest(wasm),
+        };
+        hook.decide(&HookRequest::new("localhost", 0, None))?;
+
+        Ok(hook)
+    }
+
+    /// Hex encoded SHA-256 of the module, as carried in the attestation user_data
+    /// under "policy_hook"
+    pub fn digest(&self) -> &str {
+        &self.digest
+    }
+
+    fn decide(&self, request: &HookRequest) -> Result<bool> {
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store
+            .add_fuel(self.fuel)
+            .map_err(|err| anyhow!("policy hook: {err}"))?;
+
+        let instance = Linker::<StoreLimits>::new(&self.engine)
+            .instantiate(&mut store, &self.module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|err| anyhow!("policy hook failed to start: {err}"))?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| anyhow!("policy hook exports no memory"))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "alloc")
+            .map_err(|err| anyhow!("policy hook alloc: {err}"))?;
+        let decide = instance
+            .get_typed_func::<(i32, i32), i32>(&store, "decide")
+            .map_err(|err| anyhow!("policy hook decide: {err}"))?;
+
+        let input = serde_json::to_vec(request)?;
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc
+            .call(&mut store, len)
+            .map_err(|err| anyhow!("policy hook alloc: {err}"))?;
+        memory
+            .write(&mut store, ptr as u32 as usize, &input)
+            .map_err(|err| anyhow!("policy hook memory: {err}"))?;
+        let verdict = decide
+            .call(&mut store, (ptr, len))
+            .map_err(|err| anyhow!("policy hook decide: {err}"))?;
+
+        Ok(verdict != 0)
+    }
+}
+
+impl EgressHook for WasmHook {
+    fn allows(&self, request: &HookRequest) -> bool {
+        match self.decide(request) {
+            Ok(allowed) => allowed,
+            Err(err) => {
+                log::warn!(
+                    "denying egress to {}:{}: {err:#}",
+                    request.host,
+                    request.port
+                );
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WasmHook, DEFAULT_FUEL};
+    use crate::policy::hook::{EgressHook, HookRequest};
+    use assert2::assert;
+
+    // A module with one page of memory, an alloc that always returns 1024, and a
+    // decide of the given body
+    fn module(decide: &[u8]) -> Vec<u8> {
+        let mut wasm = vec![
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic, version
+            0x01, 0x0c, 0x02, 0x60, 0x01, 0x7f, 0x01, 0x7f, // types: (i32) -> i32
+            0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f, //            (i32, i32) -> i32
+            0x03, 0x03, 0x02, 0x00, 0x01, // functions
+            0x05, 0x03, 0x01, 0x00, 0x01, // memory
+            0x07, 0x1b, 0x03, // exports
+            0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00, //
+            0x05, b'a', b'l', b'l', b'o', b'c', 0x00, 0x00, //
+            0x06, b'd', b'e', b'c', b'i', b'd', b'e', 0x00, 0x01, //
+        ];
+        let alloc = [0x05, 0x00, 0x41, 0x80, 0x08, 0x0b];
+        wasm.extend([0x0a, (alloc.len() + decide.len() + 2) as u8, 0x02]);
+        wasm.extend(alloc);
+        wasm.push(decide.len() as u8);
+        wasm.extend(decide);
+        wasm
+    }
+
+    #[test]
+    fn test_wasm_hook() {
+        // Allows hosts that start with an a, the first byte after {"host":"
+        let hook = WasmHook::new(
+            &module(&[
+                0x00, 0x20, 0x00, 0x2d, 0x00, 0x09, 0x41, 0xe1, 0x00, 0x46, 0x0b,
+            ]),
+            DEFAULT_FUEL,
+        )
+        .unwrap();
+        assert!(hook.allows(&HookRequest::new("api.example.com", 443, None)));
+        assert!(!hook.allows(&HookRequest::new("www.example.com", 443, None)));
+        assert!(hook.digest().len() == 64);
+
+        // A module that never answers runs out of fuel
+        let looping = module(&[0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b, 0x41, 0x00, 0x0b]);
+        assert!(WasmHook::new(&looping, DEFAULT_FUEL).is_err());
+
+        assert!(WasmHook::new(b"not wasm", DEFAULT_FUEL).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+//! Narrowing the egress policy of a running enclave. A policy update is a YAML
+//! document with allow and deny lists, signed with the key in the manifest's
+//! egress.policy_signing_key. The host pushes it to odyn over a vsock port, and odyn
+//! swaps it in as a restriction of the manifest policy. An update can only take away
+//! from what the manifest allows, so the manifest, which is measured, stays the upper
+//! bound of what the enclave can reach. Only the policy of the default egress is
+//! narrowed: the named proxies, the IMDS relay and the UDP relays, which the host
+//! checks, keep the manifest policy.
+
+use anyhow::{anyhow, Result};
+use rsa::PaddingScheme;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_vsock::VsockStream;
+
+use crate::constants::POLICY_UPDATE_PORT;
+use crate::keypair::{verify_pkcs1v15_sha256, KeyPair};
+use crate::manifest::{validate_egress_patterns, Egress};
+
+const MAX_POLICY_UPDATE_SIZE: u64 = 64 * 1024;
+
+/// A policy update document along with the signature over it, both base64 encoded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedPolicyUpdate {
+    pub document: String,
+    pub signature: String,
+}
+
+impl SignedPolicyUpdate {
+    pub fn new(document: &[u8], signature: &[u8]) -> Self {
+        Self {
+            document: base64::encode(document),
+            signature: base64::encode(signature),
+        }
+    }
+
+    /// Signs document as verify() expects, with the private key matching
+    /// policy_signing_key, e.g. to push updates from tools and tests
+    pub fn sign(keypair: &KeyPair, document: &[u8]) -> Result<Self> {
+        let signature = keypair.private.sign(
+            PaddingScheme::new_pkcs1v15_sign::<Sha256>(),
+            &Sha256::digest(document),
+        )?;
+
+        Ok(Self::new(document, &signature))
+    }
+
+    /// Verifies the RSA PKCS#1 v1.5 SHA-256 signature over the document and parses it
+    pub fn verify(&self, public_key_pem: &str) -> Result<EgressPolicyUpdate> {
+        let document = base64::decode(&self.document)?;
+        let signature = base64::decode(&self.signature)?;
+
+        verify_pkcs1v15_sha256(public_key_pem, &document, &signature)
+            .map_err(|_| anyhow!("policy update signature verification failed"))?;
+
+        EgressPolicyUpdate::parse(&document)
+    }
+}
+
+/// The egress an enclave is narrowed to. The serial must grow with every update, so
+/// an older signed document cannot be replayed to undo a newer one. odyn only keeps
+/// the last serial in memory, so once the enclave restarts, the only updates it
+/// refuses as old are those at or below egress.policy_serial_floor of the manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EgressPolicyUpdate {
+    pub serial: u64,
+    pub allow: Vec<String>,
+
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl EgressPolicyUpdate {
+    pub fn parse(document: &[u8]) -> Result<Self> {
+        let update: Self = serde_yaml::from_slice(document)?;
+        validate_egress_patterns(&update.egress())?;
+
+        Ok(update)
+    }
+
+    /// The update as an egress section, to build the restricting policy from
+    pub fn egress(&self) -> Egress {
+        Egress {
+            allow: Some(self.allow.clone()),
+            deny: Some(self.deny.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+/// What odyn answers a policy update with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum PolicyUpdateReply {
+    Applied { serial: u64 },
+    Rejected { error: String },
+}
+
+/// Pushes a policy update to the enclave with the given CID (host side). Returns the
+/// serial of the update once odyn has applied it.
+pub async fn push(cid: u32, update: &SignedPolicyUpdate) -> Result<u64> {
+    let mut conn = VsockStream::connect(cid, POLICY_UPDATE_PORT)
+        .await
+        .map_err(|e| anyhow!("failed to connect to enclave {cid}: {e}"))?;
+
+    conn.write_all(&serde_json::to_vec(update)?).await?;
+    AsyncWriteExt::shutdown(&mut conn).await?;
+
+    match read_message(&mut conn).await? {
+        PolicyUpdateReply::Applied { serial } => Ok(serial),
+        PolicyUpdateReply::Rejected { error } => {
+            Err(anyhow!("the enclave rejected the policy update: {error}"))
+        }
+    }
+}
+
+/// Reads a JSON message, up to the point the peer shuts down its side
+pub async fn read_message<S, T>(conn: &mut S) -> Result<T>
+where
+    S: AsyncRead + Unpin,
+    T: for<'de> Deserialize<'de>,
+{
+    let mut buf = Vec::new();
+    conn.take(MAX_POLICY_UPDATE_SIZE + 1)
+        .read_to_end(&mut buf)
+        .await?;
+
+    if buf.len() as u64 > MAX_POLICY_UPDATE_SIZE {
+        return Err(anyhow!(
+            "policy update exceeds {MAX_POLICY_UPDATE_SIZE} bytes"
+        ));
+    }
+
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+/// Answers a policy update (enclave side)
+pub async fn reply<S: AsyncWrite + Unpin>(conn: &mut S, reply: &PolicyUpdateReply) -> Result<()> {
+    conn.write_all(&serde_json::to_vec(reply)?).await?;
+    conn.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EgressPolicyUpdate, PolicyUpdateReply, SignedPolicyUpdate};
+    use crate::keypair::KeyPair;
+    use assert2::assert;
+
+    #[test]
+    fn test_verify() {
+        let keypair = KeyPair::generate().unwrap();
+        let public_key = keypair.public_key_as_pem().unwrap();
+
+        let document = b"serial: 3\nallow:\n  - api.example.com\ndeny:\n  - 10.0.0.0/8\n";
+        let signed = SignedPolicyUpdate::sign(&keypair, document).unwrap();
+        let update = signed.verify(&public_key).unwrap();
+        assert!(update.serial == 3);
+        assert!(update.allow == vec!["api.example.com".to_string()]);
+        assert!(update.deny == vec!["10.0.0.0/8".to_string()]);
+
+        let tampered = SignedPolicyUpdate {
+            document: base64::encode(b"serial: 3\nallow:\n  - \"**\"\n"),
+            ..signed
+        };
+        assert!(tampered.verify(&public_key).is_err());
+
+        let other = KeyPair::generate().unwrap();
+        let signed = SignedPolicyUpdate::sign(&other, document).unwrap();
+        assert!(signed.verify(&public_key).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let update = EgressPolicyUpdate::parse(b"serial: 1\nallow: []\n").unwrap();
+        assert!(update.allow.is_empty());
+        assert!(update.deny.is_empty());
+
+        assert!(EgressPolicyUpdate::parse(b"allow: [example.com]\n").is_err());
+        assert!(EgressPolicyUpdate::parse(b"serial: 1\nallow: [\"example.com:https\"]\n").is_err());
+        assert!(EgressPolicyUpdate::parse(b"serial: 1\nallow: []\nprotocols: []\n").is_err());
+    }
+
+    #[test]
+    fn test_reply() {
+        let applied = serde_json::to_string(&PolicyUpdateReply::Applied { serial: 2 }).unwrap();
+        assert!(applied == r#"{"result":"applied","serial":2}"#);
+
+        let rejected: PolicyUpdateReply =
+            serde_json::from_str(r#"{"result":"rejected","error":"stale serial"}"#).unwrap();
+        assert!(
+            rejected
+                == PolicyUpdateReply::Rejected {
+                    error: "stale serial".to_string()
+                }
+        );
+    }
+}
@@ -0,0 +1,234 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::cpu_topology;
+
+const NE_CPUS_PATH: &str = "/sys/module/nitro_enclaves/parameters/ne_cpus";
+const ALLOCATOR_CONFIG_PATH: &str = "/etc/nitro_enclaves/allocator.yaml";
+const HUGEPAGES_DIR: &str = "/sys/kernel/mm/hugepages";
+
+// The EIF is unpacked into the memory of the enclave, which AWS recommends be at
+// least this many times its size
+const EIF_MEMORY_FACTOR: u64 = 4;
+
+// Checks that can be run against the host before starting an enclave, to catch
+// problems without actually launching anything.
+#[derive(Debug)]
+pub enum CheckResult {
+    Ok(String),
+    Failed(String),
+    Skipped(String),
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: String,
+    pub result: CheckResult,
+}
+
+impl Check {
+    pub fn failed(&self) -> bool {
+        matches!(self.result, CheckResult::Failed(_))
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.result {
+            CheckResult::Ok(msg) => write!(f, "[ok]      {}: {msg}", self.name),
+            CheckResult::Failed(msg) => write!(f, "[failed]  {}: {msg}", self.name),
+            CheckResult::Skipped(msg) => write!(f, "[skipped] {}: {msg}", self.name),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AllocatorConfig {
+    memory_mib: Option<i64>,
+    cpu_count: Option<usize>,
+    cpu_pool: Option<String>,
+}
+
+fn load_allocator_config() -> Result<AllocatorConfig> {
+    let raw = std::fs::read(ALLOCATOR_CONFIG_PATH)?;
+    Ok(serde_yaml::from_slice(&raw)?)
+}
+
+/// Checks that the CPU pool set aside for enclaves is large enough. Prefers the pool
+/// the driver actually holds, and falls back to the allocator configuration.
+pub fn check_cpus(cpu_count: i32) -> Check {
+    let available = match std::fs::read_to_string(NE_CPUS_PATH) {
+        Ok(cpus) => parse_cpu_list(&cpus).map(|n| (n, "enclave CPU pool")),
+        Err(_) => load_allocator_config().and_then(|config| {
+            let n = match (config.cpu_count, config.cpu_pool) {
+                (Some(n), _) => n,
+                (None, Some(pool)) => parse_cpu_list(&pool)?,
+                (None, None) => return Err(anyhow!("no CPUs configured")),
+            };
+            Ok((n, "allocator configuration"))
+        }),
+    };
+
+    let result = match available {
+        Ok((n, source)) if n >= cpu_count as usize => {
+            CheckResult::Ok(format!("{cpu_count} requested, {n} in {source}"))
+        }
+        Ok((n, source)) => {
+            CheckResult::Failed(format!("{cpu_count} requested, only {n} in {source}"))
+        }
+        Err(err) => CheckResult::Skipped(format!("unable to determine enclave CPUs: {err}")),
+    };
+
+    Check {
+        name: "cpus".to_string(),
+        result,
+    }
+}
+
+/// Checks that enough free huge pages are available to back the enclave memory.
+pub fn check_memory(memory_mb: i32) -> Check {
+    let result = match free_hugepages_mib(Path::new(HUGEPAGES_DIR)) {
+        Ok(free) if free >= memory_mb as u64 => {
+            CheckResult::Ok(format!("{memory_mb} MiB requested, {free} MiB free"))
+        }
+        Ok(free) => CheckResult::Failed(format!(
+            "{memory_mb} MiB requested, only {free} MiB of huge pages free"
+        )),
+        Err(_) => match load_allocator_config() {
+            Ok(AllocatorConfig {
+                memory_mib: Some(reserved),
+                ..
+            }) if reserved < memory_mb.into() => CheckResult::Failed(format!(
+                "{memory_mb} MiB requested, allocator only reserves {reserved} MiB"
+            )),
+            Ok(AllocatorConfig {
+                memory_mib: Some(reserved),
+                ..
+            }) => CheckResult::Ok(format!(
+                "{memory_mb} MiB requested, allocator reserves {reserved} MiB"
+            )),
+            _ => CheckResult::Skipped("unable to determine enclave memory".to_string()),
+        },
+    };
+
+    Check {
+        name: "memory".to_string(),
+        result,
+    }
+}
+
+/// Warns if memory_mb looks too small for an enclave booted from an EIF of eif_size
+/// bytes, before nitro-cli or the app inside run out of it
+pub fn eif_memory_warning(eif_size: u64, memory_mb: i32) -> Option<String> {
+    let mib = |bytes: u64| (bytes + 1024 * 1024 - 1) / (1024 * 1024);
+    let needed_mb = mib(eif_size * EIF_MEMORY_FACTOR);
+    (needed_mb > memory_mb.max(0) as u64).then(|| {
+        format!(
+            "{memory_mb} MiB of enclave memory looks too small for an EIF of {} MiB, \
+             at least {needed_mb} MiB are recommended",
+            mib(eif_size)
+        )
+    })
+}
+
+/// Checks that nothing else is already listening on a port we need to bind, on addr
+/// or on all addresses if it is unspecified.
+pub fn check_port(addr: IpAddr, port: u16) -> Check {
+    let result = match TcpListener::bind((addr, port)) {
+        Ok(_) => CheckResult::Ok("available".to_string()),
+        Err(err) => CheckResult::Failed(format!("unable to bind: {err}")),
+    };
+
+    let name = match addr.is_unspecified() {
+        true => format!("port {port}"),
+        false => format!("port {}", SocketAddr::new(addr, port)),
+    };
+
+    Check { name, result }
+}
+
+fn free_hugepages_mib(dir: &Path) -> Result<u64> {
+    let mut free_kb = 0;
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let page_kb: u64 = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("hugepages-"))
+            .and_then(|n| n.strip_suffix("kB"))
+            .ok_or_else(|| anyhow!("unexpected entry {name:?}"))?
+            .parse()?;
+
+        let free: u64 = std::fs::read_to_string(entry.path().join("free_hugepages"))?
+            .trim()
+            .parse()?;
+
+        free_kb += free * page_kb;
+    }
+
+    Ok(free_kb / 1024)
+}
+
+// Counts the CPUs in a list such as "1,3-5"
+fn parse_cpu_list(list: &str) -> Result<usize> {
+    Ok(cpu_topology::parse_cpu_list(list)?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_port, eif_memory_warning, free_hugepages_mib, parse_cpu_list};
+    use assert2::assert;
+    use std::net::{IpAddr, Ipv4Addr, TcpListener};
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert!(parse_cpu_list("1,3\n").unwrap() == 2);
+        assert!(parse_cpu_list("1,3-5").unwrap() == 4);
+        assert!(parse_cpu_list("").unwrap() == 0);
+        assert!(parse_cpu_list("5-3").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    #[test]
+    fn test_free_hugepages() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, free) in [
+            ("hugepages-2048kB", "512\n"),
+            ("hugepages-1048576kB", "2\n"),
+        ] {
+            std::fs::create_dir(dir.path().join(name)).unwrap();
+            std::fs::write(dir.path().join(name).join("free_hugepages"), free).unwrap();
+        }
+
+        assert!(free_hugepages_mib(dir.path()).unwrap() == 1024 + 2048);
+    }
+
+    #[test]
+    fn test_eif_memory_warning() {
+        let eif_size = 300 * 1024 * 1024;
+        assert!(eif_memory_warning(eif_size, 1200).is_none());
+
+        let warning = eif_memory_warning(eif_size, 1024).unwrap();
+        assert!(warning.contains("at least 1200 MiB"));
+    }
+
+    #[test]
+    fn test_check_port() {
+        let any = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        let listener = TcpListener::bind((any, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        assert!(check_port(any, port).failed());
+        assert!(check_port(any, port).name == format!("port {port}"));
+        drop(listener);
+        assert!(!check_port(any, port).failed());
+
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(check_port(localhost, port).name == format!("port 127.0.0.1:{port}"));
+    }
+}
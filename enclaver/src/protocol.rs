@@ -0,0 +1,362 @@
+//! What enclaver-run and odyn send each other over vsock. Both sides build on these
+//! types, and supervisors other than enclaver-run can implement the host side with
+//! them.
+//!
+//! - Status, vsock port 17000 of the enclave: odyn writes each connection a line of
+//!   JSON, a [`Versioned`] [`EnclaveStatus`], whenever the status of the app changes,
+//!   and with fresh stats every 10 seconds while the app runs.
+//! - Egress, vsock port 17002 of the host, or those of `egress.relays`: odyn opens a
+//!   connection per tunnel and sends a [`Versioned`] [`ConnectRequest`], framed as
+//!   [`JsonTransport`] does. The host answers with a [`ConnectResponse`] and, once it
+//!   has connected, relays bytes from then on.
+//!
+//! Messages only grow: a newer version adds fields with defaults, or variants, and
+//! never removes or repurposes one. A receiver reads the messages of any version and
+//! ignores what it does not know.
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::policy::limits::LimitStats;
+use crate::proxy::audit::DenialStats;
+use crate::proxy::error::ProxyError;
+use crate::status::{FatalCode, LogStats, MemoryStats};
+
+/// The version of the protocol this build speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A message along with the version of the protocol its sender speaks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    /// 0 for senders from before the protocol was versioned, which leave it out
+    #[serde(default)]
+    pub version: u32,
+
+    #[serde(flatten)]
+    pub message: T,
+}
+
+impl<T> Versioned<T> {
+    pub fn new(message: T) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            message,
+        }
+    }
+}
+
+/// The status of the app, as odyn reports it on the status port
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum EnclaveStatus {
+    /// Older odyn versions report no stats
+    Running {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        memory: Option<MemoryStats>,
+
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        egress_limits: Vec<LimitStats>,
+
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        egress_denials: Vec<DenialStats>,
+
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        app_log: Option<LogStats>,
+    },
+
+    Exited {
+        code: i32,
+    },
+
+    /// The app was killed by the signal. It is sent by name, e.g. "SIGTERM", and read
+    /// by name or number.
+    Signaled {
+        #[serde(with = "signal_name")]
+        signal: i32,
+    },
+
+    /// odyn gave up before or while running the app. Older odyn versions send no code.
+    Fatal {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<FatalCode>,
+        error: String,
+    },
+}
+
+impl EnclaveStatus {
+    /// Parses a line from the status port
+    pub fn parse(line: &str) -> Result<Versioned<Self>, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+
+    /// A line for the status port, newline included
+    pub fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(&Versioned::new(self)).unwrap();
+        line.push('\n');
+        line
+    }
+}
+
+// Older enclaver-run versions only read signals by name
+mod signal_name {
+    use nix::sys::signal::Signal;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(signal: &i32, serializer: S) -> Result<S::Ok, S::Error> {
+        match Signal::try_from(*signal) {
+            Ok(signal) => serializer.serialize_str(signal.as_str()),
+            Err(_) => serializer.serialize_i32(*signal),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RawSignal {
+            Number(i32),
+            Name(String),
+        }
+
+        match RawSignal::deserialize(deserializer)? {
+            RawSignal::Number(signal) => Ok(signal),
+            RawSignal::Name(name) => name
+                .parse::<Signal>()
+                .map(|signal| signal as i32)
+                .map_err(|_| serde::de::Error::custom(format!("unknown signal {name}"))),
+        }
+    }
+}
+
+/// Asks the host to connect out, once per connection to an egress relay
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectRequest {
+    pub host: String,
+    pub port: u16,
+
+    /// Name of the egress policy that allowed the connection, unset for the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+}
+
+impl ConnectRequest {
+    pub fn new(host: String, port: u16, policy: Option<&str>) -> Self {
+        Self {
+            host,
+            port,
+            policy: policy.map(str::to_string),
+        }
+    }
+
+    /// A health check of the relay, which names no host. The host answers it with Ok
+    /// without connecting anywhere.
+    pub fn probe() -> Self {
+        Self::new(String::new(), 0, None)
+    }
+
+    pub fn is_probe(&self) -> bool {
+        self.host.is_empty()
+    }
+}
+
+/// What the host answers a connect request with. It is not versioned, the request
+/// it answers was.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConnectResponse {
+    Ok,
+
+    /// The raw OS error of the failed connect, 0 if it had none
+    Err {
+        os_code: i32,
+        message: String,
+    },
+}
+
+impl ConnectResponse {
+    pub fn failed(err: &std::io::Error) -> Self {
+        Self::Err {
+            os_code: err.raw_os_error().unwrap_or(0i32),
+            message: err.to_string(),
+        }
+    }
+}
+
+/// JSON messages framed by their length, as 2 little endian bytes
+#[async_trait]
+pub trait JsonTransport: Sized + Sync {
+    async fn send<W: AsyncWrite + Unpin + Send>(&self, w: &mut W) -> Result<(), ProxyError>;
+    async fn recv<R: AsyncRead + Unpin + Send>(r: &mut R) -> Result<Self, ProxyError>;
+}
+
+#[async_trait]
+impl<M: Serialize + DeserializeOwned + Sync> JsonTransport for M {
+    async fn send<W: AsyncWrite + Unpin + Send>(&self, w: &mut W) -> Result<(), ProxyError> {
+        // Frame and serialize
+        // use JSON serialization to avoid pulling in another dependency
+        let msg = serde_json::to_vec(self)?;
+        // frame it by a 2 byte length
+        let len = msg.len() as u16;
+        let mut pkt = Vec::with_capacity(2 + msg.len());
+        pkt.extend_from_slice(&len.to_le_bytes());
+        pkt.extend_from_slice(&msg);
+        w.write_all(&pkt).await?;
+        Ok(())
+    }
+
+    async fn recv<R: AsyncRead + Unpin + Send>(r: &mut R) -> Result<Self, ProxyError> {
+        let mut len_buf = [0u8; 2];
+        r.read_exact(&mut len_buf).await?;
+        let len = u16::from_le_bytes(len_buf);
+
+        let mut msg = vec![0u8; len as usize];
+        r.read_exact(&mut msg).await?;
+
+        let req: Self = serde_json::from_slice(&msg)?;
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ConnectRequest, ConnectResponse, EnclaveStatus, JsonTransport, Versioned, PROTOCOL_VERSION,
+    };
+    use crate::status::{FatalCode, MemoryStats};
+    use assert2::assert;
+    use serde::{Deserialize, Serialize};
+
+    // A connect request as odyn and enclaver-run read it before the protocol was
+    // versioned
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct ConnectRequestV0 {
+        host: String,
+        port: u16,
+        policy: Option<String>,
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let status = EnclaveStatus::parse(r#"{"status":"signaled","signal":"SIGTERM"}"#);
+        assert!(status.unwrap().message == EnclaveStatus::Signaled { signal: 15 });
+
+        let status = EnclaveStatus::parse(r#"{"status":"signaled","signal":9}"#);
+        assert!(status.unwrap().message == EnclaveStatus::Signaled { signal: 9 });
+
+        assert!(EnclaveStatus::parse(r#"{"status":"signaled","signal":"SIGNOPE"}"#).is_err());
+
+        let status = EnclaveStatus::parse(r#"{"status":"exited","code":3}"#).unwrap();
+        assert!(status.message == EnclaveStatus::Exited { code: 3 });
+
+        let status = EnclaveStatus::parse(r#"{"status":"running"}"#).unwrap();
+        assert!(
+            status.message
+                == EnclaveStatus::Running {
+                    memory: None,
+                    egress_limits: Vec::new(),
+                    egress_denials: Vec::new(),
+                    app_log: None,
+                }
+        );
+
+        let status = EnclaveStatus::parse(
+            r#"{"status":"running","memory":{"heap_bytes":4096,"heap_peak_bytes":8192,"buffer_bytes":0}}"#,
+        )
+        .unwrap();
+        assert!(
+            status.message
+                == EnclaveStatus::Running {
+                    memory: Some(MemoryStats {
+                        heap_bytes: 4096,
+                        heap_peak_bytes: 8192,
+                        buffer_bytes: 0,
+                        buffer_limit_bytes: None,
+                    }),
+                    egress_limits: Vec::new(),
+                    egress_denials: Vec::new(),
+                    app_log: None,
+                }
+        );
+
+        let status = EnclaveStatus::parse(
+            r#"{"status":"running","app_log":{"produced_bytes":300,"trimmed_bytes":100,"delivered_bytes":250,"skipped_bytes":50}}"#,
+        )
+        .unwrap();
+        let app_log = match status.message {
+            EnclaveStatus::Running { app_log, .. } => app_log.unwrap(),
+            _ => panic!("not running"),
+        };
+        assert!(app_log.skipped_bytes == 50);
+        assert!(app_log.subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_status_versions() {
+        // Lines of odyn from before the protocol was versioned
+        let status = EnclaveStatus::parse(r#"{"status":"fatal","error":"boom"}"#).unwrap();
+        assert!(status.version == 0);
+        assert!(
+            status.message
+                == EnclaveStatus::Fatal {
+                    code: None,
+                    error: "boom".to_string(),
+                }
+        );
+
+        // Lines of newer versions, with what this one does not know of
+        let status =
+            EnclaveStatus::parse(r#"{"version":7,"status":"exited","code":1,"took_ms":30}"#);
+        let status = status.unwrap();
+        assert!(status.version == 7);
+        assert!(status.message == EnclaveStatus::Exited { code: 1 });
+
+        // The lines of this version only add the version to what older hosts read
+        let line = EnclaveStatus::Signaled { signal: 15 }.to_line();
+        assert!(line == "{\"version\":1,\"status\":\"signaled\",\"signal\":\"SIGTERM\"}\n");
+        let fatal = EnclaveStatus::Fatal {
+            code: Some(FatalCode::ConfigError),
+            error: "boom".to_string(),
+        };
+        let line = fatal.to_line();
+        assert!(
+            line == "{\"version\":1,\"status\":\"fatal\",\"code\":\"config_error\",\"error\":\"boom\"}\n"
+        );
+        let status = EnclaveStatus::parse(line.trim_end()).unwrap();
+        assert!(status.version == PROTOCOL_VERSION);
+        assert!(status.message == fatal);
+    }
+
+    #[tokio::test]
+    async fn test_connect_versions() {
+        let request = ConnectRequest::new("example.com".to_string(), 443, Some("payments"));
+
+        // What this version sends, older hosts read
+        let (mut enclave, mut host) = tokio::io::duplex(1024);
+        Versioned::new(request.clone())
+            .send(&mut enclave)
+            .await
+            .unwrap();
+        let old = ConnectRequestV0::recv(&mut host).await.unwrap();
+        assert!(
+            old == ConnectRequestV0 {
+                host: "example.com".to_string(),
+                port: 443,
+                policy: Some("payments".to_string()),
+            }
+        );
+
+        // What older odyn versions send, this version reads
+        let json = r#"{"host":"example.com","port":443,"policy":"payments"}"#;
+        let old: Versioned<ConnectRequest> = serde_json::from_str(json).unwrap();
+        assert!(old.version == 0);
+        assert!(old.message == request);
+
+        let probe = serde_json::to_string(&Versioned::new(ConnectRequest::probe())).unwrap();
+        assert!(probe == r#"{"version":1,"host":"","port":0}"#);
+
+        let ok = serde_json::to_string(&ConnectResponse::Ok).unwrap();
+        assert!(ok == r#""Ok""#);
+        let refused = std::io::Error::from_raw_os_error(111);
+        let err = serde_json::to_value(ConnectResponse::failed(&refused)).unwrap();
+        assert!(err["Err"]["os_code"] == 111);
+    }
+}
@@ -0,0 +1,530 @@
+//! Audit log of the egress decisions of the HTTP proxy. Every connection or request
+//! the proxy allows or denies becomes an event, kept in a bounded buffer inside the
+//! enclave and streamed to the host on a vsock port of its own.
+//!
+//! Events are numbered. A host that connects sends the number of the next event it
+//! wants and gets every event from there on, as JSON lines, so it can resume after a
+//! reconnect. Events trimmed from the buffer before the host read them show up as a
+//! gap in the numbers. The host may ask for the stream to be compressed, see
+//! [crate::compression].
+
+use std::collections::{BTreeMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use anyhow::Result;
+use futures::StreamExt;
+use hyper::Body;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, BufReader, ReadBuf};
+use tokio::sync::watch;
+
+use crate::compression::{self, Compression, StreamRequest};
+use crate::journal::unix_time;
+use crate::policy::Decision;
+
+/// Events kept for a host that is not reading, past which the oldest are dropped
+pub const AUDIT_LOG_CAPACITY: usize = 10_000;
+
+const AUDIT_LINE_MAX_LEN: u64 = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Allowed,
+    Denied,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    // A CONNECT tunnel
+    Connect,
+    // A plain HTTP request forwarded by the proxy
+    Request,
+    // An open tunnel closed because a policy update took away what it was allowed for
+    Revoked,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub seq: u64,
+    pub timestamp: u64,
+    pub kind: AuditKind,
+    pub host: String,
+    pub port: u16,
+
+    // Name of the egress policy, unset for the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+
+    // The rule that decided, unset if none matched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+
+    pub verdict: Verdict,
+
+    // From the application to the remote, and back
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl AuditEvent {
+    pub fn new(
+        kind: AuditKind,
+        host: &str,
+        port: u16,
+        policy: Option<&str>,
+        decision: &Decision,
+    ) -> Self {
+        Self {
+            seq: 0,
+            timestamp: unix_time(),
+            kind,
+            host: host.to_string(),
+            port,
+            policy: policy.map(str::to_string),
+            rule: decision.rule.clone(),
+            verdict: match decision.allowed {
+                true => Verdict::Allowed,
+                false => Verdict::Denied,
+            },
+            bytes_sent: 0,
+            bytes_received: 0,
+        }
+    }
+}
+
+/// How many connections and requests the HTTP proxy of an egress policy denied,
+/// reported to the host
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DenialStats {
+    /// Name of the egress proxy, unset for the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+
+    /// Connections and requests denied, including those refused by a rate limit
+    pub denied: u64,
+}
+
+struct Events {
+    lines: VecDeque<(u64, Arc<Vec<u8>>)>,
+    next: u64,
+    capacity: usize,
+}
+
+impl Events {
+    // The number of the first event a host that wants seq gets. A number past the
+    // last event cannot come from this log, e.g. the host saw a previous boot of the
+    // enclave, so it starts over from the oldest event.
+    fn resume(&self, seq: u64) -> u64 {
+        let head = self.next - self.lines.len() as u64;
+        if seq < head || seq > self.next {
+            head
+        } else {
+            seq
+        }
+    }
+
+    fn read(&self, seq: &mut u64) -> Vec<Arc<Vec<u8>>> {
+        let lines: Vec<_> = self
+            .lines
+            .iter()
+            .skip_while(|(n, _)| n < seq)
+            .map(|(_, line)| line.clone())
+            .collect();
+
+        *seq = self.next;
+        lines
+    }
+}
+
+/// The audit events of one enclave, shared by all of its egress proxies
+#[derive(Clone)]
+pub struct AuditLog {
+    events: Arc<Mutex<Events>>,
+    tail: Arc<watch::Sender<u64>>,
+
+    // Denied events by policy, which unlike the events themselves are never dropped
+    denials: Arc<Mutex<BTreeMap<Option<String>, u64>>>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::with_capacity(AUDIT_LOG_CAPACITY)
+    }
+}
+
+impl AuditLog {
+    pub fn with_capacity(capacity: usize) -> Self {
+        let (tail, _) = watch::channel(0);
+
+        Self {
+            events: Arc::new(Mutex::new(Events {
+                lines: VecDeque::new(),
+                next: 0,
+                capacity,
+            })),
+            tail: Arc::new(tail),
+            denials: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    pub fn record(&self, mut event: AuditEvent) {
+        if event.verdict == Verdict::Denied {
+            *self
+                .denials
+                .lock()
+                .unwrap()
+                .entry(event.policy.clone())
+                .or_default() += 1;
+        }
+
+        let mut events = self.events.lock().unwrap();
+        event.seq = events.next;
+
+        let mut line = match serde_json::to_vec(&event) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("failed to serialize an audit event: {err}");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        if events.lines.len() == events.capacity {
+            events.lines.pop_front();
+        }
+        events.lines.push_back((event.seq, Arc::new(line)));
+        events.next += 1;
+
+        self.tail.send_replace(events.next);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn events(&self) -> Vec<AuditEvent> {
+        self.events
+            .lock()
+            .unwrap()
+            .lines
+            .iter()
+            .map(|(_, line)| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    pub fn denials(&self) -> Vec<DenialStats> {
+        self.denials
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(policy, denied)| DenialStats {
+                policy: policy.clone(),
+                denied: *denied,
+            })
+            .collect()
+    }
+
+    /// Starts recording an allowed connection or request, which is logged along with
+    /// the bytes counted on the record once the last reference to it is dropped
+    pub fn start(&self, event: AuditEvent) -> Arc<AuditRecord> {
+        Arc::new(AuditRecord {
+            log: self.clone(),
+            event: Some(event),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+        })
+    }
+
+    /// Streams the events to the host on the given vsock port (enclave side)
+    pub async fn serve(self, port: u32) -> Result<()> {
+        let mut incoming = crate::vsock::serve(port)?;
+
+        while let Some(conn) = incoming.next().await {
+            let log = self.clone();
+            tokio::task::spawn(async move {
+                if let Err(err) = log.stream(conn).await {
+                    debug!("audit stream ended: {err}");
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn stream<S: AsyncRead + AsyncWrite + Unpin>(&self, mut conn: S) -> Result<()> {
+        let request = StreamRequest::read(&mut conn).await?;
+
+        let mut tail = self.tail.subscribe();
+        let mut seq = self.events.lock().unwrap().resume(request.cursor);
+        let mut writer = request.start(conn, seq).await?;
+
+        loop {
+            // Written at once, so that compression sees more than one event
+            let lines = self.events.lock().unwrap().read(&mut seq);
+            let lines: Vec<u8> = lines.iter().flat_map(|line| line.iter().copied()).collect();
+            writer.write(&lines).await?;
+
+            tail.changed().await?;
+        }
+    }
+}
+
+/// An allowed connection or request in progress
+pub struct AuditRecord {
+    log: AuditLog,
+    event: Option<AuditEvent>,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl AuditRecord {
+    pub fn add_sent(&self, n: u64) {
+        self.sent.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, n: u64) {
+        self.received.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Counts the bytes of a body sent to the remote
+    pub fn count_sent(self: &Arc<Self>, body: Body) -> Body {
+        self.count(body, true)
+    }
+
+    /// Counts the bytes of a body received from the remote
+    pub fn count_received(self: &Arc<Self>, body: Body) -> Body {
+        self.count(body, false)
+    }
+
+    fn count(self: &Arc<Self>, body: Body, sent: bool) -> Body {
+        // An empty body stays one, so no framing is added to it
+        if hyper::body::HttpBody::is_end_stream(&body) {
+            return body;
+        }
+
+        let record = self.clone();
+        Body::wrap_stream(body.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                match sent {
+                    true => record.add_sent(chunk.len() as u64),
+                    false => record.add_received(chunk.len() as u64),
+                }
+            }
+        }))
+    }
+}
+
+/// A stream to the application, counting what it sends and receives on a record
+pub struct Counted<S> {
+    inner: S,
+    record: Arc<AuditRecord>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, record: Arc<AuditRecord>) -> Self {
+        Self { inner, record }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.record.add_sent((buf.filled().len() - before) as u64);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.record.add_received(n as u64);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Drop for AuditRecord {
+    fn drop(&mut self) {
+        if let Some(mut event) = self.event.take() {
+            event.bytes_sent = *self.sent.get_mut();
+            event.bytes_received = *self.received.get_mut();
+            self.log.record(event);
+        }
+    }
+}
+
+/// Reads the events of the enclave with the given CID from its audit port (host
+/// side), starting at the event numbered cursor, and passes each line to sink.
+/// cursor is left past the last event read, for the next connection to resume from.
+pub async fn follow<F>(
+    cid: u32,
+    port: u32,
+    cursor: &mut u64,
+    compression: &mut Compression,
+    mut sink: F,
+) -> Result<()>
+where
+    F: FnMut(&str) -> Result<()>,
+{
+    let (conn, start) = compression::connect(cid, port, *cursor, compression).await?;
+    if start > *cursor {
+        warn!(
+            "{} egress audit events were lost while disconnected",
+            start - *cursor
+        );
+    }
+    *cursor = start;
+
+    let mut reader = BufReader::new(conn);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let n = (&mut reader)
+            .take(AUDIT_LINE_MAX_LEN)
+            .read_line(&mut line)
+            .await?;
+        if n == 0 || !line.ends_with('\n') {
+            return Ok(());
+        }
+
+        let event: AuditEvent = serde_json::from_str(&line)?;
+        if event.seq > *cursor {
+            warn!(
+                "{} egress audit events were dropped by the enclave",
+                event.seq - *cursor
+            );
+        }
+        *cursor = event.seq + 1;
+
+        sink(line.trim_end())?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuditEvent, AuditKind, AuditLog, Verdict};
+    use crate::policy::Decision;
+    use assert2::assert;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    fn event(host: &str) -> AuditEvent {
+        let decision = Decision {
+            allowed: true,
+            rule: Some("allow **".to_string()),
+        };
+        AuditEvent::new(AuditKind::Connect, host, 443, None, &decision)
+    }
+
+    async fn read_event<R: AsyncBufReadExt + Unpin>(r: &mut R) -> AuditEvent {
+        let mut line = String::new();
+        r.read_line(&mut line).await.unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn test_record() {
+        let log = AuditLog::default();
+        let record = log.start(event("example.com"));
+        record.add_sent(10);
+        record.add_received(20);
+        assert!(log.events.lock().unwrap().lines.is_empty());
+        drop(record);
+
+        let mut seq = 0;
+        let lines = log.events.lock().unwrap().read(&mut seq);
+        assert!(seq == 1);
+        let recorded: AuditEvent = serde_json::from_slice(&lines[0]).unwrap();
+        assert!(recorded.verdict == Verdict::Allowed);
+        assert!(recorded.rule.as_deref() == Some("allow **"));
+        assert!(recorded.bytes_sent == 10);
+        assert!(recorded.bytes_received == 20);
+
+        let json: serde_json::Value = serde_json::from_slice(&lines[0]).unwrap();
+        assert!(json["kind"] == "connect");
+        assert!(json["verdict"] == "allowed");
+        assert!(json.get("policy").is_none());
+    }
+
+    #[test]
+    fn test_denials() {
+        let log = AuditLog::with_capacity(1);
+        let denied = Decision {
+            allowed: false,
+            rule: None,
+        };
+        for (host, policy) in [("a.com", None), ("b.com", None), ("c.com", Some("uploads"))] {
+            log.record(AuditEvent::new(
+                AuditKind::Connect,
+                host,
+                443,
+                policy,
+                &denied,
+            ));
+        }
+        log.record(event("d.example.com"));
+
+        // Still counted once the events are trimmed
+        let denials = log.denials();
+        assert!(denials.len() == 2);
+        assert!(denials[0].policy.is_none() && denials[0].denied == 2);
+        assert!(denials[1].policy.as_deref() == Some("uploads") && denials[1].denied == 1);
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let log = AuditLog::with_capacity(2);
+        for host in ["a.example.com", "b.example.com", "c.example.com"] {
+            log.record(event(host));
+        }
+
+        // The first event was trimmed, so the stream starts at the second
+        let (host, enclave) = tokio::io::duplex(64 * 1024);
+        let streamer = log.clone();
+        tokio::task::spawn(async move { streamer.stream(enclave).await });
+
+        let (r, mut w) = tokio::io::split(host);
+        let mut r = BufReader::new(r);
+        w.write_u64(0).await.unwrap();
+        assert!(r.read_u64().await.unwrap() == 1);
+        assert!(read_event(&mut r).await.host == "b.example.com");
+        assert!(read_event(&mut r).await.host == "c.example.com");
+
+        // New events follow as they are recorded
+        log.record(event("d.example.com"));
+        let next = read_event(&mut r).await;
+        assert!(next.seq == 3);
+        assert!(next.host == "d.example.com");
+
+        // A reconnect resumes where the last stream ended
+        let (host, enclave) = tokio::io::duplex(64 * 1024);
+        let streamer = log.clone();
+        tokio::task::spawn(async move { streamer.stream(enclave).await });
+
+        let (r, mut w) = tokio::io::split(host);
+        let mut r = BufReader::new(r);
+        w.write_u64(3).await.unwrap();
+        assert!(r.read_u64().await.unwrap() == 3);
+        assert!(read_event(&mut r).await.host == "d.example.com");
+    }
+}
@@ -0,0 +1,217 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::proxy::error::ProxyError;
+
+type Result<T> = std::result::Result<T, ProxyError>;
+
+const MAX_HOST_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+/// Where a proxied request goes, in the one spelling the egress policy and the
+/// logs see: lower case names without a trailing dot, and IP addresses in their
+/// standard form, with IPv6 addresses unbracketed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    /// Parses the authority of a CONNECT request or of an absolute-form URI, using
+    /// default_port if it has none. Anything that different resolvers or servers
+    /// might read differently, e.g. credentials, IPv6 zones or numeric shorthands
+    /// for IPv4 addresses, is refused instead of guessed at.
+    pub fn parse(authority: &str, default_port: Option<u16>) -> Result<Self> {
+        if authority.contains('@') {
+            return Err(invalid(format!(
+                "credentials are not allowed in {authority:?}"
+            )));
+        }
+
+        let (host, port) = split_port(authority)?;
+        let port = match (port, default_port) {
+            (Some(port), _) => parse_port(port)?,
+            (None, Some(port)) => port,
+            (None, None) => return Err(invalid(format!("{authority:?} is missing a port"))),
+        };
+
+        Ok(Self {
+            host: canonical_host(host)?,
+            port,
+        })
+    }
+
+    /// The host and port as they would appear in an authority
+    pub fn authority(&self) -> String {
+        match self.host.contains(':') {
+            true => format!("[{}]:{}", self.host, self.port),
+            false => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+// Splits off the port, leaving IPv6 brackets on the host
+fn split_port(authority: &str) -> Result<(&str, Option<&str>)> {
+    if authority.starts_with('[') {
+        let end = authority
+            .find(']')
+            .ok_or_else(|| invalid(format!("unterminated IPv6 address in {authority:?}")))?;
+
+        return match &authority[end + 1..] {
+            "" => Ok((&authority[..=end], None)),
+            rest => match rest.strip_prefix(':') {
+                Some(port) => Ok((&authority[..=end], Some(port))),
+                None => Err(invalid(format!("unexpected {rest:?} after IPv6 address"))),
+            },
+        };
+    }
+
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Ok((host, Some(port))),
+        None => Ok((authority, None)),
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16> {
+    if port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid(format!("invalid port {port:?}")));
+    }
+
+    match port.parse() {
+        Ok(0) | Err(_) => Err(invalid(format!("invalid port {port:?}"))),
+        Ok(port) => Ok(port),
+    }
+}
+
+fn invalid(reason: String) -> ProxyError {
+    ProxyError::InvalidTarget(reason)
+}
+
+fn canonical_host(host: &str) -> Result<String> {
+    if let Some(inner) = host.strip_prefix('[') {
+        let inner = inner.trim_end_matches(']');
+        if inner.contains('%') {
+            return Err(invalid(format!(
+                "IPv6 zone identifiers are not allowed in {host:?}"
+            )));
+        }
+
+        let addr: Ipv6Addr = inner
+            .parse()
+            .map_err(|_| invalid(format!("invalid IPv6 address {host:?}")))?;
+
+        // Policies match IPv4 mapped addresses as the IPv4 address they stand for
+        return Ok(match addr.to_ipv4_mapped() {
+            Some(v4) => v4.to_string(),
+            None => addr.to_string(),
+        });
+    }
+
+    // A single trailing dot marks a fully qualified name, and is otherwise the same
+    let name = host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase();
+
+    if name.is_empty() || name.len() > MAX_HOST_LEN {
+        return Err(invalid(format!("invalid host {host:?}")));
+    }
+
+    let labels: Vec<&str> = name.split('.').collect();
+    for label in &labels {
+        let valid = !label.is_empty()
+            && label.len() <= MAX_LABEL_LEN
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            return Err(invalid(format!("invalid host {host:?}")));
+        }
+    }
+
+    if let Ok(addr) = name.parse::<Ipv4Addr>() {
+        return Ok(addr.to_string());
+    }
+
+    // No top level domain is numeric, so this is one of the shorthands inet_aton
+    // accepts for IPv4 addresses, like 2130706433 or 0x7f.1
+    let tld = labels[labels.len() - 1];
+    if tld.bytes().all(|b| b.is_ascii_digit())
+        || (tld.starts_with("0x") && tld[2..].bytes().all(|b| b.is_ascii_hexdigit()))
+    {
+        return Err(invalid(format!("ambiguous IPv4 address {host:?}")));
+    }
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Target;
+    use assert2::assert;
+
+    fn parse(authority: &str) -> Option<(String, u16)> {
+        Target::parse(authority, None)
+            .ok()
+            .map(|target| (target.host, target.port))
+    }
+
+    #[test]
+    fn test_canonical() {
+        let cases = [
+            ("example.com:443", "example.com"),
+            ("EXAMPLE.com:443", "example.com"),
+            ("example.com.:443", "example.com"),
+            ("_srv.example.com:443", "_srv.example.com"),
+            ("10.0.0.1:443", "10.0.0.1"),
+            ("[::1]:443", "::1"),
+            ("[2001:DB8:0:0::1]:443", "2001:db8::1"),
+            ("[::ffff:10.0.0.1]:443", "10.0.0.1"),
+        ];
+
+        for (authority, host) in cases {
+            assert!(parse(authority) == Some((host.to_string(), 443)));
+        }
+    }
+
+    #[test]
+    fn test_evasions() {
+        let cases = [
+            // credentials, which http::Uri would silently drop from the host
+            "user:pass@example.com:443",
+            "allowed.com:443@evil.com:443",
+            // empty labels and repeated trailing dots
+            "example..com:443",
+            ".example.com:443",
+            "example.com..:443",
+            // numeric IPv4 shorthands
+            "2130706433:443",
+            "0x7f000001:443",
+            "127.1:443",
+            "0x7f.0.0.1:443",
+            // IPv6 oddities
+            "[::1%eth0]:443",
+            "[::1:443",
+            "[::1]x:443",
+            "::1:443",
+            // bad ports
+            "example.com:",
+            "example.com:0",
+            "example.com:+443",
+            "example.com:65536",
+            "example.com",
+            // anything else that is not a host name
+            "exa mple.com:443",
+            "example.com/x:443",
+            "exämple.com:443",
+        ];
+
+        for authority in cases {
+            assert!(parse(authority) == None, "{authority} was accepted");
+        }
+    }
+
+    #[test]
+    fn test_default_port() {
+        let target = Target::parse("[::1]", Some(80)).unwrap();
+        assert!(target.port == 80);
+        assert!(target.authority() == "[::1]:80");
+    }
+}
@@ -0,0 +1,228 @@
+//! AWS credentials for the proxies of odyn on hosts without an instance profile to
+//! lend them: those in the environment of enclaver-run, those of its container
+//! credentials endpoint, or a web identity token exchanged with STS.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use aws_config::web_identity_token::{StaticConfiguration, WebIdentityTokenCredentialsProvider};
+use aws_credential_types::provider::{self, error::CredentialsError, ProvideCredentials};
+use aws_credential_types::Credentials;
+use aws_smithy_types::date_time::{DateTime, Format};
+use aws_types::region::Region;
+use http::Uri;
+use hyper::client::{Client, HttpConnector};
+use hyper::{Body, Request};
+use hyper_proxy::{Intercept, Proxy, ProxyConnector};
+use serde::Deserialize;
+
+use super::aws_util;
+use crate::boot_config::{
+    AWS_ACCESS_KEY_ID, AWS_CONTAINER_AUTHORIZATION_TOKEN, AWS_CONTAINER_CREDENTIALS_FULL_URI,
+    AWS_CONTAINER_CREDENTIALS_RELATIVE_URI, AWS_DEFAULT_REGION, AWS_REGION, AWS_SECRET_ACCESS_KEY,
+    AWS_SESSION_TOKEN,
+};
+
+const PROVIDER_NAME: &str = "enclaver-run";
+
+// Where ECS serves the relative URIs
+const CONTAINER_CREDENTIALS_HOST: &str = "http://169.254.170.2";
+
+/// The region set in the environment of enclaver-run, if any
+pub fn env_region(env: &BTreeMap<String, String>) -> Option<Region> {
+    env.get(AWS_REGION)
+        .or_else(|| env.get(AWS_DEFAULT_REGION))
+        .map(|region| Region::new(region.clone()))
+}
+
+/// The static credentials in the environment of enclaver-run. They are not
+/// refreshed, so temporary ones stop working once they expire.
+pub fn env_credentials(env: &BTreeMap<String, String>) -> Result<Credentials> {
+    let var = |name: &str| {
+        env.get(name)
+            .ok_or_else(|| anyhow!("{name} is not set in the environment of enclaver-run"))
+    };
+
+    Ok(Credentials::new(
+        var(AWS_ACCESS_KEY_ID)?,
+        var(AWS_SECRET_ACCESS_KEY)?,
+        env.get(AWS_SESSION_TOKEN).cloned(),
+        None,
+        PROVIDER_NAME,
+    ))
+}
+
+/// Credentials assumed with the web identity token in token_file, through STS
+pub fn web_identity_provider(
+    proxy_uri: Uri,
+    region: Region,
+    token_file: PathBuf,
+    role_arn: String,
+    session_name: String,
+) -> Result<WebIdentityTokenCredentialsProvider> {
+    let config = aws_util::provider_config_with_proxy(proxy_uri, Some(region))?;
+
+    Ok(WebIdentityTokenCredentialsProvider::builder()
+        .configure(&config)
+        .static_configuration(StaticConfiguration {
+            web_identity_token_file: token_file,
+            role_arn,
+            session_name,
+        })
+        .build())
+}
+
+/// Fetches credentials from the container credentials endpoint of enclaver-run, as
+/// ECS and EKS Pod Identity serve them, through the egress proxy
+pub struct ContainerCredentialsProvider {
+    client: Client<ProxyConnector<HttpConnector>, Body>,
+    uri: Uri,
+    authorization: Option<String>,
+}
+
+impl ContainerCredentialsProvider {
+    pub fn new(env: &BTreeMap<String, String>, proxy_uri: Uri) -> Result<Self> {
+        let uri = match (
+            env.get(AWS_CONTAINER_CREDENTIALS_RELATIVE_URI),
+            env.get(AWS_CONTAINER_CREDENTIALS_FULL_URI),
+        ) {
+            (Some(relative), _) => format!("{CONTAINER_CREDENTIALS_HOST}{relative}").parse()?,
+            (None, Some(full)) => full.parse()?,
+            (None, None) => {
+                return Err(anyhow!(
+                    "neither {AWS_CONTAINER_CREDENTIALS_RELATIVE_URI} nor \
+                     {AWS_CONTAINER_CREDENTIALS_FULL_URI} is set in the environment of \
+                     enclaver-run"
+                ))
+            }
+        };
+
+        // Plain HTTP endpoints are tunneled, like IMDS is
+        let mut proxy = Proxy::new(Intercept::All, proxy_uri);
+        proxy.force_connect();
+        let connector = ProxyConnector::from_proxy(HttpConnector::new(), proxy)?;
+
+        Ok(Self {
+            client: Client::builder().build(connector),
+            uri,
+            authorization: env.get(AWS_CONTAINER_AUTHORIZATION_TOKEN).cloned(),
+        })
+    }
+
+    async fn fetch(&self) -> Result<Credentials> {
+        let mut req = Request::get(&self.uri);
+        if let Some(ref authorization) = self.authorization {
+            req = req.header(http::header::AUTHORIZATION, authorization);
+        }
+
+        let resp = self.client.request(req.body(Body::empty())?).await?;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await?;
+        if !status.is_success() {
+            return Err(anyhow!(
+                "{} answered {status}: {}",
+                self.uri,
+                String::from_utf8_lossy(&body)
+            ));
+        }
+
+        parse_container_credentials(&body)
+    }
+}
+
+// Leaves out the authorization token
+impl fmt::Debug for ContainerCredentialsProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContainerCredentialsProvider")
+            .field("uri", &self.uri)
+            .finish()
+    }
+}
+
+impl ProvideCredentials for ContainerCredentialsProvider {
+    fn provide_credentials<'a>(&'a self) -> provider::future::ProvideCredentials<'a>
+    where
+        Self: 'a,
+    {
+        provider::future::ProvideCredentials::new(async move {
+            self.fetch().await.map_err(|e| {
+                CredentialsError::provider_error(format!(
+                    "failed to fetch container credentials: {e}"
+                ))
+            })
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: Option<String>,
+    expiration: Option<String>,
+}
+
+fn parse_container_credentials(body: &[u8]) -> Result<Credentials> {
+    let creds: ContainerCredentials = serde_json::from_slice(body)?;
+
+    let expiry = match creds.expiration {
+        Some(expiration) => Some(SystemTime::try_from(DateTime::from_str(
+            &expiration,
+            Format::DateTime,
+        )?)?),
+        None => None,
+    };
+
+    Ok(Credentials::new(
+        creds.access_key_id,
+        creds.secret_access_key,
+        creds.token,
+        expiry,
+        PROVIDER_NAME,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{env_credentials, env_region, parse_container_credentials};
+    use assert2::assert;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_env_credentials() {
+        let mut env = BTreeMap::new();
+        env.insert("AWS_ACCESS_KEY_ID".to_string(), "AKID".to_string());
+        assert!(env_credentials(&env).is_err());
+        assert!(env_region(&env).is_none());
+
+        env.insert("AWS_SECRET_ACCESS_KEY".to_string(), "secret".to_string());
+        env.insert("AWS_DEFAULT_REGION".to_string(), "eu-west-1".to_string());
+        let credentials = env_credentials(&env).unwrap();
+        assert!(credentials.access_key_id() == "AKID");
+        assert!(credentials.session_token().is_none());
+        assert!(env_region(&env).unwrap().as_ref() == "eu-west-1");
+    }
+
+    #[test]
+    fn test_parse_container_credentials() {
+        let body = br#"{
+            "AccessKeyId": "ASIA",
+            "SecretAccessKey": "secret",
+            "Token": "token",
+            "Expiration": "2023-11-14T22:13:20Z",
+            "RoleArn": "arn:aws:iam::123456789012:role/app"
+        }"#;
+        let credentials = parse_container_credentials(body).unwrap();
+        assert!(credentials.access_key_id() == "ASIA");
+        assert!(credentials.session_token() == Some("token"));
+        let expiry = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert!(credentials.expiry() == Some(expiry));
+
+        assert!(parse_container_credentials(br#"{"AccessKeyId": "ASIA"}"#).is_err());
+    }
+}
@@ -3,6 +3,9 @@ use anyhow::{anyhow, Result};
 use http::Uri;
 use hyper::client::HttpConnector;
 use hyper_proxy::{Intercept, Proxy, ProxyConnector};
+use lazy_static::lazy_static;
+use log::debug;
+use regex::Regex;
 
 use aws_config::imds;
 use aws_config::imds::credentials::ImdsCredentialsProvider;
@@ -11,10 +14,13 @@ use aws_config::provider_config::ProviderConfig;
 use aws_credential_types::provider::SharedCredentialsProvider;
 use aws_smithy_client::{bounds::SmithyConnector, erase::DynConnector, hyper_ext};
 use aws_smithy_http::result::ConnectorError;
+use aws_types::region::Region;
 use aws_types::sdk_config::SdkConfig;
 
 const IMDS_URL: &str = "http://169.254.169.254:80/";
 
+const X_AMZ_CREDENTIAL: &str = "X-Amz-Credential";
+
 fn new_proxy_connector(
     proxy_uri: Uri,
 ) -> Result<impl SmithyConnector<Error = ConnectorError> + Send> {
@@ -26,10 +32,21 @@ fn new_proxy_connector(
     Ok(hyper_ext::Adapter::builder().build(proxy_connector))
 }
 
-pub async fn imds_client_with_proxy(proxy_uri: Uri) -> Result<imds::Client> {
+/// Config for the providers of the AWS SDK, e.g. of web identity credentials, that
+/// has them reach AWS through the proxy at proxy_uri
+pub fn provider_config_with_proxy(
+    proxy_uri: Uri,
+    region: Option<Region>,
+) -> Result<ProviderConfig> {
     let connector = new_proxy_connector(proxy_uri)?;
 
-    let config = ProviderConfig::without_region().with_http_connector(DynConnector::new(connector));
+    Ok(ProviderConfig::without_region()
+        .with_region(region)
+        .with_http_connector(DynConnector::new(connector)))
+}
+
+pub async fn imds_client_with_proxy(proxy_uri: Uri) -> Result<imds::Client> {
+    let config = provider_config_with_proxy(proxy_uri, None)?;
 
     let client = imds::Client::builder()
         .configure(&config)
@@ -59,3 +76,68 @@ pub async fn load_config_from_imds(imds_client: imds::Client) -> Result<SdkConfi
 
     Ok(config)
 }
+
+// Used to parse out the required fields out of the Authorization header or query parameters.
+// TODO: make it work using string references to avoid numerous copies.
+pub(crate) struct CredentialScope {
+    pub(crate) region: String,
+    pub(crate) service: String,
+}
+
+impl CredentialScope {
+    pub(crate) fn from_request(head: &http::request::Parts) -> Result<Self> {
+        lazy_static! {
+            // e.g.: AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, ...
+            static ref HEADER_RE: Regex = Regex::new(r"AWS4\-HMAC\-SHA256 Credential=.*?/.*?/(.*?)/(.*?)/aws4_request,").unwrap();
+            static ref QUERY_RE: Regex = Regex::new(r".*?/.*?/(.*?)/(.*?)/aws4_request").unwrap();
+        }
+
+        use std::ops::Deref;
+
+        // Look for the signature either in the Authorization HTTP header or in a query string
+        let (cred, re) = match head.headers.get(http::header::AUTHORIZATION) {
+            Some(authz) => (authz.to_str()?.to_string(), HEADER_RE.deref()),
+            None => {
+                let cred = amz_credential_query(&head.uri)
+                    .ok_or(anyhow!("No AWS SigV4 found in the request"))?;
+                (cred, QUERY_RE.deref())
+            }
+        };
+
+        debug!("CredentialScope: {cred}");
+
+        let groups = re.captures(&cred).ok_or(anyhow!(
+            "{} header has an invalid format",
+            http::header::AUTHORIZATION
+        ))?;
+
+        Ok(Self {
+            region: groups.get(1).unwrap().as_str().to_string(),
+            service: groups.get(2).unwrap().as_str().to_string(),
+        })
+    }
+
+    pub(crate) fn validate(&self, service: &str) -> Result<()> {
+        if self.service != service {
+            return Err(anyhow!(
+                "Received request signed for a non-{} ({}) service",
+                service.to_uppercase(),
+                self.service
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn amz_credential_query(uri: &Uri) -> Option<String> {
+    let q = uri.path_and_query()?.query()?;
+
+    for (k, v) in form_urlencoded::parse(q.as_bytes()) {
+        if X_AMZ_CREDENTIAL.eq_ignore_ascii_case(&k) {
+            return Some(v.to_string());
+        }
+    }
+
+    None
+}
@@ -0,0 +1,170 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Limits on the connections an enclave accepts, across all of its ingress listeners
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BudgetConfig {
+    pub max_connections: Option<u32>,
+    pub accepts_per_second: Option<u32>,
+}
+
+/// Shared by the ingress listeners of one enclave, so a flood on one port cannot
+/// take more than the enclave's share of the host. Waiters are served in FIFO
+/// order, so listeners get turns fairly once the budget is exhausted.
+#[derive(Clone)]
+pub struct ConnectionBudget {
+    connections: Option<Arc<Semaphore>>,
+    rate: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+impl ConnectionBudget {
+    pub fn new(config: BudgetConfig) -> Self {
+        Self {
+            connections: config
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max as usize))),
+            rate: config
+                .accepts_per_second
+                .map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate)))),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(BudgetConfig::default())
+    }
+
+    /// Waits until another connection fits in the budget. The returned permit must
+    /// be held for as long as the connection is open.
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if let Some(ref rate) = self.rate {
+            rate.lock().await.take().await;
+        }
+
+        match self.connections {
+            // The semaphore is never closed
+            Some(ref sem) => sem.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+}
+
+/// Limits the accepts of one listener that closes the connections past the rate
+/// rather than holding them back
+pub struct AcceptRate {
+    bucket: TokenBucket,
+}
+
+impl AcceptRate {
+    pub fn new(accepts_per_second: u32) -> Self {
+        Self {
+            bucket: TokenBucket::new(accepts_per_second),
+        }
+    }
+
+    /// Whether another connection may be accepted now
+    pub fn try_accept(&mut self) -> bool {
+        self.bucket.try_take()
+    }
+}
+
+// Allows bursts of up to one second's worth of accepts
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        let rate = rate.max(1) as f64;
+        Self {
+            rate,
+            tokens: rate,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    async fn take(&mut self) {
+        self.refill();
+
+        if self.tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            tokio::time::sleep(wait).await;
+            self.tokens = 1.0;
+            self.updated = Instant::now();
+        }
+
+        self.tokens -= 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AcceptRate, BudgetConfig, ConnectionBudget};
+    use assert2::assert;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let budget = ConnectionBudget::new(BudgetConfig {
+            max_connections: Some(1),
+            ..Default::default()
+        });
+
+        let permit = budget.acquire().await;
+        assert!(permit.is_some());
+
+        let blocked = tokio::time::timeout(Duration::from_millis(50), budget.acquire()).await;
+        assert!(blocked.is_err());
+
+        drop(permit);
+        let unblocked = tokio::time::timeout(Duration::from_millis(50), budget.acquire()).await;
+        assert!(unblocked.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_accept_rate() {
+        let budget = ConnectionBudget::new(BudgetConfig {
+            accepts_per_second: Some(10),
+            ..Default::default()
+        });
+
+        // The first second's worth is a burst, the next one waits for a refill
+        let start = Instant::now();
+        for _ in 0..11 {
+            budget.acquire().await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[tokio::test]
+    async fn test_try_accept() {
+        let mut rate = AcceptRate::new(10);
+        for _ in 0..10 {
+            assert!(rate.try_accept());
+        }
+        assert!(!rate.try_accept());
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(rate.try_accept());
+    }
+}
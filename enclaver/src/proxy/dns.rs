@@ -0,0 +1,372 @@
+//! DNS for applications that resolve names themselves. odyn answers queries on
+//! 127.0.0.1:53 and forwards the ones for names the egress policy allows over
+//! vsock to the host, which resolves them with its resolver. Queries for any other
+//! name are answered with NXDOMAIN and never leave the enclave. The host and its
+//! services are answered by odyn itself, with egress.host_address.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use futures::{Stream, StreamExt};
+use log::{debug, error, warn, Level};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio_vsock::VsockStream;
+
+use crate::policy::EgressPolicy;
+use crate::proxy::error::ProxyError;
+use crate::resolver::Resolver;
+use crate::utils;
+
+const MAX_MESSAGE_LEN: usize = 4096;
+
+const TTL: u32 = 300;
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+const RCODE_FORMERR: u16 = 1;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+
+pub struct EnclaveDnsForwarder {
+    socket: Arc<UdpSocket>,
+    host_names: Arc<Vec<String>>,
+    host_address: Ipv4Addr,
+}
+
+impl EnclaveDnsForwarder {
+    pub async fn bind(addr: SocketAddr) -> Result<Self, ProxyError> {
+        Ok(Self {
+            socket: Arc::new(UdpSocket::bind(addr).await?),
+            host_names: Arc::new(Vec::new()),
+            host_address: Ipv4Addr::LOCALHOST,
+        })
+    }
+
+    /// Names to answer with address, instead of asking the host to resolve them
+    pub fn with_host_names(mut self, names: Vec<String>, address: Ipv4Addr) -> Self {
+        self.host_names = Arc::new(names);
+        self.host_address = address;
+        self
+    }
+
+    pub async fn serve(self, dns_port: u32, egress_policy: Arc<EgressPolicy>) {
+        let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+
+        loop {
+            let (len, peer) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    utils::log_limited!(Level::Error, "", "DNS receive failed: {err}");
+                    continue;
+                }
+            };
+
+            let query = buf[..len].to_vec();
+            let socket = self.socket.clone();
+            let egress_policy = egress_policy.clone();
+            let host_names = self.host_names.clone();
+            let host_address = self.host_address;
+
+            tokio::task::spawn(async move {
+                let resp =
+                    Self::resolve(&query, dns_port, &egress_policy, &host_names, host_address)
+                        .await;
+                if let Some(resp) = resp {
+                    if let Err(err) = socket.send_to(&resp, peer).await {
+                        debug!("DNS reply to {peer} failed: {err}");
+                    }
+                }
+            });
+        }
+    }
+
+    async fn resolve(
+        query: &[u8],
+        dns_port: u32,
+        egress_policy: &EgressPolicy,
+        host_names: &[String],
+        host_address: Ipv4Addr,
+    ) -> Option<Vec<u8>> {
+        let name = question_name(query)?;
+
+        if !egress_policy.is_name_allowed(&name) {
+            debug!("refusing to resolve {name}, egress to it is not allowed");
+            return error_response(query, RCODE_NXDOMAIN);
+        }
+
+        // The host resolver knows nothing of these, the egress proxy maps them
+        if host_names.contains(&name) {
+            return address_answer(query, |_| host_address);
+        }
+
+        match remote_resolve(dns_port, query).await {
+            Ok(resp) => Some(resp),
+            Err(err) => {
+                warn!("failed to resolve {name}: {err}");
+                error_response(query, RCODE_SERVFAIL)
+            }
+        }
+    }
+}
+
+// Asks the host to resolve the query, over a vsock connection of its own
+async fn remote_resolve(dns_port: u32, query: &[u8]) -> Result<Vec<u8>, ProxyError> {
+    let mut vsock = VsockStream::connect(crate::vsock::VMADDR_CID_HOST, dns_port).await?;
+    write_message(&mut vsock, query).await?;
+    Ok(read_message(&mut vsock).await?)
+}
+
+pub struct HostDnsProxy {
+    incoming: Box<dyn Stream<Item = VsockStream> + Unpin + Send>,
+    resolver: Arc<Resolver>,
+}
+
+impl HostDnsProxy {
+    pub fn bind(dns_port: u32) -> Result<Self, ProxyError> {
+        Ok(Self {
+            incoming: Box::new(crate::vsock::serve(dns_port)?),
+            resolver: Arc::new(Resolver::system()),
+        })
+    }
+
+    pub fn with_resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    pub async fn serve(self) {
+        let mut incoming = Box::into_pin(self.incoming);
+
+        while let Some(mut stream) = incoming.next().await {
+            let resolver = self.resolver.clone();
+
+            tokio::task::spawn(async move {
+                if let Err(err) = HostDnsProxy::service_conn(&mut stream, &resolver).await {
+                    error!("{err}");
+                }
+            });
+        }
+    }
+
+    async fn service_conn(vsock: &mut VsockStream, resolver: &Resolver) -> std::io::Result<()> {
+        let query = read_message(vsock).await?;
+
+        let resp = match resolver.forward(&query).await {
+            Ok(resp) => resp,
+            Err(err) => {
+                warn!("DNS query from the enclave failed: {err}");
+                match error_response(&query, RCODE_SERVFAIL) {
+                    Some(resp) => resp,
+                    None => return Ok(()),
+                }
+            }
+        };
+
+        write_message(vsock, &resp).await
+    }
+}
+
+// Messages are framed by a 2 byte length, as in DNS over TCP. The UDP relay frames
+// datagrams the same way.
+pub(crate) async fn write_message<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    msg: &[u8],
+) -> std::io::Result<()> {
+    let len = u16::try_from(msg.len()).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "DNS message too long")
+    })?;
+
+    let mut pkt = Vec::with_capacity(2 + msg.len());
+    pkt.extend_from_slice(&len.to_be_bytes());
+    pkt.extend_from_slice(msg);
+    w.write_all(&pkt).await
+}
+
+pub(crate) async fn read_message<R: AsyncRead + Unpin>(r: &mut R) -> std::io::Result<Vec<u8>> {
+    let len = r.read_u16().await?;
+    let mut msg = vec![0u8; len as usize];
+    r.read_exact(&mut msg).await?;
+    Ok(msg)
+}
+
+// The name asked about by a query with a single question, lower cased
+fn question_name(query: &[u8]) -> Option<String> {
+    if query.len() < 12 || query[2] & 0x80 != 0 || query[4..6] != [0, 1] {
+        return None;
+    }
+
+    let (name, _) = read_name(query, 12)?;
+    Some(name.to_ascii_lowercase())
+}
+
+/// Reads an uncompressed name, as found in queries. Returns it along with the
+/// offset just past it.
+pub(crate) fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+
+    loop {
+        let len = *msg.get(pos)? as usize;
+        pos += 1;
+
+        match len {
+            0 => return Some((labels.join("."), pos)),
+            1..=63 => {
+                let label = std::str::from_utf8(msg.get(pos..pos + len)?).ok()?;
+                labels.push(label);
+                pos += len;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Answers A queries with the address address_of gives for the name, and every
+/// other type with no records. Returns None for anything but a query.
+pub(crate) fn address_answer(
+    query: &[u8],
+    address_of: impl FnOnce(&str) -> Ipv4Addr,
+) -> Option<Vec<u8>> {
+    if query.len() < 12 || query[2] & 0x80 != 0 {
+        return None;
+    }
+
+    let id = &query[0..2];
+    let opcode = (query[2] >> 3) & 0x0f;
+    let recursion_desired = query[2] & 0x01;
+
+    let reply = |rcode: u16, question: &[u8], answers: u16| {
+        let flags = 0x8080 | u16::from(recursion_desired) << 8 | rcode;
+        let mut msg = Vec::with_capacity(query.len() + 16);
+        msg.extend_from_slice(id);
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&u16::from(!question.is_empty()).to_be_bytes());
+        msg.extend_from_slice(&answers.to_be_bytes());
+        msg.extend_from_slice(&[0, 0, 0, 0]);
+        msg.extend_from_slice(question);
+        msg
+    };
+
+    if opcode != 0 {
+        return Some(reply(RCODE_NOTIMP, &[], 0));
+    }
+    if u16::from_be_bytes([query[4], query[5]]) != 1 {
+        return Some(reply(RCODE_FORMERR, &[], 0));
+    }
+
+    let (name, end) = match read_name(query, 12) {
+        Some(parsed) if parsed.1 + 4 <= query.len() => parsed,
+        _ => return Some(reply(RCODE_FORMERR, &[], 0)),
+    };
+    let question = &query[12..end + 4];
+    let qtype = u16::from_be_bytes([query[end], query[end + 1]]);
+    let qclass = u16::from_be_bytes([query[end + 2], query[end + 3]]);
+
+    if qtype != TYPE_A || qclass != CLASS_IN || name.is_empty() {
+        return Some(reply(0, question, 0));
+    }
+
+    let addr = address_of(&name);
+    debug!("resolved {name} to {addr}");
+
+    let mut msg = reply(0, question, 1);
+    // A pointer to the name in the question
+    msg.extend_from_slice(&[0xc0, 12]);
+    msg.extend_from_slice(&TYPE_A.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    msg.extend_from_slice(&TTL.to_be_bytes());
+    msg.extend_from_slice(&4u16.to_be_bytes());
+    msg.extend_from_slice(&addr.octets());
+
+    Some(msg)
+}
+
+// A response to query with no records, carrying rcode. None if the query does not
+// even have a complete question to echo back.
+fn error_response(query: &[u8], rcode: u16) -> Option<Vec<u8>> {
+    let (_, end) = read_name(query, 12)?;
+    let question = query.get(12..end + 4)?;
+
+    // QR and RA, keeping the opcode and RD of the query
+    let flags = 0x8080 | (u16::from_be_bytes([query[2], query[3]]) & 0x7900) | rcode;
+
+    let mut msg = Vec::with_capacity(12 + question.len());
+    msg.extend_from_slice(&query[0..2]);
+    msg.extend_from_slice(&flags.to_be_bytes());
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    msg.extend_from_slice(question);
+    Some(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        error_response, question_name, read_message, write_message, EnclaveDnsForwarder,
+        RCODE_NXDOMAIN,
+    };
+    use crate::manifest::Egress;
+    use crate::policy::EgressPolicy;
+    use assert2::assert;
+    use std::net::Ipv4Addr;
+
+    // A query for db.internal, with an EDNS record
+    const QUERY: &[u8] = &[
+        0x12, 0x34, 0x01, 0x20, 0, 1, 0, 0, 0, 0, 0, 1, 2, b'd', b'B', 8, b'i', b'n', b't', b'e',
+        b'r', b'n', b'a', b'l', 0, 0, 1, 0, 1, 0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    #[test]
+    fn test_question_name() {
+        assert!(question_name(QUERY).as_deref() == Some("db.internal"));
+        assert!(question_name(&QUERY[..10]) == None);
+
+        let mut response = QUERY.to_vec();
+        response[2] |= 0x80;
+        assert!(question_name(&response) == None);
+    }
+
+    #[test]
+    fn test_error_response() {
+        let resp = error_response(QUERY, RCODE_NXDOMAIN).unwrap();
+        assert!(resp[0..2] == [0x12, 0x34]);
+        assert!(resp[2..4] == [0x81, 0x83]);
+        assert!(resp[4..12] == [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(resp[12..] == QUERY[12..29]);
+
+        assert!(error_response(&QUERY[..20], RCODE_NXDOMAIN) == None);
+    }
+
+    #[tokio::test]
+    async fn test_host_names() {
+        let policy = EgressPolicy::new(&Egress {
+            allow: Some(vec!["db.internal".to_string()]),
+            ..Default::default()
+        });
+        let address = Ipv4Addr::new(10, 0, 0, 1);
+
+        let host_names = vec!["db.internal".to_string()];
+        let resp = EnclaveDnsForwarder::resolve(QUERY, 0, &policy, &host_names, address)
+            .await
+            .unwrap();
+        assert!(resp[6..8] == [0, 1]);
+        assert!(resp[resp.len() - 4..] == address.octets());
+
+        let denied = EgressPolicy::new(&Egress::default());
+        let resp = EnclaveDnsForwarder::resolve(QUERY, 0, &denied, &host_names, address)
+            .await
+            .unwrap();
+        assert!(resp[3] & 0x0f == RCODE_NXDOMAIN as u8);
+    }
+
+    #[tokio::test]
+    async fn test_framing() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, QUERY).await.unwrap();
+        assert!(buf[0..2] == [0, QUERY.len() as u8]);
+
+        let mut reader = buf.as_slice();
+        assert!(read_message(&mut reader).await.unwrap() == QUERY);
+    }
+}
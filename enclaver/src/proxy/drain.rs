@@ -0,0 +1,164 @@
+//! Draining the ingress of an enclave that is being stopped. Once a drain starts, the
+//! host stops accepting on the ingress ports and asks odyn over a vsock port to send
+//! SIGTERM to the app, so it can finish what it is serving. The enclave is terminated
+//! once the connections that were open have closed, or the drain timed out.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::watch;
+use tokio_vsock::VsockStream;
+
+use crate::constants::DRAIN_PORT;
+
+const MAX_REPLY_SIZE: u64 = 4 * 1024;
+
+/// Shared by the ingress listeners of one enclave. Counts the connections they have
+/// open, and tells them when to stop accepting.
+#[derive(Clone)]
+pub struct Drain {
+    draining: Arc<watch::Sender<bool>>,
+    active: Arc<watch::Sender<usize>>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        Self {
+            draining: Arc::new(watch::Sender::new(false)),
+            active: Arc::new(watch::Sender::new(0)),
+        }
+    }
+}
+
+impl Drain {
+    /// Records a new connection. It is waited for until the guard is dropped.
+    pub fn track(&self) -> DrainGuard {
+        self.active.send_modify(|active| *active += 1);
+        DrainGuard {
+            active: self.active.clone(),
+        }
+    }
+
+    /// Number of connections currently open
+    pub fn active(&self) -> usize {
+        *self.active.borrow()
+    }
+
+    /// Tells the listeners to stop accepting
+    pub fn start(&self) {
+        self.draining.send_replace(true);
+    }
+
+    /// Completes once the drain has started
+    pub async fn started(&self) {
+        let mut draining = self.draining.subscribe();
+        _ = draining.wait_for(|draining| *draining).await;
+    }
+
+    /// Waits up to timeout for the open connections to close. Returns how many are
+    /// still open.
+    pub async fn wait(&self, timeout: Duration) -> usize {
+        let mut active = self.active.subscribe();
+        _ = tokio::time::timeout(timeout, active.wait_for(|active| *active == 0)).await;
+        self.active()
+    }
+}
+
+pub struct DrainGuard {
+    active: Arc<watch::Sender<usize>>,
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        self.active.send_modify(|active| *active -= 1);
+    }
+}
+
+/// What odyn answers a drain with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum DrainReply {
+    Signaled,
+    Failed { error: String },
+}
+
+/// Asks odyn in the enclave with the given CID to send SIGTERM to the app (host side)
+pub async fn notify(cid: u32) -> Result<()> {
+    let mut conn = VsockStream::connect(cid, DRAIN_PORT)
+        .await
+        .map_err(|e| anyhow!("failed to connect to enclave {cid}: {e}"))?;
+    AsyncWriteExt::shutdown(&mut conn).await?;
+
+    match read_reply(&mut conn).await? {
+        DrainReply::Signaled => Ok(()),
+        DrainReply::Failed { error } => {
+            Err(anyhow!("the enclave failed to signal the app: {error}"))
+        }
+    }
+}
+
+async fn read_reply<S: AsyncRead + Unpin>(conn: &mut S) -> Result<DrainReply> {
+    let mut buf = Vec::new();
+    conn.take(MAX_REPLY_SIZE + 1).read_to_end(&mut buf).await?;
+
+    if buf.len() as u64 > MAX_REPLY_SIZE {
+        return Err(anyhow!("reply exceeds {MAX_REPLY_SIZE} bytes"));
+    }
+
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+/// Answers a drain (enclave side)
+pub async fn reply<S: AsyncWrite + Unpin>(conn: &mut S, reply: &DrainReply) -> Result<()> {
+    conn.write_all(&serde_json::to_vec(reply)?).await?;
+    conn.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_reply, reply, Drain, DrainReply};
+    use assert2::assert;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let drain = Drain::default();
+        let first = drain.track();
+        let second = drain.track();
+        assert!(drain.active() == 2);
+
+        let listener = tokio::spawn({
+            let drain = drain.clone();
+            async move { drain.started().await }
+        });
+        drain.start();
+        listener.await.unwrap();
+
+        drop(first);
+        assert!(drain.wait(Duration::from_millis(10)).await == 1);
+
+        let waiter = tokio::spawn({
+            let drain = drain.clone();
+            async move { drain.wait(Duration::from_secs(10)).await }
+        });
+        drop(second);
+        assert!(waiter.await.unwrap() == 0);
+    }
+
+    #[tokio::test]
+    async fn test_reply() {
+        let (mut host, mut enclave) = tokio::io::duplex(1024);
+        let failed = DrainReply::Failed {
+            error: "no such process".to_string(),
+        };
+        reply(&mut enclave, &failed).await.unwrap();
+        assert!(read_reply(&mut host).await.unwrap() == failed);
+
+        let json = serde_json::to_string(&DrainReply::Signaled).unwrap();
+        assert!(json == r#"{"result":"signaled"}"#);
+    }
+}
@@ -1,121 +1,199 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::connections::{ConnectionKind, ConnectionTable, TrackedConnection};
+use crate::metrics::{ConnectionMetrics, CountedStream, Histogram, StreamMetrics};
 use crate::utils;
-use anyhow::anyhow;
-use async_trait::async_trait;
 use futures::{Stream, StreamExt};
-use http::uri::PathAndQuery;
+use http::uri::{PathAndQuery, Scheme};
 use hyper::client::conn::Builder;
 use hyper::header::HeaderValue;
 use hyper::server::conn::Http;
 use hyper::service::service_fn;
-use hyper::{Body, Method, Request, Response};
-use log::{debug, error};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use hyper::{Body, Method, Request, Response, Version};
+use hyper_rustls::ConfigBuilderExt;
+use log::{debug, error, info, warn, Level};
+use rustls::{ClientConfig, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsConnector;
 use tokio_vsock::VsockStream;
 
-use crate::policy::EgressPolicy;
-
-#[async_trait]
-trait JsonTransport: Sized + Sync {
-    async fn send<W: AsyncWrite + Unpin + Send>(&self, w: &mut W) -> anyhow::Result<()>;
-    async fn recv<R: AsyncRead + Unpin + Send>(r: &mut R) -> anyhow::Result<Self>;
-}
-
-#[async_trait]
-impl<M: Serialize + DeserializeOwned + Sync> JsonTransport for M {
-    async fn send<W: AsyncWrite + Unpin + Send>(&self, w: &mut W) -> anyhow::Result<()> {
-        // Frame and serialize
-        // use JSON serialization to avoid pulling in another dependency
-        let msg = serde_json::to_vec(self)?;
-        // frame it by a 2 byte length
-        let len = msg.len() as u16;
-        let mut pkt = Vec::with_capacity(2 + msg.len());
-        pkt.extend_from_slice(&len.to_le_bytes());
-        pkt.extend_from_slice(&msg);
-        w.write_all(&pkt).await?;
-        Ok(())
-    }
-
-    async fn recv<R: AsyncRead + Unpin + Send>(r: &mut R) -> anyhow::Result<Self> {
-        let mut len_buf = [0u8; 2];
-        r.read_exact(&mut len_buf).await?;
-        let len = u16::from_le_bytes(len_buf);
-
-        let mut msg = vec![0u8; len as usize];
-        r.read_exact(&mut msg).await?;
-
-        let req: Self = serde_json::from_slice(&msg)?;
-        Ok(req)
-    }
-}
-
-#[derive(Serialize, Deserialize)]
-struct ConnectRequest {
-    host: String,
+use crate::constants::OUTSIDE_HOST;
+use crate::manifest::{Egress, EgressService, IMDS_RELAY_POLICY};
+use crate::policy::limits::EgressLimiter;
+use crate::policy::requests::RequestFilter;
+use crate::policy::{Decision, EgressPolicy, ProtocolMatch};
+use crate::protocol::{ConnectRequest, ConnectResponse, JsonTransport, Versioned};
+use crate::proxy::audit::{AuditEvent, AuditKind, AuditLog, Counted};
+use crate::proxy::authority::Target;
+use crate::proxy::error::ProxyError;
+use crate::proxy::host_relays::HostRelays;
+use crate::proxy::inspect::{Direction, Inspected, ProtocolInspector};
+use crate::proxy::pool::{Connection, ConnectionPool};
+use crate::proxy::revoke::{Revocable, Revoker};
+use crate::proxy::sni::read_client_hello;
+use crate::proxy::throttle::{self, Throttled};
+use crate::resolver::{DnsPins, Resolver};
+
+// Upstream connections of plain HTTP requests kept open per host:port, and for how
+// long they may go unused
+const POOL_MAX_PER_HOST: usize = 8;
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The rule recorded for tunnels closed because of their ClientHello
+const SNI_RULE: &str = "verify_sni";
+
+// Keeps track of the tunnels of one client connection, CONNECT ones and upgraded
+// requests: lists them in a connection table, for debugging, and registers them with
+// the revoker of the policy
+#[derive(Clone)]
+struct Tunnels {
+    table: Option<ConnectionTable>,
+    revoker: Option<Revoker>,
+    client: SocketAddr,
     port: u16,
 }
 
-impl ConnectRequest {
-    fn new(host: String, port: u16) -> Self {
-        Self { host, port }
+impl Tunnels {
+    fn track(&self, host: &str, port: u16) -> Option<TrackedConnection> {
+        let table = self.table.as_ref()?;
+        Some(table.track(
+            ConnectionKind::Egress,
+            self.port,
+            self.client.to_string(),
+            format!("{host}:{port}"),
+        ))
     }
-}
 
-#[derive(Serialize, Deserialize)]
-enum ConnectResponse {
-    Ok,
-    Err { os_code: i32, message: String },
-}
-
-impl ConnectResponse {
-    fn failed(err: &std::io::Error) -> Self {
-        Self::Err {
-            os_code: err.raw_os_error().unwrap_or(0i32),
-            message: err.to_string(),
-        }
+    fn open(&self, host: &str, port: u16) -> Option<Revocable> {
+        self.revoker.as_ref()?.open(host, port)
     }
 }
 
 pub struct EnclaveHttpProxy {
     listener: TcpListener,
+    pool: ConnectionPool,
+    tls: UpstreamTls,
+    audit: AuditLog,
+    connections: Option<ConnectionTable>,
+    revoker: Option<Revoker>,
 }
 
 impl EnclaveHttpProxy {
-    pub async fn bind(port: u16) -> anyhow::Result<Self> {
+    pub async fn bind(port: u16) -> Result<Self, ProxyError> {
         let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        let tls_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_webpki_roots()
+            .with_no_client_auth();
+
         Ok(Self {
             listener: TcpListener::bind(addr).await?,
+            pool: ConnectionPool::new(POOL_MAX_PER_HOST, POOL_IDLE_TIMEOUT),
+            tls: UpstreamTls::new(tls_config),
+            audit: AuditLog::default(),
+            connections: None,
+            revoker: None,
         })
     }
 
-    pub async fn serve(self, egress_port: u32, egress_policy: Arc<EgressPolicy>) {
+    /// Where the proxy listens, e.g. when bound to port 0
+    pub fn local_addr(&self) -> Result<SocketAddr, ProxyError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn with_pool(mut self, pool: ConnectionPool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// TLS settings for https:// requests, which are sent to the origin over TLS
+    pub fn with_tls_config(mut self, tls_config: ClientConfig) -> Self {
+        self.tls = UpstreamTls::new(tls_config);
+        self
+    }
+
+    /// Where to record the connections and requests the proxy allows or denies
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Lists the open CONNECT tunnels and upgraded requests of the proxy in
+    /// connections, for debugging
+    pub fn with_connection_table(mut self, connections: Option<ConnectionTable>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Registers the CONNECT tunnels and upgraded requests of the proxy with revoker,
+    /// which closes those a policy update takes away
+    pub fn with_revoker(mut self, revoker: Revoker) -> Self {
+        self.revoker = Some(revoker);
+        self
+    }
+
+    pub async fn serve(self, relays: HostRelays, egress_policy: Arc<EgressPolicy>) {
+        let port = self.listener.local_addr().map_or(0, |addr| addr.port());
         loop {
             match self.listener.accept().await {
-                Ok((sock, _)) => {
+                Ok((sock, client)) => {
+                    let relays = relays.clone();
                     let egress_policy = egress_policy.clone();
+                    let pool = self.pool.clone();
+                    let tls = self.tls.clone();
+                    let audit = self.audit.clone();
+                    let tunnels = Tunnels {
+                        table: self.connections.clone(),
+                        revoker: self.revoker.clone(),
+                        client,
+                        port,
+                    };
 
                     utils::spawn!("egress stream", async move {
-                        EnclaveHttpProxy::service_conn(sock, egress_port, egress_policy).await;
+                        EnclaveHttpProxy::service_conn(
+                            sock,
+                            relays,
+                            egress_policy,
+                            pool,
+                            tls,
+                            audit,
+                            tunnels,
+                        )
+                        .await;
                     })
                     .expect("spawn egress stream");
                 }
                 Err(err) => {
-                    error!("Accept failed: {err}");
+                    utils::log_limited!(Level::Error, "", "Accept failed: {err}");
                 }
             }
         }
     }
 
-    async fn service_conn(tcp: TcpStream, egress_port: u32, egress_policy: Arc<EgressPolicy>) {
+    async fn service_conn(
+        tcp: TcpStream,
+        relays: HostRelays,
+        egress_policy: Arc<EgressPolicy>,
+        pool: ConnectionPool,
+        tls: UpstreamTls,
+        audit: AuditLog,
+        tunnels: Tunnels,
+    ) {
         let svc = service_fn(move |req| {
+            let relays = relays.clone();
             let egress_policy = egress_policy.clone();
-            async move { proxy(egress_port, req, &egress_policy).await }
+            let pool = pool.clone();
+            let tls = tls.clone();
+            let audit = audit.clone();
+            let tunnels = tunnels.clone();
+            async move { proxy(&relays, req, &egress_policy, &pool, &tls, &audit, &tunnels).await }
         });
 
+        // Clients may speak HTTP/2 with prior knowledge as well
         if let Err(err) = Http::new()
             .http1_preserve_header_case(true)
             .http1_title_case_headers(true)
@@ -123,56 +201,220 @@ impl EnclaveHttpProxy {
             .with_upgrades()
             .await
         {
-            error!("Failed to serve connection: {err}");
+            let key = &err.to_string();
+            utils::log_limited!(Level::Error, key, "Failed to serve connection: {err}");
         }
     }
 }
 
 pub struct HostHttpProxy {
     incoming: Box<dyn Stream<Item = VsockStream> + Unpin + Send>,
+    metrics: ConnectionMetrics,
+    streams: StreamMetrics,
+    connect_latency: Option<Arc<Histogram>>,
+    resolver: Arc<Resolver>,
+    services: Arc<HashMap<String, u16>>,
+    pins: Option<(DnsPins, Vec<String>)>,
+    policies: Option<Arc<HostPolicies>>,
+}
+
+// The egress policies of the manifest, by the name the enclave gives in its connect
+// requests
+struct HostPolicies {
+    default: EgressPolicy,
+    named: HashMap<String, EgressPolicy>,
+}
+
+impl HostPolicies {
+    fn new(egress: &Egress) -> Self {
+        let relay = egress
+            .imds_relay_policy()
+            .map(|relay| (IMDS_RELAY_POLICY.to_string(), EgressPolicy::new(&relay)));
+
+        Self {
+            default: EgressPolicy::new(egress),
+            named: egress
+                .proxies
+                .iter()
+                .flatten()
+                .map(|proxy| (proxy.name.clone(), EgressPolicy::new(&proxy.policy())))
+                .chain(relay)
+                .collect(),
+        }
+    }
+
+    // Whether the policy the enclave names allows the connection. Policy updates only
+    // narrow what the manifest allows, so the manifest policy is the upper bound.
+    fn allows(&self, conn_req: &ConnectRequest) -> bool {
+        let policy = match conn_req.policy {
+            Some(ref name) => self.named.get(name),
+            None => Some(&self.default),
+        };
+
+        policy.is_some_and(|policy| policy.is_connect_allowed(&conn_req.host, conn_req.port))
+    }
 }
 
 impl HostHttpProxy {
-    pub fn bind(egress_port: u32) -> anyhow::Result<Self> {
+    pub fn bind(egress_port: u32) -> Result<Self, ProxyError> {
         Ok(Self {
             incoming: Box::new(crate::vsock::serve(egress_port)?),
+            metrics: ConnectionMetrics::default(),
+            streams: StreamMetrics::default(),
+            connect_latency: None,
+            resolver: Arc::new(Resolver::system()),
+            services: Arc::new(HashMap::new()),
+            pins: None,
+            policies: None,
         })
     }
 
+    pub fn with_metrics(mut self, metrics: ConnectionMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Counts the bytes of the tunnels, from the point of view of the enclave: what
+    /// it sends out is received from it
+    pub fn with_stream_metrics(mut self, streams: StreamMetrics) -> Self {
+        self.streams = streams;
+        self
+    }
+
+    /// Records how long connecting to the remotes takes, lookup included
+    pub fn with_connect_latency(mut self, histogram: Arc<Histogram>) -> Self {
+        self.connect_latency = Some(histogram);
+        self
+    }
+
+    pub fn with_resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    pub fn with_services<'a>(
+        mut self,
+        services: impl IntoIterator<Item = &'a EgressService>,
+    ) -> Self {
+        self.services = Arc::new(
+            services
+                .into_iter()
+                .map(|service| (service.name.to_ascii_lowercase(), service.port))
+                .collect(),
+        );
+        self
+    }
+
+    /// Pins the names the enclave connects to, so that it only reaches the addresses
+    /// they first resolved to until their pins expire. The given names, those the
+    /// policies allow, are pinned before the first connection and for as long as the
+    /// proxy runs.
+    pub fn with_dns_pins(mut self, names: Vec<String>) -> Self {
+        self.pins = Some((DnsPins::default(), names));
+        self
+    }
+
+    /// Checks each connection the enclave asks for against the egress policy of the
+    /// manifest again before connecting, so that an enclave whose own checks were
+    /// bypassed still cannot reach anything the manifest does not allow
+    pub fn with_policy(mut self, egress: &Egress) -> Self {
+        self.policies = Some(Arc::new(HostPolicies::new(egress)));
+        self
+    }
+
     pub async fn serve(self) {
         let mut incoming = Box::into_pin(self.incoming);
+        let dns_pins = self.pins.as_ref().map(|(pins, _)| pins.clone());
+
+        let accept = async {
+            while let Some(stream) = incoming.next().await {
+                let conn = self.metrics.track();
+                let stream = self.streams.wrap(stream);
+                let connect_latency = self.connect_latency.clone();
+                let resolver = self.resolver.clone();
+                let services = self.services.clone();
+                let dns_pins = dns_pins.clone();
+                let policies = self.policies.clone();
+
+                tokio::task::spawn(async move {
+                    if let Err(err) = HostHttpProxy::service_conn(
+                        stream,
+                        &resolver,
+                        dns_pins.as_ref(),
+                        policies.as_deref(),
+                        &services,
+                        connect_latency,
+                    )
+                    .await
+                    {
+                        utils::log_limited!(Level::Error, &err.to_string(), "{err}");
+                    }
+                    drop(conn);
+                });
+            }
+        };
 
-        while let Some(stream) = incoming.next().await {
-            tokio::task::spawn(async move {
-                if let Err(err) = HostHttpProxy::service_conn(stream).await {
-                    error!("{err}");
+        // Runs alongside the proxy rather than spawned, to stop along with it
+        let refresh = async {
+            match self.pins {
+                Some((ref pins, ref names)) => {
+                    pins.prefetch(&self.resolver, names.iter().cloned()).await;
+                    pins.clone().refresh(self.resolver.clone()).await;
                 }
-            });
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = accept => {}
+            _ = refresh => {}
         }
     }
 
-    async fn service_conn(mut vsock: VsockStream) -> anyhow::Result<()> {
-        let conn_req = ConnectRequest::recv(&mut vsock).await?;
+    async fn service_conn(
+        mut vsock: CountedStream<VsockStream>,
+        resolver: &Resolver,
+        pins: Option<&DnsPins>,
+        policies: Option<&HostPolicies>,
+        services: &HashMap<String, u16>,
+        connect_latency: Option<Arc<Histogram>>,
+    ) -> Result<(), ProxyError> {
+        let conn_req = Versioned::<ConnectRequest>::recv(&mut vsock).await?.message;
+        if conn_req.is_probe() {
+            return ConnectResponse::Ok.send(&mut vsock).await;
+        }
 
-        // A special hostname "host" refers to the localhost on the outside
-        // of the enclave.
-        let host = if conn_req
-            .host
-            .eq_ignore_ascii_case(crate::constants::OUTSIDE_HOST)
-        {
-            "127.0.0.1".to_string()
-        } else {
-            conn_req.host
-        };
+        if policies.is_some_and(|policies| !policies.allows(&conn_req)) {
+            warn!(
+                "egress connection to {}:{} denied by the {} policy on the host, although the enclave allowed it",
+                conn_req.host,
+                conn_req.port,
+                conn_req.policy.as_deref().unwrap_or("default")
+            );
+            let err = ProxyError::Denied(format!("{}:{}", conn_req.host, conn_req.port));
+            let err = std::io::Error::new(std::io::ErrorKind::PermissionDenied, err.to_string());
+            ConnectResponse::failed(&err).send(&mut vsock).await?;
+            return Ok(());
+        }
 
-        match TcpStream::connect((host.as_ref(), conn_req.port)).await {
+        let (host, port) = outside_target(services, &conn_req.host, conn_req.port);
+
+        info!(
+            "egress connection to {}:{} ({host}:{port}) allowed by the {} policy",
+            conn_req.host,
+            conn_req.port,
+            conn_req.policy.as_deref().unwrap_or("default")
+        );
+
+        let started = Instant::now();
+        match connect(resolver, pins, &host, port).await {
             Ok(mut tcp) => {
+                if let Some(histogram) = connect_latency {
+                    histogram.observe(started.elapsed().as_secs_f64());
+                }
                 ConnectResponse::Ok.send(&mut vsock).await?;
 
-                debug!(
-                    "Connected to {}:{}, starting to proxy bytes",
-                    host, conn_req.port
-                );
+                debug!("Connected to {host}:{port}, starting to proxy bytes");
                 _ = tokio::io::copy_bidirectional(&mut vsock, &mut tcp).await;
             }
             Err(err) => {
@@ -184,16 +426,70 @@ impl HostHttpProxy {
     }
 }
 
+// A special hostname "host" refers to the localhost on the outside of the enclave,
+// and so do the names of its services, each on a fixed port
+fn outside_target(services: &HashMap<String, u16>, host: &str, port: u16) -> (String, u16) {
+    match services.get(&host.to_ascii_lowercase()) {
+        Some(service_port) => ("127.0.0.1".to_string(), *service_port),
+        None if host.eq_ignore_ascii_case(OUTSIDE_HOST) => ("127.0.0.1".to_string(), port),
+        None => (host.to_string(), port),
+    }
+}
+
+// Connects to the first reachable address of host, among those it is pinned to if
+// names are pinned
+async fn connect(
+    resolver: &Resolver,
+    pins: Option<&DnsPins>,
+    host: &str,
+    port: u16,
+) -> std::io::Result<TcpStream> {
+    let addrs = match pins {
+        Some(pins) => pins.lookup(resolver, host, port).await,
+        None => resolver.lookup(host, port).await,
+    };
+    let addrs =
+        addrs.map_err(|err| std::io::Error::new(std::io::ErrorKind::NotFound, err.to_string()))?;
+
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(tcp) => return Ok(tcp),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no addresses found for {host}"),
+        )
+    }))
+}
+
 async fn proxy(
-    egress_port: u32,
+    relays: &HostRelays,
     req: Request<Body>,
-    egress_policy: &EgressPolicy,
+    egress_policy: &Arc<EgressPolicy>,
+    pool: &ConnectionPool,
+    tls: &UpstreamTls,
+    audit: &AuditLog,
+    tunnels: &Tunnels,
 ) -> Result<Response<Body>, hyper::Error> {
     if Method::CONNECT == req.method() {
-        Ok(handle_connect(egress_port, req, egress_policy).await)
+        Ok(handle_connect(relays, req, egress_policy, audit, tunnels).await)
     } else {
-        match handle_request(egress_port, req, egress_policy).await {
+        match handle_request(relays, req, egress_policy, pool, tls, audit, tunnels).await {
             Ok(resp) => Ok(resp),
+            Err(ProxyError::Denied(target)) => Ok(blocked(target)),
+            Err(err @ ProxyError::RateLimited(_)) => Ok(err_resp(
+                http::StatusCode::TOO_MANY_REQUESTS,
+                err.to_string(),
+            )),
+            Err(err @ ProxyError::RequestDenied { .. }) => {
+                Ok(err_resp(http::StatusCode::FORBIDDEN, err.to_string()))
+            }
+            Err(err @ ProxyError::InvalidTarget(_)) => Ok(bad_request(err.to_string())),
             Err(err) => Ok(err_resp(
                 http::StatusCode::SERVICE_UNAVAILABLE,
                 err.to_string(),
@@ -203,45 +499,118 @@ async fn proxy(
 }
 
 async fn handle_connect(
-    egress_port: u32,
+    relays: &HostRelays,
     req: Request<Body>,
-    egress_policy: &EgressPolicy,
+    egress_policy: &Arc<EgressPolicy>,
+    audit: &AuditLog,
+    tunnels: &Tunnels,
 ) -> Response<Body> {
     match req.uri().authority() {
         Some(authority) => {
-            let port = match authority.port() {
-                Some(port) => port.as_u16(),
-                None => {
-                    let err_msg = "CONNECT address is missing a port";
+            let target = match Target::parse(authority.as_str(), None) {
+                Ok(target) => target,
+                Err(err) => {
+                    let err_msg = format!("invalid CONNECT address: {err}");
                     error!("{err_msg}");
-                    return bad_request(err_msg.to_string());
+                    return bad_request(err_msg);
                 }
             };
 
             // Check the policy
-            if !egress_policy.is_host_allowed(authority.host()) {
-                return blocked();
+            let decision = egress_policy.decide_connect(&target.host, target.port);
+            let event = AuditEvent::new(
+                AuditKind::Connect,
+                &target.host,
+                target.port,
+                egress_policy.name(),
+                &decision,
+            );
+            if !decision.allowed {
+                audit.record(event);
+                return blocked(target.authority());
+            }
+            // What goes through a tunnel cannot be held to the request rules
+            if let Some(filter) = egress_policy.request_filter(&target.host, target.port) {
+                audit.record(request_denied(
+                    AuditKind::Connect,
+                    &target,
+                    egress_policy,
+                    &filter,
+                ));
+                return blocked(target.authority());
             }
+            let limit = match admit(egress_policy, AuditKind::Connect, &target, audit) {
+                Ok(limit) => limit,
+                Err(err) => return err_resp(http::StatusCode::TOO_MANY_REQUESTS, err.to_string()),
+            };
+            let Target { host, port } = target;
+
+            let protocol = egress_policy.protocol(&host, port);
 
-            debug!("Handling CONNECT to {}:{port}", authority.host());
+            debug!("Handling CONNECT to {host}:{port}");
 
             // Connect to remote server before the upgrade so we can return an error if it fails
-            let mut remote = match remote_connect(egress_port, authority.host(), port).await {
+            let remote = match relays.connect(&host, port, egress_policy.name()).await {
                 Ok(remote) => remote,
                 Err(err) => {
-                    return err_resp(http::StatusCode::SERVICE_UNAVAILABLE, err.to_string())
+                    audit.record(event);
+                    return err_resp(http::StatusCode::SERVICE_UNAVAILABLE, err.to_string());
                 }
             };
 
+            // Protocol rules follow plaintext protocols, which the server speaks first
+            let verify_sni = egress_policy.verifies_sni() && protocol.is_none();
+            let egress_policy = egress_policy.clone();
+            let audit = audit.clone();
+            let tracked = tunnels.track(&host, port);
+            let revocable = tunnels.open(&host, port);
+
             tokio::task::spawn(async move {
-                match hyper::upgrade::on(req).await {
-                    Ok(mut upgraded) => {
-                        _ = tokio::io::copy_bidirectional(&mut upgraded, &mut remote).await;
-                    }
+                let mut upgraded = match hyper::upgrade::on(req).await {
+                    Ok(upgraded) => upgraded,
                     Err(err) => {
                         error!("Upgrade failed: {err}");
+                        audit.record(event);
+                        return;
+                    }
+                };
+
+                let mut remote = remote;
+                let mut sent = 0;
+                if verify_sni {
+                    match check_sni(&mut upgraded, &egress_policy, port).await {
+                        Ok(hello) => {
+                            if remote.write_all(&hello).await.is_err() {
+                                audit.record(event);
+                                return;
+                            }
+                            sent = hello.len() as u64;
+                        }
+                        Err(err) => {
+                            warn!("closing CONNECT tunnel to {host}:{port}: {err}");
+                            let decision = Decision {
+                                allowed: false,
+                                rule: Some(SNI_RULE.to_string()),
+                            };
+                            audit.record(AuditEvent::new(
+                                AuditKind::Connect,
+                                &host,
+                                port,
+                                egress_policy.name(),
+                                &decision,
+                            ));
+                            return;
+                        }
                     }
                 }
+
+                let record = audit.start(event);
+                record.add_sent(sent);
+                let counts = tracked
+                    .as_ref()
+                    .map_or_else(StreamMetrics::default, |tracked| tracked.counts().clone());
+                let client = counts.wrap(Counted::new(upgraded, record));
+                splice(client, remote, protocol, limit, revocable, &host, port).await
             });
 
             Response::new(Body::empty())
@@ -254,32 +623,217 @@ async fn handle_connect(
     }
 }
 
+// Reads the ClientHello the application opens the tunnel with, and checks the
+// server name it asks for against the policy. An IP address has no server name, so
+// a ClientHello without one is let through. Returns the record it was read from, to
+// pass on to the remote.
+async fn check_sni<C: AsyncRead + Unpin>(
+    client: &mut C,
+    egress_policy: &EgressPolicy,
+    port: u16,
+) -> Result<Vec<u8>, ProxyError> {
+    let hello = read_client_hello(client).await?;
+
+    if let Some(ref server_name) = hello.server_name {
+        if !egress_policy.is_connect_allowed(server_name, port) {
+            return Err(ProxyError::Denied(format!("TLS server name {server_name}")));
+        }
+    }
+
+    Ok(hello.record)
+}
+
+// Takes a connection or request to target from the egress limit on it, if there is
+// one, and returns the limit for the bandwidth. One past the rate limit is recorded
+// as denied by the limit.
+fn admit(
+    egress_policy: &EgressPolicy,
+    kind: AuditKind,
+    target: &Target,
+    audit: &AuditLog,
+) -> Result<Option<Arc<EgressLimiter>>, ProxyError> {
+    match egress_policy.limit(&target.host, target.port) {
+        Some(limit) if !limit.admit() => {
+            let decision = Decision {
+                allowed: false,
+                rule: Some(format!("limit {}", limit.rule())),
+            };
+            audit.record(AuditEvent::new(
+                kind,
+                &target.host,
+                target.port,
+                egress_policy.name(),
+                &decision,
+            ));
+            Err(ProxyError::RateLimited(target.authority()))
+        }
+        limit => Ok(limit),
+    }
+}
+
+// A request to a host with a request rule. Upgrades are refused, as they turn the
+// connection into a tunnel.
+fn check_request(req: &Request<Body>, filter: &RequestFilter) -> Result<(), String> {
+    if req.headers().contains_key(hyper::header::UPGRADE) {
+        return Err("upgrades are not allowed".to_string());
+    }
+    let content_length = req
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse().ok());
+
+    filter.check(
+        req.method().as_str(),
+        req.uri().path(),
+        content_length,
+        |name| req.headers().contains_key(name),
+    )
+}
+
+fn request_denied(
+    kind: AuditKind,
+    target: &Target,
+    egress_policy: &EgressPolicy,
+    filter: &RequestFilter,
+) -> AuditEvent {
+    let decision = Decision {
+        allowed: false,
+        rule: Some(format!("request {}", filter.rule())),
+    };
+    AuditEvent::new(
+        kind,
+        &target.host,
+        target.port,
+        egress_policy.name(),
+        &decision,
+    )
+}
+
+// Cuts a body without a declared length off once it grows past max, which fails the
+// request to the origin midway
+fn limit_body(body: Body, max: Option<u64>) -> Body {
+    let Some(max) = max else {
+        return body;
+    };
+    if hyper::body::HttpBody::is_end_stream(&body) {
+        return body;
+    }
+
+    let mut sent = 0u64;
+    Body::wrap_stream(body.map(
+        move |chunk| -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+            let chunk = chunk?;
+            sent += chunk.len() as u64;
+            if sent > max {
+                return Err(format!("request body is over {max} bytes").into());
+            }
+            Ok(chunk)
+        },
+    ))
+}
+
+// Copies bytes between the application and the host relay until either side is
+// done, following the protocol along the way if a protocol rule allowed it, and
+// holding back what the application sends to the bandwidth of the limit, if any.
+// A tunnel a policy update revokes is cut short.
+pub(crate) async fn splice<C, R>(
+    client: C,
+    mut remote: R,
+    protocol: Option<ProtocolMatch>,
+    limit: Option<Arc<EgressLimiter>>,
+    revocable: Option<Revocable>,
+    host: &str,
+    port: u16,
+) where
+    C: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client = Throttled::new(client, limit);
+    let relay = async {
+        match protocol {
+            Some(rule) => {
+                let inspector = ProtocolInspector::new(rule.protocol, host, port)
+                    .with_require_tls(rule.require_tls);
+                let inspector = Arc::new(Mutex::new(inspector));
+                let mut client = Inspected::new(client, inspector.clone(), Direction::ToServer);
+                let mut server = Inspected::new(remote, inspector, Direction::ToClient);
+                _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+            }
+            None => {
+                _ = tokio::io::copy_bidirectional(&mut client, &mut remote).await;
+            }
+        }
+    };
+
+    match revocable {
+        Some(mut revocable) => tokio::select! {
+            _ = relay => (),
+            _ = revocable.revoked() => {
+                info!("Closed the tunnel to {host}:{port}, a policy update revoked it");
+            }
+        },
+        None => relay.await,
+    }
+}
+
 async fn handle_request(
-    egress_port: u32,
+    relays: &HostRelays,
     mut req: Request<Body>,
     egress_policy: &EgressPolicy,
-) -> anyhow::Result<Response<Body>> {
-    let host = match req.uri().host() {
-        Some(host) => host,
+    pool: &ConnectionPool,
+    tls: &UpstreamTls,
+    audit: &AuditLog,
+    tunnels: &Tunnels,
+) -> Result<Response<Body>, ProxyError> {
+    let authority = match req.uri().authority() {
+        Some(authority) => authority,
         None => return Ok(bad_request("URI is missing a host".to_string())),
     };
-    let port = req.uri().port_u16().unwrap_or(80);
+    let scheme = match req.uri().scheme() {
+        Some(scheme) if *scheme == Scheme::HTTPS => Scheme::HTTPS,
+        _ => Scheme::HTTP,
+    };
+    let default_port = if scheme == Scheme::HTTPS { 443 } else { 80 };
+    let target = Target::parse(authority.as_str(), Some(default_port))
+        .map_err(|err| ProxyError::InvalidTarget(format!("invalid URI host: {err}")))?;
 
     // Check the policy
-    if !egress_policy.is_host_allowed(host) {
-        return Ok(blocked());
+    let decision = egress_policy.decide_host(&target.host, target.port);
+    let event = AuditEvent::new(
+        AuditKind::Request,
+        &target.host,
+        target.port,
+        egress_policy.name(),
+        &decision,
+    );
+    if !decision.allowed {
+        audit.record(event);
+        return Err(ProxyError::Denied(target.authority()));
     }
+    let filter = egress_policy.request_filter(&target.host, target.port);
+    if let Some(ref filter) = filter {
+        if let Err(reason) = check_request(&req, filter) {
+            audit.record(request_denied(
+                AuditKind::Request,
+                &target,
+                egress_policy,
+                filter,
+            ));
+            return Err(ProxyError::RequestDenied {
+                target: target.authority(),
+                reason,
+            });
+        }
+    }
+    let limit = admit(egress_policy, AuditKind::Request, &target, audit)?;
+    let record = audit.start(event);
 
-    // TODO: pool connections
-    let stream = remote_connect(egress_port, host, port).await?;
-
-    // Set the Host: header to match the URL
+    // The Host: header or :authority to match the URL
     let host_hdr = match req.uri().port() {
-        Some(port) => format!("{host}:{port}"),
-        None => host.to_string(),
+        Some(_) => target.authority(),
+        None if target.host.contains(':') => format!("[{}]", target.host),
+        None => target.host.clone(),
     };
-    req.headers_mut()
-        .insert(hyper::header::HOST, HeaderValue::from_str(&host_hdr)?);
 
     // If a proxy receives an OPTIONS request with an absolute-form of
     // request-target in which the URI has an empty path and no query
@@ -296,11 +850,129 @@ async fn handle_request(
         }
     };
 
-    *req.uri_mut() = http::Uri::builder().path_and_query(pq).build()?;
+    // An upgrade, e.g. to a WebSocket, takes over the connection to the origin, so it
+    // gets one of its own, over HTTP/1.1
+    let upgrade = req.headers().contains_key(hyper::header::UPGRADE);
+
+    let key = format!("{scheme}://{}", target.authority());
+    let pooled = if upgrade { None } else { pool.checkout(&key) };
+    let mut conn = match pooled {
+        Some(conn) => conn,
+        None => {
+            let stream = relays
+                .connect(&target.host, target.port, egress_policy.name())
+                .await?;
+
+            if scheme == Scheme::HTTPS {
+                // HTTP/2 if the origin picks it over ALPN
+                let name = ServerName::try_from(target.host.as_str()).map_err(|_| {
+                    ProxyError::InvalidTarget(format!("invalid TLS server name {}", target.host))
+                })?;
+                let stream = tls.connector(!upgrade).connect(name, stream).await?;
+                let http2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                handshake(stream, http2).await?
+            } else {
+                let http2 =
+                    !upgrade && egress_policy.is_http2_prior_knowledge(&target.host, target.port);
+                handshake(stream, http2).await?
+            }
+        }
+    };
+
+    if conn.http2 {
+        // HTTP/2 carries the origin in the :scheme and :authority pseudo-headers
+        req.headers_mut().remove(hyper::header::HOST);
+        *req.uri_mut() = http::Uri::builder()
+            .scheme(scheme)
+            .authority(host_hdr)
+            .path_and_query(pq)
+            .build()?;
+        *req.version_mut() = Version::HTTP_2;
+    } else {
+        req.headers_mut().insert(
+            hyper::header::HOST,
+            HeaderValue::from_str(&host_hdr).map_err(http::Error::from)?,
+        );
+        *req.uri_mut() = http::Uri::builder().path_and_query(pq).build()?;
+        *req.version_mut() = Version::HTTP_11;
+    }
 
-    let (mut sender, conn) = Builder::new()
+    if !upgrade {
+        let max_body = filter.and_then(|filter| filter.max_body_bytes());
+        let req = req.map(|body| limit_body(body, max_body));
+        let req = req.map(|body| record.count_sent(throttle::throttle_body(body, limit.as_ref())));
+        let resp = conn.sender.send_request(req).await?;
+        pool.checkin(key, conn);
+        return Ok(resp.map(|body| record.count_received(body)));
+    }
+
+    // Once the origin switches protocols, both connections are handed off and
+    // spliced, and tracked and revoked, as for CONNECT
+    let client = hyper::upgrade::on(&mut req);
+    let mut resp = conn.sender.send_request(req).await?;
+    if resp.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+        let origin = hyper::upgrade::on(&mut resp);
+        let tracked = tunnels.track(&target.host, target.port);
+        let revocable = tunnels.open(&target.host, target.port);
+        let Target { host, port } = target;
+        tokio::task::spawn(async move {
+            match tokio::try_join!(client, origin) {
+                Ok((client, origin)) => {
+                    let counts = tracked
+                        .as_ref()
+                        .map_or_else(StreamMetrics::default, |tracked| tracked.counts().clone());
+                    let client = counts.wrap(Counted::new(client, record));
+                    splice(client, origin, None, limit, revocable, &host, port).await
+                }
+                Err(err) => error!("Upgrade failed: {err}"),
+            }
+        });
+        return Ok(resp);
+    }
+
+    Ok(resp.map(|body| record.count_received(body)))
+}
+
+// TLS to the origins of https:// requests, which offers HTTP/2 over ALPN unless the
+// request is an upgrade
+#[derive(Clone)]
+struct UpstreamTls {
+    http2: TlsConnector,
+    http1: TlsConnector,
+}
+
+impl UpstreamTls {
+    fn new(tls_config: ClientConfig) -> Self {
+        let with_alpn = |protocols: &[&[u8]]| {
+            let mut tls_config = tls_config.clone();
+            tls_config.alpn_protocols = protocols.iter().map(|p| p.to_vec()).collect();
+            TlsConnector::from(Arc::new(tls_config))
+        };
+
+        Self {
+            http2: with_alpn(&[b"h2", b"http/1.1"]),
+            http1: with_alpn(&[b"http/1.1"]),
+        }
+    }
+
+    fn connector(&self, http2: bool) -> &TlsConnector {
+        if http2 {
+            &self.http2
+        } else {
+            &self.http1
+        }
+    }
+}
+
+// Starts HTTP/1.1 on a connection to the origin, or HTTP/2 if it is known to speak it
+async fn handshake<S>(stream: S, http2: bool) -> Result<Connection, ProxyError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, conn) = Builder::new()
         .http1_preserve_header_case(true)
         .http1_title_case_headers(true)
+        .http2_only(http2)
         .handshake(stream)
         .await?;
 
@@ -310,7 +982,7 @@ async fn handle_request(
         _ = conn.await;
     });
 
-    Ok(sender.send_request(req).await?)
+    Ok(Connection { sender, http2 })
 }
 
 fn err_resp(status: http::StatusCode, msg: String) -> Response<Body> {
@@ -323,10 +995,10 @@ fn bad_request(msg: String) -> Response<Body> {
     err_resp(http::StatusCode::BAD_REQUEST, msg)
 }
 
-fn blocked() -> Response<Body> {
+fn blocked(target: String) -> Response<Body> {
     err_resp(
         http::StatusCode::UNAUTHORIZED,
-        "blocked by egress security policy".to_string(),
+        ProxyError::Denied(target).to_string(),
     )
 }
 
@@ -345,39 +1017,89 @@ fn is_empty(pq: Option<&PathAndQuery>) -> bool {
     }
 }
 
-// connects to the host via vsock and then asks it to
-// connect to the remote address
-async fn remote_connect(egress_port: u32, host: &str, port: u16) -> anyhow::Result<VsockStream> {
-    let mut vsock = VsockStream::connect(crate::vsock::VMADDR_CID_HOST, egress_port).await?;
+// Connects to the relay on the host listening on vsock egress_port, and then asks
+// it to connect to the remote address. The vsock connection is given timeout, and
+// the answer of the relay, which waits for the remote, response_timeout.
+pub(crate) async fn relay_connect(
+    egress_port: u32,
+    timeout: Duration,
+    response_timeout: Duration,
+    host: &str,
+    port: u16,
+    policy: Option<&str>,
+) -> Result<VsockStream, ProxyError> {
+    let connect = VsockStream::connect(crate::vsock::VMADDR_CID_HOST, egress_port);
+    let mut vsock = tokio::time::timeout(timeout, connect)
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
     debug!(
         "Connected to vsock {}:{}, sending connect request",
         crate::vsock::VMADDR_CID_HOST,
         egress_port
     );
 
-    ConnectRequest::new(host.to_string(), port)
+    Versioned::new(ConnectRequest::new(host.to_string(), port, policy))
         .send(&mut vsock)
         .await?;
     debug!("Sent request to connect to {host}:{port}");
 
-    match ConnectResponse::recv(&mut vsock).await? {
+    let response = tokio::time::timeout(response_timeout, ConnectResponse::recv(&mut vsock))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    match response {
         ConnectResponse::Ok => Ok(vsock),
-        ConnectResponse::Err { os_code, message } => Err(anyhow!("os_err: {os_code}: {message}")),
+        ConnectResponse::Err { os_code, message } => Err(ProxyError::ConnectFailed {
+            target: Target {
+                host: host.to_string(),
+                port,
+            }
+            .authority(),
+            os_code,
+            message,
+        }),
     }
 }
 
+// Checks that the relay on egress_port answers a connect request, one for no host that
+// it answers without connecting anywhere
+pub(crate) async fn probe_relay(egress_port: u32, timeout: Duration) -> Result<(), ProxyError> {
+    let probe = async {
+        let mut vsock = VsockStream::connect(crate::vsock::VMADDR_CID_HOST, egress_port).await?;
+        Versioned::new(ConnectRequest::probe())
+            .send(&mut vsock)
+            .await?;
+        ConnectResponse::recv(&mut vsock).await?;
+        Ok::<_, ProxyError>(())
+    };
+
+    tokio::time::timeout(timeout, probe)
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))?
+}
+
 #[cfg(test)]
 mod tests {
+    use super::{outside_target, probe_relay, relay_connect, ConnectRequest, HostPolicies};
+    use crate::connections::ConnectionTable;
+    use crate::manifest::{RevokeMode, RevokedConnections};
+    use crate::proxy::audit::{AuditKind, AuditLog, Verdict};
+    use crate::proxy::host_relays::HostRelays;
+    use crate::proxy::revoke::Revoker;
+    use crate::proxy::sni::tests::client_hello;
     use assert2::assert;
     use http::{uri::PathAndQuery, Method, Version};
     use hyper::server::conn::AddrIncoming;
     use hyper::{Body, Request, Response, Server};
     use rand::RngCore;
+    use std::collections::HashMap;
     use std::convert::Infallible;
     use std::future::Future;
     use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
+    use std::time::Duration;
     use tls_listener::TlsListener;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::task::JoinHandle;
 
     async fn echo(req: Request<Body>) -> Result<Response<Body>, Infallible> {
@@ -432,7 +1154,7 @@ mod tests {
         let proxy = super::EnclaveHttpProxy::bind(proxy_port).await.unwrap();
         let policy = Arc::new(crate::policy::EgressPolicy::allow_all());
         tokio::task::spawn(async move {
-            proxy.serve(egress_port, policy).await;
+            proxy.serve(HostRelays::new([egress_port]), policy).await;
         })
     }
 
@@ -529,6 +1251,448 @@ mod tests {
         fixture.stop().await;
     }
 
+    #[tokio::test]
+    async fn test_relay_failover() {
+        const PORT: u16 = 3600;
+
+        // The first relay is not there, so connections fail over to the second
+        let proxy = super::EnclaveHttpProxy::bind(PORT).await.unwrap();
+        let relays = HostRelays::new([PORT as u32 + 2, PORT as u32]);
+        let policy = Arc::new(crate::policy::EgressPolicy::allow_all());
+        let enclave_proxy_task = tokio::task::spawn(proxy.serve(relays, policy));
+        let host_proxy_task = start_host_proxy(PORT as u32);
+        let echo_task = start_echo_server(PORT + 1, false);
+
+        let timeout = std::time::Duration::from_secs(1);
+        assert!(probe_relay(PORT as u32, timeout).await.is_ok());
+        assert!(probe_relay(PORT as u32 + 2, timeout).await.is_err());
+
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::http(format!("http://127.0.0.1:{PORT}")).unwrap())
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            let resp = client
+                .post(format!("http://localhost:{}/echo", PORT + 1))
+                .body("ping")
+                .send()
+                .await
+                .unwrap();
+            assert!(resp.bytes().await.unwrap() == "ping");
+        }
+
+        echo_task.abort();
+        _ = echo_task.await;
+
+        enclave_proxy_task.abort();
+        _ = enclave_proxy_task.await;
+
+        host_proxy_task.abort();
+        _ = host_proxy_task.await;
+    }
+
+    #[tokio::test]
+    async fn test_relay_response_timeout() {
+        const PORT: u32 = 3650;
+
+        // A relay that accepts the connection, but never answers the request
+        let mut listener =
+            tokio_vsock::VsockListener::bind(crate::vsock::VMADDR_CID_ANY, PORT).unwrap();
+        let stuck_task = tokio::task::spawn(async move {
+            let (_conn, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let timeout = Duration::from_secs(1);
+        let connect = relay_connect(PORT, timeout, timeout, "localhost", 443, None).await;
+        assert!(matches!(
+            connect,
+            Err(super::ProxyError::Io(err)) if err.kind() == std::io::ErrorKind::TimedOut
+        ));
+
+        stuck_task.abort();
+        _ = stuck_task.await;
+    }
+
+    #[tokio::test]
+    async fn test_connection_reuse() {
+        const PORT: u16 = 3100;
+
+        let enclave_proxy_task = start_enclave_proxy(PORT, PORT as u32).await;
+        let host_proxy_task = start_host_proxy(PORT as u32);
+
+        // Counts the connections the proxy opens to the server
+        let connections = Arc::new(AtomicUsize::new(0));
+        let make_svc = hyper::service::make_service_fn({
+            let connections = connections.clone();
+            move |_conn| {
+                connections.fetch_add(1, Ordering::SeqCst);
+                async { Ok::<_, Infallible>(hyper::service::service_fn(echo)) }
+            }
+        });
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, PORT + 1));
+        let echo_task = tokio::task::spawn(Server::bind(&addr).serve(make_svc));
+
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::http(format!("http://127.0.0.1:{PORT}")).unwrap())
+            .build()
+            .unwrap();
+
+        for _ in 0..3 {
+            let resp = client
+                .post(format!("http://localhost:{}/echo", PORT + 1))
+                .body("ping")
+                .send()
+                .await
+                .unwrap();
+            assert!(resp.bytes().await.unwrap() == "ping");
+        }
+        assert!(connections.load(Ordering::SeqCst) == 1);
+
+        echo_task.abort();
+        _ = echo_task.await;
+
+        enclave_proxy_task.abort();
+        _ = enclave_proxy_task.await;
+
+        host_proxy_task.abort();
+        _ = host_proxy_task.await;
+    }
+
+    #[tokio::test]
+    async fn test_http2_upstream() {
+        const PORT: u16 = 3200;
+
+        async fn version(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+            Ok(Response::new(format!("{:?}", req.version()).into()))
+        }
+
+        // Cleartext HTTP/2 only, and HTTP/2 picked over ALPN
+        let h2c_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, PORT + 1));
+        let h2c_task = tokio::task::spawn(Server::bind(&h2c_addr).http2_only(true).serve(
+            hyper::service::make_service_fn(|_conn| async {
+                Ok::<_, Infallible>(hyper::service::service_fn(version))
+            }),
+        ));
+
+        let mut server_config = (*crate::tls::test_server_config().unwrap()).clone();
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let acceptor: tokio_rustls::TlsAcceptor = Arc::new(server_config).into();
+        let h2_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, PORT + 2));
+        let incoming = TlsListener::new(acceptor, AddrIncoming::bind(&h2_addr).unwrap());
+        let h2_task = tokio::task::spawn(Server::builder(incoming).serve(
+            hyper::service::make_service_fn(|_conn| async {
+                Ok::<_, Infallible>(hyper::service::service_fn(version))
+            }),
+        ));
+
+        let policy = Arc::new(crate::policy::EgressPolicy::new(&crate::manifest::Egress {
+            allow: Some(vec!["localhost".to_string()]),
+            http2_prior_knowledge: Some(vec![format!("localhost:{}", PORT + 1)]),
+            ..Default::default()
+        }));
+        let tls_config = (*crate::tls::load_insecure_client_config().unwrap()).clone();
+        let proxy = super::EnclaveHttpProxy::bind(PORT)
+            .await
+            .unwrap()
+            .with_tls_config(tls_config);
+        let enclave_proxy_task =
+            tokio::task::spawn(proxy.serve(HostRelays::new([PORT as u32]), policy));
+        let host_proxy_task = start_host_proxy(PORT as u32);
+
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", PORT))
+            .await
+            .unwrap();
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::task::spawn(conn);
+
+        for uri in [
+            format!("http://localhost:{}/", PORT + 1),
+            format!("https://localhost:{}/", PORT + 2),
+        ] {
+            let req = Request::get(uri).body(Body::empty()).unwrap();
+            let resp = sender.send_request(req).await.unwrap();
+            assert!(resp.status() == http::StatusCode::OK);
+            assert!(hyper::body::to_bytes(resp.into_body()).await.unwrap() == "HTTP/2.0");
+        }
+
+        h2c_task.abort();
+        _ = h2c_task.await;
+
+        h2_task.abort();
+        _ = h2_task.await;
+
+        enclave_proxy_task.abort();
+        _ = enclave_proxy_task.await;
+
+        host_proxy_task.abort();
+        _ = host_proxy_task.await;
+    }
+
+    #[tokio::test]
+    async fn test_audit() {
+        const PORT: u16 = 3400;
+
+        let policy = Arc::new(crate::policy::EgressPolicy::new(&crate::manifest::Egress {
+            allow: Some(vec![format!("localhost:{}", PORT + 1)]),
+            ..Default::default()
+        }));
+        let audit = AuditLog::default();
+        let proxy = super::EnclaveHttpProxy::bind(PORT)
+            .await
+            .unwrap()
+            .with_audit(audit.clone());
+        let enclave_proxy_task =
+            tokio::task::spawn(proxy.serve(HostRelays::new([PORT as u32]), policy));
+        let host_proxy_task = start_host_proxy(PORT as u32);
+        let echo_task = start_echo_server(PORT + 1, false);
+
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::http(format!("http://127.0.0.1:{PORT}")).unwrap())
+            .build()
+            .unwrap();
+
+        let resp = client
+            .post(format!("http://localhost:{}/echo", PORT + 1))
+            .body(random_bytes(1000))
+            .send()
+            .await
+            .unwrap();
+        assert!(resp.bytes().await.unwrap().len() == 1000);
+
+        let resp = client.get("http://example.com/").send().await.unwrap();
+        assert!(resp.status() == reqwest::StatusCode::UNAUTHORIZED);
+
+        // An allowed request is recorded once both bodies are done with
+        let mut events = audit.events();
+        for _ in 0..50 {
+            if events.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            events = audit.events();
+        }
+        events.sort_by_key(|e| e.host.clone());
+
+        assert!(events.len() == 2);
+        assert!(events[0].host == "example.com");
+        assert!(events[0].port == 80);
+        assert!(events[0].verdict == Verdict::Denied);
+        assert!(events[0].rule == None);
+
+        assert!(events[1].host == "localhost");
+        assert!(events[1].kind == AuditKind::Request);
+        assert!(events[1].verdict == Verdict::Allowed);
+        assert!(events[1].rule == Some(format!("allow localhost:{}", PORT + 1)));
+        assert!(events[1].bytes_sent == 1000);
+        assert!(events[1].bytes_received == 1000);
+
+        echo_task.abort();
+        _ = echo_task.await;
+
+        enclave_proxy_task.abort();
+        _ = enclave_proxy_task.await;
+
+        host_proxy_task.abort();
+        _ = host_proxy_task.await;
+    }
+
+    #[tokio::test]
+    async fn test_verify_sni() {
+        const PORT: u16 = 3500;
+
+        let policy = Arc::new(crate::policy::EgressPolicy::new(&crate::manifest::Egress {
+            allow: Some(vec![format!("localhost:{}", PORT + 1)]),
+            verify_sni: Some(true),
+            ..Default::default()
+        }));
+        let audit = AuditLog::default();
+        let proxy = super::EnclaveHttpProxy::bind(PORT)
+            .await
+            .unwrap()
+            .with_audit(audit.clone());
+        let enclave_proxy_task =
+            tokio::task::spawn(proxy.serve(HostRelays::new([PORT as u32]), policy));
+        let host_proxy_task = start_host_proxy(PORT as u32);
+        let echo_task = start_echo_server(PORT + 1, true);
+
+        // The server name matches the CONNECT authority
+        let client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(format!("http://127.0.0.1:{PORT}")).unwrap())
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let resp = client
+            .post(format!("https://localhost:{}/echo", PORT + 1))
+            .body(random_bytes(1000))
+            .send()
+            .await
+            .unwrap();
+        assert!(resp.bytes().await.unwrap().len() == 1000);
+
+        // An allowed CONNECT, followed by a ClientHello for somewhere else
+        let mut tcp = tokio::net::TcpStream::connect(("127.0.0.1", PORT))
+            .await
+            .unwrap();
+        let connect = format!(
+            "CONNECT localhost:{0} HTTP/1.1\r\nHost: localhost:{0}\r\n\r\n",
+            PORT + 1
+        );
+        tcp.write_all(connect.as_bytes()).await.unwrap();
+        let mut buf = vec![0u8; 1024];
+        let len = tcp.read(&mut buf).await.unwrap();
+        assert!(buf[..len].starts_with(b"HTTP/1.1 200"));
+
+        tcp.write_all(&client_hello("evil.example.com"))
+            .await
+            .unwrap();
+        assert!(matches!(tcp.read(&mut buf).await, Ok(0) | Err(_)));
+
+        let events = audit.events();
+        assert!(events
+            .iter()
+            .any(|e| e.verdict == Verdict::Denied && e.rule.as_deref() == Some("verify_sni")));
+
+        echo_task.abort();
+        _ = echo_task.await;
+
+        enclave_proxy_task.abort();
+        _ = enclave_proxy_task.await;
+
+        host_proxy_task.abort();
+        _ = host_proxy_task.await;
+    }
+
+    #[tokio::test]
+    async fn test_upgrade() {
+        const PORT: u16 = 3300;
+
+        let connections = ConnectionTable::default();
+        let revoker = Revoker::new(Some(&RevokedConnections {
+            mode: RevokeMode::Terminate,
+            drain_seconds: None,
+        }));
+        let proxy = super::EnclaveHttpProxy::bind(PORT)
+            .await
+            .unwrap()
+            .with_connection_table(Some(connections.clone()))
+            .with_revoker(revoker.clone());
+        let policy = Arc::new(crate::policy::EgressPolicy::allow_all());
+        let enclave_proxy_task =
+            tokio::task::spawn(proxy.serve(HostRelays::new([PORT as u32]), policy));
+        let host_proxy_task = start_host_proxy(PORT as u32);
+
+        // Switches to echoing raw bytes, like a WebSocket server would to frames
+        async fn switch(mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
+            assert!(req.headers()[hyper::header::UPGRADE] == "websocket");
+            tokio::task::spawn(async move {
+                let mut upgraded = hyper::upgrade::on(&mut req).await.unwrap();
+                let (mut r, mut w) = tokio::io::split(&mut upgraded);
+                _ = tokio::io::copy(&mut r, &mut w).await;
+            });
+
+            let resp = Response::builder()
+                .status(http::StatusCode::SWITCHING_PROTOCOLS)
+                .header(hyper::header::CONNECTION, "upgrade")
+                .header(hyper::header::UPGRADE, "websocket")
+                .body(Body::empty())
+                .unwrap();
+            Ok(resp)
+        }
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, PORT + 1));
+        let ws_task =
+            tokio::task::spawn(Server::bind(&addr).serve(hyper::service::make_service_fn(
+                |_conn| async { Ok::<_, Infallible>(hyper::service::service_fn(switch)) },
+            )));
+
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", PORT))
+            .await
+            .unwrap();
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::task::spawn(conn);
+
+        let req = Request::get(format!("http://localhost:{}/chat", PORT + 1))
+            .header(hyper::header::CONNECTION, "upgrade")
+            .header(hyper::header::UPGRADE, "websocket")
+            .body(Body::empty())
+            .unwrap();
+        let mut resp = sender.send_request(req).await.unwrap();
+        assert!(resp.status() == http::StatusCode::SWITCHING_PROTOCOLS);
+
+        let mut upgraded = hyper::upgrade::on(&mut resp).await.unwrap();
+        upgraded.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        upgraded.read_exact(&mut buf).await.unwrap();
+        assert!(&buf == b"ping");
+
+        // The upgraded connection is listed and revoked like a CONNECT tunnel
+        let snapshot = connections.snapshot();
+        assert!(snapshot.len() == 1);
+        assert!(snapshot[0].target == format!("localhost:{}", PORT + 1));
+
+        let restricted = crate::policy::EgressPolicy::new(&crate::manifest::Egress {
+            allow: Some(vec!["example.com".to_string()]),
+            ..Default::default()
+        });
+        assert!(revoker.revoke(&restricted) == 1);
+        let read = tokio::time::timeout(Duration::from_secs(1), upgraded.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0))));
+
+        ws_task.abort();
+        _ = ws_task.await;
+
+        enclave_proxy_task.abort();
+        _ = enclave_proxy_task.await;
+
+        host_proxy_task.abort();
+        _ = host_proxy_task.await;
+    }
+
+    #[test]
+    fn test_outside_target() {
+        let services = HashMap::from([("redis.host.internal".to_string(), 6379)]);
+
+        assert!(
+            outside_target(&services, "Redis.host.internal", 80) == ("127.0.0.1".to_string(), 6379)
+        );
+        assert!(outside_target(&services, "HOST", 8080) == ("127.0.0.1".to_string(), 8080));
+        assert!(outside_target(&services, "example.com", 443) == ("example.com".to_string(), 443));
+    }
+
+    #[test]
+    fn test_host_policies() {
+        let egress: crate::manifest::Egress = serde_yaml::from_str(
+            r#"
+allow: ["api.example.com:443"]
+protocols:
+  - { protocol: smtp, allow: ["smtp.example.com"], ports: [587] }
+proxies:
+  - { name: metrics, proxy_port: 10002, allow: ["metrics.example.net"] }
+imds_relay: { listen_port: 9001 }
+"#,
+        )
+        .unwrap();
+        let policies = HostPolicies::new(&egress);
+        let allows = |host: &str, port, policy: Option<&str>| {
+            policies.allows(&ConnectRequest::new(host.to_string(), port, policy))
+        };
+
+        assert!(allows("api.example.com", 443, None));
+        assert!(allows("smtp.example.com", 587, None));
+        assert!(!allows("api.example.com", 80, None));
+        assert!(!allows("metrics.example.net", 443, None));
+
+        // Each connection is checked against the policy that allowed it in the enclave
+        assert!(allows("metrics.example.net", 443, Some("metrics")));
+        assert!(!allows("api.example.com", 443, Some("metrics")));
+        assert!(!allows("api.example.com", 443, Some("unknown")));
+
+        // odyn reaches IMDS for the relay, and only IMDS, by a policy of its own
+        assert!(allows("169.254.169.254", 80, Some("imds_relay")));
+        assert!(!allows("169.254.169.254", 80, None));
+        assert!(!allows("api.example.com", 443, Some("imds_relay")));
+    }
+
     #[tokio::test]
     async fn test_https_proxy() {
         let fixture = HttpProxyFixture::start(4000, true).await;
@@ -0,0 +1,46 @@
+use thiserror::Error;
+
+/// Why the egress proxy refused or failed to carry a connection
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    /// The requested host and port could not be read unambiguously
+    #[error("{0}")]
+    InvalidTarget(String),
+
+    /// The egress policy does not allow the target
+    #[error("{0} is blocked by egress security policy")]
+    Denied(String),
+
+    /// A request rule of the target does not allow the request
+    #[error("{target} does not allow the request: {reason}")]
+    RequestDenied { target: String, reason: String },
+
+    /// The rate limit of an egress limit on the target is used up
+    #[error("{0} is over its egress rate limit")]
+    RateLimited(String),
+
+    /// The host side of the proxy could not reach the target
+    #[error("failed to connect to {target}: os_err: {os_code}: {message}")]
+    ConnectFailed {
+        target: String,
+        os_code: i32,
+        message: String,
+    },
+
+    /// TLS that odyn originates to the target could not be set up
+    #[error("TLS to {target} failed: {message}")]
+    Tls { target: String, message: String },
+
+    /// A message relayed over vsock between the enclave and the host was malformed
+    #[error("malformed relay message: {0}")]
+    Relay(#[from] serde_json::Error),
+
+    #[error("invalid request: {0}")]
+    InvalidRequest(#[from] http::Error),
+
+    #[error(transparent)]
+    Http(#[from] hyper::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
@@ -0,0 +1,206 @@
+//! Egress for clients that cannot use an HTTP proxy at all, e.g. database drivers
+//! and Kafka clients. Each forward listens on a port inside the enclave and pipes
+//! every connection to one fixed remote, as if it had asked for a CONNECT tunnel.
+//! A forward can also originate TLS to the remote, for upstreams that require a
+//! client certificate whose key should stay inside the enclave.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use log::{debug, Level};
+use rustls::ServerName;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use crate::manifest::EgressForward;
+use crate::policy::EgressPolicy;
+use crate::proxy::egress_http::splice;
+use crate::proxy::error::ProxyError;
+use crate::proxy::host_relays::HostRelays;
+use crate::proxy::revoke::Revoker;
+use crate::{tls, utils};
+
+/// Where a forward that originates TLS gets the certificate chain and private key it
+/// presents, both PEM encoded. Asked for every connection, so renewed ones are used
+/// as soon as they are there.
+pub trait ClientCredentials: Send + Sync {
+    fn load(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)>;
+}
+
+/// A certificate chain and key in files inside the enclave, e.g. written by secrets
+pub struct CredentialFiles {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+impl ClientCredentials for CredentialFiles {
+    fn load(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).map_err(|err| anyhow!("{}: {err}", path.display()))
+        };
+        Ok((read(&self.cert_file)?, read(&self.key_file)?))
+    }
+}
+
+/// TLS a forward originates to its remote, with a client certificate
+pub struct OriginateTls {
+    credentials: Arc<dyn ClientCredentials>,
+    ca_file: Option<PathBuf>,
+}
+
+impl OriginateTls {
+    /// Verifies the remote against the CA certificates in ca_file, or the webpki roots
+    pub fn new(credentials: Arc<dyn ClientCredentials>, ca_file: Option<PathBuf>) -> Self {
+        Self {
+            credentials,
+            ca_file,
+        }
+    }
+
+    async fn connect<S>(&self, stream: S, host: &str) -> Result<TlsStream<S>, ProxyError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let tls_err = |message: String| ProxyError::Tls {
+            target: host.to_string(),
+            message,
+        };
+
+        let (certs, key) = self
+            .credentials
+            .load()
+            .map_err(|err| tls_err(format!("no client certificate: {err}")))?;
+        let roots = self
+            .ca_file
+            .as_ref()
+            .map(|ca_file| {
+                std::fs::read(ca_file)
+                    .map_err(|err| tls_err(format!("{}: {err}", ca_file.display())))
+            })
+            .transpose()?;
+        let config = tls::load_client_auth_config(&certs, &key, roots.as_deref())
+            .map_err(|err| tls_err(err.to_string()))?;
+        let server_name = ServerName::try_from(host).map_err(|err| tls_err(err.to_string()))?;
+
+        TlsConnector::from(config)
+            .connect(server_name, stream)
+            .await
+            .map_err(|err| tls_err(err.to_string()))
+    }
+}
+
+pub struct EgressForwarder {
+    listener: TcpListener,
+    host: String,
+    port: u16,
+    tls: Option<Arc<OriginateTls>>,
+    revoker: Option<Revoker>,
+}
+
+impl EgressForwarder {
+    pub async fn bind(forward: &EgressForward) -> Result<Self, ProxyError> {
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, forward.local_port);
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            host: forward.host.clone(),
+            port: forward.port,
+            tls: None,
+            revoker: None,
+        })
+    }
+
+    /// Originate TLS to the remote, so the application connects in plaintext
+    pub fn with_tls(mut self, tls: OriginateTls) -> Self {
+        self.tls = Some(Arc::new(tls));
+        self
+    }
+
+    /// Registers the forwarded connections with revoker, which closes them if a
+    /// policy update takes the remote away
+    pub fn with_revoker(mut self, revoker: Revoker) -> Self {
+        self.revoker = Some(revoker);
+        self
+    }
+
+    pub async fn serve(self, relays: HostRelays, egress_policy: Arc<EgressPolicy>) {
+        let target = Arc::new((self.host, self.port));
+
+        loop {
+            match self.listener.accept().await {
+                Ok((sock, _)) => {
+                    let relays = relays.clone();
+                    let egress_policy = egress_policy.clone();
+                    let target = target.clone();
+                    let tls = self.tls.clone();
+                    let revoker = self.revoker.clone();
+
+                    utils::spawn!("egress forward stream", async move {
+                        let (ref host, port) = *target;
+                        if let Err(err) = Self::service_conn(
+                            sock,
+                            &relays,
+                            &egress_policy,
+                            tls.as_deref(),
+                            revoker.as_ref(),
+                            host,
+                            port,
+                        )
+                        .await
+                        {
+                            utils::log_limited!(
+                                Level::Error,
+                                &format!("{host}:{port}"),
+                                "egress forward to {host}:{port}: {err}"
+                            );
+                        }
+                    })
+                    .expect("spawn egress forward stream");
+                }
+                Err(err) => {
+                    utils::log_limited!(Level::Error, "", "Accept failed: {err}");
+                }
+            }
+        }
+    }
+
+    async fn service_conn(
+        tcp: TcpStream,
+        relays: &HostRelays,
+        egress_policy: &EgressPolicy,
+        tls: Option<&OriginateTls>,
+        revoker: Option<&Revoker>,
+        host: &str,
+        port: u16,
+    ) -> Result<(), ProxyError> {
+        // Hosts with request rules are only reached through the HTTP proxy
+        if !egress_policy.is_connect_allowed(host, port)
+            || egress_policy.request_filter(host, port).is_some()
+        {
+            return Err(ProxyError::Denied(format!("{host}:{port}")));
+        }
+        let protocol = egress_policy.protocol(host, port);
+        let limit = egress_policy.limit(host, port);
+        if limit.as_ref().is_some_and(|limit| !limit.admit()) {
+            return Err(ProxyError::RateLimited(format!("{host}:{port}")));
+        }
+
+        debug!("Forwarding connection to {host}:{port}");
+
+        let remote = relays.connect(host, port, egress_policy.name()).await?;
+        let revocable = revoker.and_then(|revoker| revoker.open(host, port));
+        match tls {
+            // What a protocol rule would inspect is inside the TLS odyn adds
+            Some(tls) => {
+                let remote = tls.connect(remote, host).await?;
+                splice(tcp, remote, None, limit, revocable, host, port).await;
+            }
+            None => splice(tcp, remote, protocol, limit, revocable, host, port).await,
+        }
+
+        Ok(())
+    }
+}
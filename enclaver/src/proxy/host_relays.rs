@@ -0,0 +1,157 @@
+//! The egress relays on the host that the egress proxies of the enclave connect out
+//! through. There is one unless the manifest lists egress.relays, e.g. an active one
+//! and a standby on another ENI. Connections go to the first relay that is up, and
+//! move on to the next when one cannot be reached or does not answer. With more than
+//! one, a health check probes each relay in the background, so that a stuck relay is
+//! passed over before connections wait on it, and used again once it answers.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio_vsock::VsockStream;
+
+use crate::proxy::egress_http::{probe_relay, relay_connect};
+use crate::proxy::error::ProxyError;
+
+// How long a relay has to accept a connection, or to answer a probe
+const RELAY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// How long a relay has to answer a connect request, which includes connecting to the
+// remote. One that takes longer is taken to be stuck.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How often each relay is probed
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Relay {
+    port: u32,
+    up: AtomicBool,
+}
+
+impl Relay {
+    // Logs the relay going down or coming back, once per change
+    fn set_up(&self, up: bool, reason: impl std::fmt::Display) {
+        if self.up.swap(up, Ordering::Relaxed) != up {
+            if up {
+                info!("Egress relay on vsock port {} is up again", self.port);
+            } else {
+                warn!("Egress relay on vsock port {} is down: {reason}", self.port);
+            }
+        }
+    }
+}
+
+/// The vsock ports of the egress relays, in order of preference
+#[derive(Clone)]
+pub struct HostRelays {
+    relays: Arc<Vec<Relay>>,
+}
+
+impl HostRelays {
+    pub fn new(ports: impl IntoIterator<Item = u32>) -> Self {
+        let relays: Vec<_> = ports
+            .into_iter()
+            .map(|port| Relay {
+                port,
+                up: AtomicBool::new(true),
+            })
+            .collect();
+        assert!(!relays.is_empty(), "no egress relays");
+
+        Self {
+            relays: Arc::new(relays),
+        }
+    }
+
+    /// Whether there is another relay to fail over to
+    pub fn has_standby(&self) -> bool {
+        self.relays.len() > 1
+    }
+
+    // The relays that are up, then the others, in case one is back before the health
+    // check notices
+    fn candidates(&self) -> impl Iterator<Item = &Relay> {
+        let up = |relay: &&Relay| relay.up.load(Ordering::Relaxed);
+        self.relays
+            .iter()
+            .filter(up)
+            .chain(self.relays.iter().filter(move |relay| !up(relay)))
+    }
+
+    /// Asks a relay to connect to host:port, failing over to the next one as long as
+    /// the relay itself cannot be reached or does not answer in time, and marking it
+    /// down until it answers again. A remote that the relay reports as unreachable
+    /// fails the connection right away, as the other relays would not reach it either.
+    pub(crate) async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        policy: Option<&str>,
+    ) -> Result<VsockStream, ProxyError> {
+        let mut failed = None;
+        for relay in self.candidates() {
+            let connect = relay_connect(
+                relay.port,
+                RELAY_TIMEOUT,
+                RESPONSE_TIMEOUT,
+                host,
+                port,
+                policy,
+            );
+            match connect.await {
+                Ok(vsock) => {
+                    relay.set_up(true, "");
+                    return Ok(vsock);
+                }
+                Err(err @ ProxyError::ConnectFailed { .. }) => return Err(err),
+                Err(err) => {
+                    relay.set_up(false, &err);
+                    failed = Some(err);
+                }
+            }
+        }
+
+        Err(failed.expect("no egress relays"))
+    }
+
+    /// Probes every relay in turn until stopped, marking each up or down
+    pub async fn check(self) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for relay in self.relays.iter() {
+                match probe_relay(relay.port, RELAY_TIMEOUT).await {
+                    Ok(()) => relay.set_up(true, ""),
+                    Err(err) => relay.set_up(false, err),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HostRelays;
+    use assert2::assert;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_candidates() {
+        let relays = HostRelays::new([17002, 17102, 17202]);
+        let ports = |relays: &HostRelays| relays.candidates().map(|r| r.port).collect::<Vec<_>>();
+        assert!(ports(&relays) == [17002, 17102, 17202]);
+        assert!(relays.has_standby());
+
+        // A relay that is down is only tried once the others have failed
+        relays.relays[0].set_up(false, "unreachable");
+        assert!(ports(&relays) == [17102, 17202, 17002]);
+
+        relays.relays[0].set_up(true, "");
+        assert!(relays.relays[0].up.load(Ordering::Relaxed));
+        assert!(ports(&relays) == [17002, 17102, 17202]);
+
+        assert!(!HostRelays::new([17002]).has_standby());
+    }
+}
@@ -0,0 +1,165 @@
+//! A local stand-in for the instance metadata service, which odyn serves the app from
+//! an IMDSv2 client of its own. It opens sessions itself and only reads the paths it
+//! is given from IMDS, so that apps and SDKs that look for the region and the
+//! credentials of the instance role in IMDS find them there, without the app being
+//! allowed to reach IMDS.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use aws_config::imds;
+use aws_config::imds::client::error::ImdsError;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::debug;
+
+use crate::http_util::{self, HttpHandler};
+use crate::policy::requests::is_plain_path;
+
+const TOKEN_PATH: &str = "/latest/api/token";
+const TOKEN_TTL_HEADER: &str = "x-aws-ec2-metadata-token-ttl-seconds";
+const TOKEN_HEADER: &str = "x-aws-ec2-metadata-token";
+
+/// Where the relay reads IMDS from
+#[async_trait]
+pub trait ImdsSource {
+    /// The body of path, or None if IMDS has nothing there
+    async fn get(&self, path: &str) -> Result<Option<String>>;
+}
+
+#[async_trait]
+impl ImdsSource for imds::Client {
+    async fn get(&self, path: &str) -> Result<Option<String>> {
+        match imds::Client::get(self, path).await {
+            Ok(body) => Ok(Some(body)),
+            Err(ImdsError::ErrorResponse(err))
+                if err.response().status() == StatusCode::NOT_FOUND =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+pub struct ImdsRelayHandler {
+    source: Box<dyn ImdsSource + Send + Sync>,
+    paths: Vec<String>,
+
+    // Handed out for every session, and checked on every read, as IMDS would
+    token: String,
+}
+
+impl ImdsRelayHandler {
+    pub fn new(source: Box<dyn ImdsSource + Send + Sync>, paths: Vec<String>) -> Self {
+        Self {
+            source,
+            paths,
+            token: uuid::Uuid::new_v4().simple().to_string(),
+        }
+    }
+
+    fn open_session(&self, req: &Request<Body>) -> Response<Body> {
+        let Some(ttl) = req.headers().get(TOKEN_TTL_HEADER) else {
+            return http_util::bad_request(format!("{TOKEN_TTL_HEADER} is required"));
+        };
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(TOKEN_TTL_HEADER, ttl)
+            .body(Body::from(self.token.clone()))
+            .unwrap()
+    }
+
+    async fn read(&self, req: &Request<Body>) -> Result<Response<Body>> {
+        let token = req.headers().get(TOKEN_HEADER);
+        if !token.is_some_and(|token| token == self.token.as_str()) {
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body(Body::empty())?);
+        }
+
+        let path = req.uri().path();
+        if !is_plain_path(path) || !self.paths.iter().any(|p| path.starts_with(p.as_str())) {
+            debug!("IMDS relay does not serve {path}");
+            return Ok(http_util::not_found());
+        }
+
+        Ok(match self.source.get(path).await? {
+            Some(body) => Response::new(Body::from(body)),
+            None => http_util::not_found(),
+        })
+    }
+}
+
+#[async_trait]
+impl HttpHandler for ImdsRelayHandler {
+    async fn handle(&self, req: Request<Body>) -> Result<Response<Body>> {
+        match (req.method(), req.uri().path()) {
+            (&Method::PUT, TOKEN_PATH) => Ok(self.open_session(&req)),
+            (&Method::GET, _) => self.read(&req).await,
+            _ => Ok(http_util::method_not_allowed()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert2::assert;
+
+    struct Mock;
+
+    #[async_trait]
+    impl ImdsSource for Mock {
+        async fn get(&self, path: &str) -> Result<Option<String>> {
+            Ok(match path {
+                "/latest/meta-data/placement/region" => Some("eu-west-1".to_string()),
+                "/latest/user-data" => Some("secret".to_string()),
+                _ => None,
+            })
+        }
+    }
+
+    fn request(method: Method, path: &str, header: Option<(&str, &str)>) -> Request<Body> {
+        let mut req = Request::builder().method(method).uri(path);
+        if let Some((name, value)) = header {
+            req = req.header(name, value);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_imds_relay() {
+        let paths = vec!["/latest/meta-data/placement/".to_string()];
+        let handler = ImdsRelayHandler::new(Box::new(Mock), paths);
+
+        let ttl = Some((TOKEN_TTL_HEADER, "21600"));
+        let resp = handler.handle(request(Method::PUT, TOKEN_PATH, ttl)).await;
+        let resp = resp.unwrap();
+        assert!(resp.status() == StatusCode::OK);
+        assert!(resp.headers()[TOKEN_TTL_HEADER] == "21600");
+        let token = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let token = std::str::from_utf8(&token).unwrap();
+
+        let get = |path, token| request(Method::GET, path, Some((TOKEN_HEADER, token)));
+        let region = "/latest/meta-data/placement/region";
+        let resp = handler.handle(get(region, token)).await.unwrap();
+        assert!(resp.status() == StatusCode::OK);
+        assert!(hyper::body::to_bytes(resp.into_body()).await.unwrap() == "eu-west-1");
+
+        // Paths it is not given, and reads without the token, are refused
+        let escape = "/latest/meta-data/placement/../../user-data";
+        let missing = "/latest/meta-data/placement/zone";
+        let untimed = request(Method::PUT, TOKEN_PATH, None);
+        let delete = request(Method::DELETE, region, None);
+        for (req, status) in [
+            (get("/latest/user-data", token), StatusCode::NOT_FOUND),
+            (get(escape, token), StatusCode::NOT_FOUND),
+            (get(missing, token), StatusCode::NOT_FOUND),
+            (get(region, "forged"), StatusCode::UNAUTHORIZED),
+            (untimed, StatusCode::BAD_REQUEST),
+            (delete, StatusCode::METHOD_NOT_ALLOWED),
+        ] {
+            assert!(handler.handle(req).await.unwrap().status() == status);
+        }
+    }
+}
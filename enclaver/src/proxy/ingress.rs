@@ -1,83 +1,526 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::connections::{ConnectionKind, ConnectionTable, TrackedConnection};
+use crate::metrics::{ConnectionMetrics, CountedStream, StreamMetrics};
+use crate::proxy::error::ProxyError;
 use crate::{utils, vsock};
-use anyhow::Result;
 use futures::{Stream, StreamExt};
-use log::{debug, error};
-use rustls::ServerConfig;
-use tokio::io::{AsyncRead, AsyncWrite};
+use log::{debug, info, warn, Level};
+use rustls::{ServerConfig, ServerConnection};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::watch;
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
 use tokio_vsock::VsockStream;
 
-use crate::vsock::TlsServerStream;
+use crate::journal::unix_time;
+use crate::proxy::budget::{AcceptRate, ConnectionBudget};
+use crate::proxy::drain::Drain;
+use crate::proxy::keepalive::Keepalive;
+use crate::proxy::proxy_protocol::ClientIdentity;
+use crate::proxy::relay::{self, BufferBudget, Buffering};
+use crate::proxy::well_known::{self, AttestationSource};
+
+// A client that has not finished its TLS handshake by then is dropped, so slow
+// clients cannot hold on to the connection slots of a port
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A connection to an ingress port that keeps an access log, logged as JSON after
+// "ingress access: " once it closes. Each side of the proxy logs what it knows: the
+// host the address of the client, the enclave the TLS session and the port of the
+// app it went to.
+#[derive(Debug, Serialize)]
+struct AccessRecord {
+    // Unix time the connection was accepted at
+    timestamp: u64,
+    port: u16,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_port: Option<u16>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_name: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_version: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cipher: Option<String>,
+
+    // Common name of the certificate the client presented
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_cn: Option<String>,
+
+    // From the client, and back to it
+    bytes_received: u64,
+    bytes_sent: u64,
+    duration_ms: u64,
+
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl AccessRecord {
+    fn new(port: u16) -> Self {
+        Self {
+            timestamp: unix_time(),
+            port,
+            client: None,
+            target_port: None,
+            server_name: None,
+            tls_version: None,
+            cipher: None,
+            client_cn: None,
+            bytes_received: 0,
+            bytes_sent: 0,
+            duration_ms: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn with_tls(mut self, conn: &ServerConnection) -> Self {
+        let identity = ClientIdentity::from_connection(conn);
+        self.server_name = conn.server_name().map(str::to_string);
+        self.tls_version = identity.version;
+        self.cipher = conn
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()));
+        self.client_cn = identity.common_name;
+        self
+    }
+
+    // Logs the record, with the bytes counted on the stream of the client
+    fn log(mut self, counts: &StreamMetrics) {
+        self.bytes_received = counts.received_bytes();
+        self.bytes_sent = counts.sent_bytes();
+        self.duration_ms = self.started.elapsed().as_millis() as u64;
+
+        if let Ok(json) = serde_json::to_string(&self) {
+            info!("ingress access: {json}");
+        }
+    }
+}
+
+// Where and how the connections of a port are proxied to the app, the same for all
+// of them
+struct Upstream {
+    port: u16,
+    addr: SocketAddrV4,
+    keepalive: Option<Keepalive>,
+    proxy_protocol: bool,
+    routes: Arc<HashMap<String, u16>>,
+    access_log: bool,
+    connections: Option<ConnectionTable>,
+    attestation: Option<Arc<dyn AttestationSource>>,
+}
+
+impl Upstream {
+    // Lists a connection from peer to target, if there is a table to list it in
+    fn track(
+        &self,
+        peer: &str,
+        target: SocketAddrV4,
+        server_name: Option<&str>,
+    ) -> Option<TrackedConnection> {
+        let table = self.connections.as_ref()?;
+        let tracked = table.track(
+            ConnectionKind::Ingress,
+            self.port,
+            peer.to_string(),
+            target.to_string(),
+        );
+        tracked.set_server_name(server_name);
+        Some(tracked)
+    }
+}
 
 // The enclave side of the proxy. Listens on a vsock and
 // connects over the localhost to the app. The connection
 // over vsock is over the TLS. EnclaveProxy terminates the
 // TLS and connects out to the app over plain TCP.
-pub struct EnclaveProxy<S> {
-    incoming: Box<dyn Stream<Item = S> + Send>,
+//
+// The accept loop of each port only accepts. TLS handshakes and proxying run in
+// tasks of their own, so a slow client never holds up the connections behind it.
+//
+// Each TLS handshake uses the server config current when its connection is
+// accepted, so a new one can be swapped in while connections made with the
+// previous one carry on.
+pub struct EnclaveProxy {
+    incoming: Box<dyn Stream<Item = VsockStream> + Unpin + Send>,
+    tls: Option<watch::Receiver<Arc<ServerConfig>>>,
     port: u16,
+    target_port: u16,
+    limit: Option<Arc<Semaphore>>,
+    rate: Option<AcceptRate>,
+    keepalive: Option<Keepalive>,
+    buffering: Buffering,
+    buffer_budget: Option<BufferBudget>,
+    proxy_protocol: bool,
+    routes: Arc<HashMap<String, u16>>,
+    access_log: bool,
+    connections: Option<ConnectionTable>,
+    attestation: Option<Arc<dyn AttestationSource>>,
 }
 
-impl EnclaveProxy<VsockStream> {
-    pub fn bind(port: u16) -> Result<EnclaveProxy<VsockStream>> {
+impl EnclaveProxy {
+    pub fn bind(port: u16) -> Result<Self, ProxyError> {
         let incoming = vsock::serve(port as u32)?;
         Ok(Self {
             incoming: Box::new(incoming),
+            tls: None,
             port,
+            target_port: port,
+            limit: None,
+            rate: None,
+            keepalive: None,
+            buffering: Buffering::default(),
+            buffer_budget: None,
+            proxy_protocol: false,
+            routes: Arc::new(HashMap::new()),
+            access_log: false,
+            connections: None,
+            attestation: None,
         })
     }
-}
 
-impl EnclaveProxy<TlsServerStream> {
-    pub fn bind_tls(
+    pub fn bind_tls(port: u16, tls_config: Arc<ServerConfig>) -> Result<Self, ProxyError> {
+        Self::bind_reloadable_tls(port, watch::channel(tls_config).1)
+    }
+
+    /// Terminates TLS with the latest server config sent on tls_configs, e.g. once
+    /// the certificates of the port have been replaced
+    pub fn bind_reloadable_tls(
         port: u16,
-        tls_config: Arc<ServerConfig>,
-    ) -> Result<EnclaveProxy<TlsServerStream>> {
-        let incoming = vsock::tls_serve(port as u32, tls_config)?;
-        Ok(Self {
-            incoming: Box::new(incoming),
-            port,
-        })
+        tls_configs: watch::Receiver<Arc<ServerConfig>>,
+    ) -> Result<Self, ProxyError> {
+        let mut proxy = Self::bind(port)?;
+        proxy.tls = Some(tls_configs);
+        Ok(proxy)
+    }
+
+    /// Port of the app that connections are proxied to, the one of the listener
+    /// unless set
+    pub fn with_target_port(mut self, port: u16) -> Self {
+        self.target_port = port;
+        self
+    }
+
+    /// Sheds connections past max on this port, closing them as soon as they are
+    /// accepted rather than queueing them behind the open ones
+    pub fn with_max_connections(mut self, max: Option<u32>) -> Self {
+        self.limit = max.map(|max| Arc::new(Semaphore::new(max as usize)));
+        self
+    }
+
+    /// Sheds connections accepted faster than this on this port, with bursts of up
+    /// to a second's worth
+    pub fn with_accepts_per_second(mut self, rate: Option<u32>) -> Self {
+        self.rate = rate.map(AcceptRate::new);
+        self
+    }
+
+    /// Keepalive for the connections to the app
+    pub fn with_keepalive(mut self, keepalive: Option<Keepalive>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Buffering of each stream, and of the connections to the app
+    pub fn with_buffering(mut self, buffering: Buffering) -> Self {
+        self.buffering = buffering;
+        self
+    }
+
+    /// Budget the buffers of the streams are taken from, usually shared by all
+    /// ports. Streams get smaller buffers once it runs low, and are closed as soon
+    /// as they are accepted once it runs out.
+    pub fn with_buffer_budget(mut self, budget: BufferBudget) -> Self {
+        self.buffer_budget = Some(budget);
+        self
+    }
+
+    /// Tells the app who the client of each TLS connection is, with a PROXY protocol
+    /// v2 header ahead of the stream
+    pub fn with_proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Ports of the app that TLS connections asking for each server name are proxied
+    /// to instead of the one of the listener. Names are matched regardless of case.
+    pub fn with_sni_routes(mut self, routes: HashMap<String, u16>) -> Self {
+        self.routes = Arc::new(
+            routes
+                .into_iter()
+                .map(|(name, port)| (name.to_ascii_lowercase(), port))
+                .collect(),
+        );
+        self
+    }
+
+    /// Logs each connection when it closes, with its TLS session, the port of the app
+    /// it went to, the bytes it carried and how long it lasted
+    pub fn with_access_log(mut self, enabled: bool) -> Self {
+        self.access_log = enabled;
+        self
+    }
+
+    /// Lists the open connections of the port in connections, for debugging
+    pub fn with_connection_table(mut self, connections: Option<ConnectionTable>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Answers requests for well_known::ATTESTATION_PATH with documents from source,
+    /// if it is set, rather than passing them on to the app
+    pub fn with_attestation(mut self, source: Option<Arc<dyn AttestationSource>>) -> Self {
+        self.attestation = source;
+        self
     }
-}
 
-impl<S> EnclaveProxy<S>
-where
-    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-{
     pub async fn serve(self, mut shutdown: watch::Receiver<()>) {
-        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, self.port);
-        let mut incoming = Box::into_pin(self.incoming);
+        let upstream = Arc::new(Upstream {
+            port: self.port,
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, self.target_port),
+            keepalive: self.keepalive,
+            proxy_protocol: self.proxy_protocol,
+            routes: self.routes.clone(),
+            access_log: self.access_log,
+            connections: self.connections.clone(),
+            attestation: self.attestation.clone(),
+        });
+        let mut incoming = self.incoming;
+        let mut rate = self.rate;
 
-        let mut proxies = Vec::new();
+        let mut connections = JoinSet::new();
+        let mut shedding = false;
+        let mut throttled = false;
+        let mut starved = false;
         loop {
             tokio::select!(
                 Some(stream) = incoming.next() => {
-                    proxies.push(
-                        utils::spawn!("ingress stream", async move {
-                            EnclaveProxy::service_conn(stream, addr).await;
-                        })
-                            .expect("spawn ingress stream"),
-                    )
+                    if let Some(ref mut rate) = rate {
+                        if !rate.try_accept() {
+                            if !throttled {
+                                let port = self.port;
+                                warn!("ingress port {port} is over its accept rate, shedding connections");
+                                throttled = true;
+                            }
+                            continue;
+                        }
+                    }
+                    if throttled {
+                        info!("ingress port {} is back under its accept rate", self.port);
+                        throttled = false;
+                    }
+
+                    let permit = match self.limit {
+                        Some(ref limit) => match limit.clone().try_acquire_owned() {
+                            Ok(permit) => Some(permit),
+                            Err(_) => {
+                                if !shedding {
+                                    let port = self.port;
+                                    warn!("ingress port {port} is full, shedding connections");
+                                    shedding = true;
+                                }
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    if shedding {
+                        info!("ingress port {} is accepting connections again", self.port);
+                        shedding = false;
+                    }
+
+                    let reservation = match self.buffer_budget {
+                        Some(ref budget) => match budget.reserve(self.buffering) {
+                            Some(reservation) => Some(reservation),
+                            None => {
+                                if !starved {
+                                    let port = self.port;
+                                    warn!("buffer budget is used up, closing connections on ingress port {port}");
+                                    starved = true;
+                                }
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    if starved {
+                        info!("ingress buffer budget has room again on port {}", self.port);
+                        starved = false;
+                    }
+
+                    let tls = self
+                        .tls
+                        .as_ref()
+                        .map(|configs| TlsAcceptor::from(configs.borrow().clone()));
+                    let upstream = upstream.clone();
+                    let buffering = reservation
+                        .as_ref()
+                        .map_or(self.buffering, |r| r.buffering());
+                    connections.spawn(async move {
+                        EnclaveProxy::service_conn(stream, tls, &upstream, buffering).await;
+                        drop(reservation);
+                        drop(permit);
+                    });
                 }
+                // Reaped as they finish, so the set only holds open connections
+                Some(_) = connections.join_next() => {}
                 Ok(()) = shutdown.changed() => break,
             )
         }
-        futures::future::join_all(proxies).await;
+        while connections.join_next().await.is_some() {}
+    }
+
+    async fn service_conn(
+        vsock: VsockStream,
+        tls: Option<TlsAcceptor>,
+        upstream: &Upstream,
+        buffering: Buffering,
+    ) {
+        let access = upstream
+            .access_log
+            .then(|| AccessRecord::new(upstream.port));
+        let peer = match upstream.connections {
+            Some(_) => vsock
+                .peer_addr()
+                .map(|addr| format!("vsock:{}:{}", addr.cid(), addr.port()))
+                .unwrap_or_default(),
+            None => String::new(),
+        };
+
+        match tls {
+            Some(acceptor) => {
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(vsock)).await {
+                    Ok(Ok(stream)) => {
+                        let conn = stream.get_ref().1;
+                        let header = upstream
+                            .proxy_protocol
+                            .then(|| ClientIdentity::from_connection(conn).to_header());
+                        let target = conn
+                            .server_name()
+                            .and_then(|name| upstream.routes.get(&name.to_ascii_lowercase()))
+                            .map_or(upstream.addr, |port| {
+                                SocketAddrV4::new(*upstream.addr.ip(), *port)
+                            });
+                        let access = access.map(|record| record.with_tls(conn));
+                        let tracked = upstream.track(&peer, target, conn.server_name());
+                        EnclaveProxy::proxy(
+                            stream, upstream, target, buffering, header, access, tracked,
+                        )
+                        .await
+                    }
+                    Ok(Err(err)) => {
+                        utils::log_limited!(Level::Error, "", "TLS handshake failed: {err}")
+                    }
+                    Err(_) => debug!("TLS handshake timed out"),
+                }
+            }
+            None => {
+                let target = upstream.addr;
+                let tracked = upstream.track(&peer, target, None);
+                EnclaveProxy::proxy(vsock, upstream, target, buffering, None, access, tracked).await
+            }
+        }
     }
 
-    async fn service_conn(mut vsock: S, target: SocketAddrV4) {
+    async fn proxy<S>(
+        stream: S,
+        upstream: &Upstream,
+        target: SocketAddrV4,
+        buffering: Buffering,
+        mut header: Option<Vec<u8>>,
+        access: Option<AccessRecord>,
+        tracked: Option<TrackedConnection>,
+    ) where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let counts = tracked
+            .as_ref()
+            .map_or_else(StreamMetrics::default, |tracked| tracked.counts().clone());
+        let mut stream = counts.wrap(stream);
+
+        // The bytes read to tell whether the client asked for an attestation are sent
+        // on to the app after the PROXY header
+        if let Some(ref source) = upstream.attestation {
+            match well_known::intercept(&mut stream, source.as_ref()).await {
+                Some(read) => header.get_or_insert_with(Vec::new).extend(read),
+                None => {
+                    if let Some(record) = access {
+                        record.log(&counts);
+                    }
+                    return;
+                }
+            }
+        }
+
+        let keepalive = upstream.keepalive;
+        EnclaveProxy::connect_and_relay(&mut stream, target, keepalive, buffering, header).await;
+
+        if let Some(mut record) = access {
+            record.target_port = Some(target.port());
+            record.log(&counts);
+        }
+    }
+
+    async fn connect_and_relay<S>(
+        stream: &mut S,
+        target: SocketAddrV4,
+        keepalive: Option<Keepalive>,
+        buffering: Buffering,
+        header: Option<Vec<u8>>,
+    ) where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
         debug!("Connecting to {target}");
         match TcpStream::connect(&target).await {
             Ok(mut tcp) => {
+                if let Some(keepalive) = keepalive {
+                    if let Err(err) = keepalive.apply(&tcp) {
+                        warn!("Failed to set keepalive on the connection to {target}: {err}");
+                    }
+                }
+                if let Err(err) = buffering.limit(&tcp) {
+                    warn!("Failed to limit the buffers of the connection to {target}: {err}");
+                }
+
+                if let Some(header) = header {
+                    if let Err(err) = tcp.write_all(&header).await {
+                        let key = &target.to_string();
+                        utils::log_limited!(
+                            Level::Error,
+                            key,
+                            "Failed to send the PROXY header to {target}: {err}"
+                        );
+                        return;
+                    }
+                }
+
                 debug!("Connected to {target}, proxying data");
-                _ = tokio::io::copy_bidirectional(&mut vsock, &mut tcp).await;
+                let started = Instant::now();
+                if let Err(err) = buffering.relay(stream, &mut tcp).await {
+                    _ = relay::reset(&tcp);
+                    info!(
+                        "Ingress stream to {target} failed after {}s: {err}",
+                        started.elapsed().as_secs()
+                    );
+                }
             }
-            Err(err) => error!("Connection to upstream ({target}) failed: {err}"),
+            Err(err) => utils::log_limited!(
+                Level::Error,
+                &target.to_string(),
+                "Connection to upstream ({target}) failed: {err}"
+            ),
         }
     }
 }
@@ -87,36 +530,167 @@ where
 // just proxies raw bytes (no TLS termination)
 pub struct HostProxy {
     listener: TcpListener,
+    metrics: ConnectionMetrics,
+    streams: StreamMetrics,
+    budget: ConnectionBudget,
+    port_budget: ConnectionBudget,
+    keepalive: Option<Keepalive>,
+    buffering: Buffering,
+    access_log: bool,
+    drain: Drain,
 }
 
 impl HostProxy {
-    pub async fn bind(port: u16) -> Result<Self> {
-        let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
+    pub async fn bind(port: u16) -> Result<Self, ProxyError> {
+        Self::bind_on(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port).await
+    }
+
+    /// Listens on port of addr only, e.g. 127.0.0.1 or the address of one interface
+    pub async fn bind_on(addr: IpAddr, port: u16) -> Result<Self, ProxyError> {
+        let addr = SocketAddr::new(addr, port);
         Ok(Self {
             listener: TcpListener::bind(addr).await?,
+            metrics: ConnectionMetrics::default(),
+            streams: StreamMetrics::default(),
+            budget: ConnectionBudget::unlimited(),
+            port_budget: ConnectionBudget::unlimited(),
+            keepalive: None,
+            buffering: Buffering::default(),
+            access_log: false,
+            drain: Drain::default(),
         })
     }
 
+    pub fn with_metrics(mut self, metrics: ConnectionMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_stream_metrics(mut self, streams: StreamMetrics) -> Self {
+        self.streams = streams;
+        self
+    }
+
+    pub fn with_budget(mut self, budget: ConnectionBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Limits of this port alone, on top of the budget it shares with the other ports
+    pub fn with_port_budget(mut self, budget: ConnectionBudget) -> Self {
+        self.port_budget = budget;
+        self
+    }
+
+    /// Keepalive for the connections of clients
+    pub fn with_keepalive(mut self, keepalive: Option<Keepalive>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Buffering of each stream, and of the connections of clients
+    pub fn with_buffering(mut self, buffering: Buffering) -> Self {
+        self.buffering = buffering;
+        self
+    }
+
+    /// Logs each connection when it closes, with the address of the client, the bytes
+    /// it carried and how long it lasted
+    pub fn with_access_log(mut self, enabled: bool) -> Self {
+        self.access_log = enabled;
+        self
+    }
+
+    /// Stops accepting once the drain starts, and has it wait for the connections
+    /// that are open
+    pub fn with_drain(mut self, drain: Drain) -> Self {
+        self.drain = drain;
+        self
+    }
+
     pub async fn serve(self, target_cid: u32, target_port: u32) {
-        while let Ok((sock, _)) = self.listener.accept().await {
+        loop {
+            // Connections over budget wait in the listen backlog. The limits of the port
+            // come first, so a port at its own limit holds none of the shared budget.
+            let accepted = async {
+                let port_permit = self.port_budget.acquire().await;
+                let permit = self.budget.acquire().await;
+                self.listener
+                    .accept()
+                    .await
+                    .map(|accepted| (accepted, permit, port_permit))
+            };
+            let ((sock, client), permit, port_permit) = tokio::select! {
+                accepted = accepted => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(_) => break,
+                },
+                // Dropping the listener refuses the clients in its backlog too
+                _ = self.drain.started() => {
+                    info!("Ingress port {target_port} is draining, no longer accepting");
+                    break;
+                }
+            };
+            let access = self.access_log.then(|| AccessRecord {
+                client: Some(client.to_string()),
+                ..AccessRecord::new(target_port as u16)
+            });
+            let conn = self.metrics.track();
+            let draining = self.drain.track();
+            if let Some(keepalive) = self.keepalive {
+                if let Err(err) = keepalive.apply(&sock) {
+                    warn!("Failed to set keepalive on an ingress connection: {err}");
+                }
+            }
+            if let Err(err) = self.buffering.limit(&sock) {
+                warn!("Failed to limit the buffers of an ingress connection: {err}");
+            }
+            let streams = self.streams.clone();
+            let buffering = self.buffering;
+
             // TODO: don't use detached tasks
             utils::spawn!(&format!("host proxy ({target_port})"), async move {
-                HostProxy::service_conn(sock, target_cid, target_port).await;
+                let counts = StreamMetrics::default();
+                let tcp = counts.wrap(streams.wrap(sock));
+                HostProxy::service_conn(tcp, &streams, buffering, target_cid, target_port).await;
+                if let Some(record) = access {
+                    record.log(&counts);
+                }
+                drop(conn);
+                drop(draining);
+                drop(permit);
+                drop(port_permit);
             })
             .expect("spawn host proxy");
         }
     }
 
-    async fn service_conn(mut tcp: TcpStream, target_cid: u32, target_port: u32) {
+    async fn service_conn(
+        mut tcp: CountedStream<CountedStream<TcpStream>>,
+        streams: &StreamMetrics,
+        buffering: Buffering,
+        target_cid: u32,
+        target_port: u32,
+    ) {
         debug!("Connecting to CID={target_cid} port={target_port}");
         match VsockStream::connect(target_cid, target_port).await {
             Ok(mut vsock) => {
                 debug!("Connected to {target_port}:{target_cid}, proxying data");
-                _ = tokio::io::copy_bidirectional(&mut vsock, &mut tcp).await;
-            }
-            Err(err) => {
-                error!("Connection to upstream vsock ({target_cid}:{target_port}) failed: {err}")
+                let started = Instant::now();
+                if let Err(err) = buffering.relay(&mut vsock, &mut tcp).await {
+                    _ = relay::reset(tcp.get_ref().get_ref());
+                    streams.error();
+                    info!(
+                        "Ingress stream on port {target_port} failed after {}s: {err}",
+                        started.elapsed().as_secs()
+                    );
+                }
             }
+            Err(err) => utils::log_limited!(
+                Level::Error,
+                &format!("{target_cid}:{target_port}"),
+                "Connection to upstream vsock ({target_cid}:{target_port}) failed: {err}"
+            ),
         }
     }
 }
@@ -129,15 +703,19 @@ mod tests {
     use rustls::ServerName;
     use rustls::{ClientConfig, ServerConfig};
     use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashMap;
     use std::hash::Hasher;
     use std::net::{Ipv4Addr, SocketAddrV4};
     use std::sync::Arc;
+    use std::time::Duration;
     use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
     use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::watch;
     use tokio::task::JoinHandle;
     use tokio_rustls::TlsConnector;
+    use tokio_vsock::VsockStream;
 
-    use super::{EnclaveProxy, HostProxy};
+    use super::{AccessRecord, EnclaveProxy, HostProxy};
 
     struct TcpEchoServer {
         listener: TcpListener,
@@ -170,7 +748,7 @@ mod tests {
     fn start_enclave_proxy(port: u16, cfg: Arc<ServerConfig>) -> JoinHandle<()> {
         let proxy = EnclaveProxy::bind_tls(port, cfg).unwrap();
         tokio::task::spawn(async move {
-            proxy.serve().await;
+            proxy.serve(watch::channel(()).1).await;
         })
     }
 
@@ -211,6 +789,24 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_access_record() {
+        let record = AccessRecord {
+            client: Some("203.0.113.7:41000".to_string()),
+            target_port: Some(8080),
+            bytes_received: 12,
+            ..AccessRecord::new(443)
+        };
+
+        let json = serde_json::to_value(&record).unwrap();
+        assert!(json["port"] == 443);
+        assert!(json["client"] == "203.0.113.7:41000");
+        assert!(json["target_port"] == 8080);
+        assert!(json["bytes_received"] == 12);
+        assert!(json.get("server_name").is_none());
+        assert!(json.get("started").is_none());
+    }
+
     #[tokio::test]
     async fn test_enclave_proxy() {
         const PORT: u16 = 7777;
@@ -252,6 +848,199 @@ mod tests {
         _ = proxy_task.await;
     }
 
+    #[tokio::test]
+    async fn test_max_connections() {
+        const PORT: u16 = 7797;
+
+        let proxy = EnclaveProxy::bind(PORT)
+            .unwrap()
+            .with_max_connections(Some(1));
+        let proxy_task = tokio::task::spawn(proxy.serve(watch::channel(()).1));
+
+        let mut echo = TcpEchoServer::bind(PORT).await.unwrap();
+        let echo_task = tokio::task::spawn(async move {
+            echo.serve().await;
+        });
+
+        let connect = || VsockStream::connect(crate::vsock::VMADDR_CID_HOST, PORT as u32);
+        let mut buf = [0u8; 4];
+
+        let mut first = connect().await.unwrap();
+        first.write_all(b"ping").await.unwrap();
+        first.read_exact(&mut buf).await.unwrap();
+        assert!(&buf == b"ping");
+
+        // Over the limit, the connection is closed without reaching the app
+        let mut second = connect().await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), second.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+
+        // Once the first closes, its slot is free again
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut third = connect().await.unwrap();
+        third.write_all(b"pong").await.unwrap();
+        third.read_exact(&mut buf).await.unwrap();
+        assert!(&buf == b"pong");
+
+        echo_task.abort();
+        _ = echo_task.await;
+
+        proxy_task.abort();
+        _ = proxy_task.await;
+    }
+
+    #[tokio::test]
+    async fn test_accepts_per_second() {
+        const PORT: u16 = 7827;
+
+        let proxy = EnclaveProxy::bind(PORT)
+            .unwrap()
+            .with_accepts_per_second(Some(1));
+        let proxy_task = tokio::task::spawn(proxy.serve(watch::channel(()).1));
+
+        let mut echo = TcpEchoServer::bind(PORT).await.unwrap();
+        let echo_task = tokio::task::spawn(async move {
+            echo.serve().await;
+        });
+
+        let connect = || VsockStream::connect(crate::vsock::VMADDR_CID_HOST, PORT as u32);
+        let mut buf = [0u8; 4];
+
+        let mut first = connect().await.unwrap();
+        first.write_all(b"ping").await.unwrap();
+        first.read_exact(&mut buf).await.unwrap();
+        assert!(&buf == b"ping");
+
+        // Over the rate, the connection is closed without reaching the app
+        let mut second = connect().await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), second.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+
+        // A second later, there is room for another
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let mut third = connect().await.unwrap();
+        third.write_all(b"pong").await.unwrap();
+        third.read_exact(&mut buf).await.unwrap();
+        assert!(&buf == b"pong");
+
+        echo_task.abort();
+        _ = echo_task.await;
+
+        proxy_task.abort();
+        _ = proxy_task.await;
+    }
+
+    #[tokio::test]
+    async fn test_sni_routes() {
+        const PORT: u16 = 7807;
+
+        let server_config = crate::tls::test_server_config().unwrap();
+        let proxy = EnclaveProxy::bind_tls(PORT, server_config)
+            .unwrap()
+            .with_sni_routes(HashMap::from([("API.test.local".to_string(), PORT + 1)]));
+        let proxy_task = tokio::task::spawn(proxy.serve(watch::channel(()).1));
+
+        // The listen port echoes, the routed one greets
+        let mut echo = TcpEchoServer::bind(PORT).await.unwrap();
+        let echo_task = tokio::task::spawn(async move {
+            echo.serve().await;
+        });
+        let api = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, PORT + 1))
+            .await
+            .unwrap();
+        let api_task = tokio::task::spawn(async move {
+            while let Ok((mut sock, _)) = api.accept().await {
+                _ = sock.write_all(b"api!").await;
+            }
+        });
+
+        let exchange = |name: &'static str| async move {
+            let client_config = crate::tls::load_insecure_client_config().unwrap();
+            let mut conn = crate::vsock::tls_connect(
+                crate::vsock::VMADDR_CID_HOST,
+                PORT as u32,
+                ServerName::try_from(name).unwrap(),
+                client_config,
+            )
+            .await
+            .unwrap();
+            conn.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            buf
+        };
+        assert!(&exchange("api.test.local").await == b"api!");
+        assert!(&exchange("test.local").await == b"ping");
+
+        api_task.abort();
+        _ = api_task.await;
+
+        echo_task.abort();
+        _ = echo_task.await;
+
+        proxy_task.abort();
+        _ = proxy_task.await;
+    }
+
+    #[tokio::test]
+    async fn test_tls_reload() {
+        use crate::keypair::KeyPair;
+        use crate::tls::{self, RenewedCertificate};
+
+        const PORT: u16 = 7817;
+
+        let (configs, updates) = watch::channel(tls::test_server_config().unwrap());
+        let proxy = EnclaveProxy::bind_reloadable_tls(PORT, updates).unwrap();
+        let proxy_task = tokio::task::spawn(proxy.serve(watch::channel(()).1));
+
+        let mut echo = TcpEchoServer::bind(PORT).await.unwrap();
+        let echo_task = tokio::task::spawn(async move {
+            echo.serve().await;
+        });
+
+        let connect = || async {
+            let client_config = tls::load_insecure_client_config().unwrap();
+            crate::vsock::tls_connect(
+                crate::vsock::VMADDR_CID_HOST,
+                PORT as u32,
+                ServerName::try_from("test.local").unwrap(),
+                client_config,
+            )
+            .await
+            .unwrap()
+        };
+        let presented = |conn: &crate::vsock::TlsClientStream| {
+            let certs = conn.get_ref().1.peer_certificates().unwrap();
+            tls::common_name(&certs[0].0)
+        };
+
+        let mut first = connect().await;
+        let original = presented(&first);
+
+        let keypair = KeyPair::generate().unwrap();
+        let cert = tls::attested_certificate(&keypair, "reloaded.local", b"doc", 0, 60).unwrap();
+        let renewed = RenewedCertificate::default();
+        renewed.set(&keypair, cert).unwrap();
+        configs.send_replace(tls::renewed_server_config(renewed, None).unwrap());
+
+        // New connections get the new certificate, open ones carry on
+        let second = connect().await;
+        assert!(presented(&second).as_deref() == Some("reloaded.local"));
+        assert!(original.as_deref() != Some("reloaded.local"));
+
+        let mut buf = [0u8; 4];
+        first.write_all(b"ping").await.unwrap();
+        first.read_exact(&mut buf).await.unwrap();
+        assert!(&buf == b"ping");
+
+        echo_task.abort();
+        _ = echo_task.await;
+
+        proxy_task.abort();
+        _ = proxy_task.await;
+    }
+
     //type TlsServerStream = tokio_rustls::server::TlsStream<TcpStream>;
     type TlsClientStream = tokio_rustls::client::TlsStream<TcpStream>;
 
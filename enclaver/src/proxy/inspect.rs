@@ -0,0 +1,616 @@
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use log::{error, info};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::manifest::Protocol;
+
+// First byte of a TLS record carrying a handshake message
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+// Longest command or reply line we bother buffering while looking for STARTTLS
+const MAX_LINE_LEN: usize = 1024;
+
+// The PostgreSQL SSLRequest and GSSENCRequest messages
+const POSTGRES_SSL_REQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+const POSTGRES_GSSENC_REQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x30];
+
+// The CLIENT_SSL capability flag of the MySQL handshake response
+const MYSQL_CLIENT_SSL: u32 = 0x0800;
+
+// Enough of a message to recognize a request to upgrade to TLS: the whole
+// PostgreSQL SSLRequest, or the MySQL packet header and capability flags
+const UPGRADE_PREFIX_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsState {
+    Plaintext,
+    UpgradeRequested,
+    UpgradeAccepted,
+    UpgradeRefused,
+    Negotiated,
+}
+
+impl fmt::Display for TlsState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsState::Plaintext => write!(f, "plaintext"),
+            TlsState::UpgradeRequested => write!(f, "TLS upgrade requested"),
+            TlsState::UpgradeAccepted => write!(f, "TLS upgrade accepted"),
+            TlsState::UpgradeRefused => write!(f, "TLS upgrade refused"),
+            TlsState::Negotiated => write!(f, "TLS"),
+        }
+    }
+}
+
+// Follows a protocol tunneled through CONNECT far enough to tell whether, and
+// how, it switched to TLS. Once TLS is negotiated the contents are opaque and
+// only counted. If TLS is required, the client is cut off before any plaintext
+// beyond the upgrade request reaches the server.
+pub struct ProtocolInspector {
+    protocol: Protocol,
+    target: String,
+    state: TlsState,
+    require_tls: bool,
+    plaintext_payload: bool,
+    client_skip: u64,
+    bytes_to_server: u64,
+    bytes_to_client: u64,
+    server_line: Vec<u8>,
+    client_line: Vec<u8>,
+    imap_tag: Option<String>,
+}
+
+impl ProtocolInspector {
+    pub fn new(protocol: Protocol, host: &str, port: u16) -> Self {
+        Self {
+            protocol,
+            target: format!("{host}:{port}"),
+            state: TlsState::Plaintext,
+            require_tls: false,
+            plaintext_payload: false,
+            client_skip: 0,
+            bytes_to_server: 0,
+            bytes_to_client: 0,
+            server_line: Vec::new(),
+            client_line: Vec::new(),
+            imap_tag: None,
+        }
+    }
+
+    pub fn with_require_tls(mut self, require_tls: bool) -> Self {
+        self.require_tls = require_tls;
+        self
+    }
+
+    pub fn state(&self) -> TlsState {
+        self.state
+    }
+
+    // Inspects data read from one side of the tunnel, before it is forwarded to
+    // the other. Returns an error if the data must not be forwarded.
+    pub fn observe(&mut self, dir: Direction, data: &[u8]) -> Result<(), String> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let first = match dir {
+            Direction::ToServer => self.bytes_to_server == 0,
+            Direction::ToClient => self.bytes_to_client == 0,
+        };
+
+        match dir {
+            Direction::ToServer => self.bytes_to_server += data.len() as u64,
+            Direction::ToClient => self.bytes_to_client += data.len() as u64,
+        }
+
+        // The remainder of an upgrade request we already recognized
+        let data = match dir {
+            Direction::ToServer if self.client_skip > 0 => {
+                let n = self.client_skip.min(data.len() as u64);
+                self.client_skip -= n;
+                &data[n as usize..]
+            }
+            _ => data,
+        };
+
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        match (self.state, dir) {
+            (TlsState::Negotiated, _) => {}
+
+            // Implicit TLS, e.g. SMTP on 465 or IMAP on 993
+            (TlsState::Plaintext, Direction::ToServer)
+                if first && data[0] == TLS_HANDSHAKE_RECORD =>
+            {
+                self.transition(TlsState::Negotiated);
+            }
+
+            (TlsState::UpgradeAccepted, Direction::ToServer) if data[0] == TLS_HANDSHAKE_RECORD => {
+                self.transition(TlsState::Negotiated);
+            }
+
+            _ => match self.protocol {
+                Protocol::Smtp | Protocol::Imap => self.observe_text(dir, data),
+                Protocol::Postgres => self.observe_postgres(dir, data),
+                Protocol::Mysql => self.observe_mysql(dir, data),
+            },
+        }
+
+        if self.require_tls && self.plaintext_payload {
+            return Err(format!(
+                "refusing plaintext {} to {}, TLS is required",
+                self.protocol, self.target
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn observe_text(&mut self, dir: Direction, data: &[u8]) {
+        match (self.state, dir) {
+            (TlsState::Plaintext | TlsState::UpgradeRefused, Direction::ToServer) => {
+                for line in take_lines(&mut self.client_line, data) {
+                    self.client_command(&line);
+                }
+            }
+
+            (TlsState::UpgradeRequested, Direction::ToClient) => {
+                for line in take_lines(&mut self.server_line, data) {
+                    self.server_reply(&line);
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    // The client opens with an SSLRequest, which the server answers with a single
+    // 'S' or 'N' byte.
+    fn observe_postgres(&mut self, dir: Direction, data: &[u8]) {
+        match (self.state, dir) {
+            (TlsState::Plaintext, Direction::ToServer) => {
+                let prefix = match take_prefix(&mut self.client_line, data) {
+                    Some(prefix) => prefix,
+                    None => return,
+                };
+
+                if prefix == POSTGRES_SSL_REQUEST {
+                    self.transition(TlsState::UpgradeRequested);
+                } else if prefix == POSTGRES_GSSENC_REQUEST {
+                    // Not TLS, but not payload either. The server refuses it
+                    // and the client carries on with its next request.
+                    self.client_line.clear();
+                } else {
+                    self.plaintext_payload = true;
+                }
+            }
+
+            (TlsState::UpgradeRequested, Direction::ToClient) => {
+                if data[0] == b'S' {
+                    self.transition(TlsState::UpgradeAccepted);
+                } else {
+                    self.transition(TlsState::UpgradeRefused);
+                }
+            }
+
+            (_, Direction::ToServer) => self.plaintext_payload = true,
+
+            _ => {}
+        }
+    }
+
+    // The server greets first. A client that wants TLS answers with a short
+    // handshake response carrying CLIENT_SSL and goes straight to TLS.
+    fn observe_mysql(&mut self, dir: Direction, data: &[u8]) {
+        match (self.state, dir) {
+            (TlsState::Plaintext, Direction::ToServer) => {
+                let prefix = match take_prefix(&mut self.client_line, data) {
+                    Some(prefix) => prefix,
+                    None => return,
+                };
+
+                let capabilities = u32::from_le_bytes([prefix[4], prefix[5], prefix[6], prefix[7]]);
+                if capabilities & MYSQL_CLIENT_SSL == 0 {
+                    self.plaintext_payload = true;
+                    return;
+                }
+
+                self.transition(TlsState::UpgradeAccepted);
+
+                // Skip over the rest of the request, and check what follows it
+                // if the client did not wait to start TLS.
+                let packet_len =
+                    4 + u32::from_le_bytes([prefix[0], prefix[1], prefix[2], 0]) as u64;
+                let start = self.bytes_to_server - data.len() as u64;
+                if self.bytes_to_server < packet_len {
+                    self.client_skip = packet_len - self.bytes_to_server;
+                } else if let Some(next) = packet_len
+                    .checked_sub(start)
+                    .and_then(|offset| data.get(offset as usize))
+                {
+                    if *next == TLS_HANDSHAKE_RECORD {
+                        self.transition(TlsState::Negotiated);
+                    } else {
+                        self.plaintext_payload = true;
+                    }
+                }
+            }
+
+            (_, Direction::ToServer) => self.plaintext_payload = true,
+
+            _ => {}
+        }
+    }
+
+    fn client_command(&mut self, line: &str) {
+        let is_starttls = match self.protocol {
+            Protocol::Smtp => line.trim().eq_ignore_ascii_case("STARTTLS"),
+            Protocol::Imap => match line.trim().split_once(' ') {
+                Some((tag, command)) if command.trim().eq_ignore_ascii_case("STARTTLS") => {
+                    self.imap_tag = Some(tag.to_string());
+                    true
+                }
+                _ => false,
+            },
+            Protocol::Postgres | Protocol::Mysql => false,
+        };
+
+        if is_starttls {
+            self.transition(TlsState::UpgradeRequested);
+        }
+    }
+
+    fn server_reply(&mut self, line: &str) {
+        let accepted = match self.protocol {
+            // Multiline replies continue with "220-", only the last line counts
+            Protocol::Smtp => match (line.get(..3), line.as_bytes().get(3)) {
+                (_, Some(b'-')) => None,
+                (Some("220"), _) => Some(true),
+                (Some(code), _) if code.starts_with(['4', '5']) => Some(false),
+                _ => None,
+            },
+            Protocol::Imap => {
+                let tag = self.imap_tag.as_deref().unwrap_or_default();
+                match line.strip_prefix(tag).map(|rest| rest.trim_start()) {
+                    Some(rest) if !tag.is_empty() && starts_with_word(rest, "OK") => Some(true),
+                    Some(rest)
+                        if !tag.is_empty()
+                            && (starts_with_word(rest, "NO") || starts_with_word(rest, "BAD")) =>
+                    {
+                        Some(false)
+                    }
+                    _ => None,
+                }
+            }
+            Protocol::Postgres | Protocol::Mysql => None,
+        };
+
+        match accepted {
+            Some(true) => self.transition(TlsState::UpgradeAccepted),
+            Some(false) => self.transition(TlsState::UpgradeRefused),
+            None => {}
+        }
+    }
+
+    fn transition(&mut self, state: TlsState) {
+        self.state = state;
+        self.server_line.clear();
+        self.client_line.clear();
+        info!("egress {} to {}: {state}", self.protocol, self.target);
+    }
+}
+
+impl Drop for ProtocolInspector {
+    fn drop(&mut self) {
+        info!(
+            "egress {} to {} closed ({}): {} bytes sent, {} bytes received",
+            self.protocol, self.target, self.state, self.bytes_to_server, self.bytes_to_client
+        );
+    }
+}
+
+// Accumulates the first bytes sent in buf, returning them once there are enough
+fn take_prefix(buf: &mut Vec<u8>, data: &[u8]) -> Option<[u8; UPGRADE_PREFIX_LEN]> {
+    let needed = UPGRADE_PREFIX_LEN.saturating_sub(buf.len());
+    buf.extend_from_slice(&data[..needed.min(data.len())]);
+
+    buf.as_slice().try_into().ok()
+}
+
+fn starts_with_word(s: &str, word: &str) -> bool {
+    match s.get(..word.len()) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(word) => {
+            s[word.len()..].is_empty() || s[word.len()..].starts_with(' ')
+        }
+        _ => false,
+    }
+}
+
+// Appends data to the partial line in buf and returns any complete lines
+fn take_lines(buf: &mut Vec<u8>, data: &[u8]) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for b in data {
+        if *b == b'\n' {
+            lines.push(
+                String::from_utf8_lossy(buf)
+                    .trim_end_matches('\r')
+                    .to_string(),
+            );
+            buf.clear();
+        } else if buf.len() < MAX_LINE_LEN {
+            buf.push(*b);
+        }
+    }
+
+    lines
+}
+
+// Passes the bytes read from the inner stream to the inspector
+pub struct Inspected<S> {
+    inner: S,
+    inspector: Arc<Mutex<ProtocolInspector>>,
+    dir: Direction,
+}
+
+impl<S> Inspected<S> {
+    pub fn new(inner: S, inspector: Arc<Mutex<ProtocolInspector>>, dir: Direction) -> Self {
+        Self {
+            inner,
+            inspector,
+            dir,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Inspected<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = res {
+            let dir = self.dir;
+            if let Err(reason) = self
+                .inspector
+                .lock()
+                .unwrap()
+                .observe(dir, &buf.filled()[before..])
+            {
+                error!("{reason}");
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::PermissionDenied, reason)));
+            }
+        }
+
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Inspected<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Direction, ProtocolInspector, TlsState};
+    use crate::manifest::Protocol;
+    use assert2::assert;
+
+    #[test]
+    fn test_smtp_starttls() {
+        let mut inspector = ProtocolInspector::new(Protocol::Smtp, "smtp.example.com", 587);
+
+        inspector
+            .observe(Direction::ToClient, b"220 smtp.example.com ESMTP\r\n")
+            .unwrap();
+        inspector
+            .observe(Direction::ToServer, b"EHLO enclave\r\n")
+            .unwrap();
+        inspector
+            .observe(
+                Direction::ToClient,
+                b"250-smtp.example.com\r\n250 STARTTLS\r\n",
+            )
+            .unwrap();
+        assert!(inspector.state() == TlsState::Plaintext);
+
+        // Commands may arrive split across reads
+        inspector.observe(Direction::ToServer, b"STAR").unwrap();
+        inspector.observe(Direction::ToServer, b"TTLS\r\n").unwrap();
+        assert!(inspector.state() == TlsState::UpgradeRequested);
+
+        inspector
+            .observe(Direction::ToClient, b"220 2.0.0 Ready to start TLS\r\n")
+            .unwrap();
+        assert!(inspector.state() == TlsState::UpgradeAccepted);
+
+        inspector
+            .observe(Direction::ToServer, &[0x16, 0x03, 0x01])
+            .unwrap();
+        assert!(inspector.state() == TlsState::Negotiated);
+    }
+
+    #[test]
+    fn test_smtp_starttls_refused() {
+        let mut inspector = ProtocolInspector::new(Protocol::Smtp, "smtp.example.com", 25);
+
+        inspector
+            .observe(Direction::ToServer, b"STARTTLS\r\n")
+            .unwrap();
+        inspector
+            .observe(Direction::ToClient, b"454 4.7.0 TLS not available\r\n")
+            .unwrap();
+        assert!(inspector.state() == TlsState::UpgradeRefused);
+
+        inspector
+            .observe(Direction::ToServer, b"MAIL FROM:<a@example.com>\r\n")
+            .unwrap();
+        assert!(inspector.state() == TlsState::UpgradeRefused);
+    }
+
+    #[test]
+    fn test_imap_starttls() {
+        let mut inspector = ProtocolInspector::new(Protocol::Imap, "imap.example.com", 143);
+
+        inspector
+            .observe(Direction::ToClient, b"* OK IMAP4rev1 ready\r\n")
+            .unwrap();
+        inspector
+            .observe(Direction::ToServer, b"a1 STARTTLS\r\n")
+            .unwrap();
+        assert!(inspector.state() == TlsState::UpgradeRequested);
+
+        inspector
+            .observe(Direction::ToClient, b"* OK still here\r\n")
+            .unwrap();
+        assert!(inspector.state() == TlsState::UpgradeRequested);
+
+        inspector
+            .observe(Direction::ToClient, b"a1 OK Begin TLS negotiation now\r\n")
+            .unwrap();
+        assert!(inspector.state() == TlsState::UpgradeAccepted);
+    }
+
+    #[test]
+    fn test_implicit_tls() {
+        let mut inspector = ProtocolInspector::new(Protocol::Imap, "imap.example.com", 993);
+
+        inspector
+            .observe(Direction::ToServer, &[0x16, 0x03, 0x01, 0x02, 0x00])
+            .unwrap();
+        assert!(inspector.state() == TlsState::Negotiated);
+
+        // Nothing is inspected from here on
+        inspector
+            .observe(Direction::ToServer, b"a1 STARTTLS\r\n")
+            .unwrap();
+        assert!(inspector.state() == TlsState::Negotiated);
+    }
+
+    #[test]
+    fn test_postgres_ssl_request() {
+        let mut inspector =
+            ProtocolInspector::new(Protocol::Postgres, "db.internal", 5432).with_require_tls(true);
+
+        inspector
+            .observe(Direction::ToServer, &[0, 0, 0, 8, 0x04, 0xd2])
+            .unwrap();
+        inspector
+            .observe(Direction::ToServer, &[0x16, 0x2f])
+            .unwrap();
+        assert!(inspector.state() == TlsState::UpgradeRequested);
+
+        inspector.observe(Direction::ToClient, b"S").unwrap();
+        assert!(inspector.state() == TlsState::UpgradeAccepted);
+
+        inspector
+            .observe(Direction::ToServer, &[0x16, 0x03, 0x01])
+            .unwrap();
+        assert!(inspector.state() == TlsState::Negotiated);
+    }
+
+    #[test]
+    fn test_postgres_require_tls() {
+        // A startup message straight away
+        let mut inspector =
+            ProtocolInspector::new(Protocol::Postgres, "db.internal", 5432).with_require_tls(true);
+        let startup = [0, 0, 0, 41, 0, 3, 0, 0, b'u', b's', b'e', b'r', 0];
+        assert!(inspector.observe(Direction::ToServer, &startup).is_err());
+
+        // The server refuses TLS and the client carries on regardless
+        let mut inspector =
+            ProtocolInspector::new(Protocol::Postgres, "db.internal", 5432).with_require_tls(true);
+        inspector
+            .observe(Direction::ToServer, &[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f])
+            .unwrap();
+        inspector.observe(Direction::ToClient, b"N").unwrap();
+        assert!(inspector.state() == TlsState::UpgradeRefused);
+        assert!(inspector.observe(Direction::ToServer, &startup).is_err());
+
+        // Without the requirement, the same is only logged
+        let mut inspector = ProtocolInspector::new(Protocol::Postgres, "db.internal", 5432);
+        assert!(inspector.observe(Direction::ToServer, &startup).is_ok());
+    }
+
+    #[test]
+    fn test_mysql_client_ssl() {
+        let mut inspector =
+            ProtocolInspector::new(Protocol::Mysql, "db.internal", 3306).with_require_tls(true);
+
+        inspector
+            .observe(
+                Direction::ToClient,
+                &[0x4a, 0, 0, 0, 0x0a, b'8', b'.', b'0'],
+            )
+            .unwrap();
+
+        // A 32 byte SSL request packet, with CLIENT_SSL in the capability flags
+        let mut request = vec![32, 0, 0, 1, 0x00, 0x0a, 0x00, 0x00];
+        request.resize(36, 0);
+
+        inspector
+            .observe(Direction::ToServer, &request[..6])
+            .unwrap();
+        inspector
+            .observe(Direction::ToServer, &request[6..20])
+            .unwrap();
+        assert!(inspector.state() == TlsState::UpgradeAccepted);
+
+        inspector
+            .observe(Direction::ToServer, &request[20..])
+            .unwrap();
+        inspector
+            .observe(Direction::ToServer, &[0x16, 0x03, 0x01])
+            .unwrap();
+        assert!(inspector.state() == TlsState::Negotiated);
+
+        // The client may not wait before starting TLS
+        let mut inspector =
+            ProtocolInspector::new(Protocol::Mysql, "db.internal", 3306).with_require_tls(true);
+        let mut eager = request.clone();
+        eager.extend_from_slice(&[0x16, 0x03, 0x01]);
+        inspector.observe(Direction::ToServer, &eager).unwrap();
+        assert!(inspector.state() == TlsState::Negotiated);
+    }
+
+    #[test]
+    fn test_mysql_require_tls() {
+        let mut inspector =
+            ProtocolInspector::new(Protocol::Mysql, "db.internal", 3306).with_require_tls(true);
+
+        let mut response = vec![64, 0, 0, 1, 0x00, 0x02, 0x00, 0x00];
+        response.resize(68, 0);
+        assert!(inspector.observe(Direction::ToServer, &response).is_err());
+        assert!(inspector.state() == TlsState::Plaintext);
+    }
+}
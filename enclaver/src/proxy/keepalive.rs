@@ -0,0 +1,87 @@
+//! TCP keepalive for the legs of long-lived ingress streams, e.g. gRPC streams that
+//! sit idle for minutes between messages. Without it, NAT gateways and load
+//! balancers silently drop such connections, and a peer that went away is never
+//! noticed. vsock has no keepalive of its own, but it only spans the host, and its
+//! connections are closed along with the TCP leg they are spliced to.
+
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use nix::sys::socket::{setsockopt, sockopt};
+
+/// Idle time before the first probe, unless the manifest sets another
+pub const DEFAULT_KEEPALIVE_IDLE: Duration = Duration::from_secs(60);
+
+// Probes after the first, and how many may go unanswered before the connection is
+// dropped
+const PROBE_INTERVAL: Duration = Duration::from_secs(15);
+const PROBE_COUNT: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    idle: Duration,
+}
+
+impl Keepalive {
+    pub fn new(idle: Duration) -> Self {
+        Self { idle }
+    }
+
+    /// The keepalive of an ingress port, from its keepalive_seconds: the default if
+    /// unset, none if 0
+    pub fn from_manifest(keepalive_seconds: Option<u32>) -> Option<Self> {
+        match keepalive_seconds {
+            None => Some(Self::new(DEFAULT_KEEPALIVE_IDLE)),
+            Some(0) => None,
+            Some(secs) => Some(Self::new(Duration::from_secs(secs.into()))),
+        }
+    }
+
+    pub fn apply<S: AsRawFd>(&self, socket: &S) -> std::io::Result<()> {
+        let fd = socket.as_raw_fd();
+        setsockopt(fd, sockopt::KeepAlive, &true)?;
+        setsockopt(
+            fd,
+            sockopt::TcpKeepIdle,
+            &(self.idle.as_secs().max(1) as u32),
+        )?;
+        setsockopt(
+            fd,
+            sockopt::TcpKeepInterval,
+            &(PROBE_INTERVAL.min(self.idle).as_secs().max(1) as u32),
+        )?;
+        setsockopt(fd, sockopt::TcpKeepCount, &PROBE_COUNT)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Keepalive, DEFAULT_KEEPALIVE_IDLE};
+    use assert2::assert;
+    use nix::sys::socket::{getsockopt, sockopt};
+    use std::os::unix::io::AsRawFd;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_apply() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        assert!(Keepalive::from_manifest(Some(0)).is_none());
+        assert!(Keepalive::from_manifest(None) == Some(Keepalive::new(DEFAULT_KEEPALIVE_IDLE)));
+        Keepalive::from_manifest(Some(120))
+            .unwrap()
+            .apply(&tcp)
+            .unwrap();
+
+        let fd = tcp.as_raw_fd();
+        assert!(getsockopt(fd, sockopt::KeepAlive).unwrap());
+        assert!(getsockopt(fd, sockopt::TcpKeepIdle).unwrap() == 120);
+        assert!(getsockopt(fd, sockopt::TcpKeepInterval).unwrap() == 15);
+        assert!(getsockopt(fd, sockopt::TcpKeepCount).unwrap() == 4);
+    }
+}
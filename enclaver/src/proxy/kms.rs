@@ -1,5 +1,6 @@
 use anyhow::{anyhow, Error, Result};
 use async_trait::async_trait;
+use aws_credential_types::cache::{ProvideCachedCredentials, SharedCredentialsCache};
 use aws_credential_types::Credentials;
 use aws_sigv4::http_request::{SignableBody, SignableRequest, SigningSettings};
 use aws_sigv4::SigningParams;
@@ -9,12 +10,11 @@ use http::Uri;
 use hyper::body::Bytes;
 use hyper::{Body, Method, Request, Response, StatusCode};
 use json::{object, JsonValue};
-use lazy_static::lazy_static;
 use log::{debug, trace};
-use regex::Regex;
 use std::sync::Arc;
 use std::time::SystemTime;
 
+use super::aws_util::CredentialScope;
 use crate::http_util::HttpHandler;
 use crate::keypair::KeyPair;
 use crate::nsm::{AttestationParams, AttestationProvider};
@@ -23,8 +23,6 @@ static X_AMZ_TARGET: HeaderName = HeaderName::from_static("x-amz-target");
 
 static X_AMZ_JSON: HeaderValue = HeaderValue::from_static("application/x-amz-json-1.1");
 
-const X_AMZ_CREDENTIAL: &str = "X-Amz-Credential";
-
 const ATTESTING_ACTIONS: [&str; 5] = [
     "TrentService.Decrypt",
     "TrentService.DeriveSharedSecret",
@@ -35,58 +33,6 @@ const ATTESTING_ACTIONS: [&str; 5] = [
 
 const KMS_SERVICE_NAME: &str = "kms";
 
-// Used to parse out the required fields out of the Authorization header or query parameters.
-// TODO: make it work using string references to avoid numerous copies.
-struct CredentialScope {
-    region: String,
-    service: String,
-}
-
-impl CredentialScope {
-    fn from_request(head: &http::request::Parts) -> Result<Self> {
-        lazy_static! {
-            // e.g.: AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, ...
-            static ref HEADER_RE: Regex = Regex::new(r"AWS4\-HMAC\-SHA256 Credential=.*?/.*?/(.*?)/(.*?)/aws4_request,").unwrap();
-            static ref QUERY_RE: Regex = Regex::new(r".*?/.*?/(.*?)/(.*?)/aws4_request").unwrap();
-        }
-
-        use std::ops::Deref;
-
-        // Look for the signature either in the Authorization HTTP header or in a query string
-        let (cred, re) = match head.headers.get(http::header::AUTHORIZATION) {
-            Some(authz) => (authz.to_str()?.to_string(), HEADER_RE.deref()),
-            None => {
-                let cred = amz_credential_query(&head.uri)
-                    .ok_or(anyhow!("No AWS SigV4 found in the request"))?;
-                (cred, QUERY_RE.deref())
-            }
-        };
-
-        debug!("CredentialScope: {cred}");
-
-        let groups = re.captures(&cred).ok_or(anyhow!(
-            "{} header has an invalid format",
-            http::header::AUTHORIZATION
-        ))?;
-
-        Ok(Self {
-            region: groups.get(1).unwrap().as_str().to_string(),
-            service: groups.get(2).unwrap().as_str().to_string(),
-        })
-    }
-
-    fn validate(&self) -> Result<()> {
-        if self.service != KMS_SERVICE_NAME {
-            return Err(anyhow!(
-                "Received request signed for a non-KMS ({}) service",
-                self.service
-            ));
-        }
-
-        Ok(())
-    }
-}
-
 struct KmsRequestIncoming {
     head: http::request::Parts,
     body: hyper::body::Bytes,
@@ -234,7 +180,7 @@ pub trait KmsEndpointProvider {
 
 pub struct KmsProxyConfig {
     pub client: Box<dyn HttpClient + Send + Sync>,
-    pub credentials: Credentials,
+    pub credentials: SharedCredentialsCache,
     pub keypair: Arc<KeyPair>,
     pub attester: Box<dyn AttestationProvider + Send + Sync>,
     pub endpoints: Arc<dyn KmsEndpointProvider + Send + Sync>,
@@ -262,13 +208,55 @@ impl KmsProxyHandler {
         debug!("Handling attesting action");
 
         let credential = req_in.credential_scope()?;
-        credential.validate()?;
+        credential.validate(KMS_SERVICE_NAME)?;
 
         let region = credential.region;
         let authority = self.config.get_authority(&region);
 
         let mut body_obj = req_in.body_as_json()?;
+        self.add_recipient(&mut body_obj)?;
+
+        let req_out = KmsRequestOutgoing::new(authority, req_in.target().unwrap(), body_obj)?;
 
+        // Send the request to the actual KMS
+        let resp = self.send(req_out, &region).await?;
+
+        // Decode the response
+        self.handle_response(resp).await
+    }
+
+    /// Decrypts ciphertext with the KMS of region for odyn itself, e.g. a key it needs
+    /// to start. The plaintext comes back for the attested keypair, as it does for the
+    /// app, so it is never seen outside the enclave.
+    pub async fn decrypt(&self, region: &str, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let mut body_obj = object! {
+            "CiphertextBlob": base64::encode(ciphertext),
+        };
+        self.add_recipient(&mut body_obj)?;
+
+        let authority = self.config.get_authority(region);
+        let action = HeaderValue::from_static("TrentService.Decrypt");
+        let req_out = KmsRequestOutgoing::new(authority, &action, body_obj)?;
+        let resp = self.send(req_out, region).await?;
+
+        let (head, body) = resp.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        if head.status != StatusCode::OK {
+            return Err(anyhow!(
+                "KMS Decrypt failed with {}: {}",
+                head.status,
+                String::from_utf8_lossy(&body)
+            ));
+        }
+
+        match json::parse(std::str::from_utf8(&body)?)? {
+            JsonValue::Object(mut body_obj) => self.recipient_plaintext(&mut body_obj),
+            _ => Err(anyhow!("The response body is not a JSON object")),
+        }
+    }
+
+    // Has KMS encrypt the result of the action for the keypair, as attested
+    fn add_recipient(&self, body_obj: &mut JsonValue) -> Result<()> {
         let attestation_doc = self.get_attestation()?;
 
         body_obj.insert(
@@ -279,21 +267,15 @@ impl KmsProxyHandler {
             },
         )?;
 
-        let req_out = KmsRequestOutgoing::new(authority, req_in.target().unwrap(), body_obj)?;
-
-        // Send the request to the actual KMS
-        let resp = self.send(req_out, &region).await?;
-
-        // Decode the response
-        self.handle_response(resp).await
+        Ok(())
     }
 
     fn get_attestation(&self) -> Result<Vec<u8>> {
-        self.config.attester.attestation(AttestationParams {
+        Ok(self.config.attester.attestation(AttestationParams {
             nonce: None,
             user_data: None,
             public_key: Some(self.config.keypair.public_key_as_der()?),
-        })
+        })?)
     }
 
     async fn handle_response(&self, resp: Response<Body>) -> Result<Response<Body>> {
@@ -310,16 +292,7 @@ impl KmsProxyHandler {
         let body_val = json::parse(std::str::from_utf8(&body)?)?;
 
         if let JsonValue::Object(mut body_obj) = body_val {
-            let b64ciphertext = body_obj
-                .remove("CiphertextForRecipient")
-                .ok_or(anyhow!("Response body is missing 'CiphertextForRecipient'"))?;
-
-            let b64ciphertext = b64ciphertext
-                .as_str()
-                .ok_or(anyhow!("CiphertextForRecipient is not a string"))?;
-
-            let ciphertext = base64::decode(b64ciphertext)?;
-            let plaintext = self.decrypt_cms(&ciphertext)?;
+            let plaintext = self.recipient_plaintext(&mut body_obj)?;
 
             body_obj["Plaintext"] = json::JsonValue::String(base64::encode(plaintext));
             Ok(json_response(head, JsonValue::Object(body_obj)))
@@ -328,9 +301,23 @@ impl KmsProxyHandler {
         }
     }
 
+    // Takes the CiphertextForRecipient out of the response and decrypts it
+    fn recipient_plaintext(&self, body_obj: &mut json::object::Object) -> Result<Vec<u8>> {
+        let b64ciphertext = body_obj
+            .remove("CiphertextForRecipient")
+            .ok_or(anyhow!("Response body is missing 'CiphertextForRecipient'"))?;
+
+        let b64ciphertext = b64ciphertext
+            .as_str()
+            .ok_or(anyhow!("CiphertextForRecipient is not a string"))?;
+
+        let ciphertext = base64::decode(b64ciphertext)?;
+        self.decrypt_cms(&ciphertext)
+    }
+
     async fn handle_forward(&self, req_in: KmsRequestIncoming) -> Result<Response<Body>> {
         let credential = req_in.credential_scope()?;
-        credential.validate()?;
+        credential.validate(KMS_SERVICE_NAME)?;
 
         let region = credential.region.to_string();
         let authority = self.config.get_authority(&region);
@@ -340,7 +327,8 @@ impl KmsProxyHandler {
     }
 
     async fn send(&self, req: KmsRequestOutgoing, region: &str) -> Result<Response<Body>> {
-        let signed = req.sign(&self.config.credentials, region)?;
+        let credentials = self.config.credentials.provide_cached_credentials().await?;
+        let signed = req.sign(&credentials, region)?;
 
         debug!("Sending Request: {:?}", signed);
         Ok(self.config.client.request(signed).await?)
@@ -409,23 +397,14 @@ fn json_response(head: http::response::Parts, json_val: JsonValue) -> Response<B
     Response::from_parts(head, json_body(json_val))
 }
 
-fn amz_credential_query(uri: &Uri) -> Option<String> {
-    let q = uri.path_and_query()?.query()?;
-
-    for (k, v) in form_urlencoded::parse(q.as_bytes()) {
-        if X_AMZ_CREDENTIAL.eq_ignore_ascii_case(&k) {
-            return Some(v.to_string());
-        }
-    }
-
-    None
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::nsm::StaticAttestationProvider;
     use assert2::assert;
+    use aws_credential_types::cache::CredentialsCache;
+    use aws_credential_types::provider::SharedCredentialsProvider;
+    use lazy_static::lazy_static;
     use pkcs8::DecodePrivateKey;
     use rsa::RsaPrivateKey;
 
@@ -541,9 +520,11 @@ mod tests {
         let key_der = base64::decode(crate::proxy::pkcs7::tests::PRIVATE_KEY).unwrap();
         let priv_key = RsaPrivateKey::from_pkcs8_der(&key_der).unwrap();
 
+        let credentials = Credentials::from_keys("TESTKEY", "TESTSECRET", None);
         let config = KmsProxyConfig {
             client: Box::new(Mock),
-            credentials: Credentials::from_keys("TESTKEY", "TESTSECRET", None),
+            credentials: CredentialsCache::no_caching()
+                .create_cache(SharedCredentialsProvider::new(credentials)),
             keypair: Arc::new(KeyPair::from_private(priv_key)),
             attester: Box::new(StaticAttestationProvider::new(ATTESTATION_DOC.to_vec())),
             endpoints: Arc::new(Mock {}),
@@ -621,4 +602,15 @@ mod tests {
             assert!("DUMMY" == msg);
         }
     }
+
+    #[tokio::test]
+    async fn test_decrypt() {
+        let handler = new_test_handler();
+
+        let plaintext = handler
+            .decrypt("us-east-1", b"~~~ ENCRYPTED Hello, World ~~~")
+            .await
+            .unwrap();
+        assert!(plaintext == b"Hello, World");
+    }
 }
@@ -1,9 +1,43 @@
+pub mod audit;
+pub mod authority;
+pub mod aws_credentials;
 pub mod aws_util;
+pub mod budget;
+pub mod dns;
+pub mod drain;
 pub mod egress_http;
+pub mod error;
+pub mod host_relays;
+
+#[cfg(feature = "odyn")]
+pub mod forward;
+
+#[cfg(feature = "odyn")]
+pub mod imds_relay;
 pub mod ingress;
+pub mod inspect;
+pub mod keepalive;
 
 #[cfg(feature = "odyn")]
 pub mod kms;
 
 #[cfg(feature = "odyn")]
-mod pkcs7;
+pub(crate) mod pkcs7;
+pub mod pool;
+pub mod proxy_protocol;
+pub mod relay;
+pub mod revoke;
+
+#[cfg(feature = "odyn")]
+pub mod s3;
+pub(crate) mod sni;
+pub mod throttle;
+
+#[cfg(feature = "odyn")]
+pub mod synthetic_dns;
+
+#[cfg(feature = "odyn")]
+pub mod transparent;
+
+pub mod udp;
+pub mod well_known;
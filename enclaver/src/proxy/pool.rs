@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::FutureExt;
+use hyper::client::conn::SendRequest;
+use hyper::Body;
+use tokio::time::Instant;
+
+/// Upstream connections of plain HTTP requests, kept open after their response for
+/// the next request to the same origin, e.g. http://example.com:80
+#[derive(Clone)]
+pub struct ConnectionPool {
+    idle: Arc<Mutex<HashMap<String, Vec<Idle>>>>,
+    max_per_host: usize,
+    idle_timeout: Duration,
+}
+
+/// A connection to an origin, over HTTP/1.1 or HTTP/2
+pub struct Connection {
+    pub sender: SendRequest<Body>,
+    pub http2: bool,
+}
+
+struct Idle {
+    conn: Connection,
+    since: Instant,
+}
+
+impl ConnectionPool {
+    /// Keeps up to max_per_host connections to each origin, closing any that go
+    /// unused for idle_timeout
+    pub fn new(max_per_host: usize, idle_timeout: Duration) -> Self {
+        Self {
+            idle: Arc::new(Mutex::new(HashMap::new())),
+            max_per_host,
+            idle_timeout,
+        }
+    }
+
+    /// A pooled connection to key that is ready for another request, if any
+    pub fn checkout(&self, key: &str) -> Option<Connection> {
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.get_mut(key)?;
+        let now = Instant::now();
+
+        let mut busy = Vec::new();
+        let mut ready = None;
+        while let Some(mut idle) = conns.pop() {
+            if now.duration_since(idle.since) >= self.idle_timeout {
+                continue;
+            }
+
+            // Pending while the body of the previous response is still being read,
+            // an error once the server closed the connection
+            match futures::future::poll_fn(|cx| idle.conn.sender.poll_ready(cx)).now_or_never() {
+                Some(Ok(())) => {
+                    ready = Some(idle.conn);
+                    break;
+                }
+                Some(Err(_)) => {}
+                None => busy.push(idle),
+            }
+        }
+        conns.extend(busy);
+
+        ready
+    }
+
+    /// Returns a connection to the pool once its response has arrived. Dropping the
+    /// last handle to a connection closes it.
+    pub fn checkin(&self, key: String, conn: Connection) {
+        let mut idle = self.idle.lock().unwrap();
+        let now = Instant::now();
+
+        // Expired connections to hosts that are no longer asked for go here too
+        idle.retain(|_, conns| {
+            conns.retain(|idle| now.duration_since(idle.since) < self.idle_timeout);
+            !conns.is_empty()
+        });
+
+        let conns = idle.entry(key).or_default();
+        if conns.len() < self.max_per_host {
+            conns.push(Idle { conn, since: now });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Connection, ConnectionPool};
+    use assert2::assert;
+    use hyper::client::conn::Builder;
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use hyper::{Body, Request, Response};
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    async fn connect() -> Connection {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::task::spawn(Http::new().serve_connection(
+            server,
+            service_fn(|_| async { Ok::<_, Infallible>(Response::new(Body::from("ok"))) }),
+        ));
+
+        let (sender, conn) = Builder::new().handshake(client).await.unwrap();
+        tokio::task::spawn(conn);
+        Connection {
+            sender,
+            http2: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_checkout() {
+        let pool = ConnectionPool::new(1, Duration::from_secs(30));
+        assert!(pool.checkout("example.com:80").is_none());
+
+        pool.checkin("example.com:80".to_string(), connect().await);
+        pool.checkin("example.com:80".to_string(), connect().await);
+
+        // Connections are ready once their task has run
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // Only one is kept, and taken out for the next request
+        let mut conn = pool.checkout("example.com:80").unwrap();
+        assert!(pool.checkout("example.com:80").is_none());
+        assert!(pool.checkout("example.net:80").is_none());
+
+        let resp = conn.sender.send_request(Request::new(Body::empty())).await;
+        assert!(resp.unwrap().status() == http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let pool = ConnectionPool::new(4, Duration::from_millis(50));
+        pool.checkin("example.com:80".to_string(), connect().await);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(pool.checkout("example.com:80").is_none());
+    }
+}
@@ -0,0 +1,123 @@
+//! PROXY protocol v2 headers, which odyn sends the application ahead of the
+//! connections of a TLS ingress port it terminates, so that the application learns
+//! who the client is without terminating TLS itself. The header carries the TLS
+//! details in a PP2_TYPE_SSL TLV, as HAProxy does. The client address is not known
+//! inside the enclave, so the addresses are left out (AF_UNSPEC).
+
+use rustls::ServerConnection;
+
+use crate::tls::common_name;
+
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// Version 2, PROXY command
+const VERSION_COMMAND: u8 = 0x21;
+const AF_UNSPEC: u8 = 0x00;
+
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+
+const PP2_CLIENT_SSL: u8 = 0x01;
+const PP2_CLIENT_CERT_CONN: u8 = 0x02;
+
+/// What the application is told about the client of a TLS connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// e.g. TLSv1.3
+    pub version: Option<String>,
+
+    /// Whether the client presented a certificate, which rustls verified by then
+    pub verified: bool,
+
+    /// Common name of the subject of the client certificate
+    pub common_name: Option<String>,
+}
+
+impl ClientIdentity {
+    pub fn from_connection(conn: &ServerConnection) -> Self {
+        let cert = conn.peer_certificates().and_then(|certs| certs.first());
+        let version = conn.protocol_version().map(|version| match version {
+            rustls::ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+            rustls::ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+            other => format!("{other:?}"),
+        });
+
+        Self {
+            version,
+            verified: cert.is_some(),
+            common_name: cert.and_then(|cert| common_name(&cert.0)),
+        }
+    }
+
+    /// The PROXY protocol v2 header that carries it
+    pub fn to_header(&self) -> Vec<u8> {
+        let mut ssl = vec![match self.verified {
+            true => PP2_CLIENT_SSL | PP2_CLIENT_CERT_CONN,
+            false => PP2_CLIENT_SSL,
+        }];
+        // Zero if the client presented a certificate that was verified
+        ssl.extend(u32::from(!self.verified).to_be_bytes());
+        if let Some(ref version) = self.version {
+            push_tlv(&mut ssl, PP2_SUBTYPE_SSL_VERSION, version.as_bytes());
+        }
+        if let Some(ref name) = self.common_name {
+            push_tlv(&mut ssl, PP2_SUBTYPE_SSL_CN, name.as_bytes());
+        }
+
+        let mut tlvs = Vec::new();
+        push_tlv(&mut tlvs, PP2_TYPE_SSL, &ssl);
+
+        let mut header = SIGNATURE.to_vec();
+        header.extend([VERSION_COMMAND, AF_UNSPEC]);
+        header.extend((tlvs.len() as u16).to_be_bytes());
+        header.extend(tlvs);
+        header
+    }
+}
+
+fn push_tlv(buf: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    buf.push(kind);
+    buf.extend((value.len() as u16).to_be_bytes());
+    buf.extend(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientIdentity;
+    use assert2::assert;
+
+    #[test]
+    fn test_header() {
+        let identity = ClientIdentity {
+            version: Some("TLSv1.3".to_string()),
+            verified: true,
+            common_name: Some("vault-1".to_string()),
+        };
+        let header = identity.to_header();
+
+        assert!(header[..12] == *b"\r\n\r\n\0\r\nQUIT\n");
+        assert!(header[12..14] == [0x21, 0x00]);
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        assert!(header.len() == 16 + len);
+
+        // One SSL TLV, with the client flags, verify, and the version and CN
+        let tlv = &header[16..];
+        assert!(tlv[0] == 0x20);
+        assert!(tlv[3] == 0x03);
+        assert!(tlv[4..8] == [0, 0, 0, 0]);
+        assert!(tlv[8..11] == [0x21, 0x00, 0x07]);
+        assert!(&tlv[11..18] == b"TLSv1.3");
+        assert!(tlv[18..21] == [0x22, 0x00, 0x07]);
+        assert!(&tlv[21..] == b"vault-1");
+
+        // Without a certificate, verify is non-zero
+        let header = ClientIdentity {
+            version: None,
+            verified: false,
+            common_name: None,
+        }
+        .to_header();
+        assert!(header[16..] == [0x20, 0x00, 0x05, 0x01, 0, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,293 @@
+//! Per-connection buffering of ingress streams. Each direction of a stream is copied
+//! through a buffer of a fixed size, and a read only happens once the previous write
+//! has completed, so an app that reads slowly stops the proxy from reading from the
+//! client. The client then sees the TCP window close instead of the proxy taking in
+//! its data. The socket buffers of the TCP legs are capped as well, since the kernel
+//! otherwise grows them to several MiB per connection. vsock buffers are bounded by
+//! the kernel at 256KiB.
+//!
+//! A stream that fails, e.g. because a peer reset its leg, is reset on the TCP leg
+//! it is relayed to, so that peer learns that the stream was cut off rather than
+//! finished.
+//!
+//! The buffers of all streams together can be capped with a [`BufferBudget`], so a
+//! burst of connections cannot take the memory the app needs. Streams past the cap
+//! get smaller buffers, down to 4KiB, and are refused once not even those fit.
+
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use nix::sys::socket::{setsockopt, sockopt};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Copy buffer of each direction of a stream, unless the manifest sets another
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+const MIN_BUFFER_SIZE: usize = 4 * 1024;
+const MAX_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffering {
+    size: usize,
+}
+
+impl Default for Buffering {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUFFER_SIZE)
+    }
+}
+
+impl Buffering {
+    /// Buffers of size bytes, within 4KiB and 4MiB
+    pub fn new(size: usize) -> Self {
+        Self {
+            size: size.clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE),
+        }
+    }
+
+    /// The buffering of an ingress port, from its buffer_bytes
+    pub fn from_manifest(buffer_bytes: Option<u32>) -> Self {
+        match buffer_bytes {
+            Some(size) => Self::new(size as usize),
+            None => Self::default(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Caps the kernel send and receive buffers of a TCP leg
+    pub fn limit<S: AsRawFd>(&self, socket: &S) -> std::io::Result<()> {
+        let fd = socket.as_raw_fd();
+        setsockopt(fd, sockopt::RcvBuf, &self.size)?;
+        setsockopt(fd, sockopt::SndBuf, &self.size)?;
+        Ok(())
+    }
+
+    /// Copies between a and b in both directions until both are shut down, and
+    /// returns the bytes copied from a to b and from b to a
+    pub async fn relay<A, B>(&self, a: &mut A, b: &mut B) -> std::io::Result<(u64, u64)>
+    where
+        A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+        B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    {
+        tokio::io::copy_bidirectional_with_sizes(a, b, self.size, self.size).await
+    }
+}
+
+/// The bytes the copy buffers of all streams may take together. Each stream
+/// reserves two buffers, one per direction, until it is done.
+#[derive(Debug, Clone)]
+pub struct BufferBudget {
+    inner: Arc<BudgetInner>,
+}
+
+#[derive(Debug)]
+struct BudgetInner {
+    limit: Option<usize>,
+    used: AtomicUsize,
+}
+
+impl BufferBudget {
+    /// A budget of limit bytes, or one that only counts if there is no limit
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            inner: Arc::new(BudgetInner {
+                limit,
+                used: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// The budget of odyn's proxies, from the proxy_buffer_mb of the manifest
+    pub fn from_manifest(proxy_buffer_mb: Option<u32>) -> Self {
+        Self::new(proxy_buffer_mb.map(|mb| mb as usize * 1024 * 1024))
+    }
+
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.inner.limit
+    }
+
+    /// Reserves the buffers of a stream, of the size buffering asks for or as much
+    /// smaller as still fits. None if not even the smallest buffers fit.
+    pub fn reserve(&self, buffering: Buffering) -> Option<Reservation> {
+        let mut used = self.used();
+        loop {
+            let size = match self.inner.limit {
+                Some(limit) => buffering.size.min(limit.saturating_sub(used) / 2),
+                None => buffering.size,
+            };
+            if size < MIN_BUFFER_SIZE {
+                return None;
+            }
+
+            let bytes = 2 * size;
+            match self.inner.used.compare_exchange_weak(
+                used,
+                used + bytes,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    return Some(Reservation {
+                        budget: self.inner.clone(),
+                        buffering: Buffering { size },
+                        bytes,
+                    })
+                }
+                Err(current) => used = current,
+            }
+        }
+    }
+}
+
+/// The buffers of one stream, given back to the budget once dropped
+#[derive(Debug)]
+pub struct Reservation {
+    budget: Arc<BudgetInner>,
+    buffering: Buffering,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// The buffering the stream got, which may be smaller than it asked for
+    pub fn buffering(&self) -> Buffering {
+        self.buffering
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// Makes a TCP connection send a RST instead of a FIN once it is dropped
+pub fn reset<S: AsRawFd>(socket: &S) -> std::io::Result<()> {
+    let linger = nix::libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    setsockopt(socket.as_raw_fd(), sockopt::Linger, &linger)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reset, BufferBudget, Buffering, DEFAULT_BUFFER_SIZE};
+    use assert2::assert;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // A connected pair, with the buffers of both ends capped
+    async fn pair(buffering: Buffering) -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        buffering.limit(&listener).unwrap();
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        buffering.limit(&socket).unwrap();
+        let client = socket
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_from_manifest() {
+        assert!(Buffering::from_manifest(None).size() == DEFAULT_BUFFER_SIZE);
+        assert!(Buffering::from_manifest(Some(16384)).size() == 16384);
+        assert!(Buffering::from_manifest(Some(1)).size() == 4096);
+        assert!(Buffering::from_manifest(Some(u32::MAX)).size() == 4 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_buffer_budget() {
+        let budget = BufferBudget::new(Some(160 * 1024));
+        let buffering = Buffering::new(64 * 1024);
+
+        let first = budget.reserve(buffering).unwrap();
+        assert!(first.buffering().size() == 64 * 1024);
+        assert!(budget.used() == 128 * 1024);
+
+        // Only 32KiB are left, so the next stream gets 16KiB per direction
+        let second = budget.reserve(buffering).unwrap();
+        assert!(second.buffering().size() == 16 * 1024);
+        assert!(budget.used() == 160 * 1024);
+
+        assert!(budget.reserve(buffering).is_none());
+
+        drop(first);
+        assert!(budget.used() == 32 * 1024);
+        assert!(budget.reserve(buffering).unwrap().buffering().size() == 64 * 1024);
+
+        drop(second);
+        assert!(budget.used() == 0);
+
+        let unlimited = BufferBudget::from_manifest(None);
+        let reservations: Vec<_> = (0..100)
+            .map(|_| unlimited.reserve(buffering).unwrap())
+            .collect();
+        assert!(unlimited.used() == 100 * 128 * 1024);
+        drop(reservations);
+        assert!(unlimited.used() == 0);
+    }
+
+    #[tokio::test]
+    async fn test_reset() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        reset(&client).unwrap();
+        drop(client);
+
+        let mut buf = [0u8; 1];
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert!(err.kind() == std::io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let buffering = Buffering::new(16 * 1024);
+        let (mut client, mut proxy_in) = pair(buffering).await;
+        let (mut proxy_out, mut app) = pair(buffering).await;
+
+        let relay =
+            tokio::task::spawn(async move { buffering.relay(&mut proxy_in, &mut proxy_out).await });
+
+        // While the app does not read, the client's writes stall once the buffers
+        // along the way are full, long before it has written everything
+        let chunk = vec![0u8; 16 * 1024];
+        let mut written = 0;
+        while written < 64 * 1024 * 1024 {
+            match tokio::time::timeout(Duration::from_millis(500), client.write(&chunk)).await {
+                Ok(n) => written += n.unwrap(),
+                Err(_) => break,
+            }
+        }
+        assert!(written < 1024 * 1024);
+
+        // Nothing is lost once the app catches up
+        let reader = tokio::task::spawn(async move {
+            let mut buf = Vec::new();
+            app.read_to_end(&mut buf).await.unwrap();
+            buf.len()
+        });
+        client.shutdown().await.unwrap();
+        assert!(reader.await.unwrap() == written);
+        drop(client);
+
+        let (sent, _) = relay.await.unwrap().unwrap();
+        assert!(sent == written as u64);
+    }
+}
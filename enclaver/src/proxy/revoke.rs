@@ -0,0 +1,258 @@
+//! What happens to the open tunnels of the default egress when a policy update takes
+//! away what they were allowed for. Tunnels are checked when they open, so without
+//! this a tunnel opened before an update outlives it. With egress.revoked_connections
+//! the proxies register their tunnels here, and odyn checks them against the policy
+//! again after each update, to close those it no longer allows right away or after a
+//! grace period. Each tunnel closed this way is recorded in the audit log.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::info;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::manifest::{RevokeMode, RevokedConnections};
+use crate::policy::{Decision, EgressPolicy};
+use crate::proxy::audit::{AuditEvent, AuditKind, AuditLog};
+
+// The rule of the audit events of revoked tunnels, as for connections a pushed
+// restriction denies
+const REVOKED_RULE: &str = "policy update";
+
+struct Tunnel {
+    host: String,
+    port: u16,
+    deadline: watch::Sender<Option<Instant>>,
+}
+
+/// The open tunnels of a policy, shared by the proxies that check connections against
+/// it and the service that applies policy updates to it
+#[derive(Clone)]
+pub struct Revoker {
+    mode: RevokeMode,
+    drain: Duration,
+    audit: AuditLog,
+    tunnels: Arc<Mutex<HashMap<u64, Tunnel>>>,
+    next: Arc<AtomicU64>,
+}
+
+impl Revoker {
+    pub fn new(spec: Option<&RevokedConnections>) -> Self {
+        let spec = spec.cloned().unwrap_or_default();
+
+        Self {
+            mode: spec.mode,
+            drain: Duration::from_secs(spec.drain_seconds()),
+            audit: AuditLog::default(),
+            tunnels: Arc::new(Mutex::new(HashMap::new())),
+            next: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Where to record the tunnels the revoker closes
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Registers a tunnel to host:port that was just opened. None if tunnels are
+    /// kept open whatever the updates say, so there is nothing to track.
+    pub fn open(&self, host: &str, port: u16) -> Option<Revocable> {
+        if self.mode == RevokeMode::Keep {
+            return None;
+        }
+
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let (deadline, revoked) = watch::channel(None);
+        self.tunnels.lock().unwrap().insert(
+            id,
+            Tunnel {
+                host: host.to_string(),
+                port,
+                deadline,
+            },
+        );
+
+        Some(Revocable {
+            id,
+            host: host.to_string(),
+            port,
+            revoked,
+            audit: self.audit.clone(),
+            tunnels: self.tunnels.clone(),
+        })
+    }
+
+    /// Checks the open tunnels against the policy, once an update has narrowed it, and
+    /// closes those it no longer allows now or once they have drained. Returns how many.
+    pub fn revoke(&self, policy: &EgressPolicy) -> usize {
+        let deadline = match self.mode {
+            RevokeMode::Keep => return 0,
+            RevokeMode::Drain => Instant::now() + self.drain,
+            RevokeMode::Terminate => Instant::now(),
+        };
+
+        let tunnels = self.tunnels.lock().unwrap();
+        let mut revoked = 0;
+        for tunnel in tunnels.values() {
+            // A tunnel revoked by an earlier update keeps its deadline
+            if tunnel.deadline.borrow().is_some()
+                || policy.is_connect_allowed(&tunnel.host, tunnel.port)
+            {
+                continue;
+            }
+
+            tunnel.deadline.send_replace(Some(deadline));
+            revoked += 1;
+        }
+
+        if revoked > 0 {
+            info!(
+                "The policy update revoked {revoked} open egress tunnels, closing them {}",
+                match self.mode {
+                    RevokeMode::Drain => format!("in {}s", self.drain.as_secs()),
+                    _ => "now".to_string(),
+                }
+            );
+        }
+
+        revoked
+    }
+}
+
+/// A registered tunnel, which leaves the revoker when dropped
+pub struct Revocable {
+    id: u64,
+    host: String,
+    port: u16,
+    revoked: watch::Receiver<Option<Instant>>,
+    audit: AuditLog,
+    tunnels: Arc<Mutex<HashMap<u64, Tunnel>>>,
+}
+
+impl Revocable {
+    /// Resolves once the tunnel has to be closed, and records that it was
+    pub async fn revoked(&mut self) {
+        let deadline = loop {
+            if let Some(deadline) = *self.revoked.borrow_and_update() {
+                break deadline;
+            }
+            // The sender lives as long as the registration
+            if self.revoked.changed().await.is_err() {
+                return std::future::pending().await;
+            }
+        };
+
+        tokio::time::sleep_until(deadline).await;
+
+        let decision = Decision {
+            allowed: false,
+            rule: Some(REVOKED_RULE.to_string()),
+        };
+        self.audit.record(AuditEvent::new(
+            AuditKind::Revoked,
+            &self.host,
+            self.port,
+            None,
+            &decision,
+        ));
+    }
+}
+
+impl Drop for Revocable {
+    fn drop(&mut self) {
+        self.tunnels.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Revoker;
+    use crate::manifest::{Egress, RevokeMode, RevokedConnections};
+    use crate::policy::EgressPolicy;
+    use crate::proxy::audit::{AuditKind, AuditLog, Verdict};
+    use assert2::assert;
+    use std::time::Duration;
+    use tokio::time::{timeout, Instant};
+
+    fn policy() -> EgressPolicy {
+        EgressPolicy::new(&Egress {
+            allow: Some(vec!["**.example.com".to_string()]),
+            ..Default::default()
+        })
+    }
+
+    fn restrict(policy: &EgressPolicy) {
+        policy.restrict(EgressPolicy::new(&Egress {
+            allow: Some(vec!["api.example.com".to_string()]),
+            ..Default::default()
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_revoke_terminate() {
+        let spec = RevokedConnections {
+            mode: RevokeMode::Terminate,
+            drain_seconds: None,
+        };
+        let audit = AuditLog::default();
+        let revoker = Revoker::new(Some(&spec)).with_audit(audit.clone());
+        let mut kept = revoker.open("api.example.com", 443).unwrap();
+        let mut closed = revoker.open("old.example.com", 443).unwrap();
+
+        let policy = policy();
+        assert!(revoker.revoke(&policy) == 0);
+        restrict(&policy);
+        assert!(revoker.revoke(&policy) == 1);
+
+        timeout(Duration::from_secs(1), closed.revoked())
+            .await
+            .unwrap();
+        let events = audit.events();
+        assert!(events.len() == 1);
+        assert!(events[0].kind == AuditKind::Revoked);
+        assert!(events[0].host == "old.example.com");
+        assert!(events[0].verdict == Verdict::Denied);
+
+        assert!(timeout(Duration::from_millis(50), kept.revoked())
+            .await
+            .is_err());
+
+        // Closed tunnels leave the revoker
+        drop(closed);
+        assert!(revoker.tunnels.lock().unwrap().len() == 1);
+    }
+
+    #[tokio::test]
+    async fn test_revoke_drain() {
+        let spec = RevokedConnections {
+            mode: RevokeMode::Drain,
+            drain_seconds: None,
+        };
+        let mut revoker = Revoker::new(Some(&spec));
+        revoker.drain = Duration::from_millis(100);
+        let mut draining = revoker.open("old.example.com", 443).unwrap();
+
+        let policy = policy();
+        restrict(&policy);
+        let started = Instant::now();
+        assert!(revoker.revoke(&policy) == 1);
+
+        // A later update does not push the deadline back
+        assert!(revoker.revoke(&policy) == 0);
+
+        timeout(Duration::from_secs(1), draining.revoked())
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_revoke_keep() {
+        let revoker = Revoker::new(None);
+        assert!(revoker.open("old.example.com", 443).is_none());
+    }
+}
@@ -0,0 +1,358 @@
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use aws_credential_types::cache::{ProvideCachedCredentials, SharedCredentialsCache};
+use aws_credential_types::Credentials;
+use aws_sigv4::http_request::{
+    PayloadChecksumKind, PercentEncodingMode, SignableBody, SignableRequest, SigningSettings,
+    UriPathNormalizationMode,
+};
+use aws_sigv4::SigningParams;
+use http::header::{HeaderMap, HeaderName};
+use http::uri::{Authority, Scheme};
+use http::Uri;
+use hyper::{Body, Request, Response};
+use log::debug;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::aws_util::CredentialScope;
+use super::kms::HttpClient;
+use crate::http_util::{self, HttpHandler};
+
+const S3_SERVICE_NAME: &str = "s3";
+
+static X_AMZ_CONTENT_SHA256: HeaderName = HeaderName::from_static("x-amz-content-sha256");
+
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+// An aws-chunked body whose chunks carry no signatures, only a checksum in the trailer
+const STREAMING_UNSIGNED_PAYLOAD_TRAILER: &str = "STREAMING-UNSIGNED-PAYLOAD-TRAILER";
+
+// The signature of the application, which is replaced, and the headers of the
+// connection to the proxy rather than of the request
+const DROPPED_HEADERS: [&str; 12] = [
+    "authorization",
+    "x-amz-date",
+    "x-amz-security-token",
+    "x-amz-content-sha256",
+    "host",
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "expect",
+];
+
+// Query parameters of presigned URLs, which cannot be combined with a signature in
+// the Authorization header
+const PRESIGNED_QUERY_PARAMS: [&str; 7] = [
+    "X-Amz-Algorithm",
+    "X-Amz-Credential",
+    "X-Amz-Date",
+    "X-Amz-Expires",
+    "X-Amz-SignedHeaders",
+    "X-Amz-Signature",
+    "X-Amz-Security-Token",
+];
+
+pub trait S3EndpointProvider {
+    fn endpoint(&self, region: &str) -> String;
+}
+
+pub struct S3ProxyConfig {
+    pub client: Box<dyn HttpClient + Send + Sync>,
+    pub credentials: SharedCredentialsCache,
+    pub endpoints: Arc<dyn S3EndpointProvider + Send + Sync>,
+}
+
+/// Forwards S3 requests of the application, signed with any credentials, to S3
+/// signed with those of the instance. Bodies are streamed through in both
+/// directions rather than buffered, so objects of any size, and each part of a
+/// multipart upload, pass through in constant memory.
+pub struct S3ProxyHandler {
+    config: S3ProxyConfig,
+}
+
+impl S3ProxyHandler {
+    pub fn new(config: S3ProxyConfig) -> Self {
+        Self { config }
+    }
+
+    fn outgoing_request(
+        &self,
+        head: &http::request::Parts,
+        region: &str,
+        body: Body,
+    ) -> Result<Request<Body>> {
+        let authority = Authority::from_maybe_shared(self.config.endpoints.endpoint(region))?;
+
+        let path_and_query = match head.uri.query().map(strip_presigned_query) {
+            Some(query) if !query.is_empty() => format!("{}?{query}", head.uri.path()),
+            _ => head.uri.path().to_string(),
+        };
+
+        let uri = Uri::builder()
+            .scheme(Scheme::HTTPS)
+            .authority(authority)
+            .path_and_query(path_and_query)
+            .build()?;
+
+        let mut req = Request::builder()
+            .method(head.method.clone())
+            .uri(uri)
+            .body(body)?;
+
+        for (name, value) in head.headers.iter() {
+            if !DROPPED_HEADERS.contains(&name.as_str()) {
+                req.headers_mut().append(name, value.clone());
+            }
+        }
+
+        Ok(req)
+    }
+}
+
+#[async_trait]
+impl HttpHandler for S3ProxyHandler {
+    async fn handle(&self, req: Request<Body>) -> Result<Response<Body>> {
+        debug!("Request: {} {}", req.method(), req.uri());
+
+        let (head, body) = req.into_parts();
+
+        let credential = match CredentialScope::from_request(&head)
+            .and_then(|c| c.validate(S3_SERVICE_NAME).map(|_| c))
+        {
+            Ok(credential) => credential,
+            Err(err) => return Ok(http_util::bad_request(err.to_string())),
+        };
+
+        let payload_hash = match payload_hash(&head.headers) {
+            Ok(hash) => hash,
+            Err(msg) => return Ok(http_util::bad_request(msg)),
+        };
+
+        let mut req = self.outgoing_request(&head, &credential.region, body)?;
+
+        let credentials = self.config.credentials.provide_cached_credentials().await?;
+        sign(&mut req, &credentials, &credential.region, payload_hash)?;
+
+        debug!("Sending Request: {} {}", req.method(), req.uri());
+        Ok(self.config.client.request(req).await?)
+    }
+}
+
+// What the application signed the body as. S3 checks a hex digest against the body
+// itself, so the proxy passes it on without reading the body.
+fn payload_hash(headers: &HeaderMap) -> Result<String, String> {
+    let value = match headers.get(&X_AMZ_CONTENT_SHA256) {
+        Some(value) => value
+            .to_str()
+            .map_err(|_| format!("invalid {X_AMZ_CONTENT_SHA256} header"))?,
+        None => return Ok(UNSIGNED_PAYLOAD.to_string()),
+    };
+
+    let is_digest = value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit());
+    if is_digest || value == UNSIGNED_PAYLOAD || value == STREAMING_UNSIGNED_PAYLOAD_TRAILER {
+        Ok(value.to_string())
+    } else {
+        // Signed chunks chain from the signature of the request, which is replaced
+        Err(format!(
+            "{X_AMZ_CONTENT_SHA256} {value} is not supported by the S3 proxy, use {UNSIGNED_PAYLOAD} or a SHA-256 digest of the body"
+        ))
+    }
+}
+
+// Removes the signature of a presigned URL, leaving the rest of the query as it was
+// encoded, e.g. the uploadId and partNumber of a multipart upload
+fn strip_presigned_query(query: &str) -> String {
+    query
+        .split('&')
+        .filter(|pair| {
+            let key = pair.split('=').next().unwrap_or_default();
+            !PRESIGNED_QUERY_PARAMS
+                .iter()
+                .any(|p| p.eq_ignore_ascii_case(key))
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn sign(
+    req: &mut Request<Body>,
+    credentials: &Credentials,
+    region: &str,
+    payload_hash: String,
+) -> Result<()> {
+    // S3 signs the path as is, and wants the payload hash in a header
+    let mut signing_settings = SigningSettings::default();
+    signing_settings.payload_checksum_kind = PayloadChecksumKind::XAmzSha256;
+    signing_settings.percent_encoding_mode = PercentEncodingMode::Single;
+    signing_settings.uri_path_normalization_mode = UriPathNormalizationMode::Disabled;
+
+    let mut signing_builder = SigningParams::builder()
+        .access_key(credentials.access_key_id())
+        .secret_key(credentials.secret_access_key())
+        .region(region)
+        .service_name(S3_SERVICE_NAME)
+        .time(SystemTime::now())
+        .settings(signing_settings);
+
+    if let Some(token) = credentials.session_token() {
+        signing_builder = signing_builder.security_token(token);
+    }
+
+    let signing_params = signing_builder.build()?;
+
+    let signable_request = SignableRequest::new(
+        req.method(),
+        req.uri(),
+        req.headers(),
+        SignableBody::Precomputed(payload_hash),
+    );
+
+    let signed =
+        aws_sigv4::http_request::sign(signable_request, &signing_params).map_err(Error::msg)?;
+
+    let (signing_instructions, _signature) = signed.into_parts();
+    signing_instructions.apply_to_request(req);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert2::assert;
+    use aws_credential_types::cache::CredentialsCache;
+    use aws_credential_types::provider::SharedCredentialsProvider;
+    use hyper::{Method, StatusCode};
+
+    const AUTHORIZATION: &str =
+        "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/eu-west-1/s3/aws4_request, ";
+
+    // Echoes the body back, so the tests can tell it went through untouched
+    struct Mock;
+
+    #[async_trait]
+    impl HttpClient for Mock {
+        async fn request(
+            &self,
+            req: Request<Body>,
+        ) -> std::result::Result<Response<Body>, hyper::Error> {
+            let authz = req
+                .headers()
+                .get(hyper::header::AUTHORIZATION)
+                .unwrap()
+                .to_str()
+                .unwrap();
+
+            assert!(authz.starts_with("AWS4-HMAC-SHA256 Credential=TESTKEY/"));
+            assert!(authz.contains("/eu-west-1/s3/aws4_request"));
+            assert!(req.uri().host() == Some("test.local"));
+            assert!(req.uri().scheme_str() == Some("https"));
+
+            let resp = Response::builder()
+                .status(StatusCode::OK)
+                .header("x-uri", req.uri().to_string())
+                .header(
+                    "x-payload",
+                    req.headers().get(&X_AMZ_CONTENT_SHA256).unwrap(),
+                )
+                .body(req.into_body())
+                .unwrap();
+
+            Ok(resp)
+        }
+    }
+
+    impl S3EndpointProvider for Mock {
+        fn endpoint(&self, _region: &str) -> String {
+            "test.local".to_string()
+        }
+    }
+
+    fn new_test_handler() -> S3ProxyHandler {
+        let credentials = Credentials::from_keys("TESTKEY", "TESTSECRET", None);
+
+        S3ProxyHandler::new(S3ProxyConfig {
+            client: Box::new(Mock),
+            credentials: CredentialsCache::no_caching()
+                .create_cache(SharedCredentialsProvider::new(credentials)),
+            endpoints: Arc::new(Mock),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_upload_part() {
+        let handler = new_test_handler();
+
+        let (mut sender, body) = Body::channel();
+        let req = Request::builder()
+            .method(Method::PUT)
+            .uri("/bucket/key?partNumber=2&uploadId=abc%2Fdef")
+            .header(hyper::header::AUTHORIZATION, AUTHORIZATION)
+            .header(&X_AMZ_CONTENT_SHA256, UNSIGNED_PAYLOAD)
+            .body(body)
+            .unwrap();
+
+        // The response arrives before the body has been sent, so nothing buffers it
+        let resp = handler.handle(req).await.unwrap();
+        sender.send_data("part two".into()).await.unwrap();
+        drop(sender);
+
+        assert!(resp.status() == StatusCode::OK);
+        assert!(
+            resp.headers()["x-uri"]
+                == "https://test.local/bucket/key?partNumber=2&uploadId=abc%2Fdef"
+        );
+        assert!(resp.headers()["x-payload"] == UNSIGNED_PAYLOAD);
+
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(body.as_ref() == b"part two");
+    }
+
+    #[tokio::test]
+    async fn test_presigned_get() {
+        let handler = new_test_handler();
+
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("/bucket/key?X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential=AKIDEXAMPLE%2F20150830%2Feu-west-1%2Fs3%2Faws4_request&X-Amz-Signature=00")
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = handler.handle(req).await.unwrap();
+        assert!(resp.status() == StatusCode::OK);
+        assert!(resp.headers()["x-uri"] == "https://test.local/bucket/key");
+    }
+
+    #[tokio::test]
+    async fn test_rejected_requests() {
+        let handler = new_test_handler();
+
+        let chunked = Request::builder()
+            .method(Method::PUT)
+            .uri("/bucket/key")
+            .header(hyper::header::AUTHORIZATION, AUTHORIZATION)
+            .header(&X_AMZ_CONTENT_SHA256, "STREAMING-AWS4-HMAC-SHA256-PAYLOAD")
+            .body(Body::empty())
+            .unwrap();
+
+        let not_s3 = Request::builder()
+            .method(Method::GET)
+            .uri("/bucket/key")
+            .header(
+                hyper::header::AUTHORIZATION,
+                AUTHORIZATION.replace("/s3/", "/kms/"),
+            )
+            .body(Body::empty())
+            .unwrap();
+
+        for req in [chunked, not_s3] {
+            let resp = handler.handle(req).await.unwrap();
+            assert!(resp.status() == StatusCode::BAD_REQUEST);
+        }
+    }
+}
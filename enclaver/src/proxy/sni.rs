@@ -0,0 +1,204 @@
+//! The server name a TLS client asks for in its ClientHello. The transparent egress
+//! proxy uses it to tell where a connection was headed, and CONNECT tunnels check it
+//! against the policy when the manifest sets verify_sni.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::proxy::error::ProxyError;
+
+pub(crate) const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXT_SERVER_NAME: u16 = 0x0000;
+
+// The largest plaintext record TLS allows
+const TLS_MAX_RECORD_LEN: usize = 16384;
+
+// A TLS client speaks first, so one that does not is not one
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The first record a TLS client sent, and the server name its ClientHello asks for
+pub(crate) struct ClientHello {
+    pub record: Vec<u8>,
+    pub server_name: Option<String>,
+}
+
+/// Reads the record holding the ClientHello of a TLS client. Fails for anything but
+/// TLS, and for a ClientHello that does not fit in a single record.
+pub(crate) async fn read_client_hello<R: AsyncRead + Unpin>(
+    r: &mut R,
+) -> Result<ClientHello, ProxyError> {
+    tokio::time::timeout(CLIENT_HELLO_TIMEOUT, read_record(r))
+        .await
+        .map_err(|_| ProxyError::InvalidTarget("no TLS ClientHello was sent".to_string()))?
+}
+
+async fn read_record<R: AsyncRead + Unpin>(r: &mut R) -> Result<ClientHello, ProxyError> {
+    let mut record = vec![0u8; 5];
+    r.read_exact(&mut record).await?;
+
+    let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+    if record[0] != TLS_HANDSHAKE || len > TLS_MAX_RECORD_LEN {
+        return Err(ProxyError::InvalidTarget(
+            "the tunnel does not start with a TLS handshake".to_string(),
+        ));
+    }
+
+    record.resize(5 + len, 0);
+    r.read_exact(&mut record[5..]).await?;
+
+    match read_server_name(&record) {
+        Ok(server_name) => Ok(ClientHello {
+            record,
+            server_name,
+        }),
+        Err(ReadError::Short) => Err(ProxyError::InvalidTarget(
+            "the TLS ClientHello spans more than one record".to_string(),
+        )),
+        Err(ReadError::Invalid) => Err(ProxyError::InvalidTarget(
+            "the tunnel does not start with a TLS ClientHello".to_string(),
+        )),
+    }
+}
+
+/// The server_name extension of a TLS ClientHello, which must fit in the record at
+/// the start of data
+pub(crate) fn read_server_name(data: &[u8]) -> Result<Option<String>, ReadError> {
+    let mut r = Reader { data, pos: 0 };
+    r.skip(3)?;
+    let len = r.u16()? as usize;
+    let mut hello = Reader {
+        data: r.sub(len)?,
+        pos: 0,
+    };
+
+    if hello.u8()? != TLS_CLIENT_HELLO {
+        return Err(ReadError::Invalid);
+    }
+    // Length, version and random
+    hello.skip(3 + 2 + 32)?;
+    // Session ID, cipher suites and compression methods
+    let len = hello.u8()? as usize;
+    hello.skip(len)?;
+    let len = hello.u16()? as usize;
+    hello.skip(len)?;
+    let len = hello.u8()? as usize;
+    hello.skip(len)?;
+
+    let len = hello.u16()? as usize;
+    let mut exts = Reader {
+        data: hello.sub(len)?,
+        pos: 0,
+    };
+    while exts.pos < exts.data.len() {
+        let ext_type = exts.u16()?;
+        let len = exts.u16()? as usize;
+        let ext = exts.sub(len)?;
+        if ext_type != TLS_EXT_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Reader { data: ext, pos: 0 };
+        names.skip(2)?;
+        while names.pos < names.data.len() {
+            let name_type = names.u8()?;
+            let len = names.u16()? as usize;
+            let name = names.sub(len)?;
+            if name_type == 0 {
+                let name = std::str::from_utf8(name).map_err(|_| ReadError::Invalid)?;
+                return Ok(Some(name.to_string()));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+pub(crate) enum ReadError {
+    Short,
+    Invalid,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn sub(&mut self, len: usize) -> Result<&'a [u8], ReadError> {
+        let data = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(ReadError::Short)?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), ReadError> {
+        self.sub(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, ReadError> {
+        Ok(self.sub(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ReadError> {
+        let data = self.sub(2)?;
+        Ok(u16::from_be_bytes([data[0], data[1]]))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::read_client_hello;
+    use assert2::assert;
+
+    pub(crate) fn client_hello(server_name: &str) -> Vec<u8> {
+        let name = server_name.as_bytes();
+        let mut sni = vec![0, 0];
+        sni.extend_from_slice(&((name.len() + 5) as u16).to_be_bytes());
+        sni.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name);
+
+        // An unrelated extension first, supported_versions
+        let mut exts = vec![0, 43, 0, 3, 2, 3, 4];
+        exts.extend_from_slice(&sni);
+
+        let mut hello = vec![3, 3];
+        hello.extend_from_slice(&[0xaa; 32]);
+        hello.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        hello.extend_from_slice(&(exts.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&exts);
+
+        let mut handshake = vec![1, 0];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![0x16, 3, 1];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello() {
+        let mut hello = client_hello("api.example.com");
+        hello.extend_from_slice(b"early data");
+
+        let mut reader = hello.as_slice();
+        let read = read_client_hello(&mut reader).await.unwrap();
+        assert!(read.server_name.as_deref() == Some("api.example.com"));
+        assert!(read.record == hello[..hello.len() - 10]);
+        assert!(reader == b"early data");
+
+        let mut reader = &b"GET / HTTP/1.1\r\n\r\n"[..];
+        assert!(read_client_hello(&mut reader).await.is_err());
+
+        let hello = client_hello("api.example.com");
+        let mut reader = &hello[..hello.len() - 4];
+        assert!(read_client_hello(&mut reader).await.is_err());
+    }
+}
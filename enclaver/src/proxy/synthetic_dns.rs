@@ -0,0 +1,167 @@
+//! A DNS server for inside the enclave, which has no resolver of its own. Every
+//! name resolves to an address of its own in a reserved network, which tells the
+//! transparent egress proxy where a connection was headed. The host resolves the
+//! name for real when it relays the connection.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, Level};
+use tokio::net::UdpSocket;
+
+use crate::proxy::dns::address_answer;
+use crate::proxy::error::ProxyError;
+use crate::utils;
+
+// 198.18.0.0/15, set aside for benchmarking by RFC 2544, so never routable
+const NETWORK: u32 = 0xc612_0000;
+const NETWORK_SIZE: u32 = 1 << 17;
+
+const MAX_MESSAGE_LEN: usize = 512;
+
+/// Names the enclave resolved, and the synthetic addresses handed out for them
+#[derive(Clone, Default)]
+pub struct SyntheticNames {
+    table: Arc<Mutex<NameTable>>,
+}
+
+#[derive(Default)]
+struct NameTable {
+    by_name: HashMap<String, Ipv4Addr>,
+    by_addr: HashMap<Ipv4Addr, String>,
+    next: u32,
+}
+
+impl SyntheticNames {
+    /// The address of name, handed out the first time it is asked for. Once the
+    /// network runs out, the oldest addresses are reused.
+    pub fn address_of(&self, name: &str) -> Ipv4Addr {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let mut table = self.table.lock().unwrap();
+
+        if let Some(addr) = table.by_name.get(&name) {
+            return *addr;
+        }
+
+        // Skips the network and broadcast addresses
+        let addr = Ipv4Addr::from(NETWORK + 1 + table.next % (NETWORK_SIZE - 2));
+        table.next = table.next.wrapping_add(1);
+
+        if let Some(old) = table.by_addr.insert(addr, name.clone()) {
+            table.by_name.remove(&old);
+        }
+        table.by_name.insert(name, addr);
+
+        addr
+    }
+
+    /// The name addr was handed out for, if it is a synthetic address
+    pub fn name_of(&self, addr: Ipv4Addr) -> Option<String> {
+        self.table.lock().unwrap().by_addr.get(&addr).cloned()
+    }
+}
+
+pub struct SyntheticDns {
+    socket: UdpSocket,
+    names: SyntheticNames,
+}
+
+impl SyntheticDns {
+    pub async fn bind(addr: SocketAddr, names: SyntheticNames) -> Result<Self, ProxyError> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr).await?,
+            names,
+        })
+    }
+
+    pub async fn serve(self) {
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+
+        loop {
+            let (len, peer) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    utils::log_limited!(Level::Error, "", "DNS receive failed: {err}");
+                    continue;
+                }
+            };
+
+            if let Some(resp) = answer(&buf[..len], &self.names) {
+                if let Err(err) = self.socket.send_to(&resp, peer).await {
+                    debug!("DNS reply to {peer} failed: {err}");
+                }
+            }
+        }
+    }
+}
+
+// Answers A queries with a synthetic address, and every other type with no
+// records, so clients fall back to IPv4. Returns None for anything but a query.
+fn answer(query: &[u8], names: &SyntheticNames) -> Option<Vec<u8>> {
+    address_answer(query, |name| names.address_of(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{answer, SyntheticNames, NETWORK_SIZE};
+    use assert2::assert;
+    use std::net::Ipv4Addr;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.push(0);
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes());
+        msg
+    }
+
+    #[test]
+    fn test_synthetic_names() {
+        let names = SyntheticNames::default();
+
+        let addr = names.address_of("S3.amazonaws.com.");
+        assert!(addr == Ipv4Addr::new(198, 18, 0, 1));
+        assert!(names.address_of("s3.amazonaws.com") == addr);
+        assert!(names.name_of(addr).as_deref() == Some("s3.amazonaws.com"));
+        assert!(names.address_of("example.com") == Ipv4Addr::new(198, 18, 0, 2));
+        assert!(names.name_of(Ipv4Addr::new(198, 18, 0, 3)) == None);
+
+        // The oldest name gives up its address once the network runs out
+        for i in 0..NETWORK_SIZE - 4 {
+            names.address_of(&format!("{i}.example.org"));
+        }
+        assert!(names.address_of("late.example.org") == addr);
+        assert!(names.name_of(addr).as_deref() == Some("late.example.org"));
+    }
+
+    #[test]
+    fn test_answer() {
+        let names = SyntheticNames::default();
+
+        let q = query("vault.example.com", 1);
+        let resp = answer(&q, &names).unwrap();
+        assert!(resp[0..2] == [0x12, 0x34]);
+        assert!(resp[2..4] == [0x81, 0x80]);
+        assert!(resp[6..8] == [0, 1]);
+        assert!(resp[12..q.len()] == q[12..]);
+        assert!(resp[resp.len() - 4..] == [198, 18, 0, 1]);
+
+        // AAAA gets no records
+        let resp = answer(&query("vault.example.com", 28), &names).unwrap();
+        assert!(resp[3] & 0x0f == 0);
+        assert!(resp[6..8] == [0, 0]);
+
+        let mut truncated = query("vault.example.com", 1);
+        truncated.truncate(20);
+        assert!(answer(&truncated, &names).unwrap()[3] & 0x0f == 1);
+
+        let mut response = query("vault.example.com", 1);
+        response[2] |= 0x80;
+        assert!(answer(&response, &names) == None);
+    }
+}
@@ -0,0 +1,158 @@
+//! Holds back what the application sends to the hosts of an egress limit to the
+//! bandwidth of the limit. Data past the bandwidth is delayed rather than dropped:
+//! the stream it was read from is not read again until the limit has caught up, so
+//! the application sees its writes slow down.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::StreamExt;
+use hyper::Body;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::policy::limits::EgressLimiter;
+
+/// A stream from the application, whose reads are held back to the bandwidth of a
+/// limit. Without a limit, it only passes the stream through.
+pub struct Throttled<S> {
+    inner: S,
+    limiter: Option<Arc<EgressLimiter>>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, limiter: Option<Arc<EgressLimiter>>) -> Self {
+        Self {
+            inner,
+            limiter,
+            delay: None,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if let Some(ref mut delay) = self.delay {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            if let Some(wait) = self.limiter.as_ref().and_then(|l| l.send(n)) {
+                self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Holds back the chunks of a request body to the bandwidth of a limit
+pub fn throttle_body(body: Body, limiter: Option<&Arc<EgressLimiter>>) -> Body {
+    let limiter = match limiter {
+        Some(limiter) if limiter.has_bandwidth() => limiter.clone(),
+        _ => return body,
+    };
+    // An empty body stays one, so no framing is added to it
+    if hyper::body::HttpBody::is_end_stream(&body) {
+        return body;
+    }
+
+    Body::wrap_stream(body.then(move |chunk| {
+        let wait = match chunk {
+            Ok(ref chunk) => limiter.send(chunk.len()),
+            Err(_) => None,
+        };
+        async move {
+            if let Some(wait) = wait {
+                tokio::time::sleep(wait).await;
+            }
+            chunk
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{throttle_body, Throttled};
+    use crate::manifest::EgressLimit;
+    use crate::policy::limits::EgressLimiter;
+    use assert2::assert;
+    use hyper::Body;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::io::AsyncReadExt;
+
+    fn limiter(bandwidth: u32) -> Arc<EgressLimiter> {
+        Arc::new(EgressLimiter::new(&EgressLimit {
+            hosts: vec!["upload.example.com".to_string()],
+            rate_limit: None,
+            bandwidth: Some(bandwidth),
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_throttled_stream() {
+        let limiter = limiter(10_000);
+        let data = vec![0u8; 13_000];
+        let mut stream = Throttled::new(&data[..], Some(limiter.clone()));
+
+        // The first read goes through at once and overdraws the limit by 3000
+        // bytes, which the next read waits for
+        let start = Instant::now();
+        let mut buf = vec![0u8; 20_000];
+        assert!(stream.read(&mut buf).await.unwrap() == 13_000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        assert!(stream.read(&mut buf).await.unwrap() == 0);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert!(limiter.stats().throttled == 1);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_body() {
+        let limiter = limiter(10_000);
+        let chunks: Vec<Result<_, std::io::Error>> =
+            vec![Ok(vec![0u8; 10_000]), Ok(vec![0u8; 3_000])];
+        let body = throttle_body(
+            Body::wrap_stream(futures::stream::iter(chunks)),
+            Some(&limiter),
+        );
+
+        let start = Instant::now();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        assert!(body.len() == 13_000);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert!(limiter.stats().throttled == 1);
+    }
+}
@@ -0,0 +1,250 @@
+//! Egress for applications that ignore http_proxy. odyn redirects their outbound
+//! TCP connections here, and the proxy works out where each was headed: from the
+//! synthetic address its name resolved to, or else from the TLS SNI or HTTP Host
+//! it opens with. From there on it is treated like a CONNECT tunnel.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, Level};
+use nix::sys::socket::{getsockopt, sockopt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::policy::EgressPolicy;
+use crate::proxy::authority::Target;
+use crate::proxy::egress_http::splice;
+use crate::proxy::error::ProxyError;
+use crate::proxy::host_relays::HostRelays;
+use crate::proxy::revoke::Revoker;
+use crate::proxy::sni::{read_server_name, ReadError, TLS_HANDSHAKE};
+use crate::proxy::synthetic_dns::SyntheticNames;
+use crate::utils;
+
+const SNIFF_LEN: usize = 4096;
+
+// Long enough for a client that speaks first, short enough not to hold up one
+// that waits for the server, e.g. SMTP
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+const SNIFF_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct TransparentProxy {
+    listener: TcpListener,
+    names: SyntheticNames,
+    revoker: Option<Revoker>,
+}
+
+impl TransparentProxy {
+    pub async fn bind(port: u16, names: SyntheticNames) -> Result<Self, ProxyError> {
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            names,
+            revoker: None,
+        })
+    }
+
+    /// Registers the intercepted connections with revoker, which closes those a
+    /// policy update takes away
+    pub fn with_revoker(mut self, revoker: Revoker) -> Self {
+        self.revoker = Some(revoker);
+        self
+    }
+
+    pub async fn serve(self, relays: HostRelays, egress_policy: Arc<EgressPolicy>) {
+        loop {
+            match self.listener.accept().await {
+                Ok((sock, _)) => {
+                    let relays = relays.clone();
+                    let egress_policy = egress_policy.clone();
+                    let names = self.names.clone();
+                    let revoker = self.revoker.clone();
+
+                    utils::spawn!("transparent egress stream", async move {
+                        if let Err(err) = Self::service_conn(
+                            sock,
+                            &relays,
+                            &egress_policy,
+                            &names,
+                            revoker.as_ref(),
+                        )
+                        .await
+                        {
+                            let key = &err.to_string();
+                            utils::log_limited!(Level::Error, key, "transparent egress: {err}");
+                        }
+                    })
+                    .expect("spawn transparent egress stream");
+                }
+                Err(err) => {
+                    utils::log_limited!(Level::Error, "", "Accept failed: {err}");
+                }
+            }
+        }
+    }
+
+    async fn service_conn(
+        tcp: TcpStream,
+        relays: &HostRelays,
+        egress_policy: &EgressPolicy,
+        names: &SyntheticNames,
+        revoker: Option<&Revoker>,
+    ) -> Result<(), ProxyError> {
+        let dst = original_dst(&tcp)?;
+
+        let host = match names.name_of(*dst.ip()) {
+            Some(name) => name,
+            None => match sniff_host(&tcp).await {
+                Some(name) => name,
+                None => dst.ip().to_string(),
+            },
+        };
+        let target = Target::parse(&format!("{host}:{}", dst.port()), None)?;
+
+        // Hosts with request rules are only reached through the HTTP proxy
+        if !egress_policy.is_connect_allowed(&target.host, target.port)
+            || egress_policy
+                .request_filter(&target.host, target.port)
+                .is_some()
+        {
+            return Err(ProxyError::Denied(target.authority()));
+        }
+        let protocol = egress_policy.protocol(&target.host, target.port);
+        let limit = egress_policy.limit(&target.host, target.port);
+        if limit.as_ref().is_some_and(|limit| !limit.admit()) {
+            return Err(ProxyError::RateLimited(target.authority()));
+        }
+
+        debug!("Intercepted connection to {dst} for {}", target.authority());
+
+        let remote = relays
+            .connect(&target.host, target.port, egress_policy.name())
+            .await?;
+        let revocable = revoker.and_then(|revoker| revoker.open(&target.host, target.port));
+        splice(
+            tcp,
+            remote,
+            protocol,
+            limit,
+            revocable,
+            &target.host,
+            target.port,
+        )
+        .await;
+
+        Ok(())
+    }
+}
+
+// Where the connection was headed before it was redirected
+fn original_dst(tcp: &TcpStream) -> std::io::Result<SocketAddrV4> {
+    let addr = getsockopt(tcp.as_raw_fd(), sockopt::OriginalDst)?;
+
+    Ok(SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    ))
+}
+
+// Peeks at what the client sends first, leaving it to be relayed as is
+async fn sniff_host(tcp: &TcpStream) -> Option<String> {
+    let mut buf = vec![0u8; SNIFF_LEN];
+
+    let sniff = async {
+        let mut seen = 0;
+        loop {
+            let len = tcp.peek(&mut buf).await.ok()?;
+            match parse_host(&buf[..len]) {
+                Sniffed::Host(host) => return Some(host),
+                Sniffed::Unknown => return None,
+                Sniffed::Incomplete if len == 0 || len == buf.len() => return None,
+                Sniffed::Incomplete if len == seen => tokio::time::sleep(SNIFF_INTERVAL).await,
+                Sniffed::Incomplete => seen = len,
+            }
+        }
+    };
+
+    tokio::time::timeout(SNIFF_TIMEOUT, sniff)
+        .await
+        .ok()
+        .flatten()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Sniffed {
+    Host(String),
+    Incomplete,
+    Unknown,
+}
+
+fn parse_host(data: &[u8]) -> Sniffed {
+    match data.first() {
+        None => Sniffed::Incomplete,
+        Some(&TLS_HANDSHAKE) => parse_client_hello(data),
+        Some(b) if b.is_ascii_uppercase() => parse_http_host(data),
+        Some(_) => Sniffed::Unknown,
+    }
+}
+
+// The server_name extension of a TLS ClientHello, assuming it fits in the first record
+fn parse_client_hello(data: &[u8]) -> Sniffed {
+    match read_server_name(data) {
+        Ok(Some(host)) => Sniffed::Host(host),
+        Ok(None) | Err(ReadError::Invalid) => Sniffed::Unknown,
+        Err(ReadError::Short) => Sniffed::Incomplete,
+    }
+}
+
+// The Host header of an HTTP/1 request
+fn parse_http_host(data: &[u8]) -> Sniffed {
+    let head = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => &data[..end],
+        None => return Sniffed::Incomplete,
+    };
+    let head = match std::str::from_utf8(head) {
+        Ok(head) => head,
+        Err(_) => return Sniffed::Unknown,
+    };
+
+    let mut lines = head.split("\r\n");
+    if !lines.next().is_some_and(|line| line.contains(" HTTP/1.")) {
+        return Sniffed::Unknown;
+    }
+
+    lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .and_then(|(_, value)| Target::parse(value.trim(), Some(80)).ok())
+        .map_or(Sniffed::Unknown, |target| Sniffed::Host(target.host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_host, Sniffed};
+    use crate::proxy::sni::tests::client_hello;
+    use assert2::assert;
+
+    #[test]
+    fn test_parse_client_hello() {
+        let hello = client_hello("s3.us-east-1.amazonaws.com");
+        assert!(parse_host(&hello) == Sniffed::Host("s3.us-east-1.amazonaws.com".to_string()));
+        assert!(parse_host(&hello[..hello.len() - 4]) == Sniffed::Incomplete);
+
+        let mut not_hello = hello.clone();
+        not_hello[5] = 2;
+        assert!(parse_host(&not_hello) == Sniffed::Unknown);
+    }
+
+    #[test]
+    fn test_parse_http_host() {
+        let req = b"GET /bucket/key HTTP/1.1\r\nAccept: */*\r\nHOST: Example.COM:8080\r\n\r\n";
+        assert!(parse_host(req) == Sniffed::Host("example.com".to_string()));
+        assert!(parse_host(&req[..30]) == Sniffed::Incomplete);
+
+        let no_host = b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n";
+        assert!(parse_host(no_host) == Sniffed::Unknown);
+        assert!(parse_host(b"EHLO mail.example.com\r\n\r\n") == Sniffed::Unknown);
+        assert!(parse_host(b"\x00\x00\x00\x08") == Sniffed::Unknown);
+    }
+}
@@ -0,0 +1,293 @@
+//! UDP egress, for NTP, statsd and DNS to servers of the application's choosing.
+//! odyn binds a UDP socket inside the enclave for each egress.udp rule, and relays
+//! the datagrams of each local peer over a vsock connection of its own to the host.
+//! The host checks the remote of the rule against the egress policy, then sends the
+//! datagrams on from a socket connected to it, and relays the replies back.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use log::{debug, error, info, warn, Level};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_vsock::VsockStream;
+
+use crate::manifest::{Egress, EgressForward};
+use crate::policy::EgressPolicy;
+use crate::proxy::dns::{read_message, write_message};
+use crate::proxy::error::ProxyError;
+use crate::resolver::Resolver;
+use crate::utils;
+
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+// Datagrams of a peer waiting to be relayed, beyond which they are dropped
+const SESSION_QUEUE_LEN: usize = 64;
+
+// A peer that has sent nothing for this long is forgotten, along with its session
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct EnclaveUdpRelay {
+    socket: Arc<UdpSocket>,
+    target: Arc<String>,
+}
+
+impl EnclaveUdpRelay {
+    pub async fn bind(rule: &EgressForward) -> Result<Self, ProxyError> {
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, rule.local_port);
+        Ok(Self {
+            socket: Arc::new(UdpSocket::bind(addr).await?),
+            target: Arc::new(format!("{}:{}", rule.host, rule.port)),
+        })
+    }
+
+    pub async fn serve(self, udp_port: u32) {
+        let mut sessions: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+
+        loop {
+            let (len, peer) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    utils::log_limited!(Level::Error, "", "UDP receive failed: {err}");
+                    continue;
+                }
+            };
+
+            if sessions
+                .get(&peer)
+                .map_or(true, |session| session.is_closed())
+            {
+                sessions.retain(|_, session| !session.is_closed());
+                sessions.insert(peer, self.start_session(peer, udp_port));
+            }
+
+            if sessions[&peer].try_send(buf[..len].to_vec()).is_err() {
+                debug!("dropping datagram from {peer} to {}", self.target);
+            }
+        }
+    }
+
+    fn start_session(&self, peer: SocketAddr, udp_port: u32) -> mpsc::Sender<Vec<u8>> {
+        let (tx, rx) = mpsc::channel(SESSION_QUEUE_LEN);
+        let socket = self.socket.clone();
+        let target = self.target.clone();
+
+        tokio::task::spawn(async move {
+            if let Err(err) = relay_session(socket, peer, rx, udp_port, &target).await {
+                warn!("UDP relay to {target}: {err}");
+            }
+        });
+
+        tx
+    }
+}
+
+// Relays the datagrams of one peer to the host, and the replies back to the peer
+async fn relay_session(
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+    udp_port: u32,
+    target: &str,
+) -> Result<(), ProxyError> {
+    let mut vsock = VsockStream::connect(crate::vsock::VMADDR_CID_HOST, udp_port).await?;
+    write_message(&mut vsock, target.as_bytes()).await?;
+    let (mut reader, mut writer) = tokio::io::split(vsock);
+
+    let replies: JoinHandle<std::io::Result<()>> = tokio::task::spawn(async move {
+        loop {
+            let reply = read_message(&mut reader).await?;
+            socket.send_to(&reply, peer).await?;
+        }
+    });
+
+    while let Ok(Some(datagram)) = tokio::time::timeout(IDLE_TIMEOUT, datagrams.recv()).await {
+        if let Err(err) = write_message(&mut writer, &datagram).await {
+            replies.abort();
+            return Err(err.into());
+        }
+    }
+
+    replies.abort();
+    Ok(())
+}
+
+pub struct HostUdpRelay {
+    incoming: Box<dyn Stream<Item = VsockStream> + Unpin + Send>,
+    policy: Arc<EgressPolicy>,
+    targets: Arc<Vec<String>>,
+    resolver: Arc<Resolver>,
+}
+
+impl HostUdpRelay {
+    /// Relays datagrams to the remotes of the egress.udp rules, as long as the egress
+    /// policy allows them
+    pub fn bind(udp_port: u32, egress: &Egress) -> Result<Self, ProxyError> {
+        let targets = egress
+            .udp
+            .iter()
+            .flatten()
+            .map(|rule| format!("{}:{}", rule.host, rule.port))
+            .collect();
+
+        Ok(Self {
+            incoming: Box::new(crate::vsock::serve(udp_port)?),
+            policy: Arc::new(EgressPolicy::new(egress)),
+            targets: Arc::new(targets),
+            resolver: Arc::new(Resolver::system()),
+        })
+    }
+
+    pub fn with_resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    pub async fn serve(self) {
+        let mut incoming = Box::into_pin(self.incoming);
+
+        while let Some(stream) = incoming.next().await {
+            let policy = self.policy.clone();
+            let targets = self.targets.clone();
+            let resolver = self.resolver.clone();
+
+            tokio::task::spawn(async move {
+                if let Err(err) =
+                    HostUdpRelay::service_conn(stream, &policy, &targets, &resolver).await
+                {
+                    error!("{err}");
+                }
+            });
+        }
+    }
+
+    async fn service_conn(
+        mut vsock: VsockStream,
+        policy: &EgressPolicy,
+        targets: &[String],
+        resolver: &Resolver,
+    ) -> Result<(), ProxyError> {
+        let target = String::from_utf8(read_message(&mut vsock).await?)
+            .map_err(|_| ProxyError::InvalidTarget("UDP target is not UTF-8".to_string()))?;
+        let (host, port) = target
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| ProxyError::InvalidTarget(format!("invalid UDP target {target}")))?;
+
+        // Only the remotes of the manifest, in case the enclave asks for others
+        if !targets.contains(&target) || !policy.is_host_allowed(host, port) {
+            return Err(ProxyError::Denied(target));
+        }
+
+        // A special hostname "host" refers to the localhost on the outside
+        // of the enclave.
+        let host = match host.eq_ignore_ascii_case(crate::constants::OUTSIDE_HOST) {
+            true => "127.0.0.1",
+            false => host,
+        };
+
+        let socket = Arc::new(connect(resolver, host, port).await?);
+        info!("UDP egress to {target} allowed by the default policy");
+
+        let (mut reader, mut writer) = tokio::io::split(vsock);
+
+        let replies: JoinHandle<std::io::Result<()>> = {
+            let socket = socket.clone();
+            tokio::task::spawn(async move {
+                let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+                loop {
+                    let len = socket.recv(&mut buf).await?;
+                    write_message(&mut writer, &buf[..len]).await?;
+                }
+            })
+        };
+
+        // Until odyn forgets the peer and closes the connection
+        while let Ok(datagram) = read_message(&mut reader).await {
+            if let Err(err) = socket.send(&datagram).await {
+                debug!("UDP send to {target} failed: {err}");
+            }
+        }
+
+        replies.abort();
+        Ok(())
+    }
+}
+
+// A UDP socket connected to the first address of host
+async fn connect(resolver: &Resolver, host: &str, port: u16) -> std::io::Result<UdpSocket> {
+    let addr = resolver
+        .lookup(host, port)
+        .await
+        .ok()
+        .and_then(|addrs| addrs.into_iter().next())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no addresses found for {host}"),
+            )
+        })?;
+
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EnclaveUdpRelay, HostUdpRelay};
+    use crate::manifest::{Egress, EgressForward};
+    use assert2::assert;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn test_udp_relay() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::task::spawn(async move {
+            let mut buf = [0u8; 1500];
+            while let Ok((len, peer)) = echo.recv_from(&mut buf).await {
+                _ = echo.send_to(&buf[..len], peer).await;
+            }
+        });
+
+        let rule = EgressForward {
+            local_port: 5300,
+            host: "127.0.0.1".to_string(),
+            port: echo_port,
+            tls: None,
+        };
+        let egress = Egress {
+            allow: Some(vec!["127.0.0.1".to_string()]),
+            udp: Some(vec![rule.clone()]),
+            ..Default::default()
+        };
+
+        let host_relay = HostUdpRelay::bind(5300, &egress).unwrap();
+        tokio::task::spawn(host_relay.serve());
+        let enclave_relay = EnclaveUdpRelay::bind(&rule).await.unwrap();
+        tokio::task::spawn(enclave_relay.serve(5300));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect("127.0.0.1:5300").await.unwrap();
+        client.send(b"ping").await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(&buf[..len] == b"ping");
+    }
+}
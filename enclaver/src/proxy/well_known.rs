@@ -0,0 +1,224 @@
+//! Attestations served on the ingress ports of an app, so that clients can verify the
+//! enclave over the connection they already have instead of through the API port of
+//! odyn. Only the first request of each connection is looked at. Unless it is a GET
+//! of ATTESTATION_PATH, it is passed on to the app with the bytes read so far, as
+//! soon as they no longer match.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const ATTESTATION_PATH: &str = "/.well-known/enclaver/attestation";
+
+const MIME_APPLICATION_CBOR: &str = "application/cbor";
+const MIME_TEXT_PLAIN: &str = "text/plain";
+
+// The largest nonce the NSM takes
+const MAX_NONCE_SIZE: usize = 512;
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+// Clients that stop halfway through the request line are passed on to the app
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Produces the documents served on ATTESTATION_PATH, for the nonce the client sent
+pub trait AttestationSource: Send + Sync {
+    fn attestation(&self, nonce: Option<Vec<u8>>) -> Result<Vec<u8>>;
+}
+
+#[cfg(feature = "odyn")]
+impl<P: crate::nsm::AttestationProvider + Send + Sync> AttestationSource for P {
+    fn attestation(&self, nonce: Option<Vec<u8>>) -> Result<Vec<u8>> {
+        let params = crate::nsm::AttestationParams {
+            nonce,
+            user_data: None,
+            public_key: None,
+        };
+        Ok(crate::nsm::AttestationProvider::attestation(self, params)?)
+    }
+}
+
+/// Answers the first request of stream if it asks for an attestation. Returns the
+/// bytes read from stream otherwise, which the app has to be sent first, or None if
+/// the request was answered and the connection is done.
+pub async fn intercept<S>(stream: &mut S, source: &dyn AttestationSource) -> Option<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = Vec::new();
+    let head = match tokio::time::timeout(READ_TIMEOUT, read_head(stream, &mut buf)).await {
+        Ok(Ok(Some(head))) => head,
+        _ => return Some(buf),
+    };
+
+    let nonce = match requested_nonce(&buf[..head]) {
+        Some(nonce) => nonce,
+        None => return Some(buf),
+    };
+
+    let (status, content_type, body) = match nonce.and_then(|nonce| source.attestation(nonce)) {
+        Ok(doc) => ("200 OK", MIME_APPLICATION_CBOR, doc),
+        Err(err) => match err.downcast_ref::<NonceError>() {
+            Some(_) => (
+                "400 Bad Request",
+                MIME_TEXT_PLAIN,
+                err.to_string().into_bytes(),
+            ),
+            None => (
+                "500 Internal Server Error",
+                MIME_TEXT_PLAIN,
+                format!("failed to produce an attestation: {err}").into_bytes(),
+            ),
+        },
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    _ = stream.write_all(response.as_bytes()).await;
+    _ = stream.write_all(&body).await;
+    _ = stream.shutdown().await;
+
+    None
+}
+
+#[derive(Debug, Error)]
+#[error("{0}")]
+struct NonceError(String);
+
+// Reads until the end of the request head, and returns its length. Stops early, with
+// None, once what was read can no longer be a request for an attestation.
+async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+) -> Result<Option<usize>> {
+    let request_line = format!("GET {ATTESTATION_PATH}");
+    let mut chunk = [0u8; 1024];
+
+    loop {
+        let len = buf.len().min(request_line.len());
+        if buf[..len] != request_line.as_bytes()[..len] {
+            return Ok(None);
+        }
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok(Some(end + 4));
+        }
+        if buf.len() >= MAX_HEAD_SIZE {
+            return Ok(None);
+        }
+
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// The nonce asked for, if head is a request for an attestation, and an error if the
+// nonce is not valid. The nonce is URL-safe base64, in the nonce query parameter.
+fn requested_nonce(head: &[u8]) -> Option<Result<Option<Vec<u8>>>> {
+    let head = std::str::from_utf8(head).ok()?;
+    let request_line = head.split("\r\n").next()?;
+
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = (parts.next()?, parts.next()?, parts.next()?);
+    if method != "GET" || !version.starts_with("HTTP/1.") || parts.next().is_some() {
+        return None;
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != ATTESTATION_PATH {
+        return None;
+    }
+
+    let nonce = form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "nonce")
+        .map(|(_, nonce)| decode_nonce(&nonce))
+        .transpose();
+    Some(nonce)
+}
+
+fn decode_nonce(nonce: &str) -> Result<Vec<u8>> {
+    let nonce = base64::decode_config(nonce, base64::URL_SAFE)
+        .map_err(|e| NonceError(format!("nonce is not valid base64: {e}")))?;
+    if nonce.len() > MAX_NONCE_SIZE {
+        return Err(NonceError(format!("nonce exceeds {MAX_NONCE_SIZE} bytes")).into());
+    }
+    Ok(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{intercept, requested_nonce, AttestationSource};
+    use anyhow::Result;
+    use assert2::assert;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Returns the nonce as the document
+    struct EchoSource;
+
+    impl AttestationSource for EchoSource {
+        fn attestation(&self, nonce: Option<Vec<u8>>) -> Result<Vec<u8>> {
+            Ok(nonce.unwrap_or_default())
+        }
+    }
+
+    async fn exchange(request: &[u8]) -> (Option<Vec<u8>>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        client.write_all(request).await.unwrap();
+
+        let passed = intercept(&mut server, &EchoSource).await;
+        drop(server);
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        (passed, response)
+    }
+
+    #[test]
+    fn test_requested_nonce() {
+        let head = |line: &str| format!("{line}\r\nHost: example.com\r\n\r\n");
+        let nonce = |line: &str| requested_nonce(head(line).as_bytes());
+
+        let none = nonce("GET /.well-known/enclaver/attestation HTTP/1.1").unwrap();
+        assert!(none.unwrap().is_none());
+        let some = nonce("GET /.well-known/enclaver/attestation?nonce=AQL_ HTTP/1.1").unwrap();
+        assert!(some.unwrap() == Some(vec![1, 2, 0xff]));
+
+        assert!(
+            nonce("GET /.well-known/enclaver/attestation?nonce=%%% HTTP/1.1")
+                .unwrap()
+                .is_err()
+        );
+        assert!(nonce("GET /.well-known/enclaver/attestations HTTP/1.1").is_none());
+        assert!(nonce("POST /.well-known/enclaver/attestation HTTP/1.1").is_none());
+        assert!(nonce("GET /.well-known/enclaver/attestation HTTP/2.0").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_intercept() {
+        let request = b"GET /.well-known/enclaver/attestation?nonce=AQID HTTP/1.1\r\n\r\n";
+        let (passed, response) = exchange(request).await;
+        assert!(passed.is_none());
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(b"\r\n\r\n\x01\x02\x03"));
+
+        let request = b"GET /.well-known/enclaver/attestation?nonce=! HTTP/1.1\r\n\r\n";
+        let (passed, response) = exchange(request).await;
+        assert!(passed.is_none());
+        assert!(response.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+
+        // Passed on as soon as it differs, before the rest of the head arrives
+        let (passed, response) = exchange(b"GET /index.html").await;
+        assert!(passed.unwrap() == b"GET /index.html");
+        assert!(response.is_empty());
+
+        let request = b"GET /.well-known/enclaver/attestation/other HTTP/1.1\r\n\r\n";
+        let (passed, _) = exchange(request).await;
+        assert!(passed.unwrap() == request);
+    }
+}
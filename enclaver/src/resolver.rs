@@ -0,0 +1,695 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use http::{header, Method, Request, Uri};
+use hyper::client::{Client, HttpConnector};
+use hyper::Body;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+const DNS_PORT: u16 = 53;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_UDP_RESPONSE: usize = 4096;
+const MIME_DNS_MESSAGE: &str = "application/dns-message";
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+// How long a name stays pinned, whatever its TTL, and for how long when the
+// resolver does not tell
+const PIN_MIN_TTL: Duration = Duration::from_secs(30);
+const PIN_MAX_TTL: Duration = Duration::from_secs(3600);
+const PIN_DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+// How often pins are checked, and how long before they expire they are renewed
+const PIN_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+const PIN_REFRESH_AHEAD: Duration = Duration::from_secs(10);
+
+const FLAG_RD: u16 = 0x0100;
+const FLAG_TC: u16 = 0x0200;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// How the host resolves the names the enclave connects to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolverConfig {
+    /// DNS servers to query, in order, instead of the ones in /etc/resolv.conf
+    pub servers: Vec<SocketAddr>,
+
+    /// Domains appended to single label names, e.g. "db" becomes "db.internal"
+    pub search: Vec<String>,
+
+    /// DNS-over-HTTPS endpoint (RFC 8484), used instead of servers if set
+    pub doh: Option<Uri>,
+}
+
+/// Parses a DNS server address, with or without a port
+pub fn parse_server(s: &str) -> Result<SocketAddr> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+
+    let ip: IpAddr = s
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|_| anyhow!("invalid DNS server address {s}"))?;
+
+    Ok(SocketAddr::new(ip, DNS_PORT))
+}
+
+/// The addresses a name resolved to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolution {
+    pub addrs: Vec<IpAddr>,
+
+    /// The lowest TTL among the records, unknown for the system resolver
+    pub ttl: Option<Duration>,
+}
+
+impl Resolution {
+    pub fn socket_addrs(&self, port: u16) -> Vec<SocketAddr> {
+        self.addrs
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, port))
+            .collect()
+    }
+}
+
+enum Transport {
+    System,
+    Servers(Vec<SocketAddr>),
+    Doh {
+        endpoint: Uri,
+        client: Box<Client<HttpsConnector<HttpConnector>>>,
+    },
+}
+
+/// Resolves host names for the egress proxy on the host
+pub struct Resolver {
+    transport: Transport,
+    search: Vec<String>,
+}
+
+impl Resolver {
+    pub fn new(config: &ResolverConfig) -> Self {
+        let transport = match (&config.doh, config.servers.is_empty()) {
+            (Some(endpoint), _) => {
+                let connector = HttpsConnectorBuilder::new()
+                    .with_webpki_roots()
+                    .https_only()
+                    .enable_http1()
+                    .build();
+
+                Transport::Doh {
+                    endpoint: endpoint.clone(),
+                    client: Box::new(Client::builder().build(connector)),
+                }
+            }
+            (None, false) => Transport::Servers(config.servers.clone()),
+            (None, true) => Transport::System,
+        };
+
+        Self {
+            transport,
+            search: config.search.clone(),
+        }
+    }
+
+    pub fn system() -> Self {
+        Self::new(&ResolverConfig::default())
+    }
+
+    /// Resolves host to its addresses, IPv4 first
+    pub async fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok(self.resolve(host).await?.socket_addrs(port))
+    }
+
+    /// Resolves host to its addresses, IPv4 first, along with their TTL if the DNS
+    /// server told
+    pub async fn resolve(&self, host: &str) -> Result<Resolution> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Resolution {
+                addrs: vec![ip],
+                ttl: None,
+            });
+        }
+
+        if let Transport::System = self.transport {
+            if self.search.is_empty() {
+                let addrs = tokio::net::lookup_host((host, 0)).await?;
+                return Ok(Resolution {
+                    addrs: addrs.map(|addr| addr.ip()).collect(),
+                    ttl: None,
+                });
+            }
+        }
+
+        let mut last_err = None;
+        for name in self.candidates(host) {
+            match self.lookup_name(&name).await {
+                Ok(resolution) if !resolution.addrs.is_empty() => {
+                    debug!("resolved {host} as {name}: {:?}", resolution.addrs);
+                    return Ok(resolution);
+                }
+                Ok(_) => {}
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| anyhow!("no addresses found for {host}")))
+    }
+
+    // The fully qualified names to try for host, in order. Like the ndots:1
+    // default of resolv.conf, search domains go first only for single labels.
+    fn candidates(&self, host: &str) -> Vec<String> {
+        if let Some(fqdn) = host.strip_suffix('.') {
+            return vec![fqdn.to_string()];
+        }
+
+        let searched = self
+            .search
+            .iter()
+            .map(|domain| format!("{host}.{}", domain.trim_matches('.')));
+
+        if host.contains('.') {
+            std::iter::once(host.to_string()).chain(searched).collect()
+        } else {
+            searched.chain(std::iter::once(host.to_string())).collect()
+        }
+    }
+
+    async fn lookup_name(&self, name: &str) -> Result<Resolution> {
+        let mut resolution = Resolution {
+            addrs: Vec::new(),
+            ttl: None,
+        };
+        for qtype in [TYPE_A, TYPE_AAAA] {
+            let (addrs, ttl) = self.query(name, qtype).await?;
+            resolution.addrs.extend(addrs);
+            resolution.ttl = match (resolution.ttl, ttl) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+
+        Ok(resolution)
+    }
+
+    // The addresses in the answer, and the lowest TTL among them
+    async fn query(&self, name: &str, qtype: u16) -> Result<(Vec<IpAddr>, Option<Duration>)> {
+        match self.transport {
+            Transport::System => {
+                let addrs = tokio::net::lookup_host((name, 0)).await?;
+                let addrs = addrs
+                    .map(|addr| addr.ip())
+                    .filter(|ip| ip.is_ipv4() == (qtype == TYPE_A))
+                    .collect();
+                Ok((addrs, None))
+            }
+
+            Transport::Servers(ref servers) => {
+                let mut last_err = None;
+                for server in servers {
+                    let id = rand::random();
+                    let query = encode_query(id, name, qtype)?;
+                    let resp = tokio::time::timeout(QUERY_TIMEOUT, query_server(*server, &query))
+                        .await
+                        .unwrap_or_else(|_| Err(anyhow!("DNS server {server} timed out")));
+
+                    match resp.and_then(|resp| decode_response(id, &resp)) {
+                        Ok(answer) => return Ok(answer),
+                        Err(err) => {
+                            debug!("DNS server {server} failed to resolve {name}: {err}");
+                            last_err = Some(err);
+                        }
+                    }
+                }
+
+                Err(last_err.unwrap_or_else(|| anyhow!("no DNS servers configured")))
+            }
+
+            Transport::Doh {
+                ref endpoint,
+                ref client,
+            } => {
+                // RFC 8484 asks for an ID of 0, to keep responses cacheable
+                let query = encode_query(0, name, qtype)?;
+                decode_response(0, &query_doh(endpoint, client, query).await?)
+            }
+        }
+    }
+
+    /// Sends a DNS query from the enclave on as is, returning the raw response.
+    /// Search domains are left to the resolver that made the query.
+    pub async fn forward(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let servers = match self.transport {
+            Transport::Doh {
+                ref endpoint,
+                ref client,
+            } => return query_doh(endpoint, client, query.to_vec()).await,
+            Transport::Servers(ref servers) => servers.clone(),
+            Transport::System => parse_resolv_conf(&std::fs::read_to_string(RESOLV_CONF)?),
+        };
+
+        let mut last_err = None;
+        for server in servers {
+            match tokio::time::timeout(QUERY_TIMEOUT, query_server(server, query)).await {
+                Ok(Ok(resp)) => return Ok(resp),
+                Ok(Err(err)) => last_err = Some(err),
+                Err(_) => last_err = Some(anyhow!("DNS server {server} timed out")),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| anyhow!("no DNS servers configured")))
+    }
+}
+
+/// Pins each name to the addresses it resolved to, so that connections only go to
+/// those until the pin expires, and a DNS server that answers differently from one
+/// lookup to the next cannot point an allowed name somewhere else in between. Pins
+/// last at least PIN_MIN_TTL, however low the TTL, and names in use are resolved
+/// again in the background before their pins expire.
+#[derive(Clone, Default)]
+pub struct DnsPins {
+    pins: Arc<Mutex<HashMap<String, Pin>>>,
+}
+
+struct Pin {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+
+    // Whether a connection used the pin since it was last resolved
+    used: bool,
+
+    // Whether the pin is renewed even when unused, for the names the policy allows
+    keep: bool,
+}
+
+impl DnsPins {
+    /// The addresses of host, from its pin, or resolved and pinned if it has none
+    /// that is still valid
+    pub async fn lookup(
+        &self,
+        resolver: &Resolver,
+        host: &str,
+        port: u16,
+    ) -> Result<Vec<SocketAddr>> {
+        if host.parse::<IpAddr>().is_ok() {
+            return resolver.lookup(host, port).await;
+        }
+
+        let name = host.to_ascii_lowercase();
+        if let Some(addrs) = self.get(&name, Instant::now()) {
+            return Ok(addrs
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect());
+        }
+
+        let resolution = resolver.resolve(host).await?;
+        let addrs = resolution.socket_addrs(port);
+        self.pin(name, resolution, Instant::now(), true);
+
+        Ok(addrs)
+    }
+
+    /// Pins names before the first connection to them, e.g. the names the egress
+    /// policy allows, and keeps them pinned for as long as the pins are refreshed.
+    /// Names that fail to resolve are left for the first lookup.
+    pub async fn prefetch(&self, resolver: &Resolver, names: impl IntoIterator<Item = String>) {
+        for name in names {
+            match resolver.resolve(&name).await {
+                Ok(resolution) => {
+                    info!("pinned {name} to {:?}", resolution.addrs);
+                    let name = name.to_ascii_lowercase();
+                    self.pin(name.clone(), resolution, Instant::now(), false);
+                    if let Some(pin) = self.pins.lock().unwrap().get_mut(&name) {
+                        pin.keep = true;
+                    }
+                }
+                Err(err) => warn!("failed to resolve {name} ahead of time: {err}"),
+            }
+        }
+    }
+
+    /// Resolves the pins about to expire again, as long as they were used since they
+    /// were last resolved or were prefetched, and drops the others once they expire.
+    /// Runs until aborted.
+    pub async fn refresh(self, resolver: Arc<Resolver>) {
+        loop {
+            tokio::time::sleep(PIN_REFRESH_INTERVAL).await;
+
+            for name in self.due(Instant::now()) {
+                match resolver.resolve(&name).await {
+                    Ok(resolution) => {
+                        let changed = self.pin(name.clone(), resolution, Instant::now(), false);
+                        if let Some(addrs) = changed {
+                            info!("pin of {name} changed to {addrs:?}");
+                        }
+                    }
+                    // The old pin stays until it expires
+                    Err(err) => warn!("failed to resolve {name} again: {err}"),
+                }
+            }
+        }
+    }
+
+    fn get(&self, name: &str, now: Instant) -> Option<Vec<IpAddr>> {
+        let mut pins = self.pins.lock().unwrap();
+        let pin = pins.get_mut(name).filter(|pin| pin.expires > now)?;
+        pin.used = true;
+        Some(pin.addrs.clone())
+    }
+
+    // Returns the new addresses if they differ from those pinned before
+    fn pin(
+        &self,
+        name: String,
+        resolution: Resolution,
+        now: Instant,
+        used: bool,
+    ) -> Option<Vec<IpAddr>> {
+        let ttl = resolution
+            .ttl
+            .unwrap_or(PIN_DEFAULT_TTL)
+            .clamp(PIN_MIN_TTL, PIN_MAX_TTL);
+
+        let mut pins = self.pins.lock().unwrap();
+        let old = pins.remove(&name);
+        let changed = match old {
+            Some(ref old) if old.addrs != resolution.addrs => Some(resolution.addrs.clone()),
+            _ => None,
+        };
+
+        pins.insert(
+            name,
+            Pin {
+                addrs: resolution.addrs,
+                expires: now + ttl,
+                used,
+                keep: old.is_some_and(|old| old.keep),
+            },
+        );
+
+        changed
+    }
+
+    // The names whose pins are about to expire and worth renewing, dropping the
+    // expired ones that are not
+    fn due(&self, now: Instant) -> Vec<String> {
+        let mut pins = self.pins.lock().unwrap();
+        pins.retain(|_, pin| pin.used || pin.keep || pin.expires > now);
+
+        pins.iter_mut()
+            .filter(|(_, pin)| (pin.used || pin.keep) && pin.expires <= now + PIN_REFRESH_AHEAD)
+            .map(|(name, pin)| {
+                pin.used = false;
+                name.clone()
+            })
+            .collect()
+    }
+}
+
+// The nameservers listed in resolv.conf
+fn parse_resolv_conf(conf: &str) -> Vec<SocketAddr> {
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|server| parse_server(server.trim()).ok())
+        .collect()
+}
+
+async fn query_doh(
+    endpoint: &Uri,
+    client: &Client<HttpsConnector<HttpConnector>>,
+    query: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(endpoint.clone())
+        .header(header::CONTENT_TYPE, MIME_DNS_MESSAGE)
+        .header(header::ACCEPT, MIME_DNS_MESSAGE)
+        .body(Body::from(query))?;
+
+    let resp = tokio::time::timeout(QUERY_TIMEOUT, client.request(req))
+        .await
+        .map_err(|_| anyhow!("DoH request to {endpoint} timed out"))??;
+
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "DoH endpoint {endpoint} returned {}",
+            resp.status()
+        ));
+    }
+
+    Ok(hyper::body::to_bytes(resp.into_body()).await?.to_vec())
+}
+
+// Sends the query over UDP, and again over TCP if the answer was truncated
+async fn query_server(server: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let bind_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+
+    let mut buf = vec![0u8; MAX_UDP_RESPONSE];
+    let len = socket.recv(&mut buf).await?;
+    buf.truncate(len);
+
+    if buf.len() >= 4 && u16::from_be_bytes([buf[2], buf[3]]) & FLAG_TC == 0 {
+        return Ok(buf);
+    }
+
+    let mut tcp = TcpStream::connect(server).await?;
+    tcp.write_all(&(query.len() as u16).to_be_bytes()).await?;
+    tcp.write_all(query).await?;
+
+    let len = tcp.read_u16().await?;
+    let mut buf = vec![0u8; len as usize];
+    tcp.read_exact(&mut buf).await?;
+
+    Ok(buf)
+}
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut msg = Vec::with_capacity(18 + name.len());
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&FLAG_RD.to_be_bytes());
+    // One question, no answer, authority or additional records
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow!("invalid DNS name {name}"));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(msg)
+}
+
+// Returns the A and AAAA records in the answer section of a response, and the lowest
+// TTL among them. NXDOMAIN is not an error, just an empty answer.
+fn decode_response(id: u16, msg: &[u8]) -> Result<(Vec<IpAddr>, Option<Duration>)> {
+    let truncated = || anyhow!("truncated DNS response");
+
+    let header = msg.get(..12).ok_or_else(truncated)?;
+    let field = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+
+    if field(0) != id {
+        return Err(anyhow!("DNS response ID does not match the query"));
+    }
+
+    match field(2) & RCODE_MASK {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok((Vec::new(), None)),
+        rcode => return Err(anyhow!("DNS server returned rcode {rcode}")),
+    }
+
+    let mut pos = 12;
+    for _ in 0..field(4) {
+        pos = skip_name(msg, pos).ok_or_else(truncated)? + 4;
+    }
+
+    let mut addrs = Vec::new();
+    let mut min_ttl: Option<u32> = None;
+    for _ in 0..field(6) {
+        pos = skip_name(msg, pos).ok_or_else(truncated)?;
+        let rr = msg.get(pos..pos + 10).ok_or_else(truncated)?;
+        let rtype = u16::from_be_bytes([rr[0], rr[1]]);
+        let ttl = u32::from_be_bytes([rr[4], rr[5], rr[6], rr[7]]);
+        let rdlen = u16::from_be_bytes([rr[8], rr[9]]) as usize;
+        pos += 10;
+
+        let rdata = msg.get(pos..pos + rdlen).ok_or_else(truncated)?;
+        pos += rdlen;
+
+        let addr = match (rtype, rdata.len()) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = rdata.try_into().unwrap();
+                IpAddr::from(octets)
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = rdata.try_into().unwrap();
+                IpAddr::from(octets)
+            }
+            // CNAMEs come with the records of their target, anything else is ignored
+            _ => continue,
+        };
+        addrs.push(addr);
+        min_ttl = Some(min_ttl.map_or(ttl, |min| min.min(ttl)));
+    }
+
+    Ok((addrs, min_ttl.map(|ttl| Duration::from_secs(ttl.into()))))
+}
+
+// Returns the position just past the (possibly compressed) name starting at pos
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_response, encode_query, parse_resolv_conf, parse_server, DnsPins, Resolution,
+        Resolver, ResolverConfig, PIN_MIN_TTL, PIN_REFRESH_AHEAD, TYPE_A,
+    };
+    use assert2::assert;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_candidates() {
+        let resolver = Resolver::new(&ResolverConfig {
+            search: vec!["internal".to_string(), "corp.example.com.".to_string()],
+            ..Default::default()
+        });
+
+        assert!(resolver.candidates("db") == ["db.internal", "db.corp.example.com", "db"]);
+        assert!(
+            resolver.candidates("api.example.com")
+                == [
+                    "api.example.com",
+                    "api.example.com.internal",
+                    "api.example.com.corp.example.com"
+                ]
+        );
+        assert!(resolver.candidates("db.") == ["db"]);
+    }
+
+    #[test]
+    fn test_parse_server() {
+        assert!(parse_server("10.0.0.2").unwrap() == "10.0.0.2:53".parse().unwrap());
+        assert!(parse_server("10.0.0.2:5353").unwrap() == "10.0.0.2:5353".parse().unwrap());
+        assert!(parse_server("[fd00::2]").unwrap() == "[fd00::2]:53".parse().unwrap());
+        assert!(parse_server("dns.example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_resolv_conf() {
+        let conf = "# generated\nnameserver 10.0.0.2\nsearch internal\n  nameserver fd00::2\n";
+        assert!(
+            parse_resolv_conf(conf)
+                == [
+                    "10.0.0.2:53".parse().unwrap(),
+                    "[fd00::2]:53".parse().unwrap()
+                ]
+        );
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let query = encode_query(0x1234, "db.internal", TYPE_A).unwrap();
+
+        // The query echoed back as a response, with a CNAME and an A record
+        let mut resp = query.clone();
+        resp[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+        resp[6..8].copy_from_slice(&2u16.to_be_bytes());
+        resp.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6]);
+        resp.extend_from_slice(&[3, b'p', b'g', b'1', 0xc0, 15]);
+        resp.extend_from_slice(&[0xc0, 41, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 5]);
+
+        let (addrs, ttl) = decode_response(0x1234, &resp).unwrap();
+        assert!(addrs == [IpAddr::from([10, 0, 0, 5])]);
+        assert!(ttl == Some(Duration::from_secs(60)));
+
+        assert!(decode_response(0x4321, &resp).is_err());
+        assert!(decode_response(0x1234, &resp[..resp.len() - 2]).is_err());
+
+        // NXDOMAIN
+        resp[3] = 0x83;
+        assert!(decode_response(0x1234, &resp).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn test_dns_pins() {
+        let pins = DnsPins::default();
+        let now = Instant::now();
+        let resolution = |ip: [u8; 4], ttl: u64| Resolution {
+            addrs: vec![IpAddr::from(ip)],
+            ttl: Some(Duration::from_secs(ttl)),
+        };
+
+        // A TTL below the minimum still pins the name for PIN_MIN_TTL
+        pins.pin(
+            "api.example.com".to_string(),
+            resolution([10, 0, 0, 1], 5),
+            now,
+            false,
+        );
+        pins.pin(
+            "db.example.com".to_string(),
+            resolution([10, 0, 0, 2], 300),
+            now,
+            false,
+        );
+        assert!(pins.get("api.example.com", now + PIN_MIN_TTL).is_none());
+        assert!(pins.get("api.example.com", now).unwrap() == [IpAddr::from([10, 0, 0, 1])]);
+
+        // Only the used pin close to expiring is due, and the expired unused one is dropped
+        let later = now + PIN_MIN_TTL - PIN_REFRESH_AHEAD;
+        assert!(pins.due(later) == ["api.example.com"]);
+        assert!(pins.due(later).is_empty());
+        assert!(pins.due(now + PIN_MIN_TTL).is_empty());
+        assert!(pins.get("api.example.com", now).is_none());
+        assert!(pins.get("db.example.com", now + PIN_MIN_TTL).is_some());
+
+        // Pinning again reports whether the addresses changed
+        let db = "db.example.com".to_string();
+        assert!(pins
+            .pin(db.clone(), resolution([10, 0, 0, 2], 300), now, false)
+            .is_none());
+        assert!(pins
+            .pin(db.clone(), resolution([10, 0, 0, 3], 300), now, false)
+            .is_some());
+
+        // Prefetched names stay pinned, used or not, also once pinned again
+        pins.pins.lock().unwrap().get_mut(&db).unwrap().keep = true;
+        pins.pin(db, resolution([10, 0, 0, 3], 300), now, false);
+        assert!(pins.due(now + Duration::from_secs(300)) == ["db.example.com"]);
+        assert!(pins.due(now + Duration::from_secs(600)) == ["db.example.com"]);
+    }
+}
@@ -1,37 +1,353 @@
+use crate::attestation::AttestationVerifier;
+use crate::banner::StartupBanner;
+use crate::boot_config::{self, BootConfig, DebugOverrides, RuntimeConfigDocument};
+use crate::compression::{self, Compression, Decoded};
+use crate::config_provider::{AttestedPayload, ConfigProvider};
 use crate::constants::{
-    APP_LOG_PORT, EIF_FILE_NAME, HTTP_EGRESS_VSOCK_PORT, MANIFEST_FILE_NAME, RELEASE_BUNDLE_DIR,
-    STATUS_PORT,
+    APP_LOG_PORT, BOOT_CONFIG_PORT, CONFIG_PROVIDER_PORT, DEFAULT_CPU_COUNT, DEFAULT_MEMORY_MB,
+    DNS_VSOCK_PORT, EGRESS_AUDIT_PORT, MANIFEST_FILE_NAME, RELEASE_BUNDLE_DIR, STATUS_PORT,
+    UDP_EGRESS_VSOCK_PORT,
 };
-use crate::manifest::{load_manifest, Defaults, Manifest};
-use crate::utils;
+use crate::cpu_topology::{self, ProxyPinning};
+use crate::eif_chunks;
+use crate::events::{EnclaveEvent, EventContext, EventNotifier, EventOutput};
+use crate::http_util::{self, HttpHandler, HttpServer};
+use crate::identity::IdentityRecord;
+use crate::journal::{self, StatusJournal};
+use crate::manifest::{load_manifest, Defaults, EgressService, ExitCodes, Ingress, Manifest};
+use crate::metrics::{
+    ConnectionMetrics, Counter, Gauge, MetricsHandler, Registry, StreamMetrics, LATENCY_BUCKETS,
+};
+use crate::utils::{self, LogRendering};
 use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use futures_util::stream::StreamExt;
-use log::{debug, error, info};
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use http::{Method, Request, Response, Uri};
+use hyper::{header, Body, StatusCode};
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::fs::File;
+use tokio::process::{Child, Command};
+use tokio::sync::watch;
 use tokio_util::codec::{FramedRead, LinesCodec};
 use tokio_util::sync::CancellationToken;
 use tokio_vsock::VsockStream;
 
-use crate::nitro_cli::{EnclaveInfo, NitroCLI, RunEnclaveArgs};
+use crate::netns;
+use crate::nitro_cli::{EnclaveInfo, InsufficientCapacity, NitroCLI, NitroCLIArgs, RunEnclaveArgs};
+use crate::policy::limits::LimitStats;
+use crate::preflight::{self, Check};
+use crate::protocol::EnclaveStatus;
+use crate::proxy::audit::{self, DenialStats};
+use crate::proxy::budget::{BudgetConfig, ConnectionBudget};
+use crate::proxy::dns::HostDnsProxy;
+use crate::proxy::drain::{self, Drain};
 use crate::proxy::egress_http::HostHttpProxy;
 use crate::proxy::ingress::HostProxy;
+use crate::proxy::keepalive::Keepalive;
+use crate::proxy::relay::Buffering;
+use crate::proxy::udp::HostUdpRelay;
+use crate::resolver::{Resolver, ResolverConfig};
+use crate::sandbox;
+use crate::status::{FatalCode, LogStats, MemoryStats};
 
 const LOG_VSOCK_RETRY_INTERVAL: Duration = Duration::from_millis(250);
 const STATUS_VSOCK_RETRY_INTERVAL: Duration = Duration::from_millis(250);
 const STATUS_VSOCK_RETRY_LIMIT: i32 = 100;
+const CAPACITY_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// Running statuses carry the counters of every egress limit
+const STATUS_LINE_MAX_LEN: usize = 64 * 1024;
+
+const ENCLAVE_SIGNALED_EXIT_CODE: u8 = 107;
+const ENCLAVE_FATAL_EXIT_CODE: u8 = 108;
+const ENCLAVER_INTERRUPTED_EXIT_CODE: u8 = 109;
 
-const DEFAULT_CPU_COUNT: i32 = 2;
-const DEFAULT_MEMORY_MB: i32 = 4096;
+/// Hidden enclaver-run subcommand that terminates an enclave once its stdin closes
+pub const TERMINATE_HELPER_COMMAND: &str = "terminate-helper";
 
 pub struct EnclaveOpts {
     pub eif_path: Option<PathBuf>,
     pub manifest_path: Option<PathBuf>,
     pub cpu_count: Option<i32>,
     pub memory_mb: Option<i32>,
+    pub ingress_budget: BudgetConfig,
     pub debug_mode: bool,
+    pub metrics_addr: Option<SocketAddr>,
+    pub webhooks: Vec<Uri>,
+    pub event_output: Option<EventOutput>,
+    pub debug_overrides: DebugOverrides,
+    pub runtime_config: Option<RuntimeConfigDocument>,
+    pub attested_config: Option<AttestedConfigOpts>,
+    pub resolver: ResolverConfig,
+    pub egress_netns: Option<PathBuf>,
+    pub egress_relay_netns: Vec<PathBuf>,
+    pub run_as: Option<String>,
+    pub state_dir: Option<PathBuf>,
+    pub egress_audit_log: Option<PathBuf>,
+    pub passthrough_exit_code: bool,
+
+    // How long to keep retrying while other enclaves hold the CPUs or memory needed
+    pub wait_for_capacity: Option<Duration>,
+
+    // Host address all ingress ports listen on, over the bind_addr of each in the
+    // manifest
+    pub ingress_bind_addr: Option<IpAddr>,
+
+    // How the logs of the enclave and its debug console are rendered on the host
+    pub log_rendering: LogRendering,
+
+    // Asked of the enclave for its app log and egress audit log streams
+    pub log_compression: Compression,
+
+    // How long to wait for the open ingress connections to close once cancelled,
+    // over the drain_seconds of the manifest
+    pub drain_seconds: Option<u64>,
+
+    // Which CPUs the threads of the supervisor are pinned to once the enclave has
+    // started, if any
+    pub proxy_pinning: Option<ProxyPinning>,
+}
+
+// A config blob and secret files to release only to an enclave that attests to
+// the expected PCRs
+pub struct AttestedConfigOpts {
+    pub config: Option<Vec<u8>>,
+    pub secret_files: BTreeMap<String, Vec<u8>>,
+    pub root_cert_pem: Vec<u8>,
+}
+
+// Dropped and throttled counters of an egress limit
+type LimitCounters = (Arc<Counter>, Arc<Counter>);
+
+// Host level metrics, independent of anything running inside the enclave
+struct HostMetrics {
+    registry: Arc<Registry>,
+    enclave_up: Arc<Gauge>,
+    enclave_starts: Arc<Counter>,
+    cpu_count_configured: Arc<Gauge>,
+    memory_mb_configured: Arc<Gauge>,
+    cpu_count_allocated: Arc<Gauge>,
+    memory_mb_allocated: Arc<Gauge>,
+    odyn_heap_bytes: Arc<Gauge>,
+    odyn_heap_peak_bytes: Arc<Gauge>,
+    odyn_buffer_bytes: Arc<Gauge>,
+    banner_mismatches: Arc<Gauge>,
+
+    // Dropped and throttled counters of each egress limit, by policy and rule
+    egress_limits: Mutex<HashMap<(Option<String>, String), LimitCounters>>,
+
+    // Denied connections and requests of each egress proxy, by policy
+    egress_denials: Mutex<HashMap<Option<String>, Arc<Counter>>>,
+
+    // Bytes of the app log produced, trimmed, delivered to and skipped by the
+    // subscribers of the log port inside the enclave
+    app_log_produced_bytes: Arc<Counter>,
+    app_log_trimmed_bytes: Arc<Counter>,
+    app_log_delivered_bytes: Arc<Counter>,
+    app_log_skipped_bytes: Arc<Counter>,
+}
+
+impl HostMetrics {
+    fn new() -> Self {
+        let registry = Arc::new(Registry::new());
+
+        Self {
+            enclave_up: registry.gauge(
+                "enclaver_enclave_up",
+                "Whether the enclave is running",
+                &[],
+            ),
+            enclave_starts: registry.counter(
+                "enclaver_enclave_starts_total",
+                "Number of times the enclave has been started",
+                &[],
+            ),
+            cpu_count_configured: registry.gauge(
+                "enclaver_enclave_cpu_count_configured",
+                "Number of CPUs requested for the enclave",
+                &[],
+            ),
+            memory_mb_configured: registry.gauge(
+                "enclaver_enclave_memory_mb_configured",
+                "Memory in MiB requested for the enclave",
+                &[],
+            ),
+            cpu_count_allocated: registry.gauge(
+                "enclaver_enclave_cpu_count_allocated",
+                "Number of CPUs allocated to the running enclave",
+                &[],
+            ),
+            memory_mb_allocated: registry.gauge(
+                "enclaver_enclave_memory_mb_allocated",
+                "Memory in MiB allocated to the running enclave",
+                &[],
+            ),
+            odyn_heap_bytes: registry.gauge(
+                "enclaver_odyn_heap_bytes",
+                "Bytes odyn has allocated inside the enclave, as last reported",
+                &[],
+            ),
+            odyn_heap_peak_bytes: registry.gauge(
+                "enclaver_odyn_heap_peak_bytes",
+                "Most bytes odyn has had allocated inside the enclave at once",
+                &[],
+            ),
+            odyn_buffer_bytes: registry.gauge(
+                "enclaver_odyn_buffer_bytes",
+                "Bytes odyn holds in the stream buffers of its ingress proxies",
+                &[],
+            ),
+            banner_mismatches: registry.gauge(
+                "enclaver_enclave_banner_mismatches",
+                "Number of ways the startup banner of the enclave differs from the manifest and measurements",
+                &[],
+            ),
+            app_log_produced_bytes: registry.counter(
+                "enclaver_app_log_produced_bytes_total",
+                "Bytes the application has written to its log inside the enclave",
+                &[],
+            ),
+            app_log_trimmed_bytes: registry.counter(
+                "enclaver_app_log_trimmed_bytes_total",
+                "Bytes trimmed from the app log inside the enclave to make room for newer ones",
+                &[],
+            ),
+            app_log_delivered_bytes: registry.counter(
+                "enclaver_app_log_delivered_bytes_total",
+                "Bytes of the app log sent to the subscribers of the log port",
+                &[],
+            ),
+            app_log_skipped_bytes: registry.counter(
+                "enclaver_app_log_skipped_bytes_total",
+                "Bytes of the app log trimmed before a subscriber of the log port got to them",
+                &[],
+            ),
+            egress_limits: Mutex::new(HashMap::new()),
+            egress_denials: Mutex::new(HashMap::new()),
+            registry,
+        }
+    }
+
+    fn odyn_memory(&self, stats: &MemoryStats) {
+        self.odyn_heap_bytes.set(stats.heap_bytes as i64);
+        self.odyn_heap_peak_bytes.set(stats.heap_peak_bytes as i64);
+        self.odyn_buffer_bytes.set(stats.buffer_bytes as i64);
+    }
+
+    fn app_log(&self, stats: &LogStats) {
+        self.app_log_produced_bytes.set(stats.produced_bytes);
+        self.app_log_trimmed_bytes.set(stats.trimmed_bytes);
+        self.app_log_delivered_bytes.set(stats.delivered_bytes);
+        self.app_log_skipped_bytes.set(stats.skipped_bytes);
+    }
+
+    fn egress_limits(&self, limits: &[LimitStats]) {
+        let mut counters = self.egress_limits.lock().unwrap();
+        for limit in limits {
+            let (dropped, throttled) = counters
+                .entry((limit.policy.clone(), limit.rule.clone()))
+                .or_insert_with(|| {
+                    let policy = limit.policy.as_deref().unwrap_or_default();
+                    let labels = [("policy", policy), ("rule", limit.rule.as_str())];
+                    (
+                        self.registry.counter(
+                            "enclaver_egress_limit_dropped_total",
+                            "Connections and requests refused by the rate limit of an egress limit",
+                            &labels,
+                        ),
+                        self.registry.counter(
+                            "enclaver_egress_limit_throttled_total",
+                            "Times traffic was held back by the bandwidth of an egress limit",
+                            &labels,
+                        ),
+                    )
+                });
+            dropped.set(limit.dropped);
+            throttled.set(limit.throttled);
+        }
+    }
+
+    fn egress_denials(&self, denials: &[DenialStats]) {
+        let mut counters = self.egress_denials.lock().unwrap();
+        for denial in denials {
+            counters
+                .entry(denial.policy.clone())
+                .or_insert_with(|| {
+                    let policy = denial.policy.as_deref().unwrap_or_default();
+                    self.registry.counter(
+                        "enclaver_egress_denied_total",
+                        "Connections and requests denied by the egress proxies inside the enclave",
+                        &[("policy", policy)],
+                    )
+                })
+                .set(denial.denied);
+        }
+    }
+}
+
+// The host API served next to the metrics: /v1/identity tells service discovery
+// which image the enclave runs, and whether it has attested to it yet, /v1/status
+// tells orchestrators the last status odyn reported and the exit code it maps to
+struct HostApiHandler {
+    metrics: MetricsHandler,
+    identity: Option<IdentityRecord>,
+    status: Arc<Mutex<Option<EnclaveStatus>>>,
+    exit_codes: ExitCodeMapping,
+}
+
+impl HostApiHandler {
+    fn status(&self) -> Result<Response<Body>> {
+        let status = self.status.lock().unwrap();
+        let status = match *status {
+            Some(ref status) => status,
+            None => return Ok(http_util::not_found()),
+        };
+
+        let mut body = serde_json::to_value(status)?;
+        // Numbered here, where odyn names it on the status port
+        if let EnclaveStatus::Signaled { signal } = status {
+            body["signal"] = (*signal).into();
+        }
+        if let Some(exit_status) = EnclaveExitStatus::from_status(status) {
+            body["exit_code"] = self.exit_codes.exit_code(&exit_status).into();
+        }
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&body)?))?)
+    }
+}
+
+#[async_trait]
+impl HttpHandler for HostApiHandler {
+    async fn handle(&self, req: Request<Body>) -> Result<Response<Body>> {
+        if req.uri().path() == "/v1/status" {
+            return match *req.method() {
+                Method::GET => self.status(),
+                _ => Ok(http_util::method_not_allowed()),
+            };
+        }
+
+        if req.uri().path() != "/v1/identity" {
+            return self.metrics.handle(req).await;
+        }
+
+        match (req.method(), &self.identity) {
+            (&Method::GET, Some(identity)) => Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_vec(&identity.current())?))?),
+            (&Method::GET, None) => Ok(http_util::not_found()),
+            _ => Ok(http_util::method_not_allowed()),
+        }
+    }
 }
 
 pub struct Enclave {
@@ -40,7 +356,32 @@ pub struct Enclave {
     manifest: Manifest,
     cpu_count: i32,
     memory_mb: i32,
+    ingress_budget: BudgetConfig,
+    ingress_bind_addr: Option<IpAddr>,
+    drain: Drain,
+    drain_timeout: Option<Duration>,
+    proxy_pinning: Option<ProxyPinning>,
     debug_mode: bool,
+    metrics_addr: Option<SocketAddr>,
+    metrics: HostMetrics,
+    events: EventNotifier,
+    boot_config: BootConfig,
+    config_provider: Option<ConfigProvider>,
+    enclave_cid: watch::Sender<Option<u32>>,
+    resolver: ResolverConfig,
+    egress_netns: Option<PathBuf>,
+    egress_relay_netns: Vec<PathBuf>,
+    run_as: Option<String>,
+    state_dir: Option<PathBuf>,
+    egress_audit_log: Option<PathBuf>,
+    exit_codes: ExitCodeMapping,
+    wait_for_capacity: Option<Duration>,
+    log_rendering: LogRendering,
+    log_compression: Compression,
+    status: Arc<Mutex<Option<EnclaveStatus>>>,
+    identity: Option<IdentityRecord>,
+    previous_starts: u64,
+    terminator: Option<Child>,
     enclave_info: Option<EnclaveInfo>,
     tasks: Vec<tokio::task::JoinHandle<()>>,
 }
@@ -49,7 +390,7 @@ impl Enclave {
     pub async fn new(opts: EnclaveOpts) -> Result<Self> {
         let eif_path = match opts.eif_path {
             Some(eif_path) => eif_path,
-            None => PathBuf::from(RELEASE_BUNDLE_DIR).join(EIF_FILE_NAME),
+            None => eif_chunks::release_eif(Path::new(RELEASE_BUNDLE_DIR)).await?,
         };
 
         // Test that the EIF exists
@@ -62,7 +403,51 @@ impl Enclave {
             None => PathBuf::from(RELEASE_BUNDLE_DIR).join(MANIFEST_FILE_NAME),
         };
 
-        let manifest = load_manifest(&manifest_path).await?;
+        let mut manifest = load_manifest(&manifest_path).await?;
+
+        // Debug and production are separate builds, with different measurements
+        match (opts.debug_mode, manifest.is_debug()) {
+            (true, false) => {
+                return Err(anyhow!(
+                    "this is a production image, rebuild it with `debug: true` in the manifest to run it in debug mode"
+                ))
+            }
+            (false, true) => {
+                return Err(anyhow!(
+                    "this is a debug image, it can only be run with --debug-mode"
+                ))
+            }
+            _ => {}
+        }
+
+        let mut boot_config = BootConfig::default();
+        if !opts.debug_overrides.is_empty() {
+            if !opts.debug_mode {
+                return Err(anyhow!("manifest overrides are only allowed in debug mode"));
+            }
+
+            warn!(
+                "DEBUG OVERRIDES ACTIVE, the manifest is being relaxed at runtime: {:?}",
+                opts.debug_overrides
+            );
+            opts.debug_overrides.apply(&mut manifest);
+            boot_config.debug_overrides = Some(opts.debug_overrides);
+        }
+
+        if opts.runtime_config.is_some() && manifest.runtime_config.is_none() {
+            return Err(anyhow!(
+                "a runtime config was given, but the manifest does not enable runtime_config"
+            ));
+        }
+        boot_config.runtime_config = opts.runtime_config;
+
+        if let Some(credentials) = manifest
+            .kms_proxy
+            .as_ref()
+            .and_then(|kms| kms.credentials.as_ref())
+        {
+            boot_config.aws_env = boot_config::aws_env(credentials.source)?;
+        }
 
         let cpu_count = match (opts.cpu_count, &manifest.defaults) {
             (Some(cpu_count), _) => cpu_count,
@@ -100,13 +485,162 @@ impl Enclave {
             }
         };
 
+        // memory_mb is that of the app, odyn and its proxies are given theirs on top
+        let supervisor_memory_mb = manifest.supervisor_memory_mb();
+        debug!("adding {supervisor_memory_mb} MiB for odyn to the {memory_mb} MiB of the app");
+        let memory_mb = memory_mb + supervisor_memory_mb;
+
+        let eif_size = tokio::fs::metadata(&eif_path).await?.len();
+        if let Some(warning) = preflight::eif_memory_warning(eif_size, memory_mb) {
+            warn!("{warning}");
+        }
+
+        // Flags override the manifest defaults one limit at a time
+        let defaults = manifest.defaults.as_ref();
+        let ingress_budget = BudgetConfig {
+            max_connections: opts
+                .ingress_budget
+                .max_connections
+                .or_else(|| defaults.and_then(|d| d.ingress_max_connections)),
+            accepts_per_second: opts
+                .ingress_budget
+                .accepts_per_second
+                .or_else(|| defaults.and_then(|d| d.ingress_accepts_per_second)),
+        };
+
+        let drain_timeout = opts
+            .drain_seconds
+            .or_else(|| defaults.and_then(|d| d.drain_seconds))
+            .filter(|seconds| *seconds > 0)
+            .map(Duration::from_secs);
+
+        let exit_codes = ExitCodeMapping::new(
+            defaults.and_then(|d| d.exit_codes.as_ref()),
+            opts.passthrough_exit_code,
+        );
+
+        let metrics = HostMetrics::new();
+        metrics.cpu_count_configured.set(cpu_count.into());
+        metrics.memory_mb_configured.set(memory_mb.into());
+
+        let cli = NitroCLI::new();
+
+        let mut event_context = EventContext {
+            manifest_name: manifest.name.clone(),
+            ..Default::default()
+        };
+
+        let measurements = match cli.describe_eif(&eif_path).await {
+            Ok(eif_info) => Some(eif_info.measurements().clone()),
+            Err(err) => {
+                error!("failed to read EIF measurements: {err}");
+                None
+            }
+        };
+
+        // Only go looking for the instance identity if someone is listening
+        if !opts.webhooks.is_empty() || opts.event_output.is_some() {
+            event_context.measurements = measurements.clone();
+            event_context.fetch_instance_identity().await;
+        }
+
+        // Sealed files can only reach the enclave through the config provider
+        for secret in manifest.secrets.iter().flatten() {
+            if let Some(name) = secret.file_name() {
+                let provided = opts
+                    .attested_config
+                    .as_ref()
+                    .is_some_and(|a| a.secret_files.contains_key(name));
+                if !provided {
+                    return Err(anyhow!(
+                        "secret {} requires the host to provide --secret-file {name}=<path>",
+                        secret.name
+                    ));
+                }
+            }
+        }
+
+        // The journal outlives enclaver-run, so the starts it recorded are those
+        // before a supervisor restarted us
+        let (journal, previous_starts) = match opts.state_dir {
+            Some(ref state_dir) => (
+                Some(StatusJournal::open(state_dir, &manifest.name)?),
+                journal::count_starts(state_dir, &manifest.name),
+            ),
+            None => (None, 0),
+        };
+        metrics.enclave_starts.add(previous_starts);
+
+        let identity = match measurements {
+            Some(ref measurements) => Some(IdentityRecord::new(
+                measurements.identity(),
+                opts.state_dir.as_deref(),
+                &manifest.name,
+            )?),
+            None => None,
+        };
+
+        let config_provider = match opts.attested_config {
+            Some(attested) => {
+                // Debug mode enclaves attest to all zero PCRs, so could never pass
+                if opts.debug_mode {
+                    return Err(anyhow!(
+                        "an attested config cannot be released to an enclave in debug mode"
+                    ));
+                }
+
+                let pcrs = measurements
+                    .as_ref()
+                    .ok_or_else(|| {
+                        anyhow!("the EIF measurements are needed to verify the enclave")
+                    })?
+                    .pcrs()?;
+                let verifier = AttestationVerifier::new(&attested.root_cert_pem, pcrs)?;
+                let payload =
+                    AttestedPayload::new(attested.config.as_deref(), &attested.secret_files);
+                let provider = ConfigProvider::new(verifier, payload);
+                Some(match identity {
+                    Some(ref identity) => provider.with_identity(identity.clone()),
+                    None => provider,
+                })
+            }
+            None => None,
+        };
+
         Ok(Self {
-            cli: NitroCLI::new(),
+            cli,
             eif_path: eif_path.to_path_buf(),
-            manifest: load_manifest(&manifest_path).await?,
+            manifest,
             cpu_count,
             memory_mb,
+            ingress_budget,
+            ingress_bind_addr: opts.ingress_bind_addr,
+            drain: Drain::default(),
+            drain_timeout,
+            proxy_pinning: opts.proxy_pinning,
             debug_mode: opts.debug_mode,
+            metrics_addr: opts.metrics_addr,
+            metrics,
+            events: EventNotifier::new(event_context, opts.webhooks)
+                .with_output(opts.event_output)
+                .with_journal(journal),
+            state_dir: opts.state_dir,
+            egress_audit_log: opts.egress_audit_log,
+            exit_codes,
+            wait_for_capacity: opts.wait_for_capacity,
+            log_rendering: opts.log_rendering,
+            log_compression: opts.log_compression,
+            status: Arc::new(Mutex::new(None)),
+            identity,
+            previous_starts,
+            boot_config,
+            config_provider,
+            enclave_cid: watch::Sender::new(None),
+            resolver: opts.resolver,
+            egress_netns: opts.egress_netns,
+            egress_relay_netns: opts.egress_relay_netns,
+            run_as: opts.run_as,
+            terminator: None,
             enclave_info: None,
             tasks: Vec::new(),
         })
@@ -119,42 +653,130 @@ impl Enclave {
             return Err(anyhow!("Enclave already started"));
         }
 
+        self.start_metrics_server()?;
+        self.start_boot_config_server()?;
+        self.start_config_provider()?;
+
         // Start the egress proxy before starting the enclave, to avoid (unlikely) race conditions
         // where something inside the enclave attempts egress before the proxy is ready.
         self.start_egress_proxy().await?;
 
         info!("starting enclave");
-        let enclave_info = self
-            .cli
-            .run_enclave(RunEnclaveArgs {
-                cpu_count: self.cpu_count,
-                memory_mb: self.memory_mb,
-                eif_path: self.eif_path.clone(),
-                cid: None,
-                debug_mode: self.debug_mode,
-            })
-            .await?;
+        let enclave_info = match self.run_enclave(&cancellation).await {
+            Ok(Some(enclave_info)) => enclave_info,
+            Ok(None) => {
+                self.cleanup().await?;
+                return Ok(EnclaveExitStatus::Cancelled);
+            }
+            Err(err) => {
+                self.events
+                    .notify(EnclaveEvent::Error {
+                        error: err.to_string(),
+                    })
+                    .await;
+                return Err(err);
+            }
+        };
 
         self.enclave_info = Some(enclave_info.clone());
+        self.enclave_cid.send_replace(Some(enclave_info.cid));
 
         info!("started enclave {}", enclave_info.id);
-
-        if self.debug_mode {
-            // TODO: Should we let an an EOF from the console terminate run?
-            self.attach_debug_console(&enclave_info.id).await?;
+        let cpus = enclave_info
+            .cpu_ids
+            .clone()
+            .map(|cpus| cpu_topology::assign(cpus, self.proxy_pinning.as_ref()));
+        if self.previous_starts > 0 {
+            self.events
+                .notify(EnclaveEvent::Restarted {
+                    previous_starts: self.previous_starts,
+                })
+                .await;
         }
+        self.events
+            .notify(EnclaveEvent::Started {
+                enclave_id: enclave_info.id.clone(),
+                cid: enclave_info.cid,
+                cpus,
+                debug: self.debug_mode,
+            })
+            .await;
+
+        self.metrics.enclave_up.set(1);
+        self.metrics.enclave_starts.inc();
+        self.metrics
+            .cpu_count_allocated
+            .set(enclave_info.cpu_count.unwrap_or(self.cpu_count).into());
+        self.metrics
+            .memory_mb_allocated
+            .set(enclave_info.memory_mib.unwrap_or(self.memory_mb).into());
 
-        self.start_odyn_log_stream(enclave_info.cid)?;
+        if self.manifest.console_enabled() {
+            if self.debug_mode {
+                // TODO: Should we let an an EOF from the console terminate run?
+                self.attach_debug_console(&enclave_info.id).await?;
+            }
 
+            self.start_odyn_log_stream(enclave_info.cid)?;
+        } else {
+            info!("console disabled by the manifest, enclave output will not be streamed");
+        }
+
+        self.start_egress_audit_stream(enclave_info.cid)?;
         self.start_ingress_proxies(enclave_info.cid).await?;
 
-        let exit_res = tokio::select! {
-            exit_res = Enclave::await_exit(enclave_info.cid) =>
-                exit_res,
+        if let Some(user) = self.run_as.clone() {
+            if let Err(err) = self.sandbox(&user, &enclave_info.id) {
+                if let Err(err) = self.cleanup().await {
+                    error!("error terminating enclave: {err}");
+                }
+                return Err(err);
+            }
+        }
+
+        let exit_res = {
+            let exit =
+                Enclave::await_exit(enclave_info.cid, &self.events, &self.status, &self.metrics);
+            tokio::pin!(exit);
+
+            let exit_res = tokio::select! {
+                exit_res = &mut exit => exit_res,
+
+                _ = cancellation.cancelled() =>
+                    Ok(EnclaveExitStatus::Cancelled),
+            };
+
+            // The app may well exit on its own once told to shut down, which ends the
+            // drain early
+            if let (Ok(EnclaveExitStatus::Cancelled), Some(timeout)) =
+                (&exit_res, self.drain_timeout)
+            {
+                tokio::select! {
+                    _ = &mut exit => debug!("enclave exited while draining"),
+                    _ = self.drain(enclave_info.cid, timeout) => {}
+                }
+            }
+
+            exit_res
+        };
 
-            _ = cancellation.cancelled() =>
-                Ok(EnclaveExitStatus::Cancelled),
+        self.metrics.enclave_up.set(0);
+        self.metrics.cpu_count_allocated.set(0);
+        self.metrics.memory_mb_allocated.set(0);
+
+        let event = match exit_res {
+            Ok(EnclaveExitStatus::Exited(code)) => EnclaveEvent::Exited { code },
+            Ok(EnclaveExitStatus::Signaled(signal)) => EnclaveEvent::Signaled { signal },
+            Ok(EnclaveExitStatus::Fatal { code, ref error }) => EnclaveEvent::Fatal {
+                code,
+                error: error.clone(),
+            },
+            Ok(EnclaveExitStatus::Cancelled) => EnclaveEvent::Stopped,
+            Err(ref err) => EnclaveEvent::Error {
+                error: err.to_string(),
+            },
         };
+        self.events.notify(event).await;
 
         if let Err(err) = self.cleanup().await {
             error!("error terminating enclave: {err}");
@@ -165,7 +787,14 @@ impl Enclave {
             Ok(EnclaveExitStatus::Signaled(signal)) => {
                 info!("enclave stopped due to signal {signal}")
             }
-            Ok(EnclaveExitStatus::Fatal(ref error)) => {
+            Ok(EnclaveExitStatus::Fatal {
+                code: Some(code),
+                ref error,
+            }) => info!("enclave exited due to fatal error ({code}): {error}"),
+            Ok(EnclaveExitStatus::Fatal {
+                code: None,
+                ref error,
+            }) => {
                 info!("enclave exited due to fatal error: {error}")
             }
             Ok(EnclaveExitStatus::Cancelled) => (),
@@ -175,6 +804,243 @@ impl Enclave {
         exit_res
     }
 
+    // Starts the enclave, retrying for up to wait_for_capacity while other enclaves
+    // hold the CPUs or memory it needs. None if cancelled while waiting.
+    async fn run_enclave(&self, cancellation: &CancellationToken) -> Result<Option<EnclaveInfo>> {
+        let deadline = self
+            .wait_for_capacity
+            .map(|wait| tokio::time::Instant::now() + wait);
+        let mut waiting = false;
+
+        loop {
+            let err = match self.cli.run_enclave(self.run_enclave_args()).await {
+                Ok(enclave_info) => return Ok(Some(enclave_info)),
+                Err(err) => err,
+            };
+            let shortage = match err.downcast_ref::<InsufficientCapacity>() {
+                Some(shortage) => *shortage,
+                None => return Err(err),
+            };
+
+            let holders = self.describe_capacity_holders().await;
+            let now = tokio::time::Instant::now();
+            let deadline = match deadline {
+                Some(deadline) if now < deadline => deadline,
+                Some(_) => return Err(anyhow!("{shortage}{holders}, gave up waiting for it")),
+                None => {
+                    return Err(anyhow!(
+                        "{shortage}{holders}, see --wait-for-capacity to wait for it"
+                    ))
+                }
+            };
+
+            if !waiting {
+                warn!("{shortage}{holders}, waiting for capacity");
+                waiting = true;
+            }
+
+            let retry_at = deadline.min(now + CAPACITY_RETRY_INTERVAL);
+            tokio::select! {
+                _ = tokio::time::sleep_until(retry_at) => {}
+                _ = cancellation.cancelled() => return Ok(None),
+            }
+        }
+    }
+
+    // Which enclaves on this host hold the pool, as a suffix for an error message
+    async fn describe_capacity_holders(&self) -> String {
+        let enclaves = match self.cli.describe_enclaves().await {
+            Ok(enclaves) => enclaves,
+            Err(err) => {
+                debug!("unable to describe the running enclaves: {err}");
+                return String::new();
+            }
+        };
+        if enclaves.is_empty() {
+            return String::new();
+        }
+
+        let holders: Vec<String> = enclaves
+            .iter()
+            .map(|enclave| {
+                format!(
+                    "{} ({}, {} CPUs, {} MiB)",
+                    enclave.name,
+                    enclave.id,
+                    enclave.cpu_count.map_or("?".to_string(), |c| c.to_string()),
+                    enclave
+                        .memory_mib
+                        .map_or("?".to_string(), |m| m.to_string())
+                )
+            })
+            .collect();
+        format!(", held by {}", holders.join(", "))
+    }
+
+    /// How the exit status returned by run() maps to the exit code of enclaver-run
+    pub fn exit_codes(&self) -> ExitCodeMapping {
+        self.exit_codes
+    }
+
+    fn run_enclave_args(&self) -> RunEnclaveArgs {
+        RunEnclaveArgs {
+            cpu_count: self.cpu_count,
+            memory_mb: self.memory_mb,
+            eif_path: self.eif_path.clone(),
+            cid: None,
+            debug_mode: self.debug_mode,
+        }
+    }
+
+    fn ingress_bind_addr(&self, item: &Ingress) -> SocketAddr {
+        let addr = self.ingress_bind_addr.unwrap_or(item.host_addr());
+        SocketAddr::new(addr, item.listen_port)
+    }
+
+    fn ingress_addrs(&self) -> Vec<SocketAddr> {
+        self.manifest
+            .ingress
+            .iter()
+            .flatten()
+            .map(|item| self.ingress_bind_addr(item))
+            .collect()
+    }
+
+    // Describes what `run` would do, without doing any of it.
+    pub fn plan(&self) -> Result<String> {
+        let nitro_cli_args: Vec<String> = self
+            .run_enclave_args()
+            .to_args()?
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+
+        let mut plan = format!("nitro-cli {}\n", nitro_cli_args.join(" "));
+        plan += &format!(
+            "memory: {} MiB, {} MiB of it for odyn\n",
+            self.memory_mb,
+            self.manifest.supervisor_memory_mb()
+        );
+
+        for addr in self.ingress_addrs() {
+            let port = addr.port();
+            plan += &format!("ingress: tcp {addr} -> enclave vsock port {port}\n");
+        }
+
+        if let Some(max) = self.ingress_budget.max_connections {
+            plan += &format!("ingress budget: {max} open connections\n");
+        }
+        if let Some(rate) = self.ingress_budget.accepts_per_second {
+            plan += &format!("ingress budget: {rate} accepts per second\n");
+        }
+        if let Some(timeout) = self.drain_timeout {
+            plan += &format!("ingress drain: up to {}s on shutdown\n", timeout.as_secs());
+        }
+        match self.proxy_pinning {
+            Some(ProxyPinning::Auto) => plan += "host proxy CPUs: away from the enclave cores\n",
+            Some(ProxyPinning::Cpus(ref cpus)) => plan += &format!("host proxy CPUs: {cpus:?}\n"),
+            None => {}
+        }
+
+        if let Some(ref egress) = self.manifest.egress {
+            for (i, port) in egress.relay_ports().into_iter().enumerate() {
+                plan += &format!("egress: HTTP proxy on vsock port {port}\n");
+                if let Some(path) = self.relay_netns(i) {
+                    plan += &format!("egress network namespace: {}\n", path.display());
+                }
+            }
+            plan += &format!("egress resolver: {}\n", self.resolver_description());
+            if self
+                .manifest
+                .egress
+                .as_ref()
+                .is_some_and(|egress| egress.is_dns_enabled())
+            {
+                plan += &format!("egress DNS: forwarded on vsock port {DNS_VSOCK_PORT}\n");
+            }
+            for service in self.manifest.egress_services() {
+                plan += &format!(
+                    "egress service: {} -> host port {}\n",
+                    service.name, service.port
+                );
+            }
+            for rule in self.manifest.egress_udp() {
+                plan += &format!(
+                    "egress UDP: enclave port {} -> {}:{} on vsock port {UDP_EGRESS_VSOCK_PORT}\n",
+                    rule.local_port, rule.host, rule.port
+                );
+            }
+            if let Some(ref path) = self.egress_audit_log {
+                plan += &format!(
+                    "egress audit log: {} from vsock port {EGRESS_AUDIT_PORT}\n",
+                    path.display()
+                );
+            }
+        } else {
+            plan += "egress: none\n";
+        }
+
+        if !self.manifest.console_enabled() {
+            plan += "logs: none, console disabled by the manifest\n";
+        }
+
+        if let Some(addr) = self.metrics_addr {
+            plan += &format!("metrics: http://{addr}/metrics\n");
+        }
+
+        if let Some(ref state_dir) = self.state_dir {
+            plan += &format!("status journal: {}\n", state_dir.display());
+        }
+
+        let exit_codes = self.exit_codes;
+        plan += &format!(
+            "exit codes: signaled {}, fatal {}, interrupted {}\n",
+            match exit_codes.passthrough {
+                true => "128+N".to_string(),
+                false => exit_codes.signaled.to_string(),
+            },
+            exit_codes.fatal,
+            exit_codes.interrupted
+        );
+
+        Ok(plan)
+    }
+
+    fn resolver_description(&self) -> String {
+        let mut desc = match (&self.resolver.doh, self.resolver.servers.as_slice()) {
+            (Some(endpoint), _) => format!("DNS-over-HTTPS {endpoint}"),
+            (None, []) => "system".to_string(),
+            (None, servers) => {
+                let servers: Vec<String> = servers.iter().map(|s| s.to_string()).collect();
+                format!("DNS {}", servers.join(", "))
+            }
+        };
+
+        if !self.resolver.search.is_empty() {
+            desc += &format!(", search {}", self.resolver.search.join(" "));
+        }
+
+        desc
+    }
+
+    // Checks the host has the resources and free ports needed to run the enclave.
+    pub fn preflight(&self) -> Vec<Check> {
+        let mut checks = vec![
+            preflight::check_cpus(self.cpu_count),
+            preflight::check_memory(self.memory_mb),
+        ];
+
+        for addr in self.ingress_addrs() {
+            checks.push(preflight::check_port(addr.ip(), addr.port()));
+        }
+
+        if let Some(addr) = self.metrics_addr {
+            checks.push(preflight::check_port(addr.ip(), addr.port()));
+        }
+
+        checks
+    }
+
     async fn start_ingress_proxies(&mut self, cid: u32) -> Result<()> {
         let ingress = match &self.manifest.ingress {
             Some(ref ingress) => ingress,
@@ -184,10 +1050,35 @@ impl Enclave {
             }
         };
 
+        // One budget for all of the enclave's listeners
+        let budget = ConnectionBudget::new(self.ingress_budget);
+
         for item in ingress {
             let listen_port = item.listen_port;
-            info!("starting ingress proxy on port {listen_port}");
-            let proxy = HostProxy::bind(listen_port).await?;
+            let bind = self.ingress_bind_addr(item);
+            info!("starting ingress proxy on {bind}");
+            let port = listen_port.to_string();
+            let labels = [("port", port.as_str())];
+            let metrics =
+                ConnectionMetrics::register(&self.metrics.registry, "enclaver_ingress", &labels);
+            let streams =
+                StreamMetrics::register(&self.metrics.registry, "enclaver_ingress", &labels);
+            let proxy = HostProxy::bind_on(bind.ip(), listen_port)
+                .await?
+                .with_metrics(metrics)
+                .with_stream_metrics(streams)
+                .with_budget(budget.clone())
+                .with_port_budget(ConnectionBudget::new(BudgetConfig {
+                    max_connections: item.max_connections,
+                    accepts_per_second: item.accepts_per_second,
+                }))
+                .with_keepalive(Keepalive::from_manifest(item.keepalive_seconds))
+                .with_buffering(Buffering::from_manifest(item.buffer_bytes))
+                .with_access_log(item.logs_access())
+                .with_drain(self.drain.clone());
+            self.events
+                .notify(EnclaveEvent::IngressListening { port: listen_port })
+                .await;
             self.tasks.push(utils::spawn!("ingress proxy", async move {
                 proxy.serve(cid, listen_port.into()).await;
             })?)
@@ -204,41 +1095,337 @@ impl Enclave {
             return Ok(());
         }
 
-        info!("starting egress proxy on vsock port {HTTP_EGRESS_VSOCK_PORT}");
-        let proxy = HostHttpProxy::bind(HTTP_EGRESS_VSOCK_PORT)?;
-        self.tasks.push(utils::spawn!("egress proxy", async move {
-            proxy.serve().await;
+        // Shared by all the relays, so that failing over does not reset the counters
+        let metrics = ConnectionMetrics::register(&self.metrics.registry, "enclaver_egress", &[]);
+        let streams = StreamMetrics::register(&self.metrics.registry, "enclaver_egress", &[]);
+        let connect_latency = self.metrics.registry.histogram(
+            "enclaver_egress_connect_duration_seconds",
+            "Time taken by the egress proxy to resolve and connect to a remote",
+            &[],
+            LATENCY_BUCKETS,
+        );
+        let pinned_names = self
+            .manifest
+            .egress
+            .as_ref()
+            .filter(|egress| egress.pins_dns())
+            .map(|egress| egress.pinned_names());
+        // Checked again on this side, in case the enclave asks for what it should not
+        let egress = self.manifest.egress.clone().unwrap_or_default();
+
+        // One relay unless the manifest lists standby ones, which odyn fails over to
+        for (i, vsock_port) in egress.relay_ports().into_iter().enumerate() {
+            info!("starting egress proxy on vsock port {vsock_port}");
+            let resolver = Resolver::new(&self.resolver);
+            let services: Vec<EgressService> = self.manifest.egress_services().cloned().collect();
+            let (metrics, streams) = (metrics.clone(), streams.clone());
+            let connect_latency = connect_latency.clone();
+            let pinned_names = pinned_names.clone();
+            let policy = egress.clone();
+            let setup = move || -> Result<HostHttpProxy> {
+                let mut proxy = HostHttpProxy::bind(vsock_port)?
+                    .with_metrics(metrics)
+                    .with_stream_metrics(streams)
+                    .with_connect_latency(connect_latency)
+                    .with_resolver(resolver)
+                    .with_services(&services)
+                    .with_policy(&policy);
+                if let Some(names) = pinned_names {
+                    proxy = proxy.with_dns_pins(names);
+                }
+                Ok(proxy)
+            };
+
+            let task = match self.relay_netns(i) {
+                Some(path) => {
+                    info!(
+                        "egress proxy on vsock port {vsock_port} will connect out from {}",
+                        path.display()
+                    );
+                    netns::spawn_in(&path, "egress proxy", move || Ok(setup()?.serve())).await?
+                }
+                None => {
+                    let proxy = setup()?;
+                    utils::spawn!("egress proxy", async move {
+                        proxy.serve().await;
+                    })?
+                }
+            };
+
+            self.events
+                .notify(EnclaveEvent::EgressListening { vsock_port })
+                .await;
+            self.tasks.push(task);
+        }
+
+        if self
+            .manifest
+            .egress
+            .as_ref()
+            .is_some_and(|egress| egress.is_dns_enabled())
+        {
+            self.start_dns_proxy().await?;
+        }
+
+        if self.manifest.egress_udp().next().is_some() {
+            self.start_udp_relay().await?;
+        }
+
+        Ok(())
+    }
+
+    // The network namespace the i-th egress relay connects out from
+    fn relay_netns(&self, i: usize) -> Option<PathBuf> {
+        i.checked_sub(1)
+            .and_then(|standby| self.egress_relay_netns.get(standby))
+            .or(self.egress_netns.as_ref())
+            .cloned()
+    }
+
+    // Resolves the names the enclave looks up, from the same place as the egress proxy
+    async fn start_dns_proxy(&mut self) -> Result<()> {
+        info!("starting DNS proxy on vsock port {DNS_VSOCK_PORT}");
+        let resolver = Resolver::new(&self.resolver);
+
+        let task = match self.egress_netns {
+            Some(ref path) => {
+                netns::spawn_in(path, "DNS proxy", move || {
+                    let proxy = HostDnsProxy::bind(DNS_VSOCK_PORT)?.with_resolver(resolver);
+                    Ok(proxy.serve())
+                })
+                .await?
+            }
+            None => {
+                let proxy = HostDnsProxy::bind(DNS_VSOCK_PORT)?.with_resolver(resolver);
+                utils::spawn!("DNS proxy", async move {
+                    proxy.serve().await;
+                })?
+            }
+        };
+        self.tasks.push(task);
+
+        Ok(())
+    }
+
+    // Sends the datagrams of the enclave's egress.udp rules on, from the same network
+    // namespace as the egress proxy
+    async fn start_udp_relay(&mut self) -> Result<()> {
+        info!("starting UDP relay on vsock port {UDP_EGRESS_VSOCK_PORT}");
+        let resolver = Resolver::new(&self.resolver);
+        let egress = self.manifest.egress.clone().unwrap_or_default();
+
+        let task = match self.egress_netns {
+            Some(ref path) => {
+                netns::spawn_in(path, "UDP relay", move || {
+                    let relay =
+                        HostUdpRelay::bind(UDP_EGRESS_VSOCK_PORT, &egress)?.with_resolver(resolver);
+                    Ok(relay.serve())
+                })
+                .await?
+            }
+            None => {
+                let relay =
+                    HostUdpRelay::bind(UDP_EGRESS_VSOCK_PORT, &egress)?.with_resolver(resolver);
+                utils::spawn!("UDP relay", async move {
+                    relay.serve().await;
+                })?
+            }
+        };
+        self.tasks.push(task);
+
+        Ok(())
+    }
+
+    fn start_boot_config_server(&mut self) -> Result<()> {
+        if self.boot_config == BootConfig::default() {
+            return Ok(());
+        }
+
+        info!("serving boot config on vsock port {BOOT_CONFIG_PORT}");
+        let config = self.boot_config.clone();
+        self.tasks
+            .push(utils::spawn!("boot config server", async move {
+                if let Err(err) = boot_config::serve(config).await {
+                    error!("error serving boot config: {err}");
+                }
+            })?);
+
+        Ok(())
+    }
+
+    fn start_config_provider(&mut self) -> Result<()> {
+        let provider = match self.config_provider.take() {
+            Some(provider) => provider,
+            None => return Ok(()),
+        };
+
+        info!("serving attested config on vsock port {CONFIG_PROVIDER_PORT}");
+        let enclave_cid = self.enclave_cid.subscribe();
+        self.tasks
+            .push(utils::spawn!("config provider", async move {
+                if let Err(err) = provider.serve(enclave_cid).await {
+                    error!("error serving attested config: {err}");
+                }
+            })?);
+
+        Ok(())
+    }
+
+    fn start_metrics_server(&mut self) -> Result<()> {
+        let addr = match self.metrics_addr {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+
+        info!("serving metrics on {addr}");
+        let srv = HttpServer::bind_addr(addr)?;
+        let handler = HostApiHandler {
+            metrics: MetricsHandler::new(self.metrics.registry.clone()),
+            identity: self.identity.clone(),
+            status: self.status.clone(),
+            exit_codes: self.exit_codes,
+        };
+        self.tasks.push(utils::spawn!("metrics server", async move {
+            if let Err(err) = srv.serve(handler).await {
+                error!("error serving metrics: {err}");
+            }
         })?);
 
         Ok(())
     }
 
     fn start_odyn_log_stream(&mut self, cid: u32) -> Result<()> {
+        // Debug mode enclaves report all zero PCRs, so only the manifest can be checked
+        let mut expected = StartupBanner::new(&self.manifest);
+        if let (Some(identity), false) = (&self.identity, self.debug_mode) {
+            let identity = identity.current();
+            expected = expected.with_pcrs([identity.pcr0, identity.pcr1, identity.pcr2]);
+        }
+        let banner_mismatches = self.metrics.banner_mismatches.clone();
+        let rendering = self.log_rendering;
+        let mut compression = self.log_compression;
+
         self.tasks
             .push(utils::spawn!("odyn log stream", async move {
                 info!("waiting for enclave to boot to stream logs");
-                let conn = loop {
-                    match VsockStream::connect(cid, APP_LOG_PORT).await {
-                        Ok(conn) => break conn,
+
+                // Offset of the next byte of the enclave log, so reconnects pick
+                // up where the last stream ended instead of replaying the log
+                let mut cursor = 0u64;
+                let mut connected = false;
+                loop {
+                    let conn = match Self::connect_odyn_log(cid, cursor, &mut compression).await {
+                        Ok((conn, start)) => {
+                            if start > cursor {
+                                warn!(
+                                    "{} bytes of enclave logs were lost while disconnected",
+                                    start - cursor
+                                );
+                            }
+                            cursor = start;
+                            conn
+                        }
 
                         // TODO: improve the polling frequency / backoff / timeout
                         Err(_) => {
                             tokio::time::sleep(LOG_VSOCK_RETRY_INTERVAL).await;
+                            continue;
                         }
+                    };
+
+                    if connected {
+                        info!("reconnected to enclave, resuming log stream at byte {cursor}");
+                    } else {
+                        info!("connected to enclave, starting log stream");
+                        connected = true;
                     }
-                };
 
-                info!("connected to enclave, starting log stream");
-                if let Err(e) = utils::log_lines_from_stream("enclave", conn).await {
-                    error!("error reading log lines from enclave: {e}");
+                    let on_line = |line: &str| {
+                        if let Some(banner) = StartupBanner::parse(line) {
+                            check_banner(banner, &expected, &banner_mismatches);
+                        }
+                    };
+                    if let Err(e) = utils::log_lines_from_cursor(
+                        "enclave",
+                        conn,
+                        &mut cursor,
+                        rendering,
+                        on_line,
+                    )
+                    .await
+                    {
+                        error!("error reading log lines from enclave: {e}");
+                    }
+                    tokio::time::sleep(LOG_VSOCK_RETRY_INTERVAL).await;
                 }
             })?);
 
         Ok(())
     }
 
-    async fn await_exit(cid: u32) -> Result<EnclaveExitStatus> {
+    // Appends the egress decisions of the enclave to the audit log file, if there is one
+    fn start_egress_audit_stream(&mut self, cid: u32) -> Result<()> {
+        use std::io::Write;
+
+        let path = match self.egress_audit_log {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        if self.manifest.egress.is_none() {
+            warn!("egress is disabled by the manifest, the egress audit log will stay empty");
+            return Ok(());
+        }
+
+        // Opened up front, so it can still be written after dropping privileges
+        let mut compression = self.log_compression;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("failed to open egress audit log {}: {e}", path.display()))?;
+
+        self.tasks
+            .push(utils::spawn!("egress audit stream", async move {
+                // Number of the next event, so reconnects pick up where the last
+                // stream ended
+                let mut cursor = 0u64;
+                loop {
+                    let res = audit::follow(
+                        cid,
+                        EGRESS_AUDIT_PORT,
+                        &mut cursor,
+                        &mut compression,
+                        |line| Ok(file.write_all(format!("{line}\n").as_bytes())?),
+                    )
+                    .await;
+                    if let Err(err) = res {
+                        debug!("egress audit stream ended: {err}");
+                    }
+                    tokio::time::sleep(LOG_VSOCK_RETRY_INTERVAL).await;
+                }
+            })?);
+
+        Ok(())
+    }
+
+    // Returns the stream along with the offset it starts at, which is past cursor
+    // when the enclave has already trimmed the bytes in between
+    async fn connect_odyn_log(
+        cid: u32,
+        cursor: u64,
+        compression: &mut Compression,
+    ) -> Result<(Decoded<VsockStream>, u64)> {
+        compression::connect(cid, APP_LOG_PORT, cursor, compression).await
+    }
+
+    async fn await_exit(
+        cid: u32,
+        events: &EventNotifier,
+        process_status: &Mutex<Option<EnclaveStatus>>,
+        metrics: &HostMetrics,
+    ) -> Result<EnclaveExitStatus> {
         let mut failed_attempts = 0;
+        let mut healthy = false;
 
         loop {
             let conn = match VsockStream::connect(cid, STATUS_PORT).await {
@@ -258,7 +1445,8 @@ impl Enclave {
 
             debug!("connected to enclave status port");
 
-            let mut framed = FramedRead::new(conn, LinesCodec::new_with_max_length(1024));
+            let mut framed =
+                FramedRead::new(conn, LinesCodec::new_with_max_length(STATUS_LINE_MAX_LEN));
 
             while let Some(line_res) = framed.next().await {
                 let line = match line_res {
@@ -269,26 +1457,44 @@ impl Enclave {
                     }
                 };
 
-                let status: EnclaveProcessStatus = match serde_json::from_str(&line) {
+                let status = match EnclaveStatus::parse(&line) {
                     Ok(status) => status,
                     Err(e) => {
                         error!("error parsing status line: {e}");
                         continue;
                     }
                 };
+                let version = status.version;
+                let status = status.message;
 
-                match status {
-                    EnclaveProcessStatus::Exited { code } => {
-                        return Ok(EnclaveExitStatus::Exited(code));
-                    }
-                    EnclaveProcessStatus::Signaled { signal } => {
-                        return Ok(EnclaveExitStatus::Signaled(signal));
+                if let EnclaveStatus::Running {
+                    ref memory,
+                    ref egress_limits,
+                    ref egress_denials,
+                    ref app_log,
+                } = status
+                {
+                    if let Some(stats) = memory {
+                        metrics.odyn_memory(stats);
                     }
-                    EnclaveProcessStatus::Fatal { error } => {
-                        return Ok(EnclaveExitStatus::Fatal(error));
+                    metrics.egress_limits(egress_limits);
+                    metrics.egress_denials(egress_denials);
+                    if let Some(stats) = app_log {
+                        metrics.app_log(stats);
                     }
-                    _ => {
-                        debug!("enclave status: {status:#?}");
+                }
+
+                let exit_status = EnclaveExitStatus::from_status(&status);
+                *process_status.lock().unwrap() = Some(status);
+
+                match exit_status {
+                    Some(exit_status) => return Ok(exit_status),
+                    // odyn keeps reporting its memory while running, which is no news
+                    None if healthy => {}
+                    None => {
+                        debug!("enclave status: running, protocol version {version}");
+                        events.notify(EnclaveEvent::Healthy).await;
+                        healthy = true;
                     }
                 }
             }
@@ -297,13 +1503,33 @@ impl Enclave {
         }
     }
 
+    // Everything that needs root has been done by now, except terminating the enclave.
+    // That is left to a helper process, started before giving up root, which does so
+    // once its stdin is closed.
+    fn sandbox(&mut self, user: &str, enclave_id: &str) -> Result<()> {
+        let terminator = Command::new(std::env::current_exe()?)
+            .arg(TERMINATE_HELPER_COMMAND)
+            .arg(enclave_id)
+            .stdin(Stdio::piped())
+            // Keep it out of our process group, so ^C does not kill it before it is needed
+            .process_group(0)
+            .spawn()
+            .map_err(|e| anyhow!("failed to start the terminate helper: {e}"))?;
+        self.terminator = Some(terminator);
+
+        sandbox::drop_privileges(user)?;
+        sandbox::apply_seccomp()
+    }
+
     async fn attach_debug_console(&mut self, enclave_id: &str) -> Result<()> {
         info!("attaching to debug console");
 
         let stdout = self.cli.console(enclave_id).await?;
+        let rendering = self.log_rendering;
 
         self.tasks.push(tokio::task::spawn(async move {
-            if let Err(e) = utils::log_lines_from_stream("nitro-cli::console", stdout).await {
+            let console = utils::log_lines_from_stream("nitro-cli::console", stdout, rendering);
+            if let Err(e) = console.await {
                 error!("error reading log lines from debug console: {e}");
             }
         }));
@@ -311,10 +1537,38 @@ impl Enclave {
         Ok(())
     }
 
+    // Stops accepting on the ingress ports, tells the app to shut down, and waits up to
+    // timeout for the connections it is serving to close
+    async fn drain(&self, cid: u32, timeout: Duration) {
+        info!(
+            "draining {} ingress connections for up to {}s",
+            self.drain.active(),
+            timeout.as_secs()
+        );
+        self.drain.start();
+
+        if let Err(err) = drain::notify(cid).await {
+            warn!("failed to tell the app to shut down: {err}");
+        }
+
+        match self.drain.wait(timeout).await {
+            0 => info!("ingress drained"),
+            active => warn!("drain timed out, dropping {active} ingress connections"),
+        }
+    }
+
     async fn cleanup(self) -> Result<()> {
         if let Some(enclave_info) = self.enclave_info {
             debug!("terminating enclave");
-            self.cli.terminate_enclave(&enclave_info.id).await?;
+            match self.terminator {
+                Some(mut terminator) => {
+                    drop(terminator.stdin.take());
+                    if !terminator.wait().await?.success() {
+                        return Err(anyhow!("the terminate helper failed"));
+                    }
+                }
+                None => self.cli.terminate_enclave(&enclave_info.id).await?,
+            }
         } else {
             debug!("no enclave to stop");
         }
@@ -333,20 +1587,31 @@ impl Enclave {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "status")]
-enum EnclaveProcessStatus {
-    #[serde(rename = "running")]
-    Running,
-
-    #[serde(rename = "exited")]
-    Exited { code: i32 },
-
-    #[serde(rename = "signaled")]
-    Signaled { signal: i32 },
+// The banner of the enclave, checked against the manifest and EIF measurements
+fn check_banner(
+    banner: serde_json::Result<StartupBanner>,
+    expected: &StartupBanner,
+    mismatches_gauge: &Gauge,
+) {
+    let banner = match banner {
+        Ok(banner) => banner,
+        Err(err) => {
+            warn!("unable to parse the startup banner of the enclave: {err}");
+            return;
+        }
+    };
 
-    #[serde(rename = "fatal")]
-    Fatal { error: String },
+    let mismatches = banner.mismatches(expected);
+    mismatches_gauge.set(mismatches.len() as i64);
+    if mismatches.is_empty() {
+        info!(
+            "enclave started odyn {}, matching the manifest and measurements",
+            banner.version
+        );
+    }
+    for mismatch in mismatches {
+        warn!("startup banner of the enclave does not match: {mismatch}");
+    }
 }
 
 #[derive(Debug)]
@@ -354,5 +1619,123 @@ pub enum EnclaveExitStatus {
     Cancelled,
     Exited(i32),
     Signaled(i32),
-    Fatal(String),
+    Fatal {
+        code: Option<FatalCode>,
+        error: String,
+    },
+}
+
+impl EnclaveExitStatus {
+    // None while the application is still running
+    fn from_status(status: &EnclaveStatus) -> Option<Self> {
+        match status {
+            EnclaveStatus::Running { .. } => None,
+            EnclaveStatus::Exited { code } => Some(Self::Exited(*code)),
+            EnclaveStatus::Signaled { signal } => Some(Self::Signaled(*signal)),
+            EnclaveStatus::Fatal { code, error } => Some(Self::Fatal {
+                code: *code,
+                error: error.clone(),
+            }),
+        }
+    }
+}
+
+/// The exit code enclaver-run reports for each way the enclave can end. An exit of
+/// the application is reported with its own code. The other ways get fixed codes,
+/// which the manifest can move out of the range the application uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitCodeMapping {
+    pub signaled: u8,
+    pub fatal: u8,
+    pub interrupted: u8,
+
+    /// Report an application killed by signal N as 128+N, the way a shell would
+    pub passthrough: bool,
+}
+
+impl Default for ExitCodeMapping {
+    fn default() -> Self {
+        Self {
+            signaled: ENCLAVE_SIGNALED_EXIT_CODE,
+            fatal: ENCLAVE_FATAL_EXIT_CODE,
+            interrupted: ENCLAVER_INTERRUPTED_EXIT_CODE,
+            passthrough: false,
+        }
+    }
+}
+
+impl ExitCodeMapping {
+    pub fn new(exit_codes: Option<&ExitCodes>, passthrough: bool) -> Self {
+        let default = Self::default();
+        let exit_codes = exit_codes.cloned().unwrap_or_default();
+
+        Self {
+            signaled: exit_codes.signaled.unwrap_or(default.signaled),
+            fatal: exit_codes.fatal.unwrap_or(default.fatal),
+            interrupted: exit_codes.interrupted.unwrap_or(default.interrupted),
+            passthrough,
+        }
+    }
+
+    pub fn exit_code(&self, status: &EnclaveExitStatus) -> u8 {
+        match status {
+            EnclaveExitStatus::Exited(code) => *code as u8,
+            EnclaveExitStatus::Signaled(signal) if self.passthrough => {
+                128u8.wrapping_add(*signal as u8)
+            }
+            EnclaveExitStatus::Signaled(_) => self.signaled,
+            EnclaveExitStatus::Fatal { .. } => self.fatal,
+            EnclaveExitStatus::Cancelled => self.interrupted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EnclaveExitStatus, ExitCodeMapping};
+    use crate::manifest::ExitCodes;
+    use crate::protocol::EnclaveStatus;
+    use assert2::assert;
+
+    #[test]
+    fn test_exit_status() {
+        let status = EnclaveStatus::parse(r#"{"status":"exited","code":3}"#).unwrap();
+        let exit_status = EnclaveExitStatus::from_status(&status.message);
+        assert!(matches!(exit_status, Some(EnclaveExitStatus::Exited(3))));
+
+        let status = EnclaveStatus::parse(r#"{"status":"signaled","signal":"SIGTERM"}"#).unwrap();
+        let exit_status = EnclaveExitStatus::from_status(&status.message);
+        assert!(matches!(exit_status, Some(EnclaveExitStatus::Signaled(15))));
+
+        let status = EnclaveStatus::parse(r#"{"status":"running"}"#).unwrap();
+        assert!(EnclaveExitStatus::from_status(&status.message).is_none());
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let fatal = EnclaveExitStatus::Fatal {
+            code: None,
+            error: "boom".to_string(),
+        };
+
+        let mapping = ExitCodeMapping::default();
+        assert!(mapping.exit_code(&EnclaveExitStatus::Exited(3)) == 3);
+        assert!(mapping.exit_code(&EnclaveExitStatus::Signaled(15)) == 107);
+        assert!(mapping.exit_code(&fatal) == 108);
+        assert!(mapping.exit_code(&EnclaveExitStatus::Cancelled) == 109);
+
+        let exit_codes = ExitCodes {
+            signaled: Some(201),
+            fatal: Some(202),
+            interrupted: None,
+        };
+        let mapping = ExitCodeMapping::new(Some(&exit_codes), false);
+        assert!(mapping.exit_code(&EnclaveExitStatus::Signaled(15)) == 201);
+        assert!(mapping.exit_code(&fatal) == 202);
+        assert!(mapping.exit_code(&EnclaveExitStatus::Cancelled) == 109);
+
+        let mapping = ExitCodeMapping::new(Some(&exit_codes), true);
+        assert!(mapping.exit_code(&EnclaveExitStatus::Signaled(15)) == 143);
+        assert!(mapping.exit_code(&fatal) == 202);
+    }
 }
@@ -1,20 +1,370 @@
 use anyhow::{anyhow, Result};
-use bollard::container::{Config, LogOutput, LogsOptions, WaitContainerOptions};
-use bollard::models::{DeviceMapping, HostConfig, PortBinding, PortMap};
+use bollard::container::{
+    Config, CreateContainerOptions, LogOutput, LogsOptions, NetworkingConfig, WaitContainerOptions,
+};
+use bollard::models::{
+    DeviceMapping, EndpointSettings, HostConfig, PortBinding, PortMap, ResourcesUlimits,
+};
 use bollard::Docker;
 use futures_util::stream::{StreamExt, TryStreamExt};
+use log::{error, info};
 use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, Stderr, Stdout};
+use tokio::net::UnixDatagram;
+
+use crate::constants::NITRO_ENCLAVES_DEVICE;
+use crate::journal::DEFAULT_STATE_DIR;
+use crate::manifest::Host;
+use crate::preflight::{self, Check};
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+const LOG_IDENTIFIER: &str = "enclaver";
+
+// syslog facility "user" combined with the "info" and "err" severities
+const SYSLOG_PRI_INFO: u8 = 14;
+const SYSLOG_PRI_ERR: u8 = 11;
+
+// The "info" and "err" severities, which journald takes without a facility
+const JOURNALD_PRIORITY_INFO: u8 = 6;
+const JOURNALD_PRIORITY_ERR: u8 = 3;
+
+// Longest line held back waiting for its newline, past which it is sent in pieces
+const MAX_PARTIAL_LINE: usize = 64 * 1024;
+
+// Denies syscalls the supervisor and its proxies never need, like mount, ptrace or
+// module loading, so a compromised proxy has less to work with
+const SECCOMP_PROFILE: &str = include_str!("profiles/seccomp.json");
+
+// The exposed ports of a container config, as Docker takes them
+type ExposedPorts = HashMap<String, HashMap<(), ()>>;
+
+/// Where the output of the wrapper container (and therefore the enclave logs) is sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogDriver {
+    Stdio,
+    Journald,
+    Syslog,
+    File(PathBuf),
+}
+
+enum LogSink {
+    Stdio {
+        stdout: Stdout,
+        stderr: Stderr,
+    },
+    Journald {
+        sock: UnixDatagram,
+        stdout: LineBuffer,
+        stderr: LineBuffer,
+    },
+    Syslog {
+        sock: UnixDatagram,
+        stdout: LineBuffer,
+        stderr: LineBuffer,
+    },
+    File(File),
+}
+
+impl LogSink {
+    async fn open(driver: &LogDriver) -> Result<Self> {
+        let sink = match driver {
+            LogDriver::Stdio => LogSink::Stdio {
+                stdout: tokio::io::stdout(),
+                stderr: tokio::io::stderr(),
+            },
+            LogDriver::Journald => LogSink::Journald {
+                sock: connect_datagram(JOURNALD_SOCKET)?,
+                stdout: LineBuffer::default(),
+                stderr: LineBuffer::default(),
+            },
+            LogDriver::Syslog => LogSink::Syslog {
+                sock: connect_datagram(SYSLOG_SOCKET)?,
+                stdout: LineBuffer::default(),
+                stderr: LineBuffer::default(),
+            },
+            LogDriver::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .map_err(|e| anyhow!("failed to open log file {}: {e}", path.display()))?;
+                LogSink::File(file)
+            }
+        };
+
+        Ok(sink)
+    }
+
+    async fn write(&mut self, output: LogOutput) -> Result<()> {
+        let (message, is_stderr) = match output {
+            LogOutput::StdOut { message } => (message, false),
+            LogOutput::StdErr { message } => (message, true),
+            _ => return Ok(()),
+        };
+
+        match self {
+            LogSink::Stdio { stdout, stderr } => match is_stderr {
+                false => stdout.write_all(&message).await?,
+                true => stderr.write_all(&message).await?,
+            },
+            LogSink::File(file) => file.write_all(&message).await?,
+            LogSink::Journald {
+                sock,
+                stdout,
+                stderr,
+            } => {
+                let (priority, buffer) = match is_stderr {
+                    false => (JOURNALD_PRIORITY_INFO, stdout),
+                    true => (JOURNALD_PRIORITY_ERR, stderr),
+                };
+                for line in buffer.push(&message) {
+                    sock.send(&journald_entry(priority, &line)).await?;
+                }
+            }
+            LogSink::Syslog {
+                sock,
+                stdout,
+                stderr,
+            } => {
+                let (pri, buffer) = match is_stderr {
+                    false => (SYSLOG_PRI_INFO, stdout),
+                    true => (SYSLOG_PRI_ERR, stderr),
+                };
+                for line in buffer.push(&message) {
+                    sock.send(&syslog_entry(pri, &line)).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Sends the lines the output ended in the middle of
+    async fn finish(&mut self) -> Result<()> {
+        match self {
+            LogSink::Journald {
+                sock,
+                stdout,
+                stderr,
+            } => {
+                for (priority, buffer) in [
+                    (JOURNALD_PRIORITY_INFO, stdout),
+                    (JOURNALD_PRIORITY_ERR, stderr),
+                ] {
+                    if let Some(line) = buffer.finish() {
+                        sock.send(&journald_entry(priority, &line)).await?;
+                    }
+                }
+            }
+            LogSink::Syslog {
+                sock,
+                stdout,
+                stderr,
+            } => {
+                for (pri, buffer) in [(SYSLOG_PRI_INFO, stdout), (SYSLOG_PRI_ERR, stderr)] {
+                    if let Some(line) = buffer.finish() {
+                        sock.send(&syslog_entry(pri, &line)).await?;
+                    }
+                }
+            }
+            LogSink::Stdio { .. } | LogSink::File(_) => {}
+        }
+
+        Ok(())
+    }
+}
+
+// Output of one stream of the container, held back until its line is complete, as
+// Docker cuts the output into chunks wherever it likes
+#[derive(Default)]
+struct LineBuffer {
+    partial: Vec<u8>,
+}
+
+impl LineBuffer {
+    // The lines that data completes. What follows the last newline is kept for the
+    // next chunk, unless it has grown past MAX_PARTIAL_LINE.
+    fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.partial.extend_from_slice(data);
+
+        let end = match self.partial.iter().rposition(|b| *b == b'\n') {
+            Some(pos) => pos + 1,
+            None if self.partial.len() > MAX_PARTIAL_LINE => self.partial.len(),
+            None => return vec![],
+        };
+        let rest = self.partial.split_off(end);
+        let complete = std::mem::replace(&mut self.partial, rest);
+
+        let mut lines: Vec<Vec<u8>> = lines(&complete).map(<[u8]>::to_vec).collect();
+        if self.partial.len() > MAX_PARTIAL_LINE {
+            lines.push(std::mem::take(&mut self.partial));
+        }
+        lines
+    }
+
+    fn finish(&mut self) -> Option<Vec<u8>> {
+        match self.partial.is_empty() {
+            true => None,
+            false => Some(std::mem::take(&mut self.partial)),
+        }
+    }
+}
+
+fn journald_entry(priority: u8, line: &[u8]) -> Vec<u8> {
+    let mut entry =
+        format!("SYSLOG_IDENTIFIER={LOG_IDENTIFIER}\nPRIORITY={priority}\n").into_bytes();
+    entry.extend_from_slice(b"MESSAGE=");
+    entry.extend_from_slice(line);
+    entry.push(b'\n');
+    entry
+}
+
+fn syslog_entry(pri: u8, line: &[u8]) -> Vec<u8> {
+    let mut entry = format!("<{pri}>{LOG_IDENTIFIER}: ").into_bytes();
+    entry.extend_from_slice(line);
+    entry
+}
+
+fn connect_datagram(path: impl AsRef<Path>) -> Result<UnixDatagram> {
+    let sock = UnixDatagram::unbound()?;
+    sock.connect(&path)
+        .map_err(|e| anyhow!("failed to connect to {}: {e}", path.as_ref().display()))?;
+    Ok(sock)
+}
+
+// Parses `[host_addr:]host_port:container_port`, where an IPv6 host_addr may be bracketed
+fn parse_port_forward(spec: &str) -> Result<(Option<IpAddr>, u16, u16)> {
+    let port_re = regex::Regex::new(r"^(?:(.+):)?(\d+):(\d+)(?:/tcp)?$")?;
+
+    let captures = port_re.captures(spec).ok_or_else(|| {
+        anyhow!(
+            "port forward specification '{spec}' does not match the format '[host_addr:]host_port:container_port'",
+        )
+    })?;
+
+    let host_addr = match captures.get(1) {
+        Some(addr) => {
+            let addr = addr.as_str().trim_start_matches('[').trim_end_matches(']');
+            Some(addr.parse().map_err(|e| {
+                anyhow!("invalid host address '{addr}' in port forward '{spec}': {e}")
+            })?)
+        }
+        None => None,
+    };
+
+    Ok((host_addr, captures[2].parse()?, captures[3].parse()?))
+}
+
+// Split a chunk of output into lines for the message oriented sinks
+fn lines(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.split(|b| *b == b'\n').filter(|line| !line.is_empty())
+}
+
+// The devices, tmpfs mounts and ulimits of the wrapper container, the Nitro Enclaves
+// device and those of the host section of the manifest
+fn host_resources(host: Option<&Host>) -> HostConfig {
+    let mut devices = vec![(NITRO_ENCLAVES_DEVICE, NITRO_ENCLAVES_DEVICE)];
+    devices.extend(host.iter().flat_map(|host| host.devices()));
+
+    let tmpfs = host.and_then(|host| host.tmpfs.as_ref()).map(|mounts| {
+        mounts
+            .iter()
+            .map(|tmpfs| {
+                let options = match tmpfs.size_mb {
+                    Some(size_mb) => format!("size={size_mb}m"),
+                    None => String::new(),
+                };
+                (tmpfs.path.clone(), options)
+            })
+            .collect()
+    });
+    let ulimits = host.and_then(|host| host.ulimits.as_ref()).map(|ulimits| {
+        ulimits
+            .iter()
+            .map(|ulimit| ResourcesUlimits {
+                name: Some(ulimit.name.clone()),
+                soft: Some(ulimit.soft),
+                hard: Some(ulimit.hard),
+            })
+            .collect()
+    });
+
+    HostConfig {
+        devices: Some(
+            devices
+                .into_iter()
+                .map(|(on_host, in_container)| DeviceMapping {
+                    path_on_host: Some(on_host.to_string()),
+                    path_in_container: Some(in_container.to_string()),
+                    cgroup_permissions: Some(String::from("rwm")),
+                })
+                .collect(),
+        ),
+        tmpfs,
+        ulimits,
+        ..Default::default()
+    }
+}
+
+/// Restrictions applied to the wrapper container in place of running it privileged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Confinement {
+    /// SELinux type to run the container as, e.g. enclaver.process from the
+    /// bundled policy
+    pub selinux_type: Option<String>,
+}
+
+impl Confinement {
+    fn host_config(&self) -> HostConfig {
+        let mut security_opt = vec![
+            "no-new-privileges".to_string(),
+            format!("seccomp={SECCOMP_PROFILE}"),
+        ];
+
+        if let Some(ref selinux_type) = self.selinux_type {
+            security_opt.push(format!("label=type:{selinux_type}"));
+        }
+
+        HostConfig {
+            privileged: Some(false),
+            cap_drop: Some(vec!["ALL".to_string()]),
+            cap_add: Some(vec!["NET_BIND_SERVICE".to_string()]),
+            security_opt: Some(security_opt),
+            ..Default::default()
+        }
+    }
+}
+
+/// Docker network a container is attached to, where the other containers on it reach
+/// it by its alias.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkAttachment {
+    pub network: String,
+    pub alias: String,
+}
 
 pub struct RunWrapper {
     docker: Arc<Docker>,
+    log_driver: LogDriver,
+    confinement: Option<Confinement>,
+    state_dir: Option<PathBuf>,
+    wait_for_capacity: Option<u64>,
+    host: Option<Host>,
+    ingress_bind_addrs: HashMap<u16, IpAddr>,
+    name: Option<String>,
+    network: Option<NetworkAttachment>,
     container_id: Option<String>,
     stream_task: Option<tokio::task::JoinHandle<()>>,
 }
 
 impl RunWrapper {
-    pub fn new() -> Result<Self> {
+    pub fn new(log_driver: LogDriver) -> Result<Self> {
         let docker_client = Arc::new(
             Docker::connect_with_local_defaults()
                 .map_err(|e| anyhow!("connecting to docker: {}", e))?,
@@ -22,70 +372,249 @@ impl RunWrapper {
 
         Ok(Self {
             docker: docker_client,
+            log_driver,
+            confinement: None,
+            state_dir: None,
+            wait_for_capacity: None,
+            host: None,
+            ingress_bind_addrs: HashMap::new(),
+            name: None,
+            network: None,
             container_id: None,
             stream_task: None,
         })
     }
 
-    pub async fn run_enclaver_image(
-        &mut self,
-        image_name: &str,
-        port_forwards: Vec<String>,
-        debug_mode: bool,
-    ) -> Result<()> {
-        if self.container_id.is_some() {
-            return Err(anyhow!("container already running"));
+    pub fn with_confinement(mut self, confinement: Option<Confinement>) -> Self {
+        self.confinement = confinement;
+        self
+    }
+
+    /// Mounts state_dir into the container for enclaver-run to keep the status
+    /// journal of the enclave in, where `enclaver ps` can find it.
+    pub fn with_state_dir(mut self, state_dir: Option<PathBuf>) -> Self {
+        self.state_dir = state_dir;
+        self
+    }
+
+    /// Has enclaver-run keep retrying for up to this many seconds while other enclaves
+    /// hold the CPUs or memory the enclave needs.
+    pub fn with_wait_for_capacity(mut self, seconds: Option<u64>) -> Self {
+        self.wait_for_capacity = seconds;
+        self
+    }
+
+    /// Gives the wrapper container the devices, tmpfs mounts and ulimits of the host
+    /// section of the manifest.
+    pub fn with_host(mut self, host: Option<Host>) -> Self {
+        self.host = host;
+        self
+    }
+
+    /// Publishes the ingress ports of the manifest, keyed by container port, on these
+    /// host addresses rather than on all of them, unless a port forward names its own.
+    pub fn with_ingress_bind_addrs(mut self, addrs: HashMap<u16, IpAddr>) -> Self {
+        self.ingress_bind_addrs = addrs;
+        self
+    }
+
+    /// Names the container, rather than letting docker pick a name.
+    pub fn with_name(mut self, name: Option<String>) -> Self {
+        self.name = name;
+        self
+    }
+
+    /// Attaches the container to a docker network instead of the default bridge.
+    pub fn with_network(mut self, network: Option<NetworkAttachment>) -> Self {
+        self.network = network;
+        self
+    }
+
+    fn host_addr(&self, spec_addr: Option<IpAddr>, container_port: u16) -> Option<IpAddr> {
+        spec_addr.or_else(|| self.ingress_bind_addrs.get(&container_port).copied())
+    }
+
+    fn host_config(&self, port_bindings: PortMap) -> HostConfig {
+        let base = match self.confinement {
+            Some(ref confinement) => confinement.host_config(),
+            None => HostConfig {
+                privileged: Some(true),
+                ..Default::default()
+            },
+        };
+        let resources = host_resources(self.host.as_ref());
+
+        HostConfig {
+            devices: resources.devices,
+            tmpfs: resources.tmpfs,
+            ulimits: resources.ulimits,
+            port_bindings: Some(port_bindings),
+            network_mode: self.network_mode(),
+            binds: self
+                .state_dir
+                .as_ref()
+                .map(|dir| vec![format!("{}:{DEFAULT_STATE_DIR}", dir.display())]),
+            ..base
         }
+    }
 
-        let port_re = regex::Regex::new(r"(\d+):(\d+)")?;
+    fn network_mode(&self) -> Option<String> {
+        self.network.as_ref().map(|network| network.network.clone())
+    }
 
-        let mut exposed_ports: HashMap<String, HashMap<(), ()>> = HashMap::new();
+    // The ports exposed by the container and their bindings on the host
+    fn port_bindings(&self, port_forwards: &[String]) -> Result<(ExposedPorts, PortMap)> {
+        let mut exposed_ports = ExposedPorts::new();
         let mut port_bindings = PortMap::new();
 
         for spec in port_forwards {
-            let captures = port_re.captures(&spec).ok_or_else(|| {
-                anyhow!(
-                    "port forward specification '{spec}' does not match the format 'host_port:container_port'",
-                )
-            })?;
-            let host_port = captures.get(1).unwrap().as_str();
-            let container_port = captures.get(2).unwrap().as_str();
+            let (host_addr, host_port, container_port) = parse_port_forward(spec)?;
             exposed_ports.insert(format!("{container_port}/tcp"), HashMap::new());
 
             port_bindings.insert(
                 format!("{container_port}/tcp"),
                 Some(vec![PortBinding {
                     host_port: Some(host_port.to_string()),
-                    host_ip: None,
+                    host_ip: self
+                        .host_addr(host_addr, container_port)
+                        .map(|addr| addr.to_string()),
                 }]),
             );
         }
 
+        Ok((exposed_ports, port_bindings))
+    }
+
+    /// Checks that the image exists and the published host ports are free.
+    pub async fn preflight(
+        &self,
+        image_name: &str,
+        port_forwards: &[String],
+    ) -> Result<Vec<Check>> {
+        self.docker
+            .inspect_image(image_name)
+            .await
+            .map_err(|e| anyhow!("inspecting image {image_name}: {e}"))?;
+
+        let mut checks = Vec::new();
+        for spec in port_forwards {
+            let (host_addr, host_port, container_port) = parse_port_forward(spec)?;
+            let host_addr = self
+                .host_addr(host_addr, container_port)
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+            checks.push(preflight::check_port(host_addr, host_port));
+        }
+
+        Ok(checks)
+    }
+
+    pub async fn run_enclaver_image(
+        &mut self,
+        image_name: &str,
+        port_forwards: Vec<String>,
+        debug_mode: bool,
+        dry_run: bool,
+    ) -> Result<()> {
+        self.start_enclaver_image(image_name, port_forwards, debug_mode, dry_run)
+            .await?;
+        self.wait().await
+    }
+
+    /// Starts the wrapper container of an Enclaver image, without waiting for it to
+    /// exit.
+    pub async fn start_enclaver_image(
+        &mut self,
+        image_name: &str,
+        port_forwards: Vec<String>,
+        debug_mode: bool,
+        dry_run: bool,
+    ) -> Result<()> {
+        let (exposed_ports, port_bindings) = self.port_bindings(&port_forwards)?;
+
+        // TODO(russell_h): pass through additional args
+        let mut cmd = Vec::new();
+        if debug_mode {
+            cmd.push("--debug-mode".to_string());
+        }
+        if dry_run {
+            cmd.push("--dry-run".to_string());
+        }
+        if self.state_dir.is_some() {
+            cmd.push(format!("--state-dir={DEFAULT_STATE_DIR}"));
+        }
+        if let Some(seconds) = self.wait_for_capacity {
+            cmd.push(format!("--wait-for-capacity={seconds}"));
+        }
+        // Docker publishes the ports from the container's own interface, so the ingress
+        // proxies listen on all of them and the host address is limited by the binding
+        cmd.push("--ingress-bind-addr=0.0.0.0".to_string());
+
+        self.start_container(Config {
+            image: Some(image_name.to_string()),
+            cmd: Some(cmd),
+            attach_stderr: Some(true),
+            attach_stdout: Some(true),
+            host_config: Some(self.host_config(port_bindings)),
+            exposed_ports: Some(exposed_ports),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Starts a plain container of any image, e.g. a database that enclaves use. It
+    /// gets none of the devices and privileges of a wrapper container.
+    pub async fn start_service_image(
+        &mut self,
+        image_name: &str,
+        port_forwards: Vec<String>,
+        command: Option<Vec<String>>,
+        env: Vec<String>,
+    ) -> Result<()> {
+        let (exposed_ports, port_bindings) = self.port_bindings(&port_forwards)?;
+
+        self.start_container(Config {
+            image: Some(image_name.to_string()),
+            cmd: command,
+            env: Some(env),
+            attach_stderr: Some(true),
+            attach_stdout: Some(true),
+            host_config: Some(HostConfig {
+                port_bindings: Some(port_bindings),
+                network_mode: self.network_mode(),
+                ..Default::default()
+            }),
+            exposed_ports: Some(exposed_ports),
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn start_container(&mut self, config: Config<String>) -> Result<()> {
+        if self.container_id.is_some() {
+            return Err(anyhow!("container already running"));
+        }
+
+        let options = self.name.as_ref().map(|name| CreateContainerOptions {
+            name: name.clone(),
+            platform: None,
+        });
+        let networking_config = self.network.as_ref().map(|network| NetworkingConfig {
+            endpoints_config: HashMap::from([(
+                network.network.clone(),
+                EndpointSettings {
+                    aliases: Some(vec![network.alias.clone()]),
+                    ..Default::default()
+                },
+            )]),
+        });
+
         let container_id = self
             .docker
-            .create_container::<String, String>(
-                None,
+            .create_container(
+                options,
                 Config {
-                    image: Some(image_name.to_string()),
-                    cmd: match debug_mode {
-                        // TODO(russell_h): pass through additional args
-                        true => Some(vec!["--debug-mode".into()]),
-                        false => None,
-                    },
-                    attach_stderr: Some(true),
-                    attach_stdout: Some(true),
-                    host_config: Some(HostConfig {
-                        devices: Some(vec![DeviceMapping {
-                            path_on_host: Some(String::from("/dev/nitro_enclaves")),
-                            path_in_container: Some(String::from("/dev/nitro_enclaves")),
-                            cgroup_permissions: Some(String::from("rwm")),
-                        }]),
-                        port_bindings: Some(port_bindings),
-                        privileged: Some(true),
-                        ..Default::default()
-                    }),
-                    exposed_ports: Some(exposed_ports),
-                    ..Default::default()
+                    networking_config,
+                    ..config
                 },
             )
             .await?
@@ -97,7 +626,16 @@ impl RunWrapper {
             .start_container::<String>(&container_id, None)
             .await?;
 
-        self.start_output_stream_task(container_id.clone()).await?;
+        self.start_output_stream_task(container_id).await
+    }
+
+    /// Waits for the started container to exit, and removes it if it exited
+    /// successfully.
+    pub async fn wait(&mut self) -> Result<()> {
+        let container_id = self
+            .container_id
+            .clone()
+            .ok_or_else(|| anyhow!("no container running"))?;
 
         let status_code = self
             .docker
@@ -121,8 +659,7 @@ impl RunWrapper {
     }
 
     async fn start_output_stream_task(&mut self, container_id: String) -> Result<()> {
-        let mut stdout = tokio::io::stdout();
-        let mut stderr = tokio::io::stderr();
+        let mut sink = LogSink::open(&self.log_driver).await?;
 
         let mut log_stream = self.docker.logs::<String>(
             &container_id,
@@ -135,11 +672,27 @@ impl RunWrapper {
         );
 
         self.stream_task = Some(tokio::task::spawn(async move {
+            // A sink that fails tends to fail for every chunk, so only the first
+            // failure is logged until a write goes through again
+            let mut failing = false;
             while let Some(Ok(item)) = log_stream.next().await {
-                match item {
-                    LogOutput::StdOut { message } => stdout.write_all(&message).await.unwrap(),
-                    LogOutput::StdErr { message } => stderr.write_all(&message).await.unwrap(),
-                    _ => {}
+                match sink.write(item).await {
+                    Ok(()) if failing => {
+                        info!("writing container output again");
+                        failing = false;
+                    }
+                    Ok(()) => {}
+                    Err(err) if !failing => {
+                        error!("failed to write container output: {err}");
+                        failing = true;
+                    }
+                    Err(_) => {}
+                }
+            }
+
+            if let Err(err) = sink.finish().await {
+                if !failing {
+                    error!("failed to write container output: {err}");
                 }
             }
         }));
@@ -161,3 +714,115 @@ impl RunWrapper {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        host_resources, lines, parse_port_forward, Confinement, LineBuffer, MAX_PARTIAL_LINE,
+        SECCOMP_PROFILE,
+    };
+    use crate::manifest::{Host, HostTmpfs, HostUlimit};
+
+    #[test]
+    fn test_lines() {
+        let data = b"first\nsecond\n\nthird";
+        let actual: Vec<&[u8]> = lines(data).collect();
+        assert_eq!(actual, vec![&b"first"[..], b"second", b"third"]);
+    }
+
+    #[test]
+    fn test_line_buffer() {
+        let mut buffer = LineBuffer::default();
+        assert_eq!(buffer.push(b"fir"), Vec::<Vec<u8>>::new());
+        assert_eq!(buffer.push(b"st\nsec"), vec![b"first".to_vec()]);
+        assert_eq!(
+            buffer.push(b"ond\n\nthird\nfou"),
+            vec![b"second".to_vec(), b"third".to_vec()]
+        );
+        assert_eq!(buffer.finish(), Some(b"fou".to_vec()));
+        assert_eq!(buffer.finish(), None);
+
+        let long = vec![b'x'; MAX_PARTIAL_LINE + 1];
+        assert_eq!(buffer.push(&long), vec![long.clone()]);
+        assert_eq!(buffer.finish(), None);
+    }
+
+    #[test]
+    fn test_parse_port_forward() {
+        assert_eq!(parse_port_forward("8080:80").unwrap(), (None, 8080, 80));
+        assert_eq!(parse_port_forward("8080:80/tcp").unwrap(), (None, 8080, 80));
+        assert_eq!(
+            parse_port_forward("127.0.0.1:8080:80").unwrap(),
+            (Some("127.0.0.1".parse().unwrap()), 8080, 80)
+        );
+        assert_eq!(
+            parse_port_forward("[::1]:8080:80").unwrap(),
+            (Some("::1".parse().unwrap()), 8080, 80)
+        );
+        assert!(parse_port_forward("8080").is_err());
+        assert!(parse_port_forward("99999:80").is_err());
+        assert!(parse_port_forward("localhost:8080:80").is_err());
+        assert!(parse_port_forward("8080:80:90").is_err());
+    }
+
+    #[test]
+    fn test_confinement() {
+        let profile: serde_json::Value = serde_json::from_str(SECCOMP_PROFILE).unwrap();
+        assert!(profile["syscalls"][0]["names"]
+            .as_array()
+            .unwrap()
+            .contains(&"ptrace".into()));
+
+        let host_config = Confinement {
+            selinux_type: Some("enclaver.process".to_string()),
+        }
+        .host_config();
+
+        assert_eq!(host_config.privileged, Some(false));
+        let security_opt = host_config.security_opt.unwrap();
+        assert!(security_opt.contains(&"no-new-privileges".to_string()));
+        assert!(security_opt.contains(&"label=type:enclaver.process".to_string()));
+    }
+
+    #[test]
+    fn test_host_resources() {
+        let nitro = host_resources(None).devices.unwrap();
+        assert_eq!(nitro.len(), 1);
+        assert_eq!(
+            nitro[0].path_on_host.as_deref(),
+            Some("/dev/nitro_enclaves")
+        );
+
+        let host = Host {
+            devices: Some(vec!["/dev/sgx_enclave".to_string()]),
+            tmpfs: Some(vec![
+                HostTmpfs {
+                    path: "/dev/hugepages".to_string(),
+                    size_mb: Some(1024),
+                },
+                HostTmpfs {
+                    path: "/scratch".to_string(),
+                    size_mb: None,
+                },
+            ]),
+            ulimits: Some(vec![HostUlimit {
+                name: "memlock".to_string(),
+                soft: -1,
+                hard: -1,
+            }]),
+        };
+        let resources = host_resources(Some(&host));
+
+        let devices = resources.devices.unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(
+            devices[1].path_in_container.as_deref(),
+            Some("/dev/sgx_enclave")
+        );
+        assert_eq!(devices[1].cgroup_permissions.as_deref(), Some("rwm"));
+        let tmpfs = resources.tmpfs.unwrap();
+        assert_eq!(tmpfs["/dev/hugepages"], "size=1024m");
+        assert_eq!(tmpfs["/scratch"], "");
+        assert_eq!(resources.ulimits.unwrap()[0].soft, Some(-1));
+    }
+}
@@ -0,0 +1,247 @@
+use std::ffi::CString;
+use std::os::unix::fs::MetadataExt;
+
+use anyhow::{anyhow, Result};
+use log::info;
+use nix::libc;
+use nix::unistd::{self, Gid, Uid, User};
+
+const NITRO_ENCLAVES_DEVICE: &str = "/dev/nitro_enclaves";
+
+// From linux/audit.h, identifying the syscall ABI in seccomp_data.arch
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// Syscall numbers with this bit set use the x32 ABI, which shares AUDIT_ARCH_X86_64
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Offsets into struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+// Nothing the supervisor does once the enclave is up: it proxies bytes, serves
+// metrics and runs nitro-cli to describe the enclave. Privilege changes are in
+// the list so a compromised proxy cannot undo the drop.
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_adjtimex,
+    libc::SYS_bpf,
+    libc::SYS_chroot,
+    libc::SYS_clock_adjtime,
+    libc::SYS_clock_settime,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_init_module,
+    libc::SYS_kexec_file_load,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_mount,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_setfsgid,
+    libc::SYS_setfsuid,
+    libc::SYS_setgid,
+    libc::SYS_setgroups,
+    libc::SYS_setns,
+    libc::SYS_setregid,
+    libc::SYS_setresgid,
+    libc::SYS_setresuid,
+    libc::SYS_setreuid,
+    libc::SYS_settimeofday,
+    libc::SYS_setuid,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+];
+
+/// Switches the whole process to user, keeping access to /dev/nitro_enclaves
+/// through its group so nitro-cli can still describe the running enclave.
+pub fn drop_privileges(user: &str) -> Result<()> {
+    let user = User::from_name(user)?.ok_or_else(|| anyhow!("no such user {user}"))?;
+    if user.uid.is_root() {
+        return Err(anyhow!("refusing to drop privileges to root"));
+    }
+
+    let name = CString::new(user.name.as_str())?;
+    let mut groups = unistd::getgrouplist(&name, user.gid)?;
+    if let Ok(device) = std::fs::metadata(NITRO_ENCLAVES_DEVICE) {
+        let gid = Gid::from_raw(device.gid());
+        if gid.as_raw() != 0 && !groups.contains(&gid) {
+            groups.push(gid);
+        }
+    }
+
+    // Groups first, while we still have the privilege to change them
+    unistd::setgroups(&groups)?;
+    unistd::setgid(user.gid)?;
+    unistd::setuid(user.uid)?;
+
+    if unistd::setuid(Uid::from_raw(0)).is_ok() {
+        return Err(anyhow!(
+            "still able to regain root after dropping privileges"
+        ));
+    }
+
+    info!("dropped privileges to {} ({})", user.name, user.uid);
+    Ok(())
+}
+
+/// Denies DENIED_SYSCALLS with EPERM, on every thread of the process
+pub fn apply_seccomp() -> Result<()> {
+    let mut filter = seccomp_filter();
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+
+    // Required to install a filter without CAP_SYS_ADMIN
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(anyhow!(
+            "failed to set no_new_privs: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog as *const libc::sock_fprog,
+        )
+    };
+
+    // With TSYNC, a positive result is the ID of a thread that could not be synced
+    match res {
+        0 => {
+            info!("applied seccomp filter");
+            Ok(())
+        }
+        tid if tid > 0 => Err(anyhow!("failed to apply seccomp filter to thread {tid}")),
+        _ => Err(anyhow!(
+            "failed to apply seccomp filter: {}",
+            std::io::Error::last_os_error()
+        )),
+    }
+}
+
+fn seccomp_filter() -> Vec<libc::sock_filter> {
+    let stmt = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |code: u32, k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+
+    let denied = DENIED_SYSCALLS.len() as u8;
+    let mut filter = vec![
+        // Any other ABI would need its own syscall numbers
+        stmt(
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            SECCOMP_DATA_ARCH,
+        ),
+        jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            AUDIT_ARCH,
+            1,
+            0,
+        ),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+        jump(
+            libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+            X32_SYSCALL_BIT,
+            denied + 1,
+            0,
+        ),
+    ];
+
+    for (i, nr) in DENIED_SYSCALLS.iter().enumerate() {
+        filter.push(jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            *nr as u32,
+            denied - i as u8,
+            0,
+        ));
+    }
+
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+    filter.push(stmt(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+    ));
+
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{seccomp_filter, DENIED_SYSCALLS};
+    use assert2::assert;
+    use nix::libc;
+
+    // Runs the filter the way the kernel would, for the syscall nr
+    fn evaluate(filter: &[libc::sock_filter], arch: u32, nr: u32) -> u32 {
+        let mut acc = 0;
+        let mut pc = 0;
+
+        loop {
+            let ins = &filter[pc];
+            let code = ins.code as u32;
+            pc += 1;
+
+            match code & 0x07 {
+                c if c == libc::BPF_LD => acc = if ins.k == 0 { nr } else { arch },
+                c if c == libc::BPF_RET => return ins.k,
+                c if c == libc::BPF_JMP => {
+                    let taken = match code & 0xf0 {
+                        op if op == libc::BPF_JEQ => acc == ins.k,
+                        op if op == libc::BPF_JGE => acc >= ins.k,
+                        _ => unreachable!(),
+                    };
+                    pc += if taken { ins.jt } else { ins.jf } as usize;
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_seccomp_filter() {
+        let filter = seccomp_filter();
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+        for nr in DENIED_SYSCALLS {
+            assert!(evaluate(&filter, super::AUDIT_ARCH, *nr as u32) == deny);
+        }
+
+        for nr in [libc::SYS_read, libc::SYS_write, libc::SYS_socket] {
+            assert!(evaluate(&filter, super::AUDIT_ARCH, nr as u32) == libc::SECCOMP_RET_ALLOW);
+        }
+
+        assert!(evaluate(&filter, super::AUDIT_ARCH, 0x4000_0000) == deny);
+        assert!(evaluate(&filter, 0x4000_0003, 0) == libc::SECCOMP_RET_KILL_PROCESS);
+    }
+}
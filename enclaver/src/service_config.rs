@@ -0,0 +1,195 @@
+//! The effective configuration of the services odyn runs, once the boot config has
+//! been applied. Attestations carry a digest of it next to that of the manifest, so
+//! that a verifier knows not only which image produced an attestation, but also which
+//! proxies ran in it and whether its console was on. A verifier recomputes the digest
+//! from its own copy of the manifest and compares.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::manifest::Manifest;
+
+/// Names the default egress proxy among egress_proxies
+pub const DEFAULT_EGRESS_PROXY: &str = "default";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceConfig {
+    /// Whether the output of the application may leave the enclave
+    pub console: bool,
+
+    /// Whether the image was built to run in debug mode
+    pub debug: bool,
+
+    /// What odyn listens on inside the enclave, other than the default egress
+    /// proxy, whose port odyn may pick, ordered by port
+    pub listeners: Vec<Listener>,
+
+    /// The egress proxies that run, the default one first, then the named ones
+    pub egress_proxies: Vec<String>,
+
+    /// Whether odyn answers DNS queries for the allowed names
+    pub egress_dns: bool,
+
+    /// Ports of the UDP relays, ordered
+    pub egress_udp: Vec<u16>,
+
+    /// Hex encoded SHA-256 of the egress policy, see policy_hash
+    pub policy_hash: Option<String>,
+
+    /// Whether the egress policy can be narrowed by signed policy updates
+    pub policy_updates: bool,
+
+    /// Whether odyn obtains an SVID for the enclave
+    pub spiffe: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Listener {
+    pub port: u16,
+    pub name: String,
+}
+
+impl ServiceConfig {
+    /// The services odyn runs for the manifest, with its console on or off. The
+    /// manifest is the one in the image, or in debug mode the one the debug overrides
+    /// of the boot config made of it.
+    pub fn new(manifest: &Manifest, console: bool) -> Self {
+        let mut listeners: Vec<Listener> = manifest
+            .listen_ports()
+            .into_iter()
+            .map(|(port, name)| Listener { port, name })
+            .collect();
+        listeners.sort_by(|a, b| (a.port, &a.name).cmp(&(b.port, &b.name)));
+
+        let egress = manifest.egress.as_ref();
+        let egress_proxies = egress
+            .filter(|egress| egress.is_enabled())
+            .map(|_| DEFAULT_EGRESS_PROXY.to_string())
+            .into_iter()
+            .chain(manifest.egress_proxies().map(|proxy| proxy.name.clone()))
+            .collect();
+
+        let mut egress_udp: Vec<u16> = manifest.egress_udp().map(|rule| rule.local_port).collect();
+        egress_udp.sort();
+
+        Self {
+            console,
+            debug: manifest.is_debug(),
+            listeners,
+            egress_proxies,
+            egress_dns: egress.is_some_and(|egress| egress.is_dns_enabled()),
+            egress_udp,
+            policy_hash: policy_hash(manifest),
+            policy_updates: egress.is_some_and(|egress| egress.policy_signing_key.is_some()),
+            spiffe: manifest.spiffe.is_some(),
+        }
+    }
+
+    /// The canonical form the digest is taken of: the configuration as JSON without
+    /// whitespace, with the fields in the order above
+    pub fn to_canonical_json(&self) -> Vec<u8> {
+        // Serializing plain strings, numbers and lists cannot fail
+        serde_json::to_vec(self).unwrap()
+    }
+
+    /// Hex encoded SHA-256 of the canonical form, as carried in the attestation
+    /// user_data under "services"
+    pub fn digest(&self) -> String {
+        hex_sha256(&self.to_canonical_json())
+    }
+}
+
+/// Hex encoded SHA-256 of the manifest file as bundled in the image, as carried in
+/// the attestation user_data under "manifest". The bundled file is an exact copy of
+/// the one the image was built from.
+pub fn manifest_digest(raw: &[u8]) -> String {
+    hex_sha256(raw)
+}
+
+/// Hex encoded SHA-256 of the policy hook module, as carried in the attestation
+/// user_data under "policy_hook"
+pub fn policy_hook_digest(wasm: &[u8]) -> String {
+    hex_sha256(wasm)
+}
+
+/// Hex encoded SHA-256 of the egress section of the manifest as JSON, unset if
+/// egress is disabled. Policy updates applied later are not reflected.
+pub fn policy_hash(manifest: &Manifest) -> Option<String> {
+    let egress = manifest.egress.as_ref()?;
+    // Serializing the manifest types cannot fail
+    Some(hex_sha256(&serde_json::to_vec(egress).unwrap()))
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{manifest_digest, ServiceConfig};
+    use crate::manifest::Manifest;
+    use assert2::assert;
+
+    fn manifest(extra: &str) -> Manifest {
+        let raw = format!(
+            r#"
+version: v1
+name: "test"
+target: "test:latest"
+sources:
+  app: "app:latest"
+ingress:
+  - listen_port: 8080
+api:
+  listen_port: 9000
+{extra}
+"#
+        );
+        serde_yaml::from_str(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_service_config() {
+        let egress = r#"egress:
+  allow: ["*.example.com"]
+  dns: true
+  proxies:
+    - { name: metrics, proxy_port: 10002, allow: ["**"] }
+"#;
+        let config = ServiceConfig::new(&manifest(egress), true);
+        assert!(config.egress_proxies == ["default", "metrics"]);
+        assert!(config.egress_dns);
+        assert!(!config.policy_updates);
+        assert!(config.policy_hash.is_some());
+        let ports: Vec<u16> = config.listeners.iter().map(|l| l.port).collect();
+        assert!(ports == [8080, 9000, 10002]);
+
+        // Same configuration, same digest
+        assert!(config.digest() == ServiceConfig::new(&manifest(egress), true).digest());
+        assert!(config.digest().len() == 64);
+
+        // Anything that changes what runs changes the digest
+        for other in [
+            ServiceConfig::new(&manifest(egress), false),
+            ServiceConfig::new(&manifest(""), true),
+            ServiceConfig::new(&manifest(&egress.replace("dns: true", "dns: false")), true),
+        ] {
+            assert!(other.digest() != config.digest());
+        }
+
+        let config = ServiceConfig::new(&manifest(""), true);
+        assert!(config.egress_proxies.is_empty());
+        assert!(config.policy_hash.is_none());
+    }
+
+    #[test]
+    fn test_manifest_digest() {
+        assert!(
+            manifest_digest(b"")
+                == "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
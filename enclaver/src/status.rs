@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+/// Why odyn gave up on the entrypoint, sent along with a fatal status on the status
+/// port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FatalCode {
+    /// The manifest, boot config or runtime config could not be loaded or applied
+    ConfigError,
+
+    /// The enclave could not be initialized, or its attested config and secrets
+    /// could not be fetched
+    BootstrapFailed,
+
+    /// One of the proxies or the API server inside the enclave failed to start
+    ServiceStartFailed,
+
+    /// The entrypoint could not be started
+    EntrypointSpawnFailed,
+
+    /// A code of a newer odyn
+    #[serde(other)]
+    Unknown,
+}
+
+impl std::fmt::Display for FatalCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = match self {
+            Self::ConfigError => "config_error",
+            Self::BootstrapFailed => "bootstrap_failed",
+            Self::ServiceStartFailed => "service_start_failed",
+            Self::EntrypointSpawnFailed => "entrypoint_spawn_failed",
+            Self::Unknown => "unknown",
+        };
+        f.write_str(code)
+    }
+}
+
+/// What odyn and its proxies hold of the memory of the enclave, sent along with a
+/// running status on the status port
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryStats {
+    /// Bytes odyn has allocated on its heap
+    pub heap_bytes: u64,
+
+    /// Most bytes odyn has had allocated at once since it started
+    pub heap_peak_bytes: u64,
+
+    /// Bytes reserved for the copy buffers of ingress streams, part of heap_bytes
+    pub buffer_bytes: u64,
+
+    /// Most bytes the copy buffers may take, if capped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffer_limit_bytes: Option<u64>,
+}
+
+/// How much the application has logged and how much of it reached the subscribers of
+/// the log port, sent along with a running status on the status port
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogStats {
+    /// Bytes the application has written to its stdout and stderr
+    pub produced_bytes: u64,
+
+    /// Bytes trimmed from the head of the log to make room for newer ones
+    pub trimmed_bytes: u64,
+
+    /// Bytes sent to subscribers, summed over all of them, those gone included
+    pub delivered_bytes: u64,
+
+    /// Bytes trimmed before a subscriber got to them, summed over all of them
+    pub skipped_bytes: u64,
+
+    /// The subscribers connected now
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subscribers: Vec<LogSubscriberStats>,
+}
+
+/// What one subscriber of the log port has been sent and has missed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogSubscriberStats {
+    /// Number of the subscriber, in the order they connected since odyn started
+    pub id: u64,
+
+    pub delivered_bytes: u64,
+
+    pub skipped_bytes: u64,
+}
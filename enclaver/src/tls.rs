@@ -1,11 +1,25 @@
 use anyhow::{anyhow, Result};
+use hyper_rustls::ConfigBuilderExt;
 use log::info;
+use rsa::PaddingScheme;
 use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier,
+    ClientHello, ResolvesServerCert,
+};
+use rustls::sign::{any_supported_type, CertifiedKey};
 use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::Item;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
-use std::sync::Arc;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::attestation::{AttestationVerifier, ATTESTATION_EXTENSION_OID};
+use crate::der;
+use crate::keypair::KeyPair;
 
 fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
     rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
@@ -39,6 +53,328 @@ pub fn load_server_config(
     ))
 }
 
+/// Like load_server_config, but asks clients for a certificate that chains to the
+/// PEM encoded CA certificates in client_ca. With require_client_auth, clients
+/// without one are turned away, otherwise they may still connect anonymously.
+pub fn load_mtls_server_config(
+    key: impl AsRef<Path>,
+    cert: impl AsRef<Path>,
+    client_ca: impl AsRef<Path>,
+    require_client_auth: bool,
+) -> Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert.as_ref())?;
+    let mut keys = load_keys(key.as_ref())?;
+
+    Ok(Arc::new(
+        rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(client_verifier(client_ca.as_ref(), require_client_auth)?)
+            .with_single_cert(certs, keys.remove(0))?,
+    ))
+}
+
+/// Like load_server_config, but presents clients that ask for one of the server names
+/// of sni_certs, given as (server name, key, cert), the certificate loaded for that
+/// name, and all other clients the one of key and cert. client_auth is the client CA
+/// and whether clients must present a certificate, as for load_mtls_server_config.
+pub fn load_sni_server_config(
+    key: impl AsRef<Path>,
+    cert: impl AsRef<Path>,
+    sni_certs: &[(String, PathBuf, PathBuf)],
+    client_auth: Option<(&Path, bool)>,
+) -> Result<Arc<ServerConfig>> {
+    let mut by_name = HashMap::new();
+    for (name, key, cert) in sni_certs {
+        by_name.insert(name.to_ascii_lowercase(), load_certified_key(key, cert)?);
+    }
+    let resolver = Arc::new(SniResolver {
+        default: load_certified_key(key.as_ref(), cert.as_ref())?,
+        by_name,
+    });
+
+    resolver_server_config(resolver, client_auth)
+}
+
+/// A server config that presents whatever certificate was last set on cert, with
+/// client_auth as for load_sni_server_config
+pub fn renewed_server_config(
+    cert: RenewedCertificate,
+    client_auth: Option<(&Path, bool)>,
+) -> Result<Arc<ServerConfig>> {
+    resolver_server_config(Arc::new(cert), client_auth)
+}
+
+fn resolver_server_config(
+    resolver: Arc<dyn ResolvesServerCert>,
+    client_auth: Option<(&Path, bool)>,
+) -> Result<Arc<ServerConfig>> {
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+    let config = match client_auth {
+        Some((client_ca, required)) => builder
+            .with_client_cert_verifier(client_verifier(client_ca, required)?)
+            .with_cert_resolver(resolver),
+        None => builder.with_no_client_auth().with_cert_resolver(resolver),
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// The server config offering clients the ALPN protocols, in order of preference
+pub fn with_alpn(config: Arc<ServerConfig>, protocols: &[String]) -> Arc<ServerConfig> {
+    if protocols.is_empty() {
+        return config;
+    }
+
+    let mut config = Arc::try_unwrap(config).unwrap_or_else(|config| (*config).clone());
+    config.alpn_protocols = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+    Arc::new(config)
+}
+
+fn client_verifier(client_ca: &Path, required: bool) -> Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for ca in read_certs(&mut BufReader::new(File::open(client_ca)?))? {
+        roots.add(&ca)?;
+    }
+    if roots.is_empty() {
+        return Err(anyhow!("no client CA certificates"));
+    }
+
+    Ok(match required {
+        true => AllowAnyAuthenticatedClient::new(roots).boxed(),
+        false => AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+    })
+}
+
+fn load_certified_key(key: &Path, cert: &Path) -> Result<Arc<CertifiedKey>> {
+    let certs = load_certs(cert)?;
+    let signing_key = match load_keys(key)?.first() {
+        Some(der) => any_supported_type(der)?,
+        None => return Err(anyhow!("no key in {}", key.display())),
+    };
+
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
+
+// Picks the certificate by the server name the client asks for, falling back to the
+// default for clients that ask for none or another
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let named = client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()));
+        Some(named.unwrap_or(&self.default).clone())
+    }
+}
+
+/// A certificate that is replaced while listeners present it, e.g. an attested
+/// certificate as it is renewed. Handshakes fail until the first one is set.
+#[derive(Clone, Default)]
+pub struct RenewedCertificate {
+    current: Arc<RwLock<Option<Arc<CertifiedKey>>>>,
+}
+
+impl RenewedCertificate {
+    pub fn set(&self, keypair: &KeyPair, cert: Certificate) -> Result<()> {
+        self.set_chain(keypair, vec![cert])
+    }
+
+    /// Sets a certificate along with the intermediates that chain it to its CA
+    pub fn set_chain(&self, keypair: &KeyPair, chain: Vec<Certificate>) -> Result<()> {
+        let signing_key = any_supported_type(&PrivateKey(keypair.private_key_as_der()?))?;
+        let certified = CertifiedKey::new(chain, signing_key);
+        *self.current.write().unwrap() = Some(Arc::new(certified));
+        Ok(())
+    }
+
+    /// Sets the certificates of the PEM encoded file cert, for the PEM encoded key held
+    /// in memory rather than in a file
+    pub fn set_pem_key(&self, key: &[u8], cert: impl AsRef<Path>) -> Result<()> {
+        let signing_key = any_supported_type(&read_private_key(&mut &key[..])?)?;
+        let certified = CertifiedKey::new(load_certs(cert.as_ref())?, signing_key);
+        *self.current.write().unwrap() = Some(Arc::new(certified));
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for RenewedCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// A self-signed certificate for the key of keypair, with the attestation document
+/// that binds the key in the extension of ATTESTATION_EXTENSION_OID, valid for
+/// lifetime seconds from now, a Unix time
+pub fn attested_certificate(
+    keypair: &KeyPair,
+    common_name: &str,
+    attestation: &[u8],
+    now: u64,
+    lifetime: u64,
+) -> Result<Certificate> {
+    let public_key = keypair.public_key_as_der()?;
+    let name = subject_name(common_name);
+
+    // Positive, of a fixed length, and different for every key
+    let mut serial = Sha256::digest(&public_key)[..16].to_vec();
+    serial[0] = (serial[0] & 0x3f) | 0x40;
+
+    let extension = der::sequence(&[
+        &der::encode(der::OID, ATTESTATION_EXTENSION_OID),
+        &der::encode(der::OCTET_STRING, attestation),
+    ]);
+    let tbs = der::sequence(&[
+        &der::encode(der::VERSION, &der::encode(der::INTEGER, &[2])),
+        &der::encode(der::INTEGER, &serial),
+        &signature_algorithm(),
+        &name,
+        &der::sequence(&[&utc_time(now), &utc_time(now + lifetime)]),
+        &name,
+        &public_key,
+        &der::encode(der::EXTENSIONS, &der::sequence(&[&extension])),
+    ]);
+
+    Ok(Certificate(sign(keypair, &tbs)?))
+}
+
+/// A PKCS#10 certificate signing request for the key of keypair, for a certificate
+/// with the first of dns_names as its common name and all of them as subject
+/// alternative names. It also asks for the attestation document that binds the key,
+/// in the extension of ATTESTATION_EXTENSION_OID, so the CA can check that the key
+/// was generated in the enclave.
+pub fn certificate_request(
+    keypair: &KeyPair,
+    dns_names: &[String],
+    attestation: &[u8],
+) -> Result<Vec<u8>> {
+    let common_name = dns_names
+        .first()
+        .ok_or_else(|| anyhow!("a certificate request needs a DNS name"))?;
+
+    let names: Vec<Vec<u8>> = dns_names
+        .iter()
+        .map(|name| der::encode(der::DNS_NAME, name.as_bytes()))
+        .collect();
+    let names: Vec<&[u8]> = names.iter().map(Vec::as_slice).collect();
+    let extensions = der::sequence(&[
+        &der::sequence(&[
+            &der::encode(der::OID, OID_SUBJECT_ALT_NAME),
+            &der::encode(der::OCTET_STRING, &der::sequence(&names)),
+        ]),
+        &der::sequence(&[
+            &der::encode(der::OID, ATTESTATION_EXTENSION_OID),
+            &der::encode(der::OCTET_STRING, attestation),
+        ]),
+    ]);
+    let extension_request = der::sequence(&[
+        &der::encode(der::OID, OID_EXTENSION_REQUEST),
+        &der::encode(der::SET, &extensions),
+    ]);
+
+    let info = der::sequence(&[
+        &der::encode(der::INTEGER, &[0]),
+        &subject_name(common_name),
+        &keypair.public_key_as_der()?,
+        &der::encode(der::ATTRIBUTES, &extension_request),
+    ]);
+
+    sign(keypair, &info)
+}
+
+/// The certificates of a PEM encoded chain issued for the key of keypair, the one
+/// for the key first
+pub fn read_issued_chain(keypair: &KeyPair, pem: &[u8]) -> Result<Vec<Certificate>> {
+    let chain = read_certs(&mut BufReader::new(pem))?;
+    let leaf = chain
+        .first()
+        .ok_or_else(|| anyhow!("no certificate in the chain"))?;
+
+    if certificate_public_key(&leaf.0) != Some(keypair.public_key_as_der()?.as_slice()) {
+        return Err(anyhow!(
+            "the first certificate of the chain is not for the key of the enclave"
+        ));
+    }
+
+    Ok(chain)
+}
+
+/// PEM encodes der, e.g. as a CERTIFICATE REQUEST
+pub fn pem_encode(label: &str, der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+fn signature_algorithm() -> Vec<u8> {
+    der::sequence(&[
+        &der::encode(der::OID, OID_SHA256_WITH_RSA),
+        &der::encode(der::NULL, &[]),
+    ])
+}
+
+fn subject_name(common_name: &str) -> Vec<u8> {
+    der::sequence(&[&der::encode(
+        der::SET,
+        &der::sequence(&[
+            &der::encode(der::OID, OID_COMMON_NAME),
+            &der::encode(der::UTF8_STRING, common_name.as_bytes()),
+        ]),
+    )])
+}
+
+// The signed structure of tbs, a certificate or a certificate request
+fn sign(keypair: &KeyPair, tbs: &[u8]) -> Result<Vec<u8>> {
+    let signature = keypair.private.sign(
+        PaddingScheme::new_pkcs1v15_sign::<Sha256>(),
+        &Sha256::digest(tbs),
+    )?;
+
+    // Preceded by the count of unused bits
+    let mut bits = vec![0];
+    bits.extend(signature);
+
+    Ok(der::sequence(&[
+        tbs,
+        &signature_algorithm(),
+        &der::encode(der::BIT_STRING, &bits),
+    ]))
+}
+
+// A UTCTime, YYMMDDHHMMSSZ, which certificates use for times before 2050
+fn utc_time(unix: u64) -> Vec<u8> {
+    let (days, secs) = ((unix / 86400) as i64, unix % 86400);
+
+    // The civil date of days since the epoch, in eras of 400 years from March 1st
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let time = format!(
+        "{:02}{month:02}{day:02}{:02}{:02}{:02}Z",
+        year % 100,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    der::encode(der::UTC_TIME, time.as_bytes())
+}
+
 pub fn load_client_config(cert: impl AsRef<Path>) -> Result<Arc<ClientConfig>> {
     let mut roots = RootCertStore::empty();
     let certs = load_certs(cert.as_ref())?;
@@ -52,6 +388,116 @@ pub fn load_client_config(cert: impl AsRef<Path>) -> Result<Arc<ClientConfig>> {
     ))
 }
 
+/// A client config that presents a certificate chain and its private key, both PEM
+/// encoded, and trusts the PEM encoded CA certificates in roots, or the webpki roots
+/// if there are none. The key may be PKCS#8, PKCS#1 RSA or SEC1 EC.
+pub fn load_client_auth_config(
+    certs: &[u8],
+    key: &[u8],
+    roots: Option<&[u8]>,
+) -> Result<Arc<ClientConfig>> {
+    let certs = read_certs(&mut &certs[..])?;
+    if certs.is_empty() {
+        return Err(anyhow!("no client certificate"));
+    }
+    let key = read_private_key(&mut &key[..])?;
+
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let builder = match roots {
+        Some(roots) => {
+            let mut store = RootCertStore::empty();
+            for cert in read_certs(&mut &roots[..])? {
+                store.add(&cert)?;
+            }
+            if store.is_empty() {
+                return Err(anyhow!("no CA certificates"));
+            }
+            builder.with_root_certificates(store)
+        }
+        None => builder.with_webpki_roots(),
+    };
+
+    Ok(Arc::new(builder.with_client_auth_cert(certs, key)?))
+}
+
+fn read_certs(pem: &mut dyn BufRead) -> Result<Vec<Certificate>> {
+    Ok(rustls_pemfile::read_all(pem)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect())
+}
+
+fn read_private_key(pem: &mut dyn BufRead) -> Result<PrivateKey> {
+    rustls_pemfile::read_all(pem)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no private key"))
+}
+
+/// The common name of the subject of a DER encoded certificate, if it has one
+pub fn common_name(cert: &[u8]) -> Option<String> {
+    // Certificate, then TBSCertificate
+    let (_, cert, _) = der::next(cert)?;
+    let (_, tbs, _) = der::next(cert)?;
+
+    // The version is optional, the serial number, signature algorithm, issuer and
+    // validity come before the subject
+    let (tag, _, mut rest) = der::next(tbs)?;
+    if tag == der::VERSION {
+        rest = der::next(rest)?.2;
+    }
+    for _ in 0..3 {
+        rest = der::next(rest)?.2;
+    }
+    let (_, mut subject, _) = der::next(rest)?;
+
+    // A sequence of sets of attributes, each an OID and a string
+    while !subject.is_empty() {
+        let (_, mut set, next) = der::next(subject)?;
+        while !set.is_empty() {
+            let (_, attribute, next) = der::next(set)?;
+            let (_, oid, value) = der::next(attribute)?;
+            if oid == OID_COMMON_NAME {
+                let (_, name, _) = der::next(value)?;
+                return String::from_utf8(name.to_vec()).ok();
+            }
+            set = next;
+        }
+        subject = next;
+    }
+
+    None
+}
+
+/// The DER SubjectPublicKeyInfo of a DER encoded certificate
+fn certificate_public_key(cert: &[u8]) -> Option<&[u8]> {
+    // Certificate, then TBSCertificate
+    let (_, cert, _) = der::next(cert)?;
+    let (_, tbs, _) = der::next(cert)?;
+
+    // The version is optional, the serial number, signature algorithm, issuer,
+    // validity and subject come before the key
+    let (tag, _, mut rest) = der::next(tbs)?;
+    if tag == der::VERSION {
+        rest = der::next(rest)?.2;
+    }
+    for _ in 0..4 {
+        rest = der::next(rest)?.2;
+    }
+    der::first(rest)
+}
+
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_EXTENSION_REQUEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+const OID_SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+
 // from rustls example code
 pub struct NoCertificateVerification {}
 
@@ -69,6 +515,49 @@ impl ServerCertVerifier for NoCertificateVerification {
     }
 }
 
+/// Trusts servers that present an attested certificate the verifier accepts, rather
+/// than a certificate that chains to a CA. The server name is not checked, as the
+/// attestation identifies the enclave instead.
+pub struct AttestedCertVerifier {
+    verifier: AttestationVerifier,
+}
+
+impl AttestedCertVerifier {
+    pub fn new(verifier: AttestationVerifier) -> Self {
+        Self { verifier }
+    }
+}
+
+impl ServerCertVerifier for AttestedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verifier
+            .verify_certificate(&end_entity.0)
+            .map_err(|err| rustls::Error::General(format!("{err:#}")))?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// A client config for the TLS ingress ports of enclaves with attested certificates
+pub fn attested_client_config(verifier: AttestationVerifier) -> Arc<ClientConfig> {
+    let mut cfg = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth();
+
+    cfg.dangerous()
+        .set_certificate_verifier(Arc::new(AttestedCertVerifier::new(verifier)));
+
+    Arc::new(cfg)
+}
+
 pub fn load_insecure_client_config() -> Result<Arc<ClientConfig>> {
     let roots = RootCertStore::empty();
 
@@ -95,3 +584,164 @@ fn data_file(name: &str) -> Result<std::path::PathBuf> {
 pub fn test_server_config() -> Result<Arc<ServerConfig>> {
     load_server_config(data_file("test.key")?, data_file("test.crt")?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        attested_certificate, certificate_request, common_name, data_file, load_client_auth_config,
+        load_mtls_server_config, load_server_config, load_sni_server_config, pem_encode,
+        read_issued_chain, utc_time, with_alpn, RenewedCertificate,
+    };
+    use crate::attestation::certificate_attestation;
+    use crate::der;
+    use crate::keypair::KeyPair;
+    use assert2::assert;
+
+    #[test]
+    fn test_client_auth_config() {
+        let cert = std::fs::read(data_file("test.crt").unwrap()).unwrap();
+        let key = std::fs::read(data_file("test.key").unwrap()).unwrap();
+
+        assert!(load_client_auth_config(&cert, &key, None).is_ok());
+        assert!(load_client_auth_config(&cert, &key, Some(&cert)).is_ok());
+
+        // Each has to be where it is expected
+        assert!(load_client_auth_config(&cert, &cert, None).is_err());
+        assert!(load_client_auth_config(&key, &key, None).is_err());
+        assert!(load_client_auth_config(&cert, &key, Some(&key)).is_err());
+    }
+
+    #[test]
+    fn test_mtls_server_config() {
+        let cert = data_file("test.crt").unwrap();
+        let key = data_file("test.key").unwrap();
+
+        assert!(load_mtls_server_config(&key, &cert, &cert, true).is_ok());
+        assert!(load_mtls_server_config(&key, &cert, &cert, false).is_ok());
+        assert!(load_mtls_server_config(&key, &cert, &key, true).is_err());
+    }
+
+    #[test]
+    fn test_sni_server_config() {
+        let cert = data_file("test.crt").unwrap();
+        let key = data_file("test.key").unwrap();
+        let named =
+            |key: &std::path::Path| vec![("API.test".to_string(), key.to_path_buf(), cert.clone())];
+
+        assert!(load_sni_server_config(&key, &cert, &[], None).is_ok());
+        assert!(load_sni_server_config(&key, &cert, &named(&key), Some((&cert, true))).is_ok());
+        assert!(load_sni_server_config(&key, &cert, &named(&cert), None).is_err());
+    }
+
+    #[test]
+    fn test_alpn() {
+        let cert = data_file("test.crt").unwrap();
+        let key = data_file("test.key").unwrap();
+        let config = load_server_config(&key, &cert).unwrap();
+
+        let alpn = ["h2".to_string(), "http/1.1".to_string()];
+        let config = with_alpn(config, &alpn);
+        assert!(config.alpn_protocols == [b"h2".to_vec(), b"http/1.1".to_vec()]);
+        assert!(with_alpn(config, &[]).alpn_protocols.len() == 2);
+    }
+
+    #[test]
+    fn test_common_name() {
+        let pem = std::fs::read(data_file("test.crt").unwrap()).unwrap();
+        let der = rustls_pemfile::certs(&mut &pem[..]).unwrap().remove(0);
+
+        assert!(common_name(&der).as_deref() == Some("test.local"));
+        assert!(common_name(&der[..der.len() / 2]).is_none());
+        assert!(common_name(b"").is_none());
+    }
+
+    #[test]
+    fn test_attested_certificate() {
+        let keypair = KeyPair::generate().unwrap();
+        let cert =
+            attested_certificate(&keypair, "enclave", b"document", 1_700_000_000, 7200).unwrap();
+
+        // As rustls parses it to check the handshake signature
+        assert!(webpki::EndEntityCert::try_from(cert.0.as_slice()).is_ok());
+        assert!(common_name(&cert.0).as_deref() == Some("enclave"));
+
+        let (public_key, doc) = certificate_attestation(&cert.0).unwrap();
+        assert!(public_key == keypair.public_key_as_der().unwrap());
+        assert!(doc == b"document");
+        assert!(certificate_attestation(&cert.0[..cert.0.len() - 1]).is_none());
+
+        let renewed = RenewedCertificate::default();
+        assert!(renewed.current.read().unwrap().is_none());
+        renewed.set(&keypair, cert).unwrap();
+        assert!(renewed.current.read().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_renewed_pem_key() {
+        let cert = data_file("test.crt").unwrap();
+        let key = std::fs::read(data_file("test.key").unwrap()).unwrap();
+        let cert_pem = std::fs::read(&cert).unwrap();
+
+        let renewed = RenewedCertificate::default();
+        assert!(renewed.set_pem_key(b"", &cert).is_err());
+        assert!(renewed.set_pem_key(&cert_pem, &cert).is_err());
+        assert!(renewed.current.read().unwrap().is_none());
+
+        renewed.set_pem_key(&key, &cert).unwrap();
+        assert!(renewed.current.read().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_certificate_request() {
+        use rsa::{PaddingScheme, PublicKey};
+        use sha2::{Digest, Sha256};
+
+        let keypair = KeyPair::generate().unwrap();
+        let names = ["api.example.com".to_string(), "www.example.com".to_string()];
+        let csr = certificate_request(&keypair, &names, b"document").unwrap();
+
+        // The request info, signed with the key it names
+        let (_, contents, rest) = der::next(&csr).unwrap();
+        assert!(rest.is_empty());
+        let info = der::first(contents).unwrap();
+        let (_, _, signature) = der::next(&contents[info.len()..]).unwrap();
+        let (_, bits, _) = der::next(signature).unwrap();
+        let digest = Sha256::digest(info);
+        let scheme = PaddingScheme::new_pkcs1v15_sign::<Sha256>();
+        assert!(keypair.public.verify(scheme, &digest, &bits[1..]).is_ok());
+
+        let public_key = keypair.public_key_as_der().unwrap();
+        assert!(info.windows(public_key.len()).any(|w| w == public_key));
+        assert!(info.windows(15).any(|w| w == b"www.example.com"));
+        assert!(info.windows(8).any(|w| w == b"document"));
+
+        assert!(certificate_request(&keypair, &[], b"document").is_err());
+        assert!(pem_encode("CERTIFICATE REQUEST", &csr)
+            .starts_with("-----BEGIN CERTIFICATE REQUEST-----\n"));
+    }
+
+    #[test]
+    fn test_read_issued_chain() {
+        let keypair = KeyPair::generate().unwrap();
+        let cert =
+            attested_certificate(&keypair, "enclave", b"document", 1_700_000_000, 7200).unwrap();
+        let test_cert = std::fs::read(data_file("test.crt").unwrap()).unwrap();
+        let pem = pem_encode("CERTIFICATE", &cert.0);
+
+        let chain =
+            read_issued_chain(&keypair, &[pem.as_bytes(), test_cert.as_slice()].concat()).unwrap();
+        assert!(chain.len() == 2);
+        assert!(chain[0] == cert);
+
+        // The chain has to start with a certificate for the key
+        assert!(read_issued_chain(&keypair, &test_cert).is_err());
+        assert!(read_issued_chain(&KeyPair::generate().unwrap(), pem.as_bytes()).is_err());
+        assert!(read_issued_chain(&keypair, b"").is_err());
+    }
+
+    #[test]
+    fn test_utc_time() {
+        assert!(utc_time(1_700_000_000)[2..] == *b"231114221320Z");
+        assert!(utc_time(951_868_799)[2..] == *b"000229235959Z");
+    }
+}
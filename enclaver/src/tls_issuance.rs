@@ -0,0 +1,129 @@
+//! Certificates a CA issues for the key odyn generates for the ingress ports with a
+//! csr in the manifest. odyn serves the certificate signing request for the key at
+//! /v1/tls/csr of its API and on a vsock port, and the host pushes the chain the CA
+//! issued back over the same port. The key never leaves the enclave.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_vsock::VsockStream;
+
+use crate::constants::TLS_ISSUANCE_PORT;
+
+const MAX_MESSAGE_SIZE: u64 = 256 * 1024;
+
+/// What the host asks of odyn
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum IssuanceRequest {
+    Csr,
+
+    /// Presents the PEM encoded chain, the certificate for the key first, on the ports
+    Install {
+        chain: String,
+    },
+}
+
+/// What odyn answers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum IssuanceReply {
+    Csr { csr: String },
+    Installed { ports: Vec<u16> },
+    Rejected { error: String },
+}
+
+/// The PEM encoded certificate signing request of the enclave with the given CID
+/// (host side)
+pub async fn fetch_csr(cid: u32) -> Result<String> {
+    match request(cid, &IssuanceRequest::Csr).await? {
+        IssuanceReply::Csr { csr } => Ok(csr),
+        IssuanceReply::Rejected { error } => Err(anyhow!("the enclave has no csr: {error}")),
+        reply => Err(anyhow!("unexpected answer from the enclave: {reply:?}")),
+    }
+}
+
+/// Pushes the PEM encoded chain a CA issued for the key of the enclave with the given
+/// CID (host side). Returns the ports that present it from then on.
+pub async fn install(cid: u32, chain: &str) -> Result<Vec<u16>> {
+    let install = IssuanceRequest::Install {
+        chain: chain.to_string(),
+    };
+    match request(cid, &install).await? {
+        IssuanceReply::Installed { ports } => Ok(ports),
+        IssuanceReply::Rejected { error } => {
+            Err(anyhow!("the enclave rejected the certificate: {error}"))
+        }
+        reply => Err(anyhow!("unexpected answer from the enclave: {reply:?}")),
+    }
+}
+
+async fn request(cid: u32, request: &IssuanceRequest) -> Result<IssuanceReply> {
+    let mut conn = VsockStream::connect(cid, TLS_ISSUANCE_PORT)
+        .await
+        .map_err(|e| {
+            anyhow!("failed to connect to enclave {cid}, only enclaves with a csr listen: {e}")
+        })?;
+
+    conn.write_all(&serde_json::to_vec(request)?).await?;
+    AsyncWriteExt::shutdown(&mut conn).await?;
+
+    read_message(&mut conn).await
+}
+
+/// Reads a JSON message, up to the point the peer shuts down its side
+pub async fn read_message<S, T>(conn: &mut S) -> Result<T>
+where
+    S: AsyncRead + Unpin,
+    T: for<'de> Deserialize<'de>,
+{
+    let mut buf = Vec::new();
+    conn.take(MAX_MESSAGE_SIZE + 1)
+        .read_to_end(&mut buf)
+        .await?;
+
+    if buf.len() as u64 > MAX_MESSAGE_SIZE {
+        return Err(anyhow!("message exceeds {MAX_MESSAGE_SIZE} bytes"));
+    }
+
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+/// Answers a request (enclave side)
+pub async fn reply<S: AsyncWrite + Unpin>(conn: &mut S, reply: &IssuanceReply) -> Result<()> {
+    conn.write_all(&serde_json::to_vec(reply)?).await?;
+    conn.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_message, reply, IssuanceReply, IssuanceRequest};
+    use assert2::assert;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_messages() {
+        let install = IssuanceRequest::Install {
+            chain: "-----BEGIN CERTIFICATE-----\n".to_string(),
+        };
+        let json = serde_json::to_string(&install).unwrap();
+        assert!(json == r#"{"request":"install","chain":"-----BEGIN CERTIFICATE-----\n"}"#);
+        let csr: IssuanceRequest = serde_json::from_str(r#"{"request":"csr"}"#).unwrap();
+        assert!(csr == IssuanceRequest::Csr);
+
+        let (mut host, mut enclave) = tokio::io::duplex(1024);
+        host.write_all(json.as_bytes()).await.unwrap();
+        host.shutdown().await.unwrap();
+        assert!(
+            read_message::<_, IssuanceRequest>(&mut enclave)
+                .await
+                .unwrap()
+                == install
+        );
+
+        let installed = IssuanceReply::Installed { ports: vec![443] };
+        reply(&mut enclave, &installed).await.unwrap();
+        assert!(read_message::<_, IssuanceReply>(&mut host).await.unwrap() == installed);
+    }
+}
@@ -0,0 +1,171 @@
+use anyhow::Result;
+use rsa::{PaddingScheme, PublicKeyParts};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::keypair::KeyPair;
+
+const ALGORITHM: &str = "RS256";
+
+/// Issues OIDC style JWTs for the application, signed by a key that never leaves
+/// the enclave. Verifiers tie the key to the enclave with an attestation document
+/// that carries its public key.
+pub struct TokenIssuer {
+    keypair: KeyPair,
+    kid: String,
+    issuer: String,
+    subject: String,
+    pcrs: [String; 3],
+    lifetime: u64,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    aud: &'a str,
+    iat: u64,
+    nbf: u64,
+    exp: u64,
+    pcr0: &'a str,
+    pcr1: &'a str,
+    pcr2: &'a str,
+}
+
+impl TokenIssuer {
+    /// subject is the sub claim of every token, and pcrs the hex encoded PCR0 to 2
+    /// of the enclave, each a claim of its own
+    pub fn new(
+        keypair: KeyPair,
+        issuer: &str,
+        subject: &str,
+        pcrs: [String; 3],
+        lifetime: u64,
+    ) -> Result<Self> {
+        // Stable for the key, so verifiers can cache the JWKS
+        let digest = Sha256::digest(keypair.public_key_as_der()?);
+        let kid = base64::encode_config(&digest[..12], base64::URL_SAFE_NO_PAD);
+
+        Ok(Self {
+            keypair,
+            kid,
+            issuer: issuer.trim_end_matches('/').to_string(),
+            subject: subject.to_string(),
+            pcrs,
+            lifetime,
+        })
+    }
+
+    /// A signed JWT for audience, valid from now for the configured lifetime
+    pub fn issue(&self, audience: &str, now: u64) -> Result<(String, u64)> {
+        let header = json!({ "alg": ALGORITHM, "typ": "JWT", "kid": self.kid });
+        let expires_at = now + self.lifetime;
+        let claims = Claims {
+            iss: &self.issuer,
+            sub: &self.subject,
+            aud: audience,
+            iat: now,
+            nbf: now,
+            exp: expires_at,
+            pcr0: &self.pcrs[0],
+            pcr1: &self.pcrs[1],
+            pcr2: &self.pcrs[2],
+        };
+
+        let signing_input = format!(
+            "{}.{}",
+            base64url(&serde_json::to_vec(&header)?),
+            base64url(&serde_json::to_vec(&claims)?)
+        );
+        let signature = self.keypair.private.sign(
+            PaddingScheme::new_pkcs1v15_sign::<Sha256>(),
+            &Sha256::digest(signing_input.as_bytes()),
+        )?;
+
+        Ok((
+            format!("{signing_input}.{}", base64url(&signature)),
+            expires_at,
+        ))
+    }
+
+    /// The key set verifiers check tokens against
+    pub fn jwks(&self) -> Value {
+        json!({
+            "keys": [{
+                "kty": "RSA",
+                "use": "sig",
+                "alg": ALGORITHM,
+                "kid": self.kid,
+                "n": base64url(&self.keypair.public.n().to_bytes_be()),
+                "e": base64url(&self.keypair.public.e().to_bytes_be()),
+            }]
+        })
+    }
+
+    /// The OIDC discovery document, pointing at the JWKS under the issuer
+    pub fn discovery(&self) -> Value {
+        json!({
+            "issuer": self.issuer,
+            "jwks_uri": format!("{}/.well-known/jwks.json", self.issuer),
+            "id_token_signing_alg_values_supported": [ALGORITHM],
+            "subject_types_supported": ["public"],
+            "response_types_supported": ["id_token"],
+        })
+    }
+
+    pub fn public_key_as_der(&self) -> Result<Vec<u8>> {
+        self.keypair.public_key_as_der()
+    }
+}
+
+fn base64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenIssuer;
+    use crate::keypair::KeyPair;
+    use assert2::assert;
+    use rsa::{PaddingScheme, PublicKey};
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_issue_token() {
+        let keypair = KeyPair::generate().unwrap();
+        let public = keypair.public.clone();
+        let pcrs = ["00".repeat(48), "01".repeat(48), "02".repeat(48)];
+        let issuer =
+            TokenIssuer::new(keypair, "https://enclave.example.com/", "app", pcrs, 300).unwrap();
+
+        let (token, expires_at) = issuer.issue("https://api.example.com", 1000).unwrap();
+        assert!(expires_at == 1300);
+
+        let parts: Vec<&str> = token.split('.').collect();
+        assert!(parts.len() == 3);
+
+        let decode = |part: &str| base64::decode_config(part, base64::URL_SAFE_NO_PAD).unwrap();
+        let header: serde_json::Value = serde_json::from_slice(&decode(parts[0])).unwrap();
+        let claims: serde_json::Value = serde_json::from_slice(&decode(parts[1])).unwrap();
+        assert!(header["kid"] == issuer.jwks()["keys"][0]["kid"]);
+        assert!(claims["iss"] == "https://enclave.example.com");
+        assert!(claims["aud"] == "https://api.example.com");
+        assert!(claims["exp"] == 1300);
+        assert!(claims["pcr1"] == "01".repeat(48));
+
+        let digest = Sha256::digest(format!("{}.{}", parts[0], parts[1]).as_bytes());
+        let signature = decode(parts[2]);
+        assert!(public
+            .verify(
+                PaddingScheme::new_pkcs1v15_sign::<Sha256>(),
+                &digest,
+                &signature
+            )
+            .is_ok());
+
+        assert!(
+            issuer.discovery()["jwks_uri"] == "https://enclave.example.com/.well-known/jwks.json"
+        );
+    }
+}
@@ -1,13 +1,22 @@
 use anyhow::{anyhow, Result};
-use futures_util::stream::StreamExt;
 use log::{info, LevelFilter};
+use std::collections::HashMap;
+use std::fmt::Write;
 use std::future::Future;
 use std::path::PathBuf;
-use tokio::io::AsyncRead;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::signal::unix::{signal, SignalKind};
-use tokio_util::codec::{FramedRead, LinesCodec};
 
-const LOG_LINE_MAX_LEN: usize = 4 * 1024;
+pub const LOG_LINE_MAX_LEN: usize = 4 * 1024;
+
+/// How often a message logged with log_limited! is logged at most
+pub const LOG_LIMIT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Keys a LogLimiter remembers, so that keys taken from peers cannot grow it
+// without bounds. Keys past this share one entry.
+const LOG_LIMIT_MAX_KEYS: usize = 256;
 
 #[cfg(feature = "tracing")]
 #[macro_export]
@@ -27,6 +36,95 @@ macro_rules! spawn {
 
 pub use spawn;
 
+/// Logs a message at most once per LOG_LIMIT_INTERVAL for each key, for error paths
+/// a flapping upstream or a failing listener can hit thousands of times a second.
+/// The messages held back in between are counted, and the count is added to the
+/// next message logged for the key. Each call site limits its messages separately.
+///
+/// ```ignore
+/// log_limited!(Level::Error, &target, "Connection to upstream ({target}) failed: {err}");
+/// ```
+#[macro_export]
+macro_rules! log_limited {
+    ($level:expr, $key:expr, $($arg:tt)+) => {{
+        static LIMITER: $crate::utils::LogLimiter =
+            $crate::utils::LogLimiter::new($crate::utils::LOG_LIMIT_INTERVAL);
+        match LIMITER.check($key) {
+            Some(0) => ::log::log!($level, $($arg)+),
+            Some(n) => ::log::log!(
+                $level,
+                "{} ({n} similar messages suppressed)",
+                format_args!($($arg)+)
+            ),
+            None => (),
+        }
+    }};
+}
+
+pub use log_limited;
+
+/// Decides which messages log_limited! logs.
+pub struct LogLimiter {
+    interval: Duration,
+    seen: Mutex<Option<HashMap<String, Seen>>>,
+}
+
+struct Seen {
+    logged: Instant,
+    suppressed: u64,
+}
+
+impl LogLimiter {
+    pub const fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            seen: Mutex::new(None),
+        }
+    }
+
+    /// Whether to log a message with the given key now, and if so, how many messages
+    /// with the key were held back since the last one logged.
+    pub fn check(&self, key: &str) -> Option<u64> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Option<u64> {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        let seen = seen.get_or_insert_with(HashMap::new);
+
+        if !seen.contains_key(key) && seen.len() >= LOG_LIMIT_MAX_KEYS {
+            seen.retain(|_, entry| now.duration_since(entry.logged) < self.interval);
+        }
+        let key = match seen.len() < LOG_LIMIT_MAX_KEYS || seen.contains_key(key) {
+            true => key,
+            false => "",
+        };
+
+        match seen.get_mut(key) {
+            Some(entry) if now.duration_since(entry.logged) < self.interval => {
+                entry.suppressed += 1;
+                None
+            }
+            Some(entry) => {
+                let suppressed = entry.suppressed;
+                entry.logged = now;
+                entry.suppressed = 0;
+                Some(suppressed)
+            }
+            None => {
+                seen.insert(
+                    key.to_string(),
+                    Seen {
+                        logged: now,
+                        suppressed: 0,
+                    },
+                );
+                Some(0)
+            }
+        }
+    }
+}
+
 pub fn init_logging(verbosity: u8) {
     fn level_filter(verbosity: u8) -> LevelFilter {
         match verbosity {
@@ -64,22 +162,150 @@ impl StringablePathExt for PathBuf {
     }
 }
 
-pub async fn log_lines_from_stream<S>(target: &str, stream: S) -> Result<()>
+/// How the host renders the log streams of the enclave and its debug console.
+#[derive(Clone, Copy, Debug)]
+pub struct LogRendering {
+    /// Longest line logged as one record, longer lines are logged in pieces
+    pub max_line_len: usize,
+
+    /// Write the lines to stdout byte for byte, line endings and all, instead of
+    /// logging them, for sinks that do their own parsing
+    pub raw: bool,
+}
+
+impl Default for LogRendering {
+    fn default() -> Self {
+        Self {
+            max_line_len: LOG_LINE_MAX_LEN,
+            raw: false,
+        }
+    }
+}
+
+impl LogRendering {
+    // Reads the next line, or the next piece of an overlong one, into line
+    async fn read_line<R>(&self, reader: &mut R, line: &mut Vec<u8>) -> Result<usize>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        line.clear();
+        let n = reader
+            .take(self.max_line_len as u64)
+            .read_until(b'\n', line)
+            .await?;
+        Ok(n)
+    }
+
+    async fn emit(&self, target: &str, line: &[u8], text: &str) -> Result<()> {
+        match self.raw {
+            true => {
+                let mut stdout = tokio::io::stdout();
+                stdout.write_all(line).await?;
+                stdout.flush().await?;
+            }
+            false => info!(target: target, "{text}"),
+        }
+        Ok(())
+    }
+}
+
+/// Renders a line of log output as printable text. The line ending is dropped, and
+/// invalid UTF-8 and control characters other than tab are escaped, e.g. the
+/// carriage returns of a progress bar as `\r` and the colors of a terminal as `\u{1b}`.
+pub fn render_log_line(line: &[u8]) -> String {
+    fn escape(out: &mut String, text: &str) {
+        for c in text.chars() {
+            match c {
+                '\t' => out.push(c),
+                c if c.is_control() => out.extend(c.escape_default()),
+                c => out.push(c),
+            }
+        }
+    }
+
+    let mut rest = line;
+    while let Some(stripped) = rest
+        .strip_suffix(b"\n")
+        .or_else(|| rest.strip_suffix(b"\r"))
+    {
+        rest = stripped;
+    }
+
+    let mut out = String::with_capacity(rest.len());
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(text) => {
+                escape(&mut out, text);
+                return out;
+            }
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                escape(&mut out, std::str::from_utf8(valid).unwrap_or_default());
+
+                // A sequence cut short by the end of the line counts as invalid too
+                let len = e.error_len().unwrap_or(invalid.len());
+                for b in &invalid[..len] {
+                    let _ = write!(out, "\\x{b:02x}");
+                }
+                rest = &invalid[len..];
+            }
+        }
+    }
+}
+
+pub async fn log_lines_from_stream<S>(
+    target: &str,
+    stream: S,
+    rendering: LogRendering,
+) -> Result<()>
 where
     S: AsyncRead + Unpin,
 {
-    let mut framed = FramedRead::new(stream, LinesCodec::new_with_max_length(LOG_LINE_MAX_LEN));
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
 
-    while let Some(line_res) = framed.next().await {
-        match line_res {
-            Ok(line) => info!(target: target, "{line}"),
-            Err(e) => info!(target: target, "error reading log stream: {e}"),
-        }
+    while rendering.read_line(&mut reader, &mut line).await? > 0 {
+        rendering
+            .emit(target, &line, &render_log_line(&line))
+            .await?;
     }
 
     Ok(())
 }
 
+/// Like log_lines_from_stream, but advances cursor past each line as it is logged,
+/// so a reconnecting reader can resume after the last complete line. A partial
+/// line at the end of the stream is left for the next connection. Each line is
+/// also handed to on_line once logged.
+pub async fn log_lines_from_cursor<S, F>(
+    target: &str,
+    stream: S,
+    cursor: &mut u64,
+    rendering: LogRendering,
+    mut on_line: F,
+) -> Result<()>
+where
+    S: AsyncRead + Unpin,
+    F: FnMut(&str),
+{
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+
+    loop {
+        let n = rendering.read_line(&mut reader, &mut line).await?;
+
+        // Overlong lines are logged in pieces
+        if n == 0 || (line.last() != Some(&b'\n') && n < rendering.max_line_len) {
+            return Ok(());
+        }
+
+        *cursor += n as u64;
+        let text = render_log_line(&line);
+        rendering.emit(target, &line, &text).await?;
+        on_line(&text);
+    }
+}
+
 pub async fn register_shutdown_signal_handler() -> Result<impl Future> {
     let mut sigint = signal(SignalKind::interrupt())?;
     let mut sigterm = signal(SignalKind::terminate())?;
@@ -92,3 +318,91 @@ pub async fn register_shutdown_signal_handler() -> Result<impl Future> {
     })
     .map_err(Into::into)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        log_lines_from_cursor, render_log_line, LogLimiter, LogRendering, LOG_LIMIT_MAX_KEYS,
+    };
+    use assert2::assert;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_log_lines_from_cursor() {
+        let mut cursor = 10;
+        let mut lines = Vec::new();
+        let rendering = LogRendering::default();
+        log_lines_from_cursor(
+            "test",
+            &b"foo\r\nbar\nba"[..],
+            &mut cursor,
+            rendering,
+            |line| lines.push(line.to_string()),
+        )
+        .await
+        .unwrap();
+        assert!(cursor == 19);
+        assert!(lines == ["foo", "bar"]);
+    }
+
+    #[tokio::test]
+    async fn test_log_lines_max_len() {
+        let mut cursor = 0;
+        let mut lines = Vec::new();
+        let rendering = LogRendering {
+            max_line_len: 4,
+            ..Default::default()
+        };
+        log_lines_from_cursor(
+            "test",
+            &b"abcdefg\nhi"[..],
+            &mut cursor,
+            rendering,
+            |line| lines.push(line.to_string()),
+        )
+        .await
+        .unwrap();
+        assert!(cursor == 8);
+        assert!(lines == ["abcd", "efg"]);
+    }
+
+    #[test]
+    fn test_render_log_line() {
+        assert!(render_log_line(b"plain text\r\n") == "plain text");
+        assert!(render_log_line(b"a\tb") == "a\tb");
+        assert!(render_log_line(b"10%\r50%\r100%\n") == "10%\\r50%\\r100%");
+        assert!(render_log_line(b"\x1b[31mred\x1b[0m") == "\\u{1b}[31mred\\u{1b}[0m");
+        assert!(render_log_line("caf\u{e9}".as_bytes()) == "caf\u{e9}");
+        assert!(render_log_line(b"\xff\xfeok\xc3") == "\\xff\\xfeok\\xc3");
+    }
+
+    #[test]
+    fn test_log_limiter() {
+        let limiter = LogLimiter::new(Duration::from_secs(10));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(limiter.check_at("10.0.0.1:80", at(0)) == Some(0));
+        assert!(limiter.check_at("10.0.0.1:80", at(1)).is_none());
+        assert!(limiter.check_at("10.0.0.1:80", at(2)).is_none());
+        assert!(limiter.check_at("10.0.0.2:80", at(2)) == Some(0));
+        assert!(limiter.check_at("10.0.0.1:80", at(10)) == Some(2));
+        assert!(limiter.check_at("10.0.0.1:80", at(11)).is_none());
+    }
+
+    #[test]
+    fn test_log_limiter_max_keys() {
+        let limiter = LogLimiter::new(Duration::from_secs(10));
+        let start = Instant::now();
+
+        for i in 0..LOG_LIMIT_MAX_KEYS {
+            assert!(limiter.check_at(&i.to_string(), start) == Some(0));
+        }
+
+        // Keys past the limit share one entry until the others expire
+        assert!(limiter.check_at("a", start) == Some(0));
+        assert!(limiter.check_at("b", start).is_none());
+        let later = start + Duration::from_secs(10);
+        assert!(limiter.check_at("b", later) == Some(0));
+    }
+}
@@ -1,12 +1,14 @@
-use anyhow::Result;
 use futures::{Stream, StreamExt};
-use log::{debug, error, info};
+use log::{debug, info, Level};
 use rustls::client::ServerName;
 use rustls::{ClientConfig, ServerConfig};
+use std::io::Result;
 use std::sync::Arc;
 use tokio_rustls::{TlsAcceptor, TlsConnector};
 use tokio_vsock::{VsockListener, VsockStream};
 
+use crate::utils;
+
 pub const VMADDR_CID_ANY: u32 = 0xFFFFFFFF;
 pub const VMADDR_CID_LOCAL: u32 = 1;
 pub const VMADDR_CID_HOST: u32 = 2;
@@ -28,7 +30,8 @@ pub fn serve(port: u32) -> Result<impl Stream<Item = VsockStream> + Unpin> {
             }
 
             Err(err) => {
-                error!("Failed to accept a vsock: {err}");
+                let key = &port.to_string();
+                utils::log_limited!(Level::Error, key, "Failed to accept a vsock: {err}");
                 None
             }
         })
@@ -56,14 +59,16 @@ pub fn tls_serve(
                     match acceptor.accept(vsock).await {
                         Ok(vsock) => Some(vsock),
                         Err(err) => {
-                            error!("TLS handshake failed: {err}");
+                            let key = &port.to_string();
+                            utils::log_limited!(Level::Error, key, "TLS handshake failed: {err}");
                             None
                         }
                     }
                 }
 
                 Err(err) => {
-                    error!("Failed to accept a vsock: {err}");
+                    let key = &port.to_string();
+                    utils::log_limited!(Level::Error, key, "Failed to accept a vsock: {err}");
                     None
                 }
             }